@@ -0,0 +1,565 @@
+//! Collector of reports of lockc instances in the reporter mode, giving
+//! fleet-wide visibility of containers and violations without Prometheus. It
+//! keeps the latest report of every node and counts violations reported
+//! since it started:
+//!
+//! * `POST /report` accepts a report of a node
+//! * `GET /nodes` lists nodes with their number of containers and
+//!   violations, and whether they stopped reporting
+//! * `GET /containers` lists containers of all nodes
+//! * `GET /violations` lists violations of all nodes, by container and hook
+//!
+//! With `--tls-cert` and `--tls-key`, the collector serves HTTPS, and with
+//! `--client-ca`, it accepts only clients with a certificate signed by the CA
+//! (mutual TLS). A report is then accepted only if the node is named by the
+//! common name or a DNS name of the client certificate, so a node can't
+//! overwrite reports of other nodes. Plain HTTP and reports without a client
+//! certificate are accepted only with `--insecure`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use clap::Parser;
+use lockc_common::{
+    control::ContainerInfo,
+    http,
+    report::{NodeReport, ViolationSummary, COLLECTOR_PORT, REPORT_PATH},
+    Enforcement, Hook,
+};
+use openssl::{
+    nid::Nid,
+    ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslRef, SslVerifyMode},
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    time,
+};
+use tokio_openssl::SslStream;
+use tracing::{debug, info, warn};
+
+/// Max size of a report. Reports of nodes with thousands of containers fit.
+const MAX_REPORT_SIZE: usize = 16 * 1024 * 1024;
+
+/// Time to complete the TLS handshake and to read a request.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max number of connections handled at once. Further clients wait until
+/// one of them is closed, so clients which didn't authenticate yet can't make
+/// the collector buffer more than this many reports.
+const MAX_CONNECTIONS: usize = 64;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Address to listen on.
+    #[arg(
+        long,
+        env = "LOCKC_COLLECTOR_ADDRESS",
+        default_value_t = SocketAddr::from(([0, 0, 0, 0], COLLECTOR_PORT))
+    )]
+    address: SocketAddr,
+
+    /// PEM file with the server certificate chain. HTTPS is served when set
+    /// together with `--tls-key`. Required unless `--insecure` is given.
+    #[arg(
+        long,
+        env = "LOCKC_COLLECTOR_TLS_CERT",
+        requires = "tls_key",
+        required_unless_present = "insecure"
+    )]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the server certificate.
+    #[arg(long, env = "LOCKC_COLLECTOR_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM file with CA certificates of clients. Only clients with a
+    /// certificate signed by one of them are accepted.
+    #[arg(long, env = "LOCKC_COLLECTOR_CLIENT_CA", requires = "tls_cert")]
+    client_ca: Option<PathBuf>,
+
+    /// Number of missed report intervals after which a node is shown as
+    /// stale.
+    #[arg(long, env = "LOCKC_COLLECTOR_STALE_INTERVALS", default_value_t = 3)]
+    stale_intervals: u32,
+
+    /// Serves plain HTTP without `--tls-cert` and accepts reports from
+    /// clients without a certificate, trusting the node name they send.
+    #[arg(long, env = "LOCKC_COLLECTOR_INSECURE")]
+    insecure: bool,
+}
+
+/// Latest report of a node and its violations since the collector started.
+struct NodeState {
+    report: NodeReport,
+    received: Instant,
+    violations: HashMap<(String, Hook, Enforcement), ViolationSummary>,
+}
+
+/// Node shown by `GET /nodes`.
+#[derive(Debug, Serialize)]
+struct NodeInfo {
+    node: String,
+    /// Time of the latest report, as sent by the node.
+    last_report: String,
+    /// Whether the node missed too many reports.
+    stale: bool,
+    containers: usize,
+    /// Violations reported since the collector started.
+    violations: u64,
+}
+
+/// Container shown by `GET /containers`.
+#[derive(Debug, Serialize)]
+struct FleetContainer {
+    node: String,
+    #[serde(flatten)]
+    container: ContainerInfo,
+}
+
+/// Violations shown by `GET /violations`.
+#[derive(Debug, Serialize)]
+struct FleetViolation {
+    node: String,
+    #[serde(flatten)]
+    summary: ViolationSummary,
+}
+
+/// Reports of all nodes.
+#[derive(Default)]
+struct Fleet {
+    nodes: BTreeMap<String, NodeState>,
+}
+
+impl Fleet {
+    fn update(&mut self, mut report: NodeReport, now: Instant) {
+        let state = self
+            .nodes
+            .entry(report.node.clone())
+            .or_insert_with(|| NodeState {
+                report: report.clone(),
+                received: now,
+                violations: HashMap::new(),
+            });
+        for summary in report.violations.drain(..) {
+            let key = (
+                summary.container_id.clone(),
+                summary.hook,
+                summary.enforcement,
+            );
+            match state.violations.get_mut(&key) {
+                Some(total) => {
+                    total.count += summary.count;
+                    if summary.last_detail.is_some() {
+                        total.last_detail = summary.last_detail;
+                    }
+                }
+                None => {
+                    state.violations.insert(key, summary);
+                }
+            }
+        }
+        state.report = report;
+        state.received = now;
+    }
+
+    fn nodes(&self, now: Instant, stale_intervals: u32) -> Vec<NodeInfo> {
+        self.nodes
+            .iter()
+            .map(|(node, state)| {
+                let stale_after = Duration::from_secs(state.report.interval_s) * stale_intervals;
+                NodeInfo {
+                    node: node.clone(),
+                    last_report: state.report.time.clone(),
+                    stale: now.duration_since(state.received) > stale_after,
+                    containers: state.report.containers.len(),
+                    violations: state.violations.values().map(|v| v.count).sum(),
+                }
+            })
+            .collect()
+    }
+
+    fn containers(&self) -> Vec<FleetContainer> {
+        self.nodes
+            .iter()
+            .flat_map(|(node, state)| {
+                state
+                    .report
+                    .containers
+                    .iter()
+                    .map(move |container| FleetContainer {
+                        node: node.clone(),
+                        container: container.clone(),
+                    })
+            })
+            .collect()
+    }
+
+    /// Returns violations of all nodes, the most frequent first.
+    fn violations(&self) -> Vec<FleetViolation> {
+        let mut violations: Vec<FleetViolation> = self
+            .nodes
+            .iter()
+            .flat_map(|(node, state)| {
+                state
+                    .violations
+                    .values()
+                    .map(move |summary| FleetViolation {
+                        node: node.clone(),
+                        summary: summary.clone(),
+                    })
+            })
+            .collect();
+        violations.sort_by(|a, b| b.summary.count.cmp(&a.summary.count));
+        violations
+    }
+}
+
+/// Returns the common names and DNS names of the client certificate, if the
+/// client sent one.
+fn peer_names(ssl: &SslRef) -> Option<Vec<String>> {
+    let cert = ssl.peer_certificate()?;
+    let mut names: Vec<String> = cert
+        .subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+        .collect();
+    if let Some(alt_names) = cert.subject_alt_names() {
+        names.extend(
+            alt_names
+                .iter()
+                .filter_map(|name| name.dnsname())
+                .map(str::to_string),
+        );
+    }
+    Some(names)
+}
+
+/// Returns whether a client may post the report of `node`. A client with a
+/// certificate may report only a node named by it, a client without one only
+/// in the insecure mode.
+fn may_report(node: &str, peer_names: Option<&[String]>, insecure: bool) -> bool {
+    match peer_names {
+        Some(names) => names.iter().any(|name| name == node),
+        None => insecure,
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> (&'static str, String) {
+    match serde_json::to_string(value) {
+        Ok(body) => ("200 OK", body),
+        Err(e) => ("500 Internal Server Error", e.to_string()),
+    }
+}
+
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer_names: Option<Vec<String>>,
+    fleet: &Mutex<Fleet>,
+    stale_intervals: u32,
+    insecure: bool,
+) -> io::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let request = time::timeout(READ_TIMEOUT, async {
+        let request = http::read_request(&mut reader).await?;
+        let body = http::read_body(&mut reader, &request, MAX_REPORT_SIZE).await?;
+        Ok::<_, io::Error>(body.map(|body| (request, body)))
+    })
+    .await
+    .map_err(http::timed_out)??;
+
+    let (status, body) = match request {
+        None => ("413 Payload Too Large", String::new()),
+        Some((request, body)) => {
+            let mut fleet = fleet
+                .lock()
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "fleet is poisoned"))?;
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", REPORT_PATH) => match serde_json::from_slice::<NodeReport>(&body) {
+                    Ok(report) if !may_report(&report.node, peer_names.as_deref(), insecure) => {
+                        warn!(
+                            node = report.node.as_str(),
+                            "refused a report of a node not named by the client certificate"
+                        );
+                        ("403 Forbidden", String::new())
+                    }
+                    Ok(report) => {
+                        debug!(
+                            node = report.node.as_str(),
+                            containers = report.containers.len(),
+                            violations = report.violations.len(),
+                            "received a report"
+                        );
+                        fleet.update(report, Instant::now());
+                        ("204 No Content", String::new())
+                    }
+                    Err(e) => ("400 Bad Request", e.to_string()),
+                },
+                ("GET", "/nodes") => json(&fleet.nodes(Instant::now(), stale_intervals)),
+                ("GET", "/containers") => json(&fleet.containers()),
+                ("GET", "/violations") => json(&fleet.violations()),
+                _ => ("404 Not Found", String::new()),
+            }
+        }
+    };
+
+    http::write_response(&mut writer, status, "application/json", &body).await?;
+    writer.shutdown().await
+}
+
+fn tls_acceptor(args: &Args) -> anyhow::Result<Option<SslAcceptor>> {
+    let (cert, key) = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder
+        .set_certificate_chain_file(cert)
+        .with_context(|| format!("could not load {}", cert.display()))?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .with_context(|| format!("could not load {}", key.display()))?;
+    builder.check_private_key()?;
+    if let Some(client_ca) = &args.client_ca {
+        builder
+            .set_ca_file(client_ca)
+            .with_context(|| format!("could not load {}", client_ca.display()))?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+    Ok(Some(builder.build()))
+}
+
+/// Completes the TLS handshake with a client.
+async fn accept_tls(acceptor: &SslAcceptor, stream: TcpStream) -> io::Result<SslStream<TcpStream>> {
+    let mut stream = Ssl::new(acceptor.context())
+        .and_then(|ssl| SslStream::new(ssl, stream))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    time::timeout(READ_TIMEOUT, Pin::new(&mut stream).accept())
+        .await
+        .map_err(http::timed_out)?
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(stream)
+}
+
+async fn serve(
+    listener: TcpListener,
+    acceptor: Option<Arc<SslAcceptor>>,
+    fleet: Arc<Mutex<Fleet>>,
+    stale_intervals: u32,
+    insecure: bool,
+) {
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let permit = match connections.clone().acquire_owned().await {
+            Ok(permit) => permit,
+            Err(_) => break,
+        };
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "could not accept a connection"
+                );
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let fleet = fleet.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let res = match acceptor {
+                Some(acceptor) => match accept_tls(&acceptor, stream).await {
+                    Ok(stream) => {
+                        let names = peer_names(stream.ssl());
+                        handle_connection(stream, names, &fleet, stale_intervals, insecure).await
+                    }
+                    Err(e) => Err(e),
+                },
+                None => handle_connection(stream, None, &fleet, stale_intervals, insecure).await,
+            };
+            if let Err(e) = res {
+                warn!(
+                    peer = peer.to_string().as_str(),
+                    error = e.to_string().as_str(),
+                    "connection failed"
+                );
+            }
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let acceptor = tls_acceptor(&args)?.map(Arc::new);
+    let listener = TcpListener::bind(args.address)
+        .await
+        .with_context(|| format!("could not listen on {}", args.address))?;
+    info!(
+        address = args.address.to_string().as_str(),
+        tls = acceptor.is_some(),
+        mutual_tls = args.client_ca.is_some(),
+        insecure = args.insecure,
+        "collecting reports"
+    );
+    serve(
+        listener,
+        acceptor,
+        Arc::new(Mutex::new(Fleet::default())),
+        args.stale_intervals,
+        args.insecure,
+    )
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::ContainerPolicyLevel;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    fn report(node: &str, count: u64, detail: Option<&str>) -> NodeReport {
+        NodeReport {
+            node: node.to_string(),
+            time: "2022-01-01T00:00:00Z".to_string(),
+            interval_s: 60,
+            containers: vec![ContainerInfo {
+                id: "abc".to_string(),
+                name: None,
+                pod: None,
+                namespace: None,
+                image: None,
+                parent: None,
+                root: None,
+                runtime_id: None,
+                policy_level: ContainerPolicyLevel::Restricted,
+                enforcement: Enforcement::Enforce,
+            }],
+            violations: vec![ViolationSummary {
+                container_id: "abc".to_string(),
+                hook: Hook::FileOpen,
+                enforcement: Enforcement::Enforce,
+                count,
+                last_detail: detail.map(str::to_string),
+            }],
+        }
+    }
+
+    #[test]
+    fn fleet_update() {
+        let mut fleet = Fleet::default();
+        let start = Instant::now();
+        fleet.update(report("node-1", 2, Some("/etc/shadow")), start);
+        fleet.update(report("node-2", 1, None), start);
+        let later = start + Duration::from_secs(200);
+        fleet.update(report("node-1", 3, None), later);
+
+        let nodes = fleet.nodes(later, 3);
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].violations, 5);
+        assert!(!nodes[0].stale);
+        assert!(nodes[1].stale);
+        assert_eq!(fleet.containers().len(), 2);
+
+        let violations = fleet.violations();
+        assert_eq!(violations[0].node, "node-1");
+        assert_eq!(violations[0].summary.count, 5);
+        assert_eq!(
+            violations[0].summary.last_detail.as_deref(),
+            Some("/etc/shadow")
+        );
+    }
+
+    async fn request(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn collector_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, None, Arc::default(), 3, true));
+
+        let body = serde_json::to_string(&report("node-1", 2, None)).unwrap();
+        let response = request(
+            addr,
+            format!(
+                "POST /report HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+
+        let response = request(
+            addr,
+            b"POST /report HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let response = request(addr, b"GET /containers HTTP/1.1\r\n\r\n").await;
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let containers: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(containers[0]["node"], "node-1");
+        assert_eq!(containers[0]["id"], "abc");
+
+        let response = request(addr, b"GET /nodes HTTP/1.1\r\n\r\n").await;
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let nodes: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(nodes[0]["violations"], 2);
+        assert_eq!(nodes[0]["stale"], false);
+
+        let response = request(addr, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[tokio::test]
+    async fn collector_refuse_reports() {
+        let names = vec!["node-1".to_string()];
+        assert!(may_report("node-1", Some(&names), false));
+        assert!(!may_report("node-2", Some(&names), true));
+        assert!(!may_report("node-1", None, false));
+        assert!(may_report("node-1", None, true));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, None, Arc::default(), 3, false));
+        let body = serde_json::to_string(&report("node-1", 2, None)).unwrap();
+        let response = request(
+            addr,
+            format!(
+                "POST /report HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+}
@@ -0,0 +1,66 @@
+//! Measures the decision logic of policies based on path prefixes, with
+//! prefixes looked up by a linear scan. It's not the hot path of lockc: eBPF
+//! programs look up the longest prefix in an LPM trie map, which takes a
+//! single lookup regardless of the number of prefixes. The results show the
+//! cost of the decision logic itself and the worst case of a naive lookup.
+//! `cargo xtask bench` collects the samples which criterion stores in
+//! `criterion/verdict_linear_scan` of the target directory.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use lockc_common::{
+    verdict::{self, PathLists},
+    ContainerPolicyLevel, FilePermission, PathClass, PATH_MAX_LIMIT,
+};
+
+/// The same prefixes used for all classes.
+struct Prefixes(Vec<String>);
+
+impl Prefixes {
+    fn new(len: u32) -> Self {
+        Prefixes((0..len).map(|i| format!("/var/lib/bench/{}", i)).collect())
+    }
+}
+
+impl PathLists for Prefixes {
+    fn lookup(&self, _class: PathClass, path: &[u8]) -> Option<FilePermission> {
+        self.0
+            .iter()
+            .filter(|prefix| path.starts_with(prefix.as_bytes()))
+            .max_by_key(|prefix| prefix.len())
+            .map(|_| FilePermission::Deny)
+    }
+}
+
+fn verdict_linear_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verdict_linear_scan");
+    for len in [8, PATH_MAX_LIMIT] {
+        let prefixes = Prefixes::new(len);
+        // Paths which don't match any prefix are the worst case, all
+        // prefixes are checked.
+        group.bench_function(format!("mount_{}_prefixes", len), |b| {
+            b.iter(|| {
+                verdict::mount(
+                    &prefixes,
+                    ContainerPolicyLevel::Restricted,
+                    "bind",
+                    black_box("/var/lib/other/volume"),
+                )
+            })
+        });
+        group.bench_function(format!("file_open_{}_prefixes", len), |b| {
+            b.iter(|| {
+                verdict::file_open(
+                    &prefixes,
+                    ContainerPolicyLevel::Restricted,
+                    black_box("/usr/lib/x86_64-linux-gnu/libc.so.6"),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, verdict_linear_scan);
+criterion_main!(benches);
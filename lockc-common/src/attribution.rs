@@ -0,0 +1,154 @@
+//! Attribution of new tasks to containers, shared by eBPF programs and
+//! tests. Threads of a process always belong to the container of the
+//! process, so tasks are attributed by their thread-group leader. New
+//! processes are attributed by the task which forked them or by their real
+//! parent, never by the tracer, which is their parent when they are
+//! ptraced.
+
+use crate::ContainerID;
+
+/// Source of containers of registered processes. eBPF programs look them up
+/// in the `PROCESSES` map.
+pub trait ProcessContainers {
+    /// Returns the container of the process with the given PID (thread-group
+    /// ID), if it's registered.
+    fn lookup(&self, tgid: i32) -> Option<ContainerID>;
+}
+
+/// IDs of a task. `tgid` is the PID of its thread-group leader, which is the
+/// PID of the process in userspace.
+#[derive(Copy, Clone)]
+pub struct TaskIds {
+    pub pid: i32,
+    pub tgid: i32,
+}
+
+impl TaskIds {
+    /// Returns whether the task is a thread other than the thread-group
+    /// leader.
+    #[inline(always)]
+    pub fn is_thread(&self) -> bool {
+        self.pid != self.tgid
+    }
+}
+
+/// Returns the container of the task. Processes are registered by their
+/// thread-group ID, so all threads of a process share its entry, including
+/// threads which existed before the process was registered (e.g. threads of
+/// the Go runtime of a container runtime).
+#[inline(always)]
+pub fn task_container<P: ProcessContainers>(processes: &P, task: TaskIds) -> Option<ContainerID> {
+    processes.lookup(task.tgid)
+}
+
+/// Returns the container which the new task has to be attributed to, `None`
+/// if it's not containerized. A new thread belongs to the container of its
+/// process. Otherwise the task belongs to the container of `parent`, the
+/// task which forked it (runc forks the init process with `CLONE_PARENT`, so
+/// its real parent is outside of the container) or its real parent when it
+/// executes a binary.
+#[inline(always)]
+pub fn new_task_container<P: ProcessContainers>(
+    processes: &P,
+    task: TaskIds,
+    parent: TaskIds,
+) -> Option<ContainerID> {
+    if task.is_thread() {
+        return task_container(processes, task);
+    }
+    task_container(processes, parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, str::FromStr};
+
+    use super::*;
+
+    struct TestProcesses(HashMap<i32, &'static str>);
+
+    impl ProcessContainers for TestProcesses {
+        fn lookup(&self, pid: i32) -> Option<ContainerID> {
+            self.0
+                .get(&pid)
+                .map(|id| ContainerID::from_str(id).unwrap())
+        }
+    }
+
+    fn container(container_id: Option<ContainerID>) -> Option<String> {
+        container_id.map(|id| id.as_str().unwrap().trim_end_matches('\0').to_string())
+    }
+
+    fn task(pid: i32, tgid: i32) -> TaskIds {
+        TaskIds { pid, tgid }
+    }
+
+    #[test]
+    fn attribution_threaded_runtime() {
+        // runc (100) is registered by userspace after the Go runtime started
+        // its threads (101, 102).
+        let processes = TestProcesses(HashMap::from([(100, "abc")]));
+
+        assert_eq!(
+            container(task_container(&processes, task(101, 100))).as_deref(),
+            Some("abc")
+        );
+        // The init process of the container is forked by one of the
+        // threads.
+        assert_eq!(
+            container(new_task_container(
+                &processes,
+                task(200, 200),
+                task(102, 100)
+            ))
+            .as_deref(),
+            Some("abc")
+        );
+        // A new thread of runc, created by another thread.
+        assert_eq!(
+            container(new_task_container(
+                &processes,
+                task(103, 100),
+                task(101, 100)
+            ))
+            .as_deref(),
+            Some("abc")
+        );
+        // Processes forked by threads of uncontainerized processes.
+        assert!(new_task_container(&processes, task(300, 300), task(51, 50)).is_none());
+        assert!(task_container(&processes, task(50, 50)).is_none());
+    }
+
+    #[test]
+    fn attribution_threaded_workload() {
+        let processes = TestProcesses(HashMap::from([(200, "abc"), (400, "def")]));
+
+        // The real parent of a new thread is the parent of its process,
+        // which is outside of the container.
+        assert_eq!(
+            container(new_task_container(
+                &processes,
+                task(201, 200),
+                task(999, 999)
+            ))
+            .as_deref(),
+            Some("abc")
+        );
+        // A process forked by an unregistered thread of a process from
+        // another container.
+        assert_eq!(
+            container(new_task_container(
+                &processes,
+                task(500, 500),
+                task(401, 400)
+            ))
+            .as_deref(),
+            Some("def")
+        );
+        // A thread executing a binary takes over the PID of the leader.
+        assert_eq!(
+            container(task_container(&processes, task(200, 200))).as_deref(),
+            Some("abc")
+        );
+    }
+}
@@ -0,0 +1,683 @@
+//! Protocol of the lockc control API, served on a unix socket. Every request
+//! and response is a single line of JSON.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ContainerPolicyLevel, Enforcement, ProcessEventKind};
+
+/// Default path of the control API socket.
+pub const CONTROL_SOCKET_PATH: &str = "/run/lockc/lockc.sock";
+
+/// Length of container IDs truncated by `crictl ps`.
+pub const SHORT_ID_LEN: usize = 13;
+
+/// Truncates the container ID the way `crictl ps` does.
+pub fn short_id(id: &str) -> &str {
+    id.get(..SHORT_ID_LEN).unwrap_or(id)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Returns digests of the loaded eBPF object and its programs.
+    Digests,
+    /// Returns containers registered by lockc.
+    Containers,
+    /// Returns registered containers matching the given ID. The ID is
+    /// matched against keys of containers in eBPF maps and IDs of containers
+    /// in the runtime, also when truncated (e.g. by `crictl ps`).
+    ResolveContainer { id: String },
+    /// Registers a container with its first process. Sent by external agents
+    /// when lockc runs without the runc watcher (`--no-watcher`). The spec
+    /// is read from the bundle, if given. Registering a container again with
+    /// the same policy only adds the process.
+    AddContainer {
+        container_id: String,
+        pid: i32,
+        policy_level: ContainerPolicyLevel,
+        #[serde(default)]
+        bundle: Option<String>,
+        #[serde(default)]
+        name: Option<String>,
+        #[serde(default)]
+        pod: Option<String>,
+        #[serde(default)]
+        namespace: Option<String>,
+        #[serde(default)]
+        image: Option<String>,
+        #[serde(default)]
+        enforcement: Enforcement,
+        /// Whether the container may read the kernel log regardless of its
+        /// policy level.
+        #[serde(default)]
+        syslog: bool,
+        /// Whether the policy of an already registered container is
+        /// replaced. Otherwise registering it with a different policy fails.
+        #[serde(default)]
+        replace: bool,
+    },
+    /// Adds a process to a registered container.
+    AddProcess { container_id: String, pid: i32 },
+    /// Deletes a registered container.
+    DeleteContainer { container_id: String },
+    /// Thaws a container frozen in response to an attack.
+    ThawContainer { container_id: String },
+    /// Switches a registered container between enforcing its policy and
+    /// the complain mode, in which denials are only reported.
+    SetEnforcement {
+        container_id: String,
+        enforcement: Enforcement,
+    },
+    /// Returns the current log filter.
+    LogFilter,
+    /// Replaces the log filter with the given `target=level` directives, e.g.
+    /// `info,lockc=debug`.
+    SetLogFilter { filter: String },
+    /// Streams events about processes of the given container, or of all
+    /// containers, until the client disconnects. Requires lockc to run in
+    /// trace mode.
+    Trace { container_id: Option<String> },
+    /// Returns bind mounts recorded in learning mode.
+    LearnedMounts,
+    /// Returns counters of invocations and decisions of LSM programs.
+    Stats,
+    /// Returns recorded changes of policies of the given container, or of
+    /// all containers, oldest first.
+    PolicyHistory { container_id: Option<String> },
+    /// Returns fingerprints of images whose reference contains the given
+    /// string, or of all images.
+    Fingerprints { image: Option<String> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum ControlResponse {
+    Digests(BpfDigests),
+    Containers { containers: Vec<ContainerInfo> },
+    LogFilter { filter: String },
+    ProcessEvent(ProcessEventInfo),
+    LearnedMounts { mounts: Vec<LearnedMountInfo> },
+    Stats { programs: Vec<ProgramStatsInfo> },
+    PolicyHistory { changes: Vec<PolicyChangeInfo> },
+    Fingerprints { images: Vec<ImageFingerprintInfo> },
+    Ok,
+    Error { message: String },
+}
+
+/// SHA-256 digests of the eBPF object loaded by lockc.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BpfDigests {
+    /// Digest of the whole ELF object.
+    pub object: String,
+    /// Path the object was loaded from, `None` if the object embedded in
+    /// lockc was used.
+    pub path: Option<String>,
+    /// Whether the signature of the object was verified with a configured
+    /// public key.
+    pub verified: bool,
+    /// Digests of the program sections.
+    pub programs: Vec<ProgramDigest>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgramDigest {
+    pub section: String,
+    pub sha256: String,
+}
+
+/// Container registered by lockc, with human-readable metadata provided by
+/// the container engine.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub name: Option<String>,
+    /// Name of the Kubernetes pod.
+    pub pod: Option<String>,
+    /// Kubernetes namespace.
+    pub namespace: Option<String>,
+    pub image: Option<String>,
+    /// ID of the container in which this container is nested.
+    pub parent: Option<String>,
+    /// Directory with the state of the container (`runc --root`).
+    #[serde(default)]
+    pub root: Option<String>,
+    /// ID of the container in the runtime, if it differs from the key of the
+    /// container in eBPF maps (`id`).
+    #[serde(default)]
+    pub runtime_id: Option<String>,
+    pub policy_level: ContainerPolicyLevel,
+    #[serde(default)]
+    pub enforcement: Enforcement,
+}
+
+impl ContainerInfo {
+    /// Returns the ID of the container in the runtime.
+    pub fn runtime_id(&self) -> &str {
+        self.runtime_id.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Returns the runtime ID truncated the way `crictl ps` does.
+    pub fn short_id(&self) -> &str {
+        short_id(self.runtime_id())
+    }
+
+    /// Returns whether the container is identified by the given ID, which
+    /// is either its key in eBPF maps or its runtime ID, possibly truncated.
+    pub fn matches(&self, id: &str) -> bool {
+        !id.is_empty() && (self.id.starts_with(id) || self.runtime_id().starts_with(id))
+    }
+}
+
+/// Fork, exec or exit of a containerized process, streamed in trace mode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessEventInfo {
+    pub kind: ProcessEventKind,
+    pub container_id: String,
+    pub pid: i32,
+    pub ppid: i32,
+    pub comm: String,
+}
+
+/// Bind mount source which containers attempted to mount, recorded in
+/// learning mode instead of being denied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LearnedMountInfo {
+    pub path: String,
+    /// Policy level of the allowed paths list which didn't allow the mount,
+    /// `restricted` (also used for offline containers) or `baseline`.
+    pub policy_level: ContainerPolicyLevel,
+    /// IDs of containers which attempted the mount.
+    pub containers: Vec<String>,
+    /// Number of attempts.
+    pub count: u64,
+}
+
+/// Counters of an LSM program since it was loaded.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgramStatsInfo {
+    pub program: String,
+    /// Whether the program is loaded into the kernel. LSM programs are
+    /// attached right after loading, cgroup programs are attached to cgroups
+    /// of containers when they are registered.
+    #[serde(default)]
+    pub loaded: bool,
+    pub invocations: u64,
+    /// Allowed operations, including violations in complain mode.
+    pub allows: u64,
+    pub denies: u64,
+}
+
+/// Origin of a change of a container policy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PolicySource {
+    /// Registration of a container created by runc, with the policy
+    /// determined from its labels, namespace or image.
+    Runtime,
+    /// Request of a control API client, identified by its credentials when
+    /// they could be read from the socket.
+    ControlApi { uid: Option<u32>, pid: Option<i32> },
+}
+
+impl std::fmt::Display for PolicySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicySource::Runtime => f.write_str("runtime"),
+            PolicySource::ControlApi { uid, pid } => {
+                f.write_str("control-api")?;
+                if let Some(uid) = uid {
+                    write!(f, " uid={}", uid)?;
+                }
+                if let Some(pid) = pid {
+                    write!(f, " pid={}", pid)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Change of the policy level or enforcement of a container, recorded for
+/// auditing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyChangeInfo {
+    /// Time of the change, RFC 3339 in UTC.
+    pub time: String,
+    pub container_id: String,
+    pub source: PolicySource,
+    /// Policy before the change, `None` when the container was registered.
+    pub old_policy_level: Option<ContainerPolicyLevel>,
+    pub old_enforcement: Option<Enforcement>,
+    pub policy_level: ContainerPolicyLevel,
+    pub enforcement: Enforcement,
+}
+
+/// Behavior of containers of an image, recorded as its fingerprint.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageFingerprintInfo {
+    pub image: String,
+    /// Number of containers of the image which were observed.
+    pub containers: u64,
+    /// Bind mount sources which allowed paths didn't allow.
+    #[serde(default)]
+    pub mounts: Vec<ObservedPathInfo>,
+    /// Opened files which allowed paths didn't allow.
+    #[serde(default)]
+    pub file_opens: Vec<ObservedPathInfo>,
+    /// Capabilities used, e.g. `CAP_NET_ADMIN`.
+    #[serde(default)]
+    pub capabilities: Vec<ObservedCountInfo>,
+    /// Violations of any kind, per hook.
+    #[serde(default)]
+    pub violations: Vec<ObservedCountInfo>,
+}
+
+/// Path which containers of an image needed, but the list of allowed paths
+/// of their policy level didn't allow.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservedPathInfo {
+    pub path: String,
+    /// Policy level of the list which didn't allow the path, `restricted`
+    /// (also used for offline containers) or `baseline`.
+    pub policy_level: ContainerPolicyLevel,
+    pub count: u64,
+}
+
+/// Number of observations of a capability or a hook.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObservedCountInfo {
+    pub name: String,
+    pub count: u64,
+}
+
+/// Names of capabilities, indexed by their numbers.
+const CAPABILITIES: &[&str] = &[
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Returns the name of the capability with the given number. Capabilities
+/// newer than lockc are named by their number, e.g. `CAP_41`.
+pub fn capability_name(capability: u32) -> String {
+    match CAPABILITIES.get(capability as usize) {
+        Some(name) => name.to_string(),
+        None => format!("CAP_{}", capability),
+    }
+}
+
+/// Capabilities which the restricted level of Pod Security Standards allows
+/// to add.
+const RESTRICTED_CAPABILITIES: &[&str] = &["CAP_NET_BIND_SERVICE"];
+
+/// Capabilities which the baseline level of Pod Security Standards allows,
+/// the default capabilities of container engines.
+const BASELINE_CAPABILITIES: &[&str] = &[
+    "CAP_AUDIT_WRITE",
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_MKNOD",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SETFCAP",
+    "CAP_SETGID",
+    "CAP_SETPCAP",
+    "CAP_SETUID",
+    "CAP_SYS_CHROOT",
+];
+
+/// Policy level suggested for an image by its fingerprint, with the
+/// observations which rule out the lower levels.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PolicySuggestion {
+    pub policy_level: ContainerPolicyLevel,
+    pub reasons: Vec<String>,
+}
+
+/// Suggests the lowest policy level which allows everything the containers
+/// of the image were observed doing. Paths not allowed by the restricted
+/// list need at least baseline, paths not allowed by the baseline list need
+/// privileged. Capabilities are ranked like in Pod Security Standards.
+pub fn suggested_policy(fingerprint: &ImageFingerprintInfo) -> PolicySuggestion {
+    let mut baseline = Vec::new();
+    let mut privileged = Vec::new();
+    let paths = fingerprint
+        .mounts
+        .iter()
+        .map(|path| ("bind mounts", path))
+        .chain(
+            fingerprint
+                .file_opens
+                .iter()
+                .map(|path| ("opens files under", path)),
+        );
+    for (operation, path) in paths {
+        let reason = format!(
+            "{} {} ({} list, {} times)",
+            operation, path.path, path.policy_level, path.count
+        );
+        match path.policy_level {
+            ContainerPolicyLevel::Baseline => privileged.push(reason),
+            _ => baseline.push(reason),
+        }
+    }
+    for capability in &fingerprint.capabilities {
+        let name = capability.name.as_str();
+        if RESTRICTED_CAPABILITIES.contains(&name) {
+            continue;
+        }
+        let reason = format!("uses {} ({} times)", name, capability.count);
+        if BASELINE_CAPABILITIES.contains(&name) {
+            baseline.push(reason);
+        } else {
+            privileged.push(reason);
+        }
+    }
+
+    if !privileged.is_empty() {
+        PolicySuggestion {
+            policy_level: ContainerPolicyLevel::Privileged,
+            reasons: privileged,
+        }
+    } else if !baseline.is_empty() {
+        PolicySuggestion {
+            policy_level: ContainerPolicyLevel::Baseline,
+            reasons: baseline,
+        }
+    } else {
+        PolicySuggestion {
+            policy_level: ContainerPolicyLevel::Restricted,
+            reasons: Vec::new(),
+        }
+    }
+}
+
+/// Returns the regular expression of an image policy rule which matches only
+/// the given image reference.
+pub fn image_pattern(image: &str) -> String {
+    let mut pattern = String::with_capacity(image.len() + 2);
+    pattern.push('^');
+    for c in image.chars() {
+        if "\\.+*?()|[]{}^$#&-~".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('$');
+    pattern
+}
+
+/// Returns paths to add to the allowed paths list of the given policy level,
+/// so all learned mounts of that level are allowed. Paths under another
+/// learned path are left out, as allowed paths are prefixes.
+pub fn suggested_paths(
+    mounts: &[LearnedMountInfo],
+    policy_level: ContainerPolicyLevel,
+) -> Vec<String> {
+    let mut paths: Vec<&str> = mounts
+        .iter()
+        .filter(|mount| mount.policy_level == policy_level)
+        .map(|mount| match mount.path.trim_end_matches('/') {
+            "" => "/",
+            path => path,
+        })
+        .collect();
+    // Shorter paths come first, so they are suggested before paths under
+    // them.
+    paths.sort_unstable_by_key(|path| (path.len(), *path));
+    paths.dedup();
+    let mut suggested: Vec<String> = Vec::new();
+    for path in paths {
+        let covered = suggested.iter().any(|prefix| {
+            prefix == "/"
+                || path
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        });
+        if !covered {
+            suggested.push(path.to_string());
+        }
+    }
+    suggested.sort_unstable();
+    suggested
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_protocol_json() {
+        let req: ControlRequest = serde_json::from_str(r#"{"request":"digests"}"#).unwrap();
+        assert!(matches!(req, ControlRequest::Digests));
+
+        let resp = ControlResponse::Error {
+            message: "oops".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&resp).unwrap(),
+            r#"{"response":"error","message":"oops"}"#
+        );
+
+        let req: ControlRequest =
+            serde_json::from_str(r#"{"request":"delete_container","container_id":"abc"}"#).unwrap();
+        assert!(matches!(
+            req,
+            ControlRequest::DeleteContainer { container_id } if container_id == "abc"
+        ));
+
+        let req: ControlRequest = serde_json::from_str(
+            r#"{"request":"add_container","container_id":"abc","pid":42,"policy_level":"baseline"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            req,
+            ControlRequest::AddContainer {
+                pid: 42,
+                policy_level: ContainerPolicyLevel::Baseline,
+                bundle: None,
+                name: None,
+                enforcement: Enforcement::Enforce,
+                syslog: false,
+                replace: false,
+                ..
+            }
+        ));
+
+        let req: ControlRequest = serde_json::from_str(
+            r#"{"request":"set_enforcement","container_id":"abc","enforcement":"complain"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            req,
+            ControlRequest::SetEnforcement {
+                enforcement: Enforcement::Complain,
+                ..
+            }
+        ));
+
+        let req: ControlRequest =
+            serde_json::from_str(r#"{"request":"policy_history","container_id":null}"#).unwrap();
+        assert!(matches!(
+            req,
+            ControlRequest::PolicyHistory { container_id: None }
+        ));
+
+        let change = PolicyChangeInfo {
+            time: "2024-02-29T12:34:56.000000005Z".to_string(),
+            container_id: "abc".to_string(),
+            source: PolicySource::ControlApi {
+                uid: Some(0),
+                pid: Some(42),
+            },
+            old_policy_level: Some(ContainerPolicyLevel::Restricted),
+            old_enforcement: Some(Enforcement::Enforce),
+            policy_level: ContainerPolicyLevel::Baseline,
+            enforcement: Enforcement::Enforce,
+        };
+        assert_eq!(
+            serde_json::to_string(&change).unwrap(),
+            r#"{"time":"2024-02-29T12:34:56.000000005Z","container_id":"abc","source":{"kind":"control_api","uid":0,"pid":42},"old_policy_level":"restricted","old_enforcement":"enforce","policy_level":"baseline","enforcement":"enforce"}"#
+        );
+        assert_eq!(change.source.to_string(), "control-api uid=0 pid=42");
+
+        let resp = ControlResponse::ProcessEvent(ProcessEventInfo {
+            kind: ProcessEventKind::Exec,
+            container_id: "abc".to_string(),
+            pid: 2,
+            ppid: 1,
+            comm: "sh".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_string(&resp).unwrap(),
+            r#"{"response":"process_event","kind":"exec","container_id":"abc","pid":2,"ppid":1,"comm":"sh"}"#
+        );
+    }
+
+    #[test]
+    fn learned_mounts_suggested_paths() {
+        let mount = |path: &str, policy_level| LearnedMountInfo {
+            path: path.to_string(),
+            policy_level,
+            containers: vec!["abc".to_string()],
+            count: 1,
+        };
+        let mounts = [
+            mount("/srv/data/cache", ContainerPolicyLevel::Baseline),
+            mount("/srv/data", ContainerPolicyLevel::Baseline),
+            mount("/srv/data-old", ContainerPolicyLevel::Baseline),
+            mount("/srv/data/", ContainerPolicyLevel::Baseline),
+            mount("/etc/ssl", ContainerPolicyLevel::Restricted),
+        ];
+        assert_eq!(
+            suggested_paths(&mounts, ContainerPolicyLevel::Baseline),
+            vec!["/srv/data", "/srv/data-old"]
+        );
+        assert_eq!(
+            suggested_paths(&mounts, ContainerPolicyLevel::Restricted),
+            vec!["/etc/ssl"]
+        );
+    }
+
+    #[test]
+    fn fingerprint_suggested_policy() {
+        let capability = |name: &str| ObservedCountInfo {
+            name: name.to_string(),
+            count: 2,
+        };
+        let mut fingerprint = ImageFingerprintInfo {
+            image: "docker.io/library/nginx:1.25".to_string(),
+            containers: 3,
+            mounts: Vec::new(),
+            file_opens: Vec::new(),
+            capabilities: vec![capability("CAP_NET_BIND_SERVICE")],
+            violations: Vec::new(),
+        };
+        assert_eq!(
+            suggested_policy(&fingerprint),
+            PolicySuggestion {
+                policy_level: ContainerPolicyLevel::Restricted,
+                reasons: Vec::new(),
+            }
+        );
+
+        fingerprint.capabilities.push(capability("CAP_CHOWN"));
+        fingerprint.mounts.push(ObservedPathInfo {
+            path: "/srv/data".to_string(),
+            policy_level: ContainerPolicyLevel::Restricted,
+            count: 1,
+        });
+        assert_eq!(
+            suggested_policy(&fingerprint),
+            PolicySuggestion {
+                policy_level: ContainerPolicyLevel::Baseline,
+                reasons: vec![
+                    "bind mounts /srv/data (restricted list, 1 times)".to_string(),
+                    "uses CAP_CHOWN (2 times)".to_string(),
+                ],
+            }
+        );
+
+        fingerprint.capabilities.push(capability("CAP_SYS_ADMIN"));
+        let suggestion = suggested_policy(&fingerprint);
+        assert_eq!(suggestion.policy_level, ContainerPolicyLevel::Privileged);
+        assert_eq!(suggestion.reasons, vec!["uses CAP_SYS_ADMIN (2 times)"]);
+
+        assert_eq!(capability_name(21), "CAP_SYS_ADMIN");
+        assert_eq!(capability_name(41), "CAP_41");
+        assert_eq!(
+            image_pattern("docker.io/library/nginx:1.25"),
+            r"^docker\.io/library/nginx:1\.25$"
+        );
+    }
+
+    #[test]
+    fn container_info_ids() {
+        let info = ContainerInfo {
+            id: "0123abcd-4f7a1c2e9b3d5f6a8c0e".to_string(),
+            name: None,
+            pod: None,
+            namespace: None,
+            image: None,
+            parent: None,
+            root: Some("/run/docker/runtime-runc/moby".to_string()),
+            runtime_id: Some("4f7a1c2e9b3d5f6a8c0e".to_string()),
+            policy_level: ContainerPolicyLevel::Baseline,
+            enforcement: Enforcement::Enforce,
+        };
+        assert_eq!(info.short_id(), "4f7a1c2e9b3d5");
+        assert!(info.matches("4f7a1c2e9b3d5"));
+        assert!(info.matches("0123abcd-4f7a"));
+        assert!(!info.matches("5f6a"));
+        assert!(!info.matches(""));
+
+        let info = ContainerInfo {
+            id: "abc".to_string(),
+            root: None,
+            runtime_id: None,
+            ..info
+        };
+        assert_eq!(info.runtime_id(), "abc");
+        assert_eq!(info.short_id(), "abc");
+    }
+}
@@ -0,0 +1,176 @@
+//! Minimal HTTP/1.1 shared by the metrics and status endpoints of lockc, its
+//! reporter and `lockc-collector`. Every connection carries one request and
+//! is closed after the response.
+
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Max size of the request line and headers.
+pub const MAX_HEADER_SIZE: u64 = 16 * 1024;
+
+/// Request line and headers of an HTTP request.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub content_length: usize,
+}
+
+impl Request {
+    /// Returns whether the request is `method` on `path`.
+    pub fn is(&self, method: &str, path: &str) -> bool {
+        self.method == method && self.path == path
+    }
+}
+
+/// Reads the request line and headers. Fails if they're larger than
+/// `MAX_HEADER_SIZE`. The body is left in the reader.
+pub async fn read_request<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> io::Result<Request> {
+    let mut head = (&mut *reader).take(MAX_HEADER_SIZE);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if head.read_line(&mut header).await? == 0 {
+            if head.limit() == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "request headers are too large",
+                ));
+            }
+            break;
+        }
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length")
+                })?;
+            }
+        }
+    }
+    Ok(Request {
+        method,
+        path,
+        content_length,
+    })
+}
+
+/// Reads the body of the request. Returns `None` if it's larger than
+/// `max_size`. The buffer grows only as the body arrives, so a client can't
+/// make the server allocate memory just by sending a large Content-Length.
+pub async fn read_body<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    request: &Request,
+    max_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    if request.content_length > max_size {
+        return Ok(None);
+    }
+    let mut body = Vec::new();
+    (&mut *reader)
+        .take(request.content_length as u64)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() < request.content_length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "request body is truncated",
+        ));
+    }
+    Ok(Some(body))
+}
+
+/// Writes a response and lets the client close the connection.
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes()).await?;
+    writer.flush().await
+}
+
+/// Posts a JSON body to `path` on `host` and returns the status line of the
+/// response.
+pub async fn post<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    path: &str,
+    body: &[u8],
+) -> io::Result<String> {
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).await?;
+    Ok(status_line.trim_end().to_string())
+}
+
+/// Converts an elapsed timeout of a client into an I/O error.
+pub fn timed_out(_: tokio::time::error::Elapsed) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "client timed out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn http_read_request() {
+        let input = b"POST /report HTTP/1.1\r\nHost: localhost\r\ncontent-length: 2\r\n\r\n{}";
+        let mut reader = BufReader::new(&input[..]);
+        let request = read_request(&mut reader).await.unwrap();
+        assert!(request.is("POST", "/report"));
+        assert_eq!(request.content_length, 2);
+        assert_eq!(
+            read_body(&mut reader, &request, 2).await.unwrap().unwrap(),
+            b"{}"
+        );
+
+        let mut input = b"GET /metrics HTTP/1.1\r\nX-Padding: ".to_vec();
+        input.resize(input.len() + MAX_HEADER_SIZE as usize, b'a');
+        let err = read_request(&mut BufReader::new(&input[..]))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn http_read_body() {
+        let input = b"POST /report HTTP/1.1\r\nContent-Length: 1073741824\r\n\r\n{}";
+        let mut reader = BufReader::new(&input[..]);
+        let request = read_request(&mut reader).await.unwrap();
+        assert!(read_body(&mut reader, &request, 1024)
+            .await
+            .unwrap()
+            .is_none());
+        let err = read_body(&mut reader, &request, usize::MAX)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,26 +1,92 @@
 #![cfg_attr(not(feature = "user"), no_std)]
 
 /// Max configurable PID limit (for x86_64, for the other architectures it's
-/// less or equal).
-// TODO(vadorovsky): I need to teach aya to be able to resize maps before they
-// are loaded into the kernel. So far aya doesn't differentiate between open()
-// and load(), it opens the ELF object and loads it immediately in one step.
-// I need to change it.
-// After that, we will be able to set the limit again up to the upper possible
-// limit. And resize according to the max PID limit in sysctl.
-// Before it's done - let's stick to the default value to not use too much RAM.
-// pub const PID_MAX_LIMIT: u32 = 4194304;
-pub const PID_MAX_LIMIT: u32 = 32768;
+/// less or equal). eBPF maps keyed by PIDs are declared with that size, but
+/// userspace resizes them to the value of `kernel.pid_max` sysctl before
+/// loading, to not waste memory.
+pub const PID_MAX_LIMIT: u32 = 4194304;
+
+/// Default value of `kernel.pid_max` sysctl, used when it cannot be read.
+pub const PID_MAX_DEFAULT: u32 = 32768;
+
+/// eBPF maps which store an entry per process (or per container, which is
+/// never more than the number of processes). Their size has to match the
+/// `kernel.pid_max` sysctl. They hold the whole state of containers and
+/// processes, which is handed over to a new instance on upgrade.
+pub const PID_MAPS: &[&str] = &[
+    "CONTAINERS",
+    "PROCESSES",
+    "CONTAINER_SPECS",
+    "CUSTOM_MOUNTS",
+    "CONTAINER_CGROUPS",
+    "CONTAINER_INITIAL_SETUID",
+];
+
+/// Returns the size of [`PID_MAPS`] for the given `kernel.pid_max` value,
+/// never exceeding the size declared in the eBPF object.
+pub fn pid_map_size(pid_max: i32) -> u32 {
+    match u32::try_from(pid_max) {
+        Ok(pid_max) if pid_max > 0 => pid_max.min(PID_MAX_LIMIT),
+        _ => PID_MAX_DEFAULT,
+    }
+}
 
 pub const MOUNT_TYPE_LEN: usize = 5;
 
 pub const PATH_LEN: usize = 64;
 
+/// Max number of path prefixes of each [`PathClass`] in the eBPF map with
+/// allowed and denied paths.
+pub const PATH_MAX_LIMIT: u32 = 128;
+
 const CONTAINER_ID_LEN: usize = 64;
 
-#[cfg_attr(feature = "user", derive(Debug))]
+/// Max number of excluded command names, and of excluded executables, in
+/// eBPF maps of processes which are never attributed to containers.
+pub const EXCLUDED_MAX: u32 = 64;
+
+/// Max number of inodes of lockc's pinned eBPF objects in the eBPF map of
+/// inodes which containers can't access.
+pub const PROTECTED_MAX: u32 = 64;
+
+/// Max number of inodes of masked paths of procfs in the eBPF map of paths
+/// which containers can't unmask.
+pub const MASKED_PROC_MAX: u32 = 32;
+
+/// Max number of containers and hooks tracked by the rate limit of
+/// [`Violation`] events. Least recently used entries are evicted, so deleted
+/// containers don't have to be cleaned up.
+pub const VIOLATION_LIMITS_MAX: u32 = 4096;
+
+/// Number of [`Violation`] events per second which each CPU sends about the
+/// same container and hook. Violations above the rate are counted and the
+/// count is sent with the next event.
+pub const VIOLATION_RATE: u64 = 10;
+
+/// Max number of host paths which a custom policy allows to bind mount.
+pub const CUSTOM_MOUNTS_MAX: usize = 8;
+
+/// Max number of networks of each IP version in eBPF maps of networks which
+/// restricted containers can't connect to.
+pub const EGRESS_DENY_MAX: u32 = 256;
+
+/// Number of [`Violation`] events about the same container and hook which
+/// each CPU can send at once, before the rate applies.
+pub const VIOLATION_BURST: u64 = 20;
+
+pub mod attribution;
+#[cfg(feature = "user")]
+pub mod control;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "user")]
+pub mod report;
+pub mod verdict;
+
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "user", serde(rename_all = "lowercase"))]
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 #[repr(C)]
 pub enum ContainerPolicyLevel {
     NotFound = -1,
@@ -34,6 +100,18 @@ pub enum ContainerPolicyLevel {
     Privileged,
 }
 
+impl ContainerPolicyLevel {
+    /// Returns the bit of the policy level in sets of policy levels stored
+    /// in eBPF maps. Internal levels have no bit.
+    #[inline(always)]
+    pub fn bit(&self) -> u8 {
+        match self {
+            ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc => 0,
+            level => 1 << (*level as u8),
+        }
+    }
+}
+
 #[cfg(feature = "user")]
 impl std::fmt::Display for ContainerPolicyLevel {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -48,6 +126,28 @@ impl std::fmt::Display for ContainerPolicyLevel {
     }
 }
 
+#[cfg(feature = "user")]
+#[derive(thiserror::Error, Debug)]
+#[error("unknown policy level: {0}")]
+pub struct ParsePolicyLevelError(String);
+
+#[cfg(feature = "user")]
+impl std::str::FromStr for ContainerPolicyLevel {
+    type Err = ParsePolicyLevelError;
+
+    /// Parses a policy level from the value of a label or setting. Only the
+    /// levels which users are allowed to assign to containers are accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restricted" => Ok(ContainerPolicyLevel::Restricted),
+            "offline" => Ok(ContainerPolicyLevel::Offline),
+            "baseline" => Ok(ContainerPolicyLevel::Baseline),
+            "privileged" => Ok(ContainerPolicyLevel::Privileged),
+            _ => Err(ParsePolicyLevelError(s.to_owned())),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct ContainerID {
@@ -112,10 +212,87 @@ impl ContainerID {
     }
 }
 
+/// Whether denials of the policy are enforced in a container. In the
+/// complain mode, operations which the policy denies are only logged and
+/// reported as violations.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "user", serde(rename_all = "lowercase"))]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Enforcement {
+    #[default]
+    Enforce,
+    Complain,
+}
+
+impl Enforcement {
+    /// Converts the raw value stored in eBPF maps and events. Unknown values
+    /// are enforced.
+    #[inline(always)]
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Enforcement::Complain,
+            _ => Enforcement::Enforce,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for Enforcement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Enforcement::Enforce => write!(f, "enforce"),
+            Enforcement::Complain => write!(f, "complain"),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+#[derive(thiserror::Error, Debug)]
+#[error("unknown enforcement mode: {0}")]
+pub struct ParseEnforcementError(String);
+
+#[cfg(feature = "user")]
+impl std::str::FromStr for Enforcement {
+    type Err = ParseEnforcementError;
+
+    /// Parses an enforcement mode from the value of a label or annotation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enforce" => Ok(Enforcement::Enforce),
+            "complain" => Ok(Enforcement::Complain),
+            _ => Err(ParseEnforcementError(s.to_owned())),
+        }
+    }
+}
+
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Container {
     pub policy_level: ContainerPolicyLevel,
+    /// Consulted by eBPF programs before denying an operation.
+    #[cfg_attr(feature = "user", serde(default))]
+    pub enforcement: Enforcement,
+    /// Whether the container may read the kernel log regardless of its
+    /// policy level, e.g. node-problem-detector or a log collector.
+    #[cfg_attr(feature = "user", serde(default))]
+    pub syslog: bool,
+    #[cfg_attr(feature = "user", serde(skip))]
+    _padding: [u8; 2],
+}
+
+impl Container {
+    #[inline(always)]
+    pub fn new(policy_level: ContainerPolicyLevel, enforcement: Enforcement) -> Self {
+        Container {
+            policy_level,
+            enforcement,
+            syslog: false,
+            _padding: [0; 2],
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -124,23 +301,1078 @@ pub struct Process {
     pub container_id: ContainerID,
 }
 
+/// Max number of user namespace ID mappings stored for a container.
+pub const ID_MAPPINGS_MAX: usize = 4;
+
+/// User namespace ID mapping from the OCI runtime spec. Entries with `size`
+/// 0 are unused.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct IdMapping {
+    pub container_id: u32,
+    pub host_id: u32,
+    pub size: u32,
+}
+
+/// Data from the OCI runtime spec (config.json) of a container, stored when
+/// the container is registered, for decisions which need more than the
+/// policy level.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct ContainerSpec {
+    pub uid_mappings: [IdMapping; ID_MAPPINGS_MAX],
+    pub gid_mappings: [IdMapping; ID_MAPPINGS_MAX],
+    /// Inode of the root filesystem of the container. Zeroed if it could not
+    /// be determined.
+    pub rootfs: InodeId,
+    /// Whether the container runs in its own user namespace.
+    pub user_namespace: bool,
+    /// Whether the container has `CAP_SYS_ADMIN` in its bounding set, which
+    /// is the case for privileged containers.
+    pub privileged: bool,
+    /// Whether the container runs in its own user namespace which doesn't
+    /// map root of the container to root of the host, so processes in the
+    /// container are unprivileged on the host. Takes the former padding, so
+    /// specs stored by older versions read as not rootless.
+    pub rootless: bool,
+    /// Whether the container is allowed to connect to sockets under denied
+    /// paths, e.g. sockets of container runtimes.
+    pub runtime_sockets: bool,
+    _padding: [u8; 4],
+}
+
+/// Host paths which the custom policy of a container allows to bind mount on
+/// top of its policy level, stored when the container is registered. Each
+/// path allows everything under it. Unused entries are zeroed.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct CustomMounts {
+    pub paths: [[u8; PATH_LEN]; CUSTOM_MOUNTS_MAX],
+}
+
+impl CustomMounts {
+    /// Returns whether `path`, terminated by a zero byte, is one of the
+    /// allowed paths or under one of them.
+    #[inline(always)]
+    pub fn allows(&self, path: &[u8; PATH_LEN]) -> bool {
+        for allowed in &self.paths {
+            if allowed[0] == 0 {
+                return false;
+            }
+            let mut i = 0;
+            while i < PATH_LEN {
+                if allowed[i] == 0 {
+                    if path[i] == 0 || path[i] == b'/' || allowed[i - 1] == b'/' {
+                        return true;
+                    }
+                    break;
+                }
+                if allowed[i] != path[i] {
+                    break;
+                }
+                i += 1;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(feature = "user")]
+impl CustomMounts {
+    /// Converts the given absolute paths. Trailing slashes are ignored.
+    pub fn new<S: AsRef<str>>(paths: &[S]) -> Result<Self, CustomMountsError> {
+        if paths.len() > CUSTOM_MOUNTS_MAX {
+            return Err(CustomMountsError::TooMany(paths.len()));
+        }
+        let mut custom_mounts = CustomMounts {
+            paths: [[0; PATH_LEN]; CUSTOM_MOUNTS_MAX],
+        };
+        for (buf, path) in custom_mounts.paths.iter_mut().zip(paths) {
+            let path = path.as_ref();
+            let trimmed = match path.trim_end_matches('/') {
+                "" => "/",
+                trimmed => trimmed,
+            };
+            if trimmed.len() >= PATH_LEN {
+                return Err(CustomMountsError::PathTooLong(PathTooLongError(
+                    path.to_owned(),
+                )));
+            }
+            buf[..trimmed.len()].copy_from_slice(trimmed.as_bytes());
+        }
+        Ok(custom_mounts)
+    }
+}
+
+#[cfg(feature = "user")]
+#[derive(thiserror::Error, Debug)]
+pub enum CustomMountsError {
+    #[error("{0} allowed mounts, at most {} are supported", CUSTOM_MOUNTS_MAX)]
+    TooMany(usize),
+
+    #[error(transparent)]
+    PathTooLong(#[from] PathTooLongError),
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct MountType {
     pub mount_type: [u8; MOUNT_TYPE_LEN],
 }
 
+/// Class of path prefixes, stored as the first byte of [`PathPrefix`] keys,
+/// so prefixes for all operations and policy levels share one LPM trie map.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PathClass {
+    MountRestricted = 1,
+    MountBaseline,
+    AccessRestricted,
+    AccessBaseline,
+}
+
+/// Key of the LPM trie map with path prefixes. The class byte comes first,
+/// so a lookup only matches prefixes of the same class.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct PathPrefix {
+    pub class: u8,
+    pub path: [u8; PATH_LEN],
+}
+
+impl PathPrefix {
+    /// Returns the length (in bits, as LPM trie expects) of a key with the
+    /// class byte and `len` bytes of a path.
+    #[inline(always)]
+    pub const fn prefix_len(len: usize) -> u32 {
+        ((1 + len) * 8) as u32
+    }
+}
+
+#[cfg(feature = "user")]
+#[derive(thiserror::Error, Debug)]
+#[error("path {0} has to be shorter than {} bytes", PATH_LEN)]
+pub struct PathTooLongError(String);
+
+#[cfg(feature = "user")]
+impl PathPrefix {
+    /// Converts the given path into a key of the given class.
+    pub fn new(class: PathClass, path: &str) -> Result<Self, PathTooLongError> {
+        if path.len() >= PATH_LEN {
+            return Err(PathTooLongError(path.to_owned()));
+        }
+        let mut buf = [0; PATH_LEN];
+        buf[..path.len()].copy_from_slice(path.as_bytes());
+        Ok(PathPrefix {
+            class: class as u8,
+            path: buf,
+        })
+    }
+
+    /// Returns the LPM trie key for the path, matching only the bytes of
+    /// the path and not the trailing zeros.
+    pub fn key(
+        class: PathClass,
+        path: &str,
+    ) -> Result<aya::maps::lpm_trie::Key<Self>, PathTooLongError> {
+        Ok(aya::maps::lpm_trie::Key::new(
+            Self::prefix_len(path.len()),
+            Self::new(class, path)?,
+        ))
+    }
+}
+
+/// Unique identifier of an inode, used as a key in inode-based eBPF maps.
+///
+/// `s_dev` is the device of the filesystem (`i_sb->s_dev`), not `i_rdev` of
+/// the inode, which is set only for device files. It's a `dev_t` (u32) in the
+/// kernel, but it's stored as u64, so the struct has no padding bytes which
+/// could make identical keys differ.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct InodeId {
+    pub i_ino: u64,
+    pub s_dev: u64,
+}
+
+/// Max number of parent directories checked when looking for inodes of path
+/// prefixes which a file is under.
+pub const INODE_WALK_DEPTH: u32 = 32;
+
+/// Key of the eBPF map with inodes of path prefixes. Paths differ between
+/// bind mounts of the same directory, inodes don't.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct InodePrefix {
+    pub inode: InodeId,
+    pub class: u8,
+    _padding: [u8; 7],
+}
+
+impl InodePrefix {
+    #[inline(always)]
+    pub fn new(class: PathClass, inode: InodeId) -> Self {
+        InodePrefix {
+            inode,
+            class: class as u8,
+            _padding: [0; 7],
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl InodeId {
+    /// Returns the identifier of the inode the metadata belongs to. Device
+    /// numbers are converted from the userspace encoding (`st_dev`) to the
+    /// one used by the kernel internally.
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+
+        let dev = metadata.dev();
+        let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+        let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+        InodeId {
+            i_ino: metadata.ino(),
+            s_dev: (major << 20) | minor,
+        }
+    }
+}
+
+/// Permission granted to containers for the given file or directory.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "user", serde(rename_all = "lowercase"))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum FilePermission {
+    Deny,
+    Allow,
+}
+
+/// Response to opening a file under a denied path, configured per path.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "user", serde(rename_all = "lowercase"))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum DenyResponse {
+    /// Deny the operation.
+    Deny,
+    /// Deny the operation and kill the process with `SIGKILL`.
+    Kill,
+    /// Allow the operation, but report it as a violation.
+    Audit,
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for DenyResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DenyResponse::Deny => write!(f, "deny"),
+            DenyResponse::Kill => write!(f, "kill"),
+            DenyResponse::Audit => write!(f, "audit"),
+        }
+    }
+}
+
+/// Information about an inode stored as a value in inode-based eBPF maps.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct InodeInfo {
+    pub permission: FilePermission,
+}
+
+/// LSM hook which denied an operation, reported in [`Violation`] events.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "user", serde(rename_all = "snake_case"))]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Hook {
+    Syslog = 1,
+    SbMount,
+    TaskFixSetuid,
+    FileOpen,
+    UnixStreamConnect,
+    InodePermission,
+    Connect,
+    FileReceive,
+    MoveMount,
+    /// Not an LSM hook. Unmounting or mounting over masked paths of procfs
+    /// is reported by programs of both `sb_mount` and `sb_umount`.
+    ProcMasking,
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hook::Syslog => write!(f, "syslog"),
+            Hook::SbMount => write!(f, "sb_mount"),
+            Hook::TaskFixSetuid => write!(f, "task_fix_setuid"),
+            Hook::FileOpen => write!(f, "file_open"),
+            Hook::UnixStreamConnect => write!(f, "unix_stream_connect"),
+            Hook::InodePermission => write!(f, "inode_permission"),
+            Hook::Connect => write!(f, "connect"),
+            Hook::FileReceive => write!(f, "file_receive"),
+            Hook::MoveMount => write!(f, "move_mount"),
+            Hook::ProcMasking => write!(f, "proc_masking"),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl TryFrom<u8> for Hook {
+    type Error = u8;
+
+    fn try_from(hook: u8) -> Result<Self, Self::Error> {
+        match hook {
+            1 => Ok(Hook::Syslog),
+            2 => Ok(Hook::SbMount),
+            3 => Ok(Hook::TaskFixSetuid),
+            4 => Ok(Hook::FileOpen),
+            5 => Ok(Hook::UnixStreamConnect),
+            6 => Ok(Hook::InodePermission),
+            7 => Ok(Hook::Connect),
+            8 => Ok(Hook::FileReceive),
+            9 => Ok(Hook::MoveMount),
+            10 => Ok(Hook::ProcMasking),
+            _ => Err(hook),
+        }
+    }
+}
+
+/// Event sent by eBPF programs to userspace every time an operation in a
+/// container is denied.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Violation {
+    pub container_id: ContainerID,
+    /// PID (TGID) of the process which attempted the operation.
+    pub pid: u32,
+    /// [`Hook`] which denied the operation, stored as a raw value, so
+    /// userspace doesn't trust the kernel with a valid discriminant.
+    pub hook: u8,
+    /// [`Enforcement`] of the container, stored as a raw value. Operations
+    /// in containers in the complain mode were allowed.
+    pub enforcement: u8,
+    /// Whether the process was killed, because of the [`DenyResponse`] of
+    /// the denied path.
+    pub killed: u8,
+    _padding: u8,
+    /// Number of violations in the same container and hook which were not
+    /// sent since the previous event, because of the rate limit.
+    pub suppressed: u32,
+    /// Nul-terminated path or mount source the operation was denied on, if
+    /// any.
+    pub detail: [u8; PATH_LEN],
+}
+
+#[cfg(not(feature = "user"))]
+impl Violation {
+    /// Prepares the event for the given container and hook. The detail is
+    /// cleared.
+    #[inline(always)]
+    pub fn set(
+        &mut self,
+        container_id: ContainerID,
+        pid: u32,
+        hook: Hook,
+        enforcement: Enforcement,
+        killed: bool,
+        suppressed: u32,
+    ) {
+        self.container_id = container_id;
+        self.pid = pid;
+        self.hook = hook as u8;
+        self.enforcement = enforcement as u8;
+        self.killed = killed as u8;
+        self.suppressed = suppressed;
+        self.detail[0] = 0;
+    }
+}
+
+/// Key of the rate limit of [`Violation`] events.
 #[derive(Copy, Clone)]
 #[repr(C)]
-pub struct Path {
+pub struct ViolationKey {
+    pub container_id: ContainerID,
+    pub hook: u8,
+    _padding: [u8; 7],
+}
+
+#[cfg(not(feature = "user"))]
+impl ViolationKey {
+    #[inline(always)]
+    pub fn new(container_id: ContainerID, hook: Hook) -> Self {
+        ViolationKey {
+            container_id,
+            hook: hook as u8,
+            _padding: [0; 7],
+        }
+    }
+}
+
+/// Token bucket of the rate limit of [`Violation`] events. Tokens are kept
+/// in nanoseconds, an event costs `1s / VIOLATION_RATE`.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ViolationLimit {
+    pub tokens: u64,
+    /// Time of the last refill, in nanoseconds since boot.
+    pub last: u64,
+    /// Number of violations not sent since the last event.
+    pub suppressed: u32,
+    _padding: [u8; 4],
+}
+
+impl ViolationLimit {
+    /// Cost of one event in tokens.
+    const COST: u64 = 1_000_000_000 / VIOLATION_RATE;
+
+    /// Creates a full bucket, after taking the token of the first event.
+    #[inline(always)]
+    pub fn new(now: u64) -> Self {
+        ViolationLimit {
+            tokens: (VIOLATION_BURST - 1) * Self::COST,
+            last: now,
+            suppressed: 0,
+            _padding: [0; 4],
+        }
+    }
+
+    /// Refills the bucket and takes a token. Returns the number of
+    /// violations suppressed since the last event if the event can be sent,
+    /// `None` otherwise.
+    #[inline(always)]
+    pub fn take(&mut self, now: u64) -> Option<u32> {
+        let elapsed = now.saturating_sub(self.last);
+        self.tokens = (self.tokens.saturating_add(elapsed)).min(VIOLATION_BURST * Self::COST);
+        self.last = now;
+        if self.tokens < Self::COST {
+            self.suppressed = self.suppressed.saturating_add(1);
+            return None;
+        }
+        self.tokens -= Self::COST;
+        let suppressed = self.suppressed;
+        self.suppressed = 0;
+        Some(suppressed)
+    }
+}
+
+#[cfg(feature = "user")]
+impl Violation {
+    /// Returns the hook, or `None` if the event comes from a different
+    /// version of eBPF programs.
+    pub fn hook(&self) -> Option<Hook> {
+        Hook::try_from(self.hook).ok()
+    }
+
+    pub fn enforcement(&self) -> Enforcement {
+        Enforcement::from_u8(self.enforcement)
+    }
+
+    pub fn killed(&self) -> bool {
+        self.killed != 0
+    }
+
+    /// Returns the detail up to the first nul byte, if not empty.
+    pub fn detail(&self) -> Option<String> {
+        let len = self.detail.iter().position(|b| *b == 0).unwrap_or(PATH_LEN);
+        (len > 0).then(|| String::from_utf8_lossy(&self.detail[..len]).into_owned())
+    }
+}
+
+/// Map operations in eBPF programs whose failures are counted, used as
+/// indexes of the error counter map.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum MapOperation {
+    /// Registration of a new process of a container.
+    ProcessInsert,
+    /// Registration of the first setuid call in a container.
+    InitialSetuidInsert,
+}
+
+/// Number of [`MapOperation`] variants.
+pub const MAP_OPERATIONS_LEN: u32 = 2;
+
+#[cfg(feature = "user")]
+impl MapOperation {
+    pub const ALL: [MapOperation; MAP_OPERATIONS_LEN as usize] = [
+        MapOperation::ProcessInsert,
+        MapOperation::InitialSetuidInsert,
+    ];
+
+    /// Name of the operation used in logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapOperation::ProcessInsert => "process_insert",
+            MapOperation::InitialSetuidInsert => "initial_setuid_insert",
+        }
+    }
+}
+
+/// Event sent by eBPF programs when a map operation fails. It's sent only
+/// when the per-CPU error count reaches a power of two, so a full map
+/// doesn't flood userspace.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct MapErrorEvent {
+    /// [`MapOperation`] which failed, stored as a raw value.
+    pub operation: u32,
+    /// Error returned by the eBPF helper (negative errno).
+    pub error: i32,
+    /// Number of failures of the operation on the CPU so far.
+    pub count: u64,
+}
+
+#[cfg(feature = "user")]
+impl MapErrorEvent {
+    /// Returns the operation, or `None` if the event comes from a different
+    /// version of eBPF programs.
+    pub fn operation(&self) -> Option<MapOperation> {
+        MapOperation::ALL.get(self.operation as usize).copied()
+    }
+}
+
+/// LSM programs whose activity is counted, used as indexes of the statistics
+/// map.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Program {
+    Syslog,
+    SbMount,
+    TaskFixSetuid,
+    UnixStreamConnect,
+    FileOpen,
+    SocketSendmsg,
+    SocketRecvmsg,
+    InodePermission,
+    Connect4,
+    Connect6,
+    FileReceive,
+    MoveMount,
+    SbMountProc,
+    SbUmount,
+    Capable,
+}
+
+/// Number of [`Program`] variants.
+pub const PROGRAMS_LEN: u32 = 15;
+
+#[cfg(feature = "user")]
+impl Program {
+    pub const ALL: [Program; PROGRAMS_LEN as usize] = [
+        Program::Syslog,
+        Program::SbMount,
+        Program::TaskFixSetuid,
+        Program::UnixStreamConnect,
+        Program::FileOpen,
+        Program::SocketSendmsg,
+        Program::SocketRecvmsg,
+        Program::InodePermission,
+        Program::Connect4,
+        Program::Connect6,
+        Program::FileReceive,
+        Program::MoveMount,
+        Program::SbMountProc,
+        Program::SbUmount,
+        Program::Capable,
+    ];
+
+    /// Name of the program used in metrics and `lockctl stats`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Program::Syslog => "syslog",
+            Program::SbMount => "sb_mount",
+            Program::TaskFixSetuid => "task_fix_setuid",
+            Program::UnixStreamConnect => "unix_stream_connect",
+            Program::FileOpen => "file_open",
+            Program::SocketSendmsg => "socket_sendmsg",
+            Program::SocketRecvmsg => "socket_recvmsg",
+            Program::InodePermission => "inode_permission",
+            Program::Connect4 => "connect4",
+            Program::Connect6 => "connect6",
+            Program::FileReceive => "file_receive",
+            Program::MoveMount => "move_mount",
+            Program::SbMountProc => "sb_mount_proc",
+            Program::SbUmount => "sb_umount",
+            Program::Capable => "capable",
+        }
+    }
+}
+
+/// Per-CPU counters of an LSM program. Operations allowed only because the
+/// container is in the complain mode are counted as allowed.
+#[cfg_attr(feature = "user", derive(Debug, PartialEq, Eq))]
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct ProgramStats {
+    pub invocations: u64,
+    pub allows: u64,
+    pub denies: u64,
+}
+
+#[cfg(feature = "user")]
+impl core::ops::Add for ProgramStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        ProgramStats {
+            invocations: self.invocations + other.invocations,
+            allows: self.allows + other.allows,
+            denies: self.denies + other.denies,
+        }
+    }
+}
+
+/// Max number of distinct bind mounts recorded in learning mode between two
+/// collections by userspace.
+pub const LEARNED_MOUNTS_MAX: u32 = 1024;
+
+/// Key of the eBPF map with bind mounts which would be denied, recorded in
+/// learning mode instead of being denied. The value is the number of
+/// attempts.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct LearnedMount {
+    pub container_id: ContainerID,
+    /// [`PathClass`] of the list which didn't allow the mount, stored as a
+    /// raw value.
+    pub class: u8,
+    _padding: [u8; 7],
+    /// Nul-terminated mount source. Bytes after the nul byte are zeroed, so
+    /// the same source always makes the same key.
     pub path: [u8; PATH_LEN],
 }
 
+#[cfg(not(feature = "user"))]
+impl LearnedMount {
+    /// Prepares the key for the given container and class. The path is
+    /// cleared.
+    #[inline(always)]
+    pub fn set(&mut self, container_id: ContainerID, class: PathClass) {
+        self.container_id = container_id;
+        self.class = class as u8;
+        self._padding = [0; 7];
+        self.path = [0; PATH_LEN];
+    }
+}
+
+#[cfg(feature = "user")]
+impl LearnedMount {
+    pub fn new(
+        container_id: ContainerID,
+        class: PathClass,
+        path: &str,
+    ) -> Result<Self, PathTooLongError> {
+        let prefix = PathPrefix::new(class, path)?;
+        Ok(LearnedMount {
+            container_id,
+            class: class as u8,
+            _padding: [0; 7],
+            path: prefix.path,
+        })
+    }
+
+    /// Returns the class, or `None` if the key comes from a different
+    /// version of eBPF programs.
+    pub fn class(&self) -> Option<PathClass> {
+        match self.class {
+            1 => Some(PathClass::MountRestricted),
+            2 => Some(PathClass::MountBaseline),
+            _ => None,
+        }
+    }
+
+    /// Returns the mount source up to the first nul byte.
+    pub fn path(&self) -> String {
+        let len = self.path.iter().position(|b| *b == 0).unwrap_or(PATH_LEN);
+        String::from_utf8_lossy(&self.path[..len]).into_owned()
+    }
+}
+
+/// Max number of distinct capabilities used by containers recorded for
+/// workload fingerprints between two collections by userspace.
+pub const LEARNED_CAPABILITIES_MAX: u32 = 4096;
+
+/// Key of the eBPF map with capabilities used by containers, recorded for
+/// workload fingerprints. The value is the number of uses.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct LearnedCapability {
+    pub container_id: ContainerID,
+    /// Number of the capability, e.g. 21 for `CAP_SYS_ADMIN`.
+    pub capability: u32,
+}
+
+/// Length of the command name of a task, including the nul byte.
+pub const TASK_COMM_LEN: usize = 16;
+
+/// Hashes the command name of a task with 32-bit FNV-1a. Only the part which
+/// fits in `task_struct` (up to `TASK_COMM_LEN - 1` bytes, ending with the
+/// first nul byte) is hashed, so names of binaries in the settings and command
+/// names seen by eBPF programs have the same hash.
+#[inline(always)]
+pub fn comm_hash(comm: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for i in 0..TASK_COMM_LEN - 1 {
+        let b = match comm.get(i) {
+            Some(b) if *b != 0 => *b,
+            _ => break,
+        };
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/// Kind of a [`ProcessEvent`], matching the sched tracepoint which sent it.
+#[cfg_attr(feature = "user", derive(Debug, serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "user", serde(rename_all = "snake_case"))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProcessEventKind {
+    Fork = 1,
+    Exec,
+    Exit,
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for ProcessEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProcessEventKind::Fork => write!(f, "fork"),
+            ProcessEventKind::Exec => write!(f, "exec"),
+            ProcessEventKind::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl TryFrom<u8> for ProcessEventKind {
+    type Error = u8;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(ProcessEventKind::Fork),
+            2 => Ok(ProcessEventKind::Exec),
+            3 => Ok(ProcessEventKind::Exit),
+            _ => Err(kind),
+        }
+    }
+}
+
+/// Event about a containerized process, sent by the sched tracepoints when
+/// lockc runs in trace mode.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct ProcessEvent {
+    pub container_id: ContainerID,
+    pub pid: i32,
+    /// PID of the parent, or of the forking process for fork events.
+    pub ppid: i32,
+    /// [`ProcessEventKind`] stored as a raw value.
+    pub kind: u8,
+    _padding: [u8; 3],
+    /// Nul-terminated command name of the process.
+    pub comm: [u8; TASK_COMM_LEN],
+}
+
+#[cfg(not(feature = "user"))]
+impl ProcessEvent {
+    #[inline(always)]
+    pub fn new(
+        kind: ProcessEventKind,
+        container_id: ContainerID,
+        pid: i32,
+        ppid: i32,
+        comm: [u8; TASK_COMM_LEN],
+    ) -> Self {
+        ProcessEvent {
+            container_id,
+            pid,
+            ppid,
+            kind: kind as u8,
+            _padding: [0; 3],
+            comm,
+        }
+    }
+}
+
+#[cfg(feature = "user")]
+impl ProcessEvent {
+    /// Returns the kind, or `None` if the event comes from a different
+    /// version of eBPF programs.
+    pub fn kind(&self) -> Option<ProcessEventKind> {
+        ProcessEventKind::try_from(self.kind).ok()
+    }
+
+    /// Returns the command name up to the first nul byte.
+    pub fn comm(&self) -> String {
+        let len = self
+            .comm
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(TASK_COMM_LEN);
+        String::from_utf8_lossy(&self.comm[..len]).into_owned()
+    }
+}
+
 #[cfg(feature = "user")]
 mod user {
     use super::*;
 
+    unsafe impl aya::Pod for ContainerPolicyLevel {}
     unsafe impl aya::Pod for ContainerID {}
     unsafe impl aya::Pod for Container {}
     unsafe impl aya::Pod for Process {}
+    unsafe impl aya::Pod for ContainerSpec {}
+    unsafe impl aya::Pod for CustomMounts {}
+    unsafe impl aya::Pod for PathPrefix {}
+    unsafe impl aya::Pod for InodeId {}
+    unsafe impl aya::Pod for InodePrefix {}
+    unsafe impl aya::Pod for FilePermission {}
+    unsafe impl aya::Pod for InodeInfo {}
+    unsafe impl aya::Pod for DenyResponse {}
+    unsafe impl aya::Pod for Violation {}
+    unsafe impl aya::Pod for MapErrorEvent {}
+    unsafe impl aya::Pod for ProgramStats {}
+    unsafe impl aya::Pod for LearnedMount {}
+    unsafe impl aya::Pod for LearnedCapability {}
+    unsafe impl aya::Pod for ProcessEvent {}
+}
+
+#[cfg(all(test, feature = "user"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_map_size_from_pid_max() {
+        assert_eq!(pid_map_size(4096), 4096);
+        assert_eq!(pid_map_size(32768), 32768);
+        assert_eq!(pid_map_size(i32::MAX), PID_MAX_LIMIT);
+        assert_eq!(pid_map_size(0), PID_MAX_DEFAULT);
+        assert_eq!(pid_map_size(-1), PID_MAX_DEFAULT);
+    }
+
+    #[test]
+    fn custom_mounts_allows() {
+        fn path(path: &str) -> [u8; PATH_LEN] {
+            let mut buf = [0; PATH_LEN];
+            buf[..path.len()].copy_from_slice(path.as_bytes());
+            buf
+        }
+
+        let custom_mounts = CustomMounts::new(&["/var/lib/gpu/", "/dev/nvidia0"]).unwrap();
+        assert!(custom_mounts.allows(&path("/var/lib/gpu")));
+        assert!(custom_mounts.allows(&path("/var/lib/gpu/driver")));
+        assert!(custom_mounts.allows(&path("/dev/nvidia0")));
+        assert!(!custom_mounts.allows(&path("/var/lib/gpudata")));
+        assert!(!custom_mounts.allows(&path("/var/lib")));
+        assert!(!custom_mounts.allows(&path("/dev/nvidia1")));
+
+        assert!(CustomMounts::new(&["/"]).unwrap().allows(&path("/etc")));
+        assert!(!CustomMounts::new::<&str>(&[])
+            .unwrap()
+            .allows(&path("/etc")));
+        assert!(matches!(
+            CustomMounts::new(&["/"; CUSTOM_MOUNTS_MAX + 1]),
+            Err(CustomMountsError::TooMany(_))
+        ));
+        assert!(matches!(
+            CustomMounts::new(&["/a".repeat(PATH_LEN)]),
+            Err(CustomMountsError::PathTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn policy_level_from_str() {
+        assert_eq!(
+            "restricted".parse::<ContainerPolicyLevel>().unwrap(),
+            ContainerPolicyLevel::Restricted
+        );
+        assert_eq!(
+            "baseline".parse::<ContainerPolicyLevel>().unwrap(),
+            ContainerPolicyLevel::Baseline
+        );
+        assert_eq!(
+            "privileged".parse::<ContainerPolicyLevel>().unwrap(),
+            ContainerPolicyLevel::Privileged
+        );
+        assert!("lockc".parse::<ContainerPolicyLevel>().is_err());
+        assert!("not found".parse::<ContainerPolicyLevel>().is_err());
+    }
+
+    #[test]
+    fn policy_level_bit() {
+        assert_eq!(ContainerPolicyLevel::NotFound.bit(), 0);
+        assert_eq!(ContainerPolicyLevel::Lockc.bit(), 0);
+        let bits = [
+            ContainerPolicyLevel::Restricted,
+            ContainerPolicyLevel::Offline,
+            ContainerPolicyLevel::Baseline,
+            ContainerPolicyLevel::Privileged,
+        ]
+        .map(|level| level.bit());
+        assert_eq!(bits, [0b10, 0b100, 0b1000, 0b10000]);
+    }
+
+    #[test]
+    fn policy_level_serde_roundtrip() {
+        let container = Container::new(ContainerPolicyLevel::Offline, Enforcement::Complain);
+        let json = serde_json::to_string(&container).unwrap();
+        assert_eq!(
+            json,
+            r#"{"policy_level":"offline","enforcement":"complain","syslog":false}"#
+        );
+        let container: Container = serde_json::from_str(&json).unwrap();
+        assert_eq!(container.policy_level, ContainerPolicyLevel::Offline);
+        assert_eq!(container.enforcement, Enforcement::Complain);
+
+        // Containers serialized before the complain mode are enforced.
+        let container: Container = serde_json::from_str(r#"{"policy_level":"baseline"}"#).unwrap();
+        assert_eq!(container.enforcement, Enforcement::Enforce);
+        assert!(!container.syslog);
+    }
+
+    #[test]
+    fn path_prefix_key() {
+        let key = PathPrefix::key(PathClass::MountBaseline, "/var/lib/docker").unwrap();
+        let prefix_len = key.prefix_len;
+        assert_eq!(prefix_len, 128);
+        assert_eq!(key.data.class, PathClass::MountBaseline as u8);
+        assert_eq!(&key.data.path[..16], b"/var/lib/docker\0");
+
+        assert!(PathPrefix::new(PathClass::AccessBaseline, &"a".repeat(PATH_LEN)).is_err());
+    }
+
+    #[test]
+    fn inode_id_from_metadata() {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = std::fs::metadata("/").unwrap();
+        let inode = InodeId::from_metadata(&metadata);
+        assert_eq!(inode.i_ino, metadata.ino());
+        // The kernel keeps the minor number in the lower 20 bits.
+        let dev = metadata.dev();
+        assert_eq!(inode.s_dev & 0xff, dev & 0xff);
+        assert_eq!(inode.s_dev >> 20, (dev >> 8) & 0xfff);
+    }
+
+    #[test]
+    fn violation_hook_detail() {
+        let mut violation = Violation {
+            container_id: ContainerID::new("abc").unwrap(),
+            pid: 1,
+            hook: Hook::FileOpen as u8,
+            enforcement: Enforcement::Complain as u8,
+            killed: 1,
+            _padding: 0,
+            suppressed: 0,
+            detail: [0; PATH_LEN],
+        };
+        assert_eq!(violation.hook(), Some(Hook::FileOpen));
+        assert_eq!(violation.enforcement(), Enforcement::Complain);
+        assert!(violation.killed());
+        assert!(violation.detail().is_none());
+
+        violation.detail[..10].copy_from_slice(b"/sys/fs/\0x");
+        assert_eq!(violation.detail().as_deref(), Some("/sys/fs/"));
+
+        violation.hook = 0;
+        assert!(violation.hook().is_none());
+    }
+
+    #[test]
+    fn violation_limit_take() {
+        const SECOND: u64 = 1_000_000_000;
+
+        let mut limit = ViolationLimit::new(SECOND);
+        for _ in 1..VIOLATION_BURST {
+            assert_eq!(limit.take(SECOND), Some(0));
+        }
+        assert_eq!(limit.take(SECOND), None);
+        assert_eq!(limit.take(SECOND), None);
+
+        // One event is allowed after the interval of the rate.
+        let now = SECOND + SECOND / VIOLATION_RATE;
+        assert_eq!(limit.take(now), Some(2));
+        assert_eq!(limit.take(now), None);
+
+        // The bucket doesn't grow over the burst.
+        let now = now + 60 * SECOND;
+        assert_eq!(limit.take(now), Some(1));
+        for _ in 1..VIOLATION_BURST {
+            assert_eq!(limit.take(now), Some(0));
+        }
+        assert_eq!(limit.take(now), None);
+    }
+
+    #[test]
+    fn learned_mount_key() {
+        let container_id = ContainerID::new("abc").unwrap();
+        let key = LearnedMount::new(container_id, PathClass::MountBaseline, "/srv/data").unwrap();
+        assert_eq!(key.class(), Some(PathClass::MountBaseline));
+        assert_eq!(key.path(), "/srv/data");
+        assert!(key.path[9..].iter().all(|b| *b == 0));
+
+        assert!(LearnedMount::new(
+            container_id,
+            PathClass::MountRestricted,
+            &"a".repeat(PATH_LEN)
+        )
+        .is_err());
+        let key = LearnedMount { class: 3, ..key };
+        assert!(key.class().is_none());
+    }
+
+    #[test]
+    fn map_error_event_operation() {
+        let mut event = MapErrorEvent {
+            operation: MapOperation::InitialSetuidInsert as u32,
+            error: -7,
+            count: 1,
+        };
+        assert_eq!(event.operation(), Some(MapOperation::InitialSetuidInsert));
+        event.operation = MAP_OPERATIONS_LEN;
+        assert!(event.operation().is_none());
+    }
+
+    #[test]
+    fn comm_hash_truncated() {
+        assert_eq!(comm_hash(b"runc"), comm_hash(b"runc\0\0\0"));
+        assert_ne!(comm_hash(b"runc"), comm_hash(b"crun"));
+        // Command names are truncated to 15 bytes by the kernel.
+        assert_eq!(
+            comm_hash(b"containerd-shim-runc-v2"),
+            comm_hash(b"containerd-shim")
+        );
+    }
+
+    #[test]
+    fn process_event_kind_comm() {
+        let mut comm = [0; TASK_COMM_LEN];
+        comm[..5].copy_from_slice(b"nginx");
+        let mut event = ProcessEvent {
+            container_id: ContainerID::new("abc").unwrap(),
+            pid: 2,
+            ppid: 1,
+            kind: ProcessEventKind::Exec as u8,
+            _padding: [0; 3],
+            comm,
+        };
+        assert_eq!(event.kind(), Some(ProcessEventKind::Exec));
+        assert_eq!(event.comm(), "nginx");
+        event.kind = 0;
+        assert!(event.kind().is_none());
+        event.comm = [b'a'; TASK_COMM_LEN];
+        assert_eq!(event.comm().len(), TASK_COMM_LEN);
+    }
 }
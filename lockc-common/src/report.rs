@@ -0,0 +1,39 @@
+//! Reports pushed by lockc in the reporter mode to `lockc-collector`, which
+//! aggregates them into a fleet-wide view. Every report is a JSON object sent
+//! in the body of an HTTP POST request.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{control::ContainerInfo, Enforcement, Hook};
+
+/// Default port of `lockc-collector`.
+pub const COLLECTOR_PORT: u16 = 9849;
+
+/// Path to which reports are posted.
+pub const REPORT_PATH: &str = "/report";
+
+/// State of a node, sent on every interval.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeReport {
+    pub node: String,
+    /// Time of the report, in RFC 3339.
+    pub time: String,
+    /// Interval of reports of the node, in seconds.
+    pub interval_s: u64,
+    /// All containers registered on the node.
+    pub containers: Vec<ContainerInfo>,
+    /// Violations since the previous report delivered to the collector.
+    pub violations: Vec<ViolationSummary>,
+}
+
+/// Violations of a container in one hook.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ViolationSummary {
+    pub container_id: String,
+    pub hook: Hook,
+    pub enforcement: Enforcement,
+    /// Number of violations, suppressed ones included.
+    pub count: u64,
+    /// Detail of the last violation, e.g. the denied path.
+    pub last_detail: Option<String>,
+}
@@ -0,0 +1,410 @@
+//! Policy decisions shared by eBPF programs and userspace. eBPF programs use
+//! them to enforce policies, lockctl uses them to evaluate what the current
+//! state of eBPF maps would decide for the given operation (dry run).
+
+use crate::{ContainerPolicyLevel, FilePermission, PathClass};
+
+/// Operations which path prefixes are checked for. Prefixes exist in a
+/// restricted and a baseline variant for each of them.
+#[cfg_attr(feature = "user", derive(Debug))]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PathList {
+    /// Paths allowed to be bind mounted into containers.
+    Mount,
+    /// Paths allowed or denied to be opened.
+    Access,
+}
+
+impl PathList {
+    /// Returns the class of prefixes for the given policy level, `None` if
+    /// the policy level doesn't restrict paths.
+    #[inline(always)]
+    pub fn class(&self, policy_level: ContainerPolicyLevel) -> Option<PathClass> {
+        let restricted = match policy_level {
+            ContainerPolicyLevel::Restricted | ContainerPolicyLevel::Offline => true,
+            ContainerPolicyLevel::Baseline => false,
+            _ => return None,
+        };
+        Some(match (self, restricted) {
+            (PathList::Mount, true) => PathClass::MountRestricted,
+            (PathList::Mount, false) => PathClass::MountBaseline,
+            (PathList::Access, true) => PathClass::AccessRestricted,
+            (PathList::Access, false) => PathClass::AccessBaseline,
+        })
+    }
+}
+
+/// Source of path prefixes. eBPF programs look them up in an LPM trie map,
+/// userspace in the same map or in the settings it's filled from.
+pub trait PathLists {
+    /// Returns the permission of the longest prefix of `path` in the given
+    /// class, `None` if no prefix matches.
+    fn lookup(&self, class: PathClass, path: &[u8]) -> Option<FilePermission>;
+}
+
+#[cfg_attr(
+    feature = "user",
+    derive(Debug, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+#[cfg(feature = "user")]
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Allow => write!(f, "allow"),
+            Verdict::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Returns whether restrictions apply to the given policy level.
+#[inline(always)]
+pub fn enforced(policy_level: ContainerPolicyLevel) -> bool {
+    match policy_level {
+        ContainerPolicyLevel::NotFound => false,
+        ContainerPolicyLevel::Lockc => false,
+        ContainerPolicyLevel::Restricted => true,
+        ContainerPolicyLevel::Offline => true,
+        ContainerPolicyLevel::Baseline => true,
+        ContainerPolicyLevel::Privileged => false,
+    }
+}
+
+/// Access to the kernel logs.
+#[inline(always)]
+pub fn syslog(policy_level: ContainerPolicyLevel) -> Verdict {
+    if enforced(policy_level) {
+        return Verdict::Deny;
+    }
+    Verdict::Allow
+}
+
+/// Mounting `src_path` with the given mount type. Only bind mounts are
+/// restricted.
+#[inline(always)]
+pub fn mount<L: PathLists>(
+    lists: &L,
+    policy_level: ContainerPolicyLevel,
+    mount_type: &str,
+    src_path: &str,
+) -> Verdict {
+    if !enforced(policy_level) || !mount_type.starts_with("bind") {
+        return Verdict::Allow;
+    }
+
+    match PathList::Mount.class(policy_level) {
+        Some(class) => match lists.lookup(class, src_path.as_bytes()) {
+            Some(FilePermission::Allow) => Verdict::Allow,
+            _ => Verdict::Deny,
+        },
+        None => Verdict::Allow,
+    }
+}
+
+/// Changing the UID to `uid_new`. The first UID change in a container is
+/// done by the container runtime and is always allowed.
+#[inline(always)]
+pub fn setuid(policy_level: ContainerPolicyLevel, initial_setuid: bool, uid_new: u32) -> Verdict {
+    if enforced(policy_level) && initial_setuid && uid_new == 0 {
+        return Verdict::Deny;
+    }
+    Verdict::Allow
+}
+
+/// Opening the file under `path`. The longest matching prefix decides, so
+/// allowed paths can be exceptions from the denied ones. Everything else is
+/// allowed.
+#[inline(always)]
+pub fn file_open<L: PathLists>(
+    lists: &L,
+    policy_level: ContainerPolicyLevel,
+    path: &str,
+) -> Verdict {
+    if !enforced(policy_level) {
+        return Verdict::Allow;
+    }
+
+    match PathList::Access.class(policy_level) {
+        Some(class) => match lists.lookup(class, path.as_bytes()) {
+            Some(FilePermission::Deny) => Verdict::Deny,
+            _ => Verdict::Allow,
+        },
+        None => Verdict::Allow,
+    }
+}
+
+/// Returns whether a file inherits `permission` of the inode of a path prefix
+/// found `depth` directories above it (0 for the file itself). Hardlinked
+/// files can be aliases of files from other directories, e.g. denied ones, so
+/// they inherit only denials, unless `hardlinks_inherit` is set.
+#[inline(always)]
+pub fn inherits(
+    permission: FilePermission,
+    depth: u32,
+    hardlinked: bool,
+    hardlinks_inherit: bool,
+) -> bool {
+    depth == 0 || permission == FilePermission::Deny || !hardlinked || hardlinks_inherit
+}
+
+/// How a hook treats rootless containers, which run in a user namespace
+/// without access to root of the host. Set per hook by userspace when
+/// loading the programs.
+#[cfg_attr(
+    feature = "user",
+    derive(Debug, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[derive(Copy, Clone, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum UsernsOverride {
+    /// Decide based on the policy level, like for any other container.
+    #[default]
+    Policy = 0,
+    /// Allow regardless of the policy level.
+    Allow = 1,
+    /// Deny regardless of the policy level.
+    Deny = 2,
+}
+
+impl UsernsOverride {
+    /// Converts the value of a global variable set by userspace. Unknown
+    /// values fall back to the policy.
+    #[inline(always)]
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => UsernsOverride::Allow,
+            2 => UsernsOverride::Deny,
+            _ => UsernsOverride::Policy,
+        }
+    }
+}
+
+/// Applies `override_` to `verdict`, the policy decision on an operation
+/// restricted by a hook, if the container is rootless. Processes outside of
+/// containers and lockc itself are never affected.
+#[inline(always)]
+pub fn user_namespace(
+    policy_level: ContainerPolicyLevel,
+    override_: UsernsOverride,
+    rootless: bool,
+    verdict: Verdict,
+) -> Verdict {
+    match policy_level {
+        ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc => return verdict,
+        _ => {}
+    }
+    match (rootless, override_) {
+        (true, UsernsOverride::Allow) => Verdict::Allow,
+        (true, UsernsOverride::Deny) => Verdict::Deny,
+        _ => verdict,
+    }
+}
+
+/// Sending or receiving messages through sockets.
+#[inline(always)]
+pub fn socket(policy_level: ContainerPolicyLevel) -> Verdict {
+    match policy_level {
+        ContainerPolicyLevel::Offline => Verdict::Deny,
+        _ => Verdict::Allow,
+    }
+}
+
+#[cfg(all(test, feature = "user"))]
+mod tests {
+    use super::*;
+
+    /// Prefixes with the same paths for all policy levels.
+    struct TestPathLists {
+        mount: &'static [(&'static str, FilePermission)],
+        access: &'static [(&'static str, FilePermission)],
+    }
+
+    impl PathLists for TestPathLists {
+        fn lookup(&self, class: PathClass, path: &[u8]) -> Option<FilePermission> {
+            let prefixes = match class {
+                PathClass::MountRestricted | PathClass::MountBaseline => self.mount,
+                PathClass::AccessRestricted | PathClass::AccessBaseline => self.access,
+            };
+            prefixes
+                .iter()
+                .filter(|(prefix, _)| path.starts_with(prefix.as_bytes()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map(|(_, permission)| *permission)
+        }
+    }
+
+    const LISTS: TestPathLists = TestPathLists {
+        mount: &[
+            ("/var/lib/kubelet", FilePermission::Allow),
+            ("/dev/pts", FilePermission::Allow),
+        ],
+        access: &[
+            ("/sys/", FilePermission::Deny),
+            ("/sys/fs/cgroup", FilePermission::Allow),
+            ("/sys/fs/cgroup/secret", FilePermission::Deny),
+        ],
+    };
+
+    #[test]
+    fn verdict_mount() {
+        assert_eq!(
+            mount(&LISTS, ContainerPolicyLevel::Baseline, "bind", "/etc"),
+            Verdict::Deny
+        );
+        assert_eq!(
+            mount(
+                &LISTS,
+                ContainerPolicyLevel::Baseline,
+                "bind",
+                "/var/lib/kubelet/pods"
+            ),
+            Verdict::Allow
+        );
+        assert_eq!(
+            mount(&LISTS, ContainerPolicyLevel::Baseline, "proc", "/etc"),
+            Verdict::Allow
+        );
+        assert_eq!(
+            mount(&LISTS, ContainerPolicyLevel::Privileged, "bind", "/etc"),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn verdict_file_open() {
+        assert_eq!(
+            file_open(
+                &LISTS,
+                ContainerPolicyLevel::Restricted,
+                "/sys/kernel/debug"
+            ),
+            Verdict::Deny
+        );
+        assert_eq!(
+            file_open(
+                &LISTS,
+                ContainerPolicyLevel::Restricted,
+                "/sys/fs/cgroup/cpu"
+            ),
+            Verdict::Allow
+        );
+        assert_eq!(
+            file_open(
+                &LISTS,
+                ContainerPolicyLevel::Restricted,
+                "/sys/fs/cgroup/secret/key"
+            ),
+            Verdict::Deny
+        );
+        assert_eq!(
+            file_open(&LISTS, ContainerPolicyLevel::Restricted, "/etc/passwd"),
+            Verdict::Allow
+        );
+        assert_eq!(
+            file_open(
+                &LISTS,
+                ContainerPolicyLevel::Privileged,
+                "/sys/kernel/debug"
+            ),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn path_list_class() {
+        assert_eq!(
+            PathList::Mount.class(ContainerPolicyLevel::Offline),
+            Some(PathClass::MountRestricted)
+        );
+        assert_eq!(
+            PathList::Access.class(ContainerPolicyLevel::Baseline),
+            Some(PathClass::AccessBaseline)
+        );
+        assert_eq!(
+            PathList::Access.class(ContainerPolicyLevel::Privileged),
+            None
+        );
+    }
+
+    #[test]
+    fn verdict_inherits() {
+        assert!(inherits(FilePermission::Allow, 2, false, false));
+        assert!(inherits(FilePermission::Deny, 2, true, false));
+        assert!(!inherits(FilePermission::Allow, 2, true, false));
+        assert!(inherits(FilePermission::Allow, 2, true, true));
+        assert!(inherits(FilePermission::Allow, 0, true, false));
+    }
+
+    #[test]
+    fn verdict_setuid() {
+        assert_eq!(
+            setuid(ContainerPolicyLevel::Baseline, true, 0),
+            Verdict::Deny
+        );
+        assert_eq!(
+            setuid(ContainerPolicyLevel::Baseline, false, 0),
+            Verdict::Allow
+        );
+        assert_eq!(
+            setuid(ContainerPolicyLevel::Baseline, true, 1000),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn verdict_user_namespace() {
+        for (override_, expected) in [
+            (UsernsOverride::Policy, Verdict::Deny),
+            (UsernsOverride::Allow, Verdict::Allow),
+            (UsernsOverride::Deny, Verdict::Deny),
+        ] {
+            assert_eq!(
+                user_namespace(
+                    ContainerPolicyLevel::Restricted,
+                    override_,
+                    true,
+                    Verdict::Deny
+                ),
+                expected
+            );
+        }
+        // Tightening applies to privileged containers as well.
+        assert_eq!(
+            user_namespace(
+                ContainerPolicyLevel::Privileged,
+                UsernsOverride::Deny,
+                true,
+                Verdict::Allow
+            ),
+            Verdict::Deny
+        );
+        // Containers mapping root of the host are not affected.
+        assert_eq!(
+            user_namespace(
+                ContainerPolicyLevel::Baseline,
+                UsernsOverride::Allow,
+                false,
+                Verdict::Deny
+            ),
+            Verdict::Deny
+        );
+        assert_eq!(
+            user_namespace(
+                ContainerPolicyLevel::NotFound,
+                UsernsOverride::Deny,
+                true,
+                Verdict::Allow
+            ),
+            Verdict::Allow
+        );
+        assert_eq!(UsernsOverride::from_u8(2), UsernsOverride::Deny);
+        assert_eq!(UsernsOverride::from_u8(7), UsernsOverride::Policy);
+    }
+}
@@ -0,0 +1,24 @@
+use aya_bpf::{cty::c_long, BpfContext};
+
+use lockc_common::{MapErrorEvent, MapOperation};
+
+use crate::maps::{MAP_ERRORS, MAP_ERROR_EVENTS};
+
+/// Counts the failed map operation and notifies userspace every time the
+/// per-CPU count reaches a power of two.
+#[inline(always)]
+pub(crate) fn report_map_error<C: BpfContext>(ctx: &C, operation: MapOperation, error: c_long) {
+    let count = match unsafe { MAP_ERRORS.get_ptr_mut(operation as u32) } {
+        Some(count) => unsafe { &mut *count },
+        None => return,
+    };
+    *count += 1;
+    if count.is_power_of_two() {
+        let event = MapErrorEvent {
+            operation: operation as u32,
+            error: error as i32,
+            count: *count,
+        };
+        unsafe { MAP_ERROR_EVENTS.output(ctx, &event, 0) };
+    }
+}
@@ -0,0 +1,42 @@
+use aya_bpf::helpers::bpf_probe_read_kernel_str_bytes;
+
+use lockc_common::{ContainerID, LearnedCapability, PathClass};
+
+use crate::maps::{LEARNED_CAPABILITIES, LEARNED_MOUNTS, LEARNED_MOUNT_BUF};
+
+/// Counts the attempt to bind mount `src_path` (a pointer to the mount source
+/// passed to the hook) in learning mode. Failures are ignored, a full map is
+/// emptied by userspace on the next collection.
+#[inline(always)]
+pub(crate) fn record_mount(container_id: ContainerID, class: PathClass, src_path: *const u8) {
+    let key = match unsafe { LEARNED_MOUNT_BUF.get_ptr_mut(0) } {
+        Some(key) => unsafe { &mut *key },
+        None => return,
+    };
+    key.set(container_id, class);
+    if unsafe { bpf_probe_read_kernel_str_bytes(src_path, &mut key.path) }.is_err() {
+        return;
+    }
+    match unsafe { LEARNED_MOUNTS.get_ptr_mut(key) } {
+        Some(count) => unsafe { *count += 1 },
+        None => {
+            let _ = unsafe { LEARNED_MOUNTS.insert(key, &1, 0) };
+        }
+    }
+}
+
+/// Counts the use of the capability by the container for its fingerprint.
+/// Failures are ignored, like in [`record_mount`].
+#[inline(always)]
+pub(crate) fn record_capability(container_id: ContainerID, capability: u32) {
+    let key = LearnedCapability {
+        container_id,
+        capability,
+    };
+    match unsafe { LEARNED_CAPABILITIES.get_ptr_mut(&key) } {
+        Some(count) => unsafe { *count += 1 },
+        None => {
+            let _ = unsafe { LEARNED_CAPABILITIES.insert(&key, &1, 0) };
+        }
+    }
+}
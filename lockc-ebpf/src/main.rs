@@ -4,85 +4,150 @@
 use aya_bpf::{
     bindings::path,
     cty::{c_char, c_long},
-    helpers::{bpf_d_path, bpf_probe_read_kernel_str_bytes},
-    macros::lsm,
-    programs::LsmContext,
+    helpers::{bpf_d_path, bpf_probe_read_kernel, bpf_probe_read_kernel_str_bytes},
+    macros::{cgroup_sock_addr, lsm},
+    maps::lpm_trie::Key,
+    programs::{LsmContext, SockAddrContext},
     BpfContext,
 };
 use aya_log_ebpf::{debug, error, info};
 
-use lockc_common::{ContainerPolicyLevel, PATH_LEN};
+use lockc_common::{
+    verdict::{self, PathList, UsernsOverride, Verdict},
+    ContainerID, ContainerPolicyLevel, DenyResponse, Enforcement, FilePermission, Hook, InodeId,
+    InodePrefix, MapOperation, PathClass, Program, INODE_WALK_DEPTH, PATH_LEN,
+};
 
+mod errors;
+mod learning;
 mod maps;
 mod policy;
 mod proc;
+mod stats;
+mod task_ext;
+mod trace;
+mod violation;
 #[allow(non_upper_case_globals)]
 #[allow(non_snake_case)]
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
 mod vmlinux;
 
-use maps::{CONTAINER_INITIAL_SETUID, MOUNT_TYPE_BUF, PATH_BUF};
+use maps::{
+    MapPathLists, CONTAINER_INITIAL_SETUID, DENY_RESPONSES, EGRESS_DENY_V4, EGRESS_DENY_V6,
+    INODE_PREFIXES, MASKED_PROC_INODES, MOUNT_TYPE_BUF, PROTECTED_INODES,
+};
 use policy::get_container_and_policy_level;
-use vmlinux::{cred, file, socket};
+use task_ext::Task;
+use vmlinux::{cred, dentry, file, inode, mnt_namespace, mount, net, sock, socket, vfsmount};
 
 const AF_INET: u16 = 2;
 const AF_INET6: u16 = 10;
 
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFSOCK: u16 = 0o140000;
+
+/// Return values of cgroup programs.
+const CGROUP_DENY: i32 = 0;
+const CGROUP_ALLOW: i32 = 1;
+
+/// Magic number of bpffs superblocks.
+const BPF_FS_MAGIC: u64 = 0xcafe4a11;
+/// Magic number of procfs superblocks.
+const PROC_SUPER_MAGIC: u64 = 0x9fa0;
+
+/// Mount flags of `sb_mount`.
+const MS_RDONLY: u64 = 1;
+const MS_REMOUNT: u64 = 32;
+const MS_UNBINDABLE: u64 = 1 << 17;
+const MS_PRIVATE: u64 = 1 << 18;
+const MS_SLAVE: u64 = 1 << 19;
+const MS_SHARED: u64 = 1 << 20;
+
+/// Whether hardlinked files inherit allow permissions of directories they
+/// are in. Set by userspace when loading the program.
+#[no_mangle]
+static HARDLINKS_INHERIT: u8 = 0;
+
+/// [`UsernsOverride`] of bind mounts in rootless containers. Set by userspace
+/// when loading the program.
+#[no_mangle]
+static USERNS_MOUNT: u8 = 0;
+
+/// Whether bind mounts denied by path lists are recorded in `LEARNED_MOUNTS`
+/// and allowed instead. Set by userspace when loading the program.
+#[no_mangle]
+static LEARNING: u8 = 0;
+
+/// [`UsernsOverride`] of changing the UID to 0 in rootless containers. Set by
+/// userspace when loading the program.
+#[no_mangle]
+static USERNS_SETUID: u8 = 0;
+
+/// Whether receiving file descriptors of host sockets and files is denied,
+/// instead of only reported. Set by userspace when loading the program.
+#[no_mangle]
+static DENY_RECEIVED_FDS: u8 = 0;
+
 /// LSM program triggered by attempts to access the kernel logs. Behavior based
 /// on policy levels:
 ///
 /// * restricted: deny
 /// * baseline: deny
 /// * privileged: allow
+///
+/// Containers exempted from the denial (e.g. log collectors) are allowed
+/// regardless of their policy level.
 #[lsm(name = "syslog")]
 pub fn syslog(ctx: LsmContext) -> i32 {
-    match try_syslog(ctx) {
+    let ret = match try_syslog(ctx) {
         Ok(ret) => ret,
         Err(ret) => ret,
-    }
+    };
+    stats::count(Program::Syslog, ret)
 }
 
 fn try_syslog(ctx: LsmContext) -> Result<i32, i32> {
-    let (_, policy_level) = get_container_and_policy_level()?;
-
-    match policy_level {
-        ContainerPolicyLevel::NotFound => {
-            return Ok(0);
-        }
-        ContainerPolicyLevel::Lockc => {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    if let Some(container_id) = container_id {
+        if policy::syslog_allowed(&container_id) {
             return Ok(0);
         }
-        ContainerPolicyLevel::Restricted => {
-            info!(&ctx, "syslog: deny accessing syslog");
-            return Err(-1);
-        }
-        ContainerPolicyLevel::Offline => {
-            info!(&ctx, "syslog: deny accessing syslog");
-            return Err(-1);
-        }
-        ContainerPolicyLevel::Baseline => {
+    }
+
+    match verdict::syslog(policy_level) {
+        Verdict::Allow => Ok(0),
+        Verdict::Deny => {
+            if let Some(container_id) = container_id {
+                if !violation::report(&ctx, container_id, Hook::Syslog, None) {
+                    info!(&ctx, "syslog: complain accessing syslog");
+                    return Ok(0);
+                }
+            }
             info!(&ctx, "syslog: deny accessing syslog");
-            return Err(-1);
-        }
-        ContainerPolicyLevel::Privileged => {
-            return Ok(0);
+            Err(-1)
         }
     }
 }
 
 /// LSM program triggered by any mount attempt. It denies bind mounts to
-/// restricted and baseline containers.
+/// restricted and baseline containers, except of paths allowed by their
+/// custom policies. Rootless containers are handled according to
+/// `USERNS_MOUNT`. In learning mode, bind mounts denied by path lists are
+/// recorded and allowed.
 #[lsm(name = "sb_mount")]
 pub fn sb_mount(ctx: LsmContext) -> i32 {
-    match try_sb_mount(ctx) {
+    let ret = match try_sb_mount(ctx) {
         Ok(ret) => ret,
         Err(ret) => ret,
-    }
+    };
+    stats::count(Program::SbMount, ret)
 }
 
 fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
     let (container_id, policy_level) = get_container_and_policy_level()?;
+    let userns_mount = UsernsOverride::from_u8(unsafe { core::ptr::read_volatile(&USERNS_MOUNT) });
 
     match policy_level {
         ContainerPolicyLevel::NotFound => {
@@ -95,7 +160,9 @@ fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
         ContainerPolicyLevel::Offline => {}
         ContainerPolicyLevel::Baseline => {}
         ContainerPolicyLevel::Privileged => {
-            return Ok(0);
+            if userns_mount == UsernsOverride::Policy {
+                return Ok(0);
+            }
         }
     }
 
@@ -114,27 +181,58 @@ fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
         return Ok(0);
     }
 
+    let lists = MapPathLists::new().ok_or(0)?;
+    let dev_name: *const c_char = unsafe { ctx.arg(0) };
     let src_path = unsafe {
-        let dev_name: *const c_char = ctx.arg(0);
-        let buf_ptr = PATH_BUF.get_ptr_mut(0).ok_or(0)?;
-        let buf = &mut *buf_ptr;
         core::str::from_utf8_unchecked(
-            bpf_probe_read_kernel_str_bytes(dev_name as *const u8, &mut buf.path)
+            bpf_probe_read_kernel_str_bytes(dev_name as *const u8, &mut *lists.path_buf())
                 .map_err(|e| e as i32)?,
         )
     };
 
-    if src_path.starts_with("/run/k3s")
-        || src_path.starts_with("/var/lib/docker")
-        || src_path.starts_with("/var/lib/kubelet")
-        || src_path.starts_with("/var/lib/rancher")
-        || src_path.starts_with("/dev/pts")
-    {
+    let mut v = verdict::mount(&lists, policy_level, mount_type, src_path);
+    if v == Verdict::Deny {
+        if let Some(container_id) = &container_id {
+            if policy::custom_mount_allowed(container_id, unsafe { &*lists.path_buf() }) {
+                v = Verdict::Allow;
+            }
+        }
+    }
+    if userns_mount != UsernsOverride::Policy {
+        if let Some(container_id) = &container_id {
+            v = verdict::user_namespace(
+                policy_level,
+                userns_mount,
+                policy::rootless(container_id),
+                v,
+            );
+        }
+    }
+    if v == Verdict::Allow {
         return Ok(0);
     }
 
     let container_id = container_id.ok_or(-1)?;
+    if unsafe { core::ptr::read_volatile(&LEARNING) } != 0 {
+        if let Some(class) = PathList::Mount.class(policy_level) {
+            learning::record_mount(container_id, class, dev_name as *const u8);
+            let container_id = unsafe { container_id.as_str() };
+            info!(
+                &ctx,
+                "sb_mount: {}: learning bind mounting {}", container_id, src_path
+            );
+            return Ok(0);
+        }
+    }
+    let enforced = violation::report(&ctx, container_id, Hook::SbMount, Some(src_path));
     let container_id = unsafe { container_id.as_str() };
+    if !enforced {
+        info!(
+            &ctx,
+            "sb_mount: {}: complain bind mounting {}", container_id, src_path
+        );
+        return Ok(0);
+    }
     error!(
         &ctx,
         "sb_mount: {}: deny bind mounting {}", container_id, src_path
@@ -143,19 +241,241 @@ fn try_sb_mount(ctx: LsmContext) -> Result<i32, i32> {
     Err(-1)
 }
 
+/// LSM program triggered by attaching a mount with `move_mount` of the new
+/// mount API. Detached bind mounts created with `open_tree(OPEN_TREE_CLONE)`
+/// never go through `sb_mount`, so their source is checked against paths
+/// allowed to be bind mounted, by inode. Like non-bind mounts in `sb_mount`,
+/// moving mounts already attached in the mount namespace of the container
+/// and attaching whole filesystems (e.g. new ones created with `fsmount`) are
+/// allowed. Denied mounts are not recorded in learning mode, as the path of
+/// their source is not known. Mounts masking paths of procfs can't be moved
+/// away, like in `sb_umount`.
+#[lsm(name = "move_mount")]
+pub fn move_mount(ctx: LsmContext) -> i32 {
+    let ret = match try_move_mount(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::MoveMount, ret)
+}
+
+fn try_move_mount(ctx: LsmContext) -> Result<i32, i32> {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    let userns_mount = UsernsOverride::from_u8(unsafe { core::ptr::read_volatile(&USERNS_MOUNT) });
+
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {
+            if userns_mount == UsernsOverride::Policy {
+                return Ok(0);
+            }
+        }
+    }
+
+    let from_path: *const vmlinux::path = unsafe { ctx.arg(0) };
+    if masked_proc_mount(unsafe { (*from_path).mnt }) & policy_level.bit() != 0 {
+        return deny_proc_unmasking(&ctx, container_id);
+    }
+    let task = Task::current().ok_or(0)?;
+    let from_mnt_ns = path_mnt_ns(from_path);
+    if from_mnt_ns.is_some() && from_mnt_ns == task.mnt_ns() {
+        return Ok(0);
+    }
+    let dentry = unsafe { (*from_path).dentry };
+    if dentry == unsafe { (*(*dentry).d_sb).s_root } {
+        return Ok(0);
+    }
+
+    let mut v = match PathList::Mount.class(policy_level) {
+        Some(class) => match inode_permission(dentry, class) {
+            Some((FilePermission::Allow, _)) => Verdict::Allow,
+            _ => Verdict::Deny,
+        },
+        None => Verdict::Allow,
+    };
+    if userns_mount != UsernsOverride::Policy {
+        if let Some(container_id) = &container_id {
+            v = verdict::user_namespace(
+                policy_level,
+                userns_mount,
+                policy::rootless(container_id),
+                v,
+            );
+        }
+    }
+    if v == Verdict::Allow {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    let enforced = violation::report(&ctx, container_id, Hook::MoveMount, None);
+    let container_id = unsafe { container_id.as_str() };
+    if !enforced {
+        info!(
+            &ctx,
+            "move_mount: {}: complain attaching a detached bind mount", container_id
+        );
+        return Ok(0);
+    }
+    error!(
+        &ctx,
+        "move_mount: {}: deny attaching a detached bind mount", container_id
+    );
+
+    Err(-1)
+}
+
+/// Returns bits of policy levels which can't unmask the dentry, if it's one
+/// of the masked paths of procfs.
+#[inline(always)]
+fn masked_proc_dentry(dentry: *const dentry) -> Option<u8> {
+    unsafe {
+        let sb = bpf_probe_read_kernel(&(*dentry).d_sb).ok()?;
+        if bpf_probe_read_kernel(&(*sb).s_magic).ok()? as u64 != PROC_SUPER_MAGIC {
+            return None;
+        }
+        let inode = bpf_probe_read_kernel(&(*dentry).d_inode).ok()?;
+        if inode.is_null() {
+            return None;
+        }
+        let i_ino = bpf_probe_read_kernel(&(*inode).i_ino).ok()? as u64;
+        MASKED_PROC_INODES.get(&i_ino).copied()
+    }
+}
+
+/// Returns bits of policy levels which can't remove or change the mount,
+/// because it masks a path of procfs. That's either a bind mount of the
+/// path (e.g. read-only `/proc/sys`) or a mount over it (e.g. `/dev/null`
+/// over `/proc/kcore`).
+#[inline(always)]
+fn masked_proc_mount(vfsmount: *const vfsmount) -> u8 {
+    unsafe {
+        let root = match bpf_probe_read_kernel(&(*vfsmount).mnt_root) {
+            Ok(root) => root,
+            Err(_) => return 0,
+        };
+        if let Some(levels) = masked_proc_dentry(root) {
+            return levels;
+        }
+        let mount = (vfsmount as *const u8).sub(core::mem::offset_of!(mount, mnt)) as *const mount;
+        match bpf_probe_read_kernel(&(*mount).mnt_mountpoint) {
+            Ok(mountpoint) => masked_proc_dentry(mountpoint).unwrap_or(0),
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Reports unmasking of a path of procfs. Returns the verdict of the
+/// program.
+#[inline(always)]
+fn deny_proc_unmasking(ctx: &LsmContext, container_id: Option<ContainerID>) -> Result<i32, i32> {
+    let container_id = container_id.ok_or(-1)?;
+    let enforced = violation::report(ctx, container_id, Hook::ProcMasking, None);
+    let container_id = unsafe { container_id.as_str() };
+    if !enforced {
+        info!(
+            ctx,
+            "proc_masking: {}: complain unmasking a path of procfs", container_id
+        );
+        return Ok(0);
+    }
+    error!(
+        ctx,
+        "proc_masking: {}: deny unmasking a path of procfs", container_id
+    );
+    Err(-1)
+}
+
+/// LSM program of the `sb_mount` hook, which denies containers of policy
+/// levels from `MASKED_PROC_INODES` mounting over mounts masking paths of
+/// procfs, or remounting them read-write, even if their spec would allow it.
+/// Masking a path by the runtime (mounting over the path itself, then
+/// remounting it read-only) and changes of mount propagation are allowed.
+/// Unlike the `sb_mount` program of bind mounts, it's attached regardless
+/// of the root filesystem.
+#[lsm(name = "sb_mount_proc")]
+pub fn sb_mount_proc(ctx: LsmContext) -> i32 {
+    let ret = match try_sb_mount_proc(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::SbMountProc, ret)
+}
+
+fn try_sb_mount_proc(ctx: LsmContext) -> Result<i32, i32> {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    if policy_level.bit() == 0 {
+        return Ok(0);
+    }
+
+    let flags: u64 = unsafe { ctx.arg(3) };
+    if flags & (MS_SHARED | MS_PRIVATE | MS_SLAVE | MS_UNBINDABLE) != 0
+        || flags & (MS_REMOUNT | MS_RDONLY) == MS_REMOUNT | MS_RDONLY
+    {
+        return Ok(0);
+    }
+    let path: *const vmlinux::path = unsafe { ctx.arg(1) };
+    let vfsmount = unsafe { (*path).mnt };
+    if unsafe { (*path).dentry != (*vfsmount).mnt_root } {
+        return Ok(0);
+    }
+    if masked_proc_mount(vfsmount) & policy_level.bit() == 0 {
+        return Ok(0);
+    }
+
+    deny_proc_unmasking(&ctx, container_id)
+}
+
+/// LSM program triggered by unmounting. It denies containers of policy
+/// levels from `MASKED_PROC_INODES` unmounting mounts which mask paths of
+/// procfs.
+#[lsm(name = "sb_umount")]
+pub fn sb_umount(ctx: LsmContext) -> i32 {
+    let ret = match try_sb_umount(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::SbUmount, ret)
+}
+
+fn try_sb_umount(ctx: LsmContext) -> Result<i32, i32> {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    if policy_level.bit() == 0 {
+        return Ok(0);
+    }
+
+    let vfsmount: *const vfsmount = unsafe { ctx.arg(0) };
+    if masked_proc_mount(vfsmount) & policy_level.bit() == 0 {
+        return Ok(0);
+    }
+
+    deny_proc_unmasking(&ctx, container_id)
+}
+
 /// LSM program triggered when user attempts to change the UID. It denies
 /// changing the UID to 0 (logging in as root) in restricted and baseline
-/// containers.
+/// containers. Rootless containers are handled according to `USERNS_SETUID`.
 #[lsm(name = "task_fix_setuid")]
 pub fn task_fix_setuid(ctx: LsmContext) -> i32 {
-    match { try_task_fix_setuid(ctx) } {
+    let ret = match try_task_fix_setuid(ctx) {
         Ok(ret) => ret,
         Err(ret) => ret,
-    }
+    };
+    stats::count(Program::TaskFixSetuid, ret)
 }
 
 fn try_task_fix_setuid(ctx: LsmContext) -> Result<i32, i32> {
     let (container_id, policy_level) = get_container_and_policy_level()?;
+    let userns_setuid =
+        UsernsOverride::from_u8(unsafe { core::ptr::read_volatile(&USERNS_SETUID) });
     match policy_level {
         ContainerPolicyLevel::NotFound => {
             return Ok(0);
@@ -167,7 +487,9 @@ fn try_task_fix_setuid(ctx: LsmContext) -> Result<i32, i32> {
         ContainerPolicyLevel::Offline => {}
         ContainerPolicyLevel::Baseline => {}
         ContainerPolicyLevel::Privileged => {
-            return Ok(0);
+            if userns_setuid == UsernsOverride::Policy {
+                return Ok(0);
+            }
         }
     }
 
@@ -177,26 +499,41 @@ fn try_task_fix_setuid(ctx: LsmContext) -> Result<i32, i32> {
     let uid_new = unsafe { (*new).uid.val };
 
     if let Some(initial_setuid) = unsafe { CONTAINER_INITIAL_SETUID.get(&container_id) } {
-        if *initial_setuid {
-            if uid_new == 0 {
-                let container_id = unsafe { container_id.as_str() };
-                error!(
+        let mut v = verdict::setuid(policy_level, *initial_setuid, uid_new);
+        // Only changing the UID to 0 is restricted.
+        if *initial_setuid && uid_new == 0 && userns_setuid != UsernsOverride::Policy {
+            v = verdict::user_namespace(
+                policy_level,
+                userns_setuid,
+                policy::rootless(&container_id),
+                v,
+            );
+        }
+        if v == Verdict::Deny {
+            let enforced = violation::report(&ctx, container_id, Hook::TaskFixSetuid, None);
+            let container_id = unsafe { container_id.as_str() };
+            if !enforced {
+                info!(
                     &ctx,
-                    "task_fix_setuid: {}: deny logging as root", container_id
+                    "task_fix_setuid: {}: complain logging as root", container_id
                 );
-                return Err(-1);
+                return Ok(0);
             }
+            error!(
+                &ctx,
+                "task_fix_setuid: {}: deny logging as root", container_id
+            );
+            return Err(-1);
         }
     } else {
         debug!(
             &ctx,
             "task_fix_setuid: an initial setuid, policy not enforced"
         );
-        unsafe {
-            CONTAINER_INITIAL_SETUID
-                .insert(&container_id, &true, 0)
-                .map_err(|e| e as i32)?
-        };
+        if let Err(e) = unsafe { CONTAINER_INITIAL_SETUID.insert(&container_id, &true, 0) } {
+            errors::report_map_error(&ctx, MapOperation::InitialSetuidInsert, e);
+            return Err(e as i32);
+        }
     }
 
     Ok(0)
@@ -214,15 +551,162 @@ pub fn my_bpf_d_path(path: *mut path, buf: &mut [u8]) -> Result<usize, c_long> {
     Ok(ret as usize)
 }
 
+/// Returns the permission of the nearest path prefix whose inode is the given
+/// dentry or one of its parents, together with the key of the prefix. Unlike
+/// paths returned by `bpf_d_path`, it doesn't depend on the mount the file is
+/// accessed through.
+#[inline(always)]
+fn inode_permission(
+    mut dentry: *mut dentry,
+    class: PathClass,
+) -> Option<(FilePermission, InodePrefix)> {
+    let hardlinks_inherit = unsafe { core::ptr::read_volatile(&HARDLINKS_INHERIT) } != 0;
+    let mut hardlinked = false;
+
+    for depth in 0..INODE_WALK_DEPTH {
+        let (key, parent) = unsafe {
+            let inode = (*dentry).d_inode;
+            if inode.is_null() {
+                return None;
+            }
+            if depth == 0 {
+                hardlinked =
+                    (*inode).i_mode & S_IFMT != S_IFDIR && (*inode).__bindgen_anon_1.i_nlink > 1;
+            }
+            let inode_id = InodeId {
+                i_ino: (*inode).i_ino as u64,
+                s_dev: (*(*inode).i_sb).s_dev as u64,
+            };
+            (InodePrefix::new(class, inode_id), (*dentry).d_parent)
+        };
+
+        if let Some(info) = unsafe { INODE_PREFIXES.get(&key) } {
+            if verdict::inherits(info.permission, depth, hardlinked, hardlinks_inherit) {
+                return Some((info.permission, key));
+            }
+        }
+
+        // The root of the filesystem is its own parent.
+        if parent == dentry {
+            return None;
+        }
+        dentry = parent;
+    }
+
+    None
+}
+
+/// Leading fields of `struct unix_sock`, which have the same layout in all
+/// kernels supporting BPF LSM. The rest of the struct is not needed.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct unix_sock {
+    sk: sock,
+    addr: *mut core::ffi::c_void,
+    path: vmlinux::path,
+}
+
+/// Returns the permission of the first inode with a path prefix found when
+/// walking from the given dentry of a socket file up to the root. Unlike in
+/// [`inode_permission`], dentries come from a pointer the verifier doesn't
+/// know the type of, so they are read with `bpf_probe_read_kernel`.
+#[inline(always)]
+fn socket_inode_permission(mut dentry: *mut dentry, class: PathClass) -> Option<FilePermission> {
+    for _ in 0..INODE_WALK_DEPTH {
+        let (inode_id, parent) = unsafe {
+            let inode = bpf_probe_read_kernel(&(*dentry).d_inode).ok()?;
+            if inode.is_null() {
+                return None;
+            }
+            let sb = bpf_probe_read_kernel(&(*inode).i_sb).ok()?;
+            let inode_id = InodeId {
+                i_ino: bpf_probe_read_kernel(&(*inode).i_ino).ok()? as u64,
+                s_dev: bpf_probe_read_kernel(&(*sb).s_dev).ok()? as u64,
+            };
+            (inode_id, bpf_probe_read_kernel(&(*dentry).d_parent).ok()?)
+        };
+
+        if let Some(info) = unsafe { INODE_PREFIXES.get(&InodePrefix::new(class, inode_id)) } {
+            return Some(info.permission);
+        }
+
+        // The root of the filesystem is its own parent.
+        if parent == dentry {
+            return None;
+        }
+        dentry = parent;
+    }
+
+    None
+}
+
+/// LSM program triggered by connecting to a unix stream socket. It denies
+/// connecting to socket files under denied paths, e.g. sockets of container
+/// runtimes, to restricted and baseline containers. Sockets are matched by
+/// inodes, so bind mounting them under a different path doesn't help.
+/// Containers with the runtime sockets exemption are not restricted.
+#[lsm(name = "unix_stream_connect")]
+pub fn unix_stream_connect(ctx: LsmContext) -> i32 {
+    let ret = match try_unix_stream_connect(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::UnixStreamConnect, ret)
+}
+
+fn try_unix_stream_connect(ctx: LsmContext) -> Result<i32, i32> {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    let class = match PathList::Access.class(policy_level) {
+        Some(class) => class,
+        None => return Ok(0),
+    };
+    let container_id = container_id.ok_or(-1)?;
+    if policy::runtime_sockets_allowed(&container_id) {
+        return Ok(0);
+    }
+
+    let other: *const socket = unsafe { ctx.arg(1) };
+    // Sockets in the abstract namespace have no dentry.
+    let dentry = unsafe {
+        let usk = (*other).sk as *const unix_sock;
+        bpf_probe_read_kernel(&(*usk).path.dentry).map_err(|_| 0)?
+    };
+    if dentry.is_null() {
+        return Ok(0);
+    }
+
+    if socket_inode_permission(dentry, class) == Some(FilePermission::Deny) {
+        let enforced = violation::report(&ctx, container_id, Hook::UnixStreamConnect, None);
+        let container_id = unsafe { container_id.as_str() };
+        if !enforced {
+            info!(
+                &ctx,
+                "unix_stream_connect: {}: complain connecting to a socket under a denied inode",
+                container_id
+            );
+            return Ok(0);
+        }
+        error!(
+            &ctx,
+            "unix_stream_connect: {}: deny connecting to a socket under a denied inode",
+            container_id
+        );
+        return Err(-1);
+    }
+
+    Ok(0)
+}
+
 /// LSM program triggered by opening a file. It denies access to directories
 /// which might leak information about host (/sys/fs, /proc/acpi etc.) to
 /// restricted and baseline containers.
 #[lsm(name = "file_open")]
 pub fn file_open(ctx: LsmContext) -> i32 {
-    match { try_file_open(ctx) } {
+    let ret = match try_file_open(ctx) {
         Ok(ret) => ret,
         Err(ret) => ret,
-    }
+    };
+    stats::count(Program::FileOpen, ret)
 }
 
 fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
@@ -242,48 +726,252 @@ fn try_file_open(ctx: LsmContext) -> Result<i32, i32> {
         }
     }
 
-    let buf = unsafe {
-        let buf_ptr = PATH_BUF.get_ptr_mut(0).ok_or(0)?;
-        &mut *buf_ptr
-    };
+    let f: *const file = unsafe { ctx.arg(0) };
+
+    let class = PathList::Access.class(policy_level).ok_or(0)?;
+    match inode_permission(unsafe { (*f).f_path.dentry }, class) {
+        Some((FilePermission::Allow, _)) => return Ok(0),
+        Some((FilePermission::Deny, key)) => {
+            let container_id = container_id.ok_or(-1)?;
+            let response = unsafe { DENY_RESPONSES.get(&key) }
+                .copied()
+                .unwrap_or(DenyResponse::Deny);
+            let (enforced, killed) =
+                violation::report_with_response(&ctx, container_id, Hook::FileOpen, response);
+            let container_id = unsafe { container_id.as_str() };
+            if killed {
+                error!(
+                    &ctx,
+                    "file_open: {}: kill process opening a file under a denied inode", container_id
+                );
+                return Err(-1);
+            }
+            if !enforced {
+                info!(
+                    &ctx,
+                    "file_open: {}: complain opening a file under a denied inode", container_id
+                );
+                return Ok(0);
+            }
+            error!(
+                &ctx,
+                "file_open: {}: deny opening a file under a denied inode", container_id
+            );
+            return Err(-1);
+        }
+        None => {}
+    }
+
+    let lists = MapPathLists::new().ok_or(0)?;
+    let buf = unsafe { &mut *lists.path_buf() };
 
     let p = unsafe {
-        let f: *const file = ctx.arg(0);
         let p = &(*f).f_path as *const _ as *mut path;
-        let len = my_bpf_d_path(p, &mut buf.path).map_err(|_| 0)?;
+        let len = my_bpf_d_path(p, buf).map_err(|_| 0)?;
         if len >= PATH_LEN {
             return Err(0);
         }
-        core::str::from_utf8_unchecked(&buf.path[..len])
+        core::str::from_utf8_unchecked(&buf[..len])
     };
 
     let container_id = container_id.ok_or(-1)?;
-    let container_id = unsafe { container_id.as_str() };
 
-    if p.starts_with("/sys/devices")
-        || p.starts_with("/sys/fs/cgroup")
-        || p.starts_with("/sys/kernel/mm")
-    {
+    match verdict::file_open(&lists, policy_level, p) {
+        Verdict::Allow => Ok(0),
+        Verdict::Deny => {
+            let enforced = violation::report(&ctx, container_id, Hook::FileOpen, Some(p));
+            let container_id = unsafe { container_id.as_str() };
+            if !enforced {
+                info!(&ctx, "file_open: {}: complain opening {}", container_id, p);
+                return Ok(0);
+            }
+            error!(&ctx, "file_open: {}: deny opening {}", container_id, p);
+            Err(-1)
+        }
+    }
+}
+
+/// Returns the network namespace of the socket file.
+#[inline(always)]
+fn socket_file_net(f: *const file) -> Option<*mut net> {
+    unsafe {
+        let socket = bpf_probe_read_kernel(&(*f).private_data).ok()? as *const socket;
+        if socket.is_null() {
+            return None;
+        }
+        let sk = bpf_probe_read_kernel(&(*socket).sk).ok()?;
+        if sk.is_null() {
+            return None;
+        }
+        bpf_probe_read_kernel(&(*sk).__sk_common.skc_net.net).ok()
+    }
+}
+
+/// Returns the mount namespace of the mount of the path. Internal mounts of
+/// the kernel (e.g. of pipes and anonymous inodes) don't belong to any
+/// namespace.
+#[inline(always)]
+fn path_mnt_ns(path: *const vmlinux::path) -> Option<*mut mnt_namespace> {
+    unsafe {
+        let vfsmount = bpf_probe_read_kernel(&(*path).mnt).ok()?;
+        let mount = (vfsmount as *const u8).sub(core::mem::offset_of!(mount, mnt)) as *const mount;
+        let mnt_ns = bpf_probe_read_kernel(&(*mount).mnt_ns).ok()?;
+        // `MNT_NS_INTERNAL` is an error pointer.
+        if mnt_ns.is_null() || mnt_ns as usize >= (-4095isize) as usize {
+            return None;
+        }
+        Some(mnt_ns)
+    }
+}
+
+/// LSM program triggered by receiving a file descriptor, e.g. over a unix
+/// socket with `SCM_RIGHTS`. Descriptors passed from the host bypass the
+/// checks of mounts and of opening files, so restricted and baseline
+/// containers receiving a socket of another network namespace, a file under
+/// a denied path, or a file from a mount outside of their mount namespace
+/// get a violation. Such descriptors are denied only if `DENY_RECEIVED_FDS`
+/// is set.
+#[lsm(name = "file_receive")]
+pub fn file_receive(ctx: LsmContext) -> i32 {
+    let ret = match try_file_receive(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::FileReceive, ret)
+}
+
+fn try_file_receive(ctx: LsmContext) -> Result<i32, i32> {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    let class = match PathList::Access.class(policy_level) {
+        Some(class) => class,
+        None => return Ok(0),
+    };
+    let container_id = container_id.ok_or(-1)?;
+
+    let f: *const file = unsafe { ctx.arg(0) };
+    let task = Task::current().ok_or(0)?;
+    let mode = unsafe {
+        let inode = bpf_probe_read_kernel(&(*f).f_inode).map_err(|_| 0)?;
+        if inode.is_null() {
+            return Ok(0);
+        }
+        bpf_probe_read_kernel(&(*inode).i_mode).map_err(|_| 0)?
+    };
+    let host = if mode & S_IFMT == S_IFSOCK {
+        let net = socket_file_net(f).ok_or(0)?;
+        task.net_ns().map_or(false, |task_net| task_net != net)
+    } else {
+        match inode_permission(unsafe { (*f).f_path.dentry }, class) {
+            Some((FilePermission::Allow, _)) => false,
+            Some((FilePermission::Deny, _)) => true,
+            None => match (path_mnt_ns(unsafe { &(*f).f_path }), task.mnt_ns()) {
+                (Some(mnt_ns), Some(task_mnt_ns)) => mnt_ns != task_mnt_ns,
+                _ => false,
+            },
+        }
+    };
+    if !host {
         return Ok(0);
     }
 
-    if p.starts_with("/proc/acpi")
-        || p.starts_with("/sys/")
-        || p.starts_with("/var/run/secrets/kubernetes.io")
-    {
-        error!(&ctx, "file_open: {}: deny opening {}", container_id, p);
-        return Err(-1);
+    let response = if unsafe { core::ptr::read_volatile(&DENY_RECEIVED_FDS) } != 0 {
+        DenyResponse::Deny
+    } else {
+        DenyResponse::Audit
+    };
+    let (enforced, _) =
+        violation::report_with_response(&ctx, container_id, Hook::FileReceive, response);
+    let container_id = unsafe { container_id.as_str() };
+    if !enforced {
+        info!(
+            &ctx,
+            "file_receive: {}: complain receiving a host file descriptor", container_id
+        );
+        return Ok(0);
     }
+    error!(
+        &ctx,
+        "file_receive: {}: deny receiving a host file descriptor", container_id
+    );
+    Err(-1)
+}
 
-    Ok(0)
+/// Denies containers any access to pinned eBPF objects of lockc and their
+/// directory, regardless of the policy and of the mount they are reached
+/// through. The permission of the inode is checked both on opening and on
+/// `BPF_OBJ_GET`, so maps can't be read or modified by containers which
+/// have the host bpffs mounted.
+#[lsm(name = "inode_permission")]
+pub fn inode_permission_pinned(ctx: LsmContext) -> i32 {
+    let inode: *const inode = unsafe { ctx.arg(0) };
+    // The hook is called for every component of every path lookup. Inodes
+    // outside of bpffs are allowed right away and not counted.
+    if unsafe { (*(*inode).i_sb).s_magic } as u64 != BPF_FS_MAGIC {
+        return 0;
+    }
+    let ret = match try_inode_permission_pinned(ctx, inode) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::InodePermission, ret)
+}
+
+fn try_inode_permission_pinned(ctx: LsmContext, inode: *const inode) -> Result<i32, i32> {
+    let (container_id, policy_level) = get_container_and_policy_level()?;
+    match policy_level {
+        ContainerPolicyLevel::NotFound => {
+            return Ok(0);
+        }
+        ContainerPolicyLevel::Lockc => {
+            return Ok(0);
+        }
+        // Privileged containers are not exempted, they are the ones which
+        // usually have the host bpffs mounted.
+        ContainerPolicyLevel::Restricted => {}
+        ContainerPolicyLevel::Offline => {}
+        ContainerPolicyLevel::Baseline => {}
+        ContainerPolicyLevel::Privileged => {}
+    }
+
+    let inode_id = unsafe {
+        InodeId {
+            i_ino: (*inode).i_ino as u64,
+            s_dev: (*(*inode).i_sb).s_dev as u64,
+        }
+    };
+    if unsafe { PROTECTED_INODES.get(&inode_id) }.is_none() {
+        return Ok(0);
+    }
+
+    let container_id = container_id.ok_or(-1)?;
+    // Tampering with the state of lockc is denied also in the complain
+    // mode.
+    violation::report_enforced(&ctx, container_id, Hook::InodePermission);
+    let container_id = unsafe { container_id.as_str() };
+    error!(
+        &ctx,
+        "inode_permission: {}: deny access to pinned eBPF objects of lockc", container_id
+    );
+    Err(-1)
+}
+
+/// Denies sending and receiving messages in offline containers, unless the
+/// container is in the complain mode.
+#[inline(always)]
+fn offline_deny(container_id: Option<ContainerID>) -> Result<i32, i32> {
+    match container_id.map(|container_id| policy::enforcement(&container_id)) {
+        Some(Enforcement::Complain) => Ok(0),
+        _ => Err(-1),
+    }
 }
 
 #[lsm(name = "socket_sendmsg")]
 pub fn socket_sendmsg(ctx: LsmContext) -> i32 {
-    match { try_socket_sendmsg(ctx) } {
+    let ret = match try_socket_sendmsg(ctx) {
         Ok(ret) => ret,
         Err(ret) => ret,
-    }
+    };
+    stats::count(Program::SocketSendmsg, ret)
 }
 
 fn try_socket_sendmsg(ctx: LsmContext) -> Result<i32, i32> {
@@ -297,7 +985,7 @@ fn try_socket_sendmsg(ctx: LsmContext) -> Result<i32, i32> {
         }
         ContainerPolicyLevel::Restricted => {}
         ContainerPolicyLevel::Offline => {
-            return Err(-1);
+            return offline_deny(container_id);
         }
         ContainerPolicyLevel::Baseline => {}
         ContainerPolicyLevel::Privileged => {
@@ -320,10 +1008,11 @@ fn try_socket_sendmsg(ctx: LsmContext) -> Result<i32, i32> {
 
 #[lsm(name = "socket_recvmsg")]
 pub fn socket_recvmsg(ctx: LsmContext) -> i32 {
-    match { try_socket_recvmsg(ctx) } {
+    let ret = match try_socket_recvmsg(ctx) {
         Ok(ret) => ret,
         Err(ret) => ret,
-    }
+    };
+    stats::count(Program::SocketRecvmsg, ret)
 }
 
 fn try_socket_recvmsg(ctx: LsmContext) -> Result<i32, i32> {
@@ -337,7 +1026,7 @@ fn try_socket_recvmsg(ctx: LsmContext) -> Result<i32, i32> {
         }
         ContainerPolicyLevel::Restricted => {}
         ContainerPolicyLevel::Offline => {
-            return Err(-1);
+            return offline_deny(container_id);
         }
         ContainerPolicyLevel::Baseline => {}
         ContainerPolicyLevel::Privileged => {
@@ -385,6 +1074,89 @@ fn try_socket_recvmsg(ctx: LsmContext) -> Result<i32, i32> {
     Ok(0)
 }
 
+/// Option of `security_capable` set by checks which don't audit denials,
+/// e.g. probing for a capability without needing it.
+const CAP_OPT_NOAUDIT: u32 = 1 << 1;
+
+/// LSM program triggered by checks of capabilities which the task has,
+/// attached by userspace only when workload fingerprints are collected. It
+/// never denies anything, uses of capabilities by containers are only
+/// recorded in `LEARNED_CAPABILITIES`. Checks without auditing are skipped.
+#[lsm(name = "capable")]
+pub fn capable(ctx: LsmContext) -> i32 {
+    let ret = match try_capable(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    };
+    stats::count(Program::Capable, ret)
+}
+
+fn try_capable(ctx: LsmContext) -> Result<i32, i32> {
+    let container_id = match get_container_and_policy_level().map_err(|_| 0)? {
+        (_, ContainerPolicyLevel::Lockc) => return Ok(0),
+        (Some(container_id), _) => container_id,
+        (None, _) => return Ok(0),
+    };
+    let cap: i32 = unsafe { ctx.arg(2) };
+    let opts: u32 = unsafe { ctx.arg(3) };
+    if cap < 0 || opts & CAP_OPT_NOAUDIT != 0 {
+        return Ok(0);
+    }
+    learning::record_capability(container_id, cap as u32);
+    Ok(0)
+}
+
+/// cgroup program attached by userspace to cgroups of containers with
+/// restricted egress. Denies connections to IPv4 networks in
+/// `EGRESS_DENY_V4`, e.g. the cloud metadata endpoint.
+#[cgroup_sock_addr(connect4)]
+pub fn connect4(ctx: SockAddrContext) -> i32 {
+    let addr = unsafe { (*ctx.sock_addr).user_ip4 };
+    // The address is in network byte order, as the keys of the map.
+    let key = Key::new(32, addr.to_ne_bytes());
+    let ret = match unsafe { EGRESS_DENY_V4.get(&key) } {
+        Some(_) => egress_deny(&ctx),
+        None => CGROUP_ALLOW,
+    };
+    stats::count(Program::Connect4, if ret == CGROUP_ALLOW { 0 } else { -1 });
+    ret
+}
+
+/// cgroup program attached by userspace to cgroups of containers with
+/// restricted egress. Denies connections to IPv6 networks in
+/// `EGRESS_DENY_V6`.
+#[cgroup_sock_addr(connect6)]
+pub fn connect6(ctx: SockAddrContext) -> i32 {
+    let ip6 = unsafe { (*ctx.sock_addr).user_ip6 };
+    let mut addr = [0u8; 16];
+    for (i, word) in ip6.iter().enumerate() {
+        addr[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+    }
+    let key = Key::new(128, addr);
+    let ret = match unsafe { EGRESS_DENY_V6.get(&key) } {
+        Some(_) => egress_deny(&ctx),
+        None => CGROUP_ALLOW,
+    };
+    stats::count(Program::Connect6, if ret == CGROUP_ALLOW { 0 } else { -1 });
+    ret
+}
+
+/// Reports the connection to a denied network. Processes in the cgroup which
+/// are not attributed to the container are denied as well.
+#[inline(always)]
+fn egress_deny(ctx: &SockAddrContext) -> i32 {
+    match get_container_and_policy_level() {
+        Ok((Some(container_id), _)) => {
+            if violation::report(ctx, container_id, Hook::Connect, None) {
+                CGROUP_DENY
+            } else {
+                CGROUP_ALLOW
+            }
+        }
+        _ => CGROUP_DENY,
+    }
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
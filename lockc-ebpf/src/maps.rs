@@ -1,9 +1,19 @@
 use aya_bpf::{
     macros::map,
-    maps::{HashMap, PerCpuArray},
+    maps::{lpm_trie::Key, HashMap, LpmTrie, LruPerCpuHashMap, PerCpuArray, PerfEventArray},
 };
 
-use lockc_common::{Container, ContainerID, MountType, Path, Process, PID_MAX_LIMIT};
+use lockc_common::{
+    attribution::ProcessContainers, verdict::PathLists, Container, ContainerID, ContainerSpec,
+    CustomMounts, DenyResponse, FilePermission, InodeId, InodeInfo, InodePrefix, LearnedCapability,
+    LearnedMount, MapErrorEvent, MountType, PathClass, PathPrefix, Process, ProcessEvent,
+    ProgramStats, Violation, ViolationKey, ViolationLimit, EGRESS_DENY_MAX, EXCLUDED_MAX,
+    LEARNED_CAPABILITIES_MAX, LEARNED_MOUNTS_MAX, MAP_OPERATIONS_LEN, MASKED_PROC_MAX, PATH_LEN,
+    PATH_MAX_LIMIT, PID_MAX_LIMIT, PROGRAMS_LEN, PROTECTED_MAX, VIOLATION_LIMITS_MAX,
+};
+
+/// LPM trie maps have to be created without preallocation.
+const BPF_F_NO_PREALLOC: u32 = 1;
 
 /// BPF map containing the info about a policy which should be enforced on the
 /// given container.
@@ -11,12 +21,34 @@ use lockc_common::{Container, ContainerID, MountType, Path, Process, PID_MAX_LIM
 pub(crate) static mut CONTAINERS: HashMap<ContainerID, Container> =
     HashMap::pinned(PID_MAX_LIMIT, 0);
 
-/// BPF map which maps the PID to a container it belongs to. The value of this
+/// BPF map which maps the PID (thread-group ID) of a process to a container
+/// it belongs to. Threads share the entry of their process. The value of this
 /// map, which represents the container, is a key of `containers` BPF map, so
 /// it can be used immediately for lookups in `containers` map.
 #[map]
 pub(crate) static mut PROCESSES: HashMap<i32, Process> = HashMap::pinned(PID_MAX_LIMIT, 0);
 
+/// BPF map with data from OCI runtime specs of containers. Programs look up
+/// whether a container is rootless only when an override for user-namespaced
+/// containers is set for their hook.
+#[map]
+pub(crate) static mut CONTAINER_SPECS: HashMap<ContainerID, ContainerSpec> =
+    HashMap::pinned(PID_MAX_LIMIT, 0);
+
+/// BPF map with host paths which custom policies of containers allow to bind
+/// mount. Only containers with such a policy have an entry, so the map is
+/// not preallocated.
+#[map]
+pub(crate) static mut CUSTOM_MOUNTS: HashMap<ContainerID, CustomMounts> =
+    HashMap::pinned(PID_MAX_LIMIT, BPF_F_NO_PREALLOC);
+
+/// BPF map which maps cgroup IDs to containers, filled by userspace when the
+/// init process of a container is registered. It attributes processes to
+/// containers by their cgroup when they are missing in `PROCESSES`.
+#[map]
+pub(crate) static mut CONTAINER_CGROUPS: HashMap<u64, ContainerID> =
+    HashMap::pinned(PID_MAX_LIMIT, 0);
+
 #[map]
 pub(crate) static mut CONTAINER_INITIAL_SETUID: HashMap<ContainerID, bool> =
     HashMap::with_max_entries(PID_MAX_LIMIT, 0);
@@ -24,5 +56,164 @@ pub(crate) static mut CONTAINER_INITIAL_SETUID: HashMap<ContainerID, bool> =
 #[map]
 pub(crate) static mut MOUNT_TYPE_BUF: PerCpuArray<MountType> = PerCpuArray::with_max_entries(1, 0);
 
+/// Buffer for keys of `PATH_PREFIXES`. Paths are read directly into it, so
+/// they don't have to be copied before the lookup.
+#[map]
+pub(crate) static mut PATH_KEY_BUF: PerCpuArray<Key<PathPrefix>> =
+    PerCpuArray::with_max_entries(1, 0);
+
+/// BPF map with path prefixes allowed to be bind mounted into containers and
+/// allowed or denied to be opened in them, for all policy levels. Classes of
+/// prefixes are distinguished by the first byte of the key.
+#[map]
+pub(crate) static mut PATH_PREFIXES: LpmTrie<PathPrefix, FilePermission> =
+    LpmTrie::pinned(PATH_MAX_LIMIT * 4, BPF_F_NO_PREALLOC);
+
+/// BPF map with inodes of path prefixes allowed to be bind mounted and
+/// allowed or denied to be opened in containers. Files are matched by inodes
+/// of their parent directories, so bind mounts of a denied directory are
+/// denied as well.
+#[map]
+pub(crate) static mut INODE_PREFIXES: HashMap<InodePrefix, InodeInfo> =
+    HashMap::pinned(PATH_MAX_LIMIT * 4, 0);
+
+/// BPF map with responses to opening files under denied inodes of
+/// `INODE_PREFIXES`. Inodes without an entry are denied.
+#[map]
+pub(crate) static mut DENY_RESPONSES: HashMap<InodePrefix, DenyResponse> =
+    HashMap::pinned(PATH_MAX_LIMIT * 2, 0);
+
+/// BPF map with hashes of command names of host processes which are never
+/// attributed to containers, even when their parent is containerized.
+#[map]
+pub(crate) static mut EXCLUDED_COMMS: HashMap<u32, bool> = HashMap::pinned(EXCLUDED_MAX, 0);
+
+/// BPF map with inodes of host executables whose processes are never
+/// attributed to containers, filled by userspace from the settings.
+#[map]
+pub(crate) static mut EXCLUDED_EXECUTABLES: HashMap<InodeId, bool> =
+    HashMap::pinned(EXCLUDED_MAX, 0);
+
+/// BPF map with inodes of pinned eBPF objects of lockc and of their
+/// directory, which containers can't access. Filled by userspace on start.
+#[map]
+pub(crate) static mut PROTECTED_INODES: HashMap<InodeId, bool> = HashMap::pinned(PROTECTED_MAX, 0);
+
+/// BPF map with inode numbers of masked paths of procfs, with bits of policy
+/// levels of containers which can't unmask them. Filled by userspace from
+/// the settings. Static entries of procfs have the same inode numbers in all
+/// its instances, so devices are not compared.
+#[map]
+pub(crate) static mut MASKED_PROC_INODES: HashMap<u64, u8> = HashMap::pinned(MASKED_PROC_MAX, 0);
+
+/// BPF map with IPv4 networks which containers with restricted egress can't
+/// connect to, filled by userspace from the settings. Keys are addresses in
+/// network byte order.
+#[map]
+pub(crate) static mut EGRESS_DENY_V4: LpmTrie<[u8; 4], u8> =
+    LpmTrie::with_max_entries(EGRESS_DENY_MAX, BPF_F_NO_PREALLOC);
+
+/// BPF map with IPv6 networks which containers with restricted egress can't
+/// connect to, filled by userspace from the settings.
+#[map]
+pub(crate) static mut EGRESS_DENY_V6: LpmTrie<[u8; 16], u8> =
+    LpmTrie::with_max_entries(EGRESS_DENY_MAX, BPF_F_NO_PREALLOC);
+
+/// Buffer for violation events, which are too large for the stack of
+/// programs reading paths.
+#[map]
+pub(crate) static mut VIOLATION_BUF: PerCpuArray<Violation> = PerCpuArray::with_max_entries(1, 0);
+
+/// Rate limits of violation events per container and hook. Each CPU has its
+/// own bucket, so they are updated without locking.
+#[map]
+pub(crate) static mut VIOLATION_LIMITS: LruPerCpuHashMap<ViolationKey, ViolationLimit> =
+    LruPerCpuHashMap::with_max_entries(VIOLATION_LIMITS_MAX, 0);
+
+/// Events about denied operations, read by userspace.
 #[map]
-pub(crate) static mut PATH_BUF: PerCpuArray<Path> = PerCpuArray::with_max_entries(1, 0);
+pub(crate) static mut VIOLATIONS: PerfEventArray<Violation> = PerfEventArray::new(0);
+
+/// Buffer for keys of `LEARNED_MOUNTS`, which are too large for the stack
+/// of the mount program.
+#[map]
+pub(crate) static mut LEARNED_MOUNT_BUF: PerCpuArray<LearnedMount> =
+    PerCpuArray::with_max_entries(1, 0);
+
+/// Bind mounts which would be denied, with the number of attempts, recorded
+/// in learning mode. Userspace collects and removes the entries
+/// periodically.
+#[map]
+pub(crate) static mut LEARNED_MOUNTS: HashMap<LearnedMount, u64> =
+    HashMap::with_max_entries(LEARNED_MOUNTS_MAX, 0);
+
+/// Capabilities used by containers, with the number of uses, recorded for
+/// workload fingerprints. Userspace collects and removes the entries
+/// periodically.
+#[map]
+pub(crate) static mut LEARNED_CAPABILITIES: HashMap<LearnedCapability, u64> =
+    HashMap::with_max_entries(LEARNED_CAPABILITIES_MAX, 0);
+
+/// Per-CPU counters of failed map operations, indexed by `MapOperation`.
+#[map]
+pub(crate) static mut MAP_ERRORS: PerCpuArray<u64> =
+    PerCpuArray::with_max_entries(MAP_OPERATIONS_LEN, 0);
+
+/// Per-CPU counters of invocations and decisions of LSM programs, indexed
+/// by `Program`.
+#[map]
+pub(crate) static mut PROGRAM_STATS: PerCpuArray<ProgramStats> =
+    PerCpuArray::with_max_entries(PROGRAMS_LEN, 0);
+
+/// Events about failed map operations, read by userspace.
+#[map]
+pub(crate) static mut MAP_ERROR_EVENTS: PerfEventArray<MapErrorEvent> = PerfEventArray::new(0);
+
+/// Events about containerized processes, sent only in trace mode.
+#[map]
+pub(crate) static mut PROCESS_EVENTS: PerfEventArray<ProcessEvent> = PerfEventArray::new(0);
+
+/// Path prefixes stored in `PATH_PREFIXES`, looked up with the key in
+/// `PATH_KEY_BUF`.
+pub(crate) struct MapPathLists {
+    key: *mut Key<PathPrefix>,
+}
+
+impl MapPathLists {
+    /// Returns the path lists with the key buffer, which the caller reads the
+    /// checked path into.
+    #[inline(always)]
+    pub(crate) fn new() -> Option<Self> {
+        let key = unsafe { PATH_KEY_BUF.get_ptr_mut(0)? };
+        Some(MapPathLists { key })
+    }
+
+    /// Returns the buffer the checked path has to be read into.
+    #[inline(always)]
+    pub(crate) fn path_buf(&self) -> *mut [u8; PATH_LEN] {
+        unsafe { core::ptr::addr_of_mut!((*self.key).data.path) }
+    }
+}
+
+impl PathLists for MapPathLists {
+    /// Looks up the longest prefix of the path, which is expected to be in
+    /// the buffer returned by [`MapPathLists::path_buf`] already.
+    #[inline(always)]
+    fn lookup(&self, class: PathClass, path: &[u8]) -> Option<FilePermission> {
+        unsafe {
+            (*self.key).prefix_len = PathPrefix::prefix_len(path.len());
+            (*self.key).data.class = class as u8;
+            PATH_PREFIXES.get(&*self.key).copied()
+        }
+    }
+}
+
+/// Containers of processes stored in `PROCESSES`.
+pub(crate) struct MapProcesses;
+
+impl ProcessContainers for MapProcesses {
+    #[inline(always)]
+    fn lookup(&self, tgid: i32) -> Option<ContainerID> {
+        unsafe { PROCESSES.get(&tgid) }.map(|process| process.container_id)
+    }
+}
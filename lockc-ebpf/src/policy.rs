@@ -1,6 +1,6 @@
 use aya_bpf::helpers::bpf_get_current_pid_tgid;
 
-use lockc_common::{ContainerID, ContainerPolicyLevel};
+use lockc_common::{ContainerID, ContainerPolicyLevel, Enforcement, PATH_LEN};
 
 use crate::maps::*;
 
@@ -13,8 +13,10 @@ use crate::maps::*;
 #[inline(always)]
 pub(crate) fn get_container_and_policy_level(
 ) -> Result<(Option<ContainerID>, ContainerPolicyLevel), i32> {
-    let pid = bpf_get_current_pid_tgid() as u32;
-    let process_o = unsafe { PROCESSES.get(&(pid as i32)) };
+    // Processes are registered by their thread-group ID, shared by all
+    // their threads.
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    let process_o = unsafe { PROCESSES.get(&(tgid as i32)) };
     match process_o {
         Some(process) => {
             let container_o = unsafe { CONTAINERS.get(&process.container_id) };
@@ -26,3 +28,48 @@ pub(crate) fn get_container_and_policy_level(
         None => Ok((None, ContainerPolicyLevel::NotFound)),
     }
 }
+
+/// Returns whether the container runs in a user namespace without access to
+/// root of the host. Containers without a stored spec are not rootless.
+#[inline(always)]
+pub(crate) fn rootless(container_id: &ContainerID) -> bool {
+    unsafe { CONTAINER_SPECS.get(container_id) }
+        .map(|spec| spec.rootless)
+        .unwrap_or(false)
+}
+
+/// Returns whether the custom policy of the container allows to bind mount
+/// the path.
+#[inline(always)]
+pub(crate) fn custom_mount_allowed(container_id: &ContainerID, path: &[u8; PATH_LEN]) -> bool {
+    unsafe { CUSTOM_MOUNTS.get(container_id) }
+        .map(|custom_mounts| custom_mounts.allows(path))
+        .unwrap_or(false)
+}
+
+/// Returns whether the container is exempted from denials of connecting to
+/// sockets under denied paths.
+#[inline(always)]
+pub(crate) fn runtime_sockets_allowed(container_id: &ContainerID) -> bool {
+    unsafe { CONTAINER_SPECS.get(container_id) }
+        .map(|spec| spec.runtime_sockets)
+        .unwrap_or(false)
+}
+
+/// Returns whether the container is exempted from denials of reading the
+/// kernel log.
+#[inline(always)]
+pub(crate) fn syslog_allowed(container_id: &ContainerID) -> bool {
+    unsafe { CONTAINERS.get(container_id) }
+        .map(|container| container.syslog)
+        .unwrap_or(false)
+}
+
+/// Returns whether denials are enforced in the container or only reported.
+/// Containers which are not registered anymore are enforced.
+#[inline(always)]
+pub(crate) fn enforcement(container_id: &ContainerID) -> Enforcement {
+    unsafe { CONTAINERS.get(container_id) }
+        .map(|container| container.enforcement)
+        .unwrap_or(Enforcement::Enforce)
+}
@@ -1,33 +1,75 @@
-use aya_bpf::{macros::btf_tracepoint, programs::BtfTracePointContext};
+use aya_bpf::{
+    helpers::bpf_probe_read_kernel, macros::btf_tracepoint, programs::BtfTracePointContext,
+    BpfContext,
+};
 use aya_log_ebpf::debug;
 
-use lockc_common::Process;
+use lockc_common::{
+    attribution::{new_task_container, TaskIds},
+    comm_hash, InodeId, MapOperation, Process, ProcessEventKind,
+};
 
-use crate::{maps::*, vmlinux::task_struct};
+use crate::{
+    errors::report_map_error,
+    maps::*,
+    task_ext::Task,
+    trace,
+    vmlinux::{linux_binprm, task_struct},
+};
 
 /// Monitors all new tasks/functions created in the system and checks whether
 /// it's a child of some already containerized process (either the container
-/// runtime or any of its children)
-/// In any other case, it does not do anything.
+/// runtime or any of its children).
+/// In any other case, it does not do anything. New threads share the entry of
+/// their process, so they are not registered. Excluded host processes are
+/// never registered, and are removed when they were registered before they
+/// executed the excluded binary.
 ///
 /// # Arguments
 ///
-/// * `ppid` - PID of the parent task
-/// * `child` - PID of the new task
+/// * `kind` - tracepoint which found the task, reported in trace mode
+/// * `task` - the new task
+/// * `parent` - the task which forked it, or its real parent on exec
+/// * `executable` - inode of the executed binary, on exec
 #[inline]
-fn handle_new_process(ctx: BtfTracePointContext, ppid: i32, pid: i32) -> Result<i32, i32> {
+fn handle_new_process(
+    ctx: BtfTracePointContext,
+    kind: ProcessEventKind,
+    task: TaskIds,
+    parent: TaskIds,
+    executable: Option<InodeId>,
+) -> Result<i32, i32> {
+    if task.is_thread() {
+        return Ok(0);
+    }
+    let (pid, ppid) = (task.tgid, parent.tgid);
+
+    let container_id = new_task_container(&MapProcesses, task, parent);
+    let registered = unsafe { PROCESSES.get(&pid) };
+    // Exclusions are checked only for processes which would be attributed to
+    // a container, to not slow down the others.
+    if (container_id.is_some() || registered.is_some()) && is_excluded(&ctx, executable) {
+        if registered.is_some() {
+            debug!(
+                &ctx,
+                "excluded process removed from its container: pid: {}", pid
+            );
+            let _ = unsafe { PROCESSES.remove(&pid) };
+        }
+        return Ok(0);
+    }
+
     // Check if parent process is containerized (already registeed in BPF map).
     // If not, don't do anything.
-    if let Some(parent) = unsafe { PROCESSES.get(&ppid) } {
+    if let Some(container_id) = container_id {
         // Check if child process is already registered. If yes, don't do
         // anything.
-        let child_lookup = unsafe { PROCESSES.get(&pid) };
-        if child_lookup.is_some() {
+        if let Some(child) = registered {
+            trace::report(&ctx, kind, child.container_id, pid, ppid);
             return Ok(0);
         }
 
         // Register a new process.
-        let container_id = parent.container_id;
         debug!(
             &ctx,
             "new containerized process: pid: {}, container_id: {}",
@@ -35,12 +77,51 @@ fn handle_new_process(ctx: BtfTracePointContext, ppid: i32, pid: i32) -> Result<
             unsafe { container_id.as_str() }
         );
         let child = Process { container_id };
-        unsafe { PROCESSES.insert(&pid, &child, 0).map_err(|e| e as i32)? };
+        if let Err(e) = unsafe { PROCESSES.insert(&pid, &child, 0) } {
+            report_map_error(&ctx, MapOperation::ProcessInsert, e);
+            return Err(e as i32);
+        }
+        trace::report(&ctx, kind, container_id, pid, ppid);
     }
 
     Ok(0)
 }
 
+/// Checks whether the current task is excluded from attribution to
+/// containers, by its command name or by the inode of the executed binary.
+#[inline(always)]
+fn is_excluded(ctx: &BtfTracePointContext, executable: Option<InodeId>) -> bool {
+    let comm = match ctx.command() {
+        Ok(comm) => unsafe { EXCLUDED_COMMS.get(&comm_hash(&comm)) }.is_some(),
+        Err(_) => false,
+    };
+    comm || executable.is_some_and(|inode| unsafe { EXCLUDED_EXECUTABLES.get(&inode) }.is_some())
+}
+
+/// Returns the inode of the binary executed with `bprm`. For scripts, it's
+/// the inode of the interpreter.
+#[inline(always)]
+fn executable_inode(bprm: *const linux_binprm) -> Option<InodeId> {
+    if bprm.is_null() {
+        return None;
+    }
+    unsafe {
+        let file = bpf_probe_read_kernel(&(*bprm).file).ok()?;
+        if file.is_null() {
+            return None;
+        }
+        let inode = bpf_probe_read_kernel(&(*file).f_inode).ok()?;
+        if inode.is_null() {
+            return None;
+        }
+        let sb = bpf_probe_read_kernel(&(*inode).i_sb).ok()?;
+        Some(InodeId {
+            i_ino: bpf_probe_read_kernel(&(*inode).i_ino).ok()? as u64,
+            s_dev: bpf_probe_read_kernel(&(*sb).s_dev).ok()? as u64,
+        })
+    }
+}
+
 /// Tracepoint program triggered by forking a process.
 ///
 /// It's used to find a potential new runc process.
@@ -53,13 +134,13 @@ pub fn sched_process_fork(ctx: BtfTracePointContext) -> i32 {
 }
 
 fn try_sched_process_fork(ctx: BtfTracePointContext) -> Result<i32, i32> {
-    let parent_task: *const task_struct = unsafe { ctx.arg(0) };
-    let child_task: *const task_struct = unsafe { ctx.arg(1) };
+    let parent_task = Task::new(unsafe { ctx.arg::<*const task_struct>(0) }).ok_or(0)?;
+    let child_task = Task::new(unsafe { ctx.arg::<*const task_struct>(1) }).ok_or(0)?;
 
-    let ppid = unsafe { (*parent_task).pid };
-    let pid = unsafe { (*child_task).pid };
+    let parent = parent_task.ids().ok_or(0)?;
+    let child = child_task.ids().ok_or(0)?;
 
-    handle_new_process(ctx, ppid, pid)
+    handle_new_process(ctx, ProcessEventKind::Fork, child, parent, None)
 }
 
 /// Tracepoint program triggered by running a new proccess with a binary
@@ -75,12 +156,19 @@ pub fn sched_process_exec(ctx: BtfTracePointContext) -> i32 {
 }
 
 fn try_sched_process_exec(ctx: BtfTracePointContext) -> Result<i32, i32> {
-    let task: *const task_struct = unsafe { ctx.arg(0) };
+    let task = Task::new(unsafe { ctx.arg::<*const task_struct>(0) }).ok_or(0)?;
 
-    let ppid = unsafe { (*(*task).parent).pid };
-    let pid = unsafe { (*task).pid };
+    // `parent` is the tracer when the task is ptraced, which would move the
+    // task to the container of a debugger.
+    let parent = task
+        .real_parent()
+        .and_then(|parent| parent.ids())
+        .ok_or(0)?;
+    let ids = task.ids().ok_or(0)?;
 
-    handle_new_process(ctx, ppid, pid)
+    let executable = executable_inode(unsafe { ctx.arg::<*const linux_binprm>(2) });
+
+    handle_new_process(ctx, ProcessEventKind::Exec, ids, parent, executable)
 }
 
 /// Tracepoint program triggered by a process exiting.
@@ -96,9 +184,31 @@ pub fn sched_process_exit(ctx: BtfTracePointContext) -> i32 {
 }
 
 fn try_sched_process_exit(ctx: BtfTracePointContext) -> Result<i32, i32> {
-    let task: *const task_struct = unsafe { ctx.arg(0) };
+    let task = Task::new(unsafe { ctx.arg::<*const task_struct>(0) }).ok_or(0)?;
 
-    let pid = unsafe { (*task).pid };
+    // Exits of single threads don't remove the process, which keeps running
+    // with its other threads.
+    if !task.group_dead().ok_or(0)? {
+        return Ok(0);
+    }
+    let pid = task.ids().ok_or(0)?.tgid;
+
+    if let Some(process) = unsafe { PROCESSES.get(&pid) } {
+        // The parent is only reported, an exiting task can be reparented
+        // already.
+        let ppid = task
+            .real_parent()
+            .and_then(|parent| parent.ids())
+            .map(|parent| parent.tgid)
+            .unwrap_or_default();
+        trace::report(
+            &ctx,
+            ProcessEventKind::Exit,
+            process.container_id,
+            pid,
+            ppid,
+        );
+    }
 
     unsafe { PROCESSES.remove(&pid).map_err(|e| e as i32)? };
 
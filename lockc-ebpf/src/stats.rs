@@ -0,0 +1,19 @@
+use lockc_common::Program;
+
+use crate::maps::PROGRAM_STATS;
+
+/// Counts the invocation of the program and its decision, given by its
+/// return value.
+#[inline(always)]
+pub(crate) fn count(program: Program, ret: i32) -> i32 {
+    if let Some(stats) = unsafe { PROGRAM_STATS.get_ptr_mut(program as u32) } {
+        let stats = unsafe { &mut *stats };
+        stats.invocations += 1;
+        if ret == 0 {
+            stats.allows += 1;
+        } else {
+            stats.denies += 1;
+        }
+    }
+    ret
+}
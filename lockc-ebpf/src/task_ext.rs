@@ -0,0 +1,103 @@
+//! Accessors of `task_struct` fields. Tasks passed to tracepoints are never
+//! dereferenced directly, fields are read with `bpf_probe_read_kernel`, so a
+//! bad pointer (e.g. a parent which already exited) fails the read instead
+//! of the program. rustc doesn't emit CO-RE relocations yet, so offsets of
+//! fields come from `vmlinux.rs` (`cargo xtask codegen`) and the accessors
+//! are the only place to adjust when the layout of `task_struct` changes.
+
+use aya_bpf::helpers::{bpf_get_current_task, bpf_probe_read_kernel};
+
+use lockc_common::attribution::TaskIds;
+
+use crate::vmlinux::{mnt_namespace, net, nsproxy, task_struct};
+
+/// Pointer to a task, checked to be non-null.
+#[derive(Clone, Copy)]
+pub(crate) struct Task(*const task_struct);
+
+impl Task {
+    #[inline(always)]
+    pub(crate) fn new(task: *const task_struct) -> Option<Self> {
+        (!task.is_null()).then_some(Task(task))
+    }
+
+    /// Task which the program runs in the context of.
+    #[inline(always)]
+    pub(crate) fn current() -> Option<Self> {
+        Task::new(unsafe { bpf_get_current_task() } as *const task_struct)
+    }
+
+    /// PID of the task (the thread ID in userspace).
+    #[inline(always)]
+    pub(crate) fn pid(&self) -> Option<i32> {
+        unsafe { bpf_probe_read_kernel(&(*self.0).pid) }.ok()
+    }
+
+    /// PID of the thread-group leader of the task (the PID in userspace).
+    #[inline(always)]
+    pub(crate) fn tgid(&self) -> Option<i32> {
+        unsafe { bpf_probe_read_kernel(&(*self.0).tgid) }.ok()
+    }
+
+    #[inline(always)]
+    pub(crate) fn ids(&self) -> Option<TaskIds> {
+        Some(TaskIds {
+            pid: self.pid()?,
+            tgid: self.tgid()?,
+        })
+    }
+
+    /// Returns whether the task is the last live thread of its process. The
+    /// kernel decrements the number of live threads before the
+    /// `sched_process_exit` tracepoint, so it's zero in the exit of the last
+    /// thread. When it can't be read, only the exit of the thread-group
+    /// leader counts as the exit of the process.
+    #[inline(always)]
+    pub(crate) fn group_dead(&self) -> Option<bool> {
+        let live = unsafe { bpf_probe_read_kernel(&(*self.0).signal) }
+            .ok()
+            .filter(|signal| !signal.is_null())
+            .and_then(|signal| unsafe { bpf_probe_read_kernel(&(*signal).live.counter) }.ok());
+        match live {
+            Some(live) => Some(live == 0),
+            None => Some(!self.ids()?.is_thread()),
+        }
+    }
+
+    /// Task which receives `SIGCHLD` of this task. It's the tracer when the
+    /// task is ptraced.
+    #[inline(always)]
+    pub(crate) fn parent(&self) -> Option<Task> {
+        Task::new(unsafe { bpf_probe_read_kernel(&(*self.0).parent) }.ok()?)
+    }
+
+    /// Task which forked this task, or the reaper it was reparented to. Falls
+    /// back to `parent`, which is the same task unless this task is ptraced,
+    /// when `real_parent` can't be read.
+    #[inline(always)]
+    pub(crate) fn real_parent(&self) -> Option<Task> {
+        unsafe { bpf_probe_read_kernel(&(*self.0).real_parent) }
+            .ok()
+            .and_then(|task| Task::new(task))
+            .or_else(|| self.parent())
+    }
+
+    #[inline(always)]
+    fn nsproxy(&self) -> Option<*mut nsproxy> {
+        unsafe { bpf_probe_read_kernel(&(*self.0).nsproxy) }
+            .ok()
+            .filter(|nsproxy| !nsproxy.is_null())
+    }
+
+    /// Network namespace of the task.
+    #[inline(always)]
+    pub(crate) fn net_ns(&self) -> Option<*mut net> {
+        unsafe { bpf_probe_read_kernel(&(*self.nsproxy()?).net_ns) }.ok()
+    }
+
+    /// Mount namespace of the task.
+    #[inline(always)]
+    pub(crate) fn mnt_ns(&self) -> Option<*mut mnt_namespace> {
+        unsafe { bpf_probe_read_kernel(&(*self.nsproxy()?).mnt_ns) }.ok()
+    }
+}
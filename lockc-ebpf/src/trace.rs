@@ -0,0 +1,29 @@
+use aya_bpf::BpfContext;
+
+use lockc_common::{ContainerID, ProcessEvent, ProcessEventKind, TASK_COMM_LEN};
+
+use crate::maps::PROCESS_EVENTS;
+
+/// Whether the sched tracepoints send events about containerized processes
+/// to userspace. Set by userspace when loading the program.
+#[no_mangle]
+static TRACE_PROCESSES: u8 = 0;
+
+/// Sends an event about the containerized process to userspace, if trace
+/// mode is enabled. The command name is taken from the current task, which
+/// for forks is the parent, with the same name as the child.
+#[inline(always)]
+pub(crate) fn report<C: BpfContext>(
+    ctx: &C,
+    kind: ProcessEventKind,
+    container_id: ContainerID,
+    pid: i32,
+    ppid: i32,
+) {
+    if unsafe { core::ptr::read_volatile(&TRACE_PROCESSES) } == 0 {
+        return;
+    }
+    let comm = ctx.command().unwrap_or([0; TASK_COMM_LEN]);
+    let event = ProcessEvent::new(kind, container_id, pid, ppid, comm);
+    unsafe { PROCESS_EVENTS.output(ctx, &event, 0) };
+}
@@ -0,0 +1,108 @@
+use aya_bpf::{
+    helpers::{bpf_ktime_get_ns, bpf_probe_read_kernel_str_bytes, bpf_send_signal},
+    BpfContext,
+};
+
+use lockc_common::{ContainerID, DenyResponse, Enforcement, Hook, ViolationKey, ViolationLimit};
+
+use crate::{
+    maps::{VIOLATIONS, VIOLATION_BUF, VIOLATION_LIMITS},
+    policy,
+};
+
+/// Signal sent to processes whose denied operation has the kill response.
+const SIGKILL: u32 = 9;
+
+/// Sends an event about the operation denied by the policy to userspace. The
+/// detail (path or mount source) is copied, if given. Events above the rate
+/// limit of the container and hook are only counted. Failures are ignored,
+/// they must not change the verdict. Returns whether the denial is enforced,
+/// `false` if the container is in the complain mode.
+#[inline(always)]
+pub(crate) fn report<C: BpfContext>(
+    ctx: &C,
+    container_id: ContainerID,
+    hook: Hook,
+    detail: Option<&str>,
+) -> bool {
+    let enforcement = policy::enforcement(&container_id);
+    output(ctx, container_id, hook, enforcement, false, detail);
+    enforcement == Enforcement::Enforce
+}
+
+/// Sends an event about the operation denied by the policy, applying the
+/// response configured for the denied path. Audited operations are reported
+/// as complained and allowed. Processes of enforced containers are killed
+/// with `SIGKILL` if the response says so. Returns whether the denial is
+/// enforced and whether the process was killed.
+#[inline(always)]
+pub(crate) fn report_with_response<C: BpfContext>(
+    ctx: &C,
+    container_id: ContainerID,
+    hook: Hook,
+    response: DenyResponse,
+) -> (bool, bool) {
+    let enforcement = match response {
+        DenyResponse::Audit => Enforcement::Complain,
+        DenyResponse::Deny | DenyResponse::Kill => policy::enforcement(&container_id),
+    };
+    let enforced = enforcement == Enforcement::Enforce;
+    let killed =
+        enforced && response == DenyResponse::Kill && unsafe { bpf_send_signal(SIGKILL) } == 0;
+    output(ctx, container_id, hook, enforcement, killed, None);
+    (enforced, killed)
+}
+
+/// Sends an event about an operation which is denied regardless of the
+/// enforcement mode of the container.
+#[inline(always)]
+pub(crate) fn report_enforced<C: BpfContext>(ctx: &C, container_id: ContainerID, hook: Hook) {
+    output(ctx, container_id, hook, Enforcement::Enforce, false, None);
+}
+
+/// Takes a token from the rate limit of the container and hook. Returns the
+/// number of violations suppressed since the last event if the event can be
+/// sent, `None` otherwise. Events are sent when the limit can't be tracked.
+#[inline(always)]
+fn rate_limit(container_id: ContainerID, hook: Hook) -> Option<u32> {
+    let key = ViolationKey::new(container_id, hook);
+    let now = bpf_ktime_get_ns();
+    match unsafe { VIOLATION_LIMITS.get_ptr_mut(&key) } {
+        Some(limit) => unsafe { &mut *limit }.take(now),
+        None => {
+            let _ = unsafe { VIOLATION_LIMITS.insert(&key, &ViolationLimit::new(now), 0) };
+            Some(0)
+        }
+    }
+}
+
+#[inline(always)]
+fn output<C: BpfContext>(
+    ctx: &C,
+    container_id: ContainerID,
+    hook: Hook,
+    enforcement: Enforcement,
+    killed: bool,
+    detail: Option<&str>,
+) {
+    let suppressed = match rate_limit(container_id, hook) {
+        Some(suppressed) => suppressed,
+        None => return,
+    };
+    if let Some(violation) = unsafe { VIOLATION_BUF.get_ptr_mut(0) } {
+        let violation = unsafe { &mut *violation };
+        violation.set(
+            container_id,
+            ctx.tgid(),
+            hook,
+            enforcement,
+            killed,
+            suppressed,
+        );
+        if let Some(detail) = detail {
+            let _ =
+                unsafe { bpf_probe_read_kernel_str_bytes(detail.as_ptr(), &mut violation.detail) };
+        }
+        unsafe { VIOLATIONS.output(ctx, violation, 0) };
+    }
+}
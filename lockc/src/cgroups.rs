@@ -0,0 +1,254 @@
+//! Cgroups of containers. Container runtimes put all processes of a container
+//! into a cgroup of its own, so processes can be attributed to containers by
+//! their cgroup even when lockc lost track of them in the process tree (e.g.
+//! after a restart of lockc or when processes are reparented).
+
+use std::{
+    io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use procfs::{process::Process, ProcError, ProcessCgroup};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Mount point of the cgroup filesystem.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// cgroup v1 hierarchies which container cgroups are looked up in, in order.
+/// Docker and containerd create the cgroup of a container in all of them.
+const V1_CONTROLLERS: &[&str] = &["pids", "memory", "name=systemd"];
+
+#[derive(Error, Debug)]
+pub enum CgroupError {
+    #[error(transparent)]
+    Proc(#[from] ProcError),
+
+    #[error("could not get the inode of cgroup {path}: {source}")]
+    Inode {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("process {pid} is not in a cgroup of container {container_id}")]
+    NotFound { pid: i32, container_id: String },
+}
+
+/// Layout of cgroup hierarchies mounted on the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CgroupLayout {
+    /// Only cgroup v2, mounted on `/sys/fs/cgroup`.
+    Unified,
+    /// cgroup v1 controllers with cgroup v2 mounted on
+    /// `/sys/fs/cgroup/unified`.
+    Hybrid,
+    /// Only cgroup v1 controllers.
+    Legacy,
+}
+
+impl CgroupLayout {
+    /// Detects the layout from the files of the cgroup filesystem, which
+    /// only the root of cgroup v2 has.
+    pub fn detect(root: &Path) -> Self {
+        if root.join("cgroup.controllers").exists() {
+            CgroupLayout::Unified
+        } else if root.join("unified").join("cgroup.controllers").exists() {
+            CgroupLayout::Hybrid
+        } else {
+            CgroupLayout::Legacy
+        }
+    }
+}
+
+/// Cgroup of a container, resolved when its first process is registered.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContainerCgroup {
+    /// Path of the cgroup directory.
+    pub path: PathBuf,
+    /// Inode number of the cgroup directory. On cgroup v2, it's the cgroup
+    /// ID returned by `bpf_get_current_cgroup_id`.
+    pub id: u64,
+}
+
+/// Returns whether the cgroup is the one of the container. Docker and
+/// containerd name the cgroup after the full container ID, with the cgroupfs
+/// driver (`/docker/<id>`, `/kubepods/.../<id>`) or as a systemd scope
+/// (`docker-<id>.scope`, `cri-containerd-<id>.scope`). Cgroups shared by
+/// multiple containers (e.g. of a pod) don't match.
+fn is_container_cgroup(pathname: &str, container_id: &str) -> bool {
+    Path::new(pathname)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains(container_id))
+}
+
+/// Returns the directory of the container cgroup among the cgroups of its
+/// process. The cgroup v2 hierarchy is preferred, as only its cgroup IDs
+/// are known to eBPF programs.
+fn cgroup_dir(
+    root: &Path,
+    layout: CgroupLayout,
+    cgroups: &[ProcessCgroup],
+    container_id: &str,
+) -> Option<PathBuf> {
+    let unified = match layout {
+        CgroupLayout::Unified => Some(root.to_path_buf()),
+        CgroupLayout::Hybrid => Some(root.join("unified")),
+        CgroupLayout::Legacy => None,
+    };
+    let v2 = unified.and_then(|mount| {
+        cgroups
+            .iter()
+            .find(|cgroup| cgroup.hierarchy == 0)
+            .map(|cgroup| (mount, cgroup))
+    });
+    let v1 = V1_CONTROLLERS.iter().filter_map(|controller| {
+        cgroups
+            .iter()
+            .find(|cgroup| {
+                cgroup.hierarchy != 0 && cgroup.controllers.iter().any(|c| c == controller)
+            })
+            .map(|cgroup| {
+                let mount = cgroup.controllers.join(",");
+                (root.join(mount.trim_start_matches("name=")), cgroup)
+            })
+    });
+    v2.into_iter()
+        .chain(v1)
+        .find(|(_, cgroup)| is_container_cgroup(&cgroup.pathname, container_id))
+        .map(|(mount, cgroup)| mount.join(cgroup.pathname.trim_start_matches('/')))
+}
+
+/// Resolves the cgroup of the container from the cgroups of its process.
+pub fn resolve(pid: i32, container_id: &str) -> Result<ContainerCgroup, CgroupError> {
+    let root = Path::new(CGROUP_ROOT);
+    let cgroups = Process::new(pid)?.cgroups()?;
+    let path =
+        cgroup_dir(root, CgroupLayout::detect(root), &cgroups, container_id).ok_or_else(|| {
+            CgroupError::NotFound {
+                pid,
+                container_id: container_id.to_string(),
+            }
+        })?;
+    let id = path
+        .metadata()
+        .map_err(|source| CgroupError::Inode {
+            path: path.clone(),
+            source,
+        })?
+        .ino();
+    Ok(ContainerCgroup { path, id })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "5833851e673d45fab4d12105bf61c3f4892b2bbf9c12d811db509a4f22475ec9";
+
+    fn cgroup(hierarchy: u32, controllers: &str, pathname: &str) -> ProcessCgroup {
+        ProcessCgroup {
+            hierarchy,
+            controllers: controllers
+                .split(',')
+                .filter(|c| !c.is_empty())
+                .map(String::from)
+                .collect(),
+            pathname: pathname.to_string(),
+        }
+    }
+
+    #[test]
+    fn cgroup_dir_unified() {
+        let root = Path::new(CGROUP_ROOT);
+        let docker = [cgroup(0, "", &format!("/system.slice/docker-{}.scope", ID))];
+        assert_eq!(
+            cgroup_dir(root, CgroupLayout::Unified, &docker, ID),
+            Some(PathBuf::from(format!(
+                "/sys/fs/cgroup/system.slice/docker-{}.scope",
+                ID
+            )))
+        );
+
+        let containerd = [cgroup(
+            0,
+            "",
+            &format!(
+                "/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1.slice/cri-containerd-{}.scope",
+                ID
+            ),
+        )];
+        assert!(cgroup_dir(root, CgroupLayout::Unified, &containerd, ID).is_some());
+
+        // The pod cgroup is not the cgroup of the container.
+        let pod = [cgroup(0, "", "/kubepods/besteffort/pod1")];
+        assert_eq!(cgroup_dir(root, CgroupLayout::Unified, &pod, ID), None);
+    }
+
+    #[test]
+    fn cgroup_dir_legacy() {
+        let root = Path::new(CGROUP_ROOT);
+        let cgroups = [
+            cgroup(12, "cpu,cpuacct", &format!("/docker/{}", ID)),
+            cgroup(5, "memory", &format!("/docker/{}", ID)),
+            cgroup(3, "pids", &format!("/docker/{}", ID)),
+            cgroup(1, "name=systemd", &format!("/docker/{}", ID)),
+        ];
+        assert_eq!(
+            cgroup_dir(root, CgroupLayout::Legacy, &cgroups, ID),
+            Some(PathBuf::from(format!("/sys/fs/cgroup/pids/docker/{}", ID)))
+        );
+
+        let cgroups = [cgroup(1, "name=systemd", &format!("/docker/{}", ID))];
+        assert_eq!(
+            cgroup_dir(root, CgroupLayout::Legacy, &cgroups, ID),
+            Some(PathBuf::from(format!(
+                "/sys/fs/cgroup/systemd/docker/{}",
+                ID
+            )))
+        );
+    }
+
+    #[test]
+    fn cgroup_dir_hybrid() {
+        let root = Path::new(CGROUP_ROOT);
+        // With the cgroupfs driver, runc doesn't move the container in the
+        // unified hierarchy, so its v1 cgroup is used.
+        let cgroups = [
+            cgroup(0, "", "/system.slice/containerd.service"),
+            cgroup(3, "pids", &format!("/kubepods/besteffort/pod1/{}", ID)),
+        ];
+        assert_eq!(
+            cgroup_dir(root, CgroupLayout::Hybrid, &cgroups, ID),
+            Some(PathBuf::from(format!(
+                "/sys/fs/cgroup/pids/kubepods/besteffort/pod1/{}",
+                ID
+            )))
+        );
+
+        let cgroups = [
+            cgroup(0, "", &format!("/system.slice/docker-{}.scope", ID)),
+            cgroup(3, "pids", &format!("/system.slice/docker-{}.scope", ID)),
+        ];
+        assert_eq!(
+            cgroup_dir(root, CgroupLayout::Hybrid, &cgroups, ID),
+            Some(PathBuf::from(format!(
+                "/sys/fs/cgroup/unified/system.slice/docker-{}.scope",
+                ID
+            )))
+        );
+    }
+
+    #[test]
+    fn cgroup_layout_detect() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(CgroupLayout::detect(dir.path()), CgroupLayout::Legacy);
+        std::fs::create_dir(dir.path().join("unified")).unwrap();
+        std::fs::write(dir.path().join("unified/cgroup.controllers"), "").unwrap();
+        assert_eq!(CgroupLayout::detect(dir.path()), CgroupLayout::Hybrid);
+        std::fs::write(dir.path().join("cgroup.controllers"), "").unwrap();
+        assert_eq!(CgroupLayout::detect(dir.path()), CgroupLayout::Unified);
+    }
+}
@@ -1,8 +1,35 @@
-use tokio::sync::oneshot;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time,
+};
+use tracing::{field, info_span, warn, Span};
 
-use lockc_common::ContainerPolicyLevel;
+use lockc_common::{
+    control::PolicySource, ContainerPolicyLevel, ContainerSpec, CustomMounts, Enforcement, Program,
+    ProgramStats,
+};
 
-use crate::maps::MapOperationError;
+use crate::{
+    cgroups::ContainerCgroup,
+    fingerprints::CapabilityUses,
+    learning::MountAttempts,
+    maps::{AddMode, MapOperationError, ProcessContainer},
+    registry::ContainerMetadata,
+    settings::{ChannelOverflow, EbpfChannel, ResponseTimeout},
+    supervisor::{EbpfHealth, EbpfState},
+};
 
 /// Set of commands that the other tokio threads can use to request eBPF map
 /// operations.
@@ -12,15 +39,309 @@ pub enum EbpfCommand {
         container_id: String,
         pid: i32,
         policy_level: ContainerPolicyLevel,
+        enforcement: Enforcement,
+        /// Whether the container may read the kernel log regardless of its
+        /// policy level.
+        syslog: bool,
+        metadata: ContainerMetadata,
+        spec: Box<ContainerSpec>,
+        /// Host paths allowed to be bind mounted by the custom policy of the
+        /// container, if any.
+        custom_mounts: Option<Box<CustomMounts>>,
+        mode: AddMode,
+        /// Origin of the registration, recorded in the policy audit trail.
+        source: PolicySource,
         responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
     },
     DeleteContainer {
         container_id: String,
         responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
     },
+    SetEnforcement {
+        container_id: String,
+        enforcement: Enforcement,
+        source: PolicySource,
+        responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
+    },
     AddProcess {
         container_id: String,
         pid: i32,
         responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
     },
+    AddCgroup {
+        container_id: String,
+        cgroup: ContainerCgroup,
+        responder_tx: oneshot::Sender<Result<(), MapOperationError>>,
+    },
+    GetProcessContainer {
+        pid: i32,
+        responder_tx: oneshot::Sender<Result<Option<ProcessContainer>, MapOperationError>>,
+    },
+    GetProgramStats {
+        responder_tx: oneshot::Sender<Result<Vec<(Program, ProgramStats)>, MapOperationError>>,
+    },
+    TakeLearnedMounts {
+        responder_tx: oneshot::Sender<Result<Vec<MountAttempts>, MapOperationError>>,
+    },
+    TakeLearnedCapabilities {
+        responder_tx: oneshot::Sender<Result<Vec<CapabilityUses>, MapOperationError>>,
+    },
+}
+
+impl EbpfCommand {
+    /// Name of the operation, used in spans and logs.
+    pub fn operation(&self) -> &'static str {
+        match self {
+            EbpfCommand::AddContainer { .. } => "add_container",
+            EbpfCommand::DeleteContainer { .. } => "delete_container",
+            EbpfCommand::SetEnforcement { .. } => "set_enforcement",
+            EbpfCommand::AddProcess { .. } => "add_process",
+            EbpfCommand::AddCgroup { .. } => "add_cgroup",
+            EbpfCommand::GetProcessContainer { .. } => "get_process_container",
+            EbpfCommand::GetProgramStats { .. } => "get_program_stats",
+            EbpfCommand::TakeLearnedMounts { .. } => "take_learned_mounts",
+            EbpfCommand::TakeLearnedCapabilities { .. } => "take_learned_capabilities",
+        }
+    }
+
+    fn container_id(&self) -> Option<&str> {
+        match self {
+            EbpfCommand::AddContainer { container_id, .. }
+            | EbpfCommand::DeleteContainer { container_id, .. }
+            | EbpfCommand::SetEnforcement { container_id, .. }
+            | EbpfCommand::AddProcess { container_id, .. }
+            | EbpfCommand::AddCgroup { container_id, .. } => Some(container_id),
+            EbpfCommand::GetProcessContainer { .. }
+            | EbpfCommand::GetProgramStats { .. }
+            | EbpfCommand::TakeLearnedMounts { .. }
+            | EbpfCommand::TakeLearnedCapabilities { .. } => None,
+        }
+    }
+}
+
+/// eBPF command together with the span it was requested in. The span is a
+/// child of the span of the requesting thread (i.e. of the fanotify event),
+/// so handling of the command in the eBPF thread is attributed to it.
+#[derive(Debug)]
+pub struct EbpfRequest {
+    pub command: EbpfCommand,
+    pub span: Span,
+}
+
+impl From<EbpfCommand> for EbpfRequest {
+    fn from(command: EbpfCommand) -> Self {
+        let span = info_span!(
+            "ebpf_command",
+            operation = command.operation(),
+            container_id = field::Empty
+        );
+        if let Some(container_id) = command.container_id() {
+            span.record("container_id", container_id);
+        }
+        EbpfRequest { command, span }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SendCommandError {
+    #[error("eBPF thread is gone")]
+    Closed,
+
+    #[error("eBPF command channel is full")]
+    Full,
+
+    #[error("eBPF maps failed and could not be recovered")]
+    Failed,
+}
+
+#[derive(Error, Debug)]
+pub enum ResponseError {
+    #[error("eBPF thread dropped the command")]
+    Closed(#[from] oneshot::error::RecvError),
+
+    #[error("eBPF thread did not respond within {0:?}")]
+    Timeout(Duration),
+}
+
+/// Sender of eBPF commands which applies the overflow policy when the
+/// channel is full and the response timeout when waiting for results.
+#[derive(Clone)]
+pub struct EbpfSender {
+    tx: mpsc::Sender<EbpfRequest>,
+    overflow: ChannelOverflow,
+    response_timeout: Option<Duration>,
+    on_timeout: ResponseTimeout,
+    /// Number of commands whose result didn't arrive in time.
+    timeouts: Arc<AtomicU64>,
+    /// State of the eBPF thread, commands are not sent when it failed.
+    health: EbpfHealth,
+}
+
+impl EbpfSender {
+    pub fn new(tx: mpsc::Sender<EbpfRequest>, settings: &EbpfChannel) -> Self {
+        EbpfSender {
+            tx,
+            overflow: settings.overflow,
+            response_timeout: (settings.response_timeout_ms > 0)
+                .then(|| Duration::from_millis(settings.response_timeout_ms)),
+            on_timeout: settings.on_timeout,
+            timeouts: Arc::default(),
+            health: EbpfHealth::default(),
+        }
+    }
+
+    /// Makes the sender fail when the eBPF thread is in the failed state.
+    pub fn with_health(mut self, health: EbpfHealth) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn overflow(&self) -> ChannelOverflow {
+        self.overflow
+    }
+
+    pub fn on_timeout(&self) -> ResponseTimeout {
+        self.on_timeout
+    }
+
+    /// Returns the counter of commands whose result didn't arrive in time.
+    pub fn timeouts(&self) -> Arc<AtomicU64> {
+        self.timeouts.clone()
+    }
+
+    /// Sends the command. When the channel is full, it waits for a free slot
+    /// or fails, depending on the overflow policy.
+    pub async fn send(&self, command: EbpfCommand) -> Result<(), SendCommandError> {
+        if self.health.state() == EbpfState::Failed {
+            return Err(SendCommandError::Failed);
+        }
+        let request = match self.tx.try_send(command.into()) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(_)) => return Err(SendCommandError::Closed),
+            Err(TrySendError::Full(request)) => request,
+        };
+        warn!(
+            capacity = self.tx.max_capacity(),
+            overflow = ?self.overflow,
+            "eBPF command channel is saturated"
+        );
+        match self.overflow {
+            ChannelOverflow::Block => self
+                .tx
+                .send(request)
+                .await
+                .map_err(|_| SendCommandError::Closed),
+            ChannelOverflow::FailOpen | ChannelOverflow::FailClose => Err(SendCommandError::Full),
+        }
+    }
+
+    /// Waits for the result of a sent command, at most for the response
+    /// timeout. The command is still performed when it times out, but its
+    /// result is discarded.
+    pub async fn response<T>(
+        &self,
+        responder_rx: oneshot::Receiver<T>,
+    ) -> Result<T, ResponseError> {
+        let timeout = match self.response_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(responder_rx.await?),
+        };
+        match time::timeout(timeout, responder_rx).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                self.timeouts.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    timeout_ms = timeout.as_millis() as u64,
+                    on_timeout = ?self.on_timeout,
+                    "eBPF thread did not respond in time"
+                );
+                Err(ResponseError::Timeout(timeout))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(overflow: ChannelOverflow) -> EbpfChannel {
+        EbpfChannel {
+            overflow,
+            ..Default::default()
+        }
+    }
+
+    fn get_program_stats() -> EbpfCommand {
+        EbpfCommand::GetProgramStats {
+            responder_tx: oneshot::channel().0,
+        }
+    }
+
+    #[tokio::test]
+    async fn ebpf_sender_overflow() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let sender = EbpfSender::new(tx.clone(), &channel(ChannelOverflow::FailClose));
+        sender.send(get_program_stats()).await.unwrap();
+        assert!(matches!(
+            sender.send(get_program_stats()).await,
+            Err(SendCommandError::Full)
+        ));
+
+        // Blocking senders wait until the eBPF thread catches up.
+        let sender = EbpfSender::new(tx, &channel(ChannelOverflow::Block));
+        let handle = tokio::spawn(async move { sender.send(get_program_stats()).await });
+        rx.recv().await.unwrap();
+        handle.await.unwrap().unwrap();
+        rx.recv().await.unwrap();
+
+        let sender = EbpfSender::new(mpsc::channel(1).0, &channel(ChannelOverflow::Block));
+        assert!(matches!(
+            sender.send(get_program_stats()).await,
+            Err(SendCommandError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ebpf_sender_failed() {
+        let (tx, _rx) = mpsc::channel(1);
+        let health = EbpfHealth::default();
+        let sender =
+            EbpfSender::new(tx, &channel(ChannelOverflow::Block)).with_health(health.clone());
+        health.set(EbpfState::Failed);
+        assert!(matches!(
+            sender.send(get_program_stats()).await,
+            Err(SendCommandError::Failed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn ebpf_sender_response_timeout() {
+        let sender = EbpfSender::new(
+            mpsc::channel(1).0,
+            &EbpfChannel {
+                response_timeout_ms: 10,
+                ..Default::default()
+            },
+        );
+        let (responder_tx, responder_rx) = oneshot::channel::<()>();
+        assert!(matches!(
+            sender.response(responder_rx).await,
+            Err(ResponseError::Timeout(_))
+        ));
+        assert_eq!(sender.timeouts().load(Ordering::Relaxed), 1);
+        drop(responder_tx);
+
+        let (responder_tx, responder_rx) = oneshot::channel();
+        responder_tx.send(42).unwrap();
+        assert_eq!(sender.response(responder_rx).await.unwrap(), 42);
+
+        let (responder_tx, responder_rx) = oneshot::channel::<()>();
+        drop(responder_tx);
+        assert!(matches!(
+            sender.response(responder_rx).await,
+            Err(ResponseError::Closed(_))
+        ));
+        assert_eq!(sender.timeouts().load(Ordering::Relaxed), 1);
+    }
 }
@@ -0,0 +1,830 @@
+use std::{
+    fs, io,
+    os::unix::{
+        fs::PermissionsExt,
+        io::{FromRawFd, RawFd},
+        net::UnixListener as StdUnixListener,
+    },
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use lockc_common::{
+    control::{
+        BpfDigests, ControlRequest, ControlResponse, PolicySource, ProcessEventInfo,
+        ProgramStatsInfo,
+    },
+    ContainerPolicyLevel, ContainerSpec, Enforcement, Program,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::UnixListener,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot,
+    },
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    cgroups,
+    communication::{EbpfCommand, EbpfRequest},
+    fingerprints::Fingerprints,
+    incident,
+    learning::LearnedMounts,
+    log_filter::LogFilter,
+    maps::{AddMode, MapOperationError},
+    policy_audit::PolicyAudit,
+    registry::{ContainerMetadata, ContainerRegistry},
+    runc::bundle_spec,
+};
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// State of lockc exposed through the control API.
+pub struct ControlState {
+    pub digests: BpfDigests,
+    pub containers: Arc<RwLock<ContainerRegistry>>,
+    /// Channel for eBPF map operations. It's weak, so the eBPF loop still
+    /// ends when the runc watcher exits.
+    pub ebpf_tx: mpsc::WeakSender<EbpfRequest>,
+    pub log_filter: LogFilter,
+    /// Channel of process events, if lockc runs in trace mode.
+    pub trace_tx: Option<broadcast::Sender<ProcessEventInfo>>,
+    /// Bind mounts recorded in learning mode, if enabled.
+    pub learned_mounts: Option<Arc<RwLock<LearnedMounts>>>,
+    /// Audit trail of policy changes, recorded by the eBPF loop.
+    pub policy_audit: Arc<Mutex<PolicyAudit>>,
+    /// Fingerprints of images, if enabled.
+    pub fingerprints: Option<Arc<RwLock<Fingerprints>>>,
+    /// Programs loaded into the kernel. Programs skipped on this kernel or
+    /// root filesystem are missing.
+    pub loaded_programs: Vec<Program>,
+}
+
+/// Binds the control API socket, replacing a stale one left by a previous
+/// instance. The socket is accessible only by root.
+pub fn bind<P: AsRef<Path>>(path: P) -> Result<StdUnixListener, io::Error> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::remove_file(path) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = StdUnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Creates the control API listener from a socket passed by systemd with
+/// socket activation.
+pub fn from_fd(fd: RawFd) -> Result<StdUnixListener, io::Error> {
+    // SAFETY: the file descriptor was passed by systemd and is not owned by
+    // anything else.
+    let listener = unsafe { StdUnixListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Accepts connections on the control API socket and handles each of them in
+/// a separate task.
+pub async fn serve(listener: UnixListener, state: Arc<ControlState>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                // Credentials of the client are recorded in the audit trail of
+                // policy changes it requests.
+                let source = match stream.peer_cred() {
+                    Ok(cred) => PolicySource::ControlApi {
+                        uid: Some(cred.uid()),
+                        pid: cred.pid(),
+                    },
+                    Err(_) => PolicySource::ControlApi {
+                        uid: None,
+                        pid: None,
+                    },
+                };
+                tokio::spawn(async move {
+                    let (reader, writer) = stream.into_split();
+                    if let Err(e) = handle_connection(reader, writer, &state, &source).await {
+                        warn!(
+                            error = e.to_string().as_str(),
+                            "control API connection failed"
+                        );
+                    }
+                });
+            }
+            Err(e) => error!(
+                error = e.to_string().as_str(),
+                "could not accept a control API connection"
+            ),
+        }
+    }
+}
+
+async fn handle_connection<R, W>(
+    reader: R,
+    mut writer: W,
+    state: &ControlState,
+    source: &PolicySource,
+) -> Result<(), ControlError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match serde_json::from_str(&line) {
+            // Tracing takes over the connection.
+            Ok(ControlRequest::Trace { container_id }) => {
+                return stream_trace(lines, writer, state, container_id).await;
+            }
+            Ok(request) => handle_request(request, state, source).await,
+            Err(e) => ControlResponse::Error {
+                message: e.to_string(),
+            },
+        };
+        write_response(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+async fn write_response<W>(writer: &mut W, response: &ControlResponse) -> Result<(), ControlError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = serde_json::to_vec(response)?;
+    buf.push(b'\n');
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Writes process events of the given container, or of all containers, until
+/// the client disconnects.
+async fn stream_trace<R, W>(
+    mut lines: Lines<BufReader<R>>,
+    mut writer: W,
+    state: &ControlState,
+    container_id: Option<String>,
+) -> Result<(), ControlError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut rx = match &state.trace_tx {
+        Some(trace_tx) => trace_tx.subscribe(),
+        None => {
+            let response = ControlResponse::Error {
+                message: "trace mode is disabled, lockc has to run with --trace".to_string(),
+            };
+            return write_response(&mut writer, &response).await;
+        }
+    };
+    debug!(container_id = container_id.as_deref(), "trace started");
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            // Any further input, including EOF, ends the trace.
+            _ = lines.next_line() => break,
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "trace client lags behind process events");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if let Some(container_id) = &container_id {
+            if *container_id != event.container_id {
+                continue;
+            }
+        }
+        write_response(&mut writer, &ControlResponse::ProcessEvent(event)).await?;
+    }
+    debug!(container_id = container_id.as_deref(), "trace finished");
+    Ok(())
+}
+
+/// Sends the eBPF command created with the given responder to the eBPF
+/// thread and waits for its result.
+async fn ebpf_command<T, F>(state: &ControlState, command: F) -> Result<T, String>
+where
+    F: FnOnce(oneshot::Sender<Result<T, MapOperationError>>) -> EbpfCommand,
+{
+    let ebpf_tx = state
+        .ebpf_tx
+        .upgrade()
+        .ok_or_else(|| "lockc is shutting down".to_string())?;
+    let (responder_tx, responder_rx) = oneshot::channel();
+    ebpf_tx
+        .send(command(responder_tx).into())
+        .await
+        .map_err(|_| "could not send the eBPF command".to_string())?;
+    responder_rx
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Registers the container in eBPF maps and the registry.
+#[allow(clippy::too_many_arguments)]
+async fn add_container(
+    state: &ControlState,
+    container_id: String,
+    pid: i32,
+    policy_level: ContainerPolicyLevel,
+    enforcement: Enforcement,
+    syslog: bool,
+    bundle: Option<String>,
+    metadata: ContainerMetadata,
+    mode: AddMode,
+    source: &PolicySource,
+) -> Result<(), String> {
+    if matches!(
+        policy_level,
+        ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc
+    ) {
+        return Err(format!(
+            "policy level {} cannot be assigned to containers",
+            policy_level
+        ));
+    }
+    let spec = match bundle {
+        Some(bundle) => bundle_spec(Path::new(&bundle))
+            .map_err(|e| format!("could not read the spec of bundle {}: {}", bundle, e))?,
+        None => ContainerSpec::default(),
+    };
+    ebpf_command(state, |responder_tx| EbpfCommand::AddContainer {
+        container_id: container_id.clone(),
+        pid,
+        policy_level,
+        enforcement,
+        syslog,
+        metadata,
+        spec: Box::new(spec),
+        custom_mounts: None,
+        mode,
+        source: source.clone(),
+        responder_tx,
+    })
+    .await?;
+
+    // The cgroup only helps to attribute processes which lockc lost track
+    // of, so the container stays registered without it.
+    let res = match cgroups::resolve(pid, &container_id) {
+        Ok(cgroup) => {
+            ebpf_command(state, |responder_tx| EbpfCommand::AddCgroup {
+                container_id: container_id.clone(),
+                cgroup,
+                responder_tx,
+            })
+            .await
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = res {
+        warn!(
+            container_id = container_id.as_str(),
+            error = e.as_str(),
+            "could not register the cgroup of the container"
+        );
+    }
+    Ok(())
+}
+
+/// Handles a request of a control API client, or of the status page.
+pub async fn handle_request(
+    request: ControlRequest,
+    state: &ControlState,
+    source: &PolicySource,
+) -> ControlResponse {
+    debug!(
+        request = format!("{:?}", request).as_str(),
+        "control request"
+    );
+    match request {
+        ControlRequest::Digests => ControlResponse::Digests(state.digests.clone()),
+        ControlRequest::Containers => match state.containers.read() {
+            Ok(containers) => ControlResponse::Containers {
+                containers: containers.list(),
+            },
+            Err(_) => ControlResponse::Error {
+                message: "container registry is poisoned".to_string(),
+            },
+        },
+        ControlRequest::ResolveContainer { id } => match state.containers.read() {
+            Ok(containers) => ControlResponse::Containers {
+                containers: containers.resolve(&id),
+            },
+            Err(_) => ControlResponse::Error {
+                message: "container registry is poisoned".to_string(),
+            },
+        },
+        ControlRequest::AddContainer {
+            container_id,
+            pid,
+            policy_level,
+            bundle,
+            name,
+            pod,
+            namespace,
+            image,
+            enforcement,
+            syslog,
+            replace,
+        } => {
+            let metadata = ContainerMetadata {
+                name,
+                pod,
+                namespace,
+                image,
+                parent: None,
+                root: None,
+                runtime_id: None,
+            };
+            let res = add_container(
+                state,
+                container_id,
+                pid,
+                policy_level,
+                enforcement,
+                syslog,
+                bundle,
+                metadata,
+                if replace {
+                    AddMode::Upsert
+                } else {
+                    AddMode::Create
+                },
+                source,
+            )
+            .await;
+            match res {
+                Ok(()) => ControlResponse::Ok,
+                Err(message) => ControlResponse::Error { message },
+            }
+        }
+        ControlRequest::AddProcess { container_id, pid } => {
+            let res = ebpf_command(state, |responder_tx| EbpfCommand::AddProcess {
+                container_id,
+                pid,
+                responder_tx,
+            })
+            .await;
+            match res {
+                Ok(()) => ControlResponse::Ok,
+                Err(message) => ControlResponse::Error { message },
+            }
+        }
+        ControlRequest::SetEnforcement {
+            container_id,
+            enforcement,
+        } => {
+            let res = ebpf_command(state, |responder_tx| EbpfCommand::SetEnforcement {
+                container_id,
+                enforcement,
+                source: source.clone(),
+                responder_tx,
+            })
+            .await;
+            match res {
+                Ok(()) => ControlResponse::Ok,
+                Err(message) => ControlResponse::Error { message },
+            }
+        }
+        ControlRequest::DeleteContainer { container_id } => {
+            let res = ebpf_command(state, |responder_tx| EbpfCommand::DeleteContainer {
+                container_id,
+                responder_tx,
+            })
+            .await;
+            match res {
+                Ok(()) => ControlResponse::Ok,
+                Err(message) => ControlResponse::Error { message },
+            }
+        }
+        ControlRequest::ThawContainer { container_id } => {
+            match incident::set_container_frozen(&state.containers, &container_id, false) {
+                Ok(()) => {
+                    info!(container_id = container_id.as_str(), "container thawed");
+                    ControlResponse::Ok
+                }
+                Err(e) => ControlResponse::Error {
+                    message: e.to_string(),
+                },
+            }
+        }
+        // Handled by `stream_trace`, which takes over the connection.
+        ControlRequest::Trace { .. } => ControlResponse::Error {
+            message: "trace can be only requested on its own connection".to_string(),
+        },
+        ControlRequest::LearnedMounts => match &state.learned_mounts {
+            Some(learned_mounts) => match learned_mounts.read() {
+                Ok(learned_mounts) => ControlResponse::LearnedMounts {
+                    mounts: learned_mounts.list(),
+                },
+                Err(_) => ControlResponse::Error {
+                    message: "learned bind mounts are poisoned".to_string(),
+                },
+            },
+            None => ControlResponse::Error {
+                message: "learning mode is disabled, set learning_mode in the settings".to_string(),
+            },
+        },
+        ControlRequest::Stats => {
+            let res = ebpf_command(state, |responder_tx| EbpfCommand::GetProgramStats {
+                responder_tx,
+            })
+            .await;
+            match res {
+                Ok(stats) => ControlResponse::Stats {
+                    programs: stats
+                        .into_iter()
+                        .map(|(program, stats)| ProgramStatsInfo {
+                            program: program.name().to_string(),
+                            loaded: state.loaded_programs.contains(&program),
+                            invocations: stats.invocations,
+                            allows: stats.allows,
+                            denies: stats.denies,
+                        })
+                        .collect(),
+                },
+                Err(message) => ControlResponse::Error { message },
+            }
+        }
+        ControlRequest::PolicyHistory { container_id } => match state.policy_audit.lock() {
+            Ok(policy_audit) => ControlResponse::PolicyHistory {
+                changes: policy_audit.history(container_id.as_deref()),
+            },
+            Err(_) => ControlResponse::Error {
+                message: "policy audit trail is poisoned".to_string(),
+            },
+        },
+        ControlRequest::Fingerprints { image } => match &state.fingerprints {
+            Some(fingerprints) => match fingerprints.read() {
+                Ok(fingerprints) => ControlResponse::Fingerprints {
+                    images: fingerprints.list(image.as_deref()),
+                },
+                Err(_) => ControlResponse::Error {
+                    message: "fingerprints are poisoned".to_string(),
+                },
+            },
+            None => ControlResponse::Error {
+                message: "fingerprints are disabled, set fingerprints.enabled in the settings"
+                    .to_string(),
+            },
+        },
+        ControlRequest::LogFilter => match state.log_filter.current() {
+            Ok(filter) => ControlResponse::LogFilter { filter },
+            Err(e) => ControlResponse::Error {
+                message: e.to_string(),
+            },
+        },
+        ControlRequest::SetLogFilter { filter } => match state.log_filter.set(&filter) {
+            Ok(filter) => {
+                info!(filter = filter.as_str(), "log filter changed");
+                ControlResponse::LogFilter { filter }
+            }
+            Err(e) => ControlResponse::Error {
+                message: e.to_string(),
+            },
+        },
+    }
+}
+
+/// Returns the state of lockc without containers and optional features.
+/// The eBPF loop is already gone.
+#[cfg(test)]
+pub(crate) fn test_state() -> ControlState {
+    ControlState {
+        digests: BpfDigests {
+            object: "abc".to_string(),
+            path: None,
+            verified: false,
+            programs: Vec::new(),
+        },
+        containers: Arc::default(),
+        ebpf_tx: mpsc::channel(1).0.downgrade(),
+        log_filter: LogFilter::new(tracing::level_filters::LevelFilter::INFO).1,
+        trace_tx: None,
+        learned_mounts: None,
+        policy_audit: Arc::default(),
+        fingerprints: None,
+        loaded_programs: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::control::ProgramDigest;
+    use tracing::level_filters::LevelFilter;
+
+    use super::*;
+    use crate::{learning::MountAttempts, policy_audit};
+
+    const CLIENT: PolicySource = PolicySource::ControlApi {
+        uid: Some(0),
+        pid: None,
+    };
+
+    #[tokio::test]
+    async fn control_digests() {
+        let mut state = test_state();
+        state.digests.programs = vec![ProgramDigest {
+            section: "lsm/syslog".to_string(),
+            sha256: "def".to_string(),
+        }];
+        let input = b"{\"request\":\"digests\"}\nnot json\n";
+        let mut output = Vec::new();
+        handle_connection(&input[..], &mut output, &state, &CLIENT)
+            .await
+            .unwrap();
+
+        let mut lines = output.split(|b| *b == b'\n');
+        match serde_json::from_slice(lines.next().unwrap()).unwrap() {
+            ControlResponse::Digests(digests) => {
+                assert_eq!(digests.object, "abc");
+                assert_eq!(digests.programs[0].section, "lsm/syslog");
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+        assert!(matches!(
+            serde_json::from_slice(lines.next().unwrap()).unwrap(),
+            ControlResponse::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn control_containers() {
+        let containers = Arc::new(RwLock::new(ContainerRegistry::default()));
+        containers.write().unwrap().insert(
+            "abc".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata {
+                name: Some("nginx".to_string()),
+                ..Default::default()
+            },
+        );
+        let state = ControlState {
+            containers,
+            ..test_state()
+        };
+        let input = b"{\"request\":\"containers\"}\n";
+        let mut output = Vec::new();
+        handle_connection(&input[..], &mut output, &state, &CLIENT)
+            .await
+            .unwrap();
+
+        let line = output.split(|b| *b == b'\n').next().unwrap();
+        match serde_json::from_slice(line).unwrap() {
+            ControlResponse::Containers { containers } => {
+                assert_eq!(containers.len(), 1);
+                assert_eq!(containers[0].id, "abc");
+                assert_eq!(containers[0].name.as_deref(), Some("nginx"));
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn control_delete_container() {
+        let (ebpf_tx, mut ebpf_rx) = mpsc::channel::<EbpfRequest>(1);
+        let state = ControlState {
+            ebpf_tx: ebpf_tx.downgrade(),
+            ..test_state()
+        };
+        tokio::spawn(async move {
+            let request = ebpf_rx.recv().await.unwrap();
+            match request.command {
+                EbpfCommand::DeleteContainer {
+                    container_id,
+                    responder_tx,
+                } => {
+                    assert_eq!(container_id, "abc");
+                    responder_tx.send(Ok(())).unwrap();
+                }
+                _ => panic!("unexpected eBPF command"),
+            }
+        });
+
+        let input = b"{\"request\":\"delete_container\",\"container_id\":\"abc\"}\n";
+        let mut output = Vec::new();
+        handle_connection(&input[..], &mut output, &state, &CLIENT)
+            .await
+            .unwrap();
+        let line = output.split(|b| *b == b'\n').next().unwrap();
+        assert!(matches!(
+            serde_json::from_slice(line).unwrap(),
+            ControlResponse::Ok
+        ));
+
+        // The eBPF loop is gone once all strong senders are dropped.
+        drop(ebpf_tx);
+        let response = handle_request(
+            ControlRequest::DeleteContainer {
+                container_id: "abc".to_string(),
+            },
+            &state,
+            &CLIENT,
+        )
+        .await;
+        assert!(matches!(response, ControlResponse::Error { .. }));
+    }
+
+    #[tokio::test]
+    async fn control_add_container() {
+        let (ebpf_tx, mut ebpf_rx) = mpsc::channel::<EbpfRequest>(1);
+        let state = ControlState {
+            ebpf_tx: ebpf_tx.downgrade(),
+            ..test_state()
+        };
+        tokio::spawn(async move {
+            let request = ebpf_rx.recv().await.unwrap();
+            match request.command {
+                EbpfCommand::AddContainer {
+                    container_id,
+                    pid,
+                    policy_level,
+                    enforcement,
+                    syslog,
+                    metadata,
+                    mode,
+                    source,
+                    responder_tx,
+                    ..
+                } => {
+                    assert_eq!(container_id, "abc");
+                    assert_eq!(pid, 42);
+                    assert!(policy_level == ContainerPolicyLevel::Baseline);
+                    assert!(enforcement == Enforcement::Complain);
+                    assert!(syslog);
+                    assert_eq!(mode, AddMode::Create);
+                    assert_eq!(source, CLIENT);
+                    assert_eq!(metadata.name.as_deref(), Some("nginx"));
+                    responder_tx.send(Ok(())).unwrap();
+                }
+                _ => panic!("unexpected eBPF command"),
+            }
+        });
+
+        let input = b"{\"request\":\"add_container\",\"container_id\":\"abc\",\"pid\":42,\"policy_level\":\"baseline\",\"name\":\"nginx\",\"enforcement\":\"complain\",\"syslog\":true}\n{\"request\":\"add_container\",\"container_id\":\"abc\",\"pid\":42,\"policy_level\":\"lockc\"}\n";
+        let mut output = Vec::new();
+        handle_connection(&input[..], &mut output, &state, &CLIENT)
+            .await
+            .unwrap();
+        let mut lines = output.split(|b| *b == b'\n');
+        assert!(matches!(
+            serde_json::from_slice(lines.next().unwrap()).unwrap(),
+            ControlResponse::Ok
+        ));
+        // Internal policy levels are rejected before reaching eBPF maps.
+        assert!(matches!(
+            serde_json::from_slice(lines.next().unwrap()).unwrap(),
+            ControlResponse::Error { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn control_policy_history() {
+        let policy_audit = Arc::new(Mutex::new(PolicyAudit::default()));
+        for container_id in ["abc", "def"] {
+            policy_audit.lock().unwrap().record(policy_audit::change(
+                container_id,
+                PolicySource::Runtime,
+                None,
+                (ContainerPolicyLevel::Baseline, Enforcement::Enforce),
+            ));
+        }
+        let state = ControlState {
+            policy_audit,
+            ..test_state()
+        };
+        let request = ControlRequest::PolicyHistory {
+            container_id: Some("de".to_string()),
+        };
+        match handle_request(request, &state, &CLIENT).await {
+            ControlResponse::PolicyHistory { changes } => {
+                assert_eq!(changes.len(), 1);
+                assert_eq!(changes[0].container_id, "def");
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn control_set_log_filter() {
+        let (_layer, log_filter) = LogFilter::new(LevelFilter::INFO);
+        let state = ControlState {
+            log_filter,
+            ..test_state()
+        };
+
+        let response = handle_request(
+            ControlRequest::SetLogFilter {
+                filter: "lockc=debug".to_string(),
+            },
+            &state,
+            &CLIENT,
+        )
+        .await;
+        assert!(
+            matches!(response, ControlResponse::LogFilter { filter } if filter == "lockc=debug")
+        );
+        let response = handle_request(
+            ControlRequest::SetLogFilter {
+                filter: "lockc=loud".to_string(),
+            },
+            &state,
+            &CLIENT,
+        )
+        .await;
+        assert!(matches!(response, ControlResponse::Error { .. }));
+        let response = handle_request(ControlRequest::LogFilter, &state, &CLIENT).await;
+        assert!(
+            matches!(response, ControlResponse::LogFilter { filter } if filter == "lockc=debug")
+        );
+    }
+
+    #[tokio::test]
+    async fn control_learned_mounts() {
+        let mut state = test_state();
+        let response = handle_request(ControlRequest::LearnedMounts, &state, &CLIENT).await;
+        assert!(matches!(response, ControlResponse::Error { .. }));
+
+        let learned_mounts = Arc::new(RwLock::new(LearnedMounts::default()));
+        learned_mounts.write().unwrap().record(MountAttempts {
+            container_id: "abc".to_string(),
+            policy_level: ContainerPolicyLevel::Baseline,
+            path: "/srv/data".to_string(),
+            count: 3,
+        });
+        state.learned_mounts = Some(learned_mounts);
+        match handle_request(ControlRequest::LearnedMounts, &state, &CLIENT).await {
+            ControlResponse::LearnedMounts { mounts } => {
+                assert_eq!(mounts.len(), 1);
+                assert_eq!(mounts[0].path, "/srv/data");
+                assert_eq!(mounts[0].count, 3);
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn control_trace() {
+        let (trace_tx, _) = broadcast::channel(16);
+        let state = Arc::new(ControlState {
+            trace_tx: Some(trace_tx.clone()),
+            ..test_state()
+        });
+        let (client, server) = tokio::io::duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let handle = tokio::spawn({
+            let state = state.clone();
+            async move { handle_connection(server_reader, server_writer, &state, &CLIENT).await }
+        });
+
+        let (client_reader, mut client_writer) = tokio::io::split(client);
+        client_writer
+            .write_all(b"{\"request\":\"trace\",\"container_id\":\"abc\"}\n")
+            .await
+            .unwrap();
+        while trace_tx.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        for container_id in ["def", "abc"] {
+            trace_tx
+                .send(ProcessEventInfo {
+                    kind: lockc_common::ProcessEventKind::Exec,
+                    container_id: container_id.to_string(),
+                    pid: 2,
+                    ppid: 1,
+                    comm: "sh".to_string(),
+                })
+                .unwrap();
+        }
+
+        let mut lines = BufReader::new(client_reader).lines();
+        let line = lines.next_line().await.unwrap().unwrap();
+        match serde_json::from_str(&line).unwrap() {
+            ControlResponse::ProcessEvent(event) => assert_eq!(event.container_id, "abc"),
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        client_writer.shutdown().await.unwrap();
+        handle.await.unwrap().unwrap();
+    }
+}
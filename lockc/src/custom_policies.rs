@@ -0,0 +1,412 @@
+//! Custom policies defined for the whole cluster by `LockcPolicy` resources.
+//! A custom policy is based on one of the policy levels enforced by eBPF
+//! programs. It can override the enforcement mode and allow bind mounts and
+//! capabilities on top of its policy level. Allowed mounts are written to the
+//! eBPF map of custom mounts when a container is registered, so the kernel
+//! allows them as well. Capabilities are granted only through the OCI runtime
+//! spec, so they are checked there. Containers select a custom policy with
+//! the `org.lockc.custom-policy` annotation or label, only in the Kubernetes
+//! namespaces listed by the policy.
+//!
+//! The controller watches `LockcPolicy` resources and keeps a node-local copy
+//! of them. The runc watcher looks custom policies up there when containers
+//! are registered.
+
+#[cfg(feature = "kubernetes")]
+use std::time::Duration;
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+#[cfg(feature = "kubernetes")]
+use futures::{pin_mut, TryStreamExt};
+#[cfg(feature = "kubernetes")]
+use kube::{
+    api::{Api, ListParams},
+    runtime::watcher::{self, watcher},
+    Client, CustomResource,
+};
+use lockc_common::{ContainerPolicyLevel, CustomMounts, CustomMountsError, Enforcement};
+#[cfg(feature = "kubernetes")]
+use schemars::JsonSchema;
+#[cfg(feature = "kubernetes")]
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+#[cfg(feature = "kubernetes")]
+use tokio::time;
+use tracing::error;
+#[cfg(feature = "kubernetes")]
+use tracing::{debug, info, warn};
+
+/// Delay before watching `LockcPolicy` resources again after an error.
+#[cfg(feature = "kubernetes")]
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum CustomPolicyError {
+    #[error("policy level {0} cannot be the base of a custom policy")]
+    InvalidPolicyLevel(ContainerPolicyLevel),
+
+    #[error("invalid capability {0:?}, expected a name like CAP_NET_ADMIN")]
+    Capability(String),
+
+    #[error("allowed mount {0:?} has to be an absolute path")]
+    RelativeMount(String),
+
+    #[error(transparent)]
+    Mounts(#[from] CustomMountsError),
+}
+
+/// Node-local state of a custom policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomPolicy {
+    /// Policy level enforced by eBPF programs.
+    pub policy_level: ContainerPolicyLevel,
+    /// Enforcement mode overriding the one of the container.
+    pub enforcement: Option<Enforcement>,
+    /// Host paths which can be bind mounted, with everything under them.
+    pub allowed_mounts: Vec<String>,
+    /// Capabilities which can be added on top of the default ones.
+    pub capabilities: Vec<String>,
+    /// Kubernetes namespaces whose containers can select the policy.
+    pub namespaces: Vec<String>,
+}
+
+impl CustomPolicy {
+    pub fn new(
+        policy_level: ContainerPolicyLevel,
+        enforcement: Option<Enforcement>,
+        allowed_mounts: Vec<String>,
+        capabilities: Vec<String>,
+        namespaces: Vec<String>,
+    ) -> Result<Self, CustomPolicyError> {
+        if let ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc = policy_level {
+            return Err(CustomPolicyError::InvalidPolicyLevel(policy_level));
+        }
+        if let Some(path) = allowed_mounts.iter().find(|path| !path.starts_with('/')) {
+            return Err(CustomPolicyError::RelativeMount(path.clone()));
+        }
+        CustomMounts::new(&allowed_mounts)?;
+        let capabilities = capabilities
+            .into_iter()
+            .map(|cap| {
+                let cap = cap.to_uppercase();
+                let cap = if cap.starts_with("CAP_") {
+                    cap
+                } else {
+                    format!("CAP_{}", cap)
+                };
+                if cap.len() > "CAP_".len()
+                    && cap.chars().all(|c| c.is_ascii_uppercase() || c == '_')
+                {
+                    Ok(cap)
+                } else {
+                    Err(CustomPolicyError::Capability(cap))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(CustomPolicy {
+            policy_level,
+            enforcement,
+            allowed_mounts,
+            capabilities,
+            namespaces,
+        })
+    }
+
+    /// Returns whether containers of the namespace can select the policy.
+    /// Containers outside of Kubernetes namespaces can't select any.
+    pub fn allows_namespace(&self, namespace: Option<&str>) -> bool {
+        namespace.map_or(false, |namespace| {
+            self.namespaces.iter().any(|allowed| allowed == namespace)
+        })
+    }
+
+    /// Returns allowed mounts for the eBPF map of custom mounts, `None` if
+    /// there are none.
+    pub fn custom_mounts(&self) -> Option<CustomMounts> {
+        if self.allowed_mounts.is_empty() {
+            return None;
+        }
+        // Validated when the policy was created.
+        CustomMounts::new(&self.allowed_mounts).ok()
+    }
+
+    /// Returns whether the bind mount of the host path is allowed.
+    pub fn allows_mount(&self, source: &str) -> bool {
+        self.allowed_mounts
+            .iter()
+            .any(|allowed| Path::new(source).starts_with(allowed))
+    }
+
+    /// Returns whether the capability can be added.
+    pub fn allows_capability(&self, cap: &str) -> bool {
+        self.capabilities.iter().any(|allowed| allowed == cap)
+    }
+}
+
+/// Custom policies by name, shared by the controller and the runc watcher.
+#[derive(Clone, Default)]
+pub struct CustomPolicies(Arc<RwLock<HashMap<String, CustomPolicy>>>);
+
+impl CustomPolicies {
+    pub fn get(&self, name: &str) -> Option<CustomPolicy> {
+        match self.0.read() {
+            Ok(policies) => policies.get(name).cloned(),
+            Err(_) => {
+                error!("custom policies are poisoned");
+                None
+            }
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, CustomPolicy>)) {
+        match self.0.write() {
+            Ok(mut policies) => f(&mut policies),
+            Err(_) => error!("custom policies are poisoned"),
+        }
+    }
+
+    pub fn insert(&self, name: String, policy: CustomPolicy) {
+        self.update(|policies| {
+            policies.insert(name, policy);
+        })
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.update(|policies| {
+            policies.remove(name);
+        })
+    }
+
+    /// Replaces all custom policies.
+    pub fn replace(&self, new: HashMap<String, CustomPolicy>) {
+        self.update(|policies| *policies = new)
+    }
+}
+
+/// Specification of a custom policy, applied to the whole cluster.
+#[cfg(feature = "kubernetes")]
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(group = "lockc.org", version = "v1alpha1", kind = "LockcPolicy")]
+#[serde(rename_all = "camelCase")]
+pub struct LockcPolicySpec {
+    /// Policy level enforced by eBPF programs: `restricted`, `offline`,
+    /// `baseline` or `privileged`.
+    #[schemars(with = "String")]
+    pub level: ContainerPolicyLevel,
+    /// Enforcement mode overriding the one of the container: `enforce` or
+    /// `complain`.
+    #[serde(default)]
+    #[schemars(with = "Option<String>")]
+    pub enforcement: Option<Enforcement>,
+    /// Host paths which can be bind mounted, on top of the allowed paths of
+    /// the policy level. At most 8 paths shorter than 64 bytes.
+    #[serde(default)]
+    pub allowed_mounts: Vec<String>,
+    /// Capabilities which can be added on top of the default ones.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Namespaces whose containers can select the policy. Containers of
+    /// other namespaces get the policy resolved without it.
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+}
+
+#[cfg(feature = "kubernetes")]
+impl TryFrom<&LockcPolicySpec> for CustomPolicy {
+    type Error = CustomPolicyError;
+
+    fn try_from(spec: &LockcPolicySpec) -> Result<Self, Self::Error> {
+        CustomPolicy::new(
+            spec.level,
+            spec.enforcement,
+            spec.allowed_mounts.clone(),
+            spec.capabilities.clone(),
+            spec.namespaces.clone(),
+        )
+    }
+}
+
+/// Returns the name and the node-local state of the resource, if it's valid.
+#[cfg(feature = "kubernetes")]
+fn custom_policy(resource: &LockcPolicy) -> Option<(String, CustomPolicy)> {
+    let name = resource.metadata.name.clone()?;
+    match CustomPolicy::try_from(&resource.spec) {
+        Ok(policy) => Some((name, policy)),
+        Err(e) => {
+            warn!(
+                custom_policy = name.as_str(),
+                error = e.to_string().as_str(),
+                "invalid custom policy, ignoring"
+            );
+            None
+        }
+    }
+}
+
+/// Applies the change of `LockcPolicy` resources to the node-local state. An
+/// invalid resource keeps the previous state of the custom policy.
+#[cfg(feature = "kubernetes")]
+fn apply(policies: &CustomPolicies, event: watcher::Event<LockcPolicy>) {
+    match event {
+        watcher::Event::Applied(resource) => {
+            if let Some((name, policy)) = custom_policy(&resource) {
+                info!(
+                    custom_policy = name.as_str(),
+                    policy_level = policy.policy_level.to_string().as_str(),
+                    "custom policy applied"
+                );
+                policies.insert(name, policy);
+            }
+        }
+        watcher::Event::Deleted(resource) => {
+            if let Some(name) = &resource.metadata.name {
+                info!(custom_policy = name.as_str(), "custom policy deleted");
+                policies.remove(name);
+            }
+        }
+        watcher::Event::Restarted(resources) => {
+            let new: HashMap<_, _> = resources.iter().filter_map(custom_policy).collect();
+            debug!(custom_policies = new.len(), "custom policies listed");
+            policies.replace(new);
+        }
+    }
+}
+
+/// Reconciles `LockcPolicy` resources into the node-local state of custom
+/// policies, for as long as lockc runs.
+#[cfg(feature = "kubernetes")]
+pub async fn reconcile(policies: CustomPolicies) {
+    let client = match Client::try_default().await {
+        Ok(client) => client,
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not create Kubernetes client, custom policies are not available"
+            );
+            return;
+        }
+    };
+    let resources: Api<LockcPolicy> = Api::all(client);
+    let events = watcher(resources, ListParams::default());
+    pin_mut!(events);
+    loop {
+        match events.try_next().await {
+            Ok(Some(event)) => apply(&policies, event),
+            Ok(None) => break,
+            Err(e) => {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "watching custom policies failed, retrying"
+                );
+                time::sleep(WATCH_RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn custom_policy_new() {
+        let policy = CustomPolicy::new(
+            ContainerPolicyLevel::Baseline,
+            None,
+            vec!["/var/lib/gpu".to_string()],
+            vec!["net_admin".to_string(), "CAP_SYS_PTRACE".to_string()],
+            vec!["gpu".to_string()],
+        )
+        .unwrap();
+        assert_eq!(policy.capabilities, ["CAP_NET_ADMIN", "CAP_SYS_PTRACE"]);
+        assert!(policy.allows_capability("CAP_NET_ADMIN"));
+        assert!(!policy.allows_capability("CAP_SYS_ADMIN"));
+        assert!(policy.allows_mount("/var/lib/gpu/driver"));
+        assert!(!policy.allows_mount("/var/lib/gpudata"));
+        assert!(policy.allows_namespace(Some("gpu")));
+        assert!(!policy.allows_namespace(Some("default")));
+        assert!(!policy.allows_namespace(None));
+        assert!(policy.custom_mounts().is_some());
+
+        assert!(matches!(
+            CustomPolicy::new(ContainerPolicyLevel::Lockc, None, vec![], vec![], vec![]),
+            Err(CustomPolicyError::InvalidPolicyLevel(_))
+        ));
+        assert!(matches!(
+            CustomPolicy::new(
+                ContainerPolicyLevel::Baseline,
+                None,
+                vec!["var/lib".to_string()],
+                vec![],
+                vec![]
+            ),
+            Err(CustomPolicyError::RelativeMount(_))
+        ));
+        assert!(matches!(
+            CustomPolicy::new(
+                ContainerPolicyLevel::Baseline,
+                None,
+                vec![format!("/{}", "a".repeat(64))],
+                vec![],
+                vec![]
+            ),
+            Err(CustomPolicyError::Mounts(_))
+        ));
+        assert!(matches!(
+            CustomPolicy::new(
+                ContainerPolicyLevel::Baseline,
+                None,
+                vec![],
+                vec!["CAP_".to_string()],
+                vec![]
+            ),
+            Err(CustomPolicyError::Capability(_))
+        ));
+    }
+
+    #[cfg(feature = "kubernetes")]
+    #[test]
+    fn custom_policies_apply() {
+        let policies = CustomPolicies::default();
+        let spec = LockcPolicySpec {
+            level: ContainerPolicyLevel::Restricted,
+            enforcement: Some(Enforcement::Complain),
+            allowed_mounts: vec![],
+            capabilities: vec!["NET_ADMIN".to_string()],
+            namespaces: vec!["net".to_string()],
+        };
+        apply(
+            &policies,
+            watcher::Event::Applied(LockcPolicy::new("net", spec.clone())),
+        );
+        let policy = policies.get("net").unwrap();
+        assert_eq!(policy.policy_level, ContainerPolicyLevel::Restricted);
+        assert_eq!(policy.enforcement, Some(Enforcement::Complain));
+
+        // Invalid resources keep the previous state.
+        let mut invalid = spec.clone();
+        invalid.level = ContainerPolicyLevel::NotFound;
+        apply(
+            &policies,
+            watcher::Event::Applied(LockcPolicy::new("net", invalid)),
+        );
+        assert_eq!(policies.get("net"), Some(policy));
+
+        apply(
+            &policies,
+            watcher::Event::Restarted(vec![LockcPolicy::new("other", spec.clone())]),
+        );
+        assert_eq!(policies.get("net"), None);
+        assert!(policies.get("other").is_some());
+
+        apply(
+            &policies,
+            watcher::Event::Deleted(LockcPolicy::new("other", spec)),
+        );
+        assert_eq!(policies.get("other"), None);
+    }
+}
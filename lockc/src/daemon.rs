@@ -0,0 +1,104 @@
+//! Daemonization for running lockc outside of systemd (e.g. with simple init
+//! systems or in development VMs). lockc forks into the background and the
+//! original process exits when the daemon is ready, or with an error when it
+//! fails before.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::Path,
+    process,
+};
+
+use nix::{
+    fcntl::OFlag,
+    unistd::{chdir, close, dup2, fork, pipe2, setsid, ForkResult},
+};
+use thiserror::Error;
+
+use crate::error::EXIT_FAILURE;
+
+#[derive(Error, Debug)]
+pub enum DaemonError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Errno(#[from] nix::errno::Errno),
+}
+
+/// Write end of the pipe which the original process waits on.
+pub struct Readiness {
+    pipe: File,
+}
+
+impl Readiness {
+    /// Lets the original process exit successfully.
+    pub fn notify(mut self) -> Result<(), io::Error> {
+        self.pipe.write_all(&[1])
+    }
+}
+
+/// Opens the file which stdout and stderr of the daemon are redirected to.
+/// Output is discarded when no log file is given.
+fn open_log(log_file: Option<&Path>) -> Result<File, io::Error> {
+    match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o640)
+            .open(path),
+        None => OpenOptions::new().write(true).open("/dev/null"),
+    }
+}
+
+/// Waits until the daemon is ready and exits. The pipe is closed without
+/// writing to it when the daemon exits before.
+fn wait_ready(mut pipe: File) -> ! {
+    let mut buf = [0u8; 1];
+    match pipe.read(&mut buf) {
+        Ok(1) => process::exit(0),
+        _ => {
+            eprintln!("lockc exited before it was ready, see its log for details");
+            process::exit(EXIT_FAILURE.into())
+        }
+    }
+}
+
+/// Forks lockc into the background, detaches it from the terminal and
+/// redirects its stdio. Only the daemon returns from this function. It has
+/// to be called before any threads are spawned.
+pub fn daemonize(log_file: Option<&Path>) -> Result<Readiness, DaemonError> {
+    // Files are opened before forking, so errors are reported on the
+    // terminal.
+    let log = open_log(log_file)?;
+    let null = File::open("/dev/null")?;
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+
+    // SAFETY: lockc is single-threaded at this point.
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        close(write_fd)?;
+        wait_ready(unsafe { File::from_raw_fd(read_fd) });
+    }
+    close(read_fd)?;
+
+    // Start a new session without a controlling terminal and fork again, so
+    // the daemon is not a session leader and can't acquire one.
+    setsid()?;
+    if let ForkResult::Parent { .. } = unsafe { fork() }? {
+        process::exit(0);
+    }
+
+    chdir("/")?;
+    dup2(null.as_raw_fd(), 0)?;
+    dup2(log.as_raw_fd(), 1)?;
+    dup2(log.as_raw_fd(), 2)?;
+
+    Ok(Readiness {
+        pipe: unsafe { File::from_raw_fd(write_fd) },
+    })
+}
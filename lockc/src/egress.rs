@@ -0,0 +1,113 @@
+//! Attachment of cgroup programs restricting egress to cgroups of containers.
+//! Programs are attached when the cgroup of a container with a restricted
+//! policy level is registered and detached when the container is deleted, so
+//! connections to denied networks (e.g. the cloud metadata endpoint) are
+//! blocked without integrating with the CNI plugin.
+
+use std::{collections::HashMap, fs, io, os::unix::io::AsRawFd, path::PathBuf};
+
+use aya::{
+    programs::{cgroup_sock_addr::CgroupSockAddrLinkId, CgroupSockAddr, ProgramError},
+    Bpf,
+};
+use lockc_common::ContainerPolicyLevel;
+use thiserror::Error;
+
+use crate::{cgroups::ContainerCgroup, load::EGRESS_PROGRAMS};
+
+#[derive(Error, Debug)]
+pub enum EgressError {
+    #[error("could not open cgroup {}: {source}", .path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error(transparent)]
+    Program(#[from] ProgramError),
+
+    #[error("eBPF program {0} is not loaded")]
+    NotLoaded(&'static str),
+}
+
+/// Links of cgroup programs attached to cgroups of containers.
+pub struct EgressLinks {
+    /// Policy levels of containers with restricted egress.
+    policy_levels: Vec<ContainerPolicyLevel>,
+    /// Links of the programs, by cgroup ID.
+    links: HashMap<u64, Vec<(&'static str, CgroupSockAddrLinkId)>>,
+}
+
+impl EgressLinks {
+    pub fn new(policy_levels: Vec<ContainerPolicyLevel>) -> Self {
+        EgressLinks {
+            policy_levels,
+            links: HashMap::new(),
+        }
+    }
+
+    /// Returns whether egress of containers with the policy level is
+    /// restricted.
+    pub fn restricted(&self, policy_level: ContainerPolicyLevel) -> bool {
+        self.policy_levels.contains(&policy_level)
+    }
+
+    /// Attaches the programs to the cgroup, unless they are attached already.
+    /// Only cgroup v2 directories can have programs attached.
+    pub fn attach(&mut self, bpf: &mut Bpf, cgroup: &ContainerCgroup) -> Result<(), EgressError> {
+        if self.links.contains_key(&cgroup.id) {
+            return Ok(());
+        }
+        let file = fs::File::open(&cgroup.path).map_err(|source| EgressError::Open {
+            path: cgroup.path.clone(),
+            source,
+        })?;
+        let mut links = Vec::new();
+        for name in EGRESS_PROGRAMS {
+            match program(bpf, name).and_then(|program| Ok(program.attach(file.as_raw_fd())?)) {
+                Ok(link) => links.push((*name, link)),
+                Err(e) => {
+                    // The cgroup is not left restricted only for one IP
+                    // version.
+                    let _ = detach_links(bpf, links);
+                    return Err(e);
+                }
+            }
+        }
+        self.links.insert(cgroup.id, links);
+        Ok(())
+    }
+
+    /// Detaches the programs from the cgroup. Returns `false` if they were
+    /// not attached.
+    pub fn detach(&mut self, bpf: &mut Bpf, cgroup_id: u64) -> Result<bool, EgressError> {
+        match self.links.remove(&cgroup_id) {
+            Some(links) => {
+                detach_links(bpf, links)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn program<'a>(
+    bpf: &'a mut Bpf,
+    name: &'static str,
+) -> Result<&'a mut CgroupSockAddr, EgressError> {
+    Ok(bpf
+        .program_mut(name)
+        .ok_or(EgressError::NotLoaded(name))?
+        .try_into()?)
+}
+
+fn detach_links(
+    bpf: &mut Bpf,
+    links: Vec<(&'static str, CgroupSockAddrLinkId)>,
+) -> Result<(), EgressError> {
+    for (name, link) in links {
+        program(bpf, name)?.detach(link)?;
+    }
+    Ok(())
+}
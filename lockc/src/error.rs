@@ -0,0 +1,263 @@
+//! Errors which make lockc exit and exit codes they map to. Exit codes are
+//! stable, so service managers and packaging scripts can react to specific
+//! failures (e.g. not restart lockc on a kernel without BPF LSM).
+
+use std::io;
+
+use thiserror::Error;
+
+use crate::{
+    daemon::DaemonError,
+    fingerprints::FingerprintError,
+    handover::HandoverError,
+    instance::InstanceError,
+    integrity::IntegrityError,
+    load::AttachError,
+    load::LoadError,
+    maps::MapOperationError,
+    namespace_policies::NamespacePoliciesError,
+    perf::PerfError,
+    pidns::PidNsError,
+    policy_audit::PolicyAuditError,
+    privileges::PrivilegesError,
+    reporter::ReporterError,
+    runc::{HandleRuncEventError, RecorderError},
+    settings::SettingsError,
+    simulate::SimulateError,
+    supervisor::HealthError,
+    systemd::SystemdError,
+    sysutils::{CheckBpfLsmError, CheckKernelError, SetupHostError},
+    FanotifyError, SetupTracingError,
+};
+
+/// Unexpected failures.
+pub const EXIT_FAILURE: u8 = 1;
+/// Invalid configuration file or profile.
+pub const EXIT_SETTINGS: u8 = 3;
+/// Simulated verdicts differ from the expected ones.
+pub const EXIT_SIMULATION: u8 = 4;
+/// BPF LSM is not enabled in the kernel.
+pub const EXIT_LSM_NOT_ENABLED: u8 = 10;
+/// eBPF object could not be read, verified, loaded or attached.
+pub const EXIT_BPF_LOAD: u8 = 11;
+/// fanotify could not be set up, usually due to missing permissions.
+pub const EXIT_FANOTIFY: u8 = 12;
+/// Another instance of lockc is running.
+pub const EXIT_ALREADY_RUNNING: u8 = 13;
+/// Control API socket could not be set up.
+pub const EXIT_CONTROL_SOCKET: u8 = 14;
+/// Privileges could not be dropped.
+pub const EXIT_PRIVILEGES: u8 = 15;
+/// PID namespace of lockc could not be determined.
+pub const EXIT_PID_NAMESPACE: u8 = 16;
+/// Kernel lockdown or missing BTF prevents loading eBPF programs.
+pub const EXIT_KERNEL_UNSUPPORTED: u8 = 17;
+/// The running instance could not hand over its state.
+pub const EXIT_HANDOVER: u8 = 18;
+/// eBPF maps failed at runtime and could not be recovered.
+pub const EXIT_EBPF_FAILED: u8 = 19;
+/// The runc watcher stopped because of an error.
+pub const EXIT_WATCHER: u8 = 20;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not set up logging: {0}")]
+    Tracing(#[from] SetupTracingError),
+
+    #[error("invalid settings: {0}")]
+    Settings(#[from] SettingsError),
+
+    #[error("invalid namespace policies: {0}")]
+    NamespacePolicies(#[from] NamespacePoliciesError),
+
+    #[error("simulation failed: {0}")]
+    Simulate(#[from] SimulateError),
+
+    #[error("could not check whether BPF LSM is enabled: {0}")]
+    BpfLsm(#[from] CheckBpfLsmError),
+
+    #[error("kernel cannot run lockc: {0}")]
+    Kernel(#[from] CheckKernelError),
+
+    #[error("could not read the eBPF object: {0}")]
+    BpfObject(#[source] io::Error),
+
+    #[error("could not compute digests of the eBPF object: {0}")]
+    BpfDigests(#[from] IntegrityError),
+
+    #[error("could not prepare the host for eBPF: {0}")]
+    Host(#[from] SetupHostError),
+
+    #[error("could not create the bpffs directory: {0}")]
+    BpfFs(#[source] io::Error),
+
+    #[error("could not load eBPF programs: {0}")]
+    BpfLoad(#[from] LoadError),
+
+    #[error("could not attach eBPF programs: {0}")]
+    BpfAttach(#[from] AttachError),
+
+    #[error("could not initialize eBPF maps: {0}")]
+    BpfMaps(#[from] MapOperationError),
+
+    #[error("could not initialize eBPF logger: {0}")]
+    BpfLogger(#[from] aya_log::Error),
+
+    #[error("could not open eBPF events: {0}")]
+    PerfEvents(#[from] PerfError),
+
+    #[error("eBPF maps failed and could not be recovered: {0}")]
+    EbpfFailed(#[from] HealthError),
+
+    #[cfg(feature = "otel")]
+    #[error("could not set up OpenTelemetry export: {0}")]
+    Otel(#[from] opentelemetry::trace::TraceError),
+
+    #[cfg(feature = "kubernetes")]
+    #[error("could not print the CustomResourceDefinition: {0}")]
+    Crd(#[source] serde_yaml::Error),
+
+    #[error("could not acquire the instance lock: {0}")]
+    Instance(#[from] InstanceError),
+
+    #[error("could not determine the PID namespace: {0}")]
+    PidNs(#[from] PidNsError),
+
+    #[error("could not watch runc binaries with fanotify: {0}")]
+    Fanotify(#[source] io::Error),
+
+    #[error("could not set up the control socket: {0}")]
+    ControlSocket(#[source] io::Error),
+
+    #[error("handover from the running instance failed: {0}")]
+    Handover(#[from] HandoverError),
+
+    #[error("could not set up the metrics endpoint: {0}")]
+    Metrics(#[source] io::Error),
+
+    #[error("could not set up the status page: {0}")]
+    Status(#[source] io::Error),
+
+    #[error("could not get sockets passed by systemd: {0}")]
+    Systemd(#[from] SystemdError),
+
+    #[error("could not drop privileges: {0}")]
+    Privileges(#[from] PrivilegesError),
+
+    #[error("could not daemonize: {0}")]
+    Daemon(#[from] DaemonError),
+
+    #[error("could not start the async runtime: {0}")]
+    Runtime(#[source] io::Error),
+
+    #[error("could not start the runc watcher: {0}")]
+    WatcherBootstrap(#[from] FanotifyError),
+
+    #[error("runc watcher failed: {0}")]
+    Watcher(#[from] HandleRuncEventError),
+
+    #[error("runc watcher thread panicked")]
+    WatcherPanic,
+
+    #[error("fanotify event recording failed: {0}")]
+    Recorder(#[from] RecorderError),
+
+    #[error("could not set up the policy audit trail: {0}")]
+    PolicyAudit(#[from] PolicyAuditError),
+
+    #[error("could not set up fingerprints: {0}")]
+    Fingerprints(#[from] FingerprintError),
+
+    #[error("could not set up the reporter: {0}")]
+    Reporter(#[from] ReporterError),
+}
+
+impl Error {
+    /// Returns the exit code of lockc for the error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Tracing(_)
+            | Error::Daemon(_)
+            | Error::Runtime(_)
+            | Error::Metrics(_)
+            | Error::Status(_)
+            | Error::PolicyAudit(_)
+            | Error::Fingerprints(_)
+            | Error::Reporter(_) => EXIT_FAILURE,
+            #[cfg(feature = "otel")]
+            Error::Otel(_) => EXIT_FAILURE,
+            #[cfg(feature = "kubernetes")]
+            Error::Crd(_) => EXIT_FAILURE,
+            Error::Settings(_) | Error::NamespacePolicies(_) => EXIT_SETTINGS,
+            Error::Simulate(SimulateError::Mismatch(_)) => EXIT_SIMULATION,
+            Error::Simulate(_) => EXIT_SETTINGS,
+            Error::BpfLsm(CheckBpfLsmError::BpfLsmDisabled) => EXIT_LSM_NOT_ENABLED,
+            Error::BpfLsm(_) => EXIT_FAILURE,
+            Error::Kernel(CheckKernelError::IO(_)) => EXIT_FAILURE,
+            Error::Kernel(_) => EXIT_KERNEL_UNSUPPORTED,
+            Error::BpfObject(_)
+            | Error::BpfDigests(_)
+            | Error::Host(_)
+            | Error::BpfFs(_)
+            | Error::BpfLoad(_)
+            | Error::BpfAttach(_)
+            | Error::BpfMaps(_)
+            | Error::BpfLogger(_)
+            | Error::PerfEvents(_) => EXIT_BPF_LOAD,
+            Error::EbpfFailed(_) => EXIT_EBPF_FAILED,
+            Error::Instance(
+                InstanceError::AlreadyRunning(_) | InstanceError::TakeoverTimeout(_),
+            ) => EXIT_ALREADY_RUNNING,
+            Error::Instance(_) => EXIT_FAILURE,
+            Error::PidNs(_) => EXIT_PID_NAMESPACE,
+            Error::Handover(_) => EXIT_HANDOVER,
+            Error::Fanotify(_) => EXIT_FANOTIFY,
+            Error::ControlSocket(_) | Error::Systemd(_) => EXIT_CONTROL_SOCKET,
+            Error::Privileges(_) => EXIT_PRIVILEGES,
+            Error::WatcherBootstrap(_)
+            | Error::Watcher(_)
+            | Error::WatcherPanic
+            | Error::Recorder(_) => EXIT_WATCHER,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_exit_codes() {
+        assert_eq!(
+            Error::BpfLsm(CheckBpfLsmError::BpfLsmDisabled).exit_code(),
+            EXIT_LSM_NOT_ENABLED
+        );
+        assert_eq!(
+            Error::Kernel(CheckKernelError::BtfMissing(
+                "/sys/kernel/btf/vmlinux".into()
+            ))
+            .exit_code(),
+            EXIT_KERNEL_UNSUPPORTED
+        );
+        assert_eq!(
+            Error::Fanotify(io::Error::from_raw_os_error(libc::EPERM)).exit_code(),
+            EXIT_FANOTIFY
+        );
+        assert_eq!(
+            Error::Instance(InstanceError::AlreadyRunning("1".to_string())).exit_code(),
+            EXIT_ALREADY_RUNNING
+        );
+        assert_eq!(
+            Error::BpfObject(io::Error::from(io::ErrorKind::NotFound)).exit_code(),
+            EXIT_BPF_LOAD
+        );
+        assert_eq!(
+            Error::Host(SetupHostError::BpffsNotMounted("/sys/fs/bpf".into())).exit_code(),
+            EXIT_BPF_LOAD
+        );
+        assert_eq!(
+            Error::EbpfFailed(HealthError::PinMissing("CONTAINERS")).exit_code(),
+            EXIT_EBPF_FAILED
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! Output of violation events as Falco alerts in JSON, so tools consuming
+//! Falco output (e.g. falcosidekick) can forward lockc alerts as well.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lockc_common::Hook;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    io::{self, AsyncWrite, AsyncWriteExt},
+    net::UnixStream,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::warn;
+
+use crate::violations::ViolationEvent;
+
+/// Destination of Falco alerts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FalcoOutput {
+    Stdout,
+    /// Unix stream socket, which alerts are written to as JSON lines.
+    Unix(PathBuf),
+}
+
+impl From<&str> for FalcoOutput {
+    /// Parses `stdout` or a path of a unix socket.
+    fn from(s: &str) -> Self {
+        match s {
+            "stdout" | "-" => FalcoOutput::Stdout,
+            path => FalcoOutput::Unix(PathBuf::from(path)),
+        }
+    }
+}
+
+/// Alert in the format of Falco JSON output.
+#[derive(Debug, Serialize)]
+pub struct FalcoAlert {
+    pub output: String,
+    pub priority: &'static str,
+    pub rule: &'static str,
+    pub time: String,
+    pub output_fields: BTreeMap<&'static str, Value>,
+    pub source: &'static str,
+    pub tags: Vec<&'static str>,
+    pub hostname: String,
+}
+
+/// Returns the Falco rule name for the hook.
+fn rule(hook: Hook) -> &'static str {
+    match hook {
+        Hook::Syslog => "lockc: Read kernel log in container",
+        Hook::SbMount => "lockc: Bind mount in container",
+        Hook::TaskFixSetuid => "lockc: Change UID to root in container",
+        Hook::FileOpen => "lockc: Open denied file in container",
+        Hook::UnixStreamConnect => "lockc: Connect to denied socket in container",
+        Hook::InodePermission => "lockc: Access lockc eBPF objects in container",
+        Hook::Connect => "lockc: Connect to denied network in container",
+        Hook::FileReceive => "lockc: Receive host file descriptor in container",
+        Hook::MoveMount => "lockc: Attach detached bind mount in container",
+        Hook::ProcMasking => "lockc: Unmask procfs path in container",
+    }
+}
+
+/// Formats the time as RFC 3339 in UTC with nanoseconds, like Falco does.
+pub fn rfc3339(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = duration.as_secs();
+    let days = (secs / 86400) as i64;
+    let (hour, min, sec) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // Conversion of days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        hour,
+        min,
+        sec,
+        duration.subsec_nanos()
+    )
+}
+
+impl FalcoAlert {
+    pub fn new(event: &ViolationEvent, hostname: &str, time: SystemTime) -> Self {
+        let mut output_fields = BTreeMap::new();
+        output_fields.insert("container.id", Value::from(event.container_id.as_str()));
+        output_fields.insert("proc.pid", Value::from(event.pid));
+        output_fields.insert("evt.type", Value::from(event.hook.to_string()));
+        output_fields.insert(
+            "lockc.enforcement",
+            Value::from(event.enforcement.to_string()),
+        );
+        if let Some(detail) = &event.detail {
+            let field = match event.hook {
+                Hook::SbMount => "mount.source",
+                _ => "fd.name",
+            };
+            output_fields.insert(field, Value::from(detail.as_str()));
+        }
+        if event.killed {
+            output_fields.insert("lockc.killed", Value::from(true));
+        }
+        if event.suppressed > 0 {
+            output_fields.insert("lockc.suppressed", Value::from(event.suppressed));
+        }
+        if let Some(info) = &event.container {
+            let optional = [
+                ("container.name", &info.name),
+                ("container.image.repository", &info.image),
+                ("k8s.pod.name", &info.pod),
+                ("k8s.ns.name", &info.namespace),
+            ];
+            for (field, value) in optional {
+                if let Some(value) = value {
+                    output_fields.insert(field, Value::from(value.as_str()));
+                }
+            }
+            output_fields.insert(
+                "lockc.policy_level",
+                Value::from(info.policy_level.to_string()),
+            );
+        }
+
+        let time = rfc3339(time);
+        let fields = output_fields
+            .iter()
+            .map(|(field, value)| match value {
+                Value::String(value) => format!("{}={}", field, value),
+                value => format!("{}={}", field, value),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let priority = if event.killed { "Critical" } else { "Warning" };
+        FalcoAlert {
+            output: format!("{}: {} {} {}", time, priority, event.summary(), fields),
+            priority,
+            rule: rule(event.hook),
+            time,
+            output_fields,
+            source: "lockc",
+            tags: vec!["container", "lockc"],
+            hostname: hostname.to_string(),
+        }
+    }
+}
+
+/// Returns the name of the node, preferring the Kubernetes node name.
+pub fn hostname() -> String {
+    env::var("NODE_NAME")
+        .ok()
+        .or_else(|| {
+            fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()
+                .map(|hostname| hostname.trim().to_string())
+        })
+        .unwrap_or_default()
+}
+
+async fn write_alert<W: AsyncWrite + Unpin>(w: &mut W, alert: &FalcoAlert) -> io::Result<()> {
+    let mut buf = serde_json::to_vec(alert)?;
+    buf.push(b'\n');
+    w.write_all(&buf).await?;
+    w.flush().await
+}
+
+/// Writes Falco alerts for violations received from the channel, until it's
+/// closed. The unix socket is reconnected after failures.
+pub async fn forward(mut rx: broadcast::Receiver<ViolationEvent>, output: FalcoOutput) {
+    let hostname = hostname();
+    let mut stdout = io::stdout();
+    let mut stream: Option<UnixStream> = None;
+
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "Falco output lags behind violations");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let alert = FalcoAlert::new(&event, &hostname, SystemTime::now());
+
+        let res = match &output {
+            FalcoOutput::Stdout => write_alert(&mut stdout, &alert).await,
+            FalcoOutput::Unix(path) => {
+                if stream.is_none() {
+                    stream = UnixStream::connect(path).await.ok();
+                }
+                match stream.as_mut() {
+                    Some(stream) => write_alert(stream, &alert).await,
+                    None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+                }
+            }
+        };
+        if let Err(e) = res {
+            stream = None;
+            warn!(
+                error = e.to_string().as_str(),
+                "could not write Falco alert"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lockc_common::ContainerPolicyLevel;
+
+    use super::*;
+    use crate::violations::test_event;
+
+    #[test]
+    fn falco_output_from_str() {
+        assert_eq!(FalcoOutput::from("stdout"), FalcoOutput::Stdout);
+        assert_eq!(
+            FalcoOutput::from("/run/falco/lockc.sock"),
+            FalcoOutput::Unix(PathBuf::from("/run/falco/lockc.sock"))
+        );
+    }
+
+    #[test]
+    fn falco_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::new(1709210096, 5)),
+            "2024-02-29T12:34:56.000000005Z"
+        );
+    }
+
+    #[test]
+    fn falco_alert() {
+        let mut event = ViolationEvent {
+            pid: 42,
+            detail: Some("/sys/fs/".to_string()),
+            ..test_event(Hook::FileOpen)
+        };
+        if let Some(container) = event.container.as_mut() {
+            container.name = Some("nginx".to_string());
+            container.pod = Some("web".to_string());
+            container.namespace = Some("default".to_string());
+            container.policy_level = ContainerPolicyLevel::Restricted;
+        }
+        let alert = FalcoAlert::new(&event, "node1", UNIX_EPOCH);
+        let json = serde_json::to_value(&alert).unwrap();
+        assert_eq!(json["rule"], "lockc: Open denied file in container");
+        assert_eq!(json["priority"], "Warning");
+        assert_eq!(json["time"], "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(json["output_fields"]["fd.name"], "/sys/fs/");
+        assert_eq!(json["output_fields"]["k8s.pod.name"], "web");
+        assert_eq!(json["output_fields"]["proc.pid"], 42);
+        assert_eq!(json["output_fields"]["lockc.enforcement"], "enforce");
+        assert!(json["output_fields"]
+            .get("container.image.repository")
+            .is_none());
+        assert!(alert
+            .output
+            .starts_with("1970-01-01T00:00:00.000000000Z: Warning lockc denied opening /sys/fs/"));
+    }
+}
@@ -0,0 +1,567 @@
+//! Behavioral fingerprints of container images. Bind mount sources and
+//! opened files which allowed paths didn't allow, uses of capabilities and
+//! violations are recorded per image of the container they happened in, so
+//! `lockctl fingerprint suggest` can suggest a policy for each image from
+//! what its containers actually do. Fingerprints are saved to a local store
+//! periodically and read again on start, so they keep growing over restarts.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use lockc_common::{
+    control::{capability_name, ImageFingerprintInfo, ObservedCountInfo, ObservedPathInfo},
+    ContainerPolicyLevel, Hook,
+};
+use thiserror::Error;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, oneshot,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    communication::{EbpfCommand, EbpfRequest},
+    learning::MountAttempts,
+    registry::ContainerRegistry,
+    settings,
+    violations::ViolationEvent,
+};
+
+/// Interval of collecting uses of capabilities from the eBPF map.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Error, Debug)]
+pub enum FingerprintError {
+    #[error("could not read the fingerprint store {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid fingerprint store {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("could not write the fingerprint store {}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Uses of the capability by the container, read from the
+/// `LEARNED_CAPABILITIES` eBPF map.
+#[derive(Debug)]
+pub struct CapabilityUses {
+    pub container_id: String,
+    pub capability: u32,
+    pub count: u64,
+}
+
+/// Behavior of a container observed by lockc.
+#[derive(Debug)]
+pub enum Observation<'a> {
+    /// Bind mount of the source which the allowed paths list of the policy
+    /// level didn't allow.
+    Mount {
+        policy_level: ContainerPolicyLevel,
+        path: &'a str,
+    },
+    /// Opening a file which the allowed paths list of the policy level
+    /// didn't allow.
+    FileOpen {
+        policy_level: ContainerPolicyLevel,
+        path: &'a str,
+    },
+    Capability(u32),
+    Violation(Hook),
+}
+
+/// Paths per allowed paths list which didn't allow them, with the number of
+/// observations.
+#[derive(Default)]
+struct ObservedPaths {
+    restricted: BTreeMap<String, u64>,
+    baseline: BTreeMap<String, u64>,
+}
+
+impl ObservedPaths {
+    fn record(&mut self, policy_level: ContainerPolicyLevel, path: &str, count: u64) {
+        let paths = match policy_level {
+            ContainerPolicyLevel::Restricted | ContainerPolicyLevel::Offline => {
+                &mut self.restricted
+            }
+            ContainerPolicyLevel::Baseline => &mut self.baseline,
+            _ => return,
+        };
+        *paths.entry(path.to_string()).or_default() += count;
+    }
+
+    fn list(&self) -> Vec<ObservedPathInfo> {
+        let restricted = self
+            .restricted
+            .iter()
+            .map(|entry| (ContainerPolicyLevel::Restricted, entry));
+        let baseline = self
+            .baseline
+            .iter()
+            .map(|entry| (ContainerPolicyLevel::Baseline, entry));
+        restricted
+            .chain(baseline)
+            .map(|(policy_level, (path, count))| ObservedPathInfo {
+                path: path.clone(),
+                policy_level,
+                count: *count,
+            })
+            .collect()
+    }
+}
+
+fn list_counts(counts: &BTreeMap<String, u64>) -> Vec<ObservedCountInfo> {
+    counts
+        .iter()
+        .map(|(name, count)| ObservedCountInfo {
+            name: name.clone(),
+            count: *count,
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct ImageFingerprint {
+    containers: u64,
+    mounts: ObservedPaths,
+    file_opens: ObservedPaths,
+    capabilities: BTreeMap<String, u64>,
+    violations: BTreeMap<String, u64>,
+}
+
+impl ImageFingerprint {
+    fn from_info(info: ImageFingerprintInfo) -> Self {
+        let mut fingerprint = ImageFingerprint {
+            containers: info.containers,
+            ..Default::default()
+        };
+        for path in info.mounts {
+            fingerprint
+                .mounts
+                .record(path.policy_level, &path.path, path.count);
+        }
+        for path in info.file_opens {
+            fingerprint
+                .file_opens
+                .record(path.policy_level, &path.path, path.count);
+        }
+        for capability in info.capabilities {
+            *fingerprint.capabilities.entry(capability.name).or_default() += capability.count;
+        }
+        for violation in info.violations {
+            *fingerprint.violations.entry(violation.name).or_default() += violation.count;
+        }
+        fingerprint
+    }
+
+    fn info(&self, image: &str) -> ImageFingerprintInfo {
+        ImageFingerprintInfo {
+            image: image.to_string(),
+            containers: self.containers,
+            mounts: self.mounts.list(),
+            file_opens: self.file_opens.list(),
+            capabilities: list_counts(&self.capabilities),
+            violations: list_counts(&self.violations),
+        }
+    }
+}
+
+/// Fingerprints of images, read from the store and recorded since lockc
+/// started.
+#[derive(Default)]
+pub struct Fingerprints {
+    images: BTreeMap<String, ImageFingerprint>,
+    /// Containers already counted in fingerprints of their images.
+    counted: HashSet<String>,
+    store: Option<PathBuf>,
+    /// Whether fingerprints changed since they were saved.
+    changed: bool,
+}
+
+impl Fingerprints {
+    /// Reads fingerprints from the store, if configured. A missing store is
+    /// created on the first save.
+    pub fn open(settings: &settings::Fingerprints) -> Result<Self, FingerprintError> {
+        let mut fingerprints = Fingerprints {
+            store: settings.store.clone(),
+            ..Default::default()
+        };
+        if let Some(path) = &settings.store {
+            fingerprints.read_store(path)?;
+        }
+        Ok(fingerprints)
+    }
+
+    fn read_store(&mut self, path: &Path) -> Result<(), FingerprintError> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(source) => {
+                return Err(FingerprintError::Read {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        let images: Vec<ImageFingerprintInfo> =
+            serde_json::from_slice(&data).map_err(|source| FingerprintError::Parse {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        for info in images {
+            self.images
+                .insert(info.image.clone(), ImageFingerprint::from_info(info));
+        }
+        Ok(())
+    }
+
+    /// Records the observation, made `count` times, in the fingerprint of the
+    /// image of the container.
+    pub fn record(
+        &mut self,
+        container_id: &str,
+        image: &str,
+        observation: Observation<'_>,
+        count: u64,
+    ) {
+        let fingerprint = self.images.entry(image.to_string()).or_default();
+        if self.counted.insert(container_id.to_string()) {
+            fingerprint.containers += 1;
+        }
+        match observation {
+            Observation::Mount { policy_level, path } => {
+                fingerprint.mounts.record(policy_level, path, count)
+            }
+            Observation::FileOpen { policy_level, path } => {
+                fingerprint.file_opens.record(policy_level, path, count)
+            }
+            Observation::Capability(capability) => {
+                *fingerprint
+                    .capabilities
+                    .entry(capability_name(capability))
+                    .or_default() += count
+            }
+            Observation::Violation(hook) => {
+                *fingerprint.violations.entry(hook.to_string()).or_default() += count
+            }
+        }
+        self.changed = true;
+    }
+
+    /// Returns fingerprints of images whose reference contains the given
+    /// string, or of all images.
+    pub fn list(&self, image: Option<&str>) -> Vec<ImageFingerprintInfo> {
+        self.images
+            .iter()
+            .filter(|(name, _)| image.map(|image| name.contains(image)).unwrap_or(true))
+            .map(|(name, fingerprint)| fingerprint.info(name))
+            .collect()
+    }
+
+    /// Writes changed fingerprints to the store. The store is replaced
+    /// atomically, so a crash doesn't leave it truncated.
+    pub fn save(&mut self) -> Result<(), FingerprintError> {
+        let path = match &self.store {
+            Some(path) if self.changed => path,
+            _ => return Ok(()),
+        };
+        let write_error = |source| FingerprintError::Write {
+            path: path.clone(),
+            source,
+        };
+        let data = serde_json::to_vec_pretty(&self.list(None))
+            .map_err(io::Error::from)
+            .map_err(write_error)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(write_error)?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, data).map_err(write_error)?;
+        fs::rename(&tmp_path, path).map_err(write_error)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Returns the image of the registered container.
+fn container_image(containers: &RwLock<ContainerRegistry>, container_id: &str) -> Option<String> {
+    containers
+        .read()
+        .ok()?
+        .get(container_id)
+        .and_then(|info| info.image.clone())
+}
+
+/// Records bind mount attempts collected in learning mode.
+pub fn record_mounts(
+    fingerprints: &RwLock<Fingerprints>,
+    containers: &RwLock<ContainerRegistry>,
+    attempts: &[MountAttempts],
+) {
+    let mut fingerprints = match fingerprints.write() {
+        Ok(fingerprints) => fingerprints,
+        Err(_) => {
+            warn!("fingerprints are poisoned");
+            return;
+        }
+    };
+    for attempts in attempts {
+        if let Some(image) = container_image(containers, &attempts.container_id) {
+            fingerprints.record(
+                &attempts.container_id,
+                &image,
+                Observation::Mount {
+                    policy_level: attempts.policy_level,
+                    path: &attempts.path,
+                },
+                attempts.count,
+            );
+        }
+    }
+}
+
+/// Records the violation, and its path if it's a denied bind mount or
+/// opened file.
+fn record_violation(fingerprints: &mut Fingerprints, event: &ViolationEvent) {
+    let container = match &event.container {
+        Some(container) => container,
+        None => return,
+    };
+    let image = match &container.image {
+        Some(image) => image,
+        None => return,
+    };
+    let count = 1 + event.suppressed;
+    fingerprints.record(
+        &event.container_id,
+        image,
+        Observation::Violation(event.hook),
+        count,
+    );
+    let policy_level = container.policy_level;
+    let observation = match (event.hook, event.detail.as_deref()) {
+        (Hook::SbMount, Some(path)) => Observation::Mount { policy_level, path },
+        (Hook::FileOpen, Some(path)) => Observation::FileOpen { policy_level, path },
+        _ => return,
+    };
+    fingerprints.record(&event.container_id, image, observation, count);
+}
+
+/// Records violation events in fingerprints, until all senders are dropped.
+pub async fn record_violations(
+    mut rx: broadcast::Receiver<ViolationEvent>,
+    fingerprints: Arc<RwLock<Fingerprints>>,
+) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "fingerprints lag behind violations");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        match fingerprints.write() {
+            Ok(mut fingerprints) => record_violation(&mut fingerprints, &event),
+            Err(_) => {
+                warn!("fingerprints are poisoned");
+                break;
+            }
+        }
+    }
+    debug!("recording violations in fingerprints finished");
+}
+
+/// Requests uses of capabilities from the eBPF thread.
+async fn take(
+    ebpf_tx: &mpsc::WeakSender<EbpfRequest>,
+) -> Option<Result<Vec<CapabilityUses>, String>> {
+    let ebpf_tx = ebpf_tx.upgrade()?;
+    let (responder_tx, responder_rx) = oneshot::channel();
+    ebpf_tx
+        .send(EbpfCommand::TakeLearnedCapabilities { responder_tx }.into())
+        .await
+        .ok()?;
+    let res = responder_rx.await.ok()?;
+    Some(res.map_err(|e| e.to_string()))
+}
+
+fn save(fingerprints: &RwLock<Fingerprints>) {
+    let res = match fingerprints.write() {
+        Ok(mut fingerprints) => fingerprints.save(),
+        Err(_) => return,
+    };
+    if let Err(e) = res {
+        warn!(
+            error = e.to_string().as_str(),
+            "could not save fingerprints"
+        );
+    }
+}
+
+/// Collects uses of capabilities from the eBPF map periodically and saves
+/// fingerprints every `save_interval`, until the eBPF thread is gone.
+/// Fingerprints are saved once more before returning.
+pub async fn collect(
+    ebpf_tx: mpsc::WeakSender<EbpfRequest>,
+    fingerprints: Arc<RwLock<Fingerprints>>,
+    containers: Arc<RwLock<ContainerRegistry>>,
+    save_interval: Duration,
+) {
+    let mut interval = tokio::time::interval(COLLECT_INTERVAL);
+    let mut saved = Instant::now();
+    loop {
+        interval.tick().await;
+        if saved.elapsed() >= save_interval {
+            save(&fingerprints);
+            saved = Instant::now();
+        }
+        let uses = match take(&ebpf_tx).await {
+            Some(Ok(uses)) => uses,
+            Some(Err(e)) => {
+                warn!(
+                    error = e.as_str(),
+                    "could not collect uses of capabilities for fingerprints"
+                );
+                continue;
+            }
+            None => break,
+        };
+        if uses.is_empty() {
+            continue;
+        }
+        let mut fingerprints = match fingerprints.write() {
+            Ok(fingerprints) => fingerprints,
+            Err(_) => {
+                warn!("fingerprints are poisoned");
+                break;
+            }
+        };
+        for uses in uses {
+            if let Some(image) = container_image(&containers, &uses.container_id) {
+                fingerprints.record(
+                    &uses.container_id,
+                    &image,
+                    Observation::Capability(uses.capability),
+                    uses.count,
+                );
+            }
+        }
+    }
+    save(&fingerprints);
+    debug!("fingerprint collection finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::Enforcement;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::violations::test_event;
+
+    fn event(hook: Hook, detail: Option<&str>, image: Option<&str>) -> ViolationEvent {
+        let mut event = ViolationEvent {
+            detail: detail.map(str::to_string),
+            enforcement: Enforcement::Complain,
+            suppressed: 1,
+            ..test_event(hook)
+        };
+        if let Some(container) = event.container.as_mut() {
+            container.image = image.map(str::to_string);
+            container.enforcement = Enforcement::Complain;
+        }
+        event
+    }
+
+    #[test]
+    fn fingerprints_record_and_reload() {
+        let dir = tempdir().unwrap();
+        let settings = settings::Fingerprints {
+            enabled: true,
+            store: Some(dir.path().join("lockc").join("fingerprints.json")),
+            save_interval_s: 60,
+        };
+
+        let mut fingerprints = Fingerprints::open(&settings).unwrap();
+        record_violation(
+            &mut fingerprints,
+            &event(Hook::FileOpen, Some("/proc/kcore"), Some("nginx")),
+        );
+        record_violation(&mut fingerprints, &event(Hook::Syslog, None, Some("nginx")));
+        record_violation(&mut fingerprints, &event(Hook::Syslog, None, None));
+        fingerprints.record(
+            "def",
+            "nginx",
+            Observation::Mount {
+                policy_level: ContainerPolicyLevel::Offline,
+                path: "/srv/data",
+            },
+            3,
+        );
+        fingerprints.record("def", "nginx", Observation::Capability(21), 4);
+        fingerprints.record("ghi", "redis", Observation::Capability(10), 1);
+        fingerprints.save().unwrap();
+
+        let fingerprints = Fingerprints::open(&settings).unwrap();
+        assert_eq!(fingerprints.list(None).len(), 2);
+        let images = fingerprints.list(Some("ngi"));
+        assert_eq!(images.len(), 1);
+        let nginx = &images[0];
+        assert_eq!(nginx.image, "nginx");
+        assert_eq!(nginx.containers, 2);
+        assert_eq!(
+            nginx.mounts,
+            vec![ObservedPathInfo {
+                path: "/srv/data".to_string(),
+                policy_level: ContainerPolicyLevel::Restricted,
+                count: 3,
+            }]
+        );
+        assert_eq!(
+            nginx.file_opens,
+            vec![ObservedPathInfo {
+                path: "/proc/kcore".to_string(),
+                policy_level: ContainerPolicyLevel::Baseline,
+                count: 2,
+            }]
+        );
+        assert_eq!(
+            nginx.capabilities,
+            vec![ObservedCountInfo {
+                name: "CAP_SYS_ADMIN".to_string(),
+                count: 4,
+            }]
+        );
+        assert_eq!(
+            nginx.violations,
+            vec![
+                ObservedCountInfo {
+                    name: "file_open".to_string(),
+                    count: 2,
+                },
+                ObservedCountInfo {
+                    name: "syslog".to_string(),
+                    count: 2,
+                },
+            ]
+        );
+    }
+}
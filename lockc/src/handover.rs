@@ -0,0 +1,529 @@
+//! Handover of the state of a running instance of lockc to a new one, for
+//! upgrades in place (`lockc --handover`). The new instance connects to the
+//! handover socket of the running one and receives file descriptors of the
+//! eBPF maps tracking containers and processes, and of the listening
+//! sockets. It pins the maps, attaches its programs and watches runc before
+//! asking for the container registry, so at every moment at least one
+//! instance enforces policies and handles runc executions. The running
+//! instance exits once the new one confirms that it took over.
+//!
+//! Messages are JSON lines. File descriptors are passed with `SCM_RIGHTS`
+//! along with the offer.
+
+use std::{
+    fs,
+    io::{self, IoSlice, IoSliceMut, Write},
+    net::TcpListener as StdTcpListener,
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::{UnixListener as StdUnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use lockc_common::control::ContainerInfo;
+use nix::{
+    cmsg_space,
+    errno::Errno,
+    sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
+
+use crate::{cgroups::ContainerCgroup, load::pin_maps, registry::ContainerRegistry, systemd};
+
+/// Default path of the handover socket.
+pub const HANDOVER_SOCKET_PATH: &str = "/run/lockc/handover.sock";
+
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Maximum number of file descriptors in the offer.
+const MAX_FDS: usize = 16;
+
+/// How long an instance waits for a message of the other one. The new
+/// instance loads and attaches eBPF programs between the offer and the
+/// request of the registry.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum HandoverError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Errno(#[from] Errno),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(
+        "could not connect to the running instance on {path}: {source}, use --takeover if it doesn't support handover"
+    )]
+    Connect {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("running instance refused the handover: {0}")]
+    Refused(String),
+
+    #[error("unsupported handover protocol version {0}")]
+    Version(u32),
+
+    #[error("unexpected handover message, expected {0}")]
+    Unexpected(&'static str),
+
+    #[error("connection closed during handover")]
+    Closed,
+
+    #[error("offer lists {expected} file descriptors, received {received}")]
+    Fds { expected: usize, received: usize },
+
+    #[error("container registry is poisoned")]
+    Poisoned,
+}
+
+/// Resource whose file descriptor is handed over.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Resource {
+    /// Pinned eBPF map with the given name.
+    Map(String),
+    /// Listener of the control API.
+    Control,
+    /// Listener of the metrics endpoint.
+    Metrics,
+    /// Listener of the status page.
+    Status,
+    /// Listener of the handover socket.
+    Handover,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HandedContainer {
+    info: ContainerInfo,
+    cgroup: Option<ContainerCgroup>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "message", rename_all = "snake_case")]
+enum Message {
+    /// Sent by the new instance after connecting.
+    Request {
+        version: u32,
+    },
+    /// Sent with file descriptors of the listed resources.
+    Offer {
+        resources: Vec<Resource>,
+    },
+    /// Sent by the new instance when its programs are attached and runc is
+    /// watched.
+    Ready,
+    Registry {
+        containers: Vec<HandedContainer>,
+    },
+    /// Sent by the new instance when it took over the registry.
+    Done,
+    Refused {
+        reason: String,
+    },
+}
+
+/// Stream of handover messages.
+struct Connection {
+    stream: UnixStream,
+    /// Received bytes which are not a whole message yet.
+    buf: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: UnixStream) -> Result<Self, io::Error> {
+        stream.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDOVER_TIMEOUT))?;
+        Ok(Connection {
+            stream,
+            buf: Vec::new(),
+        })
+    }
+
+    fn send(&mut self, message: &Message, fds: &[RawFd]) -> Result<(), HandoverError> {
+        let mut data = serde_json::to_vec(message)?;
+        data.push(b'\n');
+        let mut sent = 0;
+        if !fds.is_empty() {
+            // File descriptors are attached to the first chunk.
+            sent = sendmsg::<()>(
+                self.stream.as_raw_fd(),
+                &[IoSlice::new(&data)],
+                &[ControlMessage::ScmRights(fds)],
+                MsgFlags::empty(),
+                None,
+            )?;
+        }
+        self.stream.write_all(&data[sent..])?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<(Message, Vec<OwnedFd>), HandoverError> {
+        let mut fds = Vec::new();
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                return Ok((serde_json::from_slice(&line)?, fds));
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut cmsg_buf = cmsg_space!([RawFd; MAX_FDS]);
+            let msg = recvmsg::<()>(
+                self.stream.as_raw_fd(),
+                &mut [IoSliceMut::new(&mut chunk)],
+                Some(&mut cmsg_buf),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            )?;
+            for cmsg in msg.cmsgs() {
+                if let ControlMessageOwned::ScmRights(received) = cmsg {
+                    // SAFETY: the file descriptors were just received and
+                    // are not owned by anything else.
+                    fds.extend(
+                        received
+                            .into_iter()
+                            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                    );
+                }
+            }
+            if msg.bytes == 0 {
+                return Err(HandoverError::Closed);
+            }
+            self.buf.extend_from_slice(&chunk[..msg.bytes]);
+        }
+    }
+}
+
+/// Binds the handover socket, replacing a stale one left by a previous
+/// instance. The socket is accessible only by root.
+pub fn bind<P: AsRef<Path>>(path: P) -> Result<StdUnixListener, io::Error> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::remove_file(path) {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = StdUnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Resources received from the running instance, used by the new one.
+pub struct Handover {
+    conn: Connection,
+    maps: Vec<(String, OwnedFd)>,
+    pub control: Option<StdUnixListener>,
+    pub metrics: Option<StdTcpListener>,
+    pub status: Option<StdTcpListener>,
+    pub listener: Option<StdUnixListener>,
+}
+
+impl Handover {
+    /// Connects to the running instance and receives its resources. The
+    /// running instance keeps working until [`Handover::complete`].
+    pub fn request<P: AsRef<Path>>(path: P) -> Result<Self, HandoverError> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path).map_err(|source| HandoverError::Connect {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut conn = Connection::new(stream)?;
+        conn.send(
+            &Message::Request {
+                version: PROTOCOL_VERSION,
+            },
+            &[],
+        )?;
+
+        let (resources, fds) = match conn.recv()? {
+            (Message::Offer { resources }, fds) => (resources, fds),
+            (Message::Refused { reason }, _) => return Err(HandoverError::Refused(reason)),
+            _ => return Err(HandoverError::Unexpected("offer")),
+        };
+        if resources.len() != fds.len() {
+            return Err(HandoverError::Fds {
+                expected: resources.len(),
+                received: fds.len(),
+            });
+        }
+
+        let mut handover = Handover {
+            conn,
+            maps: Vec::new(),
+            control: None,
+            metrics: None,
+            status: None,
+            listener: None,
+        };
+        for (resource, fd) in resources.into_iter().zip(fds) {
+            debug!(resource = ?resource, "received resource of the running instance");
+            match resource {
+                Resource::Map(map) => handover.maps.push((map, fd)),
+                Resource::Control => handover.control = Some(StdUnixListener::from(fd)),
+                Resource::Metrics => handover.metrics = Some(StdTcpListener::from(fd)),
+                Resource::Status => handover.status = Some(StdTcpListener::from(fd)),
+                Resource::Handover => handover.listener = Some(StdUnixListener::from(fd)),
+            }
+        }
+        Ok(handover)
+    }
+
+    /// Pins the maps of the running instance under `path_base`, so the
+    /// programs of the new instance use them instead of empty ones.
+    pub fn pin_maps(&self, path_base: &Path) -> Result<(), HandoverError> {
+        pin_maps(path_base, &self.maps)?;
+        Ok(())
+    }
+
+    /// Takes over the container registry of the running instance and lets
+    /// it exit. Has to be called once the programs of the new instance are
+    /// attached and runc is watched. Returns the number of restored
+    /// containers.
+    pub fn complete(
+        mut self,
+        registry: &RwLock<ContainerRegistry>,
+    ) -> Result<usize, HandoverError> {
+        self.conn.send(&Message::Ready, &[])?;
+        let containers = match self.conn.recv()? {
+            (Message::Registry { containers }, _) => containers,
+            (Message::Refused { reason }, _) => return Err(HandoverError::Refused(reason)),
+            _ => return Err(HandoverError::Unexpected("registry")),
+        };
+
+        let mut restored = 0;
+        {
+            let mut registry = registry.write().map_err(|_| HandoverError::Poisoned)?;
+            for container in containers {
+                if registry.restore(container.info, container.cgroup) {
+                    restored += 1;
+                }
+            }
+        }
+
+        self.conn.send(&Message::Done, &[])?;
+        Ok(restored)
+    }
+}
+
+/// Serves handover requests of new instances in the running one.
+pub struct HandoverServer {
+    listener: StdUnixListener,
+    resources: Vec<(Resource, OwnedFd)>,
+}
+
+impl HandoverServer {
+    /// Creates the server handing over the given maps and duplicates of the
+    /// given listeners.
+    pub fn new(
+        listener: StdUnixListener,
+        maps: Vec<(String, OwnedFd)>,
+        control: &StdUnixListener,
+        metrics: Option<&StdTcpListener>,
+        status: Option<&StdTcpListener>,
+    ) -> Result<Self, io::Error> {
+        let mut resources: Vec<(Resource, OwnedFd)> = maps
+            .into_iter()
+            .map(|(map, fd)| (Resource::Map(map), fd))
+            .collect();
+        resources.push((Resource::Control, control.try_clone()?.into()));
+        if let Some(metrics) = metrics {
+            resources.push((Resource::Metrics, metrics.try_clone()?.into()));
+        }
+        if let Some(status) = status {
+            resources.push((Resource::Status, status.try_clone()?.into()));
+        }
+        resources.push((Resource::Handover, listener.try_clone()?.into()));
+        Ok(HandoverServer {
+            listener,
+            resources,
+        })
+    }
+
+    /// Accepts handover requests. After a successful handover, lockc exits.
+    /// A failed one is logged and the running instance keeps working.
+    pub fn serve(self, registry: Arc<RwLock<ContainerRegistry>>) {
+        for stream in self.listener.incoming() {
+            match stream {
+                Ok(stream) => match self.handle(stream, &registry) {
+                    Ok(()) => {
+                        info!("handed over to the new instance of lockc, exiting");
+                        if let Err(e) = systemd::notify("STOPPING=1") {
+                            warn!(
+                                error = e.to_string().as_str(),
+                                "could not notify systemd about stopping"
+                            );
+                        }
+                        process::exit(0);
+                    }
+                    Err(e) => warn!(
+                        error = e.to_string().as_str(),
+                        "handover failed, keeping running"
+                    ),
+                },
+                Err(e) => error!(
+                    error = e.to_string().as_str(),
+                    "could not accept a handover connection"
+                ),
+            }
+        }
+    }
+
+    fn handle(
+        &self,
+        stream: UnixStream,
+        registry: &RwLock<ContainerRegistry>,
+    ) -> Result<(), HandoverError> {
+        let mut conn = Connection::new(stream)?;
+        match conn.recv()? {
+            (Message::Request { version }, _) if version == PROTOCOL_VERSION => {}
+            (Message::Request { version }, _) => {
+                conn.send(
+                    &Message::Refused {
+                        reason: format!("unsupported protocol version {}", version),
+                    },
+                    &[],
+                )?;
+                return Err(HandoverError::Version(version));
+            }
+            _ => return Err(HandoverError::Unexpected("request")),
+        }
+        info!("new instance of lockc requested handover");
+
+        let resources = self.resources.iter().map(|(r, _)| r.clone()).collect();
+        let fds: Vec<RawFd> = self
+            .resources
+            .iter()
+            .map(|(_, fd)| fd.as_raw_fd())
+            .collect();
+        conn.send(&Message::Offer { resources }, &fds)?;
+
+        // The new instance loads its programs in the meantime.
+        match conn.recv()? {
+            (Message::Ready, _) => {}
+            _ => return Err(HandoverError::Unexpected("ready")),
+        }
+        let containers = {
+            let registry = registry.read().map_err(|_| HandoverError::Poisoned)?;
+            registry
+                .list()
+                .into_iter()
+                .map(|info| HandedContainer {
+                    cgroup: registry.cgroup(&info.id).cloned(),
+                    info,
+                })
+                .collect()
+        };
+        conn.send(&Message::Registry { containers }, &[])?;
+
+        match conn.recv()? {
+            (Message::Done, _) => Ok(()),
+            _ => Err(HandoverError::Unexpected("done")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, thread};
+
+    use lockc_common::{ContainerPolicyLevel, Enforcement};
+
+    use super::*;
+    use crate::registry::ContainerMetadata;
+
+    #[test]
+    fn handover_protocol() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("handover.sock");
+        let listener = bind(&socket_path).unwrap();
+        let control = StdUnixListener::bind(dir.path().join("control.sock")).unwrap();
+
+        // A regular file stands in for an eBPF map, which is just a file
+        // descriptor for the protocol.
+        fs::write(dir.path().join("PROCESSES"), "processes").unwrap();
+        let map = fs::File::open(dir.path().join("PROCESSES")).unwrap();
+        let server = HandoverServer::new(
+            listener,
+            vec![("PROCESSES".to_string(), map.into())],
+            &control,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let registry = RwLock::new(ContainerRegistry::default());
+        registry.write().unwrap().insert(
+            "abc".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata {
+                name: Some("nginx".to_string()),
+                ..Default::default()
+            },
+        );
+        registry
+            .write()
+            .unwrap()
+            .set_enforcement("abc", Enforcement::Complain);
+
+        let server_thread = thread::spawn(move || {
+            let (stream, _) = server.listener.accept().unwrap();
+            server.handle(stream, &registry)
+        });
+
+        let mut handover = Handover::request(&socket_path).unwrap();
+        assert!(handover.control.is_some());
+        assert!(handover.metrics.is_none());
+        assert!(handover.status.is_none());
+        assert!(handover.listener.is_some());
+        let (map, fd) = handover.maps.pop().unwrap();
+        assert_eq!(map, "PROCESSES");
+        let mut contents = String::new();
+        fs::File::from(fd).read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "processes");
+
+        let new_registry = RwLock::new(ContainerRegistry::default());
+        assert_eq!(handover.complete(&new_registry).unwrap(), 1);
+        server_thread.join().unwrap().unwrap();
+
+        let new_registry = new_registry.read().unwrap();
+        let info = new_registry.get("abc").unwrap();
+        assert_eq!(info.name.as_deref(), Some("nginx"));
+        assert_eq!(info.enforcement, Enforcement::Complain);
+    }
+
+    #[test]
+    fn handover_version_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("handover.sock");
+        let listener = bind(&socket_path).unwrap();
+        let control = StdUnixListener::bind(dir.path().join("control.sock")).unwrap();
+        let server = HandoverServer::new(listener, Vec::new(), &control, None, None).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let (stream, _) = server.listener.accept().unwrap();
+            server.handle(stream, &RwLock::new(ContainerRegistry::default()))
+        });
+
+        let mut conn = Connection::new(UnixStream::connect(&socket_path).unwrap()).unwrap();
+        conn.send(&Message::Request { version: 0 }, &[]).unwrap();
+        assert!(matches!(conn.recv().unwrap().0, Message::Refused { .. }));
+        assert!(matches!(
+            server_thread.join().unwrap(),
+            Err(HandoverError::Version(0))
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+//! Response to attacks on containers. Containers whose processes are denied
+//! too many operations in a short time, or a high-severity operation (e.g.
+//! accessing pinned eBPF objects of lockc), are frozen through the cgroup v2
+//! freezer, so an attacker can't try further until an operator looks at the
+//! container and thaws it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use lockc_common::{Enforcement, Hook};
+use thiserror::Error;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, warn};
+
+use crate::{registry::ContainerRegistry, settings::IncidentResponse, violations::ViolationEvent};
+
+/// File of the cgroup v2 freezer.
+const FREEZE_FILE: &str = "cgroup.freeze";
+
+#[derive(Error, Debug)]
+pub enum FreezeError {
+    #[error("cgroup of container {0} is unknown")]
+    NoCgroup(String),
+
+    #[error("cgroup {} has no freezer, cgroup v2 is required", .0.display())]
+    NoFreezer(PathBuf),
+
+    #[error("could not write to {}: {source}", .path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// Freezes or thaws the cgroup.
+pub fn set_frozen(cgroup: &Path, frozen: bool) -> Result<(), FreezeError> {
+    let path = cgroup.join(FREEZE_FILE);
+    if !path.exists() {
+        return Err(FreezeError::NoFreezer(cgroup.to_path_buf()));
+    }
+    fs::write(&path, if frozen { "1" } else { "0" })
+        .map_err(|source| FreezeError::Write { path, source })
+}
+
+/// Freezes or thaws the container through its cgroup.
+pub fn set_container_frozen(
+    containers: &RwLock<ContainerRegistry>,
+    container_id: &str,
+    frozen: bool,
+) -> Result<(), FreezeError> {
+    let cgroup = containers
+        .read()
+        .ok()
+        .and_then(|containers| containers.cgroup(container_id).map(|c| c.path.clone()))
+        .ok_or_else(|| FreezeError::NoCgroup(container_id.to_string()))?;
+    set_frozen(&cgroup, frozen)
+}
+
+/// Reason of freezing a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trigger {
+    /// Denial of an operation of a high-severity hook.
+    Hook(Hook),
+    /// Number of denials in the window reached the threshold.
+    Threshold(u64),
+}
+
+impl std::fmt::Display for Trigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trigger::Hook(hook) => write!(f, "denied {}", hook),
+            Trigger::Threshold(denials) => write!(f, "{} denials", denials),
+        }
+    }
+}
+
+/// Counts denials of containers and decides which containers get frozen.
+pub struct DenialCounter {
+    settings: IncidentResponse,
+    /// Times of denials of each container within the window, with their
+    /// counts.
+    denials: HashMap<String, VecDeque<(Instant, u64)>>,
+}
+
+impl DenialCounter {
+    pub fn new(settings: IncidentResponse) -> Self {
+        DenialCounter {
+            settings,
+            denials: HashMap::new(),
+        }
+    }
+
+    /// Counts the violation and returns why its container has to be frozen,
+    /// if it has to. Counted denials of a frozen container are forgotten, so
+    /// it's not frozen again right after being thawed.
+    pub fn push(&mut self, event: &ViolationEvent, now: Instant) -> Option<Trigger> {
+        if event.enforcement != Enforcement::Enforce {
+            return None;
+        }
+        let policy_level = event.container.as_ref()?.policy_level;
+        if !self.settings.policy_levels.contains(&policy_level) {
+            return None;
+        }
+        if self.settings.immediate_hooks.contains(&event.hook) {
+            self.denials.remove(&event.container_id);
+            return Some(Trigger::Hook(event.hook));
+        }
+
+        let window = Duration::from_secs(self.settings.window_s);
+        // Forget containers without recent denials, deleted ones included.
+        self.denials.retain(|_, denials| {
+            while denials
+                .front()
+                .is_some_and(|(time, _)| now.duration_since(*time) >= window)
+            {
+                denials.pop_front();
+            }
+            !denials.is_empty()
+        });
+        let denials = self.denials.entry(event.container_id.clone()).or_default();
+        denials.push_back((now, 1 + event.suppressed));
+        let count: u64 = denials.iter().map(|(_, count)| count).sum();
+        if count < self.settings.denial_threshold {
+            return None;
+        }
+        self.denials.remove(&event.container_id);
+        Some(Trigger::Threshold(count))
+    }
+}
+
+/// Freezes containers in response to violation events, until all senders
+/// are dropped.
+pub async fn respond(
+    mut rx: broadcast::Receiver<ViolationEvent>,
+    settings: IncidentResponse,
+    containers: Arc<RwLock<ContainerRegistry>>,
+) {
+    let mut counter = DenialCounter::new(settings);
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "incident response lags behind violations");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let trigger = match counter.push(&event, Instant::now()) {
+            Some(trigger) => trigger,
+            None => continue,
+        };
+        let container = event.container.as_ref();
+        match set_container_frozen(&containers, &event.container_id, true) {
+            Ok(()) => error!(
+                container_id = event.container_id.as_str(),
+                pod = container.and_then(|c| c.pod.as_deref()),
+                namespace = container.and_then(|c| c.namespace.as_deref()),
+                reason = trigger.to_string().as_str(),
+                last_violation = event.summary().as_str(),
+                "container frozen, thaw it with `lockctl container thaw`"
+            ),
+            Err(e) => error!(
+                container_id = event.container_id.as_str(),
+                reason = trigger.to_string().as_str(),
+                error = e.to_string().as_str(),
+                "could not freeze the container"
+            ),
+        }
+    }
+    debug!("incident response finished");
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::ContainerPolicyLevel;
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{cgroups::ContainerCgroup, registry::ContainerMetadata, violations::test_event};
+
+    fn event(hook: Hook, policy_level: ContainerPolicyLevel, suppressed: u64) -> ViolationEvent {
+        let mut event = ViolationEvent {
+            suppressed,
+            ..test_event(hook)
+        };
+        if let Some(container) = event.container.as_mut() {
+            container.policy_level = policy_level;
+        }
+        event
+    }
+
+    #[test]
+    fn denial_counter() {
+        let mut counter = DenialCounter::new(IncidentResponse {
+            policy_levels: vec![ContainerPolicyLevel::Restricted],
+            denial_threshold: 5,
+            window_s: 10,
+            immediate_hooks: vec![Hook::InodePermission],
+        });
+        let start = Instant::now();
+        let restricted = event(Hook::FileOpen, ContainerPolicyLevel::Restricted, 0);
+
+        assert_eq!(
+            counter.push(
+                &event(Hook::InodePermission, ContainerPolicyLevel::Restricted, 0),
+                start
+            ),
+            Some(Trigger::Hook(Hook::InodePermission))
+        );
+        // Other policy levels and complain mode are ignored.
+        assert_eq!(
+            counter.push(
+                &event(Hook::InodePermission, ContainerPolicyLevel::Baseline, 0),
+                start
+            ),
+            None
+        );
+        let complain = ViolationEvent {
+            enforcement: Enforcement::Complain,
+            ..event(Hook::InodePermission, ContainerPolicyLevel::Restricted, 0)
+        };
+        assert_eq!(counter.push(&complain, start), None);
+
+        // Suppressed violations count, old ones expire.
+        assert_eq!(counter.push(&restricted, start), None);
+        let later = start + Duration::from_secs(11);
+        assert_eq!(counter.push(&restricted, later), None);
+        assert_eq!(
+            counter.push(
+                &event(Hook::FileOpen, ContainerPolicyLevel::Restricted, 3),
+                later
+            ),
+            Some(Trigger::Threshold(5))
+        );
+        // Denials are counted from zero after the freeze.
+        assert_eq!(counter.push(&restricted, later), None);
+    }
+
+    #[test]
+    fn freeze_container() {
+        let dir = tempdir().unwrap();
+        let containers = RwLock::new(ContainerRegistry::default());
+        containers.write().unwrap().insert(
+            "abc".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata::default(),
+        );
+        assert!(matches!(
+            set_container_frozen(&containers, "abc", true),
+            Err(FreezeError::NoCgroup(_))
+        ));
+
+        containers.write().unwrap().set_cgroup(
+            "abc",
+            ContainerCgroup {
+                path: dir.path().to_path_buf(),
+                id: 1,
+            },
+        );
+        assert!(matches!(
+            set_container_frozen(&containers, "abc", true),
+            Err(FreezeError::NoFreezer(_))
+        ));
+
+        fs::write(dir.path().join(FREEZE_FILE), "0").unwrap();
+        set_container_frozen(&containers, "abc", true).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join(FREEZE_FILE)).unwrap(),
+            "1"
+        );
+        set_container_frozen(&containers, "abc", false).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join(FREEZE_FILE)).unwrap(),
+            "0"
+        );
+    }
+}
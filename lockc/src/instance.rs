@@ -0,0 +1,222 @@
+//! Ensures that only one instance of lockc operates on the pinned eBPF maps
+//! at a time.
+
+use std::{
+    fs,
+    io::{self, Read, Seek, Write},
+    os::unix::io::AsRawFd,
+    path::Path,
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    errno::Errno,
+    fcntl::{flock, FlockArg},
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use thiserror::Error;
+use tracing::{debug, info};
+
+/// Default path of the instance lock file, which contains the PID of the
+/// running instance.
+pub const LOCK_PATH: &str = "/run/lockc/lockc.pid";
+
+/// How long to wait for the running instance to exit on takeover.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+const TAKEOVER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Error, Debug)]
+pub enum InstanceError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Errno(#[from] Errno),
+
+    #[error(
+        "another instance of lockc is already running (pid {0}), use --takeover to replace it"
+    )]
+    AlreadyRunning(String),
+
+    #[error("running instance of lockc did not exit within {0:?}")]
+    TakeoverTimeout(Duration),
+}
+
+/// What to do when another instance holds the lock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Contention {
+    Fail,
+    /// Terminate the running instance and wait until it exits.
+    Terminate,
+    /// Wait until the running instance exits on its own.
+    Wait,
+}
+
+/// Exclusive lock held for the whole lifetime of the lockc process. The lock
+/// is released by the kernel when the process exits.
+pub struct InstanceLock {
+    _file: fs::File,
+}
+
+fn try_lock(file: &fs::File) -> Result<bool, Errno> {
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(_) => Ok(true),
+        Err(Errno::EWOULDBLOCK) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_pid(file: &mut fs::File) -> Result<Option<i32>, io::Error> {
+    let mut buf = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut buf)?;
+    Ok(buf.trim().parse().ok())
+}
+
+impl InstanceLock {
+    /// Acquires the instance lock. If another instance holds it and
+    /// `takeover` is true, that instance is terminated and the lock is
+    /// acquired after it exits. The new instance then continues with the
+    /// eBPF maps pinned by the previous one.
+    pub fn acquire<P: AsRef<Path>>(path: P, takeover: bool) -> Result<Self, InstanceError> {
+        let contention = if takeover {
+            Contention::Terminate
+        } else {
+            Contention::Fail
+        };
+        Self::acquire_with_timeout(path, contention, TAKEOVER_TIMEOUT)
+    }
+
+    /// Acquires the instance lock after the running instance exits on its
+    /// own, which it does after handing over its state.
+    pub fn wait<P: AsRef<Path>>(path: P) -> Result<Self, InstanceError> {
+        Self::acquire_with_timeout(path, Contention::Wait, TAKEOVER_TIMEOUT)
+    }
+
+    fn acquire_with_timeout<P: AsRef<Path>>(
+        path: P,
+        contention: Contention,
+        timeout: Duration,
+    ) -> Result<Self, InstanceError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Don't remove the PID of a running instance before locking.
+            .truncate(false)
+            .open(path)?;
+
+        if !try_lock(&file)? {
+            let pid = read_pid(&mut file)?;
+            match contention {
+                Contention::Fail => {
+                    return Err(InstanceError::AlreadyRunning(
+                        pid.map(|pid| pid.to_string())
+                            .unwrap_or_else(|| "unknown".to_string()),
+                    ));
+                }
+                Contention::Terminate => {
+                    info!(pid = pid, "taking over from the running instance");
+                    if let Some(pid) = pid {
+                        if pid != process::id() as i32 {
+                            kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+                        }
+                    }
+                }
+                Contention::Wait => {
+                    debug!(pid = pid, "waiting for the running instance to exit");
+                }
+            }
+
+            let start = Instant::now();
+            while !try_lock(&file)? {
+                if start.elapsed() >= timeout {
+                    return Err(InstanceError::TakeoverTimeout(timeout));
+                }
+                thread::sleep(TAKEOVER_POLL_INTERVAL);
+            }
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", process::id())?;
+        debug!(
+            path = path.to_string_lossy().as_ref(),
+            "acquired instance lock"
+        );
+
+        Ok(InstanceLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_lock_exclusive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockc.pid");
+
+        let lock = InstanceLock::acquire(&path, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().trim(),
+            process::id().to_string()
+        );
+        assert!(matches!(
+            InstanceLock::acquire(&path, false),
+            Err(InstanceError::AlreadyRunning(pid)) if pid == process::id().to_string()
+        ));
+
+        drop(lock);
+        InstanceLock::acquire(&path, false).unwrap();
+    }
+
+    #[test]
+    fn instance_lock_takeover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockc.pid");
+
+        let lock = InstanceLock::acquire(&path, false).unwrap();
+        assert!(matches!(
+            InstanceLock::acquire_with_timeout(
+                &path,
+                Contention::Terminate,
+                Duration::from_millis(200)
+            ),
+            Err(InstanceError::TakeoverTimeout(_))
+        ));
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+        InstanceLock::acquire(&path, true).unwrap();
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn instance_lock_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockc.pid");
+
+        let lock = InstanceLock::acquire(&path, false).unwrap();
+        assert!(matches!(
+            InstanceLock::acquire_with_timeout(&path, Contention::Wait, Duration::from_millis(200)),
+            Err(InstanceError::TakeoverTimeout(_))
+        ));
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+        InstanceLock::wait(&path).unwrap();
+        release.join().unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::unix::fs::{FileExt, MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use lockc_common::control::{BpfDigests, ProgramDigest};
+use object::{Object, ObjectSection, SectionKind};
+use openssl::{
+    pkey::{Id, PKey},
+    sha::sha256,
+    sign::Verifier,
+};
+use thiserror::Error;
+use tracing::info;
+
+#[derive(Error, Debug)]
+pub enum IntegrityError {
+    #[error("eBPF object digest mismatch (expected {expected}, got {actual})")]
+    DigestMismatch { expected: String, actual: String },
+
+    #[error("eBPF object is not signed, but a public key is configured")]
+    Unsigned,
+
+    #[error("invalid signature of the eBPF object")]
+    InvalidSignature,
+
+    #[error(transparent)]
+    Object(#[from] object::Error),
+
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error("runc binary {path} has an unexpected digest {actual}")]
+    RuncDigestMismatch { path: String, actual: String },
+}
+
+/// Returns the hex-encoded SHA-256 digest of the given data.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(sha256(data))
+}
+
+/// Checks the eBPF object against the digest recorded at build time (if
+/// known) and, if a public key is given, verifies its Ed25519 signature.
+pub fn verify(
+    obj: &[u8],
+    expected_digest: Option<&str>,
+    signature: &[u8],
+    public_key: Option<&[u8]>,
+) -> Result<(), IntegrityError> {
+    if let Some(expected) = expected_digest {
+        let actual = sha256_hex(obj);
+        let expected = expected.trim();
+        if actual != expected {
+            return Err(IntegrityError::DigestMismatch {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+
+    if let Some(public_key) = public_key {
+        if signature.is_empty() {
+            return Err(IntegrityError::Unsigned);
+        }
+        let valid = PKey::public_key_from_raw_bytes(public_key, Id::ED25519)
+            .and_then(|key| Verifier::new_without_digest(&key)?.verify_oneshot(signature, obj))
+            .unwrap_or(false);
+        if !valid {
+            return Err(IntegrityError::InvalidSignature);
+        }
+    }
+
+    Ok(())
+}
+
+/// Computes digests of the eBPF object and of each of its program sections.
+/// `path` is the location the object was read from, `None` if embedded.
+pub fn digests(
+    obj: &[u8],
+    path: Option<String>,
+    verified: bool,
+) -> Result<BpfDigests, IntegrityError> {
+    let file = object::File::parse(obj)?;
+    let mut programs = Vec::new();
+    for section in file.sections() {
+        if section.kind() != SectionKind::Text {
+            continue;
+        }
+        let data = section.data()?;
+        if data.is_empty() {
+            continue;
+        }
+        programs.push(ProgramDigest {
+            section: section.name()?.to_string(),
+            sha256: sha256_hex(data),
+        });
+    }
+
+    Ok(BpfDigests {
+        object: sha256_hex(obj),
+        path,
+        verified,
+        programs,
+    })
+}
+
+/// Version of a file's content. Any modification of the file changes its
+/// ctime.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct FileVersion {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+/// Verifies SHA-256 digests of executed runc binaries, to detect binaries
+/// replaced or overwritten by an attacker (i.e. CVE-2019-5736). Binaries are
+/// checked against the configured allow-list or, if it's empty and recording
+/// is enabled, against the digest recorded on the first execution of the given
+/// path.
+pub struct RuncVerifier {
+    allowed: Vec<String>,
+    record: bool,
+    recorded: HashMap<PathBuf, String>,
+    /// Digests of already hashed files, to not hash runc on every execution.
+    cache: HashMap<FileVersion, String>,
+}
+
+impl RuncVerifier {
+    pub fn new(allowed: &[String], record: bool) -> Self {
+        RuncVerifier {
+            allowed: allowed
+                .iter()
+                .map(|digest| digest.trim().to_lowercase())
+                .collect(),
+            record,
+            recorded: HashMap::new(),
+            cache: HashMap::new(),
+        }
+    }
+
+    fn digest(&mut self, file: &File) -> Result<String, io::Error> {
+        let metadata = file.metadata()?;
+        let version = FileVersion {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.size(),
+            ctime: metadata.ctime(),
+            ctime_nsec: metadata.ctime_nsec(),
+        };
+        if let Some(digest) = self.cache.get(&version) {
+            return Ok(digest.clone());
+        }
+
+        // Read with an explicit offset, the file descriptor might be shared
+        // with the process executing the file.
+        let mut data = vec![0; metadata.size() as usize];
+        file.read_exact_at(&mut data, 0)?;
+        let digest = sha256_hex(&data);
+
+        self.cache.insert(version, digest.clone());
+        Ok(digest)
+    }
+
+    /// Returns whether the digest of `file` is on the allow-list.
+    pub fn is_allowed(&mut self, file: &File) -> Result<bool, io::Error> {
+        if self.allowed.is_empty() {
+            return Ok(false);
+        }
+        let digest = self.digest(file)?;
+        Ok(self.allowed.contains(&digest))
+    }
+
+    /// Checks the digest of the runc binary opened as `file`.
+    pub fn verify(&mut self, path: &Path, file: &File) -> Result<(), IntegrityError> {
+        if self.allowed.is_empty() && !self.record {
+            return Ok(());
+        }
+        let actual = self.digest(file)?;
+        let allowed = if self.allowed.is_empty() {
+            self.recorded.entry(path.to_path_buf()).or_insert_with(|| {
+                info!(
+                    path = path.to_string_lossy().as_ref(),
+                    digest = actual.as_str(),
+                    "recorded the digest of runc"
+                );
+                actual.clone()
+            }) == &actual
+        } else {
+            self.allowed.contains(&actual)
+        };
+
+        match allowed {
+            true => Ok(()),
+            false => Err(IntegrityError::RuncDigestMismatch {
+                path: path.to_string_lossy().to_string(),
+                actual,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use openssl::sign::Signer;
+
+    use super::*;
+
+    const OBJ: &[u8] = b"\x7fELF not really a BPF object";
+
+    #[test]
+    fn verify_digest() {
+        let digest = sha256_hex(OBJ);
+        verify(OBJ, Some(&format!("{}\n", digest)), &[], None).unwrap();
+        verify(b"tampered", None, &[], None).unwrap();
+        assert!(matches!(
+            verify(b"tampered", Some(&digest), &[], None),
+            Err(IntegrityError::DigestMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn verify_signature() {
+        let key = PKey::generate_ed25519().unwrap();
+        let public_key = key.raw_public_key().unwrap();
+        let signature = Signer::new_without_digest(&key)
+            .unwrap()
+            .sign_oneshot_to_vec(OBJ)
+            .unwrap();
+        let digest = sha256_hex(OBJ);
+
+        verify(OBJ, Some(&digest), &signature, Some(&public_key)).unwrap();
+        assert!(matches!(
+            verify(OBJ, Some(&digest), &[], Some(&public_key)),
+            Err(IntegrityError::Unsigned)
+        ));
+
+        let other_key = PKey::generate_ed25519().unwrap();
+        assert!(matches!(
+            verify(
+                OBJ,
+                Some(&digest),
+                &signature,
+                Some(&other_key.raw_public_key().unwrap())
+            ),
+            Err(IntegrityError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify(OBJ, Some(&digest), &signature, Some(b"not a key")),
+            Err(IntegrityError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn verify_runc_recorded() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(b"runc").unwrap();
+        let mut verifier = RuncVerifier::new(&[], true);
+        verifier.verify(f.path(), f.as_file()).unwrap();
+        verifier.verify(f.path(), f.as_file()).unwrap();
+
+        f.write_all(b" with a payload").unwrap();
+        assert!(matches!(
+            verifier.verify(f.path(), f.as_file()),
+            Err(IntegrityError::RuncDigestMismatch { .. })
+        ));
+
+        // Without recording, an upgraded runc is allowed.
+        let mut verifier = RuncVerifier::new(&[], false);
+        verifier.verify(f.path(), f.as_file()).unwrap();
+        f.write_all(b" upgraded").unwrap();
+        verifier.verify(f.path(), f.as_file()).unwrap();
+    }
+
+    #[test]
+    fn verify_runc_allowed() {
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        f.write_all(b"runc").unwrap();
+        let mut verifier = RuncVerifier::new(&[sha256_hex(b"runc").to_uppercase()], false);
+        verifier.verify(f.path(), f.as_file()).unwrap();
+        assert!(verifier.is_allowed(f.as_file()).unwrap());
+
+        let mut verifier = RuncVerifier::new(&[sha256_hex(b"other runc")], false);
+        assert!(matches!(
+            verifier.verify(f.path(), f.as_file()),
+            Err(IntegrityError::RuncDigestMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+//! Forwarding of violation events to Kubernetes Events attached to pods, so
+//! denials show up in `kubectl describe pod` without access to node logs.
+
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, Instant},
+};
+
+use k8s_openapi::api::core::v1::{ObjectReference, Pod};
+use kube::{
+    api::Api,
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Client,
+};
+use lockc_common::Hook;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::violations::ViolationEvent;
+
+/// Max number of rate limiter entries kept before expired ones are pruned.
+const LIMITER_PRUNE_LEN: usize = 1024;
+
+/// Limits Kubernetes Events to one per container and hook in the given
+/// interval. Repeated violations are counted and reported with the next
+/// event.
+pub struct EventLimiter {
+    interval: Duration,
+    last: HashMap<(String, Hook), (Instant, u64)>,
+}
+
+impl EventLimiter {
+    pub fn new(interval: Duration) -> Self {
+        EventLimiter {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of violations suppressed since the last event if
+    /// an event can be created now, `None` otherwise.
+    pub fn check(&mut self, container_id: &str, hook: Hook, now: Instant) -> Option<u64> {
+        if self.last.len() >= LIMITER_PRUNE_LEN {
+            let interval = self.interval;
+            self.last
+                .retain(|_, (last, _)| now.duration_since(*last) < interval);
+        }
+
+        match self.last.get_mut(&(container_id.to_string(), hook)) {
+            Some((last, suppressed)) if now.duration_since(*last) < self.interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((last, suppressed)) => {
+                *last = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                self.last.insert((container_id.to_string(), hook), (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Returns the reference to the pod the container belongs to, with its UID,
+/// which `kubectl describe` uses to find events.
+async fn pod_reference(
+    client: &Client,
+    namespace: &str,
+    pod: &str,
+) -> Result<ObjectReference, kube::Error> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let uid = pods.get(pod).await?.metadata.uid;
+    Ok(ObjectReference {
+        api_version: Some("v1".to_string()),
+        kind: Some("Pod".to_string()),
+        name: Some(pod.to_string()),
+        namespace: Some(namespace.to_string()),
+        uid,
+        ..Default::default()
+    })
+}
+
+/// Creates Kubernetes Events for violations received from the channel, until
+/// it's closed. Violations in containers which are not Kubernetes pods are
+/// ignored.
+pub async fn forward(mut rx: broadcast::Receiver<ViolationEvent>, interval: Duration) {
+    let client = match Client::try_default().await {
+        Ok(client) => client,
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not create Kubernetes client, violations are not reported as events"
+            );
+            return;
+        }
+    };
+    let reporter = Reporter {
+        controller: "lockc".to_string(),
+        instance: env::var("NODE_NAME").ok(),
+    };
+    let mut limiter = EventLimiter::new(interval);
+
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "Kubernetes events lag behind violations");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let (namespace, pod) = match event
+            .container
+            .as_ref()
+            .and_then(|info| Some((info.namespace.as_deref()?, info.pod.as_deref()?)))
+        {
+            Some((namespace, pod)) => (namespace.to_string(), pod.to_string()),
+            None => continue,
+        };
+        let suppressed = match limiter.check(&event.container_id, event.hook, Instant::now()) {
+            Some(suppressed) => suppressed + event.suppressed,
+            None => continue,
+        };
+
+        let reference = match pod_reference(&client, &namespace, &pod).await {
+            Ok(reference) => reference,
+            Err(e) => {
+                debug!(
+                    namespace = namespace.as_str(),
+                    pod = pod.as_str(),
+                    error = e.to_string().as_str(),
+                    "could not get the pod of the violation"
+                );
+                continue;
+            }
+        };
+        let mut note = event.description();
+        if let Some(name) = event
+            .container
+            .as_ref()
+            .and_then(|info| info.name.as_deref())
+        {
+            note = format!("{} in container {}", note, name);
+        }
+        if suppressed > 0 {
+            note = format!("{}, {} similar denials suppressed", note, suppressed);
+        }
+
+        let recorder = Recorder::new(client.clone(), reporter.clone(), reference);
+        if let Err(e) = recorder
+            .publish(Event {
+                type_: EventType::Warning,
+                reason: "PolicyViolation".to_string(),
+                note: Some(note),
+                action: event.hook.to_string(),
+                secondary: None,
+            })
+            .await
+        {
+            warn!(
+                namespace = namespace.as_str(),
+                pod = pod.as_str(),
+                error = e.to_string().as_str(),
+                "could not create Kubernetes event"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_limiter() {
+        let mut limiter = EventLimiter::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        assert_eq!(limiter.check("a", Hook::FileOpen, now), Some(0));
+        assert_eq!(limiter.check("a", Hook::FileOpen, now), None);
+        assert_eq!(
+            limiter.check("a", Hook::FileOpen, now + Duration::from_secs(1)),
+            None
+        );
+        // Other hooks and containers are limited separately.
+        assert_eq!(limiter.check("a", Hook::SbMount, now), Some(0));
+        assert_eq!(limiter.check("b", Hook::FileOpen, now), Some(0));
+
+        assert_eq!(
+            limiter.check("a", Hook::FileOpen, now + Duration::from_secs(10)),
+            Some(2)
+        );
+        assert_eq!(
+            limiter.check("a", Hook::FileOpen, now + Duration::from_secs(11)),
+            None
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Learning mode. eBPF programs record bind mounts which allowed paths don't
+//! allow, instead of denying them, and lockc collects them periodically, so
+//! `lockctl learn export` can suggest allowed paths which workloads need.
+//! Collected mounts are recorded in fingerprints of images as well, if they
+//! are enabled.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use lockc_common::{control::LearnedMountInfo, ContainerPolicyLevel};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use crate::{
+    communication::{EbpfCommand, EbpfRequest},
+    fingerprints::{self, Fingerprints},
+    registry::ContainerRegistry,
+};
+
+/// Interval of collecting bind mount attempts from the eBPF map.
+const COLLECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Attempts to bind mount the path by the container, read from the
+/// `LEARNED_MOUNTS` eBPF map.
+#[derive(Debug)]
+pub struct MountAttempts {
+    pub container_id: String,
+    /// Policy level of the allowed paths list which didn't allow the mount,
+    /// restricted or baseline.
+    pub policy_level: ContainerPolicyLevel,
+    pub path: String,
+    pub count: u64,
+}
+
+#[derive(Default)]
+struct LearnedPath {
+    containers: BTreeSet<String>,
+    count: u64,
+}
+
+/// Bind mounts learned since lockc started, per allowed paths list. Entries
+/// are kept after containers are deleted.
+#[derive(Default)]
+pub struct LearnedMounts {
+    restricted: BTreeMap<String, LearnedPath>,
+    baseline: BTreeMap<String, LearnedPath>,
+}
+
+impl LearnedMounts {
+    pub fn record(&mut self, attempts: MountAttempts) {
+        let paths = match attempts.policy_level {
+            ContainerPolicyLevel::Restricted => &mut self.restricted,
+            ContainerPolicyLevel::Baseline => &mut self.baseline,
+            _ => return,
+        };
+        let learned = paths.entry(attempts.path).or_default();
+        learned.containers.insert(attempts.container_id);
+        learned.count += attempts.count;
+    }
+
+    pub fn list(&self) -> Vec<LearnedMountInfo> {
+        let restricted = self
+            .restricted
+            .iter()
+            .map(|entry| (ContainerPolicyLevel::Restricted, entry));
+        let baseline = self
+            .baseline
+            .iter()
+            .map(|entry| (ContainerPolicyLevel::Baseline, entry));
+        restricted
+            .chain(baseline)
+            .map(|(policy_level, (path, learned))| LearnedMountInfo {
+                path: path.clone(),
+                policy_level,
+                containers: learned.containers.iter().cloned().collect(),
+                count: learned.count,
+            })
+            .collect()
+    }
+}
+
+/// Requests bind mount attempts from the eBPF thread.
+async fn take(
+    ebpf_tx: &mpsc::WeakSender<EbpfRequest>,
+) -> Option<Result<Vec<MountAttempts>, String>> {
+    let ebpf_tx = ebpf_tx.upgrade()?;
+    let (responder_tx, responder_rx) = oneshot::channel();
+    ebpf_tx
+        .send(EbpfCommand::TakeLearnedMounts { responder_tx }.into())
+        .await
+        .ok()?;
+    let res = responder_rx.await.ok()?;
+    Some(res.map_err(|e| e.to_string()))
+}
+
+/// Collects bind mount attempts from the eBPF map periodically, until the
+/// eBPF thread is gone.
+pub async fn collect(
+    ebpf_tx: mpsc::WeakSender<EbpfRequest>,
+    learned: Arc<RwLock<LearnedMounts>>,
+    fingerprints: Option<Arc<RwLock<Fingerprints>>>,
+    containers: Arc<RwLock<ContainerRegistry>>,
+) {
+    let mut interval = tokio::time::interval(COLLECT_INTERVAL);
+    loop {
+        interval.tick().await;
+        let attempts = match take(&ebpf_tx).await {
+            Some(Ok(attempts)) => attempts,
+            Some(Err(e)) => {
+                warn!(
+                    error = e.as_str(),
+                    "could not collect bind mounts recorded in learning mode"
+                );
+                continue;
+            }
+            None => break,
+        };
+        if attempts.is_empty() {
+            continue;
+        }
+        if let Some(fingerprints) = &fingerprints {
+            fingerprints::record_mounts(fingerprints, &containers, &attempts);
+        }
+        let mut learned = match learned.write() {
+            Ok(learned) => learned,
+            Err(_) => {
+                warn!("learned bind mounts are poisoned");
+                break;
+            }
+        };
+        for attempts in attempts {
+            debug!(
+                container = attempts.container_id.as_str(),
+                path = attempts.path.as_str(),
+                count = attempts.count,
+                "learned bind mount"
+            );
+            learned.record(attempts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn learned_mounts_record() {
+        let mut learned = LearnedMounts::default();
+        for (container_id, policy_level, path, count) in [
+            ("abc", ContainerPolicyLevel::Baseline, "/srv/data", 2),
+            ("def", ContainerPolicyLevel::Baseline, "/srv/data", 1),
+            ("abc", ContainerPolicyLevel::Baseline, "/srv/data", 3),
+            ("abc", ContainerPolicyLevel::Restricted, "/srv/data", 1),
+            ("abc", ContainerPolicyLevel::Privileged, "/", 1),
+        ] {
+            learned.record(MountAttempts {
+                container_id: container_id.to_string(),
+                policy_level,
+                path: path.to_string(),
+                count,
+            });
+        }
+
+        let mounts = learned.list();
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].policy_level, ContainerPolicyLevel::Restricted);
+        assert_eq!(mounts[0].count, 1);
+        assert_eq!(mounts[1].policy_level, ContainerPolicyLevel::Baseline);
+        assert_eq!(mounts[1].path, "/srv/data");
+        assert_eq!(mounts[1].containers, vec!["abc", "def"]);
+        assert_eq!(mounts[1].count, 6);
+    }
+}
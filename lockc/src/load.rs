@@ -1,12 +1,41 @@
-use std::{io, path::Path};
+use std::{
+    borrow::Cow,
+    ffi::CString,
+    fs, io, mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd},
+    },
+    path::{Path, PathBuf},
+};
 
 use aya::{
     include_bytes_aligned,
-    programs::{BtfTracePoint, Lsm, ProgramError},
+    programs::{BtfTracePoint, CgroupSockAddr, Lsm, ProgramError},
     Bpf, BpfError, BpfLoader, Btf, BtfError,
 };
+use lockc_common::{control::BpfDigests, pid_map_size, Program, PID_MAPS, PID_MAX_DEFAULT};
 use thiserror::Error;
-use tracing::warn;
+use tracing::{debug, warn};
+
+use crate::{
+    integrity::{digests, verify, IntegrityError},
+    settings::UserNamespaces,
+};
+
+/// Pinned eBPF maps which are filled from the settings on every start. Their
+/// pins are removed before loading, so they are recreated instead of reused.
+/// These are `PATH_PREFIXES`, `INODE_PREFIXES`, `DENY_RESPONSES`, maps of
+/// excluded processes, `PROTECTED_INODES` and `MASKED_PROC_INODES`.
+const UNPINNED_MAPS: &[&str] = &[
+    "PATH_PREFIXES",
+    "INODE_PREFIXES",
+    "DENY_RESPONSES",
+    "EXCLUDED_COMMS",
+    "EXCLUDED_EXECUTABLES",
+    "PROTECTED_INODES",
+    "MASKED_PROC_INODES",
+];
 
 #[derive(Error, Debug)]
 pub enum LoadError {
@@ -15,25 +44,287 @@ pub enum LoadError {
 
     #[error(transparent)]
     Bpf(#[from] BpfError),
+
+    #[error(transparent)]
+    Integrity(#[from] IntegrityError),
 }
 
-/// Loads BPF programs from the object file built with clang.
-pub fn load_bpf<P: AsRef<Path>>(path_base_r: P) -> Result<Bpf, LoadError> {
-    let path_base = path_base_r.as_ref();
-    std::fs::create_dir_all(path_base)?;
+/// Default locations of the eBPF object installed by distribution packages.
+pub const BPF_OBJECT_PATHS: &[&str] = &["/usr/lib/lockc/lockc.bpf.o"];
 
+/// Returns the eBPF object built with `cargo xtask build-ebpf`.
+fn bpf_object_embedded() -> &'static [u8] {
     #[cfg(debug_assertions)]
-    let bpf = BpfLoader::new()
-        .map_pin_path(path_base)
-        .load(include_bytes_aligned!(
-            "../../target/bpfel-unknown-none/debug/lockc"
-        ))?;
+    let obj = include_bytes_aligned!("../../target/bpfel-unknown-none/debug/lockc");
     #[cfg(not(debug_assertions))]
-    let bpf = BpfLoader::new()
-        .map_pin_path(path_base)
-        .load(include_bytes_aligned!(
-            "../../target/bpfel-unknown-none/release/lockc"
-        ))?;
+    let obj = include_bytes_aligned!("../../target/bpfel-unknown-none/release/lockc");
+    obj
+}
+
+/// SHA-256 digest of the eBPF object, recorded by `cargo xtask build-ebpf`.
+#[cfg(debug_assertions)]
+const BPF_DIGEST: &str = include_str!("../../target/bpfel-unknown-none/debug/lockc.sha256");
+#[cfg(not(debug_assertions))]
+const BPF_DIGEST: &str = include_str!("../../target/bpfel-unknown-none/release/lockc.sha256");
+
+/// Ed25519 signature of the eBPF object. Empty if the object was built without
+/// a signing key.
+#[cfg(debug_assertions)]
+const BPF_SIGNATURE: &[u8] = include_bytes!("../../target/bpfel-unknown-none/debug/lockc.sig");
+#[cfg(not(debug_assertions))]
+const BPF_SIGNATURE: &[u8] = include_bytes!("../../target/bpfel-unknown-none/release/lockc.sig");
+
+/// Reads the `kernel.pid_max` sysctl and returns the size of PID maps.
+fn pid_max() -> u32 {
+    match procfs::sys::kernel::pid_max() {
+        Ok(pid_max) => pid_map_size(pid_max),
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                default = PID_MAX_DEFAULT,
+                "could not read kernel.pid_max, using the default value"
+            );
+            PID_MAX_DEFAULT
+        }
+    }
+}
+
+/// Returns the path of a file stored next to the given one, with the given
+/// suffix appended (e.g. `lockc.bpf.o.sha256`).
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(suffix);
+    PathBuf::from(sibling)
+}
+
+/// Reads the given file, returning `None` if it does not exist.
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, io::Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// eBPF object to load, either read from disk or embedded in lockc.
+pub struct BpfObject {
+    data: Cow<'static, [u8]>,
+    path: Option<PathBuf>,
+    digest: Option<String>,
+    signature: Vec<u8>,
+}
+
+impl BpfObject {
+    /// Returns the object embedded in lockc at build time.
+    pub fn embedded() -> Self {
+        BpfObject {
+            data: Cow::Borrowed(bpf_object_embedded()),
+            path: None,
+            digest: Some(BPF_DIGEST.to_string()),
+            signature: BPF_SIGNATURE.to_vec(),
+        }
+    }
+
+    /// Reads the object from the given file. Its digest and signature are
+    /// read from the `.sha256` and `.sig` files next to it, if present.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let digest = read_if_exists(&sibling_path(path, ".sha256"))?
+            .map(|digest| String::from_utf8_lossy(&digest).into_owned());
+        let signature = read_if_exists(&sibling_path(path, ".sig"))?.unwrap_or_default();
+        Ok(BpfObject {
+            data: Cow::Owned(data),
+            path: Some(path.to_path_buf()),
+            digest,
+            signature,
+        })
+    }
+
+    /// Returns the first object found in the given locations. Falls back to
+    /// the embedded object if none of them exists.
+    pub fn find<P: AsRef<Path>>(paths: &[P]) -> Result<Self, io::Error> {
+        for path in paths {
+            let path = path.as_ref();
+            if path.exists() {
+                return Self::from_file(path);
+            }
+        }
+        Ok(Self::embedded())
+    }
+
+    /// Returns the object if it can be trusted before loading it, otherwise
+    /// the embedded one. The embedded object is covered by the digest
+    /// compiled into lockc. The digest next to an object read from disk can
+    /// be replaced together with the object, so such an object is used only
+    /// with a public key verifying its signature, or when `allow_unsigned` is
+    /// set.
+    pub fn verifiable_or_embedded(self, public_key: Option<&[u8]>, allow_unsigned: bool) -> Self {
+        let path = match (&self.path, public_key) {
+            (Some(path), None) => path.to_string_lossy().into_owned(),
+            _ => return self,
+        };
+        if allow_unsigned {
+            warn!(
+                path = path.as_str(),
+                "loading eBPF object from disk without verifying its signature"
+            );
+            return self;
+        }
+        warn!(
+            path = path.as_str(),
+            "eBPF object from disk can't be verified without bpf_public_key, using the embedded object"
+        );
+        Self::embedded()
+    }
+
+    /// Returns digests of the object and its programs.
+    pub fn digests(&self, verified: bool) -> Result<BpfDigests, IntegrityError> {
+        digests(
+            &self.data,
+            self.path
+                .as_ref()
+                .map(|path| path.to_string_lossy().into_owned()),
+            verified,
+        )
+    }
+}
+
+/// Removes pins of maps from [`UNPINNED_MAPS`] under `path_base`. Programs of
+/// a running instance keep using their maps until they are detached.
+fn remove_unpinned_maps(path_base: &Path) -> Result<(), io::Error> {
+    for map in UNPINNED_MAPS {
+        match fs::remove_file(path_base.join(map)) {
+            Ok(()) => debug!(map = map, "removed pinned eBPF map"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+const BPF_OBJ_PIN: libc::c_long = 6;
+const BPF_OBJ_GET: libc::c_long = 7;
+
+/// `bpf_attr` of the `BPF_OBJ_PIN` and `BPF_OBJ_GET` commands.
+#[repr(C)]
+struct BpfObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+fn bpf_obj(cmd: libc::c_long, path: &Path, fd: u32) -> Result<libc::c_long, io::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let attr = BpfObjAttr {
+        pathname: path.as_ptr() as u64,
+        bpf_fd: fd,
+        file_flags: 0,
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            &attr as *const BpfObjAttr,
+            mem::size_of::<BpfObjAttr>(),
+        )
+    };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res)
+}
+
+/// Opens maps from [`PID_MAPS`] pinned under `path_base`.
+pub fn open_pinned_maps(path_base: &Path) -> Result<Vec<(String, OwnedFd)>, io::Error> {
+    PID_MAPS
+        .iter()
+        .map(|map| {
+            let fd = bpf_obj(BPF_OBJ_GET, &path_base.join(map), 0)?;
+            // SAFETY: the file descriptor was just returned by the kernel.
+            Ok((map.to_string(), unsafe { OwnedFd::from_raw_fd(fd as i32) }))
+        })
+        .collect()
+}
+
+/// Pins the given maps under `path_base`, replacing the existing pins, so
+/// programs loaded afterwards reuse them. Only maps from [`PID_MAPS`] are
+/// pinned.
+pub fn pin_maps(path_base: &Path, maps: &[(String, OwnedFd)]) -> Result<(), io::Error> {
+    fs::create_dir_all(path_base)?;
+    for (map, fd) in maps {
+        if !PID_MAPS.contains(&map.as_str()) {
+            warn!(map = map.as_str(), "not pinning unknown eBPF map");
+            continue;
+        }
+        let path = path_base.join(map);
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        bpf_obj(BPF_OBJ_PIN, &path, fd.as_raw_fd() as u32)?;
+        debug!(map = map.as_str(), "pinned handed over eBPF map");
+    }
+    Ok(())
+}
+
+/// Loads BPF programs from the given object. The object is verified against
+/// its build-time digest and, if `public_key` is given, its signature before
+/// loading. With `trace`, the programs send events about containerized
+/// processes. With `learning`, bind mounts denied by allowed paths are
+/// recorded instead. With `deny_received_fds`, receiving file descriptors of
+/// host sockets and files is denied, not only reported. `user_namespaces`
+/// overrides decisions for rootless containers.
+pub fn load_bpf<P: AsRef<Path>>(
+    path_base_r: P,
+    obj: &BpfObject,
+    public_key: Option<&[u8]>,
+    hardlinks_inherit: bool,
+    trace: bool,
+    learning: bool,
+    deny_received_fds: bool,
+    user_namespaces: &UserNamespaces,
+) -> Result<Bpf, LoadError> {
+    let path_base = path_base_r.as_ref();
+    std::fs::create_dir_all(path_base)?;
+
+    let path = obj
+        .path
+        .as_ref()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "embedded".to_string());
+    verify(&obj.data, obj.digest.as_deref(), &obj.signature, public_key)?;
+    debug!(
+        path = path.as_str(),
+        signature_verified = public_key.is_some(),
+        "verified eBPF object"
+    );
+
+    let pid_max = pid_max();
+    debug!(pid_max = pid_max, "resizing PID maps");
+
+    remove_unpinned_maps(path_base)?;
+
+    let hardlinks_inherit = hardlinks_inherit as u8;
+    let trace = trace as u8;
+    let learning = learning as u8;
+    let deny_received_fds = deny_received_fds as u8;
+    let userns_mount = user_namespaces.mount as u8;
+    let userns_setuid = user_namespaces.setuid as u8;
+    let mut loader = BpfLoader::new();
+    loader.map_pin_path(path_base);
+    for map in PID_MAPS {
+        loader.set_max_entries(map, pid_max);
+    }
+    loader.set_global("HARDLINKS_INHERIT", &hardlinks_inherit);
+    loader.set_global("TRACE_PROCESSES", &trace);
+    loader.set_global("LEARNING", &learning);
+    loader.set_global("DENY_RECEIVED_FDS", &deny_received_fds);
+    loader.set_global("USERNS_MOUNT", &userns_mount);
+    loader.set_global("USERNS_SETUID", &userns_setuid);
+
+    let bpf = loader.load(&obj.data)?;
 
     Ok(bpf)
 }
@@ -64,6 +355,32 @@ fn is_root_btrfs() -> bool {
     }
 }
 
+/// Loads and attaches the LSM program of a hook which older kernels might
+/// not have. Returns `false` if the kernel doesn't have the hook.
+fn attach_optional_lsm(bpf: &mut Bpf, btf: &Btf, name: &str) -> Result<bool, AttachError> {
+    let program: &mut Lsm = bpf
+        .program_mut(name)
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    match program.load(name, btf) {
+        Ok(()) => {}
+        Err(ProgramError::Btf(e)) => {
+            debug!(
+                program = name,
+                error = e.to_string().as_str(),
+                "hook not found in kernel BTF"
+            );
+            return Ok(false);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    program.attach()?;
+    Ok(true)
+}
+
+/// cgroup programs restricting egress of containers.
+pub const EGRESS_PROGRAMS: &[&str] = &["connect4", "connect6"];
+
 pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
     let btf = Btf::from_sys_fs()?;
 
@@ -108,6 +425,22 @@ pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
         warn!("Root filesystem is not BTRFS, skipping mount policies");
     }
 
+    // Masked paths of procfs are protected on any root filesystem, by a
+    // separate program of the same hook.
+    let program: &mut Lsm = bpf
+        .program_mut("sb_mount_proc")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("sb_mount", &btf)?;
+    program.attach()?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("sb_umount")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("sb_umount", &btf)?;
+    program.attach()?;
+
     let program: &mut Lsm = bpf
         .program_mut("task_fix_setuid")
         .ok_or(AttachError::ProgLoad)?
@@ -122,6 +455,33 @@ pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
     program.load("file_open", &btf)?;
     program.attach()?;
 
+    let program: &mut Lsm = bpf
+        .program_mut("unix_stream_connect")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("unix_stream_connect", &btf)?;
+    program.attach()?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("file_receive")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("file_receive", &btf)?;
+    program.attach()?;
+
+    // The new mount API (`open_tree`, `fsmount`, `move_mount`) bypasses
+    // `sb_mount`.
+    if !attach_optional_lsm(bpf, &btf, "move_mount")? {
+        warn!("kernel has no move_mount hook, skipping mount policies of the new mount API");
+    }
+
+    let program: &mut Lsm = bpf
+        .program_mut("inode_permission")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("inode_permission", &btf)?;
+    program.attach()?;
+
     let program: &mut Lsm = bpf
         .program_mut("socket_sendmsg")
         .ok_or(AttachError::ProgLoad)?
@@ -136,17 +496,157 @@ pub fn attach_programs(bpf: &mut Bpf) -> Result<(), AttachError> {
     program.load("socket_recvmsg", &btf)?;
     program.attach()?;
 
+    // cgroup programs are attached to cgroups of containers when they are
+    // registered.
+    for name in EGRESS_PROGRAMS {
+        let program: &mut CgroupSockAddr = bpf
+            .program_mut(name)
+            .ok_or(AttachError::ProgLoad)?
+            .try_into()?;
+        program.load()?;
+    }
+
+    Ok(())
+}
+
+/// Returns programs loaded into the kernel, which for LSM programs means
+/// attached.
+pub fn loaded_programs(bpf: &Bpf) -> Vec<Program> {
+    Program::ALL
+        .iter()
+        .copied()
+        .filter(|program| {
+            bpf.program(program.name())
+                .and_then(|program| program.fd())
+                .is_some()
+        })
+        .collect()
+}
+
+/// Attaches the program recording uses of capabilities for fingerprints. It
+/// runs on every capability check, so it's attached only when fingerprints
+/// are collected.
+pub fn attach_fingerprinting(bpf: &mut Bpf) -> Result<(), AttachError> {
+    let btf = Btf::from_sys_fs()?;
+
+    let program: &mut Lsm = bpf
+        .program_mut("capable")
+        .ok_or(AttachError::ProgLoad)?
+        .try_into()?;
+    program.load("capable", &btf)?;
+    program.attach()?;
+
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{integrity::sha256_hex, settings::Settings, sysutils::test_bpffs};
+
+    #[test]
+    fn bpf_object_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockc.bpf.o");
+        fs::write(&path, b"object").unwrap();
+
+        let obj = BpfObject::find(&[dir.path().join("missing.bpf.o"), path.clone()]).unwrap();
+        assert_eq!(obj.path.as_deref(), Some(path.as_path()));
+        assert_eq!(obj.digest, None);
+        assert!(obj.signature.is_empty());
+
+        fs::write(sibling_path(&path, ".sha256"), b"abc\n").unwrap();
+        let obj = BpfObject::from_file(&path).unwrap();
+        assert_eq!(obj.digest.as_deref(), Some("abc\n"));
+    }
+
+    /// Writes an object with its digest and an empty signature, the way
+    /// packages and `cargo xtask install` install it.
+    fn installed_object(dir: &Path) -> PathBuf {
+        let path = dir.join("lockc.bpf.o");
+        fs::write(&path, b"object").unwrap();
+        fs::write(sibling_path(&path, ".sha256"), sha256_hex(b"object")).unwrap();
+        fs::write(sibling_path(&path, ".sig"), b"").unwrap();
+        path
+    }
+
+    #[test]
+    fn bpf_object_from_file_needs_public_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = installed_object(dir.path());
+
+        let obj = BpfObject::from_file(&path)
+            .unwrap()
+            .verifiable_or_embedded(None, false);
+        assert!(obj.path.is_none());
+        let obj = BpfObject::from_file(&path)
+            .unwrap()
+            .verifiable_or_embedded(None, true);
+        assert_eq!(obj.path.as_deref(), Some(path.as_path()));
+        let obj = BpfObject::from_file(&path)
+            .unwrap()
+            .verifiable_or_embedded(Some(&[0; 32]), false);
+        assert_eq!(obj.path.as_deref(), Some(path.as_path()));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn load_installed_bpf_default_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = installed_object(dir.path());
+        let settings = Settings::default();
+        let public_key = settings.bpf_public_key().unwrap();
+
+        let obj = BpfObject::from_file(&path)
+            .unwrap()
+            .verifiable_or_embedded(public_key.as_deref(), settings.allow_unsigned_bpf);
+        load_bpf(
+            test_bpffs().join("lockc-test-installed"),
+            &obj,
+            public_key.as_deref(),
+            settings.hardlinks_inherit_permission,
+            false,
+            settings.learning_mode,
+            false,
+            &settings.user_namespaces,
+        )
+        .expect("Loading BPF failed");
+    }
+
+    #[test]
+    fn bpf_object_embedded_fallback() {
+        let obj = BpfObject::find(&["/nonexistent/lockc.bpf.o"]).unwrap();
+        assert!(obj.path.is_none());
+        assert!(obj.digest.is_some());
+    }
+
+    #[test]
+    fn remove_unpinned_maps_keeps_others() {
+        let dir = tempfile::tempdir().unwrap();
+        for map in ["CONTAINERS", "PATH_PREFIXES", "INODE_PREFIXES"] {
+            fs::write(dir.path().join(map), b"").unwrap();
+        }
+
+        remove_unpinned_maps(dir.path()).unwrap();
+        assert!(dir.path().join("CONTAINERS").exists());
+        assert!(!dir.path().join("PATH_PREFIXES").exists());
+        assert!(!dir.path().join("INODE_PREFIXES").exists());
+    }
 
     #[test]
     #[cfg_attr(not(feature = "tests_bpf"), ignore)]
     fn load_and_attach_bpf() {
-        let mut bpf = load_bpf("/sys/fs/bpf/lockc-test").expect("Loading BPF failed");
+        let mut bpf = load_bpf(
+            test_bpffs().join("lockc-test"),
+            &BpfObject::embedded(),
+            None,
+            false,
+            false,
+            false,
+            false,
+            &UserNamespaces::default(),
+        )
+        .expect("Loading BPF failed");
         attach_programs(&mut bpf).expect("Attaching BPF programs failed");
     }
 }
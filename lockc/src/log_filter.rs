@@ -0,0 +1,118 @@
+//! Filter of logs which can be changed at runtime, with the control API or by
+//! sending SIGUSR1, to capture debug logs without restarting lockc.
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{filter::Targets, reload, Registry};
+
+/// Handle of the filter applied on all logs. Filters are lists of
+/// `target=level` directives with an optional default level, like
+/// `info,lockc=debug`.
+#[derive(Clone)]
+pub struct LogFilter {
+    handle: reload::Handle<Targets, Registry>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LogFilterError {
+    #[error("invalid log filter: {0}")]
+    Parse(#[from] tracing_subscriber::filter::ParseError),
+
+    #[error(transparent)]
+    Reload(#[from] reload::Error),
+}
+
+/// Returns the next more verbose level, wrapping from trace to error.
+fn next_level(level: LevelFilter) -> LevelFilter {
+    if level == LevelFilter::ERROR {
+        LevelFilter::WARN
+    } else if level == LevelFilter::WARN {
+        LevelFilter::INFO
+    } else if level == LevelFilter::INFO {
+        LevelFilter::DEBUG
+    } else if level == LevelFilter::DEBUG {
+        LevelFilter::TRACE
+    } else {
+        LevelFilter::ERROR
+    }
+}
+
+impl LogFilter {
+    /// Returns the filter layer, which has to be added to the subscriber, and
+    /// the handle to change it.
+    pub fn new(level: LevelFilter) -> (reload::Layer<Targets, Registry>, Self) {
+        let (layer, handle) = reload::Layer::new(Targets::new().with_default(level));
+        (layer, LogFilter { handle })
+    }
+
+    /// Replaces the filter with the given directives and returns the new
+    /// filter.
+    pub fn set(&self, directives: &str) -> Result<String, LogFilterError> {
+        let targets: Targets = directives.parse()?;
+        let current = targets.to_string();
+        self.handle.reload(targets)?;
+        Ok(current)
+    }
+
+    /// Switches the default level to the next more verbose one, wrapping
+    /// from trace to error. Directives for specific targets are kept.
+    pub fn cycle(&self) -> Result<String, LogFilterError> {
+        let mut current = String::new();
+        self.handle.modify(|targets| {
+            let level = next_level(targets.default_level().unwrap_or(LevelFilter::OFF));
+            *targets = targets.clone().with_default(level);
+            current = targets.to_string();
+        })?;
+        Ok(current)
+    }
+
+    /// Returns the current filter.
+    pub fn current(&self) -> Result<String, LogFilterError> {
+        Ok(self.handle.with_current(|targets| targets.to_string())?)
+    }
+}
+
+/// Cycles the default log level every time lockc receives SIGUSR1.
+pub async fn cycle_on_sigusr1(log_filter: LogFilter) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not handle SIGUSR1, log level cannot be changed with it"
+            );
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        match log_filter.cycle() {
+            Ok(filter) => info!(filter = filter.as_str(), "log filter changed"),
+            Err(e) => warn!(
+                error = e.to_string().as_str(),
+                "could not change the log filter"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_filter_set_cycle() {
+        let (_layer, log_filter) = LogFilter::new(LevelFilter::INFO);
+        assert_eq!(log_filter.current().unwrap(), "info");
+
+        assert_eq!(log_filter.cycle().unwrap(), "debug");
+        assert_eq!(log_filter.cycle().unwrap(), "trace");
+        assert_eq!(log_filter.cycle().unwrap(), "error");
+
+        let filter = log_filter.set("warn,lockc=debug").unwrap();
+        assert!(filter.contains("lockc=debug"));
+        assert!(log_filter.cycle().unwrap().contains("lockc=debug"));
+        assert!(log_filter.current().unwrap().contains("info"));
+
+        assert!(log_filter.set("lockc=loud").is_err());
+    }
+}
@@ -1,50 +1,167 @@
-use std::{env, fs, path, thread};
+use std::{
+    env, fs, io,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    os::unix::net::UnixListener as StdUnixListener,
+    path,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{atomic::AtomicU64, Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
 
+use aya::Bpf;
 use aya_log::BpfLogger;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(feature = "kubernetes")]
+use kube::CustomResourceExt;
+use lockc_common::{
+    control::{short_id, PolicyChangeInfo, CONTROL_SOCKET_PATH},
+    MapErrorEvent,
+};
 use thiserror::Error;
 use tokio::{
-    runtime::Runtime,
-    sync::{mpsc, oneshot},
+    net::{TcpListener, UnixListener},
+    runtime::{Builder, Runtime},
+    sync::{broadcast, mpsc, oneshot},
+    time::{self, Interval},
 };
-use tracing::{debug, error, Level};
+use tracing::{debug, error, info, level_filters::LevelFilter, warn};
 use tracing_log::LogTracer;
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::{
+    fmt::format::FmtSpan,
+    layer::{Layer as _, SubscriberExt},
+    Registry,
+};
 
+mod cgroups;
 mod communication;
+mod control;
+mod custom_policies;
+mod daemon;
+mod egress;
+mod error;
+mod falco;
+mod fingerprints;
+mod handover;
+mod incident;
+mod instance;
+mod integrity;
+#[cfg(feature = "kubernetes")]
+mod k8s_events;
+mod learning;
 mod load;
+mod log_filter;
 mod maps;
+mod metrics;
+mod namespace_policies;
+#[cfg(feature = "otel")]
+mod otel;
+mod perf;
+mod pidns;
+mod policy_audit;
+mod privileges;
+mod profiles;
+mod registry;
+mod reporter;
 mod runc;
+mod settings;
+mod simulate;
+mod status;
+mod supervisor;
+mod systemd;
 mod sysutils;
+mod trace;
+mod validation;
+mod violations;
 
-use communication::EbpfCommand;
-use load::{attach_programs, load_bpf};
-use maps::{add_container, add_process, delete_container};
+use cgroups::ContainerCgroup;
+use communication::{EbpfCommand, EbpfRequest, EbpfSender};
+use control::ControlState;
+use custom_policies::CustomPolicies;
+#[cfg(feature = "kubernetes")]
+use custom_policies::LockcPolicy;
+use daemon::{daemonize, Readiness};
+use egress::{EgressError, EgressLinks};
+use error::Error;
+use falco::FalcoOutput;
+use fingerprints::Fingerprints;
+use handover::{Handover, HandoverServer, HANDOVER_SOCKET_PATH};
+use instance::{InstanceLock, LOCK_PATH};
+use integrity::RuncVerifier;
+use load::{
+    attach_fingerprinting, attach_programs, load_bpf, loaded_programs, open_pinned_maps, BpfObject,
+};
+use log_filter::LogFilter;
+use maps::{
+    add_container, add_container_cgroup, add_process, delete_container, delete_container_cgroup,
+    get_map_errors, get_process_container, get_program_stats, init_allowed_paths, init_egress,
+    init_excluded, init_proc_masking, init_protected, set_enforcement, take_learned_capabilities,
+    take_learned_mounts, AddOutcome, LockcMaps, MapOperationError,
+};
+use metrics::{EbpfCounters, Histogram};
+use namespace_policies::NamespacePolicies;
+use perf::PerfBuffers;
+use pidns::{PidTranslator, HOST_PROC_PATH};
+use policy_audit::{PolicyAudit, PolicyAuditError};
+use privileges::drop_privileges;
+use profiles::{AllowedPaths, Profile};
+use registry::ContainerRegistry;
+use reporter::{Reporter, ViolationSummaries};
 // use runc::{attach_runc_nsexec, handle_events, mark_runc_binaries};
-use runc::RuncWatcher;
-use sysutils::check_bpf_lsm_enabled;
+use runc::{read_recording, RecordedEvent, Recorder, RuncWatcher};
+use settings::{AsyncRuntime, ImagePolicies, IncidentResponse, Settings, SettingsError};
+use simulate::SimulateError;
+use status::RecentViolations;
+use supervisor::{EbpfHealth, HealthError, Supervisor};
+use systemd::Liveness;
+use sysutils::{
+    bump_memlock_rlimit, check_bpf_lsm_enabled, check_kernel, ensure_bpffs, secure_boot_enabled,
+    BPFFS_PATH, BTF_PATH, LOCKDOWN_PATH, SECURE_BOOT_PATH,
+};
+use validation::SpecValidator;
 
 #[derive(Error, Debug)]
 enum FanotifyError {
     #[error("could not send the message")]
     Send,
+
+    #[error("fanotify thread did not confirm the bootstrap")]
+    Ready,
+}
+
+/// How containers get registered.
+enum Registration {
+    /// By the fanotify-based runc watcher, which gets bootstrapped through
+    /// the given channel and advances the given token in its loop. There is
+    /// no loop when recorded events are replayed.
+    Watcher(oneshot::Sender<oneshot::Sender<()>>, Option<Liveness>),
+    /// Only by an external agent through the control API. The sender keeps
+    /// the eBPF loop alive, as there is no watcher holding it.
+    ControlApi(mpsc::Sender<EbpfRequest>),
 }
 
 /// Runs an fanotify-based runc watcher, which registers containers every time
-/// they are created or deleted.
-fn fanotify(
-    fanotify_bootstrap_rx: oneshot::Receiver<()>,
-    ebpf_tx: mpsc::Sender<EbpfCommand>,
-) -> Result<(), anyhow::Error> {
-    RuncWatcher::new(fanotify_bootstrap_rx, ebpf_tx)?.work_loop()?;
+/// they are created or deleted. Recorded events are replayed instead, if
+/// given.
+fn fanotify(mut watcher: RuncWatcher, replay: Option<Vec<RecordedEvent>>) -> Result<(), Error> {
+    match replay {
+        Some(events) => watcher.replay(events)?,
+        None => watcher.work_loop()?,
+    }
     Ok(())
 }
 
-/// Loads and attaches eBPF programs, then fetches logs and events from them.
-async fn ebpf(
-    fanotify_bootstrap_tx: oneshot::Sender<()>,
-    mut ebpf_rx: mpsc::Receiver<EbpfCommand>,
-) -> Result<(), anyhow::Error> {
+/// Loads and attaches eBPF programs. With handover, the programs use maps of
+/// the running instance.
+fn setup_bpf(
+    bpf_object: &BpfObject,
+    bpf_public_key: Option<&[u8]>,
+    allowed_paths: &AllowedPaths,
+    settings: &Settings,
+    trace: bool,
+    handover: Option<&Handover>,
+) -> Result<Bpf, Error> {
     // Check whether BPF LSM is enabled in the kernel. That check should be
     // omitted in Kubernetes (where lockc runs in a container) or nested
     // containers, because sysctls inside containers might hide the fact
@@ -56,72 +173,788 @@ async fn ebpf(
             .join("lsm");
         check_bpf_lsm_enabled(sys_lsm_path)?;
     }
+    // Fail with an actionable error instead of an opaque verifier one.
+    let lockdown = check_kernel(LOCKDOWN_PATH, BTF_PATH, SECURE_BOOT_PATH)?;
+    debug!(
+        lockdown = ?lockdown,
+        secure_boot = secure_boot_enabled(SECURE_BOOT_PATH),
+        "kernel checked"
+    );
 
-    // let config = new_config().await?;
+    if bump_memlock_rlimit()? {
+        debug!("RLIMIT_MEMLOCK removed");
+    }
+    // bpffs is expected on the parent of the pin directory, which is
+    // `/sys/fs/bpf` by default.
+    let bpffs_path = settings
+        .bpf_pin_path
+        .parent()
+        .unwrap_or_else(|| path::Path::new(BPFFS_PATH));
+    if ensure_bpffs(bpffs_path, settings.mount_bpffs)? {
+        info!(
+            path = bpffs_path.to_string_lossy().as_ref(),
+            "mounted bpffs"
+        );
+    }
 
-    let path_base = std::path::Path::new("/sys")
-        .join("fs")
-        .join("bpf")
-        .join("lockc");
-    fs::create_dir_all(&path_base)?;
+    let path_base = settings.bpf_pin_path();
+    fs::create_dir_all(&path_base).map_err(Error::BpfFs)?;
+    if let Some(handover) = handover {
+        handover.pin_maps(&path_base)?;
+        debug!("pinned eBPF maps of the running instance");
+    }
 
-    let mut bpf = load_bpf(&path_base)?;
-    BpfLogger::init(&mut bpf)?;
+    let mut bpf = load_bpf(
+        &path_base,
+        bpf_object,
+        bpf_public_key,
+        settings.hardlinks_inherit_permission,
+        trace,
+        settings.learning_mode,
+        settings.deny_received_fds,
+        &settings.user_namespaces,
+    )?;
 
-    // init_allowed_paths(&mut bpf, &config)?;
+    init_allowed_paths(&mut bpf, allowed_paths)?;
     debug!("allowed paths initialized");
+    init_excluded(&mut bpf, &settings.excluded_processes)?;
+    debug!("excluded processes initialized");
+    init_egress(&mut bpf, &settings.egress.networks())?;
+    debug!("denied egress networks initialized");
+    init_protected(&mut bpf, &path_base)?;
+    debug!("pinned eBPF objects protected");
+    init_proc_masking(&mut bpf, &settings.proc_masking)?;
+    debug!("masked paths of procfs protected");
     attach_programs(&mut bpf)?;
     debug!("attached programs");
+    if settings.fingerprints.enabled {
+        attach_fingerprinting(&mut bpf)?;
+        debug!("recording uses of capabilities for fingerprints");
+    }
+
+    Ok(bpf)
+}
+
+/// Prometheus metrics endpoint, enabled with a command line option.
+struct MetricsEndpoint {
+    listener: StdTcpListener,
+    /// Container registration latency, if runc is watched.
+    registration_latency: Option<Arc<Histogram>>,
+    /// Timeouts of eBPF commands of the runc watcher, if runc is watched.
+    command_timeouts: Option<Arc<AtomicU64>>,
+}
+
+/// Status page, enabled with a command line option.
+struct StatusEndpoint {
+    listener: StdTcpListener,
+    health: EbpfHealth,
+}
+
+/// Consumers of container lifecycle and violation events, enabled with
+/// command line options.
+struct EventSinks {
+    /// Interval of identical violations, zero if they are not deduplicated.
+    violations_dedup: Duration,
+    /// Interval of Kubernetes Events about the same violation, if enabled.
+    #[cfg(feature = "kubernetes")]
+    k8s_events: Option<Duration>,
+    falco: Option<FalcoOutput>,
+    #[cfg(feature = "otel")]
+    otel: bool,
+    /// Freezing of containers under attack, if enabled.
+    incident_response: Option<IncidentResponse>,
+    /// Interval of saving fingerprints of images, if they are collected.
+    fingerprints: Option<Duration>,
+    /// Whether recent violations are kept for the status page.
+    status: bool,
+    /// Reports to the collector, if configured.
+    reporter: Option<Reporter>,
+}
+
+impl EventSinks {
+    fn violations(&self) -> bool {
+        #[cfg(feature = "otel")]
+        if self.otel {
+            return true;
+        }
+        #[cfg(feature = "kubernetes")]
+        if self.k8s_events.is_some() {
+            return true;
+        }
+        self.falco.is_some()
+            || self.incident_response.is_some()
+            || self.fingerprints.is_some()
+            || self.status
+            || self.reporter.is_some()
+    }
+}
 
-    // Bootstrap the fanotify thread.
-    fanotify_bootstrap_tx
-        .send(())
-        .map_err(|_| FanotifyError::Send)?;
+/// Attaches egress programs to the cgroup of the container. A container
+/// which can't be restricted is frozen rather than left with unrestricted
+/// egress.
+fn restrict_egress(
+    egress: &mut EgressLinks,
+    bpf: &mut Bpf,
+    container_id: &str,
+    cgroup: &ContainerCgroup,
+) -> Result<(), EgressError> {
+    let res = egress.attach(bpf, cgroup);
+    match &res {
+        Ok(()) => debug!(container_id, "container egress restricted"),
+        Err(e) => {
+            error!(
+                container_id,
+                cgroup = cgroup.path.to_string_lossy().as_ref(),
+                error = e.to_string().as_str(),
+                "could not restrict egress of the container, freezing it"
+            );
+            if let Err(e) = incident::set_frozen(&cgroup.path, true) {
+                error!(
+                    container_id,
+                    error = e.to_string().as_str(),
+                    "could not freeze the container with unrestricted egress"
+                );
+            }
+        }
+    }
+    res
+}
+
+/// Sends the result of an eBPF command to the requester. The operation is
+/// done regardless of whether the requester is still waiting, so only its
+/// outcome is logged when the result can't be delivered. Returns the error
+/// if the map itself can't be used, so the eBPF thread can recover.
+fn respond<T>(
+    command: &'static str,
+    responder_tx: oneshot::Sender<Result<T, MapOperationError>>,
+    res: Result<T, MapOperationError>,
+) -> Option<HealthError> {
+    let succeeded = res.is_ok();
+    let fatal = match &res {
+        Err(e) if supervisor::is_fatal(e) => Some(HealthError::Command {
+            command,
+            error: e.to_string(),
+        }),
+        _ => None,
+    };
+    if responder_tx.send(res).is_err() {
+        warn!(
+            command,
+            succeeded, "requester of the eBPF command is gone, could not send the result"
+        );
+    }
+    fatal
+}
+
+/// Records the change of a container policy in the audit trail.
+fn audit_policy_change(policy_audit: &Mutex<PolicyAudit>, change: PolicyChangeInfo) {
+    match policy_audit.lock() {
+        Ok(mut policy_audit) => policy_audit.record(change),
+        Err(_) => error!("policy audit trail is poisoned"),
+    }
+}
+
+/// Completes on the next tick of the interval, never if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads counters of eBPF programs for the metrics endpoint and the status
+/// page.
+fn read_counters(maps: &LockcMaps, counters: &Mutex<EbpfCounters>) {
+    let res = get_map_errors(maps).and_then(|map_errors| {
+        Ok(EbpfCounters {
+            map_errors,
+            program_stats: get_program_stats(maps)?,
+        })
+    });
+    match res {
+        Ok(read) => match counters.lock() {
+            Ok(mut counters) => *counters = read,
+            Err(_) => error!("eBPF counters are poisoned"),
+        },
+        Err(e) => warn!(
+            error = e.to_string().as_str(),
+            "could not read counters of eBPF programs"
+        ),
+    }
+}
+
+/// Fetches logs and events from eBPF programs and performs eBPF map
+/// operations requested by the other threads.
+#[allow(clippy::too_many_arguments)]
+async fn ebpf(
+    mut bpf: Bpf,
+    registration: Registration,
+    mut ebpf_rx: mpsc::Receiver<EbpfRequest>,
+    control_listener: StdUnixListener,
+    control_state: ControlState,
+    metrics: Option<MetricsEndpoint>,
+    status: Option<StatusEndpoint>,
+    sinks: EventSinks,
+    readiness: Option<Readiness>,
+    supervisor: Supervisor,
+    mut egress: EgressLinks,
+) -> Result<(), Error> {
+    BpfLogger::init(&mut bpf)?;
+
+    PerfBuffers::open(&mut bpf, "MAP_ERROR_EVENTS")?.spawn(|event: MapErrorEvent| {
+        error!(
+            operation = event.operation().map(|op| op.name()).unwrap_or("unknown"),
+            error = event.error,
+            count = event.count,
+            "eBPF map operation failed, process tracking is degraded"
+        );
+        true
+    });
+
+    let containers = control_state.containers.clone();
+    let policy_audit = control_state.policy_audit.clone();
+
+    // Links of the previous instance are gone with it, so cgroups of
+    // containers handed over have to be restricted again.
+    let restored: Vec<(String, ContainerCgroup)> = match containers.read() {
+        Ok(containers) => containers
+            .list()
+            .iter()
+            .filter(|info| egress.restricted(info.policy_level))
+            .filter_map(|info| {
+                containers
+                    .cgroup(&info.id)
+                    .cloned()
+                    .map(|cgroup| (info.id.clone(), cgroup))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    for (container_id, cgroup) in restored {
+        let _ = restrict_egress(&mut egress, &mut bpf, &container_id, &cgroup);
+    }
+
+    #[cfg(feature = "otel")]
+    let otel = if sinks.otel {
+        Some(Arc::new(otel::OtelExporter::new()?))
+    } else {
+        None
+    };
 
-    while let Some(cmd) = ebpf_rx.recv().await {
-        match cmd {
+    let recent_violations = Arc::new(Mutex::new(RecentViolations::default()));
+    let violation_summaries = Arc::new(Mutex::new(ViolationSummaries::default()));
+    if sinks.violations() {
+        let (violations_tx, _) = broadcast::channel(100);
+        #[cfg(feature = "kubernetes")]
+        if let Some(interval) = sinks.k8s_events {
+            tokio::spawn(k8s_events::forward(violations_tx.subscribe(), interval));
+            debug!("forwarding violations to Kubernetes events");
+        }
+        if let Some(output) = sinks.falco {
+            tokio::spawn(falco::forward(violations_tx.subscribe(), output));
+            debug!("writing violations as Falco alerts");
+        }
+        if let Some(incident_response) = sinks.incident_response {
+            tokio::spawn(incident::respond(
+                violations_tx.subscribe(),
+                incident_response,
+                containers.clone(),
+            ));
+            debug!("freezing containers under attack");
+        }
+        if let Some(fingerprints) = &control_state.fingerprints {
+            tokio::spawn(fingerprints::record_violations(
+                violations_tx.subscribe(),
+                fingerprints.clone(),
+            ));
+        }
+        if sinks.status {
+            tokio::spawn(status::record_violations(
+                violations_tx.subscribe(),
+                recent_violations.clone(),
+            ));
+        }
+        if sinks.reporter.is_some() {
+            tokio::spawn(reporter::record_violations(
+                violations_tx.subscribe(),
+                violation_summaries.clone(),
+            ));
+        }
+        #[cfg(feature = "otel")]
+        if let Some(otel) = &otel {
+            let otel = otel.clone();
+            let mut violations_rx = violations_tx.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    match violations_rx.recv().await {
+                        Ok(event) => otel.violation(&event),
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            warn!(skipped, "OpenTelemetry export lags behind violations")
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+            debug!("exporting events to OpenTelemetry");
+        }
+        violations::spawn(
+            &mut bpf,
+            containers.clone(),
+            violations_tx,
+            sinks.violations_dedup,
+        )?;
+    }
+
+    if let Some(trace_tx) = &control_state.trace_tx {
+        trace::spawn(&mut bpf, trace_tx.clone())?;
+        debug!("trace mode enabled");
+    }
+
+    // Event readers took their maps, the rest is operated on by commands.
+    let mut maps = LockcMaps::new(&bpf)?;
+
+    if let Some(learned_mounts) = &control_state.learned_mounts {
+        tokio::spawn(learning::collect(
+            control_state.ebpf_tx.clone(),
+            learned_mounts.clone(),
+            control_state.fingerprints.clone(),
+            containers.clone(),
+        ));
+        warn!("learning mode enabled, bind mounts denied by allowed paths are only recorded");
+    }
+
+    if let (Some(fingerprints), Some(save_interval)) =
+        (&control_state.fingerprints, sinks.fingerprints)
+    {
+        tokio::spawn(fingerprints::collect(
+            control_state.ebpf_tx.clone(),
+            fingerprints.clone(),
+            containers.clone(),
+            save_interval,
+        ));
+        debug!("collecting fingerprints of images");
+    }
+
+    if let Some(reporter) = sinks.reporter {
+        tokio::spawn(reporter::report(
+            reporter,
+            containers.clone(),
+            violation_summaries,
+        ));
+        debug!("sending reports to the collector");
+    }
+
+    tokio::spawn(log_filter::cycle_on_sigusr1(
+        control_state.log_filter.clone(),
+    ));
+
+    let control_listener =
+        UnixListener::from_std(control_listener).map_err(Error::ControlSocket)?;
+    let ebpf_tx = control_state.ebpf_tx.clone();
+    let control_state = Arc::new(control_state);
+    tokio::spawn(control::serve(control_listener, control_state.clone()));
+    debug!("control API started");
+
+    // Counters of eBPF programs are read on an interval for the metrics
+    // endpoint and the status page, not on every request.
+    let counters = Arc::new(Mutex::new(EbpfCounters::default()));
+    let mut counter_ticks =
+        (status.is_some() || metrics.is_some()).then(|| time::interval(metrics::COUNTERS_INTERVAL));
+    if let Some(status) = status {
+        let status_listener = TcpListener::from_std(status.listener).map_err(Error::Status)?;
+        tokio::spawn(status::serve(
+            status_listener,
+            control_state,
+            status.health,
+            recent_violations,
+            counters.clone(),
+        ));
+        debug!("status page started");
+    }
+
+    if let Some(metrics) = metrics {
+        let metrics_listener = TcpListener::from_std(metrics.listener).map_err(Error::Metrics)?;
+        tokio::spawn(metrics::serve(
+            metrics_listener,
+            ebpf_tx,
+            counters.clone(),
+            metrics.registration_latency,
+            metrics.command_timeouts,
+        ));
+        debug!("metrics endpoint started");
+    }
+
+    let (_ebpf_tx, fanotify_liveness) = match registration {
+        Registration::Watcher(fanotify_bootstrap_tx, liveness) => {
+            // Bootstrap the fanotify thread and wait until it watches runc
+            // binaries.
+            let (fanotify_ready_tx, fanotify_ready_rx) = oneshot::channel();
+            fanotify_bootstrap_tx
+                .send(fanotify_ready_tx)
+                .map_err(|_| FanotifyError::Send)?;
+            fanotify_ready_rx.await.map_err(|_| FanotifyError::Ready)?;
+            (None, liveness)
+        }
+        Registration::ControlApi(ebpf_tx) => {
+            info!(
+                "runc watcher disabled, containers have to be registered through the control API"
+            );
+            (Some(ebpf_tx), None)
+        }
+    };
+
+    // Notify systemd that lockc is ready and start the watchdog heartbeat,
+    // if configured. Heartbeats are sent only while both this loop and the
+    // fanotify loop make progress.
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!(
+            error = e.to_string().as_str(),
+            "could not notify systemd about readiness"
+        );
+    }
+    let ebpf_liveness = Liveness::default();
+    let mut liveness_ticks = None;
+    if let Some(timeout) = systemd::watchdog_timeout() {
+        debug!(timeout = timeout.as_secs(), "starting systemd watchdog");
+        let mut loops = vec![("ebpf", ebpf_liveness.clone())];
+        loops.extend(fanotify_liveness.map(|liveness| ("fanotify", liveness)));
+        tokio::spawn(systemd::watchdog(timeout, loops));
+        liveness_ticks = Some(time::interval(systemd::LIVENESS_INTERVAL));
+    }
+    // Let the original process exit, if daemonized.
+    if let Some(readiness) = readiness {
+        if let Err(e) = readiness.notify() {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not notify the original process about readiness"
+            );
+        }
+    }
+
+    // The first check is done after the interval, lockc was just set up.
+    let mut checks = supervisor
+        .check_interval()
+        .map(|period| time::interval_at(time::Instant::now() + period, period));
+    loop {
+        let request = tokio::select! {
+            request = ebpf_rx.recv() => request,
+            _ = tick(&mut checks) => {
+                match supervisor.check(&maps) {
+                    Ok(()) => supervisor.checked(),
+                    Err(e) => supervisor.recover(&maps, e).await?,
+                }
+                continue;
+            }
+            _ = tick(&mut liveness_ticks) => {
+                ebpf_liveness.advance();
+                continue;
+            }
+            _ = tick(&mut counter_ticks) => {
+                read_counters(&maps, &counters);
+                continue;
+            }
+        };
+        let EbpfRequest { command, span } = match request {
+            Some(request) => request,
+            None => break,
+        };
+        // Handling of the command doesn't await, so the span can be entered
+        // for the whole handling.
+        let _enter = span.enter();
+        let failure = match command {
             EbpfCommand::AddContainer {
                 container_id,
                 pid,
                 policy_level,
+                enforcement,
+                syslog,
+                metadata,
+                spec,
+                custom_mounts,
+                mode,
+                source,
+                responder_tx,
+            } => {
+                let res = add_container(
+                    &mut maps,
+                    container_id.clone(),
+                    pid,
+                    policy_level,
+                    enforcement,
+                    syslog,
+                    *spec,
+                    custom_mounts.map(|custom_mounts| *custom_mounts),
+                    mode,
+                );
+                match &res {
+                    Ok(AddOutcome::Unchanged) => {
+                        debug!(
+                            container_id = container_id.as_str(),
+                            pid, "container already registered with the same policy"
+                        );
+                    }
+                    Ok(outcome) => {
+                        if let AddOutcome::Replaced {
+                            policy_level: replaced,
+                            enforcement: replaced_enforcement,
+                        } = outcome
+                        {
+                            warn!(
+                                container_id = container_id.as_str(),
+                                replaced = format!("{}", replaced).as_str(),
+                                replaced_enforcement = replaced_enforcement.to_string().as_str(),
+                                "replacing policy of registered container"
+                            );
+                        }
+                        info!(
+                            container_id = container_id.as_str(),
+                            short_id =
+                                short_id(metadata.runtime_id.as_deref().unwrap_or(&container_id)),
+                            name = metadata.name.as_deref(),
+                            pod = metadata.pod.as_deref(),
+                            namespace = metadata.namespace.as_deref(),
+                            image = metadata.image.as_deref(),
+                            parent = metadata.parent.as_deref(),
+                            policy_level = format!("{}", policy_level).as_str(),
+                            enforcement = enforcement.to_string().as_str(),
+                            syslog,
+                            "container registered"
+                        );
+                        match containers.write() {
+                            Ok(mut containers) => {
+                                containers.insert(container_id.clone(), policy_level, metadata);
+                                containers.set_enforcement(&container_id, enforcement);
+                                #[cfg(feature = "otel")]
+                                if let Some(otel) = &otel {
+                                    otel.container_registered(
+                                        &container_id,
+                                        containers.get(&container_id),
+                                    );
+                                }
+                            }
+                            Err(_) => error!("container registry is poisoned"),
+                        }
+                        let old = match outcome {
+                            AddOutcome::Replaced {
+                                policy_level,
+                                enforcement,
+                            } => Some((*policy_level, *enforcement)),
+                            _ => None,
+                        };
+                        audit_policy_change(
+                            &policy_audit,
+                            policy_audit::change(
+                                &container_id,
+                                source,
+                                old,
+                                (policy_level, enforcement),
+                            ),
+                        );
+                    }
+                    Err(e @ MapOperationError::PolicyConflict { .. }) => {
+                        warn!(
+                            container_id = container_id.as_str(),
+                            error = e.to_string().as_str(),
+                            "conflicting registration of container"
+                        );
+                        #[cfg(feature = "otel")]
+                        if let Some(otel) = &otel {
+                            match containers.read() {
+                                Ok(containers) => otel.container_policy_conflict(
+                                    &container_id,
+                                    containers.get(&container_id),
+                                    policy_level,
+                                    enforcement,
+                                ),
+                                Err(_) => error!("container registry is poisoned"),
+                            }
+                        }
+                    }
+                    Err(_) => {}
+                }
+                respond("add_container", responder_tx, res.map(|_| ()))
+            }
+            EbpfCommand::SetEnforcement {
+                container_id,
+                enforcement,
+                source,
                 responder_tx,
             } => {
-                let res = add_container(&mut bpf, container_id, pid, policy_level);
-                match responder_tx.send(res) {
-                    Ok(_) => {}
-                    Err(_) => error!(
-                        command = "add_container",
-                        "could not send eBPF command result although the operation was succeessful"
-                    ),
+                let res = set_enforcement(&mut maps, container_id.clone(), enforcement);
+                if res.is_ok() {
+                    match containers.write() {
+                        Ok(mut containers) => {
+                            let old = containers
+                                .get(&container_id)
+                                .map(|info| (info.policy_level, info.enforcement));
+                            containers.set_enforcement(&container_id, enforcement);
+                            info!(
+                                container_id = container_id.as_str(),
+                                short_id =
+                                    containers.get(&container_id).map(|info| info.short_id()),
+                                enforcement = enforcement.to_string().as_str(),
+                                "container enforcement changed"
+                            );
+                            if let Some(old @ (policy_level, old_enforcement)) = old {
+                                if old_enforcement != enforcement {
+                                    audit_policy_change(
+                                        &policy_audit,
+                                        policy_audit::change(
+                                            &container_id,
+                                            source,
+                                            Some(old),
+                                            (policy_level, enforcement),
+                                        ),
+                                    );
+                                }
+                            }
+                        }
+                        Err(_) => error!("container registry is poisoned"),
+                    }
                 }
+                respond("set_enforcement", responder_tx, res)
             }
             EbpfCommand::DeleteContainer {
                 container_id,
                 responder_tx,
             } => {
-                let res = delete_container(&mut bpf, container_id);
-                match responder_tx.send(res) {
-                    Ok(_) => {}
-                    Err(_) => error!(
-                        command = "delete_container",
-                        "could not send eBPF command result although the operation was succeessful"
-                    ),
+                let res = delete_container(&mut maps, container_id.clone());
+                if res.is_ok() {
+                    let (info, nested, cgroup_ids) = match containers.write() {
+                        Ok(mut containers) => {
+                            let nested = containers.descendants(&container_id);
+                            let cgroup_ids: Vec<u64> = nested
+                                .iter()
+                                .chain([&container_id])
+                                .filter_map(|id| containers.cgroup(id).map(|cgroup| cgroup.id))
+                                .collect();
+                            for nested_id in &nested {
+                                containers.remove(nested_id);
+                            }
+                            (containers.remove(&container_id), nested, cgroup_ids)
+                        }
+                        Err(_) => (None, Vec::new(), Vec::new()),
+                    };
+                    for cgroup_id in cgroup_ids {
+                        if let Err(e) = egress.detach(&mut bpf, cgroup_id) {
+                            warn!(
+                                cgroup_id,
+                                error = e.to_string().as_str(),
+                                "could not detach egress programs from the cgroup"
+                            );
+                        }
+                        if let Err(e) = delete_container_cgroup(&mut maps, cgroup_id) {
+                            warn!(
+                                cgroup_id,
+                                error = e.to_string().as_str(),
+                                "could not delete the cgroup of the container"
+                            );
+                        }
+                    }
+                    info!(
+                        container_id = container_id.as_str(),
+                        short_id = info.as_ref().map(|info| info.short_id()),
+                        name = info.as_ref().and_then(|info| info.name.as_deref()),
+                        pod = info.as_ref().and_then(|info| info.pod.as_deref()),
+                        namespace = info.as_ref().and_then(|info| info.namespace.as_deref()),
+                        "container deleted"
+                    );
+                    #[cfg(feature = "otel")]
+                    if let Some(otel) = &otel {
+                        otel.container_deleted(&container_id, info.as_ref());
+                    }
+                    // Nested containers are not deleted by runc when their
+                    // parent is gone.
+                    for nested_id in nested {
+                        match delete_container(&mut maps, nested_id.clone()) {
+                            Ok(_) => info!(
+                                container_id = nested_id.as_str(),
+                                parent = container_id.as_str(),
+                                "nested container deleted"
+                            ),
+                            Err(e) => warn!(
+                                container_id = nested_id.as_str(),
+                                error = e.to_string().as_str(),
+                                "could not delete the nested container"
+                            ),
+                        }
+                    }
                 }
+                respond("delete_container", responder_tx, res)
             }
             EbpfCommand::AddProcess {
                 container_id,
                 pid,
                 responder_tx,
             } => {
-                let res = add_process(&mut bpf, container_id, pid);
-                match responder_tx.send(res) {
-                    Ok(_) => {}
-                    Err(_) => error!(
-                        command = "add_proceess",
-                        "could not send eBPF command result although the operation was succeessful"
-                    ),
+                if let Ok(containers) = containers.read() {
+                    debug!(
+                        container_id = container_id.as_str(),
+                        name = containers
+                            .get(&container_id)
+                            .and_then(|info| info.name.as_deref()),
+                        pid = pid,
+                        "adding process"
+                    );
                 }
+                let res = add_process(&mut maps, container_id, pid);
+                respond("add_process", responder_tx, res)
             }
+            EbpfCommand::AddCgroup {
+                container_id,
+                cgroup,
+                responder_tx,
+            } => {
+                let mut res = add_container_cgroup(&mut maps, container_id.clone(), cgroup.id);
+                if res.is_ok() {
+                    debug!(
+                        container_id = container_id.as_str(),
+                        cgroup = cgroup.path.to_string_lossy().as_ref(),
+                        "container cgroup registered"
+                    );
+                    let restricted = match containers.write() {
+                        Ok(mut containers) => {
+                            containers.set_cgroup(&container_id, cgroup.clone());
+                            containers
+                                .get(&container_id)
+                                .map_or(false, |info| egress.restricted(info.policy_level))
+                        }
+                        Err(_) => {
+                            // The policy is unknown, fail closed.
+                            error!("container registry is poisoned");
+                            true
+                        }
+                    };
+                    if restricted {
+                        res = restrict_egress(&mut egress, &mut bpf, &container_id, &cgroup)
+                            .map_err(MapOperationError::from);
+                    }
+                }
+                respond("add_cgroup", responder_tx, res)
+            }
+            EbpfCommand::GetProcessContainer { pid, responder_tx } => {
+                let res = get_process_container(&maps, pid);
+                respond("get_process_container", responder_tx, res)
+            }
+            EbpfCommand::GetProgramStats { responder_tx } => {
+                let res = get_program_stats(&maps);
+                respond("get_program_stats", responder_tx, res)
+            }
+            EbpfCommand::TakeLearnedMounts { responder_tx } => {
+                let res = take_learned_mounts(&mut maps);
+                respond("take_learned_mounts", responder_tx, res)
+            }
+            EbpfCommand::TakeLearnedCapabilities { responder_tx } => {
+                let res = take_learned_capabilities(&mut maps);
+                respond("take_learned_capabilities", responder_tx, res)
+            }
+        };
+        drop(_enter);
+        // Requests received in the meantime wait for the recovery.
+        if let Some(failure) = failure {
+            supervisor.recover(&maps, failure).await?;
         }
     }
 
@@ -131,6 +964,8 @@ async fn ebpf(
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
 struct Opt {
+    /// Initial log level. The log filter can be changed at runtime with
+    /// `lockctl log-filter`, SIGUSR1 switches to the next more verbose level.
     #[cfg_attr(
         debug_assertions,
         clap(value_enum, long, env = "LOCKC_LOG_LEVEL", default_value_t = LogLevel::Debug)
@@ -143,6 +978,191 @@ struct Opt {
 
     #[clap(value_enum, long, env="LOCKC_LOG_FMT", default_value_t = LogFmt::Text)]
     log_fmt: LogFmt,
+
+    /// Log closing of spans (fanotify events, policy resolution, eBPF
+    /// commands) with their duration, to profile the container start path.
+    #[clap(long, env = "LOCKC_LOG_SPANS")]
+    log_spans: bool,
+
+    /// Path to the configuration file.
+    #[clap(long, env = "LOCKC_CONFIG", default_value = "/etc/lockc/lockc.toml")]
+    config: PathBuf,
+
+    /// Path to the control API socket [default: /run/lockc/lockc.sock,
+    /// namespaced by the instance].
+    #[clap(long, env = "LOCKC_CONTROL_SOCKET")]
+    control_socket: Option<PathBuf>,
+
+    /// Directory in bpffs where eBPF maps are pinned. Overrides the
+    /// `bpf_pin_path` setting.
+    #[clap(long, env = "LOCKC_BPF_PIN_PATH")]
+    bpf_pin_path: Option<PathBuf>,
+
+    /// Name of the instance, for running multiple instances (e.g. staging
+    /// and production) side by side. The pin directory, the lock file and
+    /// the sockets get the `-<instance>` suffix on their directory, e.g.
+    /// `/sys/fs/bpf/lockc-staging`. Overrides the `instance` setting.
+    #[clap(long, env = "LOCKC_INSTANCE")]
+    instance: Option<String>,
+
+    /// Address to serve Prometheus metrics on (e.g. `127.0.0.1:9847`).
+    /// Metrics are disabled when not set.
+    #[clap(long, env = "LOCKC_METRICS_ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+
+    /// Address to serve the status page on (e.g. `127.0.0.1:9848`), with
+    /// containers, programs and recent violations as JSON at `/status` and
+    /// as HTML at `/`. The page is disabled when not set.
+    #[clap(long, env = "LOCKC_STATUS_ADDR")]
+    status_addr: Option<SocketAddr>,
+
+    /// Path to the eBPF object. Overrides the locations from the
+    /// configuration file.
+    #[clap(long, env = "LOCKC_BPF_PATH")]
+    bpf_path: Option<PathBuf>,
+
+    /// Path to the lock file which ensures that only one instance of lockc
+    /// is running. It contains the PID of the running instance, so it
+    /// serves as the PID file [default: /run/lockc/lockc.pid, namespaced by
+    /// the instance].
+    #[clap(long, visible_alias = "pidfile", env = "LOCKC_LOCK_FILE")]
+    lock_file: Option<PathBuf>,
+
+    /// Fork into the background and detach from the terminal, for running
+    /// lockc without systemd. The command exits once lockc is ready, or
+    /// with an error when it fails to start.
+    #[clap(long, env = "LOCKC_DAEMONIZE")]
+    daemonize: bool,
+
+    /// Stay in the foreground (default), even when `LOCKC_DAEMONIZE` is set.
+    #[clap(long)]
+    foreground: bool,
+
+    /// File which stdout and stderr (logs and Falco alerts written to
+    /// stdout) are appended to when daemonized. They are discarded when not
+    /// set.
+    #[clap(long, env = "LOCKC_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Path to the procfs of the host. Has to be set when lockc runs in a
+    /// container with its own PID namespace.
+    #[clap(long, env = "LOCKC_HOST_PROC", default_value = HOST_PROC_PATH)]
+    host_proc: PathBuf,
+
+    /// Environment which determines the built-in allowed paths. Overrides
+    /// the profile from the configuration file. When not set anywhere, it's
+    /// detected from the host filesystem, which has to be set explicitly
+    /// when lockc runs in a container.
+    #[clap(value_enum, long, env = "LOCKC_PROFILE")]
+    profile: Option<Profile>,
+
+    /// Report policy violations as Kubernetes Events attached to pods. Has
+    /// to be used only when lockc runs in a Kubernetes cluster.
+    #[cfg(feature = "kubernetes")]
+    #[clap(long, env = "LOCKC_K8S_EVENTS")]
+    k8s_events: bool,
+
+    /// Minimum interval, in seconds, between Kubernetes Events about the
+    /// same container and LSM hook. Violations in between are counted and
+    /// reported with the next event.
+    #[cfg(feature = "kubernetes")]
+    #[clap(long, env = "LOCKC_K8S_EVENTS_INTERVAL", default_value_t = 10)]
+    k8s_events_interval: u64,
+
+    /// Write violations as Falco alerts in JSON to `stdout` or to the unix
+    /// socket with the given path. Logs are written to stdout as well, so
+    /// consumers of alerts on stdout have to skip lines of other formats.
+    #[clap(long, env = "LOCKC_FALCO_OUTPUT")]
+    falco_output: Option<FalcoOutput>,
+
+    /// Interval, in seconds, in which identical violations (same container,
+    /// LSM hook and path) are reported once. Repeats are counted and
+    /// reported as a summary when the interval ends. 0 disables
+    /// deduplication.
+    #[clap(long, env = "LOCKC_VIOLATIONS_DEDUP_INTERVAL", default_value_t = 5)]
+    violations_dedup_interval: u64,
+
+    /// Export container lifecycle and violation events to an OpenTelemetry
+    /// collector, configured with the standard `OTEL_*` environment
+    /// variables.
+    #[cfg(feature = "otel")]
+    #[clap(long, env = "LOCKC_OTEL")]
+    otel: bool,
+
+    /// Trace mode: send fork, exec and exit events of containerized
+    /// processes from eBPF programs, to be streamed with `lockctl trace`.
+    /// Meant for debugging, as it adds overhead to every process event.
+    #[clap(long, env = "LOCKC_TRACE")]
+    trace: bool,
+
+    /// Don't watch runc binaries with fanotify. lockc only loads eBPF
+    /// programs and serves the control API, containers have to be registered
+    /// by an external agent with `add_container` and `add_process` requests.
+    #[clap(long, env = "LOCKC_NO_WATCHER")]
+    no_watcher: bool,
+
+    /// Record fanotify events about runc executions, with command lines of
+    /// the processes and digests of container bundles, to the given file.
+    /// The recording can be replayed with `--replay` to reproduce
+    /// registration of containers on another node.
+    #[clap(long, value_name = "FILE", conflicts_with = "no_watcher")]
+    record: Option<PathBuf>,
+
+    /// Register containers from fanotify events recorded with `--record`
+    /// instead of watching runc, then exit. Init processes of replayed
+    /// containers don't exist, so only the registration is reproduced.
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["no_watcher", "record"])]
+    replay: Option<PathBuf>,
+
+    /// Terminate the running instance of lockc and take over its pinned
+    /// eBPF maps (used for upgrades).
+    #[clap(long, conflicts_with = "handover")]
+    takeover: bool,
+
+    /// Take over eBPF maps, listening sockets and registered containers of
+    /// the running instance of lockc, which exits once this one enforces
+    /// policies and watches runc. Unlike `--takeover`, no runc execution is
+    /// missed during the upgrade.
+    #[clap(long)]
+    handover: bool,
+
+    /// Path to the socket on which the running instance accepts handover.
+    #[clap(long, env = "LOCKC_HANDOVER_SOCKET")]
+    handover_socket: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the configuration file (`--config`) and exit without loading
+    /// eBPF programs.
+    ValidateConfig,
+    /// Evaluate operations of workloads against a policy without loading
+    /// eBPF programs. Exits with an error when any verdict differs from the
+    /// expected one.
+    Simulate {
+        /// Settings file with the policy, followed by workload files.
+        #[clap(short = 'f', long = "file", value_name = "FILE", required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Print the CustomResourceDefinition of `LockcPolicy` resources with
+    /// custom policies.
+    #[cfg(feature = "kubernetes")]
+    Crd,
+    /// Verify the hash chain of the policy audit log and signatures of its
+    /// checkpoints. Exits with an error when lines were modified or removed.
+    VerifyAuditLog {
+        /// Audit log to verify, the one from the configuration file by
+        /// default.
+        #[clap(long)]
+        log: Option<PathBuf>,
+        /// Hex-encoded Ed25519 public key of checkpoints. If not set,
+        /// signatures are not verified.
+        #[clap(long)]
+        public_key: Option<String>,
+    },
 }
 
 #[derive(ValueEnum, Clone)]
@@ -169,37 +1189,352 @@ enum SetupTracingError {
     SetGlobalDefault(#[from] tracing_core::dispatcher::SetGlobalDefaultError),
 }
 
-fn setup_tracing(opt: &Opt) -> Result<(), SetupTracingError> {
-    let (level_tracing, level_log) = match opt.log_level {
-        LogLevel::Trace => (Level::TRACE, log::LevelFilter::Trace),
-        LogLevel::Debug => (Level::DEBUG, log::LevelFilter::Debug),
-        LogLevel::Info => (Level::INFO, log::LevelFilter::Info),
-        LogLevel::Warn => (Level::WARN, log::LevelFilter::Warn),
-        LogLevel::Error => (Level::ERROR, log::LevelFilter::Error),
+/// Sets up logging and returns the handle of the log filter, which can be
+/// changed at runtime.
+fn setup_tracing(opt: &Opt) -> Result<LogFilter, SetupTracingError> {
+    let level = match opt.log_level {
+        LogLevel::Trace => LevelFilter::TRACE,
+        LogLevel::Debug => LevelFilter::DEBUG,
+        LogLevel::Info => LevelFilter::INFO,
+        LogLevel::Warn => LevelFilter::WARN,
+        LogLevel::Error => LevelFilter::ERROR,
+    };
+    let (filter, log_filter) = LogFilter::new(level);
+
+    let span_events = if opt.log_spans {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
     };
+    // The log filter applies only to the output of logs, so the console layer
+    // still gets the trace-level events of tokio tasks.
+    let fmt = tracing_subscriber::fmt::layer().with_span_events(span_events);
+    let fmt = match opt.log_fmt {
+        LogFmt::Json => fmt.json().with_filter(filter).boxed(),
+        LogFmt::Text => fmt.with_filter(filter).boxed(),
+    };
+    let subscriber = Registry::default().with(fmt);
+    #[cfg(feature = "console")]
+    let subscriber = subscriber.with(console_subscriber::spawn());
+    tracing::subscriber::set_global_default(subscriber)?;
+
+    // Records of the `log` crate (including logs of eBPF programs) are
+    // filtered by the log filter, which can be more verbose than the
+    // initial level.
+    LogTracer::builder()
+        .with_max_level(log::LevelFilter::Trace)
+        .init()?;
+
+    Ok(log_filter)
+}
 
-    let builder = FmtSubscriber::builder().with_max_level(level_tracing);
-    match opt.log_fmt {
-        LogFmt::Json => {
-            let subscriber = builder.json().finish();
-            tracing::subscriber::set_global_default(subscriber)?;
+/// Detects the profile from the host filesystem. When lockc runs in a
+/// Kubernetes cluster (`NODE_NAME` is set), managed distributions are also
+/// detected from labels of the node, as the host filesystem is usually not
+/// visible in the container.
+fn detect_profile() -> Profile {
+    let profile = Profile::detect("/");
+    #[cfg(feature = "kubernetes")]
+    if let (Profile::Docker | Profile::Kubeadm, Ok(node_name)) = (profile, env::var("NODE_NAME")) {
+        let res = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())
+            .and_then(|rt| {
+                rt.block_on(profiles::detect_node(&node_name))
+                    .map_err(|e| e.to_string())
+            });
+        match res {
+            Ok(Some(managed)) => return managed,
+            Ok(None) => {}
+            Err(e) => warn!(
+                node = node_name.as_str(),
+                error = e.as_str(),
+                "could not detect the profile from node labels"
+            ),
         }
-        LogFmt::Text => {
-            let subscriber = builder.finish();
-            tracing::subscriber::set_global_default(subscriber)?;
+    }
+    profile
+}
+
+/// Sets up serving of handover requests of the next instance, with the
+/// listener handed over by the previous one, if any.
+fn handover_server(
+    path: &path::Path,
+    path_base: &path::Path,
+    listener: Option<StdUnixListener>,
+    control_listener: &StdUnixListener,
+    metrics: Option<&MetricsEndpoint>,
+    status: Option<&StatusEndpoint>,
+) -> Result<HandoverServer, std::io::Error> {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => handover::bind(path)?,
+    };
+    HandoverServer::new(
+        listener,
+        open_pinned_maps(path_base)?,
+        control_listener,
+        metrics.map(|metrics| &metrics.listener),
+        status.map(|status| &status.listener),
+    )
+}
+
+fn run(opt: Opt, log_filter: LogFilter) -> Result<(), Error> {
+    let mut settings = Settings::new(&opt.config)?;
+    if let Some(bpf_pin_path) = &opt.bpf_pin_path {
+        if !bpf_pin_path.is_absolute() {
+            return Err(SettingsError::RelativePinPath(bpf_pin_path.clone()).into());
         }
+        settings.bpf_pin_path = bpf_pin_path.clone();
+    }
+    if let Some(instance) = &opt.instance {
+        settings::validate_instance(instance)?;
+        settings.instance = Some(instance.clone());
+    }
+    let instance = settings.instance.as_deref();
+    let instance_file = |path: &Option<PathBuf>, default: &str| match path {
+        Some(path) => path.clone(),
+        None => settings::instance_file(path::Path::new(default), instance),
     };
+    let control_socket = instance_file(&opt.control_socket, CONTROL_SOCKET_PATH);
+    let lock_file = instance_file(&opt.lock_file, LOCK_PATH);
+    let handover_socket = instance_file(&opt.handover_socket, HANDOVER_SOCKET_PATH);
+    let image_policies = ImagePolicies::new(&settings.image_policies)?;
+    let bpf_public_key = settings.bpf_public_key()?;
+    let bpf_object = match &opt.bpf_path {
+        Some(bpf_path) => BpfObject::from_file(bpf_path),
+        None => BpfObject::find(&settings.bpf_paths),
+    }
+    .map_err(Error::BpfObject)?
+    .verifiable_or_embedded(bpf_public_key.as_deref(), settings.allow_unsigned_bpf);
 
-    LogTracer::builder().with_max_level(level_log).init()?;
+    // Fork before detecting the profile, which can spawn threads. Invalid
+    // settings are still reported on the terminal.
+    let readiness = if opt.daemonize && !opt.foreground {
+        Some(daemonize(opt.log_file.as_deref())?)
+    } else {
+        None
+    };
 
-    Ok(())
-}
+    let profile = opt
+        .profile
+        .or(settings.profile)
+        .unwrap_or_else(detect_profile);
+    info!(profile = profile.to_string().as_str(), "using profile");
+    let mut allowed_paths = profile.allowed_paths();
+    allowed_paths.extend(&settings.allowed_paths);
+    if settings.deny_runtime_sockets {
+        allowed_paths.deny_runtime_sockets();
+    }
 
-fn main() -> Result<(), anyhow::Error> {
-    let opt = Opt::parse();
-    setup_tracing(&opt)?;
+    // Only one instance can operate on the pinned eBPF maps. The lock is held
+    // until the process exits. With handover, the running instance holds it
+    // until it hands over its state and exits.
+    let mut handover = opt
+        .handover
+        .then(|| Handover::request(&handover_socket))
+        .transpose()?;
+    let mut _instance_lock = match handover {
+        Some(_) => None,
+        None => Some(InstanceLock::acquire(&lock_file, opt.takeover)?),
+    };
+
+    // Step 1: Do all the setup which requires full privileges:
+    // * loading and attaching of eBPF programs
+    // * adding fanotify marks on runc binaries, unless the watcher is disabled
+    // * binding the control API socket, the metrics endpoint, the status page
+    //   and the handover socket, unless they are handed over by the running
+    //   instance
+    // * loading certificates of the reporter
+    // That happens before spawning any threads, so privileges can be dropped
+    // for the whole process afterwards.
+    let bpf = setup_bpf(
+        &bpf_object,
+        bpf_public_key.as_deref(),
+        &allowed_paths,
+        &settings,
+        opt.trace,
+        handover.as_ref(),
+    )?;
+    let health = EbpfHealth::default();
+    let mut supervisor = Supervisor::new(
+        health.clone(),
+        settings.bpf_pin_path(),
+        settings.mount_bpffs,
+        open_pinned_maps(&settings.bpf_pin_path()).map_err(HealthError::Open)?,
+        settings.ebpf_supervision,
+    );
+
+    // eBPF thread channel - used by fanotify thread to request eBFP operations
+    // from the async eBPF thread.
+    let (ebpf_tx, ebpf_rx) = mpsc::channel::<EbpfRequest>(settings.ebpf_channel.capacity);
+
+    let containers = Arc::new(RwLock::new(ContainerRegistry::default()));
+    let control_state = ControlState {
+        digests: bpf_object.digests(bpf_public_key.is_some())?,
+        containers: containers.clone(),
+        ebpf_tx: ebpf_tx.downgrade(),
+        log_filter,
+        trace_tx: opt.trace.then(|| broadcast::channel(100).0),
+        learned_mounts: settings.learning_mode.then(Arc::default),
+        policy_audit: Arc::new(Mutex::new(PolicyAudit::new(&settings.policy_audit)?)),
+        fingerprints: settings
+            .fingerprints
+            .enabled
+            .then(|| Fingerprints::open(&settings.fingerprints))
+            .transpose()?
+            .map(|fingerprints| Arc::new(RwLock::new(fingerprints))),
+        loaded_programs: loaded_programs(&bpf),
+    };
+
+    let custom_policies = settings.custom_policies.then(CustomPolicies::default);
+    #[cfg(not(feature = "kubernetes"))]
+    if custom_policies.is_some() {
+        warn!("lockc is built without the kubernetes feature, custom policies are not reconciled");
+    }
+
+    let replay = opt.replay.as_deref().map(read_recording).transpose()?;
+    let (registration, watcher) = if opt.no_watcher {
+        (Registration::ControlApi(ebpf_tx), None)
+    } else {
+        // Fanotify thread bootstrap channel - used later to start the real
+        // bootstrap of the thread. We want to bootstrap it later, after the
+        // Tokio runtime is ready to handle eBPF commands.
+        let (fanotify_bootstrap_tx, fanotify_bootstrap_rx) =
+            oneshot::channel::<oneshot::Sender<()>>();
+        let pids = PidTranslator::new(&opt.host_proc)?;
+        let runc_verifier = RuncVerifier::new(&settings.runc_digests, settings.record_runc_digests);
+        let new_watcher = if replay.is_some() {
+            RuncWatcher::unmarked
+        } else {
+            RuncWatcher::new
+        };
+        let mut watcher = new_watcher(
+            fanotify_bootstrap_rx,
+            EbpfSender::new(ebpf_tx, &settings.ebpf_channel).with_health(health.clone()),
+            image_policies,
+            NamespacePolicies::new(&settings.namespace_policies)?,
+            pids,
+            runc_verifier,
+            &settings,
+            SpecValidator::new(settings.spec_validation.mode, allowed_paths.clone()),
+        )
+        .map_err(Error::Fanotify)?;
+        if let Some(custom_policies) = &custom_policies {
+            watcher = watcher.with_custom_policies(custom_policies.clone());
+        }
+        if let Some(path) = &opt.record {
+            watcher = watcher.with_recorder(Recorder::create(path)?);
+            warn!(
+                path = path.to_string_lossy().as_ref(),
+                "recording fanotify events"
+            );
+        }
+        let liveness = replay.is_none().then(|| watcher.liveness());
+        (
+            Registration::Watcher(fanotify_bootstrap_tx, liveness),
+            Some(watcher),
+        )
+    };
 
-    // Step 1: Create a synchronous thread which takes care of fanotify
+    // Use the socket passed by systemd with socket activation, or the one of
+    // the running instance on handover, if any.
+    let handed_control = handover
+        .as_mut()
+        .and_then(|handover| handover.control.take());
+    let control_listener = match (systemd::listen_fds()?.first(), handed_control) {
+        (Some(fd), _) => control::from_fd(*fd),
+        (None, Some(listener)) => Ok(listener),
+        (None, None) => control::bind(&control_socket),
+    }
+    .map_err(Error::ControlSocket)?;
+
+    let registration_latency = watcher.as_ref().map(RuncWatcher::registration_latency);
+    let command_timeouts = watcher.as_ref().map(RuncWatcher::command_timeouts);
+    let metrics = opt
+        .metrics_address
+        .map(|addr| {
+            // The running instance still listens on the address.
+            let handed_metrics = handover
+                .as_mut()
+                .and_then(|handover| handover.metrics.take())
+                .filter(|listener| listener.local_addr().ok() == Some(addr));
+            let listener = match handed_metrics {
+                Some(listener) => listener,
+                None => StdTcpListener::bind(addr)?,
+            };
+            listener.set_nonblocking(true)?;
+            Ok(MetricsEndpoint {
+                listener,
+                registration_latency,
+                command_timeouts,
+            })
+        })
+        .transpose()
+        .map_err(Error::Metrics)?;
+    let status = opt
+        .status_addr
+        .map(|addr| {
+            let handed_status = handover
+                .as_mut()
+                .and_then(|handover| handover.status.take())
+                .filter(|listener| listener.local_addr().ok() == Some(addr));
+            let listener = match handed_status {
+                Some(listener) => listener,
+                None => StdTcpListener::bind(addr)?,
+            };
+            listener.set_nonblocking(true)?;
+            Ok(StatusEndpoint {
+                listener,
+                health: health.clone(),
+            })
+        })
+        .transpose()
+        .map_err(Error::Status)?;
+    let reporter = Reporter::new(&settings.reporter)?;
+
+    let handed_listener = handover
+        .as_mut()
+        .and_then(|handover| handover.listener.take());
+    let handover_server = match handover_server(
+        &handover_socket,
+        &settings.bpf_pin_path(),
+        handed_listener,
+        &control_listener,
+        metrics.as_ref(),
+        status.as_ref(),
+    ) {
+        Ok(handover_server) => Some(handover_server),
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not set up handover, upgrades of this instance need --takeover"
+            );
+            None
+        }
+    };
+
+    // Programs of this instance are attached and runc binaries are marked,
+    // so the running instance can exit.
+    if let Some(handover) = handover {
+        let restored = handover.complete(&containers)?;
+        info!(containers = restored, "took over from the running instance");
+        _instance_lock = Some(InstanceLock::wait(&lock_file)?);
+    }
+
+    if let Some(user) = &settings.user {
+        if settings.incident_response.enabled() {
+            warn!(
+                user = user.as_str(),
+                "containers can't be frozen without root, incident response will fail"
+            );
+        }
+        drop_privileges(user)?;
+        supervisor = supervisor.without_repair();
+    }
+
+    // Step 2: Create a synchronous thread which takes care of fanotify
     // polling on runc binaries. We monitor all possible runc binaries to get
     // all runc execution events (and therefore - all operations on
     // containers).
@@ -211,33 +1546,231 @@ fn main() -> Result<(), anyhow::Error> {
     //   container exactly before we allow runc to be actually executed;
     //   otherwise we cannot guarantee that lockc will actually enforce
     //   anything on that container.
-
-    // Fanotify thread bootstrap channel - used later to start the real bootstrap
-    // of the thread. We want to bootstrap it later, after loading eBPF
-    // programs (which happens in async code in Tokio runtime).
-    let (fanotify_bootstrap_tx, fanotify_bootstrap_rx) = oneshot::channel::<()>();
-
-    // eBPF thread channel - used by fanotify thread to request eBFP operations
-    // from the async eBPF thread.
-    let (ebpf_tx, ebpf_rx) = mpsc::channel::<EbpfCommand>(100);
+    // The thread is not created when containers are registered by an
+    // external agent through the control API.
 
     // Start the thread (but it's going to wait for bootstrap).
-    let fanotify_thread = thread::spawn(move || fanotify(fanotify_bootstrap_rx, ebpf_tx));
+    let fanotify_thread = watcher.map(|watcher| thread::spawn(move || fanotify(watcher, replay)));
 
-    // Step 2: Setup a Tokio runtime for asynchronous part of lockc, which
+    // Serve handover requests of the next instance.
+    if let Some(handover_server) = handover_server {
+        thread::spawn(move || handover_server.serve(containers));
+    }
+
+    // Step 3: Setup a Tokio runtime for asynchronous part of lockc, which
     // takes care of:
-    // * loading and attaching of eBPF programs
     // * fetching events/logs from eBPF programs
-    // After initializing the eBPF world, the thread from the step 1 is going
+    // * performing eBPF map operations
+    // * serving the control API
+    // After initializing the eBPF world, the thread from the step 2 is going
     // to be bootstraped.
 
-    let rt = Runtime::new()?;
+    let rt = runtime(&settings.async_runtime).map_err(Error::Runtime)?;
+
+    #[cfg(feature = "kubernetes")]
+    if let Some(custom_policies) = custom_policies {
+        rt.spawn(custom_policies::reconcile(custom_policies));
+        debug!("reconciling custom policies");
+    }
+
+    if settings.policy_audit.log.is_some() && settings.policy_audit.checkpoint_interval_s > 0 {
+        rt.spawn(policy_audit::checkpoints(
+            control_state.policy_audit.clone(),
+            Duration::from_secs(settings.policy_audit.checkpoint_interval_s),
+        ));
+        debug!("writing checkpoints of the policy audit log");
+    }
+
+    // The eBPF loop owns the `Bpf` object and runs on this thread, not on a
+    // worker thread. Map operations block, scans of large maps for a while,
+    // but tasks serving the control API, metrics and events keep running on
+    // the workers in the meantime.
 
-    rt.block_on(ebpf(fanotify_bootstrap_tx, ebpf_rx))?;
+    rt.block_on(ebpf(
+        bpf,
+        registration,
+        ebpf_rx,
+        control_listener,
+        control_state,
+        metrics,
+        status,
+        EventSinks {
+            violations_dedup: Duration::from_secs(opt.violations_dedup_interval),
+            #[cfg(feature = "kubernetes")]
+            k8s_events: opt
+                .k8s_events
+                .then(|| Duration::from_secs(opt.k8s_events_interval)),
+            falco: opt.falco_output,
+            #[cfg(feature = "otel")]
+            otel: opt.otel,
+            incident_response: settings
+                .incident_response
+                .enabled()
+                .then(|| settings.incident_response.clone()),
+            fingerprints: settings
+                .fingerprints
+                .enabled
+                .then(|| Duration::from_secs(settings.fingerprints.save_interval_s)),
+            status: opt.status_addr.is_some(),
+            reporter,
+        },
+        readiness,
+        supervisor,
+        EgressLinks::new(settings.egress.policy_levels.clone()),
+    ))?;
 
-    if let Err(e) = fanotify_thread.join() {
-        error!("failed to join the fanotify thread: {:?}", e);
+    // The eBPF loop ends when the fanotify thread exits.
+    match fanotify_thread.map(|thread| thread.join()) {
+        Some(Ok(res)) => res,
+        Some(Err(_)) => Err(Error::WatcherPanic),
+        None => Ok(()),
     }
+}
 
+/// Builds the multi-threaded Tokio runtime.
+fn runtime(settings: &AsyncRuntime) -> io::Result<Runtime> {
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name("lockc-worker");
+    if settings.worker_threads > 0 {
+        builder.worker_threads(settings.worker_threads);
+    }
+    builder.build()
+}
+
+/// Parses the configuration file and all settings which are validated only
+/// when lockc starts (image policies, the public key).
+fn validate_config(path: &path::Path) -> Result<(), Error> {
+    let settings = Settings::from_file(path)?;
+    ImagePolicies::new(&settings.image_policies)?;
+    NamespacePolicies::new(&settings.namespace_policies)?;
+    settings.bpf_public_key()?;
     Ok(())
 }
+
+/// Prints verdicts of the simulation and fails if any of them differs from
+/// the expected one.
+fn simulate(files: &[PathBuf], profile: Option<Profile>) -> Result<(), Error> {
+    let decisions = simulate::simulate(files, profile)?;
+    println!(
+        "{:<24} {:<12} {:<48} {:<8} EXPECTED",
+        "CONTAINER", "POLICY", "OPERATION", "VERDICT"
+    );
+    for decision in decisions.iter() {
+        let policy_level = decision
+            .policy_level
+            .map_or_else(|| "-".to_string(), |policy_level| policy_level.to_string());
+        let expected = match decision.expected {
+            Some(expected) if !decision.matches() => format!("{} (mismatch)", expected),
+            Some(expected) => expected.to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<24} {:<12} {:<48} {:<8} {}",
+            decision.container,
+            policy_level,
+            decision.operation.to_string(),
+            decision.verdict.to_string(),
+            expected
+        );
+    }
+
+    let mismatches = decisions.iter().filter(|d| !d.matches()).count();
+    if mismatches > 0 {
+        return Err(SimulateError::Mismatch(mismatches).into());
+    }
+    Ok(())
+}
+
+/// Prints issues found in the policy audit log and its last checkpoint, to
+/// be compared with the one logged by lockc. Fails if there are any issues.
+fn verify_audit_log(
+    config: &path::Path,
+    log: Option<&path::Path>,
+    public_key: Option<&str>,
+) -> Result<(), Error> {
+    let log = match log {
+        Some(log) => log.to_path_buf(),
+        None => Settings::from_file(config)?
+            .policy_audit
+            .log
+            .ok_or(PolicyAuditError::NotConfigured)?,
+    };
+    let public_key = public_key
+        .map(hex::decode)
+        .transpose()
+        .map_err(PolicyAuditError::from)?;
+    let verification = policy_audit::verify(&log, public_key.as_deref())?;
+    for issue in &verification.issues {
+        println!("{}", issue);
+    }
+    println!(
+        "{}: {} lines, last hash {}",
+        log.display(),
+        verification.records,
+        verification.hash
+    );
+    match &verification.last_checkpoint {
+        Some(checkpoint) => println!(
+            "last checkpoint at {}: {} lines, hash {}",
+            checkpoint.time, checkpoint.records, checkpoint.hash
+        ),
+        None => println!("no checkpoint"),
+    }
+    if !verification.issues.is_empty() {
+        return Err(PolicyAuditError::Verification(verification.issues.len()).into());
+    }
+    Ok(())
+}
+
+/// Runs a subcommand, which doesn't start lockc.
+fn command(opt: &Opt, command: &Command) -> Result<(), Error> {
+    match command {
+        Command::ValidateConfig => {
+            validate_config(&opt.config)?;
+            println!("{}: configuration is valid", opt.config.display());
+        }
+        Command::Simulate { files } => simulate(files, opt.profile)?,
+        #[cfg(feature = "kubernetes")]
+        Command::Crd => {
+            let crd = serde_yaml::to_string(&LockcPolicy::crd()).map_err(Error::Crd)?;
+            print!("{}", crd);
+        }
+        Command::VerifyAuditLog { log, public_key } => {
+            verify_audit_log(&opt.config, log.as_deref(), public_key.as_deref())?
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let opt = Opt::parse();
+    if let Some(cmd) = &opt.command {
+        return match command(&opt, cmd) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("{}", e);
+                ExitCode::from(e.exit_code())
+            }
+        };
+    }
+    let log_filter = match setup_tracing(&opt) {
+        Ok(log_filter) => log_filter,
+        Err(e) => {
+            let e = Error::from(e);
+            eprintln!("{}", e);
+            return ExitCode::from(e.exit_code());
+        }
+    };
+
+    match run(opt, log_filter) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!(
+                error = e.to_string().as_str(),
+                exit_code = e.exit_code(),
+                "lockc failed"
+            );
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
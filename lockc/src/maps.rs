@@ -1,69 +1,603 @@
+use std::{fs, io, net::IpAddr, path::Path};
+
 use aya::{
-    maps::{HashMap, MapError},
+    maps::{lpm_trie::Key, HashMap, LpmTrie, MapError, MapRef, MapRefMut, PerCpuArray},
     Bpf,
 };
-use config::ConfigError;
+use nix::sys::statfs::{statfs, PROC_SUPER_MAGIC};
 use thiserror::Error;
 use tracing::{debug, warn};
 
-use lockc_common::{Container, ContainerID, ContainerPolicyLevel, NewContainerIDError, Process};
+use lockc_common::{
+    comm_hash, Container, ContainerID, ContainerPolicyLevel, ContainerSpec, CustomMounts,
+    DenyResponse, Enforcement, FilePermission, InodeId, InodeInfo, InodePrefix, LearnedCapability,
+    LearnedMount, MapOperation, NewContainerIDError, PathClass, PathPrefix, PathTooLongError,
+    Process, Program, ProgramStats, EGRESS_DENY_MAX, EXCLUDED_MAX, MASKED_PROC_MAX, PATH_MAX_LIMIT,
+    PROTECTED_MAX,
+};
+
+use crate::{
+    egress::EgressError,
+    fingerprints::CapabilityUses,
+    learning::MountAttempts,
+    profiles::AllowedPaths,
+    settings::{Cidr, ExcludedProcesses, ProcMasking},
+};
 
 #[derive(Error, Debug)]
 pub enum MapOperationError {
-    #[error(transparent)]
-    Config(#[from] ConfigError),
-
     #[error(transparent)]
     Map(#[from] MapError),
 
     #[error(transparent)]
     NewContainerID(#[from] NewContainerIDError),
+
+    #[error(transparent)]
+    ContainerIDUtf8(#[from] std::str::Utf8Error),
+
+    #[error(transparent)]
+    PathTooLong(#[from] PathTooLongError),
+
+    #[error("could not get the inode of {path}: {source}")]
+    Inode {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("too many paths of class {class:?}, the limit is {}", PATH_MAX_LIMIT)]
+    TooManyPaths { class: PathClass },
+
+    #[error(
+        "too many excluded processes, the limit is {} command names and {} paths",
+        EXCLUDED_MAX,
+        EXCLUDED_MAX
+    )]
+    TooManyExcluded,
+
+    #[error(
+        "too many pinned eBPF objects to protect, the limit is {}",
+        PROTECTED_MAX
+    )]
+    TooManyProtected,
+
+    #[error(
+        "too many denied networks, the limit is {} of each IP version",
+        EGRESS_DENY_MAX
+    )]
+    TooManyNetworks,
+
+    #[error("too many masked paths of procfs, the limit is {}", MASKED_PROC_MAX)]
+    TooManyMaskedProc,
+
+    #[error("container {0} is not registered")]
+    ContainerNotFound(String),
+
+    #[error("could not restrict egress of the container: {0}")]
+    Egress(#[from] EgressError),
+
+    #[error(
+        "container {container_id} is already registered with policy {registered} ({registered_enforcement}), not {requested} ({requested_enforcement})"
+    )]
+    PolicyConflict {
+        container_id: String,
+        registered: ContainerPolicyLevel,
+        registered_enforcement: Enforcement,
+        requested: ContainerPolicyLevel,
+        requested_enforcement: Enforcement,
+    },
 }
 
-pub fn add_container(
+/// Container which the process belongs to.
+#[derive(Debug)]
+pub struct ProcessContainer {
+    pub container_id: String,
+    pub policy_level: ContainerPolicyLevel,
+}
+
+/// How [`add_container`] handles a container which is already registered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddMode {
+    /// Register a new container. Registering it again with the same policy
+    /// (e.g. when runc create is retried) only adds the process, a different
+    /// policy is a conflict.
+    Create,
+    /// Register the container or replace the policy of the registered one.
+    Upsert,
+}
+
+/// Outcome of [`add_container`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddOutcome {
+    Created,
+    /// The container was already registered with the same policy.
+    Unchanged,
+    /// The container was registered with the given policy, which was
+    /// replaced.
+    Replaced {
+        policy_level: ContainerPolicyLevel,
+        enforcement: Enforcement,
+    },
+}
+
+/// Decides what happens to the container, given its registered policy.
+fn add_outcome(
+    container_id: &str,
+    registered: Option<Container>,
+    requested: Container,
+    mode: AddMode,
+) -> Result<AddOutcome, MapOperationError> {
+    let registered = match registered {
+        Some(registered) => registered,
+        None => return Ok(AddOutcome::Created),
+    };
+    if registered.policy_level == requested.policy_level
+        && registered.enforcement == requested.enforcement
+        && registered.syslog == requested.syslog
+    {
+        return Ok(AddOutcome::Unchanged);
+    }
+    match mode {
+        AddMode::Create => Err(MapOperationError::PolicyConflict {
+            container_id: container_id.to_string(),
+            registered: registered.policy_level,
+            registered_enforcement: registered.enforcement,
+            requested: requested.policy_level,
+            requested_enforcement: requested.enforcement,
+        }),
+        AddMode::Upsert => Ok(AddOutcome::Replaced {
+            policy_level: registered.policy_level,
+            enforcement: registered.enforcement,
+        }),
+    }
+}
+
+/// eBPF maps operated on by the eBPF thread after startup. Typed handles are
+/// created once after loading the programs, so commands don't look the maps
+/// up by name and convert them every time. The handles hold locks of the
+/// maps, which can't be taken from [`Bpf`] anymore while they exist.
+pub struct LockcMaps {
+    containers: HashMap<MapRefMut, ContainerID, Container>,
+    container_specs: HashMap<MapRefMut, ContainerID, ContainerSpec>,
+    custom_mounts: HashMap<MapRefMut, ContainerID, CustomMounts>,
+    processes: HashMap<MapRefMut, i32, Process>,
+    container_cgroups: HashMap<MapRefMut, u64, ContainerID>,
+    learned_mounts: HashMap<MapRefMut, LearnedMount, u64>,
+    learned_capabilities: HashMap<MapRefMut, LearnedCapability, u64>,
+    map_errors: PerCpuArray<MapRef, u64>,
+    program_stats: PerCpuArray<MapRef, ProgramStats>,
+}
+
+impl LockcMaps {
+    pub fn new(bpf: &Bpf) -> Result<Self, MapOperationError> {
+        Ok(LockcMaps {
+            containers: bpf.map_mut("CONTAINERS")?.try_into()?,
+            container_specs: bpf.map_mut("CONTAINER_SPECS")?.try_into()?,
+            custom_mounts: bpf.map_mut("CUSTOM_MOUNTS")?.try_into()?,
+            processes: bpf.map_mut("PROCESSES")?.try_into()?,
+            container_cgroups: bpf.map_mut("CONTAINER_CGROUPS")?.try_into()?,
+            learned_mounts: bpf.map_mut("LEARNED_MOUNTS")?.try_into()?,
+            learned_capabilities: bpf.map_mut("LEARNED_CAPABILITIES")?.try_into()?,
+            map_errors: bpf.map("MAP_ERRORS")?.try_into()?,
+            program_stats: bpf.map("PROGRAM_STATS")?.try_into()?,
+        })
+    }
+}
+
+/// Writes allowed and denied path prefixes to the `PATH_PREFIXES` and
+/// `INODE_PREFIXES` eBPF maps. The maps are recreated on every start, so they
+/// contain only the current prefixes. Only the prefixes themselves are
+/// inserted, large trees (e.g. /var/lib/docker) are never walked here. eBPF
+/// programs match paths of files in their subdirectories with a longest
+/// prefix lookup in `PATH_PREFIXES`, and inodes by walking up to
+/// `INODE_WALK_DEPTH` parent dentries of the file and looking each of them up
+/// in `INODE_PREFIXES`.
+pub fn init_allowed_paths(
+    bpf: &mut Bpf,
+    allowed_paths: &AllowedPaths,
+) -> Result<(), MapOperationError> {
+    let prefixes = allowed_paths.prefixes();
+    for class in [
+        PathClass::MountRestricted,
+        PathClass::MountBaseline,
+        PathClass::AccessRestricted,
+        PathClass::AccessBaseline,
+    ] {
+        let count = prefixes.iter().filter(|(c, _, _)| *c == class).count();
+        if count > PATH_MAX_LIMIT as usize {
+            return Err(MapOperationError::TooManyPaths { class });
+        }
+    }
+
+    let mut map: LpmTrie<_, PathPrefix, FilePermission> =
+        bpf.map_mut("PATH_PREFIXES")?.try_into()?;
+    for (class, path, permission) in &prefixes {
+        debug!(
+            path = path,
+            class = ?class,
+            permission = ?permission,
+            map = "PATH_PREFIXES",
+            "adding path to eBPF map"
+        );
+        map.insert(&PathPrefix::key(*class, path)?, *permission, 0)?;
+    }
+
+    init_inode_prefixes(bpf, &prefixes)?;
+    init_deny_responses(bpf, allowed_paths)
+}
+
+/// Returns the inode of the path, `None` if it doesn't exist on the host.
+fn path_inode(path: &str) -> Result<Option<InodeId>, MapOperationError> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(InodeId::from_metadata(&metadata))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(source) => Err(MapOperationError::Inode {
+            path: path.to_string(),
+            source,
+        }),
+    }
+}
+
+/// Writes inodes of path prefixes to the `INODE_PREFIXES` eBPF map. They are
+/// checked when opening files and when attaching detached bind mounts. Paths
+/// which don't exist on the host are matched only by `PATH_PREFIXES`. When
+/// two paths point to the same inode, the denied one wins, as denied paths
+/// come after the allowed ones.
+fn init_inode_prefixes(
+    bpf: &mut Bpf,
+    prefixes: &[(PathClass, &str, FilePermission)],
+) -> Result<(), MapOperationError> {
+    let mut map: HashMap<_, InodePrefix, InodeInfo> = bpf.map_mut("INODE_PREFIXES")?.try_into()?;
+    for (class, path, permission) in prefixes {
+        let inode = match path_inode(path)? {
+            Some(inode) => inode,
+            None => {
+                debug!(path = path, "path doesn't exist, not adding its inode");
+                continue;
+            }
+        };
+        debug!(
+            path = path,
+            i_ino = inode.i_ino,
+            s_dev = inode.s_dev,
+            map = "INODE_PREFIXES",
+            "adding inode to eBPF map"
+        );
+        map.insert(
+            InodePrefix::new(*class, inode),
+            InodeInfo {
+                permission: *permission,
+            },
+            0,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes responses to opening denied paths to the `DENY_RESPONSES` eBPF map.
+/// Audited paths are added to `INODE_PREFIXES` as denied, so eBPF programs
+/// look up their response. Paths which don't exist on the host have no
+/// response.
+fn init_deny_responses(
     bpf: &mut Bpf,
+    allowed_paths: &AllowedPaths,
+) -> Result<(), MapOperationError> {
+    if allowed_paths.responses.len() > PATH_MAX_LIMIT as usize {
+        return Err(MapOperationError::TooManyPaths {
+            class: PathClass::AccessRestricted,
+        });
+    }
+
+    let mut inodes = Vec::new();
+    for (path, response) in &allowed_paths.responses {
+        match path_inode(path)? {
+            Some(inode) => inodes.push((path, inode, *response)),
+            None => warn!(
+                path = path.as_str(),
+                response = response.to_string().as_str(),
+                "path doesn't exist, its response is not applied"
+            ),
+        }
+    }
+
+    let mut prefixes: HashMap<_, InodePrefix, InodeInfo> =
+        bpf.map_mut("INODE_PREFIXES")?.try_into()?;
+    for (_, inode, response) in &inodes {
+        if *response != DenyResponse::Audit {
+            continue;
+        }
+        for class in [PathClass::AccessRestricted, PathClass::AccessBaseline] {
+            prefixes.insert(
+                InodePrefix::new(class, *inode),
+                InodeInfo {
+                    permission: FilePermission::Deny,
+                },
+                0,
+            )?;
+        }
+    }
+
+    let mut responses: HashMap<_, InodePrefix, DenyResponse> =
+        bpf.map_mut("DENY_RESPONSES")?.try_into()?;
+    for (path, inode, response) in inodes {
+        debug!(
+            path = path.as_str(),
+            i_ino = inode.i_ino,
+            s_dev = inode.s_dev,
+            response = response.to_string().as_str(),
+            map = "DENY_RESPONSES",
+            "adding inode to eBPF map"
+        );
+        for class in [PathClass::AccessRestricted, PathClass::AccessBaseline] {
+            responses.insert(InodePrefix::new(class, inode), response, 0)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes hashes of excluded command names and inodes of excluded
+/// executables to the `EXCLUDED_COMMS` and `EXCLUDED_EXECUTABLES` eBPF maps.
+/// Executables which don't exist on the host are skipped.
+pub fn init_excluded(bpf: &mut Bpf, excluded: &ExcludedProcesses) -> Result<(), MapOperationError> {
+    if excluded.comms.len() > EXCLUDED_MAX as usize || excluded.paths.len() > EXCLUDED_MAX as usize
+    {
+        return Err(MapOperationError::TooManyExcluded);
+    }
+
+    let mut comms: HashMap<_, u32, bool> = bpf.map_mut("EXCLUDED_COMMS")?.try_into()?;
+    for comm in excluded.comms.iter() {
+        debug!(comm = comm.as_str(), "excluding processes");
+        comms.insert(comm_hash(comm.as_bytes()), true, 0)?;
+    }
+
+    let mut executables: HashMap<_, InodeId, bool> =
+        bpf.map_mut("EXCLUDED_EXECUTABLES")?.try_into()?;
+    for path in excluded.paths.iter() {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!(path = path.as_str(), "excluded executable doesn't exist");
+                continue;
+            }
+            Err(source) => {
+                return Err(MapOperationError::Inode {
+                    path: path.clone(),
+                    source,
+                })
+            }
+        };
+        let inode = InodeId::from_metadata(&metadata);
+        debug!(
+            path = path.as_str(),
+            i_ino = inode.i_ino,
+            s_dev = inode.s_dev,
+            "excluding processes"
+        );
+        executables.insert(inode, true, 0)?;
+    }
+
+    Ok(())
+}
+
+/// Writes networks which containers with restricted egress can't connect to
+/// to the `EGRESS_DENY_V4` and `EGRESS_DENY_V6` eBPF maps.
+pub fn init_egress(bpf: &mut Bpf, networks: &[Cidr]) -> Result<(), MapOperationError> {
+    let v4 = networks
+        .iter()
+        .filter(|network| network.addr.is_ipv4())
+        .count();
+    if v4 > EGRESS_DENY_MAX as usize || networks.len() - v4 > EGRESS_DENY_MAX as usize {
+        return Err(MapOperationError::TooManyNetworks);
+    }
+
+    for network in networks {
+        debug!(
+            network = network.to_string().as_str(),
+            "denying connections to network"
+        );
+        let prefix_len = network.prefix_len.into();
+        match network.addr {
+            IpAddr::V4(addr) => {
+                let mut map: LpmTrie<_, [u8; 4], u8> = bpf.map_mut("EGRESS_DENY_V4")?.try_into()?;
+                map.insert(&Key::new(prefix_len, addr.octets()), 1, 0)?;
+            }
+            IpAddr::V6(addr) => {
+                let mut map: LpmTrie<_, [u8; 16], u8> =
+                    bpf.map_mut("EGRESS_DENY_V6")?.try_into()?;
+                map.insert(&Key::new(prefix_len, addr.octets()), 1, 0)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes inodes of the directory with pinned eBPF objects of lockc and of
+/// the objects in it to the `PROTECTED_INODES` eBPF map. Has to be called
+/// after loading, when all maps are pinned.
+pub fn init_protected(bpf: &mut Bpf, path_base: &Path) -> Result<(), MapOperationError> {
+    let inode_error = |path: &Path, source| MapOperationError::Inode {
+        path: path.to_string_lossy().into_owned(),
+        source,
+    };
+    let mut paths = vec![path_base.to_path_buf()];
+    for entry in fs::read_dir(path_base).map_err(|e| inode_error(path_base, e))? {
+        paths.push(entry.map_err(|e| inode_error(path_base, e))?.path());
+    }
+    if paths.len() > PROTECTED_MAX as usize {
+        return Err(MapOperationError::TooManyProtected);
+    }
+
+    let mut protected: HashMap<_, InodeId, bool> = bpf.map_mut("PROTECTED_INODES")?.try_into()?;
+    for path in paths {
+        let metadata = fs::symlink_metadata(&path).map_err(|e| inode_error(&path, e))?;
+        let inode = InodeId::from_metadata(&metadata);
+        debug!(
+            path = path.to_string_lossy().as_ref(),
+            i_ino = inode.i_ino,
+            s_dev = inode.s_dev,
+            "protecting pinned eBPF object"
+        );
+        protected.insert(inode, true, 0)?;
+    }
+
+    Ok(())
+}
+
+/// Writes inode numbers of masked paths of procfs, with bits of policy levels
+/// of containers which can't unmask them, to the `MASKED_PROC_INODES` eBPF
+/// map. Paths which don't exist on the host (e.g. on older kernels) or are
+/// not on procfs are skipped.
+pub fn init_proc_masking(
+    bpf: &mut Bpf,
+    proc_masking: &ProcMasking,
+) -> Result<(), MapOperationError> {
+    let levels = proc_masking
+        .policy_levels
+        .iter()
+        .fold(0, |levels, level| levels | level.bit());
+    if levels == 0 {
+        return Ok(());
+    }
+    if proc_masking.paths.len() > MASKED_PROC_MAX as usize {
+        return Err(MapOperationError::TooManyMaskedProc);
+    }
+
+    let mut masked: HashMap<_, u64, u8> = bpf.map_mut("MASKED_PROC_INODES")?.try_into()?;
+    for path in &proc_masking.paths {
+        let inode = match path_inode(path)? {
+            Some(inode) => inode,
+            None => {
+                debug!(path = path.as_str(), "masked path doesn't exist, skipping");
+                continue;
+            }
+        };
+        match statfs(path.as_str()) {
+            Ok(stat) if stat.filesystem_type() == PROC_SUPER_MAGIC => {}
+            _ => {
+                warn!(
+                    path = path.as_str(),
+                    "masked path is not on procfs, skipping"
+                );
+                continue;
+            }
+        }
+        debug!(
+            path = path.as_str(),
+            i_ino = inode.i_ino,
+            "protecting masked path of procfs"
+        );
+        masked.insert(inode.i_ino, levels, 0)?;
+    }
+
+    Ok(())
+}
+
+/// Registers the container with its first process. A registered container
+/// is handled according to `mode`, a conflict leaves it untouched. `syslog`
+/// exempts the container from denials of reading the kernel log.
+#[allow(clippy::too_many_arguments)]
+pub fn add_container(
+    maps: &mut LockcMaps,
     container_id: String,
     pid: i32,
     policy_level: ContainerPolicyLevel,
-) -> Result<(), MapOperationError> {
+    enforcement: Enforcement,
+    syslog: bool,
+    spec: ContainerSpec,
+    custom_mounts: Option<CustomMounts>,
+    mode: AddMode,
+) -> Result<AddOutcome, MapOperationError> {
     debug!(
         container = container_id.as_str(),
         pid = pid,
         // policy_level = policy_level,
+        enforcement = enforcement.to_string().as_str(),
+        syslog,
+        rootless = spec.rootless,
         map = "CONTAINERS",
         "adding container to eBPF map",
     );
 
-    let mut containers: HashMap<_, ContainerID, Container> =
-        bpf.map_mut("CONTAINERS")?.try_into()?;
     let container_key = ContainerID::new(&container_id)?;
-    let container = Container { policy_level };
-    containers.insert(container_key, container, 0)?;
+    let mut container = Container::new(policy_level, enforcement);
+    container.syslog = syslog;
+    let registered = match maps.containers.get(&container_key, 0) {
+        Ok(registered) => Some(registered),
+        Err(MapError::KeyNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let outcome = add_outcome(&container_id, registered, container, mode)?;
+    if outcome != AddOutcome::Unchanged {
+        maps.containers.insert(container_key, container, 0)?;
+    }
+
+    maps.container_specs.insert(container_key, spec, 0)?;
+    match custom_mounts {
+        Some(custom_mounts) => maps.custom_mounts.insert(container_key, custom_mounts, 0)?,
+        // Stale entries of a replaced container can't be left behind.
+        None => match maps.custom_mounts.remove(&container_key) {
+            Ok(()) | Err(MapError::KeyNotFound) => {}
+            Err(MapError::SyscallError { io_error, .. })
+                if io_error.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e.into()),
+        },
+    }
 
-    let mut processes: HashMap<_, i32, Process> = bpf.map_mut("PROCESSES")?.try_into()?;
     let process = Process {
         container_id: container_key,
     };
-    processes.insert(pid, process, 0)?;
+    maps.processes.insert(pid, process, 0)?;
+
+    Ok(outcome)
+}
+
+/// Switches the registered container between enforcing its policy and the
+/// complain mode. The policy level and the syslog exemption are kept.
+pub fn set_enforcement(
+    maps: &mut LockcMaps,
+    container_id: String,
+    enforcement: Enforcement,
+) -> Result<(), MapOperationError> {
+    debug!(
+        container = container_id.as_str(),
+        enforcement = enforcement.to_string().as_str(),
+        map = "CONTAINERS",
+        "changing enforcement of container in eBPF map"
+    );
+
+    let container_key = ContainerID::new(&container_id)?;
+    let mut container = match maps.containers.get(&container_key, 0) {
+        Ok(container) => container,
+        Err(MapError::KeyNotFound) => {
+            return Err(MapOperationError::ContainerNotFound(container_id))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    container.enforcement = enforcement;
+    maps.containers.insert(container_key, container, 0)?;
 
     Ok(())
 }
 
-pub fn delete_container(bpf: &mut Bpf, container_id: String) -> Result<(), MapOperationError> {
+pub fn delete_container(
+    maps: &mut LockcMaps,
+    container_id: String,
+) -> Result<(), MapOperationError> {
     debug!(
         container = container_id.as_str(),
         map = "CONTAINERS",
         "deleting container from eBPF map"
     );
 
-    let mut containers: HashMap<_, ContainerID, Container> =
-        bpf.map_mut("CONTAINERS")?.try_into()?;
     let container_key = ContainerID::new(&container_id)?;
 
     // An error while removing a container entry is expected when lockc was
     // installed after some containers were running (which is always the case
     // on Kubernetes). Instead of returning an error, let's warn users.
-    if let Err(e) = containers.remove(&container_key) {
+    if let Err(e) = maps.containers.remove(&container_key) {
         if let MapError::SyscallError { .. } = e {
             warn!(
                 container = container_id.as_str(),
@@ -73,12 +607,31 @@ pub fn delete_container(bpf: &mut Bpf, container_id: String) -> Result<(), MapOp
         }
     }
 
+    if let Err(e) = maps.container_specs.remove(&container_key) {
+        if let MapError::SyscallError { .. } = e {
+            warn!(
+                container = container_id.as_str(),
+                error = e.to_string().as_str(),
+                "could not remove the eBPF map container spec entry"
+            );
+        }
+    }
+
+    if let Err(e) = maps.custom_mounts.remove(&container_key) {
+        if let MapError::SyscallError { .. } = e {
+            warn!(
+                container = container_id.as_str(),
+                error = e.to_string().as_str(),
+                "could not remove the eBPF map custom mounts entry"
+            );
+        }
+    }
+
     // TODO(vadorovsky): Add iter_mut() to HashMap in aya. Due to lack of it,
     // we cannot remove elements immediately when iterating, because iter()
     // borrows the HashMap immutably.
-    let mut processes: HashMap<_, i32, Process> = bpf.map_mut("PROCESSES")?.try_into()?;
     let mut to_remove = Vec::new();
-    for res in processes.iter() {
+    for res in maps.processes.iter() {
         let (pid, process) = res?;
         if process.container_id.id == container_key.id {
             to_remove.push(pid);
@@ -86,13 +639,17 @@ pub fn delete_container(bpf: &mut Bpf, container_id: String) -> Result<(), MapOp
         }
     }
     for pid in to_remove {
-        processes.remove(&pid)?;
+        maps.processes.remove(&pid)?;
     }
 
     Ok(())
 }
 
-pub fn add_process(bpf: &mut Bpf, container_id: String, pid: i32) -> Result<(), MapOperationError> {
+pub fn add_process(
+    maps: &mut LockcMaps,
+    container_id: String,
+    pid: i32,
+) -> Result<(), MapOperationError> {
     debug!(
         pid = pid,
         container = container_id.as_str(),
@@ -100,21 +657,168 @@ pub fn add_process(bpf: &mut Bpf, container_id: String, pid: i32) -> Result<(),
         "adding process to eBPF map",
     );
 
-    let mut processes: HashMap<_, i32, Process> = bpf.map_mut("PROCESSES")?.try_into()?;
     let container_key = ContainerID::new(&container_id)?;
     let process = Process {
         container_id: container_key,
     };
-    processes.insert(pid, process, 0)?;
+    maps.processes.insert(pid, process, 0)?;
 
     Ok(())
 }
 
+/// Maps the cgroup ID to the container in the `CONTAINER_CGROUPS` eBPF map.
+pub fn add_container_cgroup(
+    maps: &mut LockcMaps,
+    container_id: String,
+    cgroup_id: u64,
+) -> Result<(), MapOperationError> {
+    debug!(
+        container = container_id.as_str(),
+        cgroup_id = cgroup_id,
+        map = "CONTAINER_CGROUPS",
+        "adding cgroup to eBPF map",
+    );
+
+    maps.container_cgroups
+        .insert(cgroup_id, ContainerID::new(&container_id)?, 0)?;
+
+    Ok(())
+}
+
+/// Removes the cgroup ID of a deleted container from the
+/// `CONTAINER_CGROUPS` eBPF map.
+pub fn delete_container_cgroup(
+    maps: &mut LockcMaps,
+    cgroup_id: u64,
+) -> Result<(), MapOperationError> {
+    debug!(
+        cgroup_id = cgroup_id,
+        map = "CONTAINER_CGROUPS",
+        "deleting cgroup from eBPF map"
+    );
+
+    match maps.container_cgroups.remove(&cgroup_id) {
+        Ok(()) | Err(MapError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Returns the container which the given process belongs to, if any.
+pub fn get_process_container(
+    maps: &LockcMaps,
+    pid: i32,
+) -> Result<Option<ProcessContainer>, MapOperationError> {
+    let process = match maps.processes.get(&pid, 0) {
+        Ok(process) => process,
+        Err(MapError::KeyNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let container = match maps.containers.get(&process.container_id, 0) {
+        Ok(container) => container,
+        Err(MapError::KeyNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(ProcessContainer {
+        container_id: process
+            .container_id
+            .as_str()?
+            .trim_end_matches('\0')
+            .to_string(),
+        policy_level: container.policy_level,
+    }))
+}
+
+/// Returns the number of failed map operations in eBPF programs, summed
+/// over all CPUs.
+pub fn get_map_errors(maps: &LockcMaps) -> Result<Vec<(MapOperation, u64)>, MapOperationError> {
+    MapOperation::ALL
+        .iter()
+        .map(|operation| {
+            let values = maps.map_errors.get(&(*operation as u32), 0)?;
+            Ok((*operation, values.iter().sum()))
+        })
+        .collect()
+}
+
+/// Returns counters of LSM programs, summed over all CPUs.
+pub fn get_program_stats(
+    maps: &LockcMaps,
+) -> Result<Vec<(Program, ProgramStats)>, MapOperationError> {
+    Program::ALL
+        .iter()
+        .map(|program| {
+            let values = maps.program_stats.get(&(*program as u32), 0)?;
+            let stats = values
+                .iter()
+                .fold(ProgramStats::default(), |sum, stats| sum + *stats);
+            Ok((*program, stats))
+        })
+        .collect()
+}
+
+/// Returns bind mount attempts recorded in learning mode and removes them
+/// from the `LEARNED_MOUNTS` eBPF map, so it doesn't fill up. Attempts made
+/// between reading and removing an entry are lost.
+pub fn take_learned_mounts(maps: &mut LockcMaps) -> Result<Vec<MountAttempts>, MapOperationError> {
+    let learned = &mut maps.learned_mounts;
+    let entries = learned.iter().collect::<Result<Vec<_>, _>>()?;
+    let mut attempts = Vec::with_capacity(entries.len());
+    for (key, count) in entries {
+        learned.remove(&key)?;
+        let policy_level = match key.class() {
+            Some(PathClass::MountRestricted) => ContainerPolicyLevel::Restricted,
+            Some(PathClass::MountBaseline) => ContainerPolicyLevel::Baseline,
+            _ => continue,
+        };
+        attempts.push(MountAttempts {
+            container_id: key
+                .container_id
+                .as_str()?
+                .trim_end_matches('\0')
+                .to_string(),
+            policy_level,
+            path: key.path(),
+            count,
+        });
+    }
+    Ok(attempts)
+}
+
+/// Returns uses of capabilities recorded for workload fingerprints and
+/// removes them from the `LEARNED_CAPABILITIES` eBPF map, like
+/// [`take_learned_mounts`].
+pub fn take_learned_capabilities(
+    maps: &mut LockcMaps,
+) -> Result<Vec<CapabilityUses>, MapOperationError> {
+    let learned = &mut maps.learned_capabilities;
+    let entries = learned.iter().collect::<Result<Vec<_>, _>>()?;
+    let mut uses = Vec::with_capacity(entries.len());
+    for (key, count) in entries {
+        learned.remove(&key)?;
+        uses.push(CapabilityUses {
+            container_id: key
+                .container_id
+                .as_str()?
+                .trim_end_matches('\0')
+                .to_string(),
+            capability: key.capability,
+            count,
+        });
+    }
+    Ok(uses)
+}
+
 #[cfg(test)]
 mod tests {
     use tempfile::{Builder, TempDir};
 
-    use crate::load::load_bpf;
+    use crate::{
+        load::{load_bpf, BpfObject},
+        settings::UserNamespaces,
+        sysutils::test_bpffs,
+    };
 
     use super::*;
 
@@ -122,7 +826,7 @@ mod tests {
         Builder::new()
             .prefix("lockc-temp")
             .rand_bytes(5)
-            .tempdir_in("/sys/fs/bpf")
+            .tempdir_in(test_bpffs())
             .expect("Creating temporary dir in BPFFS failed")
     }
 
@@ -130,13 +834,179 @@ mod tests {
     #[cfg_attr(not(feature = "tests_bpf"), ignore)]
     fn test_add_container() {
         let path_base = tmp_path_base();
-        let mut bpf = load_bpf(path_base).expect("Loading BPF failed");
+        let bpf = load_bpf(
+            path_base,
+            &BpfObject::embedded(),
+            None,
+            false,
+            false,
+            false,
+            false,
+            &UserNamespaces::default(),
+        )
+        .expect("Loading BPF failed");
+        let mut maps = LockcMaps::new(&bpf).expect("Resolving maps failed");
         add_container(
-            &mut bpf,
+            &mut maps,
             "5833851e673d45fab4d12105bf61c3f4892b2bbf9c12d811db509a4f22475ec9".to_string(),
             42069,
             ContainerPolicyLevel::Baseline,
+            Enforcement::Enforce,
+            false,
+            ContainerSpec::default(),
+            Some(CustomMounts::new(&["/var/lib/gpu"]).unwrap()),
+            AddMode::Create,
         )
         .expect("Adding container failed");
     }
+
+    #[test]
+    fn add_outcome_conflict() {
+        let baseline = Container::new(ContainerPolicyLevel::Baseline, Enforcement::Enforce);
+        let privileged = Container::new(ContainerPolicyLevel::Privileged, Enforcement::Enforce);
+        let complain = Container::new(ContainerPolicyLevel::Baseline, Enforcement::Complain);
+
+        assert_eq!(
+            add_outcome("abc", None, baseline, AddMode::Create).unwrap(),
+            AddOutcome::Created
+        );
+        // Retried registrations with the same policy are fine.
+        assert_eq!(
+            add_outcome("abc", Some(baseline), baseline, AddMode::Create).unwrap(),
+            AddOutcome::Unchanged
+        );
+        assert!(matches!(
+            add_outcome("abc", Some(baseline), privileged, AddMode::Create),
+            Err(MapOperationError::PolicyConflict {
+                registered: ContainerPolicyLevel::Baseline,
+                requested: ContainerPolicyLevel::Privileged,
+                ..
+            })
+        ));
+        assert!(matches!(
+            add_outcome("abc", Some(baseline), complain, AddMode::Create),
+            Err(MapOperationError::PolicyConflict { .. })
+        ));
+        let mut syslog = baseline;
+        syslog.syslog = true;
+        assert!(matches!(
+            add_outcome("abc", Some(baseline), syslog, AddMode::Create),
+            Err(MapOperationError::PolicyConflict { .. })
+        ));
+        assert_eq!(
+            add_outcome("abc", Some(baseline), privileged, AddMode::Upsert).unwrap(),
+            AddOutcome::Replaced {
+                policy_level: ContainerPolicyLevel::Baseline,
+                enforcement: Enforcement::Enforce,
+            }
+        );
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn test_init_proc_masking() {
+        let path_base = tmp_path_base();
+        let mut bpf = load_bpf(
+            path_base,
+            &BpfObject::embedded(),
+            None,
+            false,
+            false,
+            false,
+            false,
+            &UserNamespaces::default(),
+        )
+        .expect("Loading BPF failed");
+        let proc_masking = ProcMasking {
+            policy_levels: vec![ContainerPolicyLevel::Restricted],
+            paths: vec![
+                "/proc/kcore".to_string(),
+                "/proc/lockc".to_string(),
+                "/etc".to_string(),
+            ],
+        };
+        init_proc_masking(&mut bpf, &proc_masking).expect("Initializing masked paths failed");
+
+        let masked: HashMap<_, u64, u8> =
+            bpf.map("MASKED_PROC_INODES").unwrap().try_into().unwrap();
+        let kcore = InodeId::from_metadata(&fs::metadata("/proc/kcore").unwrap());
+        assert_eq!(
+            masked.get(&kcore.i_ino, 0).unwrap(),
+            ContainerPolicyLevel::Restricted.bit()
+        );
+        assert_eq!(masked.keys().count(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn test_init_excluded() {
+        let path_base = tmp_path_base();
+        let mut bpf = load_bpf(
+            path_base,
+            &BpfObject::embedded(),
+            None,
+            false,
+            false,
+            false,
+            false,
+            &UserNamespaces::default(),
+        )
+        .expect("Loading BPF failed");
+        let dir = tempfile::tempdir().unwrap();
+        let agent = dir.path().join("backup-agent");
+        fs::write(&agent, "").unwrap();
+        let excluded = ExcludedProcesses {
+            comms: vec!["velero".to_string()],
+            paths: vec![
+                agent.to_string_lossy().into_owned(),
+                dir.path().join("missing").to_string_lossy().into_owned(),
+            ],
+        };
+        init_excluded(&mut bpf, &excluded).expect("Initializing excluded processes failed");
+
+        let comms: HashMap<_, u32, bool> = bpf.map("EXCLUDED_COMMS").unwrap().try_into().unwrap();
+        assert!(comms.get(&comm_hash(b"velero"), 0).unwrap());
+        let executables: HashMap<_, InodeId, bool> =
+            bpf.map("EXCLUDED_EXECUTABLES").unwrap().try_into().unwrap();
+        let inode = InodeId::from_metadata(&fs::metadata(&agent).unwrap());
+        assert!(executables.get(&inode, 0).unwrap());
+        assert_eq!(executables.keys().count(), 1);
+
+        let too_many = ExcludedProcesses {
+            comms: vec!["velero".to_string(); EXCLUDED_MAX as usize + 1],
+            paths: Vec::new(),
+        };
+        assert!(matches!(
+            init_excluded(&mut bpf, &too_many),
+            Err(MapOperationError::TooManyExcluded)
+        ));
+    }
+
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn test_init_protected() {
+        let path_base = tmp_path_base();
+        let mut bpf = load_bpf(
+            &path_base,
+            &BpfObject::embedded(),
+            None,
+            false,
+            false,
+            false,
+            false,
+            &UserNamespaces::default(),
+        )
+        .expect("Loading BPF failed");
+        init_protected(&mut bpf, path_base.path()).expect("Initializing protected inodes failed");
+
+        let protected: HashMap<_, InodeId, bool> =
+            bpf.map("PROTECTED_INODES").unwrap().try_into().unwrap();
+        for path in [
+            path_base.path().to_path_buf(),
+            path_base.path().join("CONTAINERS"),
+        ] {
+            let inode = InodeId::from_metadata(&fs::metadata(&path).unwrap());
+            assert!(protected.get(&inode, 0).unwrap());
+        }
+    }
 }
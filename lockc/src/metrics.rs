@@ -0,0 +1,406 @@
+//! Prometheus metrics endpoint. Only the text exposition format over plain
+//! HTTP is supported, which is all Prometheus needs for scraping. Counters of
+//! eBPF programs are read by the eBPF thread on an interval, so scrapes never
+//! queue commands in front of registrations of containers.
+
+use std::{
+    fmt::Write as _,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use lockc_common::{http, MapOperation, Program, ProgramStats};
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tracing::{error, warn};
+
+use crate::communication::EbpfRequest;
+
+/// Interval of reading counters of eBPF programs.
+pub const COUNTERS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time to handle a connection, including reading the request.
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bounds, in seconds, of buckets of the container registration
+/// latency histogram.
+pub const REGISTRATION_LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Prometheus histogram which can be updated from other threads without
+/// locking.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Number of observations in each bucket (not cumulative), the last one
+    /// is the `+Inf` bucket.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Formats the histogram in the Prometheus text format.
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = write!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} histogram\n",
+            name = name,
+            help = help
+        );
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, count);
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = write!(
+            out,
+            "{name}_sum {sum}\n{name}_count {count}\n",
+            name = name,
+            sum = sum,
+            count = count
+        );
+    }
+}
+
+/// Counters of eBPF programs, as last read by the eBPF thread.
+#[derive(Clone, Debug, Default)]
+pub struct EbpfCounters {
+    pub map_errors: Vec<(MapOperation, u64)>,
+    pub program_stats: Vec<(Program, ProgramStats)>,
+}
+
+/// Metrics collected for a single scrape.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub map_errors: Vec<(MapOperation, u64)>,
+    pub program_stats: Vec<(Program, ProgramStats)>,
+    /// Number of eBPF commands waiting in the channel.
+    pub channel_depth: usize,
+    pub channel_capacity: usize,
+    /// Time from receiving the fanotify event about runc creating a
+    /// container to registering it, if runc is watched.
+    pub registration_latency: Option<Arc<Histogram>>,
+    /// Number of eBPF commands of the runc watcher which timed out, if runc
+    /// is watched.
+    pub command_timeouts: Option<u64>,
+}
+
+impl Metrics {
+    /// Collects metrics, with counters of eBPF programs as last read by the
+    /// eBPF thread.
+    fn collect(
+        ebpf_tx: &mpsc::WeakSender<EbpfRequest>,
+        counters: &Mutex<EbpfCounters>,
+        registration_latency: Option<Arc<Histogram>>,
+        command_timeouts: Option<&AtomicU64>,
+    ) -> Result<Self, String> {
+        let ebpf_tx = ebpf_tx
+            .upgrade()
+            .ok_or_else(|| "lockc is shutting down".to_string())?;
+        let channel_capacity = ebpf_tx.max_capacity();
+        let channel_depth = channel_capacity - ebpf_tx.capacity();
+        let EbpfCounters {
+            map_errors,
+            program_stats,
+        } = counters
+            .lock()
+            .map_err(|_| "eBPF counters are poisoned".to_string())?
+            .clone();
+        Ok(Metrics {
+            map_errors,
+            program_stats,
+            channel_depth,
+            channel_capacity,
+            registration_latency,
+            command_timeouts: command_timeouts.map(|timeouts| timeouts.load(Ordering::Relaxed)),
+        })
+    }
+
+    /// Formats one of the counters of LSM programs, labeled by the program.
+    fn render_program_counter<F>(&self, out: &mut String, name: &str, help: &str, counter: F)
+    where
+        F: Fn(&ProgramStats) -> u64,
+    {
+        let _ = write!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} counter\n",
+            name = name,
+            help = help
+        );
+        for (program, stats) in &self.program_stats {
+            let _ = writeln!(
+                out,
+                "{}{{program=\"{}\"}} {}",
+                name,
+                program.name(),
+                counter(stats)
+            );
+        }
+    }
+
+    /// Formats metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str(
+            "# HELP lockc_map_errors_total Failed eBPF map operations in eBPF programs.\n\
+             # TYPE lockc_map_errors_total counter\n",
+        );
+        for (operation, count) in &self.map_errors {
+            let _ = writeln!(
+                out,
+                "lockc_map_errors_total{{operation=\"{}\"}} {}",
+                operation.name(),
+                count
+            );
+        }
+        self.render_program_counter(
+            &mut out,
+            "lockc_program_invocations_total",
+            "Invocations of LSM programs.",
+            |stats| stats.invocations,
+        );
+        self.render_program_counter(
+            &mut out,
+            "lockc_program_allows_total",
+            "Operations allowed by LSM programs, including violations in complain mode.",
+            |stats| stats.allows,
+        );
+        self.render_program_counter(
+            &mut out,
+            "lockc_program_denies_total",
+            "Operations denied by LSM programs.",
+            |stats| stats.denies,
+        );
+        let _ = write!(
+            out,
+            "# HELP lockc_ebpf_channel_depth eBPF commands waiting to be handled.\n\
+             # TYPE lockc_ebpf_channel_depth gauge\n\
+             lockc_ebpf_channel_depth {}\n\
+             # HELP lockc_ebpf_channel_capacity Capacity of the eBPF command channel.\n\
+             # TYPE lockc_ebpf_channel_capacity gauge\n\
+             lockc_ebpf_channel_capacity {}\n",
+            self.channel_depth, self.channel_capacity
+        );
+        if let Some(command_timeouts) = self.command_timeouts {
+            let _ = write!(
+                out,
+                "# HELP lockc_ebpf_command_timeouts_total eBPF commands of the runc watcher without a result within the response timeout.\n\
+                 # TYPE lockc_ebpf_command_timeouts_total counter\n\
+                 lockc_ebpf_command_timeouts_total {}\n",
+                command_timeouts
+            );
+        }
+        if let Some(registration_latency) = &self.registration_latency {
+            registration_latency.render(
+                &mut out,
+                "lockc_container_registration_seconds",
+                "Time from runc execution to registration of the created container.",
+            );
+        }
+        out
+    }
+}
+
+/// Handles a connection of an HTTP client within `CONNECTION_TIMEOUT`.
+pub async fn with_timeout<F>(connection: F) -> io::Result<()>
+where
+    F: std::future::Future<Output = io::Result<()>>,
+{
+    time::timeout(CONNECTION_TIMEOUT, connection)
+        .await
+        .map_err(http::timed_out)?
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    ebpf_tx: &mpsc::WeakSender<EbpfRequest>,
+    counters: &Mutex<EbpfCounters>,
+    registration_latency: Option<Arc<Histogram>>,
+    command_timeouts: Option<&AtomicU64>,
+) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = http::read_request(&mut stream).await?;
+    let (status, body) = if !request.is("GET", "/metrics") {
+        ("404 Not Found", String::new())
+    } else {
+        match Metrics::collect(ebpf_tx, counters, registration_latency, command_timeouts) {
+            Ok(metrics) => ("200 OK", metrics.render()),
+            Err(e) => {
+                warn!(error = e.as_str(), "could not collect metrics");
+                ("500 Internal Server Error", e)
+            }
+        }
+    };
+    http::write_response(&mut stream, status, "text/plain; version=0.0.4", &body).await
+}
+
+/// Serves metrics on the given listener.
+pub async fn serve(
+    listener: TcpListener,
+    ebpf_tx: mpsc::WeakSender<EbpfRequest>,
+    counters: Arc<Mutex<EbpfCounters>>,
+    registration_latency: Option<Arc<Histogram>>,
+    command_timeouts: Option<Arc<AtomicU64>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let ebpf_tx = ebpf_tx.clone();
+                let counters = counters.clone();
+                let registration_latency = registration_latency.clone();
+                let command_timeouts = command_timeouts.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = with_timeout(handle_connection(
+                        stream,
+                        &ebpf_tx,
+                        &counters,
+                        registration_latency,
+                        command_timeouts.as_deref(),
+                    ))
+                    .await
+                    {
+                        warn!(error = e.to_string().as_str(), "metrics connection failed");
+                    }
+                });
+            }
+            Err(e) => error!(
+                error = e.to_string().as_str(),
+                "could not accept a metrics connection"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[test]
+    fn metrics_render() {
+        let metrics = Metrics {
+            map_errors: vec![
+                (MapOperation::ProcessInsert, 3),
+                (MapOperation::InitialSetuidInsert, 0),
+            ],
+            program_stats: vec![(
+                Program::FileOpen,
+                ProgramStats {
+                    invocations: 10,
+                    allows: 8,
+                    denies: 2,
+                },
+            )],
+            channel_depth: 2,
+            channel_capacity: 100,
+            registration_latency: None,
+            command_timeouts: Some(1),
+        };
+        let out = metrics.render();
+        assert!(out.contains("# TYPE lockc_map_errors_total counter\n"));
+        assert!(out.contains("lockc_map_errors_total{operation=\"process_insert\"} 3\n"));
+        assert!(out.contains("lockc_map_errors_total{operation=\"initial_setuid_insert\"} 0\n"));
+        assert!(out.contains("# TYPE lockc_program_invocations_total counter\n"));
+        assert!(out.contains("lockc_program_invocations_total{program=\"file_open\"} 10\n"));
+        assert!(out.contains("lockc_program_allows_total{program=\"file_open\"} 8\n"));
+        assert!(out.contains("lockc_program_denies_total{program=\"file_open\"} 2\n"));
+        assert!(out.contains("lockc_ebpf_channel_depth 2\n"));
+        assert!(out.contains("lockc_ebpf_channel_capacity 100\n"));
+        assert!(out.contains("lockc_ebpf_command_timeouts_total 1\n"));
+        assert!(!out.contains("lockc_container_registration_seconds"));
+    }
+
+    #[test]
+    fn histogram_render() {
+        let histogram = Histogram::new(&[0.01, 0.1]);
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(60));
+        histogram.observe(Duration::from_secs(2));
+        let mut out = String::new();
+        histogram.render(&mut out, "test_seconds", "Test.");
+        assert_eq!(
+            out,
+            "# HELP test_seconds Test.\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"0.01\"} 1\n\
+             test_seconds_bucket{le=\"0.1\"} 3\n\
+             test_seconds_bucket{le=\"+Inf\"} 4\n\
+             test_seconds_sum 2.115\n\
+             test_seconds_count 4\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_serve() {
+        let (ebpf_tx, _ebpf_rx) = mpsc::channel::<EbpfRequest>(1);
+        let counters = Arc::new(Mutex::new(EbpfCounters {
+            map_errors: vec![(MapOperation::ProcessInsert, 1)],
+            program_stats: vec![(Program::Syslog, ProgramStats::default())],
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registration_latency = Arc::new(Histogram::new(REGISTRATION_LATENCY_BUCKETS));
+        registration_latency.observe(Duration::from_millis(20));
+        tokio::spawn(serve(
+            listener,
+            ebpf_tx.downgrade(),
+            counters,
+            Some(registration_latency),
+            Some(Arc::default()),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response)
+            .await
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("lockc_map_errors_total{operation=\"process_insert\"} 1\n"));
+        assert!(response.contains("lockc_program_denies_total{program=\"syslog\"} 0\n"));
+        assert!(response.contains("lockc_ebpf_channel_capacity 1\n"));
+        assert!(response.contains("lockc_ebpf_command_timeouts_total 0\n"));
+        assert!(response.contains("lockc_container_registration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(response.ends_with("lockc_container_registration_seconds_count 1\n"));
+        drop(ebpf_tx);
+    }
+}
@@ -0,0 +1,232 @@
+//! Static mapping of Kubernetes namespaces to policy levels, for clusters
+//! without Pod Security labels on namespaces (e.g. air-gapped clusters or
+//! clusters without Pod Security Admission).
+//!
+//! Namespaces are mapped by the `LOCKC_NAMESPACE_POLICIES` environment
+//! variable (`<namespace>=<policy>` pairs separated by commas) and by a file
+//! in one of the settings formats, with namespaces as keys and policies as
+//! values. The environment variable takes precedence over the file, both
+//! take precedence over labels of namespaces. The file is reloaded when it
+//! changes, invalid contents keep the previous mapping.
+
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use lockc_common::ContainerPolicyLevel;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use crate::settings::{read_file, SettingsError};
+
+/// Environment variable with the mapping of namespaces to policies.
+pub const NAMESPACE_POLICIES_ENV: &str = "LOCKC_NAMESPACE_POLICIES";
+
+#[derive(Error, Debug)]
+pub enum NamespacePoliciesError {
+    #[error("invalid namespace policy {0:?}, expected <namespace>=<policy>")]
+    Pair(String),
+
+    #[error("invalid policy of namespace {namespace}: {source}")]
+    Policy {
+        namespace: String,
+        #[source]
+        source: lockc_common::ParsePolicyLevelError,
+    },
+
+    #[error("policy level {1} cannot be assigned to namespace {0}")]
+    InvalidPolicyLevel(String, ContainerPolicyLevel),
+
+    #[error(transparent)]
+    Settings(#[from] SettingsError),
+}
+
+type Mapping = HashMap<String, ContainerPolicyLevel>;
+
+/// Parses the mapping from the value of [`NAMESPACE_POLICIES_ENV`], e.g.
+/// `default=restricted,monitoring=privileged`.
+pub fn parse_pairs(value: &str) -> Result<Mapping, NamespacePoliciesError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (namespace, policy) = pair
+                .split_once('=')
+                .ok_or_else(|| NamespacePoliciesError::Pair(pair.to_string()))?;
+            let namespace = namespace.trim().to_string();
+            let policy =
+                policy
+                    .trim()
+                    .parse()
+                    .map_err(|source| NamespacePoliciesError::Policy {
+                        namespace: namespace.clone(),
+                        source,
+                    })?;
+            Ok((namespace, policy))
+        })
+        .collect()
+}
+
+/// Reads the mapping from the file. A missing file results in an empty
+/// mapping.
+fn read_mapping(path: &Path) -> Result<Mapping, NamespacePoliciesError> {
+    if !path.exists() {
+        return Ok(Mapping::new());
+    }
+    let mapping: Mapping = read_file(path)?;
+    // Internal levels are accepted by the deserializer.
+    if let Some((namespace, policy_level)) = mapping.iter().find(|(_, policy_level)| {
+        matches!(
+            policy_level,
+            ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc
+        )
+    }) {
+        return Err(NamespacePoliciesError::InvalidPolicyLevel(
+            namespace.clone(),
+            *policy_level,
+        ));
+    }
+    Ok(mapping)
+}
+
+/// Returns the modification time of the file, `None` if it doesn't exist.
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Mapping from the file, with the modification time it was read at.
+struct FileMapping {
+    mapping: Mapping,
+    modified: Option<SystemTime>,
+}
+
+/// Policies of namespaces from the environment variable and the file.
+pub struct NamespacePolicies {
+    env: Mapping,
+    path: PathBuf,
+    file: Mutex<FileMapping>,
+}
+
+impl NamespacePolicies {
+    /// Reads the mapping from the environment variable and the file.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, NamespacePoliciesError> {
+        let env = match env::var(NAMESPACE_POLICIES_ENV) {
+            Ok(value) => parse_pairs(&value)?,
+            Err(_) => Mapping::new(),
+        };
+        Self::with_env(env, path)
+    }
+
+    fn with_env<P: AsRef<Path>>(env: Mapping, path: P) -> Result<Self, NamespacePoliciesError> {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let mapping = read_mapping(&path)?;
+        Ok(NamespacePolicies {
+            env,
+            path,
+            file: Mutex::new(FileMapping { mapping, modified }),
+        })
+    }
+
+    /// Returns the policy of the namespace, if it's mapped. The file is
+    /// reloaded first if it changed.
+    pub fn policy(&self, namespace: &str) -> Option<ContainerPolicyLevel> {
+        if let Some(policy_level) = self.env.get(namespace) {
+            return Some(*policy_level);
+        }
+        let mut file = self.file.lock().ok()?;
+        let modified = modified(&self.path);
+        if modified != file.modified {
+            match read_mapping(&self.path) {
+                Ok(mapping) => {
+                    info!(
+                        path = self.path.to_string_lossy().as_ref(),
+                        namespaces = mapping.len(),
+                        "reloaded namespace policies"
+                    );
+                    file.mapping = mapping;
+                }
+                Err(e) => warn!(
+                    path = self.path.to_string_lossy().as_ref(),
+                    error = e.to_string().as_str(),
+                    "could not reload namespace policies, keeping the previous ones"
+                ),
+            }
+            file.modified = modified;
+        }
+        file.mapping.get(namespace).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn namespace_policies_pairs() {
+        let mapping = parse_pairs("default=restricted, monitoring = privileged,").unwrap();
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(mapping["default"], ContainerPolicyLevel::Restricted);
+        assert_eq!(mapping["monitoring"], ContainerPolicyLevel::Privileged);
+
+        assert!(parse_pairs("").unwrap().is_empty());
+        assert!(matches!(
+            parse_pairs("default"),
+            Err(NamespacePoliciesError::Pair(_))
+        ));
+        assert!(matches!(
+            parse_pairs("default=lockc"),
+            Err(NamespacePoliciesError::Policy { .. })
+        ));
+    }
+
+    #[test]
+    fn namespace_policies_precedence_and_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("namespace_policies.yaml");
+
+        let env = parse_pairs("default=privileged").unwrap();
+        let policies = NamespacePolicies::with_env(env, &path).unwrap();
+        assert_eq!(policies.policy("web"), None);
+
+        fs::write(&path, "default: restricted\nweb: baseline\n").unwrap();
+        assert_eq!(
+            policies.policy("default"),
+            Some(ContainerPolicyLevel::Privileged)
+        );
+        assert_eq!(policies.policy("web"), Some(ContainerPolicyLevel::Baseline));
+
+        // Invalid contents keep the previous mapping.
+        fs::write(&path, "web: lockc\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(policies.policy("web"), Some(ContainerPolicyLevel::Baseline));
+
+        fs::write(&path, "web: offline\n").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(2))
+            .unwrap();
+        assert_eq!(policies.policy("web"), Some(ContainerPolicyLevel::Offline));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(policies.policy("web"), None);
+    }
+
+    #[test]
+    fn namespace_policies_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("namespace_policies.yaml");
+        fs::write(&path, "default: lockc\n").unwrap();
+        assert!(matches!(
+            NamespacePolicies::with_env(Mapping::new(), &path),
+            Err(NamespacePoliciesError::InvalidPolicyLevel(..))
+        ));
+    }
+}
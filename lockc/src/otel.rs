@@ -0,0 +1,144 @@
+//! Export of container lifecycle and violation events as OpenTelemetry
+//! traces over OTLP. The exporter is configured with the standard `OTEL_*`
+//! environment variables (e.g. `OTEL_EXPORTER_OTLP_ENDPOINT`,
+//! `OTEL_RESOURCE_ATTRIBUTES`).
+
+use std::{env, time::Duration};
+
+use lockc_common::{control::ContainerInfo, ContainerPolicyLevel, Enforcement};
+use opentelemetry::{
+    global,
+    sdk::{
+        resource::{EnvResourceDetector, ResourceDetector},
+        trace as sdktrace, Resource,
+    },
+    trace::{Span, TraceError, Tracer},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+
+use crate::violations::ViolationEvent;
+
+/// Attributes of the container, named after OpenTelemetry semantic
+/// conventions.
+fn container_attributes(container_id: &str, info: Option<&ContainerInfo>) -> Vec<KeyValue> {
+    let mut attributes = vec![KeyValue::new("container.id", container_id.to_string())];
+    let info = match info {
+        Some(info) => info,
+        None => return attributes,
+    };
+    let optional = [
+        ("container.name", &info.name),
+        ("container.image.name", &info.image),
+        ("k8s.pod.name", &info.pod),
+        ("k8s.namespace.name", &info.namespace),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            attributes.push(KeyValue::new(key, value.clone()));
+        }
+    }
+    attributes.push(KeyValue::new(
+        "lockc.policy_level",
+        info.policy_level.to_string(),
+    ));
+    attributes
+}
+
+/// Sends events as spans to an OTLP endpoint.
+pub struct OtelExporter {
+    tracer: sdktrace::Tracer,
+}
+
+impl OtelExporter {
+    /// Installs the OTLP pipeline. Has to be called inside the Tokio runtime,
+    /// which exports spans in batches.
+    pub fn new() -> Result<Self, TraceError> {
+        let mut resource = vec![KeyValue::new("service.name", "lockc")];
+        if let Ok(node) = env::var("NODE_NAME") {
+            resource.push(KeyValue::new("k8s.node.name", node));
+        }
+        // Attributes from `OTEL_RESOURCE_ATTRIBUTES` take precedence.
+        let detectors: Vec<Box<dyn ResourceDetector>> = vec![Box::new(EnvResourceDetector::new())];
+        let resource = Resource::new(resource)
+            .merge(&Resource::from_detectors(Duration::from_secs(0), detectors));
+
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+            .with_trace_config(sdktrace::config().with_resource(resource))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+        Ok(OtelExporter { tracer })
+    }
+
+    fn export(&self, name: &'static str, attributes: Vec<KeyValue>) {
+        let mut span = self
+            .tracer
+            .span_builder(name)
+            .with_attributes(attributes)
+            .start(&self.tracer);
+        span.end();
+    }
+
+    pub fn container_registered(&self, container_id: &str, info: Option<&ContainerInfo>) {
+        self.export(
+            "container_registered",
+            container_attributes(container_id, info),
+        );
+    }
+
+    /// Exports a registration of the container which was refused, because
+    /// the container is registered with a different policy.
+    pub fn container_policy_conflict(
+        &self,
+        container_id: &str,
+        info: Option<&ContainerInfo>,
+        requested: ContainerPolicyLevel,
+        requested_enforcement: Enforcement,
+    ) {
+        let mut attributes = container_attributes(container_id, info);
+        attributes.push(KeyValue::new(
+            "lockc.requested_policy_level",
+            requested.to_string(),
+        ));
+        attributes.push(KeyValue::new(
+            "lockc.requested_enforcement",
+            requested_enforcement.to_string(),
+        ));
+        self.export("container_policy_conflict", attributes);
+    }
+
+    pub fn container_deleted(&self, container_id: &str, info: Option<&ContainerInfo>) {
+        self.export(
+            "container_deleted",
+            container_attributes(container_id, info),
+        );
+    }
+
+    pub fn violation(&self, event: &ViolationEvent) {
+        let mut attributes = container_attributes(&event.container_id, event.container.as_ref());
+        attributes.push(KeyValue::new("lockc.hook", event.hook.to_string()));
+        attributes.push(KeyValue::new(
+            "lockc.enforcement",
+            event.enforcement.to_string(),
+        ));
+        attributes.push(KeyValue::new("process.pid", event.pid as i64));
+        if let Some(detail) = &event.detail {
+            attributes.push(KeyValue::new("lockc.detail", detail.clone()));
+        }
+        if event.killed {
+            attributes.push(KeyValue::new("lockc.killed", true));
+        }
+        if event.suppressed > 0 {
+            attributes.push(KeyValue::new("lockc.suppressed", event.suppressed as i64));
+        }
+        self.export("violation", attributes);
+    }
+}
+
+impl Drop for OtelExporter {
+    /// Flushes spans which were not exported yet.
+    fn drop(&mut self) {
+        global::shutdown_tracer_provider();
+    }
+}
@@ -0,0 +1,94 @@
+//! Reading of events which eBPF programs send through perf event array maps.
+
+use std::mem;
+
+use aya::{
+    maps::{
+        perf::{AsyncPerfEventArray, AsyncPerfEventArrayBuffer, PerfBufferError},
+        MapError, MapRefMut,
+    },
+    util::online_cpus,
+    Bpf, Pod,
+};
+use bytes::BytesMut;
+use thiserror::Error;
+use tracing::warn;
+
+/// Number of events read from a per-CPU buffer at once.
+const READ_BATCH: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum PerfError {
+    #[error(transparent)]
+    Map(#[from] MapError),
+
+    #[error("could not get online CPUs: {0}")]
+    OnlineCpus(#[source] std::io::Error),
+
+    #[error(transparent)]
+    PerfBuffer(#[from] PerfBufferError),
+}
+
+/// Per-CPU buffers of a perf event array map.
+pub struct PerfBuffers {
+    map: &'static str,
+    buffers: Vec<AsyncPerfEventArrayBuffer<MapRefMut>>,
+}
+
+impl PerfBuffers {
+    /// Opens buffers of the given map for all online CPUs.
+    pub fn open(bpf: &mut Bpf, map: &'static str) -> Result<Self, PerfError> {
+        let mut perf_array: AsyncPerfEventArray<_> = bpf.map_mut(map)?.try_into()?;
+        let buffers = online_cpus()
+            .map_err(PerfError::OnlineCpus)?
+            .into_iter()
+            .map(|cpu_id| perf_array.open(cpu_id, None))
+            .collect::<Result<_, _>>()?;
+        Ok(PerfBuffers { map, buffers })
+    }
+
+    /// Spawns tasks reading events of type `T` from all buffers and passing
+    /// them to the handler. The tasks end when the handler returns `false`.
+    pub fn spawn<T, F>(self, handler: F)
+    where
+        T: Pod + Send,
+        F: Fn(T) -> bool + Clone + Send + 'static,
+    {
+        let map = self.map;
+        for mut buf in self.buffers {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut buffers: Vec<BytesMut> = (0..READ_BATCH)
+                    .map(|_| BytesMut::with_capacity(mem::size_of::<T>()))
+                    .collect();
+                loop {
+                    let events = match buf.read_events(&mut buffers).await {
+                        Ok(events) => events,
+                        Err(e) => {
+                            warn!(
+                                map,
+                                error = e.to_string().as_str(),
+                                "could not read eBPF events"
+                            );
+                            return;
+                        }
+                    };
+                    if events.lost > 0 {
+                        warn!(map, lost = events.lost, "eBPF events lost");
+                    }
+                    for data in buffers.iter().take(events.read) {
+                        if data.len() < mem::size_of::<T>() {
+                            continue;
+                        }
+                        // SAFETY: the buffer is large enough and `T` is
+                        // plain old data.
+                        let event = unsafe { (data.as_ptr() as *const T).read_unaligned() };
+                        if !handler(event) {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Translation of PIDs between PID namespaces. eBPF programs see PIDs from
+//! the initial PID namespace (host PIDs), while lockc running in a container
+//! and runc running in a nested container see PIDs of their own namespaces.
+//! Translation is based on the host procfs - the `NSpid` field of
+//! `/proc/<pid>/status` and `/proc/<pid>/ns/pid` links.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+use tracing::debug;
+
+/// Default path of the host procfs.
+pub const HOST_PROC_PATH: &str = "/proc";
+
+#[derive(Error, Debug)]
+pub enum PidNsError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error("could not parse NSpid of process {0}")]
+    NsPid(i32),
+
+    #[error("could not find the host PID of process {pid} in PID namespace {ns}")]
+    NotFound { pid: i32, ns: String },
+}
+
+/// PID namespace, identified by the target of a `/proc/<pid>/ns/pid` link
+/// (e.g. `pid:[4026531836]`), which is the same in all procfs mounts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PidNs {
+    id: String,
+    /// Level of the namespace, where 1 is the namespace of the host procfs.
+    level: usize,
+}
+
+fn pid_ns_id(proc_path: &Path, pid: &str) -> Result<String, io::Error> {
+    Ok(fs::read_link(proc_path.join(pid).join("ns").join("pid"))?
+        .to_string_lossy()
+        .to_string())
+}
+
+/// Returns PIDs of the process in all namespaces it's visible in, starting
+/// from the namespace of the procfs mount.
+fn ns_pids(proc_path: &Path, pid: &str) -> Result<Vec<i32>, PidNsError> {
+    let status = fs::read_to_string(proc_path.join(pid).join("status"))?;
+    let parse_err = || PidNsError::NsPid(pid.parse().unwrap_or(-1));
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))
+        .ok_or_else(parse_err)?;
+    line.split_whitespace()
+        .map(|pid| pid.parse().map_err(|_| parse_err()))
+        .collect()
+}
+
+fn pid_ns(proc_path: &Path, pid: &str) -> Result<PidNs, PidNsError> {
+    Ok(PidNs {
+        id: pid_ns_id(proc_path, pid)?,
+        level: ns_pids(proc_path, pid)?.len(),
+    })
+}
+
+/// Finds the host PID of the process with PID `pid` in the namespace `ns`.
+/// Processes living directly in `ns` are preferred over processes from its
+/// descendant namespaces.
+fn find_host_pid(proc_path: &Path, ns: &PidNs, pid: i32) -> Result<i32, PidNsError> {
+    let mut descendant = None;
+    for entry in fs::read_dir(proc_path)? {
+        let entry = entry?;
+        let host_pid = entry.file_name().to_string_lossy().to_string();
+        let host_pid_n: i32 = match host_pid.parse() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        // Processes might exit while we iterate.
+        let pids = match ns_pids(proc_path, &host_pid) {
+            Ok(pids) => pids,
+            Err(_) => continue,
+        };
+        if pids.get(ns.level - 1) != Some(&pid) {
+            continue;
+        }
+        match pid_ns_id(proc_path, &host_pid) {
+            Ok(id) if id == ns.id => return Ok(host_pid_n),
+            Ok(_) if pids.len() > ns.level => {
+                descendant.get_or_insert(host_pid_n);
+            }
+            _ => {}
+        }
+    }
+    descendant.ok_or_else(|| PidNsError::NotFound {
+        pid,
+        ns: ns.id.clone(),
+    })
+}
+
+/// Translates PIDs seen by lockc and by container runtimes to host PIDs.
+#[derive(Clone)]
+pub struct PidTranslator {
+    host_proc: PathBuf,
+    /// PID namespace of lockc, if it's different than the host one.
+    local_ns: Option<PidNs>,
+}
+
+impl PidTranslator {
+    /// Creates a translator using the host procfs mounted in `host_proc`.
+    /// When lockc runs in a container with its own PID namespace, the host
+    /// procfs has to be mounted in that container.
+    pub fn new<P: AsRef<Path>>(host_proc: P) -> Result<Self, PidNsError> {
+        let host_proc = host_proc.as_ref().to_path_buf();
+        let local_ns_id = pid_ns_id(Path::new("/proc"), "self")?;
+        let host_ns_id = pid_ns_id(&host_proc, "1")?;
+
+        let local_ns = if local_ns_id == host_ns_id {
+            None
+        } else {
+            // Any process from our namespace, visible in the host procfs,
+            // tells the level of the namespace. lockc itself is one of them.
+            let mut local_ns = None;
+            for entry in fs::read_dir(&host_proc)? {
+                let host_pid = entry?.file_name().to_string_lossy().to_string();
+                if host_pid.parse::<i32>().is_err() {
+                    continue;
+                }
+                if let Ok(ns) = pid_ns(&host_proc, &host_pid) {
+                    if ns.id == local_ns_id {
+                        local_ns = Some(ns);
+                        break;
+                    }
+                }
+            }
+            let local_ns = local_ns.ok_or_else(|| PidNsError::NotFound {
+                pid: std::process::id() as i32,
+                ns: local_ns_id,
+            })?;
+            debug!(
+                ns = local_ns.id.as_str(),
+                level = local_ns.level,
+                "lockc runs in a separate PID namespace"
+            );
+            Some(local_ns)
+        };
+
+        Ok(PidTranslator {
+            host_proc,
+            local_ns,
+        })
+    }
+
+    /// Translates a PID from the namespace of lockc (e.g. the one reported
+    /// by fanotify) to the host PID.
+    pub fn to_host(&self, pid: i32) -> Result<i32, PidNsError> {
+        match &self.local_ns {
+            Some(ns) => find_host_pid(&self.host_proc, ns, pid),
+            None => Ok(pid),
+        }
+    }
+
+    /// Returns the PID namespace of the process with the given host PID.
+    pub fn namespace(&self, host_pid: i32) -> Result<PidNs, PidNsError> {
+        pid_ns(&self.host_proc, &host_pid.to_string())
+    }
+
+    /// Translates a PID from the given namespace (e.g. the one written by
+    /// runc to a PID file) to the host PID.
+    pub fn to_host_in(&self, ns: &PidNs, pid: i32) -> Result<i32, PidNsError> {
+        if ns.level == 1 {
+            return Ok(pid);
+        }
+        find_host_pid(&self.host_proc, ns, pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn fake_process(proc_path: &Path, host_pid: i32, ns: &str, ns_pids: &[i32]) {
+        let dir = proc_path.join(host_pid.to_string());
+        fs::create_dir_all(dir.join("ns")).unwrap();
+        symlink(ns, dir.join("ns").join("pid")).unwrap();
+        let ns_pids: Vec<String> = ns_pids.iter().map(|pid| pid.to_string()).collect();
+        fs::write(
+            dir.join("status"),
+            format!(
+                "Name:\tsh\nPid:\t{}\nNSpid:\t{}\n",
+                host_pid,
+                ns_pids.join("\t")
+            ),
+        )
+        .unwrap();
+    }
+
+    fn fake_proc() -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fake_process(dir.path(), 1, "pid:[1]", &[1]);
+        // Two sibling containers with the same in-container PIDs.
+        fake_process(dir.path(), 100, "pid:[2]", &[100, 1]);
+        fake_process(dir.path(), 101, "pid:[2]", &[101, 7]);
+        fake_process(dir.path(), 200, "pid:[3]", &[200, 1]);
+        fake_process(dir.path(), 201, "pid:[3]", &[201, 7]);
+        // Container nested in the first one.
+        fake_process(dir.path(), 300, "pid:[4]", &[300, 9, 1]);
+        dir
+    }
+
+    #[test]
+    fn ns_pids_from_status() {
+        let proc_path = fake_proc();
+        assert_eq!(ns_pids(proc_path.path(), "300").unwrap(), vec![300, 9, 1]);
+        assert_eq!(
+            pid_ns(proc_path.path(), "101").unwrap(),
+            PidNs {
+                id: "pid:[2]".to_string(),
+                level: 2
+            }
+        );
+    }
+
+    #[test]
+    fn find_host_pid_in_ns() {
+        let proc_path = fake_proc();
+        let ns = |host_pid: &str| pid_ns(proc_path.path(), host_pid).unwrap();
+        assert_eq!(find_host_pid(proc_path.path(), &ns("100"), 7).unwrap(), 101);
+        assert_eq!(find_host_pid(proc_path.path(), &ns("200"), 7).unwrap(), 201);
+        // Process from a nested namespace.
+        assert_eq!(find_host_pid(proc_path.path(), &ns("101"), 9).unwrap(), 300);
+        assert!(matches!(
+            find_host_pid(proc_path.path(), &ns("100"), 8),
+            Err(PidNsError::NotFound { pid: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn translate_to_host_pid() {
+        let proc_path = fake_proc();
+        let translator = PidTranslator {
+            host_proc: proc_path.path().to_path_buf(),
+            local_ns: None,
+        };
+        assert_eq!(translator.to_host(42).unwrap(), 42);
+
+        let translator = PidTranslator {
+            host_proc: proc_path.path().to_path_buf(),
+            local_ns: Some(pid_ns(proc_path.path(), "200").unwrap()),
+        };
+        assert_eq!(translator.to_host(7).unwrap(), 201);
+
+        // PIDs from namespaces of other processes.
+        let host_ns = translator.namespace(1).unwrap();
+        assert_eq!(translator.to_host_in(&host_ns, 42).unwrap(), 42);
+        let ns = translator.namespace(100).unwrap();
+        assert_eq!(translator.to_host_in(&ns, 7).unwrap(), 101);
+    }
+}
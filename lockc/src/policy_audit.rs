@@ -0,0 +1,784 @@
+//! Audit trail of changes of container policies. Every registration of a
+//! container and every change of its policy or enforcement is recorded with
+//! its source, so it can be told who changed the policy and when. The most
+//! recent changes are kept in memory for `lockctl history` and, if
+//! configured, all of them are appended to a file as lines of JSON. The file
+//! is read again on start, so the history survives restarts.
+//!
+//! Lines of the file form a hash chain: each of them includes the SHA-256 of
+//! the previous line, so a modified or removed line breaks the chain at the
+//! next one. Checkpoints with the number of lines and the hash of the last
+//! one are appended periodically, signed with an Ed25519 key if configured,
+//! and logged, so truncation of the file can be told by comparing it with
+//! the last checkpoint logged elsewhere. A log written before the hash chain
+//! was introduced gets a checkpoint as soon as lockc opens it, so a log
+//! without any chained line has had the chain stripped.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, BufReader, LineWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use lockc_common::{
+    control::{PolicyChangeInfo, PolicySource},
+    ContainerPolicyLevel, Enforcement,
+};
+use openssl::{
+    pkey::{Id, PKey, Private},
+    sign::{Signer, Verifier},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{debug, error, info, warn};
+
+use crate::{falco::rfc3339, integrity::sha256_hex, settings};
+
+#[derive(Error, Debug)]
+pub enum PolicyAuditError {
+    #[error("could not open the policy audit log {}: {source}", .path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("could not read the policy audit log {}: {source}", .path.display())]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("could not read the checkpoint key {}: {source}", .path.display())]
+    ReadKey {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("invalid checkpoint key {}: {reason}", .path.display())]
+    InvalidKey { path: PathBuf, reason: String },
+
+    #[error("no policy audit log is configured")]
+    NotConfigured,
+
+    #[error("invalid public key of checkpoints: {0}")]
+    PublicKey(#[from] hex::FromHexError),
+
+    #[error("policy audit log failed verification with {0} issue(s)")]
+    Verification(usize),
+}
+
+/// Issue found when verifying the hash chain of the audit log.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    #[error("line {line}: invalid entry: {error}")]
+    Invalid { line: usize, error: String },
+
+    #[error("line {line}: hash of the previous line doesn't match")]
+    ChainBroken { line: usize },
+
+    #[error("line {line}: entry without the hash of the previous line after chained ones")]
+    Unchained { line: usize },
+
+    #[error("line {line}: checkpoint doesn't match the lines before it")]
+    CheckpointMismatch { line: usize },
+
+    #[error("line {line}: checkpoint is not signed with the given key")]
+    InvalidSignature { line: usize },
+
+    #[error("no line includes the hash of the previous one")]
+    NotChained,
+
+    #[error("no checkpoint signed with the given key")]
+    NoCheckpoint,
+}
+
+/// Policy of a container before or after a change.
+pub type Policy = (ContainerPolicyLevel, Enforcement);
+
+/// Returns the change of the container policy happening now.
+pub fn change(
+    container_id: &str,
+    source: PolicySource,
+    old: Option<Policy>,
+    new: Policy,
+) -> PolicyChangeInfo {
+    PolicyChangeInfo {
+        time: rfc3339(SystemTime::now()),
+        container_id: container_id.to_string(),
+        source,
+        old_policy_level: old.map(|(policy_level, _)| policy_level),
+        old_enforcement: old.map(|(_, enforcement)| enforcement),
+        policy_level: new.0,
+        enforcement: new.1,
+    }
+}
+
+/// State of the audit log at some point, signed to attest it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Time of the checkpoint, RFC 3339 in UTC.
+    pub time: String,
+    /// Number of lines of the log before the checkpoint.
+    pub records: u64,
+    /// SHA-256 of the last of these lines, empty if there are none.
+    pub hash: String,
+    /// Hex-encoded Ed25519 signature of the fields above, if a key is
+    /// configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Checkpoint {
+    /// Returns the signed message.
+    fn message(&self) -> Vec<u8> {
+        format!("{}\n{}\n{}", self.time, self.records, self.hash).into_bytes()
+    }
+
+    fn verify_signature(&self, public_key: &[u8]) -> bool {
+        self.signature
+            .as_deref()
+            .and_then(|signature| hex::decode(signature).ok())
+            .and_then(|signature| {
+                PKey::public_key_from_raw_bytes(public_key, Id::ED25519)
+                    .and_then(|key| {
+                        Verifier::new_without_digest(&key)?
+                            .verify_oneshot(&signature, &self.message())
+                    })
+                    .ok()
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Line of the audit log. Lines written before the hash chain was introduced
+/// don't have `prev_hash`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Checkpoint {
+        checkpoint: Checkpoint,
+        prev_hash: Option<String>,
+    },
+    Change {
+        #[serde(flatten)]
+        change: PolicyChangeInfo,
+        prev_hash: Option<String>,
+    },
+}
+
+/// Hash chain of the lines read from the audit log.
+#[derive(Default)]
+struct Chain {
+    /// SHA-256 of the last line, empty before the first one.
+    hash: String,
+    /// Number of lines.
+    records: u64,
+    /// Whether any line includes the hash of the previous one.
+    chained: bool,
+    last_checkpoint: Option<Checkpoint>,
+}
+
+impl Chain {
+    /// Appends the line to the chain. Returns the change recorded in it and
+    /// the issue found, if any.
+    fn push(
+        &mut self,
+        number: usize,
+        line: &str,
+        public_key: Option<&[u8]>,
+    ) -> (Option<PolicyChangeInfo>, Option<IntegrityIssue>) {
+        let expected = std::mem::replace(&mut self.hash, sha256_hex(line.as_bytes()));
+        let records = self.records;
+        self.records += 1;
+
+        let record = match serde_json::from_str(line) {
+            Ok(record) => record,
+            Err(e) => {
+                return (
+                    None,
+                    Some(IntegrityIssue::Invalid {
+                        line: number,
+                        error: e.to_string(),
+                    }),
+                )
+            }
+        };
+        let prev_hash = match &record {
+            Record::Checkpoint { prev_hash, .. } | Record::Change { prev_hash, .. } => prev_hash,
+        };
+        let issue = match prev_hash {
+            Some(prev_hash) => {
+                self.chained = true;
+                (*prev_hash != expected).then_some(IntegrityIssue::ChainBroken { line: number })
+            }
+            None => self
+                .chained
+                .then_some(IntegrityIssue::Unchained { line: number }),
+        };
+
+        match record {
+            Record::Change { change, .. } => (Some(change), issue),
+            Record::Checkpoint { checkpoint, .. } => {
+                let issue = issue.or_else(|| {
+                    if checkpoint.records != records || checkpoint.hash != expected {
+                        Some(IntegrityIssue::CheckpointMismatch { line: number })
+                    } else if public_key.map_or(false, |key| !checkpoint.verify_signature(key)) {
+                        Some(IntegrityIssue::InvalidSignature { line: number })
+                    } else {
+                        None
+                    }
+                });
+                self.last_checkpoint = Some(checkpoint);
+                (None, issue)
+            }
+        }
+    }
+}
+
+/// Reads the audit log line by line into the hash chain. Returns `None` if
+/// the file doesn't exist.
+fn read_chain(
+    path: &Path,
+    public_key: Option<&[u8]>,
+    mut f: impl FnMut(Option<PolicyChangeInfo>, Option<IntegrityIssue>),
+) -> Result<Option<Chain>, PolicyAuditError> {
+    let read_error = |source| PolicyAuditError::Read {
+        path: path.to_path_buf(),
+        source,
+    };
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(read_error(e)),
+    };
+    let mut chain = Chain::default();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(read_error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (change, issue) = chain.push(i + 1, &line, public_key);
+        f(change, issue);
+    }
+    Ok(Some(chain))
+}
+
+/// Result of the verification of the audit log.
+pub struct Verification {
+    /// Number of lines.
+    pub records: u64,
+    /// SHA-256 of the last line.
+    pub hash: String,
+    pub last_checkpoint: Option<Checkpoint>,
+    pub issues: Vec<IntegrityIssue>,
+}
+
+/// Verifies the hash chain of the audit log and, if a public key is given,
+/// signatures of its checkpoints. With a public key, the log has to have a
+/// checkpoint, so it can't be replaced with an unsigned one.
+pub fn verify(path: &Path, public_key: Option<&[u8]>) -> Result<Verification, PolicyAuditError> {
+    let mut issues = Vec::new();
+    let chain =
+        read_chain(path, public_key, |_, issue| issues.extend(issue))?.ok_or_else(|| {
+            PolicyAuditError::Read {
+                path: path.to_path_buf(),
+                source: io::ErrorKind::NotFound.into(),
+            }
+        })?;
+    if chain.records > 0 && !chain.chained {
+        issues.push(IntegrityIssue::NotChained);
+    }
+    if public_key.is_some() && chain.last_checkpoint.is_none() {
+        issues.push(IntegrityIssue::NoCheckpoint);
+    }
+    Ok(Verification {
+        records: chain.records,
+        hash: chain.hash,
+        last_checkpoint: chain.last_checkpoint,
+        issues,
+    })
+}
+
+/// Reads the Ed25519 key pair from a PKCS#8 document.
+fn read_key(path: &Path) -> Result<PKey<Private>, PolicyAuditError> {
+    let pkcs8 = fs::read(path).map_err(|source| PolicyAuditError::ReadKey {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let key = PKey::private_key_from_pkcs8(&pkcs8).map_err(|e| PolicyAuditError::InvalidKey {
+        path: path.to_path_buf(),
+        reason: e.to_string(),
+    })?;
+    if key.id() != Id::ED25519 {
+        return Err(PolicyAuditError::InvalidKey {
+            path: path.to_path_buf(),
+            reason: "not an Ed25519 key".to_string(),
+        });
+    }
+    Ok(key)
+}
+
+/// Returns whether the file ends with a line without a newline.
+fn unterminated(file: &mut fs::File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::End(-1))?;
+    let mut last = [0; 1];
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Recorded changes of container policies.
+pub struct PolicyAudit {
+    changes: VecDeque<PolicyChangeInfo>,
+    retained: usize,
+    log: Option<LineWriter<fs::File>>,
+    /// SHA-256 of the last line of the log.
+    hash: String,
+    /// Number of lines of the log.
+    records: u64,
+    /// Number of lines of the log at the last checkpoint.
+    checkpointed: u64,
+    /// Whether any line of the log read on start is chained.
+    chained: bool,
+    key: Option<PKey<Private>>,
+}
+
+/// Keeps changes only in memory.
+impl Default for PolicyAudit {
+    fn default() -> Self {
+        PolicyAudit {
+            changes: VecDeque::new(),
+            retained: settings::PolicyAudit::default().retained,
+            log: None,
+            hash: String::new(),
+            records: 0,
+            checkpointed: 0,
+            chained: false,
+            key: None,
+        }
+    }
+}
+
+impl PolicyAudit {
+    /// Opens the audit log, if configured, and reads the most recent changes
+    /// recorded in it. Invalid lines, e.g. the last one written before a
+    /// crash, are skipped. Breaks of the hash chain are logged.
+    pub fn new(settings: &settings::PolicyAudit) -> Result<Self, PolicyAuditError> {
+        let mut audit = PolicyAudit {
+            retained: settings.retained,
+            key: settings
+                .checkpoint_key
+                .as_deref()
+                .map(read_key)
+                .transpose()?,
+            ..Default::default()
+        };
+        if let Some(path) = &settings.log {
+            audit.read_log(path)?;
+            let open_error = |source| PolicyAuditError::Open {
+                path: path.clone(),
+                source,
+            };
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(open_error)?;
+            }
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(path)
+                .map_err(open_error)?;
+            // Don't append to a line cut off by a crash.
+            if unterminated(&mut file).map_err(open_error)? {
+                file.write_all(b"\n").map_err(open_error)?;
+            }
+            audit.log = Some(LineWriter::new(file));
+            // Chain the lines written before the hash chain was introduced.
+            if !audit.chained {
+                audit.checkpoint();
+            }
+        }
+        Ok(audit)
+    }
+
+    fn read_log(&mut self, path: &Path) -> Result<(), PolicyAuditError> {
+        let public_key = self.key.as_ref().and_then(|key| key.raw_public_key().ok());
+        let mut changes = Vec::new();
+        let chain = read_chain(path, public_key.as_deref(), |change, issue| {
+            changes.extend(change);
+            match issue {
+                Some(IntegrityIssue::Invalid { line, error }) => warn!(
+                    path = path.to_string_lossy().as_ref(),
+                    line,
+                    error = error.as_str(),
+                    "skipping invalid entry of the policy audit log"
+                ),
+                Some(issue) => warn!(
+                    path = path.to_string_lossy().as_ref(),
+                    issue = issue.to_string().as_str(),
+                    "policy audit log failed verification"
+                ),
+                None => {}
+            }
+        })?;
+        for change in changes {
+            self.retain(change);
+        }
+        if let Some(chain) = chain {
+            self.hash = chain.hash;
+            self.records = chain.records;
+            self.chained = chain.chained;
+            self.checkpointed = chain
+                .last_checkpoint
+                .map(|checkpoint| checkpoint.records + 1)
+                .unwrap_or(0);
+        }
+        Ok(())
+    }
+
+    fn retain(&mut self, change: PolicyChangeInfo) {
+        if self.retained == 0 {
+            return;
+        }
+        while self.changes.len() >= self.retained {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Appends the record to the log and the hash chain.
+    fn append(&mut self, record: &Record) -> io::Result<()> {
+        let log = match &mut self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let mut line = serde_json::to_vec(record)?;
+        let hash = sha256_hex(&line);
+        line.push(b'\n');
+        log.write_all(&line)?;
+        self.hash = hash;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Records the change. A failed write to the audit log is logged, the
+    /// change is kept in memory anyway.
+    pub fn record(&mut self, change: PolicyChangeInfo) {
+        debug!(
+            container_id = change.container_id.as_str(),
+            source = change.source.to_string().as_str(),
+            old_policy_level = change.old_policy_level.map(|p| p.to_string()).as_deref(),
+            old_enforcement = change.old_enforcement.map(|e| e.to_string()).as_deref(),
+            policy_level = change.policy_level.to_string().as_str(),
+            enforcement = change.enforcement.to_string().as_str(),
+            "container policy changed"
+        );
+        let record = Record::Change {
+            change: change.clone(),
+            prev_hash: Some(self.hash.clone()),
+        };
+        if let Err(e) = self.append(&record) {
+            warn!(
+                container_id = change.container_id.as_str(),
+                error = e.to_string().as_str(),
+                "could not write to the policy audit log"
+            );
+        }
+        self.retain(change);
+    }
+
+    /// Appends a checkpoint to the log, if anything was written since the
+    /// last one, and logs it.
+    pub fn checkpoint(&mut self) {
+        if self.log.is_none() || self.records == self.checkpointed {
+            return;
+        }
+        let mut checkpoint = Checkpoint {
+            time: rfc3339(SystemTime::now()),
+            records: self.records,
+            hash: self.hash.clone(),
+            signature: None,
+        };
+        let message = checkpoint.message();
+        let signature = self.key.as_ref().map(|key| {
+            Signer::new_without_digest(key)
+                .and_then(|mut signer| signer.sign_oneshot_to_vec(&message))
+        });
+        checkpoint.signature = match signature.transpose() {
+            Ok(signature) => signature.map(hex::encode),
+            Err(e) => {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "could not sign a checkpoint of the policy audit log"
+                );
+                return;
+            }
+        };
+        let record = Record::Checkpoint {
+            checkpoint: checkpoint.clone(),
+            prev_hash: Some(self.hash.clone()),
+        };
+        if let Err(e) = self.append(&record) {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not write a checkpoint to the policy audit log"
+            );
+            return;
+        }
+        self.checkpointed = self.records;
+        info!(
+            records = checkpoint.records,
+            hash = checkpoint.hash.as_str(),
+            signature = checkpoint.signature.as_deref(),
+            "policy audit log checkpoint"
+        );
+    }
+
+    /// Returns the retained changes of containers whose ID starts with the
+    /// given one, or of all containers, oldest first.
+    pub fn history(&self, container_id: Option<&str>) -> Vec<PolicyChangeInfo> {
+        self.changes
+            .iter()
+            .filter(|change| {
+                container_id
+                    .map(|id| !id.is_empty() && change.container_id.starts_with(id))
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Appends checkpoints to the audit log in the given interval, for as long
+/// as lockc runs.
+pub async fn checkpoints(policy_audit: Arc<Mutex<PolicyAudit>>, interval: Duration) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        match policy_audit.lock() {
+            Ok(mut policy_audit) => policy_audit.checkpoint(),
+            Err(_) => {
+                error!("policy audit trail is poisoned");
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn policy_audit_record_and_reload() {
+        let dir = tempdir().unwrap();
+        let settings = settings::PolicyAudit {
+            log: Some(dir.path().join("audit").join("policy.jsonl")),
+            retained: 2,
+            ..Default::default()
+        };
+
+        let mut audit = PolicyAudit::new(&settings).unwrap();
+        audit.record(change(
+            "abc",
+            PolicySource::Runtime,
+            None,
+            (ContainerPolicyLevel::Restricted, Enforcement::Enforce),
+        ));
+        audit.record(change(
+            "def",
+            PolicySource::Runtime,
+            None,
+            (ContainerPolicyLevel::Baseline, Enforcement::Enforce),
+        ));
+        audit.record(change(
+            "abc",
+            PolicySource::ControlApi {
+                uid: Some(0),
+                pid: Some(42),
+            },
+            Some((ContainerPolicyLevel::Restricted, Enforcement::Enforce)),
+            (ContainerPolicyLevel::Restricted, Enforcement::Complain),
+        ));
+
+        // Only the most recent changes are retained.
+        let history = audit.history(Some("abc"));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].old_enforcement, Some(Enforcement::Enforce));
+        assert_eq!(history[0].enforcement, Enforcement::Complain);
+        assert_eq!(audit.history(None).len(), 2);
+        assert!(audit.history(Some("")).is_empty());
+        drop(audit);
+
+        // The log keeps all changes, and invalid lines are skipped.
+        let path = settings.log.as_ref().unwrap();
+        let mut log = fs::OpenOptions::new().append(true).open(path).unwrap();
+        log.write_all(b"{\"time\":").unwrap();
+        let settings = settings::PolicyAudit {
+            retained: 10,
+            ..settings.clone()
+        };
+        let mut audit = PolicyAudit::new(&settings).unwrap();
+        assert_eq!(audit.history(None).len(), 3);
+        audit.record(change(
+            "abc",
+            PolicySource::Runtime,
+            Some((ContainerPolicyLevel::Restricted, Enforcement::Complain)),
+            (ContainerPolicyLevel::Baseline, Enforcement::Complain),
+        ));
+        drop(audit);
+
+        let audit = PolicyAudit::new(&settings).unwrap();
+        let history = audit.history(Some("abc"));
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[2].policy_level, ContainerPolicyLevel::Baseline);
+        assert_eq!(history[0].source, PolicySource::Runtime);
+        assert_eq!(history[0].old_policy_level, None);
+        assert_eq!(
+            history[1].source,
+            PolicySource::ControlApi {
+                uid: Some(0),
+                pid: Some(42),
+            }
+        );
+
+        // The line cut off is part of the chain, only reported as invalid.
+        let verification = verify(path, None).unwrap();
+        assert_eq!(verification.records, 5);
+        assert!(matches!(
+            verification.issues[..],
+            [IntegrityIssue::Invalid { line: 4, .. }]
+        ));
+    }
+
+    #[test]
+    fn policy_audit_hash_chain() {
+        let dir = tempdir().unwrap();
+        let key_path = dir.path().join("checkpoint.pk8");
+        let pkcs8 = PKey::generate_ed25519()
+            .unwrap()
+            .private_key_to_pkcs8()
+            .unwrap();
+        fs::write(&key_path, pkcs8).unwrap();
+        let public_key = read_key(&key_path).unwrap().raw_public_key().unwrap();
+        let path = dir.path().join("policy.jsonl");
+        let settings = settings::PolicyAudit {
+            log: Some(path.clone()),
+            checkpoint_key: Some(key_path),
+            ..Default::default()
+        };
+
+        // Lines written before the hash chain are accepted at the start.
+        let legacy = change(
+            "abc",
+            PolicySource::Runtime,
+            None,
+            (ContainerPolicyLevel::Restricted, Enforcement::Enforce),
+        );
+        fs::write(
+            &path,
+            format!("{}\n", serde_json::to_string(&legacy).unwrap()),
+        )
+        .unwrap();
+
+        let mut audit = PolicyAudit::new(&settings).unwrap();
+        for id in ["def", "ghi", "jkl"] {
+            audit.record(change(
+                id,
+                PolicySource::Runtime,
+                None,
+                (ContainerPolicyLevel::Baseline, Enforcement::Enforce),
+            ));
+        }
+        audit.checkpoint();
+        // Nothing changed since the last checkpoint.
+        audit.checkpoint();
+        drop(audit);
+
+        // The legacy line got a checkpoint when the log was opened.
+        let verification = verify(&path, Some(&public_key)).unwrap();
+        assert!(verification.issues.is_empty());
+        assert_eq!(verification.records, 6);
+        let checkpoint = verification.last_checkpoint.unwrap();
+        assert_eq!(checkpoint.records, 5);
+
+        // The chain continues after a restart.
+        let mut audit = PolicyAudit::new(&settings).unwrap();
+        assert_eq!(audit.history(None).len(), 4);
+        audit.checkpoint();
+        audit.record(change(
+            "mno",
+            PolicySource::Runtime,
+            None,
+            (ContainerPolicyLevel::Offline, Enforcement::Enforce),
+        ));
+        drop(audit);
+        assert!(verify(&path, Some(&public_key)).unwrap().issues.is_empty());
+
+        // A modified line breaks the chain at the next one.
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("\"ghi\"", "\"xyz\"", 1)).unwrap();
+        assert_eq!(
+            verify(&path, Some(&public_key)).unwrap().issues,
+            [IntegrityIssue::ChainBroken { line: 5 }]
+        );
+
+        // So does a removed one, and the checkpoint doesn't match the lines
+        // before it.
+        let lines: Vec<_> = content
+            .lines()
+            .filter(|line| !line.contains("\"def\""))
+            .collect();
+        fs::write(&path, lines.join("\n")).unwrap();
+        assert_eq!(
+            verify(&path, Some(&public_key)).unwrap().issues,
+            [
+                IntegrityIssue::ChainBroken { line: 3 },
+                IntegrityIssue::CheckpointMismatch { line: 5 }
+            ]
+        );
+
+        // Stripping hashes and checkpoints doesn't make it a legacy log.
+        let stripped: Vec<_> = content
+            .lines()
+            .filter(|line| !line.contains("\"checkpoint\""))
+            .map(|line| {
+                let mut record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record.as_object_mut().unwrap().remove("prev_hash");
+                record.to_string()
+            })
+            .collect();
+        fs::write(&path, stripped.join("\n")).unwrap();
+        assert_eq!(
+            verify(&path, None).unwrap().issues,
+            [IntegrityIssue::NotChained]
+        );
+        assert_eq!(
+            verify(&path, Some(&public_key)).unwrap().issues,
+            [IntegrityIssue::NotChained, IntegrityIssue::NoCheckpoint]
+        );
+
+        // Checkpoints signed with another key are reported.
+        fs::write(&path, &content).unwrap();
+        let other_key = PKey::generate_ed25519().unwrap();
+        assert_eq!(
+            verify(&path, Some(&other_key.raw_public_key().unwrap()))
+                .unwrap()
+                .issues,
+            [
+                IntegrityIssue::InvalidSignature { line: 2 },
+                IntegrityIssue::InvalidSignature { line: 6 }
+            ]
+        );
+    }
+}
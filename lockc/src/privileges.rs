@@ -0,0 +1,215 @@
+//! Dropping privileges after the setup is done. lockc switches to an
+//! unprivileged user and keeps only the capabilities needed to operate on
+//! already loaded eBPF maps and to inspect containers.
+
+use std::{fs, io};
+
+use nix::{
+    errno::Errno,
+    unistd::{setgid, setgroups, setuid, User},
+};
+use procfs::ProcError;
+use thiserror::Error;
+use tracing::debug;
+
+const CAP_DAC_READ_SEARCH: u32 = 2;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_PTRACE: u32 = 19;
+const CAP_PERFMON: u32 = 38;
+const CAP_BPF: u32 = 39;
+
+/// Capabilities retained after dropping privileges:
+/// * CAP_BPF and CAP_PERFMON - operations on eBPF maps through the file
+///   descriptors opened during setup and reading logs from eBPF programs
+/// * CAP_DAC_READ_SEARCH and CAP_SYS_PTRACE - reading container bundles and
+///   `/proc` entries of container processes
+/// * CAP_NET_ADMIN - attaching egress programs to cgroups of new containers
+const RETAINED_CAPS: &[u32] = &[
+    CAP_DAC_READ_SEARCH,
+    CAP_NET_ADMIN,
+    CAP_SYS_PTRACE,
+    CAP_PERFMON,
+    CAP_BPF,
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[derive(Error, Debug)]
+pub enum PrivilegesError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Errno(#[from] Errno),
+
+    #[error(transparent)]
+    Proc(#[from] ProcError),
+
+    #[error("user {0} not found")]
+    UserNotFound(String),
+
+    #[error("privileges can be dropped only by a single-threaded process, found {0} threads")]
+    MultipleThreads(i64),
+}
+
+/// Returns the highest capability supported by the kernel.
+fn cap_last_cap() -> Result<u32, io::Error> {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Returns the capability sets containing the given capabilities.
+fn cap_data(caps: &[u32]) -> [CapUserData; 2] {
+    let mut data = [CapUserData::default(); 2];
+    for cap in caps {
+        let bit = 1 << (cap % 32);
+        let data = &mut data[(cap / 32) as usize];
+        data.effective |= bit;
+        data.permitted |= bit;
+    }
+    data
+}
+
+fn capset(caps: &[u32]) -> Result<(), Errno> {
+    let header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = cap_data(caps);
+    let res = unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) };
+    Errno::result(res).map(drop)
+}
+
+fn prctl(option: libc::c_int, arg: libc::c_ulong) -> Result<(), Errno> {
+    let res = unsafe { libc::prctl(option, arg, 0, 0, 0) };
+    Errno::result(res).map(drop)
+}
+
+/// Switches to the given user, keeping only the capabilities from
+/// `RETAINED_CAPS`. Capabilities are per-thread, so this has to be done
+/// before spawning any threads, which then inherit the reduced set.
+pub fn drop_privileges(user_name: &str) -> Result<(), PrivilegesError> {
+    let num_threads = procfs::process::Process::myself()?.stat()?.num_threads;
+    if num_threads > 1 {
+        return Err(PrivilegesError::MultipleThreads(num_threads));
+    }
+
+    let user = User::from_name(user_name)?
+        .ok_or_else(|| PrivilegesError::UserNotFound(user_name.to_string()))?;
+
+    let last_cap = cap_last_cap()?;
+    let caps: Vec<u32> = RETAINED_CAPS
+        .iter()
+        .copied()
+        .filter(|cap| *cap <= last_cap)
+        .collect();
+
+    // Remove all other capabilities from the bounding set, so they cannot be
+    // regained by executing anything.
+    for cap in 0..=last_cap {
+        if !caps.contains(&cap) {
+            prctl(libc::PR_CAPBSET_DROP, cap.into())?;
+        }
+    }
+
+    // Keep the permitted capabilities when switching from root.
+    prctl(libc::PR_SET_KEEPCAPS, 1)?;
+    setgroups(&[user.gid])?;
+    setgid(user.gid)?;
+    setuid(user.uid)?;
+    prctl(libc::PR_SET_KEEPCAPS, 0)?;
+
+    capset(&caps)?;
+    prctl(libc::PR_SET_NO_NEW_PRIVS, 1)?;
+
+    debug!(
+        user = user_name,
+        uid = user.uid.as_raw(),
+        "dropped privileges"
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use nix::{
+        sys::wait::{waitpid, WaitStatus},
+        unistd::{fork, getuid, ForkResult},
+    };
+
+    #[test]
+    fn cap_data_sets() {
+        let data = cap_data(RETAINED_CAPS);
+        let low = (1 << CAP_DAC_READ_SEARCH) | (1 << CAP_NET_ADMIN) | (1 << CAP_SYS_PTRACE);
+        let high = (1 << (CAP_PERFMON - 32)) | (1 << (CAP_BPF - 32));
+        assert_eq!(data[0].effective, low);
+        assert_eq!(data[0].permitted, low);
+        assert_eq!(data[1].effective, high);
+        assert_eq!(data[1].permitted, high);
+        assert_eq!(data[0].inheritable, 0);
+        assert_eq!(data[1].inheritable, 0);
+    }
+
+    /// Drops privileges in a forked, single-threaded child and checks that
+    /// exactly the retained capabilities are effective there.
+    #[test]
+    #[cfg_attr(not(feature = "tests_bpf"), ignore)]
+    fn drop_privileges_keeps_retained_caps() {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let code = match drop_privileges("nobody") {
+                    Ok(()) => {
+                        let last_cap = cap_last_cap().unwrap();
+                        let expected = RETAINED_CAPS
+                            .iter()
+                            .filter(|cap| **cap <= last_cap)
+                            .fold(0u64, |caps, cap| caps | (1 << cap));
+                        let status = procfs::process::Process::myself()
+                            .unwrap()
+                            .status()
+                            .unwrap();
+                        if status.capeff == expected
+                            && status.capprm == expected
+                            && !getuid().is_root()
+                        {
+                            0
+                        } else {
+                            eprintln!(
+                                "effective capabilities {:#x}, permitted {:#x}, expected {:#x}",
+                                status.capeff, status.capprm, expected
+                            );
+                            1
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("could not drop privileges: {}", e);
+                        2
+                    }
+                };
+                unsafe { libc::_exit(code) };
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+        }
+    }
+}
@@ -0,0 +1,613 @@
+//! Built-in allowed and denied paths for environments lockc runs in. Container
+//! engines and Kubernetes distributions keep container storage, sandbox files
+//! and pod volumes in different directories, so the paths which have to be
+//! allowed for bind mounts differ between them.
+
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use clap::ValueEnum;
+use lockc_common::{verdict::PathLists, DenyResponse, FilePermission, PathClass};
+use serde::Deserialize;
+
+/// Environment which lockc runs in.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    /// Plain host with Docker (or containerd) and no Kubernetes.
+    Docker,
+    /// k3s node.
+    K3s,
+    /// RKE2 node.
+    Rke2,
+    /// OpenShift node with CRI-O.
+    #[value(name = "openshift")]
+    OpenShift,
+    /// Kubernetes node set up with kubeadm, using containerd.
+    Kubeadm,
+    /// Google Kubernetes Engine node.
+    Gke,
+    /// Amazon Elastic Kubernetes Service node.
+    Eks,
+    /// Azure Kubernetes Service node.
+    Aks,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Profile::Docker => write!(f, "docker"),
+            Profile::K3s => write!(f, "k3s"),
+            Profile::Rke2 => write!(f, "rke2"),
+            Profile::OpenShift => write!(f, "openshift"),
+            Profile::Kubeadm => write!(f, "kubeadm"),
+            Profile::Gke => write!(f, "gke"),
+            Profile::Eks => write!(f, "eks"),
+            Profile::Aks => write!(f, "aks"),
+        }
+    }
+}
+
+/// Pseudo-terminal devices, needed for the `-it` option of container engines.
+const MOUNT_COMMON: &[&str] = &["/dev/pts"];
+
+const MOUNT_DOCKER: &[&str] = &[
+    // Container data (hosts, hostname, resolv.conf) and volumes.
+    "/var/lib/docker",
+];
+
+const MOUNT_K3S: &[&str] = &[
+    // State of the containerd embedded in k3s.
+    "/run/k3s",
+    "/var/lib/rancher/k3s",
+    // Pod volumes.
+    "/var/lib/kubelet",
+];
+
+const MOUNT_RKE2: &[&str] = &[
+    // RKE2 keeps the containerd socket and state in the same place as k3s.
+    "/run/k3s",
+    "/var/lib/rancher/rke2",
+    // Pod volumes.
+    "/var/lib/kubelet",
+];
+
+const MOUNT_OPENSHIFT: &[&str] = &[
+    // Container data (hosts, hostname, resolv.conf) managed by CRI-O.
+    "/run/containers/storage",
+    "/var/run/containers/storage",
+    "/var/lib/containers/storage",
+    // Pod volumes.
+    "/var/lib/kubelet",
+];
+
+const MOUNT_KUBEADM: &[&str] = &[
+    // Sandbox files (hostname, resolv.conf) managed by containerd.
+    "/run/containerd/io.containerd.grpc.v1.cri/sandboxes",
+    "/var/lib/containerd/io.containerd.grpc.v1.cri/sandboxes",
+    // Pod volumes.
+    "/var/lib/kubelet",
+];
+
+// Managed Kubernetes distributions run containerd set up the same way as
+// kubeadm. Kubelet plugin sockets and registration (`plugins`,
+// `plugins_registry`) are under the pod volumes directory.
+
+const MOUNT_GKE: &[&str] = &[
+    // Mount utilities and FlexVolume drivers of Container-Optimized OS.
+    "/home/kubernetes/containerized_mounter",
+    "/home/kubernetes/flexvolume",
+];
+
+const MOUNT_EKS: &[&str] = &[
+    // Socket and logs of the AWS VPC CNI.
+    "/var/run/aws-node",
+    "/var/log/aws-routed-eni",
+    // State and configuration of the EFS CSI driver.
+    "/var/amazon/efs",
+    "/etc/amazon/efs",
+];
+
+const MOUNT_AKS: &[&str] = &[
+    // Cloud provider configuration and managed identity credentials, used by
+    // the Azure Disk and Azure File CSI drivers.
+    "/etc/kubernetes/azure.json",
+    "/var/lib/waagent/ManagedIdentity-Settings",
+];
+
+/// Node label prefixes set by managed Kubernetes distributions.
+const NODE_LABELS: &[(&str, Profile)] = &[
+    ("cloud.google.com/gke-", Profile::Gke),
+    ("eks.amazonaws.com/", Profile::Eks),
+    ("kubernetes.azure.com/", Profile::Aks),
+];
+
+/// Directories which containers with the baseline policy can mount on top of
+/// the ones used by container engines.
+const MOUNT_BASELINE: &[&str] = &["/home", "/var/data"];
+
+/// Exceptions from denied paths.
+const ACCESS_ALLOWED: &[&str] = &["/sys/devices", "/sys/fs/cgroup", "/sys/kernel/mm"];
+
+const ACCESS_DENIED_BASELINE: &[&str] = &[
+    "/proc/acpi",
+    "/sys/",
+    // Service account tokens. /var/run is usually a symlink to /run and
+    // paths are resolved by the kernel, so both locations are listed.
+    "/run/secrets/kubernetes.io",
+    "/var/run/secrets/kubernetes.io",
+];
+
+const ACCESS_DENIED_RESTRICTED: &[&str] = &["/proc/sys"];
+
+/// API sockets of container engines and CRI runtimes. Containers connecting
+/// to them can create privileged containers and escape to the host.
+const RUNTIME_SOCKETS: &[&str] = &[
+    "/run/docker.sock",
+    "/var/run/docker.sock",
+    "/run/containerd/containerd.sock",
+    "/var/run/containerd/containerd.sock",
+    "/run/k3s/containerd/containerd.sock",
+    "/run/crio/crio.sock",
+    "/var/run/crio/crio.sock",
+    "/run/podman/podman.sock",
+    "/var/run/podman/podman.sock",
+];
+
+/// Allowed and denied path prefixes for all policy levels.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AllowedPaths {
+    /// Paths allowed to be bind mounted in restricted (and offline)
+    /// containers.
+    pub mount_restricted: Vec<String>,
+    /// Paths allowed to be bind mounted in baseline containers.
+    pub mount_baseline: Vec<String>,
+    /// Paths allowed to be opened in restricted containers, even if they are
+    /// under a shorter denied path.
+    pub access_restricted: Vec<String>,
+    /// Paths allowed to be opened in baseline containers, even if they are
+    /// under a shorter denied path.
+    pub access_baseline: Vec<String>,
+    /// Paths denied to be opened in restricted containers.
+    pub denied_access_restricted: Vec<String>,
+    /// Paths denied to be opened in baseline containers.
+    pub denied_access_baseline: Vec<String>,
+    /// Paths denied to be opened in restricted and baseline containers, with
+    /// the response to opening them. Responses are applied only to paths
+    /// matched by inode.
+    pub responses: BTreeMap<String, DenyResponse>,
+}
+
+fn to_strings<'a, I: IntoIterator<Item = &'a &'a str>>(paths: I) -> Vec<String> {
+    paths.into_iter().map(|path| path.to_string()).collect()
+}
+
+fn append(dst: &mut Vec<String>, src: &[String]) {
+    for path in src {
+        if !dst.contains(path) {
+            dst.push(path.clone());
+        }
+    }
+}
+
+impl AllowedPaths {
+    /// Denies sockets of container runtimes to baseline and restricted
+    /// containers.
+    pub fn deny_runtime_sockets(&mut self) {
+        let sockets = to_strings(RUNTIME_SOCKETS);
+        append(&mut self.denied_access_restricted, &sockets);
+        append(&mut self.denied_access_baseline, &sockets);
+    }
+
+    /// Appends paths from `other` which are not present yet.
+    pub fn extend(&mut self, other: &AllowedPaths) {
+        append(&mut self.mount_restricted, &other.mount_restricted);
+        append(&mut self.mount_baseline, &other.mount_baseline);
+        append(&mut self.access_restricted, &other.access_restricted);
+        append(&mut self.access_baseline, &other.access_baseline);
+        append(
+            &mut self.denied_access_restricted,
+            &other.denied_access_restricted,
+        );
+        append(
+            &mut self.denied_access_baseline,
+            &other.denied_access_baseline,
+        );
+        for (path, response) in &other.responses {
+            self.responses.entry(path.clone()).or_insert(*response);
+        }
+    }
+
+    /// Returns entries of the LPM trie with path prefixes. When the same path
+    /// is both allowed and denied to be opened, it's denied. Paths with a
+    /// response are denied, unless they are only audited.
+    pub fn prefixes(&self) -> Vec<(PathClass, &str, FilePermission)> {
+        let lists: [(PathClass, FilePermission, &[String]); 6] = [
+            (
+                PathClass::MountRestricted,
+                FilePermission::Allow,
+                &self.mount_restricted,
+            ),
+            (
+                PathClass::MountBaseline,
+                FilePermission::Allow,
+                &self.mount_baseline,
+            ),
+            (
+                PathClass::AccessRestricted,
+                FilePermission::Allow,
+                &self.access_restricted,
+            ),
+            (
+                PathClass::AccessBaseline,
+                FilePermission::Allow,
+                &self.access_baseline,
+            ),
+            (
+                PathClass::AccessRestricted,
+                FilePermission::Deny,
+                &self.denied_access_restricted,
+            ),
+            (
+                PathClass::AccessBaseline,
+                FilePermission::Deny,
+                &self.denied_access_baseline,
+            ),
+        ];
+
+        let mut prefixes: Vec<(PathClass, &str, FilePermission)> = Vec::new();
+        for (class, permission, paths) in lists {
+            for path in paths.iter().filter(|path| !path.is_empty()) {
+                match prefixes
+                    .iter_mut()
+                    .find(|(c, p, _)| *c == class && p == path)
+                {
+                    Some(entry) => entry.2 = FilePermission::Deny,
+                    None => prefixes.push((class, path, permission)),
+                }
+            }
+        }
+        for (path, response) in &self.responses {
+            if path.is_empty() || *response == DenyResponse::Audit {
+                continue;
+            }
+            for class in [PathClass::AccessRestricted, PathClass::AccessBaseline] {
+                match prefixes
+                    .iter_mut()
+                    .find(|(c, p, _)| *c == class && p == path)
+                {
+                    Some(entry) => entry.2 = FilePermission::Deny,
+                    None => prefixes.push((class, path, FilePermission::Deny)),
+                }
+            }
+        }
+        prefixes
+    }
+}
+
+impl PathLists for AllowedPaths {
+    fn lookup(&self, class: PathClass, path: &[u8]) -> Option<FilePermission> {
+        self.prefixes()
+            .into_iter()
+            .filter(|(c, prefix, _)| *c == class && path.starts_with(prefix.as_bytes()))
+            .max_by_key(|(_, prefix, _)| prefix.len())
+            .map(|(_, _, permission)| permission)
+    }
+}
+
+impl Profile {
+    /// Detects the environment by looking for directories specific to
+    /// Kubernetes distributions under `root`. Hosts without any of them are
+    /// assumed to run plain Docker.
+    pub fn detect<P: AsRef<Path>>(root: P) -> Profile {
+        let root = root.as_ref();
+        let exists = |path: &str| root.join(path).exists();
+        if exists("home/kubernetes/kubelet-config.yaml") {
+            Profile::Gke
+        } else if exists("etc/eks") {
+            Profile::Eks
+        } else if exists("etc/kubernetes/azure.json") {
+            Profile::Aks
+        } else if exists("var/lib/rancher/rke2") {
+            Profile::Rke2
+        } else if exists("var/lib/rancher/k3s") {
+            Profile::K3s
+        } else if exists("etc/kubernetes/static-pod-resources") {
+            Profile::OpenShift
+        } else if exists("etc/kubernetes/manifests") {
+            Profile::Kubeadm
+        } else {
+            Profile::Docker
+        }
+    }
+
+    /// Detects a managed Kubernetes distribution from labels of the node.
+    pub fn from_node_labels(labels: &BTreeMap<String, String>) -> Option<Profile> {
+        labels.keys().find_map(|key| {
+            NODE_LABELS
+                .iter()
+                .find(|(prefix, _)| key.starts_with(prefix))
+                .map(|(_, profile)| *profile)
+        })
+    }
+
+    /// Returns the built-in allowed and denied paths for the environment.
+    pub fn allowed_paths(&self) -> AllowedPaths {
+        let (mount, managed): (&[&str], &[&str]) = match self {
+            Profile::Docker => (MOUNT_DOCKER, &[]),
+            Profile::K3s => (MOUNT_K3S, &[]),
+            Profile::Rke2 => (MOUNT_RKE2, &[]),
+            Profile::OpenShift => (MOUNT_OPENSHIFT, &[]),
+            Profile::Kubeadm => (MOUNT_KUBEADM, &[]),
+            Profile::Gke => (MOUNT_KUBEADM, MOUNT_GKE),
+            Profile::Eks => (MOUNT_KUBEADM, MOUNT_EKS),
+            Profile::Aks => (MOUNT_KUBEADM, MOUNT_AKS),
+        };
+        let mount = || MOUNT_COMMON.iter().chain(mount).chain(managed);
+        let mount_restricted = to_strings(mount());
+
+        AllowedPaths {
+            mount_baseline: to_strings(mount().chain(MOUNT_BASELINE)),
+            mount_restricted,
+            access_restricted: to_strings(ACCESS_ALLOWED),
+            access_baseline: to_strings(ACCESS_ALLOWED),
+            denied_access_restricted: to_strings(
+                ACCESS_DENIED_BASELINE
+                    .iter()
+                    .chain(ACCESS_DENIED_RESTRICTED),
+            ),
+            denied_access_baseline: to_strings(ACCESS_DENIED_BASELINE),
+        }
+    }
+}
+
+/// Detects a managed Kubernetes distribution from labels of the node with the
+/// given name, fetched from the API server.
+#[cfg(feature = "kubernetes")]
+pub async fn detect_node(node_name: &str) -> Result<Option<Profile>, kube::Error> {
+    let client = kube::Client::try_default().await?;
+    let nodes: kube::api::Api<k8s_openapi::api::core::v1::Node> = kube::api::Api::all(client);
+    let node = nodes.get(node_name).await?;
+    Ok(node
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(Profile::from_node_labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::{
+        verdict::{self, Verdict},
+        ContainerPolicyLevel,
+    };
+
+    use super::*;
+
+    #[test]
+    fn profile_detect() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Profile::detect(dir.path()), Profile::Docker);
+
+        std::fs::create_dir_all(dir.path().join("etc/kubernetes/manifests")).unwrap();
+        assert_eq!(Profile::detect(dir.path()), Profile::Kubeadm);
+
+        std::fs::create_dir_all(dir.path().join("var/lib/rancher/k3s")).unwrap();
+        assert_eq!(Profile::detect(dir.path()), Profile::K3s);
+
+        std::fs::create_dir_all(dir.path().join("var/lib/rancher/rke2")).unwrap();
+        assert_eq!(Profile::detect(dir.path()), Profile::Rke2);
+
+        std::fs::write(dir.path().join("etc/kubernetes/azure.json"), "{}").unwrap();
+        assert_eq!(Profile::detect(dir.path()), Profile::Aks);
+    }
+
+    #[test]
+    fn profile_from_node_labels() {
+        let labels = |keys: &[&str]| {
+            keys.iter()
+                .map(|key| (key.to_string(), "x".to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(
+            Profile::from_node_labels(&labels(&[
+                "kubernetes.io/hostname",
+                "cloud.google.com/gke-nodepool"
+            ])),
+            Some(Profile::Gke)
+        );
+        assert_eq!(
+            Profile::from_node_labels(&labels(&["eks.amazonaws.com/nodegroup"])),
+            Some(Profile::Eks)
+        );
+        assert_eq!(
+            Profile::from_node_labels(&labels(&["kubernetes.azure.com/cluster"])),
+            Some(Profile::Aks)
+        );
+        assert_eq!(
+            Profile::from_node_labels(&labels(&["kubernetes.io/hostname"])),
+            None
+        );
+    }
+
+    #[test]
+    fn managed_profile_paths() {
+        let eks = Profile::Eks.allowed_paths();
+        let kubeadm = Profile::Kubeadm.allowed_paths();
+        assert!(eks.mount_restricted.starts_with(&kubeadm.mount_restricted));
+        assert_eq!(
+            verdict::mount(
+                &eks,
+                ContainerPolicyLevel::Restricted,
+                "bind",
+                "/var/run/aws-node"
+            ),
+            Verdict::Allow
+        );
+        assert_eq!(
+            verdict::mount(
+                &kubeadm,
+                ContainerPolicyLevel::Restricted,
+                "bind",
+                "/var/run/aws-node"
+            ),
+            Verdict::Deny
+        );
+        assert_eq!(
+            verdict::mount(
+                &Profile::Aks.allowed_paths(),
+                ContainerPolicyLevel::Baseline,
+                "bind",
+                "/etc/kubernetes/azure.json"
+            ),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn profile_mount_verdicts() {
+        let docker = Profile::Docker.allowed_paths();
+        let k3s = Profile::K3s.allowed_paths();
+        let volume = "/var/lib/kubelet/pods/1234/volumes";
+
+        assert_eq!(
+            verdict::mount(&docker, ContainerPolicyLevel::Restricted, "bind", volume),
+            Verdict::Deny
+        );
+        assert_eq!(
+            verdict::mount(&k3s, ContainerPolicyLevel::Restricted, "bind", volume),
+            Verdict::Allow
+        );
+        assert_eq!(
+            verdict::mount(&k3s, ContainerPolicyLevel::Restricted, "bind", "/home"),
+            Verdict::Deny
+        );
+        assert_eq!(
+            verdict::mount(&k3s, ContainerPolicyLevel::Baseline, "bind", "/home/user"),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn runtime_sockets_denied() {
+        let mut paths = Profile::K3s.allowed_paths();
+        let socket = "/run/k3s/containerd/containerd.sock";
+        assert_eq!(
+            verdict::file_open(&paths, ContainerPolicyLevel::Baseline, socket),
+            Verdict::Allow
+        );
+
+        paths.deny_runtime_sockets();
+        for policy_level in [
+            ContainerPolicyLevel::Restricted,
+            ContainerPolicyLevel::Baseline,
+        ] {
+            assert_eq!(
+                verdict::file_open(&paths, policy_level, socket),
+                Verdict::Deny
+            );
+            assert_eq!(
+                verdict::file_open(&paths, policy_level, "/var/run/docker.sock"),
+                Verdict::Deny
+            );
+        }
+        assert_eq!(
+            verdict::file_open(&paths, ContainerPolicyLevel::Privileged, socket),
+            Verdict::Allow
+        );
+    }
+
+    #[test]
+    fn profile_file_open_verdicts() {
+        let paths = Profile::Kubeadm.allowed_paths();
+        assert_eq!(
+            verdict::file_open(&paths, ContainerPolicyLevel::Offline, "/proc/sys/kernel"),
+            Verdict::Deny
+        );
+        assert_eq!(
+            verdict::file_open(&paths, ContainerPolicyLevel::Baseline, "/proc/sys/kernel"),
+            Verdict::Allow
+        );
+        assert_eq!(
+            verdict::file_open(
+                &paths,
+                ContainerPolicyLevel::Baseline,
+                "/sys/fs/cgroup/memory.max"
+            ),
+            Verdict::Allow
+        );
+        assert_eq!(
+            verdict::file_open(&paths, ContainerPolicyLevel::Baseline, "/sys/kernel/debug"),
+            Verdict::Deny
+        );
+    }
+
+    #[test]
+    fn allowed_paths_extend() {
+        let mut paths = Profile::Docker.allowed_paths();
+        paths.extend(&AllowedPaths {
+            mount_restricted: vec!["/srv/data".to_string(), "/dev/pts".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            paths.mount_restricted,
+            vec!["/dev/pts", "/var/lib/docker", "/srv/data"]
+        );
+        assert!(!paths.mount_baseline.contains(&"/srv/data".to_string()));
+    }
+
+    #[test]
+    fn allowed_paths_prefixes_deny_wins() {
+        let paths = AllowedPaths {
+            access_baseline: vec!["/sys/kernel".to_string()],
+            denied_access_baseline: vec!["/sys/kernel".to_string(), "/proc/acpi".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            paths.prefixes(),
+            vec![
+                (
+                    PathClass::AccessBaseline,
+                    "/sys/kernel",
+                    FilePermission::Deny
+                ),
+                (
+                    PathClass::AccessBaseline,
+                    "/proc/acpi",
+                    FilePermission::Deny
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowed_paths_prefixes_responses() {
+        let mut paths = AllowedPaths {
+            access_restricted: vec!["/usr/bin/runc".to_string()],
+            ..Default::default()
+        };
+        paths.extend(&AllowedPaths {
+            responses: BTreeMap::from([
+                ("/usr/bin/runc".to_string(), DenyResponse::Kill),
+                ("/etc/shadow".to_string(), DenyResponse::Audit),
+            ]),
+            ..Default::default()
+        });
+        // Audited paths are not denied by path.
+        assert_eq!(
+            paths.prefixes(),
+            vec![
+                (
+                    PathClass::AccessRestricted,
+                    "/usr/bin/runc",
+                    FilePermission::Deny
+                ),
+                (
+                    PathClass::AccessBaseline,
+                    "/usr/bin/runc",
+                    FilePermission::Deny
+                ),
+            ]
+        );
+    }
+}
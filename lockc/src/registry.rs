@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+
+use lockc_common::{control::ContainerInfo, ContainerPolicyLevel, Enforcement};
+
+use crate::cgroups::ContainerCgroup;
+
+/// Human-readable metadata of a container, retrieved from its bundle.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContainerMetadata {
+    pub name: Option<String>,
+    /// Name of the Kubernetes pod.
+    pub pod: Option<String>,
+    /// Kubernetes namespace, or containerd namespace for containers created
+    /// by containerd clients (nerdctl, ctr).
+    pub namespace: Option<String>,
+    pub image: Option<String>,
+    /// ID of the container in which this container is nested.
+    pub parent: Option<String>,
+    /// Directory with the state of the container (`runc --root`), which
+    /// tells apart containers with the same ID in different containerd
+    /// namespaces or Docker instances.
+    pub root: Option<String>,
+    /// ID of the container in the runtime, if it differs from its key.
+    pub runtime_id: Option<String>,
+}
+
+/// Registry of containers known to lockc. eBPF maps store only container
+/// IDs, the registry maps them to names used in logs and control API
+/// responses.
+#[derive(Default)]
+pub struct ContainerRegistry {
+    containers: HashMap<String, ContainerInfo>,
+    /// Cgroups of containers, resolved when their init processes are
+    /// registered.
+    cgroups: HashMap<String, ContainerCgroup>,
+}
+
+impl ContainerRegistry {
+    pub fn insert(
+        &mut self,
+        container_id: String,
+        policy_level: ContainerPolicyLevel,
+        metadata: ContainerMetadata,
+    ) {
+        self.containers.insert(
+            container_id.clone(),
+            ContainerInfo {
+                id: container_id,
+                name: metadata.name,
+                pod: metadata.pod,
+                namespace: metadata.namespace,
+                image: metadata.image,
+                parent: metadata.parent,
+                root: metadata.root,
+                runtime_id: metadata.runtime_id,
+                policy_level,
+                enforcement: Enforcement::Enforce,
+            },
+        );
+    }
+
+    /// Records the enforcement mode of the container. Returns `false` if the
+    /// container is not registered.
+    pub fn set_enforcement(&mut self, container_id: &str, enforcement: Enforcement) -> bool {
+        match self.containers.get_mut(container_id) {
+            Some(info) => {
+                info.enforcement = enforcement;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records the cgroup of the container. Returns `false` if the container
+    /// is not registered.
+    pub fn set_cgroup(&mut self, container_id: &str, cgroup: ContainerCgroup) -> bool {
+        if !self.containers.contains_key(container_id) {
+            return false;
+        }
+        self.cgroups.insert(container_id.to_string(), cgroup);
+        true
+    }
+
+    /// Registers a container handed over by the previous instance of lockc,
+    /// unless this instance already registered it. Returns `false` if the
+    /// container was already registered.
+    pub fn restore(&mut self, info: ContainerInfo, cgroup: Option<ContainerCgroup>) -> bool {
+        if self.containers.contains_key(&info.id) {
+            return false;
+        }
+        if let Some(cgroup) = cgroup {
+            self.cgroups.insert(info.id.clone(), cgroup);
+        }
+        self.containers.insert(info.id.clone(), info);
+        true
+    }
+
+    pub fn remove(&mut self, container_id: &str) -> Option<ContainerInfo> {
+        self.cgroups.remove(container_id);
+        self.containers.remove(container_id)
+    }
+
+    pub fn get(&self, container_id: &str) -> Option<&ContainerInfo> {
+        self.containers.get(container_id)
+    }
+
+    pub fn cgroup(&self, container_id: &str) -> Option<&ContainerCgroup> {
+        self.cgroups.get(container_id)
+    }
+
+    /// Returns IDs of containers nested in the given container, including
+    /// containers nested in them.
+    pub fn descendants(&self, container_id: &str) -> Vec<String> {
+        let mut descendants = Vec::new();
+        let mut parents = vec![container_id.to_string()];
+        while let Some(parent) = parents.pop() {
+            for info in self.containers.values() {
+                if info.parent.as_deref() == Some(parent.as_str()) {
+                    descendants.push(info.id.clone());
+                    parents.push(info.id.clone());
+                }
+            }
+        }
+        descendants
+    }
+
+    /// Returns all registered containers, sorted by ID.
+    pub fn list(&self) -> Vec<ContainerInfo> {
+        let mut containers: Vec<ContainerInfo> = self.containers.values().cloned().collect();
+        containers.sort_by(|a, b| a.id.cmp(&b.id));
+        containers
+    }
+
+    /// Returns containers identified by the given key or runtime ID, sorted
+    /// by key. Truncated IDs are matched only if no container has exactly
+    /// the given ID. Containers with the same runtime ID in different runc
+    /// roots are all returned.
+    pub fn resolve(&self, id: &str) -> Vec<ContainerInfo> {
+        let exact: Vec<&ContainerInfo> = self
+            .containers
+            .values()
+            .filter(|info| info.id == id || info.runtime_id() == id)
+            .collect();
+        let mut containers: Vec<ContainerInfo> = if exact.is_empty() {
+            self.containers
+                .values()
+                .filter(|info| info.matches(id))
+                .cloned()
+                .collect()
+        } else {
+            exact.into_iter().cloned().collect()
+        };
+        containers.sort_by(|a, b| a.id.cmp(&b.id));
+        containers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registry_insert_remove() {
+        let mut registry = ContainerRegistry::default();
+        registry.insert(
+            "b".to_string(),
+            ContainerPolicyLevel::Baseline,
+            ContainerMetadata {
+                name: Some("nginx".to_string()),
+                pod: Some("web".to_string()),
+                namespace: Some("default".to_string()),
+                image: Some("docker.io/library/nginx:latest".to_string()),
+                parent: None,
+                root: None,
+                runtime_id: None,
+            },
+        );
+        registry.insert(
+            "a".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata::default(),
+        );
+
+        assert_eq!(registry.get("b").unwrap().pod.as_deref(), Some("web"));
+        let ids: Vec<String> = registry.list().into_iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+
+        assert_eq!(registry.get("a").unwrap().enforcement, Enforcement::Enforce);
+        assert!(registry.set_enforcement("a", Enforcement::Complain));
+        assert_eq!(
+            registry.get("a").unwrap().enforcement,
+            Enforcement::Complain
+        );
+        assert!(!registry.set_enforcement("c", Enforcement::Complain));
+
+        let cgroup = ContainerCgroup {
+            path: "/sys/fs/cgroup/docker/b".into(),
+            id: 42,
+        };
+        assert!(registry.set_cgroup("b", cgroup.clone()));
+        assert!(!registry.set_cgroup("c", cgroup.clone()));
+        assert_eq!(registry.cgroup("b"), Some(&cgroup));
+
+        assert!(registry.remove("b").is_some());
+        assert!(registry.get("b").is_none());
+        assert!(registry.cgroup("b").is_none());
+        assert!(registry.remove("b").is_none());
+    }
+
+    #[test]
+    fn registry_restore() {
+        let mut registry = ContainerRegistry::default();
+        registry.insert(
+            "a".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata::default(),
+        );
+
+        let mut handed_over = ContainerRegistry::default();
+        handed_over.insert(
+            "a".to_string(),
+            ContainerPolicyLevel::Baseline,
+            ContainerMetadata::default(),
+        );
+        handed_over.insert(
+            "b".to_string(),
+            ContainerPolicyLevel::Baseline,
+            ContainerMetadata::default(),
+        );
+        handed_over.set_enforcement("b", Enforcement::Complain);
+        let cgroup = ContainerCgroup {
+            path: "/sys/fs/cgroup/docker/b".into(),
+            id: 42,
+        };
+        for info in handed_over.list() {
+            let cgroup = (info.id == "b").then(|| cgroup.clone());
+            registry.restore(info, cgroup);
+        }
+
+        // Containers registered by this instance are not replaced.
+        assert_eq!(
+            registry.get("a").unwrap().policy_level,
+            ContainerPolicyLevel::Restricted
+        );
+        assert_eq!(
+            registry.get("b").unwrap().enforcement,
+            Enforcement::Complain
+        );
+        assert_eq!(registry.cgroup("b"), Some(&cgroup));
+    }
+
+    #[test]
+    fn registry_descendants() {
+        let mut registry = ContainerRegistry::default();
+        let nested = |parent: &str| ContainerMetadata {
+            parent: Some(parent.to_string()),
+            ..Default::default()
+        };
+        registry.insert(
+            "dind".to_string(),
+            ContainerPolicyLevel::Privileged,
+            ContainerMetadata::default(),
+        );
+        registry.insert(
+            "inner".to_string(),
+            ContainerPolicyLevel::Baseline,
+            nested("dind"),
+        );
+        registry.insert(
+            "innermost".to_string(),
+            ContainerPolicyLevel::Baseline,
+            nested("inner"),
+        );
+        registry.insert(
+            "other".to_string(),
+            ContainerPolicyLevel::Baseline,
+            ContainerMetadata::default(),
+        );
+
+        let mut descendants = registry.descendants("dind");
+        descendants.sort();
+        assert_eq!(descendants, vec!["inner", "innermost"]);
+        assert!(registry.descendants("other").is_empty());
+    }
+
+    #[test]
+    fn registry_resolve() {
+        let mut registry = ContainerRegistry::default();
+        let in_root = |root: &str| ContainerMetadata {
+            root: Some(root.to_string()),
+            runtime_id: Some("4f7a1c2e9b3d5f6a".to_string()),
+            ..Default::default()
+        };
+        registry.insert(
+            "4f7a1c2e9b3d5f6a".to_string(),
+            ContainerPolicyLevel::Baseline,
+            ContainerMetadata::default(),
+        );
+        registry.insert(
+            "0123abcd-4f7a1c2e9b3d5f6a".to_string(),
+            ContainerPolicyLevel::Baseline,
+            in_root("/run/containerd/runc/default"),
+        );
+        registry.insert(
+            "4f7a1c2e9b3d5f6b".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata::default(),
+        );
+
+        let ids =
+            |id: &str| -> Vec<String> { registry.resolve(id).into_iter().map(|c| c.id).collect() };
+        // The same runtime ID in different roots.
+        assert_eq!(
+            ids("4f7a1c2e9b3d5f6a"),
+            vec!["0123abcd-4f7a1c2e9b3d5f6a", "4f7a1c2e9b3d5f6a"]
+        );
+        assert_eq!(
+            ids("0123abcd-4f7a1c2e9b3d5f6a"),
+            vec!["0123abcd-4f7a1c2e9b3d5f6a"]
+        );
+        // Truncated by crictl.
+        assert_eq!(
+            ids("4f7a1c2e9b3d5"),
+            vec![
+                "0123abcd-4f7a1c2e9b3d5f6a",
+                "4f7a1c2e9b3d5f6a",
+                "4f7a1c2e9b3d5f6b"
+            ]
+        );
+        assert_eq!(ids("0123abcd"), vec!["0123abcd-4f7a1c2e9b3d5f6a"]);
+        assert!(ids("5f6a").is_empty());
+    }
+}
@@ -0,0 +1,496 @@
+//! Reporter mode. Registered containers and summaries of violations are
+//! pushed to `lockc-collector` on an interval, over HTTP or HTTPS with
+//! optional client certificates, so violations of the whole fleet can be
+//! seen in one place without Prometheus.
+
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use lockc_common::{
+    http,
+    report::{NodeReport, ViolationSummary, COLLECTOR_PORT},
+    Enforcement, Hook,
+};
+use openssl::{
+    error::ErrorStack,
+    ssl::{SslConnector, SslFiletype, SslMethod},
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::broadcast::{self, error::RecvError},
+    time::{timeout, MissedTickBehavior},
+};
+use tokio_openssl::SslStream;
+use tracing::{debug, warn};
+
+use crate::{
+    falco::{hostname, rfc3339},
+    registry::ContainerRegistry,
+    settings,
+    violations::ViolationEvent,
+};
+
+/// Time after which an unanswered report fails.
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum ReporterError {
+    #[error("invalid URL of the collector {0:?}, expected http(s)://host[:port][/path]")]
+    Url(String),
+
+    #[error("could not set up TLS: {0}")]
+    Tls(#[from] ErrorStack),
+
+    #[error("could not load {}: {source}", .path.display())]
+    Certificate {
+        path: PathBuf,
+        #[source]
+        source: ErrorStack,
+    },
+
+    #[error("could not connect to {address}: {source}")]
+    Connect {
+        address: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("TLS handshake with {address} failed: {source}")]
+    Handshake {
+        address: String,
+        #[source]
+        source: openssl::ssl::Error,
+    },
+
+    #[error("could not send the report: {0}")]
+    Send(#[from] io::Error),
+
+    #[error("collector did not answer in {0:?}")]
+    Timeout(Duration),
+
+    #[error("collector rejected the report: {0}")]
+    Rejected(String),
+}
+
+/// Address of the collector.
+#[derive(Debug, PartialEq, Eq)]
+struct Endpoint {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = ReporterError;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = || ReporterError::Url(url.to_string());
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        // IPv6 addresses are in brackets, e.g. `[::1]:9849`.
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => COLLECTOR_PORT,
+        };
+        Ok(Endpoint {
+            tls,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Summaries of violations since the last delivered report.
+#[derive(Default)]
+pub struct ViolationSummaries(HashMap<(String, Hook, Enforcement), ViolationSummary>);
+
+impl ViolationSummaries {
+    fn record(&mut self, event: &ViolationEvent) {
+        let summary = self
+            .0
+            .entry((event.container_id.clone(), event.hook, event.enforcement))
+            .or_insert_with(|| ViolationSummary {
+                container_id: event.container_id.clone(),
+                hook: event.hook,
+                enforcement: event.enforcement,
+                count: 0,
+                last_detail: None,
+            });
+        summary.count += 1 + event.suppressed;
+        if event.detail.is_some() {
+            summary.last_detail = event.detail.clone();
+        }
+    }
+
+    fn take(&mut self) -> Vec<ViolationSummary> {
+        self.0.drain().map(|(_, summary)| summary).collect()
+    }
+
+    /// Puts back summaries of a report which wasn't delivered. Violations
+    /// recorded in the meantime keep their last detail.
+    fn restore(&mut self, summaries: Vec<ViolationSummary>) {
+        for summary in summaries {
+            let key = (
+                summary.container_id.clone(),
+                summary.hook,
+                summary.enforcement,
+            );
+            match self.0.get_mut(&key) {
+                Some(newer) => newer.count += summary.count,
+                None => {
+                    self.0.insert(key, summary);
+                }
+            }
+        }
+    }
+}
+
+/// Summarizes violations for reports, until all senders are dropped.
+pub async fn record_violations(
+    mut rx: broadcast::Receiver<ViolationEvent>,
+    summaries: Arc<Mutex<ViolationSummaries>>,
+) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "reporter lags behind violations");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        match summaries.lock() {
+            Ok(mut summaries) => summaries.record(&event),
+            Err(_) => {
+                warn!("summaries of violations are poisoned");
+                break;
+            }
+        }
+    }
+    debug!("summarizing violations for reports finished");
+}
+
+/// Sends reports to the collector.
+pub struct Reporter {
+    endpoint: Endpoint,
+    connector: Option<SslConnector>,
+    node: String,
+    interval: Duration,
+}
+
+impl Reporter {
+    /// Returns the reporter, if the URL of the collector is configured.
+    /// Certificates are loaded right away, so they can be readable only by
+    /// root.
+    pub fn new(settings: &settings::Reporter) -> Result<Option<Self>, ReporterError> {
+        let url = match &settings.url {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let endpoint: Endpoint = url.parse()?;
+        let connector = if endpoint.tls {
+            let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+            if let Some(path) = &settings.ca_cert {
+                builder
+                    .set_ca_file(path)
+                    .map_err(|source| ReporterError::Certificate {
+                        path: path.clone(),
+                        source,
+                    })?;
+            }
+            if let (Some(cert), Some(key)) = (&settings.client_cert, &settings.client_key) {
+                builder.set_certificate_chain_file(cert).map_err(|source| {
+                    ReporterError::Certificate {
+                        path: cert.clone(),
+                        source,
+                    }
+                })?;
+                builder
+                    .set_private_key_file(key, SslFiletype::PEM)
+                    .map_err(|source| ReporterError::Certificate {
+                        path: key.clone(),
+                        source,
+                    })?;
+                builder.check_private_key()?;
+            }
+            Some(builder.build())
+        } else {
+            if settings.client_cert.is_some() {
+                warn!(
+                    url = url.as_str(),
+                    "client certificate is not used, the collector URL is not https"
+                );
+            }
+            None
+        };
+        Ok(Some(Reporter {
+            endpoint,
+            connector,
+            node: settings.node.clone().unwrap_or_else(hostname),
+            interval: Duration::from_secs(settings.interval_s),
+        }))
+    }
+
+    async fn send(&self, report: &NodeReport) -> Result<(), ReporterError> {
+        let body = serde_json::to_vec(report).map_err(io::Error::from)?;
+        let address = self.endpoint.address();
+        let tcp = TcpStream::connect(&address)
+            .await
+            .map_err(|source| ReporterError::Connect {
+                address: address.clone(),
+                source,
+            })?;
+        match &self.connector {
+            Some(connector) => {
+                let ssl = connector.configure()?.into_ssl(&self.endpoint.host)?;
+                let mut stream = SslStream::new(ssl, tcp)?;
+                Pin::new(&mut stream)
+                    .connect()
+                    .await
+                    .map_err(|source| ReporterError::Handshake { address, source })?;
+                post(&mut stream, &self.endpoint, &body).await
+            }
+            None => {
+                let mut stream = tcp;
+                post(&mut stream, &self.endpoint, &body).await
+            }
+        }
+    }
+}
+
+/// Posts the body and checks the status of the response.
+async fn post<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    endpoint: &Endpoint,
+    body: &[u8],
+) -> Result<(), ReporterError> {
+    let status_line = http::post(stream, &endpoint.address(), &endpoint.path, body).await?;
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(ReporterError::Rejected(status_line)),
+    }
+}
+
+/// Sends a report on every interval. Summaries of violations of a failed
+/// report are sent with the next one.
+pub async fn report(
+    reporter: Reporter,
+    containers: Arc<RwLock<ContainerRegistry>>,
+    summaries: Arc<Mutex<ViolationSummaries>>,
+) {
+    let mut interval = tokio::time::interval(reporter.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let containers = match containers.read() {
+            Ok(containers) => containers.list(),
+            Err(_) => {
+                warn!("container registry is poisoned, reports stopped");
+                break;
+            }
+        };
+        let violations = match summaries.lock() {
+            Ok(mut summaries) => summaries.take(),
+            Err(_) => {
+                warn!("summaries of violations are poisoned, reports stopped");
+                break;
+            }
+        };
+        let report = NodeReport {
+            node: reporter.node.clone(),
+            time: rfc3339(SystemTime::now()),
+            interval_s: reporter.interval.as_secs(),
+            containers,
+            violations,
+        };
+        let res = match timeout(SEND_TIMEOUT, reporter.send(&report)).await {
+            Ok(res) => res,
+            Err(_) => Err(ReporterError::Timeout(SEND_TIMEOUT)),
+        };
+        match res {
+            Ok(()) => debug!(
+                containers = report.containers.len(),
+                violations = report.violations.len(),
+                "report sent to the collector"
+            ),
+            Err(e) => {
+                warn!(
+                    collector = reporter.endpoint.address().as_str(),
+                    error = e.to_string().as_str(),
+                    "could not send the report to the collector"
+                );
+                if let Ok(mut summaries) = summaries.lock() {
+                    summaries.restore(report.violations);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::violations::test_event;
+
+    fn event(hook: Hook, detail: &str, suppressed: u64) -> ViolationEvent {
+        ViolationEvent {
+            detail: Some(detail.to_string()),
+            suppressed,
+            ..test_event(hook)
+        }
+    }
+
+    #[test]
+    fn endpoint_parse() {
+        assert_eq!(
+            "https://collector:9000/lockc/report"
+                .parse::<Endpoint>()
+                .unwrap(),
+            Endpoint {
+                tls: true,
+                host: "collector".to_string(),
+                port: 9000,
+                path: "/lockc/report".to_string(),
+            }
+        );
+        let endpoint: Endpoint = "http://[::1]".parse().unwrap();
+        assert_eq!(endpoint.port, COLLECTOR_PORT);
+        assert_eq!(endpoint.path, "/");
+        assert_eq!(endpoint.address(), "[::1]:9849");
+        for url in [
+            "collector:9849",
+            "ftp://collector",
+            "http://",
+            "http://c:x/",
+        ] {
+            assert!(matches!(
+                url.parse::<Endpoint>(),
+                Err(ReporterError::Url(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn violation_summaries() {
+        let mut summaries = ViolationSummaries::default();
+        summaries.record(&event(Hook::FileOpen, "/etc/shadow", 0));
+        summaries.record(&event(Hook::FileOpen, "/etc/gshadow", 2));
+        summaries.record(&event(Hook::SbMount, "/", 0));
+        let mut taken = summaries.take();
+        taken.sort_by_key(|summary| summary.count);
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[1].count, 4);
+        assert_eq!(taken[1].last_detail.as_deref(), Some("/etc/gshadow"));
+        assert!(summaries.take().is_empty());
+
+        // Counts of undelivered summaries are added to newer ones.
+        summaries.record(&event(Hook::FileOpen, "/etc/passwd", 0));
+        summaries.restore(taken);
+        let mut taken = summaries.take();
+        taken.sort_by_key(|summary| summary.count);
+        assert_eq!(taken[1].count, 5);
+        assert_eq!(taken[1].last_detail.as_deref(), Some("/etc/passwd"));
+    }
+
+    #[tokio::test]
+    async fn reporter_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let collector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // The request ends with the JSON body.
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let reporter = Reporter::new(&settings::Reporter {
+            url: Some(format!("http://{}/report", addr)),
+            node: Some("node-1".to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+        let report = NodeReport {
+            node: reporter.node.clone(),
+            time: rfc3339(SystemTime::UNIX_EPOCH),
+            interval_s: 60,
+            containers: Vec::new(),
+            violations: vec![ViolationSummary {
+                container_id: "abc".to_string(),
+                hook: Hook::FileOpen,
+                enforcement: Enforcement::Enforce,
+                count: 1,
+                last_detail: None,
+            }],
+        };
+        reporter.send(&report).await.unwrap();
+
+        let request = collector.await.unwrap();
+        assert!(request.starts_with("POST /report HTTP/1.1\r\n"));
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        let received: NodeReport = serde_json::from_str(body).unwrap();
+        assert_eq!(received.node, "node-1");
+        assert_eq!(received.violations, report.violations);
+
+        assert!(Reporter::new(&settings::Reporter::default())
+            .unwrap()
+            .is_none());
+    }
+}
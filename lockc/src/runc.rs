@@ -1,33 +1,142 @@
-use std::{collections, fs, io, os::unix::fs::PermissionsExt, path::Path, string::String};
+use std::{
+    collections, fs, io,
+    mem::{self, ManuallyDrop},
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    string::String,
+    sync::{atomic::AtomicU64, Arc},
+    thread,
+    time::{Duration, Instant},
+};
 
 use fanotify::{
-    high_level::{Event, Fanotify, FanotifyMode, FanotifyResponse},
-    low_level::FAN_OPEN_EXEC_PERM,
+    high_level::{Event, FanEvent, Fanotify, FanotifyResponse},
+    low_level::{
+        fanotify_init, fanotify_mark, AT_FDCWD, FAN_AUDIT, FAN_CLASS_CONTENT, FAN_CLOEXEC,
+        FAN_DENY, FAN_ENABLE_AUDIT, FAN_MARK_ADD, FAN_MARK_FILESYSTEM, FAN_OPEN_EXEC_PERM,
+        FAN_UNLIMITED_MARKS, FAN_UNLIMITED_QUEUE, O_CLOEXEC, O_LARGEFILE, O_RDONLY,
+    },
 };
+#[cfg(feature = "kubernetes")]
 use k8s_openapi::api::core::v1;
-use lockc_common::ContainerPolicyLevel;
-use nix::poll::{poll, PollFd, PollFlags};
-use procfs::{process::Process, ProcError};
-use scopeguard::defer;
+use lockc_common::{
+    control::{short_id, PolicySource},
+    ContainerPolicyLevel, ContainerSpec, CustomMounts, Enforcement, IdMapping, InodeId,
+    ID_MAPPINGS_MAX,
+};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+    unistd::close,
+};
+use openssl::sha::sha256;
+use procfs::{
+    process::{all_processes, Process},
+    ProcError,
+};
 use serde::Deserialize;
 use serde_json::Value;
 use thiserror::Error;
-use tokio::{
-    runtime::Builder,
-    sync::{mpsc, oneshot},
+use tokio::{runtime::Builder, sync::oneshot};
+use tracing::{debug, debug_span, error, field, info, info_span, warn, Span};
+
+use crate::{
+    cgroups::{self, CgroupError},
+    communication::{EbpfCommand, EbpfSender, ResponseError, SendCommandError},
+    custom_policies::{CustomPolicies, CustomPolicy},
+    integrity::RuncVerifier,
+    maps::{AddMode, MapOperationError, ProcessContainer},
+    metrics::{Histogram, REGISTRATION_LATENCY_BUCKETS},
+    namespace_policies::NamespacePolicies,
+    pidns::{PidNsError, PidTranslator},
+    registry::ContainerMetadata,
+    settings::{
+        ChannelOverflow, FanotifyFlags, ImagePolicies, KubernetesRuntimes, PrivilegedContainers,
+        PrivilegedMode, ResponseTimeout, RuncWatchMode, Settings, SpecValidationMode, Syslog,
+        UnknownContainerPolicy,
+    },
+    systemd::{Liveness, LIVENESS_INTERVAL},
+    sysutils::pid_ns_depth,
+    validation::{BundleConfig, SpecValidator},
 };
-use tracing::{debug, error, warn};
-use walkdir::WalkDir;
 
-use crate::{communication::EbpfCommand, maps::MapOperationError};
+mod args;
+mod discovery;
+mod pid_file;
+mod recorder;
 
-// static LABEL_NAMESPACE: &str = "io.kubernetes.pod.namespace";
+use args::{ContainerAction, RuncInvocation};
+use discovery::RuncDiscovery;
+use pid_file::{PidFile, PidFileError};
+use recorder::ProcessSnapshot;
+pub use recorder::{read_recording, RecordedEvent, Recorder, RecorderError};
+
+#[cfg(feature = "kubernetes")]
 static LABEL_POLICY_ENFORCE: &str = "pod-security.kubernetes.io/enforce";
 // static LABEL_POLICY_AUDIT: &str = "pod-security.kubernetes.io/audit";
 // static LABEL_POLICY_WARN: &str = "pod-security.kubernetes.io/warn";
 
+/// Namespace, pod and container names of Kubernetes containers, set as
+/// annotations by CRI implementations other than containerd (e.g. CRI-O) and
+/// as labels of Docker containers by cri-dockerd.
+static ANNOTATION_POD_NAMESPACE: &str = "io.kubernetes.pod.namespace";
+static ANNOTATION_POD_NAME: &str = "io.kubernetes.pod.name";
+static ANNOTATION_CONTAINER_NAME: &str = "io.kubernetes.container.name";
+/// Prefix of labels of Docker containers under which cri-dockerd stores
+/// annotations of pods.
+static LABEL_CRI_DOCKERD_ANNOTATION: &str = "annotation.";
 static ANNOTATION_CONTAINERD_LOG_DIRECTORY: &str = "io.kubernetes.cri.sandbox-log-directory";
 static ANNOTATION_CONTAINERD_SANDBOX_ID: &str = "io.kubernetes.cri.sandbox-id";
+static ANNOTATION_CONTAINERD_IMAGE_NAME: &str = "io.kubernetes.cri.image-name";
+static ANNOTATION_OCI_IMAGE_NAME: &str = "org.opencontainers.image.ref.name";
+static ANNOTATION_CONTAINERD_CONTAINER_NAME: &str = "io.kubernetes.cri.container-name";
+static ANNOTATION_CONTAINERD_SANDBOX_NAME: &str = "io.kubernetes.cri.sandbox-name";
+static ANNOTATION_CONTAINERD_SANDBOX_NAMESPACE: &str = "io.kubernetes.cri.sandbox-namespace";
+static ANNOTATION_NERDCTL_NAME: &str = "nerdctl/name";
+static ANNOTATION_NERDCTL_NAMESPACE: &str = "nerdctl/namespace";
+/// Policy of containers created by containerd clients. The same key as the
+/// label used for Docker containers.
+static ANNOTATION_POLICY: &str = "org.lockc.policy";
+/// Policy label of Docker containers in the style of Docker Compose files,
+/// e.g. `labels: [lockc.policy=restricted]`. `org.lockc.policy` takes
+/// precedence.
+static LABEL_POLICY_COMPOSE: &str = "lockc.policy";
+/// Exemption from denials of connecting to sockets of container runtimes,
+/// when set to `allow`. The same key is used for Docker labels and for
+/// annotations.
+static ANNOTATION_RUNTIME_SOCKETS: &str = "org.lockc.runtime-sockets";
+/// Exemption from denials of reading the kernel log, when set to `allow`.
+/// The same key is used for Docker labels and for annotations.
+static ANNOTATION_SYSLOG: &str = "org.lockc.syslog";
+/// Enforcement mode of the container, `enforce` or `complain`. The same key
+/// is used for Docker labels and for annotations.
+static ANNOTATION_ENFORCEMENT: &str = "org.lockc.enforcement";
+/// Policy of a Kubernetes pod resolved from the labels of its namespace by
+/// an admission webhook, which saves the API call when the container is
+/// created.
+static ANNOTATION_RESOLVED_POLICY: &str = "org.lockc.resolved-policy";
+/// Name of the custom policy of the container, defined by a `LockcPolicy`
+/// resource. The same key is used for Docker labels and for annotations.
+static ANNOTATION_CUSTOM_POLICY: &str = "org.lockc.custom-policy";
+
+/// Directory of containerd (runtime v2) with bundles of containers, in
+/// `<namespace>/<container ID>` subdirectories.
+static CONTAINERD_TASK_DIR: &str = "io.containerd.runtime.v2.task";
+
+/// Directory with states of containers when runc is executed without
+/// `--root`.
+static RUNC_DEFAULT_ROOT: &str = "/run/runc";
+/// Directory which containerd-shim passes to runc as `--root`, in
+/// `<namespace>` subdirectories.
+static CONTAINERD_RUNC_ROOT: &str = "/run/containerd/runc";
+
+/// Max length of container keys. eBPF maps store them nul-terminated in 64
+/// bytes.
+const CONTAINER_KEY_MAX: usize = 63;
+
+/// Time to wait for runc to write the PID of the init process of a created
+/// container.
+const PID_FILE_TIMEOUT: Duration = Duration::from_secs(60);
 
 /// Type of Kubernetes container determined by annotations.
 enum KubernetesContainerType {
@@ -36,6 +145,9 @@ enum KubernetesContainerType {
     /// Containerd CRI, part of another sandbox which has its own log
     /// directory.
     ContainerdPartOfSandbox,
+    /// Other CRI implementations, which annotate every container with the
+    /// namespace of its pod.
+    Annotated,
     /// Unknown type of Kubernetes annotations.
     Unknown,
 }
@@ -45,6 +157,8 @@ fn kubernetes_type(annotations: &collections::HashMap<String, String>) -> Kubern
         return KubernetesContainerType::ContainerdMain;
     } else if annotations.contains_key(ANNOTATION_CONTAINERD_SANDBOX_ID) {
         return KubernetesContainerType::ContainerdPartOfSandbox;
+    } else if annotations.contains_key(ANNOTATION_POD_NAMESPACE) {
+        return KubernetesContainerType::Annotated;
     }
     KubernetesContainerType::Unknown
 }
@@ -52,21 +166,286 @@ fn kubernetes_type(annotations: &collections::HashMap<String, String>) -> Kubern
 /// Type of container by engine/runtime.
 enum ContainerType {
     Docker,
-    KubernetesContainerd,
+    /// Kubernetes container created by containerd or another CRI
+    /// implementation. Containers created by cri-dockerd are Docker
+    /// containers.
+    Kubernetes,
+    /// Container created by a containerd client without Kubernetes (nerdctl,
+    /// ctr).
+    Containerd,
     Unknown,
 }
 
+impl ContainerType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ContainerType::Docker => "docker",
+            ContainerType::Kubernetes => "kubernetes",
+            ContainerType::Containerd => "containerd",
+            ContainerType::Unknown => "unknown",
+        }
+    }
+}
+
+/// Information about a container retrieved from its bundle.
+struct ContainerData {
+    container_type: ContainerType,
+    /// Engine-specific data - Kubernetes namespace, path to Docker's
+    /// config.v2.json or the policy annotation of a containerd container.
+    data: Option<String>,
+    /// Human-readable metadata, if exposed by the engine in annotations.
+    metadata: ContainerMetadata,
+    /// Data from the runtime spec stored in eBPF maps.
+    spec: ContainerSpec,
+    /// Parts of the runtime spec validated against the policy.
+    config: BundleConfig,
+    /// Enforcement mode from the annotation.
+    enforcement: Enforcement,
+    /// Exemption from denials of reading the kernel log from the annotation.
+    syslog: bool,
+    /// Policy of a Kubernetes container resolved by an admission webhook.
+    resolved_policy: Option<ContainerPolicyLevel>,
+    /// Name of the custom policy from the annotation.
+    custom_policy: Option<String>,
+    /// Whether the Kubernetes namespace comes from annotations which only
+    /// trusted runtimes are allowed to set.
+    annotated: bool,
+}
+
+/// Returns the enforcement mode from the value of the enforcement label or
+/// annotation. Containers without a valid value are enforced.
+fn enforcement(value: Option<&str>) -> Enforcement {
+    match value.map(str::parse) {
+        Some(Ok(enforcement)) => enforcement,
+        Some(Err(e)) => {
+            warn!(error = e.to_string().as_str(), "enforcing the container");
+            Enforcement::Enforce
+        }
+        None => Enforcement::Enforce,
+    }
+}
+
+/// Returns the policy resolved by an admission webhook from the annotation,
+/// if it's valid.
+fn resolved_policy(
+    annotations: &collections::HashMap<String, String>,
+) -> Option<ContainerPolicyLevel> {
+    parse_resolved_policy(annotations.get(ANNOTATION_RESOLVED_POLICY)?)
+}
+
+fn parse_resolved_policy(value: &str) -> Option<ContainerPolicyLevel> {
+    match value.parse() {
+        Ok(policy_level) => Some(policy_level),
+        Err(e) => {
+            warn!(
+                error = e.to_string().as_str(),
+                "invalid resolved policy annotation, resolving the policy from the namespace"
+            );
+            None
+        }
+    }
+}
+
+/// Returns the key of the container in eBPF maps and in the registry.
+/// Container IDs are unique only within a runc root (e.g. a containerd
+/// namespace or a Docker instance), so IDs of containers outside of the
+/// default root are prefixed with a digest of the root.
+pub(crate) fn container_key(root: Option<&str>, container_id: &str) -> String {
+    let root = root
+        .map(|root| root.trim_end_matches('/'))
+        .filter(|root| !root.is_empty() && *root != RUNC_DEFAULT_ROOT);
+    let root = match root {
+        Some(root) => root,
+        None => return container_id.to_string(),
+    };
+    let root_digest = sha256(root.as_bytes());
+    let mut key = format!("{}-{}", hex::encode(&root_digest[..4]), container_id);
+    while key.len() > CONTAINER_KEY_MAX {
+        key.pop();
+    }
+    key
+}
+
+/// Returns the key of the container created by containerd in the given
+/// namespace, for events which don't come with the runc root.
+pub(crate) fn containerd_container_key(namespace: &str, container_id: &str) -> String {
+    container_key(
+        Some(&format!("{}/{}", CONTAINERD_RUNC_ROOT, namespace)),
+        container_id,
+    )
+}
+
+/// Returns the Kubernetes namespace from the annotations, if set by the CRI
+/// implementation.
+fn kubernetes_namespace(annotations: &collections::HashMap<String, String>) -> Option<&String> {
+    annotations
+        .get(ANNOTATION_CONTAINERD_SANDBOX_NAMESPACE)
+        .or_else(|| annotations.get(ANNOTATION_POD_NAMESPACE))
+}
+
+/// Parses the Kubernetes namespace from the log directory of the pod
+/// (`/var/log/pods/<namespace>_<pod>_<uid>`), for containerd versions which
+/// don't annotate containers with the namespace.
+fn namespace_from_log_directory(log_directory: &str) -> Result<String, ContainerError> {
+    let log_path = Path::new(log_directory);
+    let file_name = log_path
+        .file_name()
+        .ok_or(ContainerError::LogFileName)?
+        .to_str()
+        .ok_or(ContainerError::LogFileName)?;
+    file_name
+        .split('_')
+        .next()
+        .filter(|namespace| !namespace.is_empty())
+        .map(String::from)
+        .ok_or(ContainerError::K8sNamespace)
+}
+
+/// Returns the container metadata from its annotations.
+fn metadata_from_annotations(
+    annotations: &collections::HashMap<String, String>,
+) -> ContainerMetadata {
+    ContainerMetadata {
+        name: annotations
+            .get(ANNOTATION_CONTAINERD_CONTAINER_NAME)
+            .or_else(|| annotations.get(ANNOTATION_CONTAINER_NAME))
+            .cloned(),
+        pod: annotations
+            .get(ANNOTATION_CONTAINERD_SANDBOX_NAME)
+            .or_else(|| annotations.get(ANNOTATION_POD_NAME))
+            .cloned(),
+        namespace: kubernetes_namespace(annotations).cloned(),
+        image: annotations
+            .get(ANNOTATION_CONTAINERD_IMAGE_NAME)
+            .or_else(|| annotations.get(ANNOTATION_OCI_IMAGE_NAME))
+            .cloned(),
+        parent: None,
+        root: None,
+        runtime_id: None,
+    }
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Mount {
     source: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Root {
+    path: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IdMappingConfig {
+    #[serde(rename = "containerID")]
+    container_id: u32,
+    #[serde(rename = "hostID")]
+    host_id: u32,
+    size: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Namespace {
+    #[serde(rename = "type")]
+    ns_type: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct LinuxConfig {
+    uid_mappings: Vec<IdMappingConfig>,
+    gid_mappings: Vec<IdMappingConfig>,
+    namespaces: Vec<Namespace>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Capabilities {
+    bounding: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ProcessConfig {
+    capabilities: Option<Capabilities>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ContainerConfig {
     mounts: Vec<Mount>,
     annotations: Option<collections::HashMap<String, String>>,
+    root: Option<Root>,
+    #[serde(default)]
+    linux: LinuxConfig,
+    #[serde(default)]
+    process: ProcessConfig,
+}
+
+fn id_mappings(mappings: &[IdMappingConfig]) -> [IdMapping; ID_MAPPINGS_MAX] {
+    if mappings.len() > ID_MAPPINGS_MAX {
+        warn!(
+            mappings = mappings.len(),
+            max = ID_MAPPINGS_MAX,
+            "too many user namespace ID mappings, storing only the first ones"
+        );
+    }
+    let mut res = [IdMapping::default(); ID_MAPPINGS_MAX];
+    for (dst, src) in res.iter_mut().zip(mappings) {
+        *dst = IdMapping {
+            container_id: src.container_id,
+            host_id: src.host_id,
+            size: src.size,
+        };
+    }
+    res
+}
+
+impl ContainerConfig {
+    /// Returns data from the spec stored in eBPF maps. The root filesystem
+    /// path is relative to the bundle, unless it's absolute.
+    fn spec(&self, bundle_path: &Path) -> ContainerSpec {
+        let mut spec = ContainerSpec::default();
+        spec.runtime_sockets = self
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(ANNOTATION_RUNTIME_SOCKETS))
+            .map(|value| value == "allow")
+            .unwrap_or(false);
+        spec.uid_mappings = id_mappings(&self.linux.uid_mappings);
+        spec.gid_mappings = id_mappings(&self.linux.gid_mappings);
+        spec.user_namespace = self.linux.namespaces.iter().any(|ns| ns.ns_type == "user");
+        // Root of the container is either mapped to an unprivileged user or
+        // not mapped at all.
+        spec.rootless = spec.user_namespace
+            && !self
+                .linux
+                .uid_mappings
+                .iter()
+                .any(|m| m.container_id == 0 && m.host_id == 0 && m.size > 0);
+        spec.privileged = self
+            .process
+            .capabilities
+            .as_ref()
+            .map(|caps| caps.bounding.iter().any(|cap| cap == "CAP_SYS_ADMIN"))
+            .unwrap_or(false);
+        if let Some(root) = &self.root {
+            let rootfs = bundle_path.join(&root.path);
+            match fs::metadata(&rootfs) {
+                Ok(metadata) => spec.rootfs = InodeId::from_metadata(&metadata),
+                Err(e) => debug!(
+                    rootfs = ?rootfs,
+                    error = e.to_string().as_str(),
+                    "could not get the inode of the container rootfs"
+                ),
+            }
+        }
+        spec
+    }
 }
 
 #[derive(Error, Debug)]
@@ -84,46 +463,81 @@ pub enum ContainerError {
     K8sNamespace,
 }
 
+/// Reads the spec of a container from the `config.json` file in its bundle.
+/// Used for containers registered through the control API.
+pub fn bundle_spec(bundle_path: &Path) -> Result<ContainerSpec, ContainerError> {
+    let f = fs::File::open(bundle_path.join("config.json"))?;
+    let config: ContainerConfig = serde_json::from_reader(io::BufReader::new(f))?;
+    Ok(config.spec(bundle_path))
+}
+
 fn container_type_data<P: AsRef<std::path::Path>>(
     container_bundle: P,
-) -> Result<(ContainerType, Option<std::string::String>), ContainerError> {
+) -> Result<ContainerData, ContainerError> {
     let bundle_path = container_bundle.as_ref();
     let config_path = bundle_path.join("config.json");
-    let f = fs::File::open(&config_path)?;
-    let r = io::BufReader::new(f);
-
-    let config: ContainerConfig = serde_json::from_reader(r)?;
+    let data = fs::read(&config_path)?;
+
+    let config: ContainerConfig = serde_json::from_slice(&data)?;
+    let spec = config.spec(bundle_path);
+    let enforcement = enforcement(
+        config
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(ANNOTATION_ENFORCEMENT))
+            .map(String::as_str),
+    );
+    let syslog = config
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(ANNOTATION_SYSLOG))
+        .map(|value| value == "allow")
+        .unwrap_or(false);
+    let custom_policy = config
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(ANNOTATION_CUSTOM_POLICY))
+        .cloned();
+    let bundle_config: BundleConfig = serde_json::from_slice(&data)?;
 
     // Kubernetes
-    if let Some(annotations) = config.annotations {
+    if let Some(annotations) = &config.annotations {
         debug!(
             bundle = ?bundle_path,
             config = ?config_path,
             "detected kubernetes container",
         );
-        match kubernetes_type(&annotations) {
+        match kubernetes_type(annotations) {
             KubernetesContainerType::ContainerdMain => {
-                // containerd doesn't expose k8s namespaces directly. They have
-                // to be parsed from the log directory path, where the first
-                // part of the filename is the namespace.
+                // Older versions of containerd don't annotate containers with
+                // the namespace. Then it has to be parsed from the log
+                // directory path, where the first part of the filename is the
+                // namespace.
                 let log_directory = &annotations[ANNOTATION_CONTAINERD_LOG_DIRECTORY];
                 debug!(
                     log_directory = log_directory.as_str(),
                     "detected k8s+containerd container",
                 );
-                let log_path = std::path::PathBuf::from(log_directory);
-                let file_name = log_path
-                    .file_name()
-                    .ok_or(ContainerError::LogFileName)?
-                    .to_str()
-                    .ok_or(ContainerError::LogFileName)?;
-                let mut splitter = file_name.split('_');
-                let namespace = splitter
-                    .next()
-                    .ok_or(ContainerError::K8sNamespace)?
-                    .to_string();
-
-                return Ok((ContainerType::KubernetesContainerd, Some(namespace)));
+                let namespace = match annotations.get(ANNOTATION_CONTAINERD_SANDBOX_NAMESPACE) {
+                    Some(namespace) => namespace.clone(),
+                    None => namespace_from_log_directory(log_directory)?,
+                };
+
+                let mut metadata = metadata_from_annotations(annotations);
+                metadata.namespace = Some(namespace.clone());
+
+                return Ok(ContainerData {
+                    container_type: ContainerType::Kubernetes,
+                    data: Some(namespace),
+                    metadata,
+                    spec,
+                    config: bundle_config,
+                    enforcement,
+                    syslog,
+                    resolved_policy: resolved_policy(annotations),
+                    custom_policy,
+                    annotated: false,
+                });
             }
             KubernetesContainerType::ContainerdPartOfSandbox => {
                 // When a container is running as a part of a previously created
@@ -141,18 +555,56 @@ fn container_type_data<P: AsRef<std::path::Path>>(
                 if let Some(v) = ancestors.next() {
                     // Then go to sandbox_id directory (sandbox's bundle).
                     let new_bundle = v.join(sandbox_id);
-                    return container_type_data(new_bundle);
+                    let mut container_data = container_type_data(new_bundle)?;
+                    // The name and image are specific to the container, the
+                    // pod and namespace can be inherited from the sandbox.
+                    let metadata = metadata_from_annotations(annotations);
+                    container_data.metadata = ContainerMetadata {
+                        name: metadata.name,
+                        pod: metadata.pod.or(container_data.metadata.pod),
+                        namespace: metadata.namespace.or(container_data.metadata.namespace),
+                        image: metadata.image,
+                        parent: None,
+                        root: None,
+                        runtime_id: None,
+                    };
+                    container_data.spec = spec;
+                    container_data.config = bundle_config;
+                    container_data.enforcement = enforcement;
+                    // The annotations are usually set on the whole pod, so
+                    // they can be inherited from the sandbox as well.
+                    container_data.syslog |= syslog;
+                    container_data.resolved_policy =
+                        resolved_policy(annotations).or(container_data.resolved_policy);
+                    container_data.custom_policy = custom_policy.or(container_data.custom_policy);
+                    return Ok(container_data);
                 }
             }
+            KubernetesContainerType::Annotated => {
+                let metadata = metadata_from_annotations(annotations);
+                debug!(
+                    namespace = metadata.namespace.as_deref(),
+                    "detected k8s container annotated with its namespace",
+                );
+                return Ok(ContainerData {
+                    container_type: ContainerType::Kubernetes,
+                    data: metadata.namespace.clone(),
+                    metadata,
+                    spec,
+                    config: bundle_config,
+                    enforcement,
+                    syslog,
+                    resolved_policy: resolved_policy(annotations),
+                    custom_policy,
+                    annotated: true,
+                });
+            }
             KubernetesContainerType::Unknown => {}
         }
-        // TODO(vadorovsky): Support more Kubernetes CRI implementations.
-        // They all come with their own annotations, so we will have to
-        // handle more keys here.
     }
 
     // Docker
-    for mount in config.mounts {
+    for mount in &config.mounts {
         let source: Vec<&str> = mount.source.split('/').collect();
         if source.len() > 1 && source[source.len() - 1] == "hostname" {
             let config_v2 = str::replace(&mount.source, "hostname", "config.v2.json");
@@ -160,16 +612,120 @@ fn container_type_data<P: AsRef<std::path::Path>>(
                 config_path = config_v2.as_str(),
                 "detected docker container"
             );
-            return Ok((ContainerType::Docker, Some(config_v2)));
+            return Ok(ContainerData {
+                container_type: ContainerType::Docker,
+                data: Some(config_v2),
+                metadata: ContainerMetadata::default(),
+                spec,
+                config: bundle_config,
+                enforcement,
+                syslog,
+                resolved_policy: None,
+                custom_policy,
+                annotated: false,
+            });
         }
     }
 
-    Ok((ContainerType::Unknown, None))
+    // containerd without Kubernetes. Docker also uses containerd, so it has
+    // to be checked first.
+    let annotations = config.annotations.unwrap_or_default();
+    let namespace = containerd_namespace(bundle_path)
+        .or_else(|| annotations.get(ANNOTATION_NERDCTL_NAMESPACE).cloned());
+    if namespace.is_some() || annotations.contains_key(ANNOTATION_NERDCTL_NAME) {
+        debug!(
+            bundle = ?bundle_path,
+            namespace = namespace.as_deref(),
+            "detected containerd container"
+        );
+        let mut metadata = metadata_from_annotations(&annotations);
+        metadata.name = annotations.get(ANNOTATION_NERDCTL_NAME).cloned();
+        metadata.namespace = namespace;
+        return Ok(ContainerData {
+            container_type: ContainerType::Containerd,
+            data: annotations.get(ANNOTATION_POLICY).cloned(),
+            metadata,
+            spec,
+            config: bundle_config,
+            enforcement,
+            syslog,
+            resolved_policy: None,
+            custom_policy,
+            annotated: false,
+        });
+    }
+
+    Ok(ContainerData {
+        container_type: ContainerType::Unknown,
+        data: None,
+        metadata: ContainerMetadata::default(),
+        spec,
+        config: bundle_config,
+        enforcement,
+        syslog,
+        resolved_policy: None,
+        custom_policy,
+        annotated: false,
+    })
+}
+
+/// Returns the containerd namespace of a container whose bundle is in the
+/// containerd task directory.
+fn containerd_namespace(bundle_path: &Path) -> Option<String> {
+    let mut components = bundle_path.components().rev();
+    // Container ID.
+    components.next()?;
+    let namespace = components.next()?.as_os_str().to_str()?.to_string();
+    match components.next()?.as_os_str().to_str()? {
+        dir if dir == CONTAINERD_TASK_DIR => Some(namespace),
+        _ => None,
+    }
+}
+
+/// Returns the policy level of a container created by a containerd client,
+/// from its policy annotation or, if not set, from the image policy rules.
+fn policy_containerd(
+    policy: Option<&str>,
+    image: Option<&str>,
+    image_policies: &ImagePolicies,
+) -> ContainerPolicyLevel {
+    match policy {
+        Some(policy) => policy.parse().unwrap_or(ContainerPolicyLevel::Baseline),
+        None => policy_image(image, image_policies),
+    }
+}
+
+/// Returns the policy level for containers without an explicit policy label,
+/// based on the image policy rules. If no rule matches, the baseline policy
+/// is returned.
+pub(crate) fn policy_image(
+    image: Option<&str>,
+    image_policies: &ImagePolicies,
+) -> ContainerPolicyLevel {
+    image
+        .and_then(|image| image_policies.policy(image))
+        .unwrap_or(ContainerPolicyLevel::Baseline)
 }
 
-/// Finds the policy for the given Kubernetes namespace. If none, the baseline
-/// policy is returned. Otherwise checks the Kubernetes namespace labels.
-async fn policy_kubernetes(namespace: String) -> Result<ContainerPolicyLevel, kube::Error> {
+/// Returns the policy of a container whose engine wasn't detected. Returns
+/// `None` if the container must not be created.
+fn policy_unknown(policy: UnknownContainerPolicy) -> Option<ContainerPolicyLevel> {
+    match policy {
+        UnknownContainerPolicy::Restricted => Some(ContainerPolicyLevel::Restricted),
+        UnknownContainerPolicy::Baseline => Some(ContainerPolicyLevel::Baseline),
+        UnknownContainerPolicy::Deny => None,
+    }
+}
+
+/// Finds the policy for the given Kubernetes namespace. If none, the policy
+/// is determined by the image policy rules. Otherwise checks the Kubernetes
+/// namespace labels.
+#[cfg(feature = "kubernetes")]
+async fn policy_kubernetes(
+    namespace: String,
+    image: Option<&str>,
+    image_policies: &ImagePolicies,
+) -> Result<ContainerPolicyLevel, kube::Error> {
     // Apply the privileged policy for kube-system containers immediately.
     // Otherwise the core k8s components (apiserver, scheduler) won't be able
     // to run.
@@ -185,18 +741,14 @@ async fn policy_kubernetes(namespace: String) -> Result<ContainerPolicyLevel, ku
 
     match namespace.metadata.labels {
         Some(v) => match v.get(LABEL_POLICY_ENFORCE) {
-            Some(v) => match v.as_str() {
-                "restricted" => Ok(ContainerPolicyLevel::Restricted),
-                "baseline" => Ok(ContainerPolicyLevel::Baseline),
-                "privileged" => Ok(ContainerPolicyLevel::Privileged),
-                _ => Ok(ContainerPolicyLevel::Baseline),
-            },
-            None => Ok(ContainerPolicyLevel::Baseline),
+            Some(v) => Ok(v.parse().unwrap_or(ContainerPolicyLevel::Baseline)),
+            None => Ok(policy_image(image, image_policies)),
         },
-        None => Ok(ContainerPolicyLevel::Baseline),
+        None => Ok(policy_image(image, image_policies)),
     }
 }
 
+#[cfg(feature = "kubernetes")]
 #[derive(Error, Debug)]
 pub enum PolicyKubernetesSyncError {
     #[error(transparent)]
@@ -208,85 +760,301 @@ pub enum PolicyKubernetesSyncError {
 
 /// Makes the `policy_label_sync` function synchronous. We use it together with
 /// poll(2) syscall, which is definitely not meant for multithreaded code.
+#[cfg(feature = "kubernetes")]
 fn policy_kubernetes_sync(
     namespace: String,
+    image: Option<&str>,
+    image_policies: &ImagePolicies,
 ) -> Result<ContainerPolicyLevel, PolicyKubernetesSyncError> {
     match Builder::new_current_thread()
         .enable_all()
         .build()?
-        .block_on(policy_kubernetes(namespace))
+        .block_on(policy_kubernetes(namespace, image, image_policies))
     {
         Ok(p) => Ok(p),
         Err(e) => Err(PolicyKubernetesSyncError::from(e)),
     }
 }
 
-fn policy_docker<P: AsRef<Path>>(docker_bundle: P) -> Result<ContainerPolicyLevel, ContainerError> {
-    let config_path = docker_bundle.as_ref();
-    let f = std::fs::File::open(config_path)?;
-    let r = std::io::BufReader::new(f);
+/// Finds the policy for the given Kubernetes namespace without access to the
+/// API server. Containers in kube-system get the privileged policy, others
+/// get the policy from image rules or the default one.
+pub(crate) fn policy_kubernetes_default(
+    namespace: &str,
+    image: Option<&str>,
+    image_policies: &ImagePolicies,
+    default_policy: ContainerPolicyLevel,
+) -> ContainerPolicyLevel {
+    if namespace == "kube-system" {
+        return ContainerPolicyLevel::Privileged;
+    }
+    image
+        .and_then(|image| image_policies.policy(image))
+        .unwrap_or(default_policy)
+}
 
-    let l: Value = serde_json::from_reader(r)?;
+/// Returns the stricter of the given policy levels. Containers nested in
+/// other containers cannot have a less strict policy than their parent.
+fn policy_nested(
+    parent_policy_level: ContainerPolicyLevel,
+    policy_level: ContainerPolicyLevel,
+) -> ContainerPolicyLevel {
+    fn strictness(policy_level: ContainerPolicyLevel) -> u8 {
+        match policy_level {
+            ContainerPolicyLevel::Offline => 3,
+            ContainerPolicyLevel::Restricted => 2,
+            ContainerPolicyLevel::Baseline => 1,
+            ContainerPolicyLevel::Privileged
+            | ContainerPolicyLevel::Lockc
+            | ContainerPolicyLevel::NotFound => 0,
+        }
+    }
+    if strictness(policy_level) >= strictness(parent_policy_level) {
+        policy_level
+    } else {
+        parent_policy_level
+    }
+}
 
-    let x = l["Config"]["Labels"]["org.lockc.policy"].as_str();
+/// Applies the restrictions of the privileged policy to the policy of a
+/// container in the given namespace. Returns `None` if the container must not
+/// be created.
+pub(crate) fn policy_privileged(
+    policy_level: ContainerPolicyLevel,
+    namespace: Option<&str>,
+    privileged: &PrivilegedContainers,
+) -> Option<ContainerPolicyLevel> {
+    if policy_level != ContainerPolicyLevel::Privileged || privileged.allowed(namespace) {
+        return Some(policy_level);
+    }
+    match privileged.mode {
+        PrivilegedMode::Allow => Some(policy_level),
+        PrivilegedMode::Refuse => Some(ContainerPolicyLevel::Baseline),
+        PrivilegedMode::Strict => None,
+    }
+}
 
-    match x {
-        Some(x) => match x {
-            "restricted" => Ok(ContainerPolicyLevel::Restricted),
-            "baseline" => Ok(ContainerPolicyLevel::Baseline),
-            "privileged" => Ok(ContainerPolicyLevel::Privileged),
-            _ => Ok(ContainerPolicyLevel::Baseline),
-        },
-        None => Ok(ContainerPolicyLevel::Baseline),
+/// Returns the root directory and the bundle path of a container created by
+/// the given runc process, as seen by lockc. Paths used by runc executed
+/// inside a container are relative to the mount namespace of that container.
+fn container_root_bundle(
+    runc_pid: i32,
+    bundle: Option<String>,
+    nested: bool,
+) -> Result<(PathBuf, PathBuf), io::Error> {
+    if !nested {
+        let bundle = match bundle {
+            Some(v) => PathBuf::from(v),
+            None => std::env::current_dir()?,
+        };
+        return Ok((PathBuf::from("/"), bundle));
     }
+
+    let proc_path = Path::new("/proc").join(runc_pid.to_string());
+    let root = proc_path.join("root");
+    let bundle = match bundle {
+        Some(v) if Path::new(&v).is_absolute() => root.join(v.trim_start_matches('/')),
+        Some(v) => proc_path.join("cwd").join(v),
+        None => proc_path.join("cwd"),
+    };
+    Ok((root, bundle))
 }
 
-enum ShimOptParsingAction {
-    NoPositional,
-    Skip,
-    ContainerId,
+/// Returns a path used by the given runc process, as seen by lockc.
+fn runc_path(runc_pid: i32, path: &str, nested: bool) -> PathBuf {
+    let proc_path = Path::new("/proc").join(runc_pid.to_string());
+    if Path::new(path).is_relative() {
+        proc_path.join("cwd").join(path)
+    } else if nested {
+        proc_path.join("root").join(path.trim_start_matches('/'))
+    } else {
+        PathBuf::from(path)
+    }
 }
 
-enum ShimContainerAction {
-    Other,
-    Delete,
+/// Reads Docker's config.v2.json of the container.
+fn docker_config<P: AsRef<Path>>(config_path: P) -> Result<Value, ContainerError> {
+    let f = std::fs::File::open(config_path.as_ref())?;
+    let r = std::io::BufReader::new(f);
+
+    Ok(serde_json::from_reader(r)?)
 }
 
-/// Types of options (prepositioned by `--`).
-enum OptParsingAction {
-    /// Option not followed by a positional argument.
-    NoPositional,
-    /// Option followed by a positional argument we don't want to store.
-    Skip,
-    /// --bundle option which we want to store.
-    Bundle,
+fn docker_metadata(config: &Value) -> ContainerMetadata {
+    ContainerMetadata {
+        name: config["Name"]
+            .as_str()
+            .map(|name| name.trim_start_matches('/').to_string()),
+        image: config["Config"]["Image"].as_str().map(String::from),
+        ..Default::default()
+    }
+}
+
+/// Returns the metadata of a Docker container created by cri-dockerd, which
+/// labels containers with the namespace, pod and container names.
+fn cri_dockerd_metadata(config: &Value) -> Option<ContainerMetadata> {
+    let labels = &config["Config"]["Labels"];
+    let namespace = labels[ANNOTATION_POD_NAMESPACE].as_str()?;
+    Some(ContainerMetadata {
+        name: labels[ANNOTATION_CONTAINER_NAME].as_str().map(String::from),
+        pod: labels[ANNOTATION_POD_NAME].as_str().map(String::from),
+        namespace: Some(namespace.to_string()),
+        image: config["Config"]["Image"].as_str().map(String::from),
+        ..Default::default()
+    })
+}
+
+/// Returns the policy resolved by an admission webhook for a container
+/// created by cri-dockerd, which stores pod annotations as prefixed labels.
+fn cri_dockerd_resolved_policy(config: &Value) -> Option<ContainerPolicyLevel> {
+    let key = format!(
+        "{}{}",
+        LABEL_CRI_DOCKERD_ANNOTATION, ANNOTATION_RESOLVED_POLICY
+    );
+    parse_resolved_policy(config["Config"]["Labels"][key.as_str()].as_str()?)
+}
+
+/// Returns the name of the custom policy of the Docker container, from its
+/// label or, for containers created by cri-dockerd, the pod annotation.
+fn docker_custom_policy(config: &Value) -> Option<String> {
+    let labels = &config["Config"]["Labels"];
+    let annotation = format!(
+        "{}{}",
+        LABEL_CRI_DOCKERD_ANNOTATION, ANNOTATION_CUSTOM_POLICY
+    );
+    labels[ANNOTATION_CUSTOM_POLICY]
+        .as_str()
+        .or_else(|| labels[annotation.as_str()].as_str())
+        .map(String::from)
 }
 
-/// Types of positional arguments.
-enum ArgParsingAction {
-    /// Argument we don't want to store.
-    None,
-    /// Container ID which we want to store.
-    ContainerId,
+/// Returns whether the Docker container has the runtime sockets exemption.
+fn docker_runtime_sockets(config: &Value) -> bool {
+    config["Config"]["Labels"][ANNOTATION_RUNTIME_SOCKETS].as_str() == Some("allow")
 }
 
-/// Types of actions performed on the container, defined by a runc subcommand.
-enum ContainerAction {
-    /// Types we don't explicitly handle, except of registering the process as
-    /// containerized.
-    Other,
-    /// Action of creating the container, when we want to register the new
-    /// container.
-    Create,
-    /// Action of deleting the container, when we want to remove the registered
-    /// container.
-    Delete,
+/// Returns whether the Docker container has the syslog exemption.
+fn docker_syslog(config: &Value) -> bool {
+    config["Config"]["Labels"][ANNOTATION_SYSLOG].as_str() == Some("allow")
+}
+
+/// Returns the enforcement mode label of the Docker container, if set.
+fn docker_enforcement(config: &Value) -> Option<Enforcement> {
+    config["Config"]["Labels"][ANNOTATION_ENFORCEMENT]
+        .as_str()
+        .map(|value| enforcement(Some(value)))
+}
+
+/// Returns the policy label among the labels of a Docker container or image.
+fn label_policy(labels: &Value) -> Option<&str> {
+    [ANNOTATION_POLICY, LABEL_POLICY_COMPOSE]
+        .iter()
+        .find_map(|key| labels[*key].as_str())
+}
+
+/// Returns the data root of the Docker instance from the path of
+/// config.v2.json of its container (`<root>/containers/<ID>/config.v2.json`).
+fn docker_root(config_path: &Path) -> Option<&Path> {
+    config_path.parent()?.parent()?.parent()
+}
+
+/// Reads labels of the image of the Docker container from the image store of
+/// Docker, whose root directory also has the `containers` directory with
+/// config.v2.json. Returns `None` when the image can't be read, e.g. with
+/// the containerd image store.
+fn docker_image_labels(config_path: &Path, config: &Value) -> Option<Value> {
+    let root = docker_root(config_path)?;
+    let driver = config["Driver"].as_str()?;
+    let (algorithm, digest) = config["Image"].as_str()?.split_once(':')?;
+    if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let image_config = root
+        .join("image")
+        .join(driver)
+        .join("imagedb")
+        .join("content")
+        .join(algorithm)
+        .join(digest);
+    let mut image = docker_config(image_config).ok()?;
+    Some(image["config"]["Labels"].take())
+}
+
+/// Returns the policy of the Docker container from, in order: its labels,
+/// labels of its image, image policy rules and the default policy.
+fn policy_docker(
+    config_path: &Path,
+    config: &Value,
+    image_policies: &ImagePolicies,
+    default_policy: ContainerPolicyLevel,
+) -> ContainerPolicyLevel {
+    let image_labels;
+    let label = match label_policy(&config["Config"]["Labels"]) {
+        Some(label) => Some(label),
+        None => {
+            image_labels = docker_image_labels(config_path, config);
+            image_labels.as_ref().and_then(label_policy)
+        }
+    };
+
+    match label {
+        Some(label) => label.parse().unwrap_or_else(|_| {
+            warn!(
+                label,
+                default_policy = default_policy.to_string().as_str(),
+                "invalid policy label of the Docker container, using the default policy"
+            );
+            default_policy
+        }),
+        None => config["Config"]["Image"]
+            .as_str()
+            .and_then(|image| image_policies.policy(image))
+            .unwrap_or(default_policy),
+    }
 }
 
 pub struct RuncWatcher {
-    bootstrap_rx: oneshot::Receiver<()>,
-    ebpf_tx: mpsc::Sender<EbpfCommand>,
+    bootstrap_rx: oneshot::Receiver<oneshot::Sender<()>>,
+    ebpf_tx: EbpfSender,
     fd: Fanotify,
+    /// Whether denied executions are recorded in the audit log.
+    audit: bool,
+    image_policies: ImagePolicies,
+    /// Static mapping of Kubernetes namespaces to policies.
+    namespace_policies: NamespacePolicies,
+    pids: PidTranslator,
+    runc_verifier: RuncVerifier,
+    /// File names of runc binaries, when whole filesystems are marked.
+    runc_names: Option<Vec<String>>,
+    /// Watch of runtime directories and k3s and RKE2 data directories for
+    /// new runc binaries, when runc binaries are marked.
+    discovery: Option<RuncDiscovery>,
+    privileged: PrivilegedContainers,
+    /// Namespaces whose containers can read the kernel log.
+    syslog: Syslog,
+    validator: SpecValidator,
+    registration_latency: Arc<Histogram>,
+    /// Advanced on every iteration of the work loop.
+    liveness: Liveness,
+    /// Registrations taking longer are logged.
+    slow_registration: Duration,
+    /// Whether the policy resolved by an admission webhook is trusted.
+    resolved_policy_annotation: bool,
+    /// Runtimes trusted to tell Kubernetes namespaces of containers.
+    kubernetes_runtimes: KubernetesRuntimes,
+    /// Policy of Kubernetes containers when namespace labels can't be read.
+    #[cfg(not(feature = "kubernetes"))]
+    kubernetes_default_policy: ContainerPolicyLevel,
+    /// Policy of Docker containers without a policy label.
+    docker_default_policy: ContainerPolicyLevel,
+    unknown_container_policy: UnknownContainerPolicy,
+    /// Custom policies reconciled from `LockcPolicy` resources, if enabled.
+    custom_policies: Option<CustomPolicies>,
+    /// Recorder of fanotify events, enabled with `--record`.
+    recorder: Option<Recorder>,
+    /// Whether recorded events are replayed. PID files of replayed
+    /// containers are never written, so they are not watched.
+    replaying: bool,
 }
 
 #[derive(Error, Debug)]
@@ -297,11 +1065,11 @@ pub enum HandleRuncEventError {
     #[error(transparent)]
     Errno(#[from] nix::errno::Errno),
 
-    #[error(transparent)]
-    CommandSend(#[from] mpsc::error::SendError<EbpfCommand>),
+    #[error("could not send the eBPF command: {0}")]
+    CommandSend(#[from] SendCommandError),
 
-    #[error(transparent)]
-    CommandRecv(#[from] oneshot::error::RecvError),
+    #[error("could not get the result of the eBPF command: {0}")]
+    CommandResponse(#[from] ResponseError),
 
     #[error(transparent)]
     BootstrapTryRecv(#[from] oneshot::error::TryRecvError),
@@ -312,126 +1080,396 @@ pub enum HandleRuncEventError {
     #[error(transparent)]
     Container(#[from] ContainerError),
 
+    #[cfg(feature = "kubernetes")]
     #[error(transparent)]
     PolicyKubernetes(#[from] PolicyKubernetesSyncError),
 
     #[error(transparent)]
     MapOperation(#[from] MapOperationError),
 
+    #[error(transparent)]
+    PidNs(#[from] PidNsError),
+
+    #[error(transparent)]
+    PidFile(#[from] PidFileError),
+
+    #[error(transparent)]
+    Cgroup(#[from] CgroupError),
+
     #[error("container data missing")]
     ContainerData,
 
     #[error("container ID missing")]
     ContainerID,
+
+    #[error("privileged container {0} is not allowed outside of allowed namespaces")]
+    PrivilegedDenied(String),
+
+    #[error("engine of container {0} was not detected and unknown containers are denied")]
+    UnknownContainerDenied(String),
+
+    #[error("OCI runtime spec of container {container_id} has {violations} policy violations")]
+    SpecViolation {
+        container_id: String,
+        violations: usize,
+    },
+
+    #[error("could not register container {container_id} in eBPF maps: {source}")]
+    Registration {
+        container_id: String,
+        #[source]
+        source: Box<MapOperationError>,
+    },
+}
+
+/// Registers the process as a part of the container.
+async fn add_process(
+    ebpf_tx: &EbpfSender,
+    container_id: String,
+    pid: i32,
+) -> Result<(), HandleRuncEventError> {
+    let (responder_tx, responder_rx) = oneshot::channel();
+
+    ebpf_tx
+        .send(EbpfCommand::AddProcess {
+            container_id: container_id.clone(),
+            pid,
+            responder_tx,
+        })
+        .await?;
+    ebpf_tx
+        .response(responder_rx)
+        .await?
+        .map_err(|source| HandleRuncEventError::Registration {
+            container_id,
+            source: Box::new(source),
+        })?;
+
+    Ok(())
+}
+
+/// Resolves the cgroup of the container from its init process and maps it
+/// to the container. Cgroups are named after the ID given to runc, not after
+/// the key of the container.
+async fn add_cgroup(
+    ebpf_tx: &EbpfSender,
+    container_id: String,
+    runtime_id: &str,
+    pid: i32,
+) -> Result<(), HandleRuncEventError> {
+    let cgroup = cgroups::resolve(pid, runtime_id)?;
+    let (responder_tx, responder_rx) = oneshot::channel();
+
+    ebpf_tx
+        .send(EbpfCommand::AddCgroup {
+            container_id: container_id.clone(),
+            cgroup,
+            responder_tx,
+        })
+        .await?;
+    ebpf_tx
+        .response(responder_rx)
+        .await?
+        .map_err(|source| HandleRuncEventError::Registration {
+            container_id,
+            source: Box::new(source),
+        })?;
+
+    Ok(())
+}
+
+/// Marks a runc binary. Files which are not executable yet are marked too,
+/// since `install` and package managers change the mode after creating the
+/// file, and only executions are reported anyway.
+fn mark_runc(fd: &Fanotify, path: &Path) -> Result<(), io::Error> {
+    fd.add_path(FAN_OPEN_EXEC_PERM, path)?;
+    debug!(path = ?path, "added runc to fanotify");
+    Ok(())
+}
+
+/// Marks whole filesystems containing the given paths.
+fn mark_filesystems(fd: &Fanotify, filesystems: &[PathBuf]) -> Result<(), io::Error> {
+    for path in filesystems {
+        fanotify_mark(
+            fd.as_raw_fd(),
+            FAN_MARK_ADD | FAN_MARK_FILESYSTEM,
+            FAN_OPEN_EXEC_PERM,
+            AT_FDCWD,
+            path,
+        )?;
+        debug!(path = ?path, "added filesystem to fanotify");
+    }
+    Ok(())
+}
+
+/// Returns flags of `fanotify_init` for the given settings.
+fn fanotify_init_flags(flags: &FanotifyFlags) -> u32 {
+    let mut init_flags = FAN_CLASS_CONTENT | FAN_CLOEXEC;
+    if flags.unlimited_queue {
+        init_flags |= FAN_UNLIMITED_QUEUE;
+    }
+    if flags.unlimited_marks {
+        init_flags |= FAN_UNLIMITED_MARKS;
+    }
+    if flags.audit {
+        init_flags |= FAN_ENABLE_AUDIT;
+    }
+    init_flags
+}
+
+/// `struct fanotify_response`, written to the fanotify group to answer a
+/// permission event.
+#[repr(C)]
+struct Response {
+    fd: i32,
+    response: u32,
 }
 
 impl RuncWatcher {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        bootstrap_rx: oneshot::Receiver<()>,
-        ebpf_tx: mpsc::Sender<EbpfCommand>,
+        bootstrap_rx: oneshot::Receiver<oneshot::Sender<()>>,
+        ebpf_tx: EbpfSender,
+        image_policies: ImagePolicies,
+        namespace_policies: NamespacePolicies,
+        pids: PidTranslator,
+        runc_verifier: RuncVerifier,
+        settings: &Settings,
+        validator: SpecValidator,
     ) -> Result<Self, io::Error> {
-        let runc_paths = vec![
-            "/usr/bin/runc",
-            "/usr/sbin/runc",
-            "/usr/local/bin/runc",
-            "/usr/local/sbin/runc",
-            "/run/torcx/unpack/docker/bin/runc",
-            "/host/usr/bin/runc",
-            "/host/usr/sbin/runc",
-            "/host/usr/local/bin/runc",
-            "/host/usr/local/sbin/runc",
-            "/host/run/torcx/unpack/docker/bin/runc",
-        ];
-        let fd = Fanotify::new_with_blocking(FanotifyMode::CONTENT);
-
-        for runc_path in runc_paths {
-            debug!(path = runc_path, "checking runc");
-            let p = Path::new(&runc_path);
-            if p.exists() {
-                let metadata = p.metadata()?;
-
-                // When the source for host mount in Kubernetes does not
-                // exists, an empty directory is created. Also, directories
-                // contain an executable bit. Skip directories before any other
-                // checks.
-                if metadata.is_dir() {
-                    continue;
-                }
-
-                // If the file is executable.
-                if metadata.permissions().mode() & 0o111 != 0 {
-                    debug!(path = runc_path, "excecutable runc binary found");
-                    fd.add_path(FAN_OPEN_EXEC_PERM, runc_path)?;
-                    debug!(path = runc_path, "added runc to fanotify");
+        let mut watcher = Self::unmarked(
+            bootstrap_rx,
+            ebpf_tx,
+            image_policies,
+            namespace_policies,
+            pids,
+            runc_verifier,
+            settings,
+            validator,
+        )?;
+        let runc_watch = &settings.runc_watch;
+
+        match runc_watch.mode {
+            RuncWatchMode::Paths => {
+                let (discovery, found) = RuncDiscovery::new(
+                    &runc_watch.directories,
+                    &runc_watch.names,
+                    discovery::DATA_DIRS,
+                )?;
+                for path in found {
+                    mark_runc(&watcher.fd, &path)?;
                 }
+                watcher.discovery = Some(discovery);
             }
-        }
-
-        let runc_lookup_paths = vec![
-            Path::new("/var/lib/rancher/k3s/data"),
-            Path::new("/host/var/lib/rancher/k3s/data"),
-        ];
-        for path in runc_lookup_paths {
-            debug!("looking for runc in: {}", path.display());
-            for entry in WalkDir::new(path) {
-                match entry {
-                    Ok(entry) => {
-                        let path = entry.path();
-                        if path.is_file() && path.file_name().unwrap().to_string_lossy() == "runc" {
-                            debug!("excecutable runc binary found: {}", path.display());
-                            fd.add_path(FAN_OPEN_EXEC_PERM, path)?;
-                            debug!("added runc to fanotify: {}", path.display());
-                        }
-                    }
-                    Err(e) => {
-                        warn!(
-                            error = e.to_string().as_str(),
-                            "could not process the walkdir entry"
-                        );
-                    }
-                }
+            RuncWatchMode::Filesystem => {
+                mark_filesystems(&watcher.fd, &runc_watch.filesystems)?;
+                watcher.runc_names = Some(runc_watch.names.clone());
             }
-        }
+        };
+
+        Ok(watcher)
+    }
+
+    /// Creates the watcher without marking runc binaries, so fanotify
+    /// doesn't report any execution. Used for replaying recorded events.
+    #[allow(clippy::too_many_arguments)]
+    pub fn unmarked(
+        bootstrap_rx: oneshot::Receiver<oneshot::Sender<()>>,
+        ebpf_tx: EbpfSender,
+        image_policies: ImagePolicies,
+        namespace_policies: NamespacePolicies,
+        pids: PidTranslator,
+        runc_verifier: RuncVerifier,
+        settings: &Settings,
+        validator: SpecValidator,
+    ) -> Result<Self, io::Error> {
+        let fd = Fanotify::from(fanotify_init(
+            fanotify_init_flags(&settings.fanotify),
+            O_RDONLY | O_LARGEFILE | O_CLOEXEC,
+        )?);
 
         Ok(RuncWatcher {
             bootstrap_rx,
             ebpf_tx,
             fd,
+            audit: settings.fanotify.audit,
+            image_policies,
+            namespace_policies,
+            pids,
+            runc_verifier,
+            runc_names: None,
+            discovery: None,
+            privileged: settings.privileged_containers.clone(),
+            syslog: settings.syslog.clone(),
+            validator,
+            registration_latency: Arc::new(Histogram::new(REGISTRATION_LATENCY_BUCKETS)),
+            liveness: Liveness::default(),
+            slow_registration: Duration::from_millis(settings.slow_registration_threshold_ms),
+            resolved_policy_annotation: settings.resolved_policy_annotation,
+            kubernetes_runtimes: settings.kubernetes_runtimes.clone(),
+            #[cfg(not(feature = "kubernetes"))]
+            kubernetes_default_policy: settings.kubernetes_default_policy,
+            docker_default_policy: settings.docker_default_policy,
+            unknown_container_policy: settings.unknown_container_policy,
+            custom_policies: None,
+            recorder: None,
+            replaying: false,
         })
     }
 
+    /// Records every fanotify event about runc to the recorder.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Looks custom policies selected by containers up in the given state.
+    pub fn with_custom_policies(mut self, custom_policies: CustomPolicies) -> Self {
+        self.custom_policies = Some(custom_policies);
+        self
+    }
+
+    /// Returns the custom policy with the given name. Containers selecting
+    /// an unknown custom policy, or one not allowed in their namespace, get
+    /// the policy resolved without it.
+    fn custom_policy(
+        &self,
+        container_id: &str,
+        name: &str,
+        namespace: Option<&str>,
+    ) -> Option<CustomPolicy> {
+        let custom_policy = self
+            .custom_policies
+            .as_ref()
+            .and_then(|custom_policies| custom_policies.get(name));
+        match custom_policy {
+            Some(custom_policy) if custom_policy.allows_namespace(namespace) => Some(custom_policy),
+            Some(_) => {
+                warn!(
+                    container_id,
+                    custom_policy = name,
+                    namespace,
+                    "custom policy is not allowed in the namespace, ignoring"
+                );
+                None
+            }
+            None => {
+                warn!(
+                    container_id,
+                    custom_policy = name,
+                    "custom policy not found, ignoring"
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns the histogram of time from receiving a fanotify event about
+    /// runc creating a container to registering that container.
+    pub fn registration_latency(&self) -> Arc<Histogram> {
+        self.registration_latency.clone()
+    }
+
+    /// Returns the token advanced by the work loop, checked by the systemd
+    /// watchdog.
+    pub fn liveness(&self) -> Liveness {
+        self.liveness.clone()
+    }
+
+    /// Returns the counter of eBPF commands whose result didn't arrive
+    /// within the response timeout.
+    pub fn command_timeouts(&self) -> Arc<AtomicU64> {
+        self.ebpf_tx.timeouts()
+    }
+
+    /// Returns whether the executed binary is runc. All marked binaries are
+    /// runc, unless whole filesystems are marked.
+    fn is_runc(&mut self, path: &Path, file: &fs::File) -> Result<bool, io::Error> {
+        let runc_names = match &self.runc_names {
+            Some(runc_names) => runc_names,
+            None => return Ok(true),
+        };
+        let name_matches = path
+            .file_name()
+            .map(|name| {
+                runc_names
+                    .iter()
+                    .any(|runc_name| name == runc_name.as_str())
+            })
+            .unwrap_or(false);
+        if name_matches {
+            return Ok(true);
+        }
+        self.runc_verifier.is_allowed(file)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn add_container(
         &self,
         container_id: String,
         pid: i32,
         policy_level: ContainerPolicyLevel,
+        enforcement: Enforcement,
+        syslog: bool,
+        metadata: ContainerMetadata,
+        spec: ContainerSpec,
+        custom_mounts: Option<CustomMounts>,
     ) -> Result<(), HandleRuncEventError> {
         let (responder_tx, responder_rx) = oneshot::channel();
 
         self.ebpf_tx
             .send(EbpfCommand::AddContainer {
-                container_id,
+                container_id: container_id.clone(),
                 pid,
                 policy_level,
+                enforcement,
+                syslog,
+                metadata,
+                spec: Box::new(spec),
+                custom_mounts: custom_mounts.map(Box::new),
+                mode: AddMode::Create,
+                source: PolicySource::Runtime,
                 responder_tx,
             })
             .await?;
-        responder_rx.await??;
+        self.ebpf_tx
+            .response(responder_rx)
+            .await?
+            .map_err(|source| HandleRuncEventError::Registration {
+                container_id,
+                source: Box::new(source),
+            })?;
 
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_container_sync(
         &self,
         container_id: String,
         pid: i32,
         policy_level: ContainerPolicyLevel,
+        enforcement: Enforcement,
+        syslog: bool,
+        metadata: ContainerMetadata,
+        spec: ContainerSpec,
+        custom_mounts: Option<CustomMounts>,
     ) -> Result<(), HandleRuncEventError> {
         debug!(container_id = container_id.as_str(), "adding container");
 
         Builder::new_current_thread()
+            .enable_time()
             .build()?
-            .block_on(self.add_container(container_id, pid, policy_level))
+            .block_on(self.add_container(
+                container_id,
+                pid,
+                policy_level,
+                enforcement,
+                syslog,
+                metadata,
+                spec,
+                custom_mounts,
+            ))
     }
 
     async fn delete_container(&self, container_id: String) -> Result<(), HandleRuncEventError> {
@@ -443,7 +1481,7 @@ impl RuncWatcher {
                 responder_tx,
             })
             .await?;
-        responder_rx.await??;
+        self.ebpf_tx.response(responder_rx).await??;
 
         Ok(())
     }
@@ -452,86 +1490,134 @@ impl RuncWatcher {
         debug!(container_id = container_id.as_str(), "deleting container");
 
         Builder::new_current_thread()
+            .enable_time()
             .build()?
             .block_on(self.delete_container(container_id))
     }
 
-    async fn add_process(
+    fn add_process_sync(&self, container_id: String, pid: i32) -> Result<(), HandleRuncEventError> {
+        debug!(
+            container = container_id.as_str(),
+            pid = pid,
+            "adding process"
+        );
+
+        Builder::new_current_thread()
+            .enable_time()
+            .build()?
+            .block_on(add_process(&self.ebpf_tx, container_id, pid))
+    }
+
+    async fn get_process_container(
         &self,
-        container_id: String,
         pid: i32,
-    ) -> Result<(), HandleRuncEventError> {
+    ) -> Result<Option<ProcessContainer>, HandleRuncEventError> {
         let (responder_tx, responder_rx) = oneshot::channel();
 
         self.ebpf_tx
-            .send(EbpfCommand::AddProcess {
-                container_id,
-                pid,
-                responder_tx,
-            })
+            .send(EbpfCommand::GetProcessContainer { pid, responder_tx })
             .await?;
-        responder_rx.await??;
+        let container = self.ebpf_tx.response(responder_rx).await??;
 
-        Ok(())
+        Ok(container)
     }
 
-    fn add_process_sync(&self, container_id: String, pid: i32) -> Result<(), HandleRuncEventError> {
-        debug!(
-            container = container_id.as_str(),
-            pid = pid,
-            "adding process"
-        );
-
+    fn get_process_container_sync(
+        &self,
+        pid: i32,
+    ) -> Result<Option<ProcessContainer>, HandleRuncEventError> {
         Builder::new_current_thread()
+            .enable_time()
             .build()?
-            .block_on(self.add_process(container_id, pid))
+            .block_on(self.get_process_container(pid))
     }
 
-    fn handle_containerd_shim_event(
+    /// Registers the init process of the created container once runc writes
+    /// its PID to the PID file. The init process is forked by runc, so it
+    /// should be already registered as its child, but a process of the
+    /// container which lockc doesn't know about would run unconfined.
+    #[allow(clippy::too_many_arguments)]
+    fn watch_pid_file(
         &self,
-        containerd_shim_process: Process,
+        container_id: String,
+        runtime_id: String,
+        runc_pid: i32,
+        host_pid: i32,
+        pid_file: &str,
+        nested: bool,
     ) -> Result<(), HandleRuncEventError> {
-        let mut opt_parsing_action = ShimOptParsingAction::NoPositional;
-        let mut container_action = ShimContainerAction::Other;
-
-        let mut container_id_o: Option<String> = None;
-
-        for arg in containerd_shim_process.cmdline()? {
-            debug!(argument = arg.as_str(), "containerd-shim");
-            match arg.as_str() {
-                "-address" => opt_parsing_action = ShimOptParsingAction::Skip,
-                "-bundle" => opt_parsing_action = ShimOptParsingAction::Skip,
-                "-id" => opt_parsing_action = ShimOptParsingAction::ContainerId,
-                "-namespace" => opt_parsing_action = ShimOptParsingAction::Skip,
-                "-publish-binary" => opt_parsing_action = ShimOptParsingAction::Skip,
-                _ => {}
-            }
-            if arg.starts_with('-') {
-                continue;
-            }
-
-            match opt_parsing_action {
-                ShimOptParsingAction::NoPositional => {}
-                ShimOptParsingAction::Skip => {
-                    opt_parsing_action = ShimOptParsingAction::NoPositional;
-                    continue;
-                }
-                ShimOptParsingAction::ContainerId => {
-                    container_id_o = Some(arg);
-                    opt_parsing_action = ShimOptParsingAction::NoPositional;
-                    continue;
+        // Paths and PIDs are resolved while runc is still running.
+        let pid_file = PidFile::watch(&runc_path(runc_pid, pid_file, nested))?;
+        let ns = self.pids.namespace(host_pid)?;
+        let pids = self.pids.clone();
+        let ebpf_tx = self.ebpf_tx.clone();
+        let span = Span::current();
+
+        thread::Builder::new()
+            .name("lockc-pid-file".to_string())
+            .spawn(move || {
+                let _enter = span.enter();
+                let res = pid_file
+                    .wait(PID_FILE_TIMEOUT)
+                    .map_err(HandleRuncEventError::from)
+                    .and_then(|pid| Ok(pids.to_host_in(&ns, pid)?))
+                    .and_then(|pid| {
+                        debug!(pid, "adding init process");
+                        Builder::new_current_thread()
+                            .enable_time()
+                            .build()?
+                            .block_on(add_process(&ebpf_tx, container_id.clone(), pid))?;
+                        Ok(pid)
+                    });
+                let pid = match res {
+                    Ok(pid) => pid,
+                    Err(e) => {
+                        warn!(
+                            error = e.to_string().as_str(),
+                            "could not register the init process of the container"
+                        );
+                        return;
+                    }
+                };
+                // runc writes the PID file after moving the init process
+                // into the cgroup of the container.
+                let res = Builder::new_current_thread()
+                    .enable_time()
+                    .build()
+                    .map_err(HandleRuncEventError::from)
+                    .and_then(|rt| {
+                        rt.block_on(add_cgroup(&ebpf_tx, container_id, &runtime_id, pid))
+                    });
+                if let Err(e) = res {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "could not register the cgroup of the container"
+                    );
                 }
-            }
+            })?;
 
-            if arg.as_str() == "delete" {
-                container_action = ShimContainerAction::Delete
-            }
-        }
+        Ok(())
+    }
 
-        match container_action {
-            ShimContainerAction::Other => {}
-            ShimContainerAction::Delete => {
-                let container_id = container_id_o.ok_or(HandleRuncEventError::ContainerID)?;
+    fn handle_containerd_shim_event(
+        &self,
+        containerd_shim_process: &ProcessSnapshot,
+    ) -> Result<(), HandleRuncEventError> {
+        let cmdline = containerd_shim_process.cmdline.clone();
+        debug!(cmdline = cmdline.join(" ").as_str(), "containerd-shim");
+        let invocation = RuncInvocation::parse_shim(cmdline);
+
+        match invocation.action {
+            ContainerAction::Other | ContainerAction::Create => {}
+            ContainerAction::Delete => {
+                let container_id = invocation.id.ok_or(HandleRuncEventError::ContainerID)?;
+                let container_id = match &invocation.namespace {
+                    Some(namespace) => containerd_container_key(namespace, &container_id),
+                    None => container_id,
+                };
+                Span::current()
+                    .record("container_id", container_id.as_str())
+                    .record("operation", "delete");
                 debug!(container = container_id.as_str(), "deleting container");
 
                 self.delete_container_sync(container_id)?;
@@ -541,111 +1627,354 @@ impl RuncWatcher {
         Ok(())
     }
 
-    fn handle_runc_event(&self, runc_process: Process) -> Result<(), HandleRuncEventError> {
-        let mut opt_parsing_action = OptParsingAction::NoPositional;
-        let mut arg_parsing_action = ArgParsingAction::None;
-        let mut container_action = ContainerAction::Other;
-
-        let mut container_bundle_o: Option<String> = None;
-        let mut container_id_o: Option<String> = None;
-
-        // for arg in cmdline.split(CMDLINE_DELIMITER) {
-        for arg in runc_process.cmdline()? {
-            debug!(argument = arg.as_str(), "runc");
-            match arg.as_str() {
-                // Options which are followed with a positional arguments we don't
-                // want to store.
-                "--log" => opt_parsing_action = OptParsingAction::Skip,
-                "--log-format" => opt_parsing_action = OptParsingAction::Skip,
-                "--pid-file" => opt_parsing_action = OptParsingAction::Skip,
-                "--process" => opt_parsing_action = OptParsingAction::Skip,
-                "--console-socket" => opt_parsing_action = OptParsingAction::Skip,
-                "--root" => opt_parsing_action = OptParsingAction::Skip,
-                // We want to explicitly store the value of --bundle and --root
-                // options.
-                "--bundle" => opt_parsing_action = OptParsingAction::Bundle,
-                _ => {}
-            }
-            if arg.starts_with('-') {
-                // After handling the option, start parsing the next argument.
-                continue;
-            }
+    /// Returns the policy of the Kubernetes namespace from the static
+    /// mapping. kube-system always gets the privileged policy, regardless of
+    /// the mapping.
+    fn static_policy(&self, namespace: Option<&str>) -> Option<ContainerPolicyLevel> {
+        match namespace? {
+            "kube-system" => None,
+            namespace => self.namespace_policies.policy(namespace),
+        }
+    }
 
-            match opt_parsing_action {
-                OptParsingAction::NoPositional => {}
-                OptParsingAction::Skip => {
-                    opt_parsing_action = OptParsingAction::NoPositional;
-                    continue;
-                }
-                OptParsingAction::Bundle => {
-                    container_bundle_o = Some(arg);
-                    opt_parsing_action = OptParsingAction::NoPositional;
-                    continue;
-                }
-            }
-            match arg_parsing_action {
-                ArgParsingAction::None => {}
-                ArgParsingAction::ContainerId => {
-                    container_id_o = Some(arg);
-                    arg_parsing_action = ArgParsingAction::None;
-                    continue;
-                }
-            }
+    /// Finds the policy of a Kubernetes container in the given namespace.
+    fn policy_kubernetes(
+        &self,
+        nested: bool,
+        resolved_policy: Option<ContainerPolicyLevel>,
+        namespace: Option<String>,
+        image: Option<&str>,
+    ) -> Result<ContainerPolicyLevel, HandleRuncEventError> {
+        // Namespaces of a nested cluster are not known to the API server lockc
+        // is talking to.
+        if nested {
+            return Ok(policy_image(image, &self.image_policies));
+        }
+        // Resolved by the admission webhook or mapped statically, the API
+        // server doesn't have to be asked.
+        if let Some(policy_level) = resolved_policy
+            .filter(|_| self.resolved_policy_annotation)
+            .or_else(|| self.static_policy(namespace.as_deref()))
+        {
+            debug!(
+                policy_level = policy_level.to_string().as_str(),
+                "using the resolved or statically mapped policy"
+            );
+            return Ok(policy_level);
+        }
+        let namespace = namespace.ok_or(HandleRuncEventError::ContainerData)?;
+        #[cfg(feature = "kubernetes")]
+        let policy_level = policy_kubernetes_sync(namespace, image, &self.image_policies)?;
+        #[cfg(not(feature = "kubernetes"))]
+        let policy_level = policy_kubernetes_default(
+            &namespace,
+            image,
+            &self.image_policies,
+            self.kubernetes_default_policy,
+        );
+        Ok(policy_level)
+    }
 
-            match arg.as_str() {
-                "checkpoint" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "create" => {
-                    arg_parsing_action = ArgParsingAction::ContainerId;
-                    container_action = ContainerAction::Create;
-                }
-                "delete" => {
-                    arg_parsing_action = ArgParsingAction::ContainerId;
-                    container_action = ContainerAction::Delete;
-                }
-                "events" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "exec" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "kill" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "pause" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "ps" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "restore" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "resume" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "run" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "start" => {
-                    arg_parsing_action = ArgParsingAction::ContainerId;
-                }
-                "state" => arg_parsing_action = ArgParsingAction::ContainerId,
-                "update" => arg_parsing_action = ArgParsingAction::ContainerId,
-                _ => {}
-            }
+    fn handle_runc_event(
+        &self,
+        runc_process: &ProcessSnapshot,
+        received: Instant,
+    ) -> Result<(), HandleRuncEventError> {
+        let cmdline = runc_process.cmdline.clone();
+        debug!(cmdline = cmdline.join(" ").as_str(), "runc");
+        let RuncInvocation {
+            action: container_action,
+            id: runtime_id_o,
+            bundle: container_bundle_o,
+            root,
+            pid_file,
+            ..
+        } = RuncInvocation::parse(cmdline);
+        let container_id_o = runtime_id_o
+            .as_deref()
+            .map(|id| container_key(root.as_deref(), id));
+
+        let span = Span::current();
+        if let Some(container_id) = &container_id_o {
+            span.record("container_id", container_id.as_str());
+        }
+        if let Some(runtime_id) = &runtime_id_o {
+            span.record("short_id", short_id(runtime_id));
+        }
+        if let Some(root) = &root {
+            span.record("root", root.as_str());
         }
+        span.record("operation", container_action.as_str());
+
+        // PID seen by eBPF programs. It differs from the PID reported by
+        // fanotify when lockc runs in its own PID namespace.
+        let host_pid = self.pids.to_host(runc_process.pid)?;
 
         match container_action {
             ContainerAction::Other => {
                 debug!("other container action");
                 if let Some(container_id) = container_id_o {
-                    self.add_process_sync(container_id, runc_process.pid)?;
+                    self.add_process_sync(container_id, host_pid)?;
                 }
             }
             ContainerAction::Create => {
                 let container_id = container_id_o.ok_or(HandleRuncEventError::ContainerID)?;
-                let container_bundle = match container_bundle_o {
-                    Some(v) => std::path::PathBuf::from(v),
-                    None => std::env::current_dir()?,
+
+                // runc executed inside an already registered container
+                // creates a nested container (i.e. Docker in Docker or
+                // Kubernetes in Docker).
+                let parent = self.get_process_container_sync(host_pid)?;
+                if parent.is_none()
+                    && runc_process.pid_ns_depth() > pid_ns_depth(&Process::myself()?)?
+                {
+                    warn!(
+                        container_id = container_id.as_str(),
+                        pid = runc_process.pid,
+                        "runc executed in a PID namespace of an unregistered container, registering as a host-level container"
+                    );
+                }
+                let nested = parent.is_some();
+                let (container_root, container_bundle) =
+                    container_root_bundle(runc_process.pid, container_bundle_o, nested)?;
+
+                let container_data = container_type_data(&container_bundle)?;
+                let mut metadata = container_data.metadata;
+                let mut spec = container_data.spec;
+                let mut enforcement = container_data.enforcement;
+                let mut syslog = container_data.syslog;
+                let mut custom_policy = container_data.custom_policy;
+                // Anyone can annotate containers with a namespace, only the
+                // trusted CRI implementations are believed.
+                let container_type = match container_data.container_type {
+                    ContainerType::Kubernetes
+                        if container_data.annotated
+                            && (nested
+                                || !self.kubernetes_runtimes.annotations_trusted(Path::new(
+                                    root.as_deref().unwrap_or(RUNC_DEFAULT_ROOT),
+                                ))) =>
+                    {
+                        warn!(
+                            container_id = container_id.as_str(),
+                            namespace = metadata.namespace.as_deref(),
+                            "Kubernetes namespace annotation from an untrusted runc root, ignoring"
+                        );
+                        metadata.namespace = None;
+                        ContainerType::Unknown
+                    }
+                    container_type => container_type,
                 };
+                let policy_span = debug_span!(
+                    "resolve_policy",
+                    container_id = container_id.as_str(),
+                    container_type = container_type.as_str()
+                );
+                let mut policy = policy_span.in_scope(|| {
+                    Ok::<_, HandleRuncEventError>(match container_type {
+                        ContainerType::Docker => {
+                            let config_path = container_data
+                                .data
+                                .ok_or(HandleRuncEventError::ContainerData)?;
+                            let config_path =
+                                container_root.join(config_path.trim_start_matches('/'));
+                            let config = docker_config(&config_path)?;
+                            spec.runtime_sockets |= docker_runtime_sockets(&config);
+                            syslog |= docker_syslog(&config);
+                            if let Some(e) = docker_enforcement(&config) {
+                                enforcement = e;
+                            }
+                            if let Some(name) = docker_custom_policy(&config) {
+                                custom_policy = Some(name);
+                            }
+                            // Only cri-dockerd is allowed to use the trusted
+                            // Docker instances.
+                            let cri_dockerd = !nested
+                                && docker_root(&config_path)
+                                    .map(|root| self.kubernetes_runtimes.cri_dockerd_trusted(root))
+                                    .unwrap_or(false);
+                            let cri_metadata = cri_dockerd_metadata(&config);
+                            if cri_metadata.is_some() && !cri_dockerd {
+                                warn!(
+                                    container_id = container_id.as_str(),
+                                    config_path = config_path.to_string_lossy().as_ref(),
+                                    "Kubernetes namespace label of a Docker instance not trusted for cri-dockerd, ignoring"
+                                );
+                            }
+                            match cri_metadata.filter(|_| cri_dockerd) {
+                                // Created by cri-dockerd for a Kubernetes pod.
+                                Some(cri_metadata) => {
+                                    debug!(
+                                        namespace = cri_metadata.namespace.as_deref(),
+                                        "detected k8s+cri-dockerd container",
+                                    );
+                                    metadata = cri_metadata;
+                                    self.policy_kubernetes(
+                                        nested,
+                                        cri_dockerd_resolved_policy(&config),
+                                        metadata.namespace.clone(),
+                                        metadata.image.as_deref(),
+                                    )?
+                                }
+                                None => {
+                                    metadata = docker_metadata(&config);
+                                    policy_docker(
+                                        &config_path,
+                                        &config,
+                                        &self.image_policies,
+                                        self.docker_default_policy,
+                                    )
+                                }
+                            }
+                        }
+                        ContainerType::Kubernetes => self.policy_kubernetes(
+                            nested,
+                            container_data.resolved_policy,
+                            container_data.data,
+                            metadata.image.as_deref(),
+                        )?,
+                        ContainerType::Containerd => policy_containerd(
+                            container_data.data.as_deref(),
+                            metadata.image.as_deref(),
+                            &self.image_policies,
+                        ),
+                        ContainerType::Unknown => {
+                            warn!(
+                                container_id = container_id.as_str(),
+                                bundle = container_bundle.to_string_lossy().as_ref(),
+                                action = self.unknown_container_policy.to_string().as_str(),
+                                "container engine not detected"
+                            );
+                            policy_unknown(self.unknown_container_policy).ok_or_else(|| {
+                                HandleRuncEventError::UnknownContainerDenied(container_id.clone())
+                            })?
+                        }
+                    })
+                })?;
+
+                if let Some(parent) = parent {
+                    debug!(
+                        container_id = container_id.as_str(),
+                        parent = parent.container_id.as_str(),
+                        pid = host_pid,
+                        ns_pid = runc_process.ns_pid(),
+                        "detected nested container"
+                    );
+                    policy = policy_nested(parent.policy_level, policy);
+                    metadata.parent = Some(parent.container_id);
+                }
+                // Custom policies can't be less strict than the policy
+                // resolved for the container, otherwise any container could
+                // select a privileged one.
+                let custom_policy = custom_policy.and_then(|name| {
+                    self.custom_policy(&container_id, &name, metadata.namespace.as_deref())
+                });
+                if let Some(custom_policy) = &custom_policy {
+                    policy = policy_nested(policy, custom_policy.policy_level);
+                    if let Some(e) = custom_policy.enforcement {
+                        enforcement = e;
+                    }
+                }
+                // Set after the Docker config replaced the metadata.
+                metadata.root = root;
+                metadata.runtime_id = runtime_id_o.clone().filter(|id| *id != container_id);
 
-                // let policy;
-                let (container_type, container_data) = container_type_data(container_bundle)?;
-                let policy: ContainerPolicyLevel = match container_type {
-                    ContainerType::Docker => {
-                        policy_docker(container_data.ok_or(HandleRuncEventError::ContainerData)?)?
+                let namespace = metadata.namespace.as_deref();
+                policy = match policy_privileged(policy, namespace, &self.privileged) {
+                    Some(p) if p != policy => {
+                        warn!(
+                            container_id = container_id.as_str(),
+                            namespace,
+                            "privileged policy is not allowed in the namespace, applying baseline"
+                        );
+                        p
                     }
-                    ContainerType::KubernetesContainerd => policy_kubernetes_sync(
-                        container_data.ok_or(HandleRuncEventError::ContainerData)?,
-                    )?,
-                    ContainerType::Unknown => ContainerPolicyLevel::Baseline,
+                    Some(p) => p,
+                    None => return Err(HandleRuncEventError::PrivilegedDenied(container_id)),
                 };
+                // The exemption is as powerful as the privileged policy.
+                if spec.runtime_sockets && !self.privileged.allowed(namespace) {
+                    warn!(
+                        container_id = container_id.as_str(),
+                        namespace,
+                        "runtime sockets exemption is not allowed in the namespace, ignoring"
+                    );
+                    spec.runtime_sockets = false;
+                }
+                // Kernel logs leak addresses and data of the host, so the
+                // label is as powerful as the privileged policy. Namespaces
+                // listed in the settings are trusted by the administrator.
+                if self.syslog.exempted(namespace) {
+                    syslog = true;
+                } else if syslog && !self.privileged.allowed(namespace) {
+                    warn!(
+                        container_id = container_id.as_str(),
+                        namespace, "syslog exemption is not allowed in the namespace, ignoring"
+                    );
+                    syslog = false;
+                }
+                // The complain mode disables enforcement of any policy.
+                if enforcement == Enforcement::Complain && !self.privileged.allowed(namespace) {
+                    warn!(
+                        container_id = container_id.as_str(),
+                        namespace, "complain mode is not allowed in the namespace, enforcing"
+                    );
+                    enforcement = Enforcement::Enforce;
+                }
 
-                self.add_container_sync(container_id, runc_process.pid, policy)?;
+                let violations =
+                    self.validator
+                        .validate(policy, &container_data.config, custom_policy.as_ref());
+                for violation in &violations {
+                    warn!(
+                        container_id = container_id.as_str(),
+                        policy_level = format!("{}", policy).as_str(),
+                        violation = violation.to_string().as_str(),
+                        "OCI runtime spec violates the policy"
+                    );
+                }
+                if !violations.is_empty() && self.validator.mode() == SpecValidationMode::Strict {
+                    return Err(HandleRuncEventError::SpecViolation {
+                        container_id,
+                        violations: violations.len(),
+                    });
+                }
+
+                self.add_container_sync(
+                    container_id.clone(),
+                    host_pid,
+                    policy,
+                    enforcement,
+                    syslog,
+                    metadata,
+                    spec,
+                    custom_policy.as_ref().and_then(CustomPolicy::custom_mounts),
+                )?;
+
+                let latency = received.elapsed();
+                self.registration_latency.observe(latency);
+                if latency > self.slow_registration {
+                    debug!(
+                        latency_ms = latency.as_millis() as u64,
+                        "slow container registration"
+                    );
+                }
+
+                if let (Some(pid_file), Some(runtime_id), false) =
+                    (pid_file, runtime_id_o, self.replaying)
+                {
+                    if let Err(e) = self.watch_pid_file(
+                        container_id,
+                        runtime_id,
+                        runc_process.pid,
+                        host_pid,
+                        &pid_file,
+                        nested,
+                    ) {
+                        warn!(
+                            error = e.to_string().as_str(),
+                            "could not watch the PID file of the container"
+                        );
+                    }
+                }
             }
             ContainerAction::Delete => {
                 let container_id = container_id_o.ok_or(HandleRuncEventError::ContainerID)?;
@@ -656,30 +1985,129 @@ impl RuncWatcher {
         Ok(())
     }
 
-    fn handle_event(&self, event: Event) -> Result<(), HandleRuncEventError> {
-        // Let the process execute again
-        defer!(self.fd.send_response(event.fd, FanotifyResponse::Allow));
+    /// Answers the permission event. Denials are recorded in the audit log
+    /// when enabled, which the high-level fanotify API doesn't support.
+    fn respond(&self, event_fd: i32, response: FanotifyResponse) {
+        if !self.audit || !matches!(response, FanotifyResponse::Deny) {
+            self.fd.send_response(event_fd, response);
+            return;
+        }
+        let response = Response {
+            fd: event_fd,
+            response: FAN_DENY | FAN_AUDIT,
+        };
+        // SAFETY: the response is a valid `struct fanotify_response`.
+        let res = unsafe {
+            libc::write(
+                self.fd.as_raw_fd(),
+                &response as *const Response as *const libc::c_void,
+                mem::size_of::<Response>(),
+            )
+        };
+        if res < 0 {
+            error!(
+                error = io::Error::last_os_error().to_string().as_str(),
+                "could not send fanotify response"
+            );
+        }
+        let _ = close(event_fd);
+    }
 
-        debug!(
+    fn handle_event(&mut self, event: Event) -> Result<(), HandleRuncEventError> {
+        // Deny executing runc binaries with unexpected content.
+        // SAFETY: the file descriptor belongs to the event and is closed
+        // after sending the response.
+        let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(event.fd) });
+        match self.is_runc(Path::new(&event.path), &file) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.respond(event.fd, FanotifyResponse::Allow);
+                return Ok(());
+            }
+            Err(e) => {
+                self.respond(event.fd, FanotifyResponse::Allow);
+                return Err(e.into());
+            }
+        }
+        if let Err(e) = self.runc_verifier.verify(Path::new(&event.path), &file) {
+            self.respond(event.fd, FanotifyResponse::Deny);
+            error!(
+                path = event.path.as_str(),
+                pid = event.pid,
+                error = e.to_string().as_str(),
+                "denied execution of a tampered runc binary"
+            );
+            return Ok(());
+        }
+
+        let span = info_span!(
+            "fanotify_event",
             path = event.path.as_str(),
             pid = event.pid,
-            "received fanotify event"
+            container_id = field::Empty,
+            short_id = field::Empty,
+            root = field::Empty,
+            operation = field::Empty
         );
+        let _enter = span.enter();
+        debug!("received fanotify event");
+
+        let res = ProcessSnapshot::read(event.pid)
+            .map_err(HandleRuncEventError::from)
+            .and_then(|process| {
+                self.record(&event.path, &process);
+                self.handle_snapshot(process, Instant::now())
+            });
+        // Let the process execute again, unless it would create a forbidden
+        // container or run unconfined because registering it failed or the
+        // eBPF thread is gone.
+        let response = match res {
+            Err(
+                HandleRuncEventError::PrivilegedDenied(_)
+                | HandleRuncEventError::UnknownContainerDenied(_)
+                | HandleRuncEventError::SpecViolation { .. }
+                | HandleRuncEventError::Registration { .. }
+                | HandleRuncEventError::CommandSend(
+                    SendCommandError::Failed | SendCommandError::Closed,
+                )
+                | HandleRuncEventError::CommandResponse(ResponseError::Closed(_)),
+            ) => FanotifyResponse::Deny,
+            Err(HandleRuncEventError::CommandSend(SendCommandError::Full))
+                if self.ebpf_tx.overflow() == ChannelOverflow::FailClose =>
+            {
+                FanotifyResponse::Deny
+            }
+            Err(HandleRuncEventError::CommandResponse(ResponseError::Timeout(_)))
+                if self.ebpf_tx.on_timeout() == ResponseTimeout::FailClose =>
+            {
+                FanotifyResponse::Deny
+            }
+            _ => FanotifyResponse::Allow,
+        };
+        self.respond(event.fd, response);
+        res
+    }
 
-        let p = Process::new(event.pid)?;
+    fn handle_process(&self, pid: i32, received: Instant) -> Result<(), HandleRuncEventError> {
+        self.handle_snapshot(ProcessSnapshot::read(pid)?, received)
+    }
 
+    fn handle_snapshot(
+        &self,
+        process: ProcessSnapshot,
+        received: Instant,
+    ) -> Result<(), HandleRuncEventError> {
         // Usually fanotify receives two notifications about executing runc:
         // 1) from containerd-shim (or similar)
         // 2) from runc
         // We are interested in parsing only runc arguments rather than
         // containerd-shim.
-        let comm = p.stat()?.comm;
-        match comm.as_str() {
+        match process.comm.as_str() {
             "runc" => {
-                self.handle_runc_event(p)?;
+                self.handle_runc_event(&process, received)?;
             }
             "containerd-shim" => {
-                self.handle_containerd_shim_event(p)?;
+                self.handle_containerd_shim_event(&process)?;
             }
             _ => {}
         }
@@ -687,12 +2115,89 @@ impl RuncWatcher {
         Ok(())
     }
 
-    pub fn work_loop(&mut self) -> Result<(), HandleRuncEventError> {
-        // Wait for the bootstrap request from the main, asynchronous part of
-        // lockc.
+    /// Writes the event to the recording, if enabled. Failures are only
+    /// logged, recording must not affect handling of the event.
+    fn record(&mut self, path: &str, process: &ProcessSnapshot) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(e) = recorder.record(path, process) {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "could not record the fanotify event"
+                );
+            }
+        }
+    }
+
+    /// Handles runc and containerd-shim processes which are running, as if
+    /// fanotify reported their execution. Executions which didn't fit in the
+    /// overflowed fanotify queue were allowed by the kernel without waiting
+    /// for lockc. Registering an already registered container again only
+    /// adds the process. runc processes which already exited can't be
+    /// recovered.
+    fn reconcile(&self) {
+        warn!("fanotify queue overflowed, reconciling running runc processes");
+        let processes = match all_processes() {
+            Ok(processes) => processes,
+            Err(e) => {
+                error!(error = e.to_string().as_str(), "could not list processes");
+                return;
+            }
+        };
+        for p in processes {
+            if !matches!(p.stat.comm.as_str(), "runc" | "containerd-shim") {
+                continue;
+            }
+            let span = info_span!(
+                "reconcile",
+                pid = p.pid,
+                container_id = field::Empty,
+                short_id = field::Empty,
+                root = field::Empty,
+                operation = field::Empty
+            );
+            let _enter = span.enter();
+            if let Err(e) = self.handle_process(p.pid, Instant::now()) {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "failed to reconcile runc process"
+                );
+            }
+        }
+    }
+
+    /// Marks runc binaries which appeared in the watched directories since
+    /// the last check.
+    fn mark_new_runc(&mut self) {
+        let discovery = match &mut self.discovery {
+            Some(discovery) => discovery,
+            None => return,
+        };
+        let found = match discovery.read_events() {
+            Ok(found) => found,
+            Err(e) => {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "could not read events of runc directories"
+                );
+                return;
+            }
+        };
+        for path in found {
+            if let Err(e) = mark_runc(&self.fd, &path) {
+                warn!(error = e.to_string().as_str(), path = ?path, "could not mark new runc");
+            }
+        }
+    }
+
+    /// Waits for the bootstrap request from the main, asynchronous part of
+    /// lockc, then confirms that runc binaries are being watched.
+    fn bootstrap(&mut self) -> Result<(), HandleRuncEventError> {
         loop {
             match self.bootstrap_rx.try_recv() {
-                Ok(_) => {
+                Ok(ready_tx) => {
+                    if ready_tx.send(()).is_err() {
+                        warn!("could not confirm the fanotify bootstrap");
+                    }
                     break;
                 }
                 Err(oneshot::error::TryRecvError::Empty) => {
@@ -701,21 +2206,92 @@ impl RuncWatcher {
                 Err(e) => return Err(HandleRuncEventError::from(e)),
             }
         }
+        Ok(())
+    }
+
+    /// Handles recorded fanotify events instead of watching runc and returns
+    /// once all of them are handled. Events are handled one after another,
+    /// regardless of the recorded time.
+    pub fn replay(&mut self, events: Vec<RecordedEvent>) -> Result<(), HandleRuncEventError> {
+        self.bootstrap()?;
+        self.replaying = true;
+
+        info!(events = events.len(), "replaying recorded fanotify events");
+        for event in events {
+            let span = info_span!(
+                "replay",
+                time_ms = event.time_ms,
+                path = event.path.as_str(),
+                pid = event.process.pid,
+                container_id = field::Empty,
+                short_id = field::Empty,
+                root = field::Empty,
+                operation = field::Empty
+            );
+            let _enter = span.enter();
+            if let Some(bundle) = event.bundle.as_ref().filter(|bundle| bundle.differs()) {
+                warn!(
+                    bundle = bundle.path.to_string_lossy().as_ref(),
+                    "bundle differs from the recorded one, the replay may diverge"
+                );
+            }
+            if let Err(e) = self.handle_snapshot(event.process, Instant::now()) {
+                error!(
+                    error = e.to_string().as_str(),
+                    "failed to handle replayed event"
+                );
+            }
+        }
+        info!("replay finished");
+
+        Ok(())
+    }
+
+    pub fn work_loop(&mut self) -> Result<(), HandleRuncEventError> {
+        self.bootstrap()?;
 
         debug!("starting work loop");
 
-        let mut fds = [PollFd::new(self.fd.as_raw_fd(), PollFlags::POLLIN)];
+        let mut fds = vec![PollFd::new(self.fd.as_raw_fd(), PollFlags::POLLIN)];
+        if let Some(discovery) = &self.discovery {
+            fds.push(PollFd::new(discovery.as_raw_fd(), PollFlags::POLLIN));
+        }
+        // Wake up periodically to show the watchdog that the loop is alive.
+        let timeout = LIVENESS_INTERVAL.as_millis() as i32;
         loop {
-            let poll_num = poll(&mut fds, -1)?;
+            self.liveness.advance();
+            // Signals handled by the daemon (e.g. SIGUSR1 reloading the log
+            // filter) can be delivered to this thread and interrupt poll.
+            let poll_num = match poll(&mut fds, timeout) {
+                Ok(poll_num) => poll_num,
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            };
             if poll_num > 0 {
+                if fds.get(1).and_then(|fd| fd.revents()).is_some() {
+                    self.mark_new_runc();
+                }
+                if fds[0].revents().is_none() {
+                    continue;
+                }
+                let mut overflowed = false;
                 for event in self.fd.read_event() {
+                    // The overflow event has no file descriptor to respond
+                    // to.
+                    if event.events.contains(&FanEvent::QueueOverflow) {
+                        overflowed = true;
+                        continue;
+                    }
                     match self.handle_event(event) {
                         Ok(_) => {}
                         Err(e) => error!(error = e.to_string().as_str(), "failed to handle event"),
                     };
                 }
-            } else {
-                debug!("poll_num <= 0!");
+                if overflowed {
+                    self.reconcile();
+                }
+            } else if poll_num < 0 {
+                debug!("poll_num < 0!");
                 break;
             }
         }
@@ -723,3 +2299,594 @@ impl RuncWatcher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fanotify_flags() {
+        let flags = fanotify_init_flags(&FanotifyFlags::default());
+        assert_eq!(
+            flags,
+            FAN_CLASS_CONTENT | FAN_CLOEXEC | FAN_UNLIMITED_QUEUE | FAN_UNLIMITED_MARKS
+        );
+
+        let flags = fanotify_init_flags(&FanotifyFlags {
+            unlimited_queue: false,
+            unlimited_marks: false,
+            audit: true,
+        });
+        assert_eq!(flags, FAN_CLASS_CONTENT | FAN_CLOEXEC | FAN_ENABLE_AUDIT);
+    }
+
+    #[test]
+    fn containerd_metadata_from_annotations() {
+        let annotations = collections::HashMap::from([
+            (
+                ANNOTATION_CONTAINERD_CONTAINER_NAME.to_string(),
+                "nginx".to_string(),
+            ),
+            (
+                ANNOTATION_CONTAINERD_SANDBOX_NAME.to_string(),
+                "web-5d8f".to_string(),
+            ),
+            (
+                ANNOTATION_CONTAINERD_SANDBOX_NAMESPACE.to_string(),
+                "default".to_string(),
+            ),
+            (
+                ANNOTATION_CONTAINERD_IMAGE_NAME.to_string(),
+                "docker.io/library/nginx:latest".to_string(),
+            ),
+        ]);
+        assert_eq!(
+            metadata_from_annotations(&annotations),
+            ContainerMetadata {
+                name: Some("nginx".to_string()),
+                pod: Some("web-5d8f".to_string()),
+                namespace: Some("default".to_string()),
+                image: Some("docker.io/library/nginx:latest".to_string()),
+                parent: None,
+                root: None,
+                runtime_id: None,
+            }
+        );
+    }
+
+    #[test]
+    fn nested_policy_is_not_less_strict() {
+        assert!(matches!(
+            policy_nested(
+                ContainerPolicyLevel::Restricted,
+                ContainerPolicyLevel::Privileged
+            ),
+            ContainerPolicyLevel::Restricted
+        ));
+        assert!(matches!(
+            policy_nested(
+                ContainerPolicyLevel::Baseline,
+                ContainerPolicyLevel::Restricted
+            ),
+            ContainerPolicyLevel::Restricted
+        ));
+        assert!(matches!(
+            policy_nested(
+                ContainerPolicyLevel::Privileged,
+                ContainerPolicyLevel::Baseline
+            ),
+            ContainerPolicyLevel::Baseline
+        ));
+        assert!(matches!(
+            policy_nested(
+                ContainerPolicyLevel::Offline,
+                ContainerPolicyLevel::Baseline
+            ),
+            ContainerPolicyLevel::Offline
+        ));
+    }
+
+    #[test]
+    fn kubernetes_default_policy() {
+        let image_policies = ImagePolicies::new(&[crate::settings::ImagePolicyRule {
+            image: "^docker.io/library/".to_string(),
+            policy: ContainerPolicyLevel::Baseline,
+        }])
+        .unwrap();
+        let policy = |namespace, image| {
+            policy_kubernetes_default(
+                namespace,
+                image,
+                &image_policies,
+                ContainerPolicyLevel::Restricted,
+            )
+        };
+        assert_eq!(
+            policy("kube-system", None),
+            ContainerPolicyLevel::Privileged
+        );
+        assert_eq!(
+            policy("default", Some("docker.io/library/nginx")),
+            ContainerPolicyLevel::Baseline
+        );
+        assert_eq!(
+            policy("default", Some("quay.io/app")),
+            ContainerPolicyLevel::Restricted
+        );
+    }
+
+    #[test]
+    fn privileged_policy_restrictions() {
+        let mut privileged = PrivilegedContainers {
+            mode: PrivilegedMode::Refuse,
+            namespaces: vec!["kube-system".to_string()],
+        };
+        assert_eq!(
+            policy_privileged(
+                ContainerPolicyLevel::Privileged,
+                Some("kube-system"),
+                &privileged
+            ),
+            Some(ContainerPolicyLevel::Privileged)
+        );
+        assert_eq!(
+            policy_privileged(ContainerPolicyLevel::Privileged, None, &privileged),
+            Some(ContainerPolicyLevel::Baseline)
+        );
+        assert_eq!(
+            policy_privileged(
+                ContainerPolicyLevel::Restricted,
+                Some("default"),
+                &privileged
+            ),
+            Some(ContainerPolicyLevel::Restricted)
+        );
+
+        privileged.mode = PrivilegedMode::Strict;
+        assert_eq!(
+            policy_privileged(
+                ContainerPolicyLevel::Privileged,
+                Some("default"),
+                &privileged
+            ),
+            None
+        );
+
+        privileged.mode = PrivilegedMode::Allow;
+        assert_eq!(
+            policy_privileged(
+                ContainerPolicyLevel::Privileged,
+                Some("default"),
+                &privileged
+            ),
+            Some(ContainerPolicyLevel::Privileged)
+        );
+    }
+
+    #[test]
+    fn unknown_container_policy() {
+        assert_eq!(
+            policy_unknown(UnknownContainerPolicy::Restricted),
+            Some(ContainerPolicyLevel::Restricted)
+        );
+        assert_eq!(
+            policy_unknown(UnknownContainerPolicy::Baseline),
+            Some(ContainerPolicyLevel::Baseline)
+        );
+        assert_eq!(policy_unknown(UnknownContainerPolicy::Deny), None);
+    }
+
+    #[test]
+    fn runc_paths() {
+        assert_eq!(
+            runc_path(42, "/run/containerd/abc/init.pid", false),
+            Path::new("/run/containerd/abc/init.pid")
+        );
+        assert_eq!(
+            runc_path(42, "/run/containerd/abc/init.pid", true),
+            Path::new("/proc/42/root/run/containerd/abc/init.pid")
+        );
+        assert_eq!(
+            runc_path(42, "init.pid", false),
+            Path::new("/proc/42/cwd/init.pid")
+        );
+    }
+
+    #[test]
+    fn nested_container_bundle() {
+        let (root, bundle) =
+            container_root_bundle(42, Some("/run/docker/abc".to_string()), true).unwrap();
+        assert_eq!(root, Path::new("/proc/42/root"));
+        assert_eq!(bundle, Path::new("/proc/42/root/run/docker/abc"));
+
+        let (_, bundle) = container_root_bundle(42, Some("abc".to_string()), true).unwrap();
+        assert_eq!(bundle, Path::new("/proc/42/cwd/abc"));
+
+        let (root, bundle) =
+            container_root_bundle(42, Some("/run/docker/abc".to_string()), false).unwrap();
+        assert_eq!(root, Path::new("/"));
+        assert_eq!(bundle, Path::new("/run/docker/abc"));
+    }
+
+    #[test]
+    fn docker_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir
+            .path()
+            .join("containers")
+            .join("abc")
+            .join("config.v2.json");
+        let image_dir = dir
+            .path()
+            .join("image")
+            .join("overlay2")
+            .join("imagedb")
+            .join("content")
+            .join("sha256");
+        fs::create_dir_all(&image_dir).unwrap();
+        fs::write(
+            image_dir.join("def"),
+            r#"{"config": {"Labels": {"org.lockc.policy": "privileged"}}}"#,
+        )
+        .unwrap();
+        let image_policies = ImagePolicies::new(&[crate::settings::ImagePolicyRule {
+            image: "^docker.io/library/".to_string(),
+            policy: ContainerPolicyLevel::Baseline,
+        }])
+        .unwrap();
+        let policy = |config: &str| {
+            let config: Value = serde_json::from_str(config).unwrap();
+            policy_docker(
+                &config_path,
+                &config,
+                &image_policies,
+                ContainerPolicyLevel::Restricted,
+            )
+        };
+
+        // Labels of the container take precedence over labels of the image.
+        assert_eq!(
+            policy(
+                r#"{"Driver": "overlay2", "Image": "sha256:def", "Config": {"Labels": {"lockc.policy": "baseline"}}}"#
+            ),
+            ContainerPolicyLevel::Baseline
+        );
+        assert_eq!(
+            policy(r#"{"Driver": "overlay2", "Image": "sha256:def", "Config": {"Labels": {}}}"#),
+            ContainerPolicyLevel::Privileged
+        );
+        // Image rules, then the default policy.
+        assert_eq!(
+            policy(
+                r#"{"Driver": "overlay2", "Image": "sha256:123", "Config": {"Image": "docker.io/library/nginx", "Labels": {}}}"#
+            ),
+            ContainerPolicyLevel::Baseline
+        );
+        assert_eq!(
+            policy(r#"{"Config": {"Image": "quay.io/app", "Labels": {}}}"#),
+            ContainerPolicyLevel::Restricted
+        );
+        assert_eq!(
+            policy(r#"{"Config": {"Labels": {"org.lockc.policy": "invalid"}}}"#),
+            ContainerPolicyLevel::Restricted
+        );
+    }
+
+    #[test]
+    fn docker_metadata_from_config() {
+        let config: Value = serde_json::from_str(
+            r#"{"Name": "/web", "Config": {"Image": "nginx:latest", "Labels": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            docker_metadata(&config),
+            ContainerMetadata {
+                name: Some("web".to_string()),
+                image: Some("nginx:latest".to_string()),
+                ..Default::default()
+            }
+        );
+        assert!(!docker_runtime_sockets(&config));
+        assert!(!docker_syslog(&config));
+
+        let config: Value = serde_json::from_str(
+            r#"{"Config": {"Labels": {"org.lockc.runtime-sockets": "allow", "org.lockc.syslog": "allow"}}}"#,
+        )
+        .unwrap();
+        assert!(docker_runtime_sockets(&config));
+        assert!(docker_syslog(&config));
+    }
+
+    #[test]
+    fn container_spec_from_config() {
+        let bundle = tempfile::tempdir().unwrap();
+        fs::create_dir(bundle.path().join("rootfs")).unwrap();
+        let config: ContainerConfig = serde_json::from_str(
+            r#"{
+                "mounts": [],
+                "annotations": {"org.lockc.runtime-sockets": "allow"},
+                "root": {"path": "rootfs"},
+                "process": {"capabilities": {"bounding": ["CAP_CHOWN", "CAP_SYS_ADMIN"]}},
+                "linux": {
+                    "namespaces": [{"type": "pid"}, {"type": "user"}],
+                    "uidMappings": [{"containerID": 0, "hostID": 100000, "size": 65536}]
+                }
+            }"#,
+        )
+        .unwrap();
+        let spec = config.spec(bundle.path());
+        assert!(spec.user_namespace);
+        assert!(spec.rootless);
+        assert!(spec.privileged);
+        assert!(spec.runtime_sockets);
+        assert_eq!(
+            spec.uid_mappings[0],
+            IdMapping {
+                container_id: 0,
+                host_id: 100000,
+                size: 65536
+            }
+        );
+        assert_eq!(spec.uid_mappings[1].size, 0);
+        assert_eq!(spec.gid_mappings[0].size, 0);
+        assert_eq!(
+            spec.rootfs,
+            InodeId::from_metadata(&fs::metadata(bundle.path().join("rootfs")).unwrap())
+        );
+
+        // User namespace mapping root of the host.
+        let config: ContainerConfig = serde_json::from_str(
+            r#"{
+                "mounts": [],
+                "linux": {
+                    "namespaces": [{"type": "user"}],
+                    "uidMappings": [{"containerID": 0, "hostID": 0, "size": 4294967295}]
+                }
+            }"#,
+        )
+        .unwrap();
+        let spec = config.spec(bundle.path());
+        assert!(spec.user_namespace);
+        assert!(!spec.rootless);
+
+        let config: ContainerConfig = serde_json::from_str(r#"{"mounts": []}"#).unwrap();
+        let spec = config.spec(bundle.path());
+        assert!(!spec.user_namespace);
+        assert!(!spec.rootless);
+        assert!(!spec.privileged);
+        assert!(!spec.runtime_sockets);
+        assert_eq!(spec.rootfs, InodeId::default());
+    }
+
+    #[test]
+    fn container_keys() {
+        assert_eq!(container_key(None, "abc"), "abc");
+        assert_eq!(container_key(Some("/run/runc/"), "abc"), "abc");
+
+        // The same ID in different containerd namespaces.
+        let k8s = container_key(Some("/run/containerd/runc/k8s.io"), "abc");
+        let moby = container_key(Some("/run/containerd/runc/moby"), "abc");
+        assert_ne!(k8s, moby);
+        assert!(k8s.ends_with("-abc"));
+        assert_eq!(containerd_container_key("k8s.io", "abc"), k8s);
+
+        // Keys of 64 characters long IDs fit in eBPF maps.
+        let id = "a".repeat(64);
+        let key = container_key(Some("/var/run/docker/runtime-runc/moby"), &id);
+        assert_eq!(key.len(), CONTAINER_KEY_MAX);
+    }
+
+    #[test]
+    fn containerd_namespace_from_bundle() {
+        assert_eq!(
+            containerd_namespace(Path::new(
+                "/run/containerd/io.containerd.runtime.v2.task/default/abc"
+            )),
+            Some("default".to_string())
+        );
+        assert_eq!(containerd_namespace(Path::new("/run/docker/abc")), None);
+        assert_eq!(containerd_namespace(Path::new("abc")), None);
+    }
+
+    #[test]
+    fn kubernetes_namespace_from_annotations() {
+        assert_eq!(
+            namespace_from_log_directory("/var/log/pods/kube-system_coredns_123").unwrap(),
+            "kube-system"
+        );
+        assert!(namespace_from_log_directory("/").is_err());
+
+        // CRI-O annotates every container with the namespace, pod and
+        // container names.
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("config.json"),
+            r#"{
+                "mounts": [],
+                "annotations": {
+                    "io.kubernetes.pod.namespace": "default",
+                    "io.kubernetes.pod.name": "web-5d8f",
+                    "io.kubernetes.container.name": "nginx",
+                    "org.lockc.resolved-policy": "restricted"
+                }
+            }"#,
+        )
+        .unwrap();
+        let container_data = container_type_data(dir.path()).unwrap();
+        assert!(matches!(
+            container_data.container_type,
+            ContainerType::Kubernetes
+        ));
+        assert_eq!(container_data.data.as_deref(), Some("default"));
+        // Trusted only for runc roots of CRI implementations.
+        assert!(container_data.annotated);
+        assert_eq!(container_data.metadata.name.as_deref(), Some("nginx"));
+        assert_eq!(container_data.metadata.pod.as_deref(), Some("web-5d8f"));
+        assert_eq!(
+            container_data.resolved_policy,
+            Some(ContainerPolicyLevel::Restricted)
+        );
+    }
+
+    #[test]
+    fn cri_dockerd_labels() {
+        let config: Value = serde_json::from_str(
+            r#"{
+                "Name": "/k8s_nginx_web-5d8f_default_123_0",
+                "Config": {
+                    "Image": "nginx:latest",
+                    "Labels": {
+                        "io.kubernetes.pod.namespace": "default",
+                        "io.kubernetes.pod.name": "web-5d8f",
+                        "io.kubernetes.container.name": "nginx",
+                        "annotation.org.lockc.resolved-policy": "baseline",
+                        "annotation.org.lockc.custom-policy": "gpu"
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            cri_dockerd_metadata(&config),
+            Some(ContainerMetadata {
+                name: Some("nginx".to_string()),
+                pod: Some("web-5d8f".to_string()),
+                namespace: Some("default".to_string()),
+                image: Some("nginx:latest".to_string()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            cri_dockerd_resolved_policy(&config),
+            Some(ContainerPolicyLevel::Baseline)
+        );
+        assert_eq!(docker_custom_policy(&config).as_deref(), Some("gpu"));
+
+        let config: Value = serde_json::from_str(
+            r#"{"Name": "/web", "Config": {"Image": "nginx:latest", "Labels": {}}}"#,
+        )
+        .unwrap();
+        assert_eq!(cri_dockerd_metadata(&config), None);
+        assert_eq!(cri_dockerd_resolved_policy(&config), None);
+        assert_eq!(docker_custom_policy(&config), None);
+
+        // Labels are trusted only for Docker instances of cri-dockerd.
+        let runtimes = KubernetesRuntimes {
+            cri_dockerd_roots: vec![PathBuf::from("/var/lib/cri-dockerd/docker/")],
+            ..Default::default()
+        };
+        let root = |path| docker_root(Path::new(path)).unwrap();
+        assert!(runtimes.cri_dockerd_trusted(root(
+            "/var/lib/cri-dockerd/docker/containers/abc/config.v2.json"
+        )));
+        assert!(
+            !runtimes.cri_dockerd_trusted(root("/var/lib/docker/containers/abc/config.v2.json"))
+        );
+    }
+
+    #[test]
+    fn nerdctl_container_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle = dir
+            .path()
+            .join(CONTAINERD_TASK_DIR)
+            .join("default")
+            .join("abc");
+        fs::create_dir_all(&bundle).unwrap();
+        fs::write(
+            bundle.join("config.json"),
+            r#"{
+                "mounts": [{"source": "/var/lib/nerdctl/abc/resolv.conf"}],
+                "annotations": {
+                    "nerdctl/name": "web",
+                    "nerdctl/namespace": "default",
+                    "org.lockc.policy": "restricted"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let container_data = container_type_data(&bundle).unwrap();
+        assert!(matches!(
+            container_data.container_type,
+            ContainerType::Containerd
+        ));
+        assert_eq!(container_data.metadata.name.as_deref(), Some("web"));
+        assert_eq!(
+            container_data.metadata.namespace.as_deref(),
+            Some("default")
+        );
+
+        let image_policies = ImagePolicies::new(&[]).unwrap();
+        assert_eq!(
+            policy_containerd(container_data.data.as_deref(), None, &image_policies),
+            ContainerPolicyLevel::Restricted
+        );
+        assert_eq!(
+            policy_containerd(None, None, &image_policies),
+            ContainerPolicyLevel::Baseline
+        );
+    }
+
+    #[test]
+    fn kubernetes_resolved_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let sandbox = dir.path().join("sandbox");
+        fs::create_dir_all(&sandbox).unwrap();
+        fs::write(
+            sandbox.join("config.json"),
+            r#"{
+                "mounts": [],
+                "annotations": {
+                    "io.kubernetes.cri.sandbox-log-directory": "/var/log/pods/default_web_123",
+                    "org.lockc.resolved-policy": "restricted",
+                    "org.lockc.syslog": "allow"
+                }
+            }"#,
+        )
+        .unwrap();
+        let container_data = container_type_data(&sandbox).unwrap();
+        assert!(matches!(
+            container_data.container_type,
+            ContainerType::Kubernetes
+        ));
+        assert_eq!(
+            container_data.resolved_policy,
+            Some(ContainerPolicyLevel::Restricted)
+        );
+        assert!(container_data.syslog);
+
+        // Containers of the pod inherit the annotations of the sandbox.
+        let container = dir.path().join("container");
+        fs::create_dir_all(&container).unwrap();
+        fs::write(
+            container.join("config.json"),
+            r#"{
+                "mounts": [],
+                "annotations": {"io.kubernetes.cri.sandbox-id": "sandbox"}
+            }"#,
+        )
+        .unwrap();
+        let container_data = container_type_data(&container).unwrap();
+        assert_eq!(container_data.data.as_deref(), Some("default"));
+        assert_eq!(
+            container_data.resolved_policy,
+            Some(ContainerPolicyLevel::Restricted)
+        );
+        assert!(container_data.syslog);
+
+        let annotations = |value: &str| {
+            collections::HashMap::from([(
+                ANNOTATION_RESOLVED_POLICY.to_string(),
+                value.to_string(),
+            )])
+        };
+        assert_eq!(
+            resolved_policy(&annotations("privileged")),
+            Some(ContainerPolicyLevel::Privileged)
+        );
+        assert_eq!(resolved_policy(&annotations("lockc")), None);
+        assert_eq!(resolved_policy(&annotations("unknown")), None);
+        assert_eq!(resolved_policy(&collections::HashMap::new()), None);
+    }
+}
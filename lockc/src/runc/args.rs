@@ -0,0 +1,582 @@
+//! Parsing of command lines of runc and containerd-shim, which tell what is
+//! going to happen to which container.
+//!
+//! Both use Go flag parsers, so long options can start with one or two
+//! dashes and values can be passed either as the next argument or after `=`.
+
+/// Global options of runc followed by a value. Options not listed here are
+/// boolean flags (e.g. `--debug`, `--systemd-cgroup`).
+const RUNC_GLOBAL_VALUE_OPTIONS: &[&str] = &["criu", "log", "log-format", "root", "rootless"];
+
+/// Returns options of the runc subcommand followed by a value. The same short
+/// option can be a flag in one subcommand and take a value in another (e.g.
+/// `delete -f` and `ps -f json`).
+fn runc_value_options(subcommand: &str) -> &'static [&'static str] {
+    match subcommand {
+        "checkpoint" => &[
+            "empty-ns",
+            "image-path",
+            "manage-cgroups-mode",
+            "page-server",
+            "parent-path",
+            "status-fd",
+            "work-path",
+        ],
+        "create" | "run" => &["b", "bundle", "console-socket", "pid-file", "preserve-fds"],
+        "events" => &["interval"],
+        "exec" => &[
+            "additional-gids",
+            "apparmor",
+            "c",
+            "cap",
+            "cgroup",
+            "console-socket",
+            "cwd",
+            "e",
+            "env",
+            "g",
+            "p",
+            "pid-file",
+            "preserve-fds",
+            "process",
+            "process-label",
+            "u",
+            "user",
+        ],
+        "ps" => &["f", "format"],
+        "restore" => &[
+            "b",
+            "bundle",
+            "console-socket",
+            "empty-ns",
+            "image-path",
+            "lsm-mount-context",
+            "lsm-profile",
+            "manage-cgroups-mode",
+            "pid-file",
+            "work-path",
+        ],
+        "update" => &[
+            "blkio-weight",
+            "cpu-period",
+            "cpu-quota",
+            "cpu-rt-period",
+            "cpu-rt-runtime",
+            "cpu-share",
+            "cpuset-cpus",
+            "cpuset-mems",
+            "kernel-memory",
+            "kernel-memory-tcp",
+            "l3-cache-schema",
+            "mem-bw-schema",
+            "memory",
+            "memory-reservation",
+            "memory-swap",
+            "pids-limit",
+            "r",
+            "resources",
+        ],
+        _ => &[],
+    }
+}
+
+/// runc subcommands which take a container ID as the first positional
+/// argument.
+const RUNC_CONTAINER_SUBCOMMANDS: &[&str] = &[
+    "checkpoint",
+    "create",
+    "delete",
+    "events",
+    "exec",
+    "kill",
+    "pause",
+    "ps",
+    "restore",
+    "resume",
+    "run",
+    "start",
+    "state",
+    "update",
+];
+
+/// Options of containerd-shim followed by a value.
+const SHIM_VALUE_OPTIONS: &[&str] = &["address", "bundle", "id", "namespace", "publish-binary"];
+
+/// Types of actions performed on the container, defined by a runc subcommand.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContainerAction {
+    /// Types we don't explicitly handle, except of registering the process as
+    /// containerized.
+    #[default]
+    Other,
+    /// Action of creating the container, when we want to register the new
+    /// container.
+    Create,
+    /// Action of deleting the container, when we want to remove the registered
+    /// container.
+    Delete,
+}
+
+impl ContainerAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContainerAction::Other => "other",
+            ContainerAction::Create => "create",
+            ContainerAction::Delete => "delete",
+        }
+    }
+}
+
+/// Container operation parsed from a command line.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RuncInvocation {
+    pub action: ContainerAction,
+    /// ID of the container, if the operation concerns one.
+    pub id: Option<String>,
+    /// Path to the bundle of the container (`--bundle`).
+    pub bundle: Option<String>,
+    /// Directory with the state of containers (`--root`).
+    pub root: Option<String>,
+    /// File which runc writes the PID of the container process to
+    /// (`--pid-file`).
+    pub pid_file: Option<String>,
+    /// containerd namespace of the container (`-namespace` of
+    /// containerd-shim).
+    pub namespace: Option<String>,
+}
+
+/// Argument of a command line.
+#[derive(Debug, PartialEq, Eq)]
+enum Arg {
+    /// Option with the name stripped of dashes and its value, if it takes
+    /// one.
+    Option(String, Option<String>),
+    Positional(String),
+}
+
+/// Splits command lines into options and positional arguments. Which options
+/// take a value is given for each argument, as it depends on the subcommand
+/// parsed so far.
+struct Args<I> {
+    args: I,
+    /// Whether `--` was seen, after which all arguments are positional.
+    options_end: bool,
+}
+
+impl<I: Iterator<Item = String>> Args<I> {
+    fn new(args: I) -> Self {
+        Args {
+            args,
+            options_end: false,
+        }
+    }
+
+    fn next(&mut self, value_options: &[&str]) -> Option<Arg> {
+        let arg = self.args.next()?;
+        if self.options_end {
+            return Some(Arg::Positional(arg));
+        }
+        if arg == "--" {
+            self.options_end = true;
+            return self.next(value_options);
+        }
+        let name = match arg.strip_prefix('-') {
+            Some(name) if !name.is_empty() => name.strip_prefix('-').unwrap_or(name),
+            _ => return Some(Arg::Positional(arg)),
+        };
+        let (name, value) = match name.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_owned())),
+            None => (name, None),
+        };
+        if !value_options.contains(&name) {
+            return Some(Arg::Option(name.to_owned(), None));
+        }
+        // A missing value ends the command line.
+        let value = match value {
+            Some(value) => value,
+            None => self.args.next()?,
+        };
+        Some(Arg::Option(name.to_owned(), Some(value)))
+    }
+}
+
+impl RuncInvocation {
+    /// Parses the command line of runc, including the binary name.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Self {
+        let mut invocation = RuncInvocation::default();
+        let mut args = args.into_iter();
+        // Skip the binary name.
+        args.next();
+        let mut args = Args::new(args);
+
+        // Global options and the subcommand.
+        let subcommand = loop {
+            match args.next(RUNC_GLOBAL_VALUE_OPTIONS) {
+                Some(Arg::Option(name, value)) => {
+                    if name == "root" {
+                        invocation.root = value;
+                    }
+                }
+                Some(Arg::Positional(subcommand)) => break subcommand,
+                None => return invocation,
+            }
+        };
+        invocation.action = match subcommand.as_str() {
+            "create" => ContainerAction::Create,
+            "delete" => ContainerAction::Delete,
+            _ => ContainerAction::Other,
+        };
+        if !RUNC_CONTAINER_SUBCOMMANDS.contains(&subcommand.as_str()) {
+            return invocation;
+        }
+
+        // Options of the subcommand and the container ID. Arguments after the
+        // ID belong to the subcommand (e.g. the process executed by `exec` or
+        // the signal sent by `kill`).
+        let value_options = runc_value_options(&subcommand);
+        while let Some(arg) = args.next(value_options) {
+            match arg {
+                Arg::Option(name, value) => match name.as_str() {
+                    "b" | "bundle" => invocation.bundle = value,
+                    "pid-file" => invocation.pid_file = value,
+                    _ => {}
+                },
+                Arg::Positional(id) => {
+                    invocation.id = Some(id);
+                    break;
+                }
+            }
+        }
+        invocation
+    }
+
+    /// Parses the command line of containerd-shim, including the binary
+    /// name. Only deletion of containers is recognized, other actions are
+    /// handled when the shim executes runc.
+    pub fn parse_shim<I: IntoIterator<Item = String>>(args: I) -> Self {
+        let mut invocation = RuncInvocation::default();
+        let mut args = args.into_iter();
+        args.next();
+        let mut args = Args::new(args);
+        while let Some(arg) = args.next(SHIM_VALUE_OPTIONS) {
+            match arg {
+                Arg::Option(name, value) => match name.as_str() {
+                    "id" => invocation.id = value,
+                    "bundle" => invocation.bundle = value,
+                    "namespace" => invocation.namespace = value,
+                    _ => {}
+                },
+                Arg::Positional(arg) => {
+                    if arg == "delete" {
+                        invocation.action = ContainerAction::Delete;
+                    }
+                }
+            }
+        }
+        invocation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn args(cmdline: &str) -> Vec<String> {
+        cmdline.split_whitespace().map(String::from).collect()
+    }
+
+    fn invocation(
+        action: ContainerAction,
+        id: Option<&str>,
+        bundle: Option<&str>,
+        root: Option<&str>,
+    ) -> RuncInvocation {
+        RuncInvocation {
+            action,
+            id: id.map(String::from),
+            bundle: bundle.map(String::from),
+            root: root.map(String::from),
+            pid_file: None,
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn parse_runc() {
+        let cases = [
+            // containerd (CRI)
+            (
+                "runc --root /run/containerd/runc/k8s.io --log /run/containerd/io.containerd.runtime.v2.task/k8s.io/abc/log.json --log-format json --systemd-cgroup create --bundle /run/containerd/io.containerd.runtime.v2.task/k8s.io/abc --pid-file /run/containerd/io.containerd.runtime.v2.task/k8s.io/abc/init.pid abc",
+                RuncInvocation {
+                    pid_file: Some(
+                        "/run/containerd/io.containerd.runtime.v2.task/k8s.io/abc/init.pid"
+                            .to_string(),
+                    ),
+                    ..invocation(
+                        ContainerAction::Create,
+                        Some("abc"),
+                        Some("/run/containerd/io.containerd.runtime.v2.task/k8s.io/abc"),
+                        Some("/run/containerd/runc/k8s.io"),
+                    )
+                },
+            ),
+            // Docker
+            (
+                "runc --root /var/run/docker/runtime-runc/moby --log log.json --log-format json create --bundle /run/containerd/io.containerd.runtime.v2.task/moby/def --pid-file init.pid --console-socket /tmp/pty.sock def",
+                RuncInvocation {
+                    pid_file: Some("init.pid".to_string()),
+                    ..invocation(
+                        ContainerAction::Create,
+                        Some("def"),
+                        Some("/run/containerd/io.containerd.runtime.v2.task/moby/def"),
+                        Some("/var/run/docker/runtime-runc/moby"),
+                    )
+                },
+            ),
+            // Options with values after `=`, short and single-dash options.
+            (
+                "runc --root=/run/runc create -b=/bundle --no-pivot abc",
+                invocation(
+                    ContainerAction::Create,
+                    Some("abc"),
+                    Some("/bundle"),
+                    Some("/run/runc"),
+                ),
+            ),
+            (
+                "runc -root /run/runc create -bundle /bundle abc",
+                invocation(
+                    ContainerAction::Create,
+                    Some("abc"),
+                    Some("/bundle"),
+                    Some("/run/runc"),
+                ),
+            ),
+            // Global boolean flags and flags with values.
+            (
+                "runc --root /run/runc --debug --systemd-cgroup --rootless=true create abc",
+                invocation(ContainerAction::Create, Some("abc"), None, Some("/run/runc")),
+            ),
+            (
+                "runc --rootless true --criu /usr/sbin/criu --debug=true create abc",
+                invocation(ContainerAction::Create, Some("abc"), None, None),
+            ),
+            // Options after the subcommand belong to it.
+            (
+                "runc delete -f abc",
+                invocation(ContainerAction::Delete, Some("abc"), None, None),
+            ),
+            (
+                "runc ps -f json abc",
+                invocation(ContainerAction::Other, Some("abc"), None, None),
+            ),
+            (
+                "runc update -r /tmp/resources.json --pids-limit 100 abc",
+                invocation(ContainerAction::Other, Some("abc"), None, None),
+            ),
+            (
+                "runc restore -b /bundle --image-path /tmp/checkpoint abc",
+                invocation(ContainerAction::Other, Some("abc"), Some("/bundle"), None),
+            ),
+            (
+                "runc start -- -abc",
+                invocation(ContainerAction::Other, Some("-abc"), None, None),
+            ),
+            // Bundle defaults to the working directory.
+            (
+                "runc create abc",
+                invocation(ContainerAction::Create, Some("abc"), None, None),
+            ),
+            (
+                "runc --root /run/runc delete --force abc",
+                invocation(
+                    ContainerAction::Delete,
+                    Some("abc"),
+                    None,
+                    Some("/run/runc"),
+                ),
+            ),
+            (
+                "runc start abc",
+                invocation(ContainerAction::Other, Some("abc"), None, None),
+            ),
+            (
+                "runc kill abc 9",
+                invocation(ContainerAction::Other, Some("abc"), None, None),
+            ),
+            // The process executed in the container is not parsed.
+            (
+                "runc exec --process /tmp/process.json --detach --pid-file exec.pid abc",
+                RuncInvocation {
+                    pid_file: Some("exec.pid".to_string()),
+                    ..invocation(ContainerAction::Other, Some("abc"), None, None)
+                },
+            ),
+            (
+                "runc exec -t abc runc create --bundle /x other",
+                invocation(ContainerAction::Other, Some("abc"), None, None),
+            ),
+            (
+                "runc exec -e PATH=/bin -u 0 abc sh -c id",
+                invocation(ContainerAction::Other, Some("abc"), None, None),
+            ),
+            // Subcommands without a container.
+            ("runc list", invocation(ContainerAction::Other, None, None, None)),
+            (
+                "runc --version",
+                invocation(ContainerAction::Other, None, None, None),
+            ),
+            // Incomplete command lines.
+            ("runc", invocation(ContainerAction::Other, None, None, None)),
+            (
+                "runc create --bundle",
+                invocation(ContainerAction::Create, None, None, None),
+            ),
+        ];
+        for (cmdline, expected) in cases {
+            assert_eq!(
+                RuncInvocation::parse(args(cmdline)),
+                expected,
+                "{}",
+                cmdline
+            );
+        }
+    }
+
+    #[test]
+    fn parse_shim() {
+        let cases = [
+            (
+                "containerd-shim-runc-v2 -namespace k8s.io -address /run/containerd/containerd.sock -publish-binary /usr/bin/containerd -id abc -bundle /run/containerd/io.containerd.runtime.v2.task/k8s.io/abc delete",
+                RuncInvocation {
+                    namespace: Some("k8s.io".to_string()),
+                    ..invocation(
+                        ContainerAction::Delete,
+                        Some("abc"),
+                        Some("/run/containerd/io.containerd.runtime.v2.task/k8s.io/abc"),
+                        None,
+                    )
+                },
+            ),
+            (
+                "containerd-shim-runc-v2 -namespace moby -id=def -address /run/containerd/containerd.sock start",
+                RuncInvocation {
+                    namespace: Some("moby".to_string()),
+                    ..invocation(ContainerAction::Other, Some("def"), None, None)
+                },
+            ),
+            (
+                "containerd-shim -namespace default -id delete",
+                RuncInvocation {
+                    namespace: Some("default".to_string()),
+                    ..invocation(ContainerAction::Other, Some("delete"), None, None)
+                },
+            ),
+        ];
+        for (cmdline, expected) in cases {
+            assert_eq!(
+                RuncInvocation::parse_shim(args(cmdline)),
+                expected,
+                "{}",
+                cmdline
+            );
+        }
+    }
+
+    /// Arguments which don't start with a dash, so they are never options.
+    fn value() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9/._]{1,20}"
+    }
+
+    /// Options which don't affect the result, as they appear on the command
+    /// line, with `value_options` taking a value and `flags` not.
+    fn noise(
+        value_options: &'static [&'static str],
+        flags: &'static [&'static str],
+    ) -> impl Strategy<Value = Vec<String>> {
+        let flags = flags
+            .iter()
+            .copied()
+            .filter(|flag| !value_options.contains(&flag.trim_start_matches('-')))
+            .collect::<Vec<_>>();
+        let value_options = value_options
+            .iter()
+            .copied()
+            .filter(|name| !["b", "bundle", "root"].contains(name))
+            .collect::<Vec<_>>();
+        let opt = if value_options.is_empty() {
+            prop::sample::select(flags)
+                .prop_map(|flag| vec![flag.to_string()])
+                .boxed()
+        } else {
+            prop_oneof![
+                prop::sample::select(flags).prop_map(|flag| vec![flag.to_string()]),
+                (prop::sample::select(value_options.clone()), value())
+                    .prop_map(|(name, value)| vec![format!("--{}", name), value]),
+                (prop::sample::select(value_options), value())
+                    .prop_map(|(name, value)| vec![format!("-{}={}", name, value)]),
+            ]
+            .boxed()
+        };
+        prop::collection::vec(opt, 0..4).prop_map(|opts| opts.into_iter().flatten().collect())
+    }
+
+    /// Formats an option with a value in one of the accepted forms.
+    fn with_value(
+        names: &'static [&'static str],
+        value: String,
+    ) -> impl Strategy<Value = Vec<String>> {
+        (prop::sample::select(names), 0..3usize).prop_map(move |(name, form)| match form {
+            0 => vec![format!("--{}", name), value.clone()],
+            1 => vec![format!("-{}", name), value.clone()],
+            _ => vec![format!("--{}={}", name, value)],
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn parse_runc_never_panics(args in prop::collection::vec(".*", 0..10)) {
+            RuncInvocation::parse(args.clone());
+            RuncInvocation::parse_shim(args);
+        }
+
+        #[test]
+        fn parse_runc_generated(
+            global in noise(RUNC_GLOBAL_VALUE_OPTIONS, &["--debug", "--systemd-cgroup", "-debug=false"]),
+            root in prop::option::of(value().prop_flat_map(|root| with_value(&["root"], root.clone()).prop_map(move |opt| (root.clone(), opt)))),
+            (subcommand, local) in prop::sample::select(RUNC_CONTAINER_SUBCOMMANDS)
+                .prop_flat_map(|subcommand| (Just(subcommand), noise(runc_value_options(subcommand), &["--detach", "-d", "--force", "-f", "--no-pivot", "-t"]))),
+            bundle in prop::option::of(value().prop_flat_map(|bundle| with_value(&["b", "bundle"], bundle.clone()).prop_map(move |opt| (bundle.clone(), opt)))),
+            id in value(),
+            rest in prop::collection::vec(".*", 0..4),
+        ) {
+            // Only some subcommands have the bundle option.
+            let bundle = bundle.filter(|_| runc_value_options(subcommand).contains(&"bundle"));
+            let mut cmdline = vec!["runc".to_string()];
+            cmdline.extend(global);
+            if let Some((_, opt)) = &root {
+                cmdline.extend(opt.clone());
+            }
+            cmdline.push(subcommand.to_string());
+            cmdline.extend(local);
+            if let Some((_, opt)) = &bundle {
+                cmdline.extend(opt.clone());
+            }
+            cmdline.push(id.clone());
+            cmdline.extend(rest);
+
+            let invocation = RuncInvocation::parse(cmdline);
+            let action = match subcommand {
+                "create" => ContainerAction::Create,
+                "delete" => ContainerAction::Delete,
+                _ => ContainerAction::Other,
+            };
+            prop_assert_eq!(invocation.action, action);
+            prop_assert_eq!(invocation.id, Some(id));
+            prop_assert_eq!(invocation.bundle, bundle.map(|(bundle, _)| bundle));
+            prop_assert_eq!(invocation.root, root.map(|(root, _)| root));
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! Discovery of runc binaries, including the ones which appear after lockc
+//! started (e.g. installed or upgraded by a package manager). Runtime
+//! directories and data directories of k3s and RKE2 are watched with
+//! inotify, runc binaries found in them are returned to be marked in
+//! fanotify.
+//!
+//! k3s and RKE2 embed runc and unpack it to `<data dir>/<release>/bin/runc`,
+//! where the release directory is named after the version or the hash of the
+//! bundle, so a new one appears with every upgrade.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+use nix::{
+    errno::Errno,
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+    unistd::close,
+};
+use tracing::{debug, warn};
+
+/// Data directories of k3s and RKE2, on the host and mounted under `/host`
+/// when lockc runs in a container.
+pub const DATA_DIRS: &[&str] = &[
+    "/var/lib/rancher/k3s/data",
+    "/var/lib/rancher/rke2/data",
+    "/host/var/lib/rancher/k3s/data",
+    "/host/var/lib/rancher/rke2/data",
+];
+
+/// Kind of a watched directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Level {
+    /// Directory with runc binaries, named after one of the runc names.
+    Runtime,
+    /// k3s or RKE2 data directory, containing releases.
+    Data,
+    /// k3s or RKE2 release directory, containing `bin`.
+    Release,
+    /// `bin` directory of a k3s or RKE2 release, containing `runc`.
+    Bin,
+}
+
+/// Events of entries appearing in a directory, either created or moved into
+/// it. Package managers and k3s write files and directories under temporary
+/// names and rename them.
+fn watch_flags() -> AddWatchFlags {
+    AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO
+}
+
+/// Watch of directories for runc binaries.
+pub struct RuncDiscovery {
+    inotify: Inotify,
+    /// File names of runc binaries in runtime directories.
+    names: Vec<String>,
+    watches: HashMap<WatchDescriptor, (PathBuf, Level)>,
+}
+
+impl RuncDiscovery {
+    /// Starts watching the runtime and data directories which exist and
+    /// returns runc binaries found in them.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        directories: &[P],
+        names: &[String],
+        data_dirs: &[Q],
+    ) -> Result<(Self, Vec<PathBuf>), Errno> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let mut discovery = RuncDiscovery {
+            inotify,
+            names: names.to_vec(),
+            watches: HashMap::new(),
+        };
+        let dirs = directories
+            .iter()
+            .map(|dir| (dir.as_ref(), Level::Runtime))
+            .chain(data_dirs.iter().map(|dir| (dir.as_ref(), Level::Data)));
+        let mut found = Vec::new();
+        for (dir, level) in dirs {
+            // When the source of a host mount in Kubernetes doesn't exist, an
+            // empty directory is created, so missing directories are not an
+            // error.
+            if dir.is_dir() {
+                discovery.scan(dir, level, &mut found);
+            } else {
+                debug!(path = ?dir, "directory doesn't exist, not watching it");
+            }
+        }
+        Ok((discovery, found))
+    }
+
+    /// Watches the directory and looks for runc binaries below it. The watch
+    /// is added before listing the directory, so no entry is missed. Adding
+    /// a watch of an already watched directory is a no-op.
+    fn scan(&mut self, dir: &Path, level: Level, found: &mut Vec<PathBuf>) {
+        match self.inotify.add_watch(dir, watch_flags()) {
+            Ok(wd) => {
+                debug!(path = ?dir, level = ?level, "watching for runc binaries");
+                self.watches.insert(wd, (dir.to_path_buf(), level));
+            }
+            Err(e) => {
+                warn!(error = e.to_string().as_str(), path = ?dir, "could not watch directory");
+                return;
+            }
+        }
+
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(error = e.to_string().as_str(), path = ?dir, "could not list directory");
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+            self.entry(dir, level, &entry.file_name(), is_dir, found);
+        }
+    }
+
+    /// Returns whether the entry of a directory of the given kind is a runc
+    /// binary.
+    fn is_runc(&self, level: Level, name: &OsStr, is_dir: bool) -> bool {
+        match level {
+            _ if is_dir => false,
+            Level::Runtime => self
+                .names
+                .iter()
+                .any(|runc_name| name == runc_name.as_str()),
+            Level::Bin => name == "runc",
+            Level::Data | Level::Release => false,
+        }
+    }
+
+    /// Handles an entry of a watched directory.
+    fn entry(
+        &mut self,
+        dir: &Path,
+        level: Level,
+        name: &OsStr,
+        is_dir: bool,
+        found: &mut Vec<PathBuf>,
+    ) {
+        let path = dir.join(name);
+        match level {
+            Level::Data if is_dir => self.scan(&path, Level::Release, found),
+            Level::Release if is_dir && name == "bin" => self.scan(&path, Level::Bin, found),
+            // The binary can be found both by listing a new directory and
+            // by an event queued before.
+            _ if self.is_runc(level, name, is_dir) && !found.contains(&path) => found.push(path),
+            _ => {}
+        }
+    }
+
+    /// Reads pending inotify events and returns runc binaries which appeared
+    /// since the last call. A binary replaced by a package upgrade is a new
+    /// inode, so it's returned again.
+    pub fn read_events(&mut self) -> Result<Vec<PathBuf>, Errno> {
+        let mut found = Vec::new();
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return Ok(found),
+            Err(e) => return Err(e),
+        };
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                warn!("inotify queue overflowed, rescanning directories");
+                let dirs: Vec<(PathBuf, Level)> = self
+                    .watches
+                    .values()
+                    .filter(|(_, level)| matches!(level, Level::Runtime | Level::Data))
+                    .cloned()
+                    .collect();
+                for (dir, level) in dirs {
+                    self.scan(&dir, level, &mut found);
+                }
+                continue;
+            }
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                self.watches.remove(&event.wd);
+                continue;
+            }
+            let name = match &event.name {
+                Some(name) => name,
+                None => continue,
+            };
+            let (dir, level) = match self.watches.get(&event.wd) {
+                Some((dir, level)) => (dir.clone(), *level),
+                None => continue,
+            };
+            let is_dir = event.mask.contains(AddWatchFlags::IN_ISDIR);
+            self.entry(&dir, level, name, is_dir, &mut found);
+        }
+        Ok(found)
+    }
+}
+
+impl AsRawFd for RuncDiscovery {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+impl Drop for RuncDiscovery {
+    fn drop(&mut self) {
+        let _ = close(self.inotify.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_DIRS: &[&str] = &[];
+
+    fn names() -> Vec<String> {
+        vec!["runc".to_string()]
+    }
+
+    fn write_runc(release: &Path) -> PathBuf {
+        fs::create_dir_all(release.join("bin")).unwrap();
+        let runc = release.join("bin").join("runc");
+        fs::write(&runc, "").unwrap();
+        runc
+    }
+
+    #[test]
+    fn discovery_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let k3s = dir.path().join("k3s/data");
+        let runc = write_runc(&k3s.join("abc123"));
+        fs::create_dir_all(k3s.join("def456/bin")).unwrap();
+        fs::write(k3s.join("def456/bin/containerd"), "").unwrap();
+        let bin = dir.path().join("usr/bin");
+        fs::create_dir_all(bin.join("runc")).unwrap();
+        fs::create_dir_all(dir.path().join("usr/sbin")).unwrap();
+        fs::write(dir.path().join("usr/sbin/runc"), "").unwrap();
+
+        let (_, found) = RuncDiscovery::new(
+            &[bin, dir.path().join("usr/sbin"), dir.path().join("missing")],
+            &names(),
+            &[k3s, dir.path().join("rke2/data")],
+        )
+        .unwrap();
+        assert_eq!(found, vec![dir.path().join("usr/sbin/runc"), runc]);
+    }
+
+    #[test]
+    fn discovery_new_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+        let (mut discovery, found) = RuncDiscovery::new(NO_DIRS, &names(), &[&data]).unwrap();
+        assert!(found.is_empty());
+
+        // A release created in place.
+        let runc = write_runc(&data.join("v1.25.4-rke2r1"));
+        let mut found = discovery.read_events().unwrap();
+        // A release unpacked under a temporary name and renamed.
+        let tmp = dir.path().join("tmp");
+        write_runc(&tmp);
+        fs::rename(&tmp, data.join("abc123")).unwrap();
+        found.extend(discovery.read_events().unwrap());
+
+        assert_eq!(found, vec![runc, data.join("abc123/bin/runc")]);
+        assert!(discovery.read_events().unwrap().is_empty());
+    }
+
+    #[test]
+    fn discovery_new_runtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        let (mut discovery, found) = RuncDiscovery::new(&[&bin], &names(), NO_DIRS).unwrap();
+        assert!(found.is_empty());
+
+        // Installed.
+        fs::write(bin.join("runc"), "").unwrap();
+        fs::write(bin.join("crun"), "").unwrap();
+        assert_eq!(discovery.read_events().unwrap(), vec![bin.join("runc")]);
+
+        // Upgraded by a package manager.
+        fs::write(bin.join("runc.dpkg-new"), "").unwrap();
+        fs::rename(bin.join("runc.dpkg-new"), bin.join("runc")).unwrap();
+        assert_eq!(discovery.read_events().unwrap(), vec![bin.join("runc")]);
+    }
+}
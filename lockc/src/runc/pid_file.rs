@@ -0,0 +1,171 @@
+//! Waiting for PID files written by runc. `runc create` writes the PID of the
+//! init process of the container to the `--pid-file` when the container is
+//! set up, after lockc allowed the runc process to execute.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use nix::{
+    poll::{poll, PollFd, PollFlags},
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify},
+    unistd::close,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PidFileError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Errno(#[from] nix::errno::Errno),
+
+    #[error("invalid PID file path {0}")]
+    Path(PathBuf),
+
+    #[error("could not parse the PID file content {0:?}")]
+    Parse(String),
+
+    #[error("PID file was not written in {0:?}")]
+    Timeout(Duration),
+}
+
+/// Watch for a PID file.
+pub struct PidFile {
+    /// Directory of the PID file, kept open as its path may be valid only
+    /// while runc is running (e.g. `/proc/<pid>/cwd`).
+    dir: fs::File,
+    name: OsString,
+    inotify: Inotify,
+}
+
+impl PidFile {
+    /// Starts watching for the PID file. runc writes the file under a
+    /// temporary name and renames it, so both renames and writes are
+    /// watched.
+    pub fn watch(path: &Path) -> Result<Self, PidFileError> {
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name.to_owned()),
+            _ => return Err(PidFileError::Path(path.to_path_buf())),
+        };
+        let dir_path = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        let pid_file = PidFile {
+            dir: fs::File::open(dir_path)?,
+            name,
+            inotify,
+        };
+        inotify.add_watch(
+            dir_path,
+            AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO,
+        )?;
+        Ok(pid_file)
+    }
+
+    /// Returns the PID if the file exists and is complete.
+    fn read(&self) -> Result<Option<i32>, PidFileError> {
+        let path = Path::new("/proc/self/fd")
+            .join(self.dir.as_raw_fd().to_string())
+            .join(&self.name);
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let content = content.trim();
+        if content.is_empty() {
+            return Ok(None);
+        }
+        content
+            .parse()
+            .map(Some)
+            .map_err(|_| PidFileError::Parse(content.to_string()))
+    }
+
+    /// Waits until the PID file is written and returns the PID.
+    pub fn wait(&self, timeout: Duration) -> Result<i32, PidFileError> {
+        let deadline = Instant::now() + timeout;
+        // The file might have been written before the watch was added.
+        if let Some(pid) = self.read()? {
+            return Ok(pid);
+        }
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(PidFileError::Timeout(timeout));
+            }
+            let mut fds = [PollFd::new(self.inotify.as_raw_fd(), PollFlags::POLLIN)];
+            if poll(&mut fds, remaining.as_millis().max(1) as i32)? == 0 {
+                continue;
+            }
+            let written = self
+                .inotify
+                .read_events()?
+                .iter()
+                .any(|event| event.name.as_ref() == Some(&self.name));
+            if written {
+                if let Some(pid) = self.read()? {
+                    return Ok(pid);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = close(self.inotify.as_raw_fd());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn pid_file_renamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("init.pid");
+        let pid_file = PidFile::watch(&path).unwrap();
+
+        let tmp_path = dir.path().join(".init.pid");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            fs::write(&tmp_path, "4242").unwrap();
+            fs::rename(&tmp_path, &path).unwrap();
+        });
+        assert_eq!(pid_file.wait(Duration::from_secs(10)).unwrap(), 4242);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn pid_file_written_before_wait() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("init.pid");
+        let pid_file = PidFile::watch(&path).unwrap();
+        fs::write(&path, "17\n").unwrap();
+        assert_eq!(pid_file.wait(Duration::from_secs(10)).unwrap(), 17);
+    }
+
+    #[test]
+    fn pid_file_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = PidFile::watch(&dir.path().join("init.pid")).unwrap();
+        fs::write(dir.path().join("other.pid"), "1").unwrap();
+        assert!(matches!(
+            pid_file.wait(Duration::from_millis(100)),
+            Err(PidFileError::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,250 @@
+//! Debug recorder of fanotify events. With `--record`, every execution of
+//! runc reported by fanotify is written to a file as a line of JSON, with the
+//! command line of the process and the digest of the container bundle. With
+//! `--replay`, the recorded events are handled instead of watching runc, so
+//! registration bugs reported from other nodes can be reproduced.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use procfs::{process::Process, ProcResult};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::args::{ContainerAction, RuncInvocation};
+use crate::integrity::sha256_hex;
+
+#[derive(Error, Debug)]
+pub enum RecorderError {
+    #[error("could not open the recording {}: {source}", .path.display())]
+    Open {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("could not write the recording: {0}")]
+    Write(#[from] io::Error),
+
+    #[error("invalid event in line {line} of the recording: {source}")]
+    Parse {
+        line: usize,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Process which executed a runc binary, as seen when fanotify reported the
+/// execution.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    pub pid: i32,
+    pub comm: String,
+    pub cmdline: Vec<String>,
+    /// PIDs of the process in the PID namespaces it's visible in, the last
+    /// one in its own namespace.
+    pub ns_pids: Vec<i32>,
+    /// Working directory, which is the bundle when runc is not given one.
+    pub cwd: Option<PathBuf>,
+}
+
+impl ProcessSnapshot {
+    pub fn read(pid: i32) -> ProcResult<Self> {
+        let process = Process::new(pid)?;
+        Ok(ProcessSnapshot {
+            pid,
+            comm: process.stat()?.comm,
+            cmdline: process.cmdline()?,
+            ns_pids: process.status()?.nspid.unwrap_or_else(|| vec![pid]),
+            cwd: process.cwd().ok(),
+        })
+    }
+
+    /// Returns the PID of the process in its own PID namespace.
+    pub fn ns_pid(&self) -> i32 {
+        self.ns_pids.last().copied().unwrap_or(self.pid)
+    }
+
+    /// Returns the number of nested PID namespaces the process is visible
+    /// in.
+    pub fn pid_ns_depth(&self) -> usize {
+        self.ns_pids.len().max(1)
+    }
+}
+
+/// Digest of the OCI runtime spec in the bundle of a created container.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleDigest {
+    pub path: PathBuf,
+    /// SHA-256 of `config.json`, `None` if it couldn't be read.
+    pub config_sha256: Option<String>,
+}
+
+impl BundleDigest {
+    /// Returns the digest of the bundle if the process is runc creating a
+    /// container.
+    pub fn of(process: &ProcessSnapshot) -> Option<Self> {
+        if process.comm != "runc" {
+            return None;
+        }
+        let invocation = RuncInvocation::parse(process.cmdline.clone());
+        if invocation.action != ContainerAction::Create {
+            return None;
+        }
+        let path = match (invocation.bundle, &process.cwd) {
+            (Some(bundle), _) if Path::new(&bundle).is_absolute() => PathBuf::from(bundle),
+            (Some(bundle), Some(cwd)) => cwd.join(bundle),
+            (None, Some(cwd)) => cwd.clone(),
+            (_, None) => return None,
+        };
+        let config_sha256 = fs::read(path.join("config.json"))
+            .ok()
+            .map(|data| sha256_hex(&data));
+        Some(BundleDigest {
+            path,
+            config_sha256,
+        })
+    }
+
+    /// Returns whether the bundle on this node differs from the recorded
+    /// one, in which case the replay may not reproduce the registration.
+    pub fn differs(&self) -> bool {
+        let config_sha256 = fs::read(self.path.join("config.json"))
+            .ok()
+            .map(|data| sha256_hex(&data));
+        config_sha256 != self.config_sha256
+    }
+}
+
+/// Execution of runc reported by fanotify.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedEvent {
+    /// Milliseconds since the start of the recording.
+    pub time_ms: u64,
+    /// Path of the executed binary.
+    pub path: String,
+    pub process: ProcessSnapshot,
+    pub bundle: Option<BundleDigest>,
+}
+
+/// Writes fanotify events to the recording file.
+pub struct Recorder {
+    file: LineWriter<fs::File>,
+    started: Instant,
+}
+
+impl Recorder {
+    /// Creates the recording file, appending to an existing one.
+    pub fn create(path: &Path) -> Result<Self, RecorderError> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|source| RecorderError::Open {
+                path: path.to_path_buf(),
+                source,
+            })?;
+        Ok(Recorder {
+            file: LineWriter::new(file),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(&mut self, path: &str, process: &ProcessSnapshot) -> Result<(), RecorderError> {
+        let event = RecordedEvent {
+            time_ms: self.started.elapsed().as_millis() as u64,
+            path: path.to_string(),
+            process: process.clone(),
+            bundle: BundleDigest::of(process),
+        };
+        let mut line = serde_json::to_vec(&event).map_err(io::Error::from)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Reads events from the recording file.
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedEvent>, RecorderError> {
+    let file = fs::File::open(path).map_err(|source| RecorderError::Open {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let mut events = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line).map_err(|source| RecorderError::Parse {
+            line: i + 1,
+            source,
+        })?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn record_and_read() {
+        let dir = tempdir().unwrap();
+        let bundle = dir.path().join("bundle");
+        fs::create_dir(&bundle).unwrap();
+        fs::write(bundle.join("config.json"), b"{}").unwrap();
+
+        let process = ProcessSnapshot {
+            pid: 42,
+            comm: "runc".to_string(),
+            cmdline: vec![
+                "runc".to_string(),
+                "create".to_string(),
+                "--bundle".to_string(),
+                "bundle".to_string(),
+                "abc".to_string(),
+            ],
+            ns_pids: vec![42],
+            cwd: Some(dir.path().to_path_buf()),
+        };
+        let path = dir.path().join("events.jsonl");
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder.record("/usr/bin/runc", &process).unwrap();
+        recorder
+            .record(
+                "/usr/bin/runc",
+                &ProcessSnapshot {
+                    cmdline: vec!["runc".to_string(), "start".to_string(), "abc".to_string()],
+                    ..process.clone()
+                },
+            )
+            .unwrap();
+        drop(recorder);
+
+        let events = read_recording(&path).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].process, process);
+        let digest = events[0].bundle.as_ref().unwrap();
+        assert_eq!(digest.path, bundle);
+        assert_eq!(digest.config_sha256, Some(sha256_hex(b"{}")));
+        assert!(!digest.differs());
+        assert!(events[1].bundle.is_none());
+
+        fs::write(bundle.join("config.json"), b"{\"ociVersion\":\"1.0.2\"}").unwrap();
+        assert!(digest.differs());
+
+        fs::write(&path, b"{\"time_ms\":0}\n").unwrap();
+        assert!(matches!(
+            read_recording(&path),
+            Err(RecorderError::Parse { line: 1, .. })
+        ));
+    }
+}
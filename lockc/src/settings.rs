@@ -0,0 +1,1662 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use lockc_common::{verdict::UsernsOverride, ContainerPolicyLevel, Hook};
+use regex::Regex;
+use serde::{de::DeserializeOwned, Deserialize};
+use thiserror::Error;
+
+use crate::{
+    load::BPF_OBJECT_PATHS,
+    profiles::{AllowedPaths, Profile},
+    sysutils::BPF_PIN_PATH,
+};
+
+/// Default path of the file mapping Kubernetes namespaces to policies.
+pub const NAMESPACE_POLICIES_PATH: &str = "/etc/lockc/namespace_policies.yaml";
+
+/// Rule assigning a policy level to containers whose image reference matches
+/// the given regular expression.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImagePolicyRule {
+    pub image: String,
+    pub policy: ContainerPolicyLevel,
+}
+
+/// How runc binaries are watched with fanotify.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuncWatchMode {
+    /// Watch runc binaries found in well-known locations.
+    Paths,
+    /// Watch executions of all binaries on the given filesystems and filter
+    /// runc binaries by name or digest. Catches copies of runc in any
+    /// location, at the cost of handling every execution on the host.
+    Filesystem,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuncWatch {
+    pub mode: RuncWatchMode,
+    /// Paths on the filesystems to watch in the filesystem mode.
+    pub filesystems: Vec<PathBuf>,
+    /// File names of runc binaries. In the paths mode, binaries with these
+    /// names in `directories` are watched. In the filesystem mode, binaries
+    /// matching one of `runc_digests` are handled regardless of their name.
+    pub names: Vec<String>,
+    /// Directories with runc binaries in the paths mode. They are watched
+    /// for binaries installed or upgraded after lockc started.
+    pub directories: Vec<PathBuf>,
+}
+
+impl Default for RuncWatch {
+    fn default() -> Self {
+        RuncWatch {
+            mode: RuncWatchMode::Paths,
+            filesystems: vec![PathBuf::from("/")],
+            names: vec!["runc".to_string()],
+            directories: [
+                "/usr/bin",
+                "/usr/sbin",
+                "/usr/local/bin",
+                "/usr/local/sbin",
+                "/run/torcx/unpack/docker/bin",
+                "/host/usr/bin",
+                "/host/usr/sbin",
+                "/host/usr/local/bin",
+                "/host/usr/local/sbin",
+                "/host/run/torcx/unpack/docker/bin",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+        }
+    }
+}
+
+/// Flags of the fanotify group watching runc executions.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanotifyFlags {
+    /// Whether the event queue is unlimited. The default limit of 16384
+    /// events can be exceeded when many containers start at once. Executions
+    /// of runc whose events didn't fit in the queue are allowed by the
+    /// kernel and lockc reconciles running runc processes afterwards.
+    pub unlimited_queue: bool,
+    /// Whether the number of marks is unlimited. The default limit is 8192
+    /// marks, which can be exceeded by runc binaries unpacked by many k3s or
+    /// RKE2 upgrades.
+    pub unlimited_marks: bool,
+    /// Whether executions of runc denied by lockc are recorded in the audit
+    /// log.
+    pub audit: bool,
+}
+
+impl Default for FanotifyFlags {
+    fn default() -> Self {
+        FanotifyFlags {
+            unlimited_queue: true,
+            unlimited_marks: true,
+            audit: false,
+        }
+    }
+}
+
+/// What the runc watcher does when the eBPF command channel is full.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelOverflow {
+    /// Block runc until the eBPF thread catches up.
+    Block,
+    /// Let runc execute without registering the container or process.
+    FailOpen,
+    /// Deny execution of runc.
+    FailClose,
+}
+
+/// What the runc watcher does when the eBPF thread doesn't respond to a
+/// command in time.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseTimeout {
+    /// Let runc execute, even though the container or process might not be
+    /// registered.
+    FailOpen,
+    /// Deny execution of runc.
+    FailClose,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EbpfChannel {
+    /// Number of eBPF commands which can be queued.
+    pub capacity: usize,
+    pub overflow: ChannelOverflow,
+    /// Milliseconds to wait for the result of an eBPF command, 0 to wait
+    /// indefinitely.
+    pub response_timeout_ms: u64,
+    pub on_timeout: ResponseTimeout,
+}
+
+impl Default for EbpfChannel {
+    fn default() -> Self {
+        EbpfChannel {
+            capacity: 100,
+            overflow: ChannelOverflow::Block,
+            response_timeout_ms: 5000,
+            on_timeout: ResponseTimeout::FailClose,
+        }
+    }
+}
+
+/// What happens when eBPF maps fail and can't be recovered.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SupervisionFailure {
+    /// Keep running and deny execution of runc.
+    FailClose,
+    /// Exit with an error, so the service manager restarts lockc.
+    Exit,
+}
+
+/// Supervision of eBPF maps operated on by the eBPF thread.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EbpfSupervision {
+    /// Seconds between checks of eBPF maps and their pins, 0 to check them
+    /// only when a map operation fails.
+    pub check_interval_s: u64,
+    /// Number of recovery attempts before giving up.
+    pub recovery_attempts: u32,
+    /// Milliseconds before the first recovery attempt, doubled with every
+    /// attempt.
+    pub initial_backoff_ms: u64,
+    pub on_failure: SupervisionFailure,
+}
+
+impl Default for EbpfSupervision {
+    fn default() -> Self {
+        EbpfSupervision {
+            check_interval_s: 30,
+            recovery_attempts: 5,
+            initial_backoff_ms: 100,
+            on_failure: SupervisionFailure::Exit,
+        }
+    }
+}
+
+/// Tokio runtime of the asynchronous part of lockc.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AsyncRuntime {
+    /// Number of worker threads, 0 for one per CPU.
+    pub worker_threads: usize,
+}
+
+/// What happens to containers whose OCI runtime spec violates their policy
+/// level.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpecValidationMode {
+    /// Specs are not validated.
+    Off,
+    /// Violations are logged.
+    Warn,
+    /// Execution of runc creating the container is denied.
+    Strict,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpecValidation {
+    pub mode: SpecValidationMode,
+}
+
+impl Default for SpecValidation {
+    fn default() -> Self {
+        SpecValidation {
+            mode: SpecValidationMode::Warn,
+        }
+    }
+}
+
+/// Network in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`. A single
+/// address is a network of one address.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid network {0:?}, expected an IP address with an optional prefix length")]
+pub struct ParseCidrError(String);
+
+impl FromStr for Cidr {
+    type Err = ParseCidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseCidrError(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| err())?,
+            None => max,
+        };
+        if prefix_len > max {
+            return Err(err());
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = ParseCidrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Cloud metadata endpoints: the link-local one used by most providers and
+/// the IPv6 one of AWS.
+const METADATA_NETWORKS: &[Cidr] = &[
+    Cidr {
+        addr: IpAddr::V4(Ipv4Addr::new(169, 254, 169, 254)),
+        prefix_len: 32,
+    },
+    Cidr {
+        addr: IpAddr::V6(Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)),
+        prefix_len: 128,
+    },
+];
+
+/// Restrictions of connections from containers, enforced by cgroup programs
+/// attached to cgroups of containers. Requires cgroup v2.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Egress {
+    /// Policy levels of containers with restricted egress. Empty disables
+    /// the restrictions.
+    pub policy_levels: Vec<ContainerPolicyLevel>,
+    /// Whether connections to cloud metadata endpoints are denied.
+    pub deny_metadata: bool,
+    /// Networks which containers can't connect to.
+    pub deny_networks: Vec<Cidr>,
+}
+
+impl Default for Egress {
+    fn default() -> Self {
+        Egress {
+            policy_levels: vec![ContainerPolicyLevel::Restricted],
+            deny_metadata: true,
+            deny_networks: Vec::new(),
+        }
+    }
+}
+
+impl Egress {
+    /// Returns all denied networks, including metadata endpoints if they are
+    /// denied.
+    pub fn networks(&self) -> Vec<Cidr> {
+        let metadata = if self.deny_metadata {
+            METADATA_NETWORKS
+        } else {
+            &[]
+        };
+        metadata
+            .iter()
+            .chain(&self.deny_networks)
+            .copied()
+            .collect()
+    }
+}
+
+/// Response to attacks on containers: containers with repeated denials, or
+/// with a denial of a high-severity operation, are frozen through the cgroup
+/// v2 freezer until they are thawed with `lockctl container thaw`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IncidentResponse {
+    /// Policy levels of containers which get frozen. Empty disables the
+    /// response.
+    pub policy_levels: Vec<ContainerPolicyLevel>,
+    /// Number of denials within the window which freezes the container.
+    pub denial_threshold: u64,
+    /// Length of the window of counted denials, in seconds.
+    pub window_s: u64,
+    /// Hooks whose first denial freezes the container.
+    pub immediate_hooks: Vec<Hook>,
+}
+
+impl Default for IncidentResponse {
+    fn default() -> Self {
+        IncidentResponse {
+            policy_levels: Vec::new(),
+            denial_threshold: 20,
+            window_s: 60,
+            immediate_hooks: vec![Hook::InodePermission],
+        }
+    }
+}
+
+impl IncidentResponse {
+    pub fn enabled(&self) -> bool {
+        !self.policy_levels.is_empty()
+    }
+}
+
+/// Pushing of registered containers and summaries of violations to
+/// `lockc-collector`, for fleet-wide visibility without Prometheus.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Reporter {
+    /// URL of the collector, e.g. `https://collector:9849/report`. Reports
+    /// are not sent when not set.
+    pub url: Option<String>,
+    /// Name of the node in reports. Defaults to `NODE_NAME`, then to the
+    /// hostname.
+    pub node: Option<String>,
+    /// Interval of reports, in seconds.
+    pub interval_s: u64,
+    /// PEM file with CA certificates verifying the collector. The system
+    /// ones are used when not set.
+    pub ca_cert: Option<PathBuf>,
+    /// PEM files with the client certificate and its private key, for
+    /// mutual TLS.
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+impl Default for Reporter {
+    fn default() -> Self {
+        Reporter {
+            url: None,
+            node: None,
+            interval_s: 60,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+/// Paths of procfs which runc masks or makes read-only by default.
+const MASKED_PROC_PATHS: &[&str] = &[
+    "/proc/acpi",
+    "/proc/asound",
+    "/proc/kcore",
+    "/proc/keys",
+    "/proc/latency_stats",
+    "/proc/timer_list",
+    "/proc/timer_stats",
+    "/proc/sched_debug",
+    "/proc/scsi",
+    "/proc/bus",
+    "/proc/fs",
+    "/proc/irq",
+    "/proc/sys",
+    "/proc/sysrq-trigger",
+];
+
+/// Protection of paths of procfs masked by container runtimes from being
+/// unmounted or mounted over.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcMasking {
+    /// Policy levels of containers which can't unmask the paths. Empty
+    /// disables the protection.
+    pub policy_levels: Vec<ContainerPolicyLevel>,
+    /// Masked and read-only paths of procfs, matched by their inodes.
+    pub paths: Vec<String>,
+}
+
+impl Default for ProcMasking {
+    fn default() -> Self {
+        ProcMasking {
+            policy_levels: vec![
+                ContainerPolicyLevel::Restricted,
+                ContainerPolicyLevel::Offline,
+            ],
+            paths: MASKED_PROC_PATHS
+                .iter()
+                .map(|path| path.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// Audit trail of changes of container policies.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyAudit {
+    /// File to which every change is appended as a line of JSON. Changes
+    /// recorded there before a restart are queryable again.
+    pub log: Option<PathBuf>,
+    /// Number of the most recent changes kept in memory for `lockctl
+    /// history`.
+    pub retained: usize,
+    /// Interval of checkpoints appended to the log, in seconds. 0 disables
+    /// them.
+    pub checkpoint_interval_s: u64,
+    /// Ed25519 key (PKCS#8 document) signing the checkpoints. If not set,
+    /// checkpoints are not signed.
+    pub checkpoint_key: Option<PathBuf>,
+}
+
+impl Default for PolicyAudit {
+    fn default() -> Self {
+        PolicyAudit {
+            log: None,
+            retained: 1000,
+            checkpoint_interval_s: 3600,
+            checkpoint_key: None,
+        }
+    }
+}
+
+/// Default file of the fingerprint store.
+const FINGERPRINTS_STORE_PATH: &str = "/var/lib/lockc/fingerprints.json";
+
+/// Collection of behavioral fingerprints of container images, from which
+/// `lockctl fingerprint suggest` derives policies of images.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fingerprints {
+    /// Whether fingerprints are collected. Uses of capabilities are recorded
+    /// by an additional LSM program, attached only when enabled.
+    pub enabled: bool,
+    /// File where fingerprints are saved as JSON and read from on start. If
+    /// not set, they are kept only in memory.
+    pub store: Option<PathBuf>,
+    /// Interval of saving changed fingerprints to the store, in seconds.
+    pub save_interval_s: u64,
+}
+
+impl Default for Fingerprints {
+    fn default() -> Self {
+        Fingerprints {
+            enabled: false,
+            store: Some(PathBuf::from(FINGERPRINTS_STORE_PATH)),
+            save_interval_s: 60,
+        }
+    }
+}
+
+/// Host processes which are never attributed to containers, even when they
+/// are executed by a container runtime or forked from a containerized
+/// process.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExcludedProcesses {
+    /// Command names. Any process can choose its command name, so containers
+    /// can use them to escape tracking.
+    pub comms: Vec<String>,
+    /// Paths of executables on the host, matched by their inodes.
+    pub paths: Vec<String>,
+}
+
+/// Overrides of policy decisions for rootless containers, per hook.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserNamespaces {
+    /// Bind mounts.
+    pub mount: UsernsOverride,
+    /// Changing the UID to 0.
+    pub setuid: UsernsOverride,
+}
+
+/// What happens to containers which would get the privileged policy outside
+/// of the allowed namespaces.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivilegedMode {
+    /// Containers are registered with the privileged policy.
+    Allow,
+    /// Containers are registered with the baseline policy instead.
+    Refuse,
+    /// Execution of runc creating the container is denied.
+    Strict,
+}
+
+/// What happens to containers whose engine can't be detected from the
+/// bundle, so neither their metadata nor their policy is known.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownContainerPolicy {
+    /// Containers are registered with the restricted policy.
+    Restricted,
+    /// Containers are registered with the baseline policy.
+    Baseline,
+    /// Execution of runc creating the container is denied.
+    Deny,
+}
+
+impl fmt::Display for UnknownContainerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnknownContainerPolicy::Restricted => write!(f, "restricted"),
+            UnknownContainerPolicy::Baseline => write!(f, "baseline"),
+            UnknownContainerPolicy::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivilegedContainers {
+    pub mode: PrivilegedMode,
+    /// Kubernetes namespaces, or containerd namespaces, in which containers
+    /// can get the privileged policy.
+    pub namespaces: Vec<String>,
+}
+
+impl Default for PrivilegedContainers {
+    fn default() -> Self {
+        PrivilegedContainers {
+            mode: PrivilegedMode::Allow,
+            namespaces: vec!["kube-system".to_string()],
+        }
+    }
+}
+
+impl PrivilegedContainers {
+    /// Returns whether containers in the given namespace can get the
+    /// privileged policy.
+    pub fn allowed(&self, namespace: Option<&str>) -> bool {
+        match self.mode {
+            PrivilegedMode::Allow => true,
+            PrivilegedMode::Refuse | PrivilegedMode::Strict => namespace
+                .map(|namespace| self.namespaces.iter().any(|allowed| allowed == namespace))
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Syslog {
+    /// Kubernetes namespaces, or containerd namespaces, whose containers can
+    /// read the kernel log regardless of their policy level.
+    pub exempt_namespaces: Vec<String>,
+}
+
+impl Syslog {
+    /// Returns whether containers in the given namespace are exempted from
+    /// denials of reading the kernel log.
+    pub fn exempted(&self, namespace: Option<&str>) -> bool {
+        namespace
+            .map(|namespace| {
+                self.exempt_namespaces
+                    .iter()
+                    .any(|exempt| exempt == namespace)
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Container runtimes trusted to tell the Kubernetes namespace of their
+/// containers. Namespaces from annotations and labels select policies and
+/// exemptions of namespaces, and anyone creating containers can set them, so
+/// they are read only from runtimes listed here. Containers of containerd's
+/// CRI plugin are always trusted.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubernetesRuntimes {
+    /// runc roots (`--root`) of CRI implementations which annotate every
+    /// container with `io.kubernetes.pod.namespace`, e.g. CRI-O. No other
+    /// engine must use them.
+    pub annotated_runc_roots: Vec<PathBuf>,
+    /// Data roots of Docker instances used only by cri-dockerd, whose
+    /// containers are labeled with `io.kubernetes.pod.namespace`. Only
+    /// cri-dockerd must have access to their sockets.
+    pub cri_dockerd_roots: Vec<PathBuf>,
+}
+
+impl KubernetesRuntimes {
+    /// Returns whether namespace annotations of containers created with the
+    /// runc root are trusted.
+    pub fn annotations_trusted(&self, runc_root: &Path) -> bool {
+        self.annotated_runc_roots
+            .iter()
+            .any(|root| root == runc_root)
+    }
+
+    /// Returns whether namespace labels of containers of the Docker instance
+    /// with the data root are trusted.
+    pub fn cri_dockerd_trusted(&self, docker_root: &Path) -> bool {
+        self.cri_dockerd_roots
+            .iter()
+            .any(|root| root == docker_root)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Rules mapping container images to policy levels. They are used only
+    /// when a container has no explicit policy label.
+    pub image_policies: Vec<ImagePolicyRule>,
+    /// Hex-encoded Ed25519 public key. When set, the signature of the eBPF
+    /// object is verified before loading it.
+    pub bpf_public_key: Option<String>,
+    /// Locations where the eBPF object is looked up, in order. The object
+    /// embedded in lockc is used if none of them exists.
+    pub bpf_paths: Vec<PathBuf>,
+    /// Allows loading an eBPF object read from disk when no public key is
+    /// configured to verify its signature. Otherwise the embedded object is
+    /// loaded instead.
+    pub allow_unsigned_bpf: bool,
+    /// Unprivileged user to switch to after loading eBPF programs and
+    /// setting up fanotify. If not set, lockc keeps running as root.
+    pub user: Option<String>,
+    /// Hex-encoded SHA-256 digests of runc binaries allowed to be executed.
+    /// When empty, any runc binary is allowed, unless
+    /// `record_runc_digests` is set.
+    pub runc_digests: Vec<String>,
+    /// Records the digest of each runc binary on its first execution and
+    /// denies executing a different binary from that path, when
+    /// `runc_digests` is empty. Upgrading runc then requires restarting
+    /// lockc.
+    pub record_runc_digests: bool,
+    /// How runc binaries are watched.
+    pub runc_watch: RuncWatch,
+    /// Flags of the fanotify group watching runc executions.
+    pub fanotify: FanotifyFlags,
+    /// Environment which determines the built-in allowed paths. Detected
+    /// automatically when not set.
+    pub profile: Option<Profile>,
+    /// Paths allowed or denied on top of the ones from the profile.
+    pub allowed_paths: AllowedPaths,
+    /// Whether baseline and restricted containers are denied to connect to
+    /// sockets of container runtimes.
+    pub deny_runtime_sockets: bool,
+    /// Whether baseline and restricted containers are denied to receive file
+    /// descriptors of host sockets and files. They are only reported when
+    /// disabled.
+    pub deny_received_fds: bool,
+    /// Whether files with more than one hard link inherit allow permissions
+    /// of allowed paths they are under. By default they inherit only denials,
+    /// because they can be aliases of denied files.
+    pub hardlinks_inherit_permission: bool,
+    /// Whether bind mounts denied by allowed paths are recorded and allowed
+    /// instead, to learn which paths workloads need.
+    pub learning_mode: bool,
+    /// Collection of fingerprints of container images.
+    pub fingerprints: Fingerprints,
+    /// Restrictions of the privileged policy.
+    pub privileged_containers: PrivilegedContainers,
+    /// Exemptions from denials of reading the kernel log.
+    pub syslog: Syslog,
+    /// Runtimes whose Kubernetes namespaces of containers are trusted.
+    pub kubernetes_runtimes: KubernetesRuntimes,
+    /// Protection of masked paths of procfs.
+    pub proc_masking: ProcMasking,
+    /// Host processes which are never attributed to containers.
+    pub excluded_processes: ExcludedProcesses,
+    /// Whether bpffs gets mounted on the parent of the pin directory
+    /// (`/sys/fs/bpf` by default) when it's absent.
+    pub mount_bpffs: bool,
+    /// Directory in bpffs where eBPF maps are pinned.
+    pub bpf_pin_path: PathBuf,
+    /// Name of the instance, which namespaces the pin directory, the lock
+    /// file and the sockets, so multiple instances can run side by side.
+    pub instance: Option<String>,
+    /// Channel of eBPF commands requested by the runc watcher.
+    pub ebpf_channel: EbpfChannel,
+    /// Supervision of eBPF maps.
+    pub ebpf_supervision: EbpfSupervision,
+    /// Threads of the Tokio runtime.
+    pub async_runtime: AsyncRuntime,
+    /// Handling of rootless containers.
+    pub user_namespaces: UserNamespaces,
+    /// Validation of OCI runtime specs of containers at create time.
+    pub spec_validation: SpecValidation,
+    /// Restrictions of connections from containers.
+    pub egress: Egress,
+    /// Audit trail of changes of container policies.
+    pub policy_audit: PolicyAudit,
+    /// Freezing of containers under attack.
+    pub incident_response: IncidentResponse,
+    /// Reports to a central collector.
+    pub reporter: Reporter,
+    /// Registrations of containers taking longer than this number of
+    /// milliseconds, counted from the execution of runc, are logged.
+    pub slow_registration_threshold_ms: u64,
+    /// Policy of Kubernetes containers not matching any image rule, when
+    /// lockc is built without the `kubernetes` feature and can't read labels
+    /// of namespaces.
+    pub kubernetes_default_policy: ContainerPolicyLevel,
+    /// Policy of Docker containers without the `org.lockc.policy` or
+    /// `lockc.policy` label, on the container or its image, which don't
+    /// match any image rule.
+    pub docker_default_policy: ContainerPolicyLevel,
+    /// Handling of containers whose engine can't be detected.
+    pub unknown_container_policy: UnknownContainerPolicy,
+    /// Whether the policy of Kubernetes pods is taken from the
+    /// `org.lockc.resolved-policy` annotation, set by an admission webhook,
+    /// instead of labels of the namespace. The webhook has to overwrite the
+    /// annotation on every pod, otherwise pods can choose their own policy.
+    pub resolved_policy_annotation: bool,
+    /// File mapping Kubernetes namespaces to policies, consulted before
+    /// labels of namespaces. It's reloaded when it changes.
+    pub namespace_policies: PathBuf,
+    /// Whether custom policies are reconciled from `LockcPolicy` resources,
+    /// so containers can select them with the `org.lockc.custom-policy`
+    /// annotation or label. Requires the `kubernetes` feature.
+    pub custom_policies: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            image_policies: Vec::new(),
+            bpf_public_key: None,
+            bpf_paths: BPF_OBJECT_PATHS.iter().map(PathBuf::from).collect(),
+            allow_unsigned_bpf: false,
+            user: None,
+            runc_digests: Vec::new(),
+            record_runc_digests: false,
+            runc_watch: RuncWatch::default(),
+            fanotify: FanotifyFlags::default(),
+            profile: None,
+            allowed_paths: AllowedPaths::default(),
+            deny_runtime_sockets: true,
+            deny_received_fds: false,
+            hardlinks_inherit_permission: false,
+            learning_mode: false,
+            fingerprints: Fingerprints::default(),
+            privileged_containers: PrivilegedContainers::default(),
+            syslog: Syslog::default(),
+            kubernetes_runtimes: KubernetesRuntimes::default(),
+            proc_masking: ProcMasking::default(),
+            excluded_processes: ExcludedProcesses::default(),
+            mount_bpffs: true,
+            bpf_pin_path: PathBuf::from(BPF_PIN_PATH),
+            instance: None,
+            ebpf_channel: EbpfChannel::default(),
+            ebpf_supervision: EbpfSupervision::default(),
+            async_runtime: AsyncRuntime::default(),
+            user_namespaces: UserNamespaces::default(),
+            spec_validation: SpecValidation::default(),
+            egress: Egress::default(),
+            policy_audit: PolicyAudit::default(),
+            incident_response: IncidentResponse::default(),
+            reporter: Reporter::default(),
+            slow_registration_threshold_ms: 100,
+            kubernetes_default_policy: ContainerPolicyLevel::Baseline,
+            docker_default_policy: ContainerPolicyLevel::Baseline,
+            unknown_container_policy: UnknownContainerPolicy::Baseline,
+            resolved_policy_annotation: false,
+            namespace_policies: PathBuf::from(NAMESPACE_POLICIES_PATH),
+            custom_policies: false,
+        }
+    }
+}
+
+/// Error of a parser of settings files. Messages point at the line and
+/// column of the invalid value or unknown key.
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum SettingsError {
+    #[error("could not read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("unsupported format of {0}, expected a .toml, .yaml, .yml or .json file")]
+    Format(PathBuf),
+
+    #[error("invalid file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: ParseError,
+    },
+
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+
+    #[error("policy level {0} cannot be assigned to containers")]
+    InvalidPolicyLevel(ContainerPolicyLevel),
+
+    #[error("invalid eBPF public key: {0}")]
+    PublicKey(#[from] hex::FromHexError),
+
+    #[error("capacity of the eBPF command channel has to be greater than 0")]
+    ChannelCapacity,
+
+    #[error("the eBPF pin path {} has to be absolute", .0.display())]
+    RelativePinPath(PathBuf),
+
+    #[error("invalid instance name {0:?}, only ASCII letters, digits, `-` and `_` are allowed")]
+    InstanceName(String),
+
+    #[error("interval of reports has to be greater than 0")]
+    ReportInterval,
+
+    #[error("the client certificate and key of the reporter have to be set together")]
+    ClientCertificate,
+}
+
+/// Checks that the instance name can be used in paths.
+pub fn validate_instance(instance: &str) -> Result<(), SettingsError> {
+    if instance.is_empty()
+        || !instance
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(SettingsError::InstanceName(instance.to_string()));
+    }
+    Ok(())
+}
+
+/// Returns the directory namespaced by the instance, e.g.
+/// `/sys/fs/bpf/lockc-staging` for `/sys/fs/bpf/lockc`. Without an instance,
+/// the directory is returned as it is.
+pub fn instance_dir(dir: &Path, instance: Option<&str>) -> PathBuf {
+    match instance {
+        Some(instance) => {
+            let mut name = dir.as_os_str().to_os_string();
+            name.push("-");
+            name.push(instance);
+            PathBuf::from(name)
+        }
+        None => dir.to_path_buf(),
+    }
+}
+
+/// Returns the file in the directory namespaced by the instance, e.g.
+/// `/run/lockc-staging/lockc.sock` for `/run/lockc/lockc.sock`.
+pub fn instance_file(file: &Path, instance: Option<&str>) -> PathBuf {
+    match (file.parent(), file.file_name()) {
+        (Some(dir), Some(name)) => instance_dir(dir, instance).join(name),
+        _ => file.to_path_buf(),
+    }
+}
+
+/// Format of a settings file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SettingsFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl SettingsFormat {
+    /// Determines the format from the extension of the file.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(SettingsFormat::Toml),
+            "yaml" | "yml" => Some(SettingsFormat::Yaml),
+            "json" => Some(SettingsFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Parses a document in the format. Empty documents result in default
+    /// values.
+    pub fn parse<T: DeserializeOwned + Default>(&self, contents: &str) -> Result<T, ParseError> {
+        if contents.trim().is_empty() {
+            return Ok(T::default());
+        }
+        Ok(match self {
+            SettingsFormat::Toml => toml::from_str(contents)?,
+            SettingsFormat::Yaml => serde_yaml::from_str(contents)?,
+            SettingsFormat::Json => serde_json::from_str(contents)?,
+        })
+    }
+}
+
+/// Reads and parses a file in one of the supported formats, determined by
+/// its extension.
+pub fn read_file<T: DeserializeOwned + Default, P: AsRef<Path>>(
+    path: P,
+) -> Result<T, SettingsError> {
+    let path = path.as_ref();
+    let format =
+        SettingsFormat::from_path(path).ok_or_else(|| SettingsError::Format(path.into()))?;
+    let contents = fs::read_to_string(path).map_err(|source| SettingsError::Read {
+        path: path.into(),
+        source,
+    })?;
+    format
+        .parse(&contents)
+        .map_err(|source| SettingsError::Parse {
+            path: path.into(),
+            source,
+        })
+}
+
+impl Settings {
+    /// Loads settings from the given file. A missing file results in default
+    /// settings.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, SettingsError> {
+        match Settings::from_file(path) {
+            Err(SettingsError::Read { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                Ok(Settings::default())
+            }
+            res => res,
+        }
+    }
+
+    /// Loads and validates settings from the given file, which has to exist.
+    /// The format is determined by the extension of the file. Unknown keys
+    /// are rejected, so typos don't silently result in default settings.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, SettingsError> {
+        let settings: Settings = read_file(path)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Checks constraints which the types of settings don't express.
+    fn validate(&self) -> Result<(), SettingsError> {
+        if self.ebpf_channel.capacity == 0 {
+            return Err(SettingsError::ChannelCapacity);
+        }
+        for default_policy in [self.kubernetes_default_policy, self.docker_default_policy] {
+            if let ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc = default_policy {
+                return Err(SettingsError::InvalidPolicyLevel(default_policy));
+            }
+        }
+        if let Some(policy_level) = self.egress.policy_levels.iter().find(|policy_level| {
+            matches!(
+                policy_level,
+                ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc
+            )
+        }) {
+            return Err(SettingsError::InvalidPolicyLevel(*policy_level));
+        }
+        if !self.bpf_pin_path.is_absolute() {
+            return Err(SettingsError::RelativePinPath(self.bpf_pin_path.clone()));
+        }
+        if let Some(instance) = &self.instance {
+            validate_instance(instance)?;
+        }
+        if self.reporter.interval_s == 0 {
+            return Err(SettingsError::ReportInterval);
+        }
+        if self.reporter.client_cert.is_some() != self.reporter.client_key.is_some() {
+            return Err(SettingsError::ClientCertificate);
+        }
+        Ok(())
+    }
+
+    /// Returns the directory where eBPF maps are pinned, namespaced by the
+    /// instance.
+    pub fn bpf_pin_path(&self) -> PathBuf {
+        instance_dir(&self.bpf_pin_path, self.instance.as_deref())
+    }
+
+    /// Returns the decoded eBPF public key, if configured.
+    pub fn bpf_public_key(&self) -> Result<Option<Vec<u8>>, SettingsError> {
+        Ok(self
+            .bpf_public_key
+            .as_deref()
+            .map(hex::decode)
+            .transpose()?)
+    }
+}
+
+/// Compiled image policy rules, evaluated in the order of definition.
+#[derive(Debug, Default)]
+pub struct ImagePolicies {
+    rules: Vec<(Regex, ContainerPolicyLevel)>,
+}
+
+impl ImagePolicies {
+    pub fn new(rules: &[ImagePolicyRule]) -> Result<Self, SettingsError> {
+        let rules = rules
+            .iter()
+            .map(|rule| match rule.policy {
+                ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc => {
+                    Err(SettingsError::InvalidPolicyLevel(rule.policy))
+                }
+                _ => Ok((Regex::new(&rule.image)?, rule.policy)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ImagePolicies { rules })
+    }
+
+    /// Returns the policy level of the first rule matching the given image
+    /// reference.
+    pub fn policy(&self, image: &str) -> Option<ContainerPolicyLevel> {
+        self.rules
+            .iter()
+            .find(|(rx, _)| rx.is_match(image))
+            .map(|(_, policy)| *policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lockc_common::DenyResponse;
+
+    use super::*;
+
+    /// Loads settings from a TOML file with the given contents.
+    fn settings_from_str(contents: &str) -> Result<Settings, SettingsError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockc.toml");
+        std::fs::write(&path, contents).unwrap();
+        Settings::new(path)
+    }
+
+    #[test]
+    fn image_policies_first_match_wins() {
+        let rules = vec![
+            ImagePolicyRule {
+                image: r"^registry\.internal/".to_string(),
+                policy: ContainerPolicyLevel::Baseline,
+            },
+            ImagePolicyRule {
+                image: ".*".to_string(),
+                policy: ContainerPolicyLevel::Restricted,
+            },
+        ];
+        let image_policies = ImagePolicies::new(&rules).unwrap();
+        assert_eq!(
+            image_policies.policy("registry.internal/team/app:1.0"),
+            Some(ContainerPolicyLevel::Baseline)
+        );
+        assert_eq!(
+            image_policies.policy("docker.io/library/nginx:latest"),
+            Some(ContainerPolicyLevel::Restricted)
+        );
+    }
+
+    #[test]
+    fn image_policies_no_match() {
+        let image_policies = ImagePolicies::new(&[]).unwrap();
+        assert_eq!(image_policies.policy("docker.io/library/nginx"), None);
+    }
+
+    #[test]
+    fn image_policies_reject_internal_levels() {
+        let rules = vec![ImagePolicyRule {
+            image: ".*".to_string(),
+            policy: ContainerPolicyLevel::Lockc,
+        }];
+        assert!(matches!(
+            ImagePolicies::new(&rules),
+            Err(SettingsError::InvalidPolicyLevel(
+                ContainerPolicyLevel::Lockc
+            ))
+        ));
+    }
+
+    #[test]
+    fn settings_from_file() {
+        let settings = settings_from_str(
+            r#"
+runc_digests = ["abc", "def"]
+
+[[image_policies]]
+image = '^registry\.internal/'
+policy = "baseline"
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.runc_digests, vec!["abc", "def"]);
+        assert_eq!(settings.image_policies.len(), 1);
+        assert_eq!(
+            settings.image_policies[0].policy,
+            ContainerPolicyLevel::Baseline
+        );
+    }
+
+    #[test]
+    fn settings_runc_watch() {
+        let settings = settings_from_str(
+            r#"
+[runc_watch]
+mode = "filesystem"
+filesystems = ["/", "/var/lib/rancher"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.runc_watch.mode, RuncWatchMode::Filesystem);
+        assert_eq!(settings.runc_watch.filesystems.len(), 2);
+        assert_eq!(settings.runc_watch.names, vec!["runc"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert_eq!(settings.runc_watch.mode, RuncWatchMode::Paths);
+        assert!(settings
+            .runc_watch
+            .directories
+            .contains(&PathBuf::from("/usr/local/sbin")));
+    }
+
+    #[test]
+    fn settings_fanotify() {
+        let settings = settings_from_str(
+            r#"
+[fanotify]
+unlimited_marks = false
+audit = true
+"#,
+        )
+        .unwrap();
+        assert!(settings.fanotify.unlimited_queue);
+        assert!(!settings.fanotify.unlimited_marks);
+        assert!(settings.fanotify.audit);
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert!(settings.fanotify.unlimited_queue);
+        assert!(settings.fanotify.unlimited_marks);
+        assert!(!settings.fanotify.audit);
+    }
+
+    #[test]
+    fn settings_privileged_containers() {
+        let settings = settings_from_str(
+            r#"
+[privileged_containers]
+mode = "strict"
+namespaces = ["kube-system", "monitoring"]
+"#,
+        )
+        .unwrap();
+        let privileged = &settings.privileged_containers;
+        assert_eq!(privileged.mode, PrivilegedMode::Strict);
+        assert!(privileged.allowed(Some("monitoring")));
+        assert!(!privileged.allowed(Some("default")));
+        assert!(!privileged.allowed(None));
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert!(settings.privileged_containers.allowed(Some("default")));
+    }
+
+    #[test]
+    fn settings_syslog() {
+        let settings = settings_from_str(
+            r#"
+[syslog]
+exempt_namespaces = ["node-problem-detector", "logging"]
+"#,
+        )
+        .unwrap();
+        assert!(settings.syslog.exempted(Some("logging")));
+        assert!(!settings.syslog.exempted(Some("default")));
+        assert!(!settings.syslog.exempted(None));
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert!(settings.syslog.exempt_namespaces.is_empty());
+    }
+
+    #[test]
+    fn settings_proc_masking() {
+        let settings = settings_from_str(
+            r#"
+[proc_masking]
+policy_levels = ["restricted", "baseline"]
+paths = ["/proc/kcore"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            settings.proc_masking.policy_levels,
+            vec![
+                ContainerPolicyLevel::Restricted,
+                ContainerPolicyLevel::Baseline
+            ]
+        );
+        assert_eq!(settings.proc_masking.paths, vec!["/proc/kcore".to_string()]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert!(settings
+            .proc_masking
+            .paths
+            .contains(&"/proc/sysrq-trigger".to_string()));
+    }
+
+    #[test]
+    fn settings_unknown_container_policy() {
+        let settings = settings_from_str("unknown_container_policy = \"deny\"\n").unwrap();
+        assert_eq!(
+            settings.unknown_container_policy,
+            UnknownContainerPolicy::Deny
+        );
+
+        assert!(settings_from_str("unknown_container_policy = \"privileged\"\n").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert_eq!(
+            settings.unknown_container_policy,
+            UnknownContainerPolicy::Baseline
+        );
+    }
+
+    #[test]
+    fn settings_policy_audit() {
+        let settings =
+            settings_from_str("[policy_audit]\nlog = \"/var/log/lockc/policy.jsonl\"\n").unwrap();
+        assert_eq!(
+            settings.policy_audit.log,
+            Some(PathBuf::from("/var/log/lockc/policy.jsonl"))
+        );
+        assert_eq!(settings.policy_audit.retained, 1000);
+        assert_eq!(settings.policy_audit.checkpoint_interval_s, 3600);
+        assert_eq!(settings.policy_audit.checkpoint_key, None);
+
+        assert!(settings_from_str("[policy_audit]\nsink = \"syslog\"\n").is_err());
+    }
+
+    #[test]
+    fn settings_fingerprints() {
+        let settings = settings_from_str("[fingerprints]\nenabled = true\n").unwrap();
+        assert!(settings.fingerprints.enabled);
+        assert_eq!(
+            settings.fingerprints.store,
+            Some(PathBuf::from(FINGERPRINTS_STORE_PATH))
+        );
+        assert_eq!(settings.fingerprints.save_interval_s, 60);
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert!(!settings.fingerprints.enabled);
+    }
+
+    #[test]
+    fn settings_reporter() {
+        let settings = settings_from_str(
+            "[reporter]\nurl = \"https://collector:9849/report\"\nclient_cert = \"/etc/lockc/client.pem\"\nclient_key = \"/etc/lockc/client.key\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings.reporter.url.as_deref(),
+            Some("https://collector:9849/report")
+        );
+        assert_eq!(settings.reporter.interval_s, 60);
+
+        assert!(matches!(
+            settings_from_str(
+                "[reporter]\nurl = \"https://collector:9849/report\"\nclient_cert = \"/etc/lockc/client.pem\"\n",
+            ),
+            Err(SettingsError::ClientCertificate)
+        ));
+    }
+
+    #[test]
+    fn settings_incident_response() {
+        let settings = settings_from_str(
+            "[incident_response]\npolicy_levels = [\"restricted\"]\nimmediate_hooks = [\"inode_permission\", \"task_fix_setuid\"]\n",
+        )
+        .unwrap();
+        assert!(settings.incident_response.enabled());
+        assert_eq!(
+            settings.incident_response.immediate_hooks,
+            vec![Hook::InodePermission, Hook::TaskFixSetuid]
+        );
+        assert_eq!(settings.incident_response.denial_threshold, 20);
+
+        assert!(
+            settings_from_str("[incident_response]\nimmediate_hooks = [\"escape\"]\n").is_err()
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert!(!settings.incident_response.enabled());
+    }
+
+    #[test]
+    fn settings_ebpf_channel() {
+        let settings = settings_from_str(
+            r#"
+[ebpf_channel]
+overflow = "fail_close"
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.ebpf_channel.capacity, 100);
+        assert_eq!(settings.ebpf_channel.overflow, ChannelOverflow::FailClose);
+        assert_eq!(settings.ebpf_channel.response_timeout_ms, 5000);
+        assert_eq!(settings.ebpf_channel.on_timeout, ResponseTimeout::FailClose);
+
+        assert!(matches!(
+            settings_from_str(
+                r#"
+[ebpf_channel]
+capacity = 0
+"#,
+            ),
+            Err(SettingsError::ChannelCapacity)
+        ));
+    }
+
+    #[test]
+    fn settings_ebpf_supervision() {
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("[ebpf_supervision]\non_failure = \"fail_close\"")
+            .unwrap();
+        assert_eq!(settings.ebpf_supervision.check_interval_s, 30);
+        assert_eq!(settings.ebpf_supervision.recovery_attempts, 5);
+        assert_eq!(
+            settings.ebpf_supervision.on_failure,
+            SupervisionFailure::FailClose
+        );
+    }
+
+    #[test]
+    fn settings_async_runtime() {
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("[async_runtime]\nworker_threads = 4")
+            .unwrap();
+        assert_eq!(settings.async_runtime.worker_threads, 4);
+        assert_eq!(Settings::default().async_runtime.worker_threads, 0);
+    }
+
+    #[test]
+    fn settings_egress() {
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>(
+                r#"
+[egress]
+policy_levels = ["restricted", "baseline"]
+deny_networks = ["10.0.0.0/8", "fd00::1"]
+"#,
+            )
+            .unwrap();
+        assert_eq!(
+            settings.egress.policy_levels,
+            [
+                ContainerPolicyLevel::Restricted,
+                ContainerPolicyLevel::Baseline
+            ]
+        );
+        let networks: Vec<String> = settings
+            .egress
+            .networks()
+            .iter()
+            .map(Cidr::to_string)
+            .collect();
+        assert_eq!(
+            networks,
+            [
+                "169.254.169.254/32",
+                "fd00:ec2::254/128",
+                "10.0.0.0/8",
+                "fd00::1/128"
+            ]
+        );
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!(SettingsFormat::Toml
+            .parse::<Settings>("[egress]\ndeny_networks = [\"metadata\"]")
+            .is_err());
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("[egress]\npolicy_levels = [\"lockc\"]")
+            .unwrap();
+        assert!(matches!(
+            settings.validate(),
+            Err(SettingsError::InvalidPolicyLevel(
+                ContainerPolicyLevel::Lockc
+            ))
+        ));
+    }
+
+    #[test]
+    fn settings_docker_default_policy() {
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("docker_default_policy = \"restricted\"")
+            .unwrap();
+        assert_eq!(
+            settings.docker_default_policy,
+            ContainerPolicyLevel::Restricted
+        );
+        settings.validate().unwrap();
+
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("docker_default_policy = \"lockc\"")
+            .unwrap();
+        assert!(matches!(
+            settings.validate(),
+            Err(SettingsError::InvalidPolicyLevel(
+                ContainerPolicyLevel::Lockc
+            ))
+        ));
+    }
+
+    #[test]
+    fn settings_spec_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("missing.toml")).unwrap();
+        assert_eq!(settings.spec_validation.mode, SpecValidationMode::Warn);
+
+        let settings = settings_from_str(
+            r#"
+[spec_validation]
+mode = "strict"
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.spec_validation.mode, SpecValidationMode::Strict);
+    }
+
+    #[test]
+    fn settings_user_namespaces() {
+        let settings = settings_from_str(
+            r#"
+[user_namespaces]
+mount = "allow"
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.user_namespaces.mount, UsernsOverride::Allow);
+        assert_eq!(settings.user_namespaces.setuid, UsernsOverride::Policy);
+    }
+
+    #[test]
+    fn settings_allowed_paths() {
+        let settings = settings_from_str(
+            r#"
+profile = "openshift"
+
+[allowed_paths]
+mount_baseline = ["/srv/data"]
+
+[allowed_paths.responses]
+"/usr/bin/runc" = "kill"
+"/etc/shadow" = "audit"
+"#,
+        )
+        .unwrap();
+        assert_eq!(settings.profile, Some(Profile::OpenShift));
+        assert_eq!(settings.allowed_paths.mount_baseline, vec!["/srv/data"]);
+        assert!(settings.allowed_paths.mount_restricted.is_empty());
+        assert_eq!(
+            settings.allowed_paths.responses.get("/usr/bin/runc"),
+            Some(&DenyResponse::Kill)
+        );
+        assert_eq!(
+            settings.allowed_paths.responses.get("/etc/shadow"),
+            Some(&DenyResponse::Audit)
+        );
+    }
+
+    #[test]
+    fn settings_formats() {
+        let yaml = r#"
+runc_digests: [abc, def]
+runc_watch:
+  mode: filesystem
+image_policies:
+  - image: '^registry\.internal/'
+    policy: baseline
+"#;
+        let settings = SettingsFormat::Yaml.parse::<Settings>(yaml).unwrap();
+        assert_eq!(settings.runc_digests, vec!["abc", "def"]);
+        assert_eq!(settings.runc_watch.mode, RuncWatchMode::Filesystem);
+        assert_eq!(settings.image_policies.len(), 1);
+
+        let json = r#"{"runc_digests": ["abc"], "user_namespaces": {"mount": "allow"}}"#;
+        let settings = SettingsFormat::Json.parse::<Settings>(json).unwrap();
+        assert_eq!(settings.runc_digests, vec!["abc"]);
+        assert_eq!(settings.user_namespaces.mount, UsernsOverride::Allow);
+
+        let settings = SettingsFormat::Yaml.parse::<Settings>("").unwrap();
+        assert!(settings.runc_digests.is_empty());
+    }
+
+    #[test]
+    fn settings_format_from_path() {
+        assert_eq!(
+            SettingsFormat::from_path(Path::new("/etc/lockc/lockc.yml")),
+            Some(SettingsFormat::Yaml)
+        );
+        assert_eq!(SettingsFormat::from_path(Path::new("lockc.ini")), None);
+        assert_eq!(SettingsFormat::from_path(Path::new("lockc")), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lockc.ini");
+        std::fs::write(&path, "").unwrap();
+        assert!(matches!(
+            Settings::new(&path),
+            Err(SettingsError::Format(_))
+        ));
+    }
+
+    #[test]
+    fn settings_invalid() {
+        let err = settings_from_str(
+            r#"
+mount_bpffs = true
+
+[runc_watch]
+mdoe = "filesystem"
+"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, SettingsError::Parse { .. }));
+        let msg = err.to_string();
+        assert!(msg.contains("unknown field `mdoe`"), "{}", msg);
+        assert!(msg.contains("line 4"), "{}", msg);
+
+        let err = SettingsFormat::Yaml
+            .parse::<Settings>("runc_digests: abc\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("line 1"), "{}", err);
+
+        let err = SettingsFormat::Json
+            .parse::<Settings>("{\n  \"mount_bpffs\": \"yes\"\n}")
+            .unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn settings_instance() {
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("bpf_pin_path = \"/sys/fs/bpf/test\"\ninstance = \"staging\"")
+            .unwrap();
+        settings.validate().unwrap();
+        assert_eq!(
+            settings.bpf_pin_path(),
+            PathBuf::from("/sys/fs/bpf/test-staging")
+        );
+        assert_eq!(
+            Settings::default().bpf_pin_path(),
+            PathBuf::from("/sys/fs/bpf/lockc")
+        );
+
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("instance = \"../prod\"")
+            .unwrap();
+        assert!(matches!(
+            settings.validate(),
+            Err(SettingsError::InstanceName(_))
+        ));
+        let settings = SettingsFormat::Toml
+            .parse::<Settings>("bpf_pin_path = \"lockc\"")
+            .unwrap();
+        assert!(matches!(
+            settings.validate(),
+            Err(SettingsError::RelativePinPath(_))
+        ));
+    }
+
+    #[test]
+    fn settings_instance_file() {
+        assert_eq!(
+            instance_file(Path::new("/run/lockc/lockc.sock"), Some("staging")),
+            PathBuf::from("/run/lockc-staging/lockc.sock")
+        );
+        assert_eq!(
+            instance_file(Path::new("/run/lockc/lockc.sock"), None),
+            PathBuf::from("/run/lockc/lockc.sock")
+        );
+    }
+
+    #[test]
+    fn settings_contrib_config() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("contrib")
+            .join("etc")
+            .join("lockc")
+            .join("lockc.toml");
+        Settings::new(path).expect("Parsing the default config failed");
+    }
+
+    #[test]
+    fn settings_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = Settings::new(dir.path().join("lockc.toml")).unwrap();
+        assert!(settings.image_policies.is_empty());
+        assert_eq!(settings.bpf_public_key().unwrap(), None);
+        assert_eq!(
+            settings.bpf_paths,
+            vec![PathBuf::from("/usr/lib/lockc/lockc.bpf.o")]
+        );
+    }
+
+    #[test]
+    fn settings_bpf_public_key() {
+        let mut settings = Settings {
+            bpf_public_key: Some("00ff".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(settings.bpf_public_key().unwrap(), Some(vec![0x00, 0xff]));
+        settings.bpf_public_key = Some("zz".to_string());
+        assert!(matches!(
+            settings.bpf_public_key(),
+            Err(SettingsError::PublicKey(_))
+        ));
+    }
+}
@@ -0,0 +1,468 @@
+//! Policy simulator. Evaluates operations of workloads described in a file
+//! against a settings file, with the same decisions which eBPF programs make,
+//! but without loading them. Platform teams can run it in CI to test changes
+//! of policies before rolling them out to nodes.
+
+use std::{fmt, path::Path};
+
+use lockc_common::{
+    verdict::{self, Verdict},
+    ContainerPolicyLevel,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    profiles::{AllowedPaths, Profile},
+    runc::{policy_image, policy_kubernetes_default, policy_privileged},
+    settings::{read_file, ImagePolicies, Settings, SettingsError},
+};
+
+#[derive(Error, Debug)]
+pub enum SimulateError {
+    #[error("invalid policy: {0}")]
+    Policy(#[source] SettingsError),
+
+    #[error("invalid workload: {0}")]
+    Workload(#[source] SettingsError),
+
+    #[error("no workload file given, the first file is the policy")]
+    NoWorkload,
+
+    #[error("policy level {policy_level} cannot be assigned to container {container}")]
+    InvalidPolicyLevel {
+        container: String,
+        policy_level: ContainerPolicyLevel,
+    },
+
+    #[error("{0} verdicts differ from the expected ones")]
+    Mismatch(usize),
+}
+
+/// Operations which lockc restricts regardless of paths.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// Reading the kernel logs.
+    Syslog,
+    /// Changing the UID to root after the container was started.
+    Setuid,
+    /// Sending and receiving messages through sockets.
+    Network,
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Syslog => write!(f, "syslog"),
+            Capability::Setuid => write!(f, "setuid"),
+            Capability::Network => write!(f, "network"),
+        }
+    }
+}
+
+fn default_mount_type() -> String {
+    "bind".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mount {
+    pub source: String,
+    #[serde(rename = "type", default = "default_mount_type")]
+    pub mount_type: String,
+    #[serde(default)]
+    pub expect: Option<Verdict>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileAccess {
+    pub path: String,
+    #[serde(default)]
+    pub expect: Option<Verdict>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CapabilityUse {
+    pub name: Capability,
+    #[serde(default)]
+    pub expect: Option<Verdict>,
+}
+
+/// Container of a simulated workload. `expect` fields are optional, a
+/// simulation fails when any verdict differs from the expected one.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkloadContainer {
+    pub name: String,
+    /// Image reference, matched against image policy rules.
+    #[serde(default)]
+    pub image: Option<String>,
+    /// Explicit policy level, as set by a label or an annotation.
+    #[serde(default)]
+    pub policy: Option<ContainerPolicyLevel>,
+    /// Kubernetes namespace of the pod. Labels of namespaces are not
+    /// simulated, `policy` has to be set instead.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Whether the container runs in a user namespace without access to root
+    /// of the host.
+    #[serde(default)]
+    pub rootless: bool,
+    /// Expected verdict on creating the container, which is denied only for
+    /// refused privileged containers.
+    #[serde(default)]
+    pub expect: Option<Verdict>,
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    #[serde(default)]
+    pub files: Vec<FileAccess>,
+    #[serde(default)]
+    pub capabilities: Vec<CapabilityUse>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Workload {
+    pub containers: Vec<WorkloadContainer>,
+}
+
+/// Simulated operation.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Mount { source: String, mount_type: String },
+    Open(String),
+    Capability(Capability),
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Create => write!(f, "create"),
+            Operation::Mount { source, mount_type } => {
+                write!(f, "mount {} ({})", source, mount_type)
+            }
+            Operation::Open(path) => write!(f, "open {}", path),
+            Operation::Capability(capability) => write!(f, "{}", capability),
+        }
+    }
+}
+
+/// Verdict on an operation of a container.
+#[derive(Debug)]
+pub struct Decision {
+    pub container: String,
+    /// Policy level of the container, `None` if it's not created.
+    pub policy_level: Option<ContainerPolicyLevel>,
+    pub operation: Operation,
+    pub verdict: Verdict,
+    pub expected: Option<Verdict>,
+}
+
+impl Decision {
+    /// Returns whether the verdict is the expected one, if any.
+    pub fn matches(&self) -> bool {
+        self.expected
+            .is_none_or(|expected| expected == self.verdict)
+    }
+}
+
+/// Policy which workloads are evaluated against.
+pub struct Simulator {
+    settings: Settings,
+    image_policies: ImagePolicies,
+    allowed_paths: AllowedPaths,
+}
+
+impl Simulator {
+    /// Builds allowed paths the same way as lockc does on a node with the
+    /// given profile.
+    pub fn new(settings: Settings, profile: Profile) -> Result<Self, SettingsError> {
+        let image_policies = ImagePolicies::new(&settings.image_policies)?;
+        let mut allowed_paths = profile.allowed_paths();
+        allowed_paths.extend(&settings.allowed_paths);
+        if settings.deny_runtime_sockets {
+            allowed_paths.deny_runtime_sockets();
+        }
+        Ok(Simulator {
+            settings,
+            image_policies,
+            allowed_paths,
+        })
+    }
+
+    /// Returns the policy level which the container would be registered
+    /// with, `None` if its creation would be denied.
+    pub fn policy_level(
+        &self,
+        container: &WorkloadContainer,
+    ) -> Result<Option<ContainerPolicyLevel>, SimulateError> {
+        let image = container.image.as_deref();
+        let policy_level = match (container.policy, container.namespace.as_deref()) {
+            (
+                Some(policy_level @ (ContainerPolicyLevel::NotFound | ContainerPolicyLevel::Lockc)),
+                _,
+            ) => {
+                return Err(SimulateError::InvalidPolicyLevel {
+                    container: container.name.clone(),
+                    policy_level,
+                })
+            }
+            (Some(policy_level), _) => policy_level,
+            (None, Some(namespace)) => policy_kubernetes_default(
+                namespace,
+                image,
+                &self.image_policies,
+                self.settings.kubernetes_default_policy,
+            ),
+            (None, None) => policy_image(image, &self.image_policies),
+        };
+        Ok(policy_privileged(
+            policy_level,
+            container.namespace.as_deref(),
+            &self.settings.privileged_containers,
+        ))
+    }
+
+    /// Evaluates all operations of the container.
+    pub fn evaluate(&self, container: &WorkloadContainer) -> Result<Vec<Decision>, SimulateError> {
+        let policy_level = self.policy_level(container)?;
+        let decision = |operation, verdict, expected| Decision {
+            container: container.name.clone(),
+            policy_level,
+            operation,
+            verdict,
+            expected,
+        };
+
+        let policy_level = match policy_level {
+            Some(policy_level) => policy_level,
+            None => {
+                return Ok(vec![decision(
+                    Operation::Create,
+                    Verdict::Deny,
+                    container.expect,
+                )])
+            }
+        };
+        let mut decisions = vec![decision(
+            Operation::Create,
+            Verdict::Allow,
+            container.expect,
+        )];
+
+        let user_namespaces = &self.settings.user_namespaces;
+        for mount in container.mounts.iter() {
+            let verdict = verdict::user_namespace(
+                policy_level,
+                user_namespaces.mount,
+                container.rootless,
+                verdict::mount(
+                    &self.allowed_paths,
+                    policy_level,
+                    &mount.mount_type,
+                    &mount.source,
+                ),
+            );
+            decisions.push(decision(
+                Operation::Mount {
+                    source: mount.source.clone(),
+                    mount_type: mount.mount_type.clone(),
+                },
+                verdict,
+                mount.expect,
+            ));
+        }
+        for file in container.files.iter() {
+            let verdict = verdict::file_open(&self.allowed_paths, policy_level, &file.path);
+            decisions.push(decision(
+                Operation::Open(file.path.clone()),
+                verdict,
+                file.expect,
+            ));
+        }
+        for capability in container.capabilities.iter() {
+            let verdict = match capability.name {
+                Capability::Syslog => verdict::syslog(policy_level),
+                Capability::Setuid => verdict::user_namespace(
+                    policy_level,
+                    user_namespaces.setuid,
+                    container.rootless,
+                    verdict::setuid(policy_level, true, 0),
+                ),
+                Capability::Network => verdict::socket(policy_level),
+            };
+            decisions.push(decision(
+                Operation::Capability(capability.name),
+                verdict,
+                capability.expect,
+            ));
+        }
+        Ok(decisions)
+    }
+}
+
+/// Evaluates workloads from the given files against the policy from the
+/// first file. The profile defaults to the one from the policy, or to Docker.
+pub fn simulate<P: AsRef<Path>>(
+    files: &[P],
+    profile: Option<Profile>,
+) -> Result<Vec<Decision>, SimulateError> {
+    let (policy, workloads) = match files {
+        [policy, workloads @ ..] if !workloads.is_empty() => (policy, workloads),
+        _ => return Err(SimulateError::NoWorkload),
+    };
+    let settings = Settings::from_file(policy).map_err(SimulateError::Policy)?;
+    let profile = profile.or(settings.profile).unwrap_or(Profile::Docker);
+    let simulator = Simulator::new(settings, profile).map_err(SimulateError::Policy)?;
+
+    let mut decisions = Vec::new();
+    for path in workloads {
+        let workload: Workload = read_file(path).map_err(SimulateError::Workload)?;
+        for container in workload.containers.iter() {
+            decisions.extend(simulator.evaluate(container)?);
+        }
+    }
+    Ok(decisions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::SettingsFormat;
+
+    use super::*;
+
+    const POLICY: &str = r#"
+[[image_policies]]
+image = '^registry\.internal/'
+policy = "restricted"
+
+[privileged_containers]
+mode = "strict"
+namespaces = ["kube-system"]
+
+[user_namespaces]
+mount = "allow"
+"#;
+
+    const WORKLOAD: &str = r#"
+containers:
+  - name: web
+    image: registry.internal/web:1.0
+    mounts:
+      - source: /var/lib/kubelet/pods/1/volumes
+      - source: /etc
+        expect: deny
+    files:
+      - path: /proc/sys/kernel/hostname
+        expect: deny
+      - path: /run/containerd/containerd.sock
+      - path: /etc/passwd
+    capabilities:
+      - name: setuid
+      - name: network
+        expect: allow
+  - name: rootless
+    rootless: true
+    mounts:
+      - source: /etc
+  - name: debug
+    namespace: default
+    policy: privileged
+    expect: allow
+"#;
+
+    fn simulator() -> Simulator {
+        let settings = SettingsFormat::Toml.parse(POLICY).unwrap();
+        Simulator::new(settings, Profile::Kubeadm).unwrap()
+    }
+
+    fn verdicts(decisions: &[Decision]) -> Vec<(String, Verdict)> {
+        decisions
+            .iter()
+            .map(|decision| (decision.operation.to_string(), decision.verdict))
+            .collect()
+    }
+
+    #[test]
+    fn simulate_workload() {
+        let simulator = simulator();
+        let workload: Workload = SettingsFormat::Yaml.parse(WORKLOAD).unwrap();
+
+        let web = simulator.evaluate(&workload.containers[0]).unwrap();
+        assert_eq!(web[0].policy_level, Some(ContainerPolicyLevel::Restricted));
+        assert_eq!(
+            verdicts(&web),
+            vec![
+                ("create".to_string(), Verdict::Allow),
+                (
+                    "mount /var/lib/kubelet/pods/1/volumes (bind)".to_string(),
+                    Verdict::Allow
+                ),
+                ("mount /etc (bind)".to_string(), Verdict::Deny),
+                ("open /proc/sys/kernel/hostname".to_string(), Verdict::Deny),
+                (
+                    "open /run/containerd/containerd.sock".to_string(),
+                    Verdict::Deny
+                ),
+                ("open /etc/passwd".to_string(), Verdict::Allow),
+                ("setuid".to_string(), Verdict::Deny),
+                ("network".to_string(), Verdict::Allow),
+            ]
+        );
+        assert!(web.iter().all(Decision::matches));
+
+        // Bind mounts are allowed to rootless containers by the policy.
+        let rootless = simulator.evaluate(&workload.containers[1]).unwrap();
+        assert_eq!(
+            rootless[0].policy_level,
+            Some(ContainerPolicyLevel::Baseline)
+        );
+        assert_eq!(rootless[1].verdict, Verdict::Allow);
+
+        // Privileged containers outside of kube-system are refused.
+        let debug = simulator.evaluate(&workload.containers[2]).unwrap();
+        assert_eq!(debug.len(), 1);
+        assert_eq!(debug[0].policy_level, None);
+        assert_eq!(debug[0].verdict, Verdict::Deny);
+        assert!(!debug[0].matches());
+    }
+
+    #[test]
+    fn simulate_example() {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("examples")
+            .join("simulate");
+        let decisions = simulate(
+            &[examples.join("policy.toml"), examples.join("workload.yaml")],
+            None,
+        )
+        .unwrap();
+        assert!(decisions.iter().all(Decision::matches), "{:?}", decisions);
+    }
+
+    #[test]
+    fn simulate_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = dir.path().join("policy.toml");
+        let workload = dir.path().join("workload.yaml");
+        std::fs::write(&policy, POLICY).unwrap();
+        std::fs::write(&workload, WORKLOAD).unwrap();
+
+        let decisions = simulate(&[&policy, &workload], None).unwrap();
+        assert_eq!(decisions.iter().filter(|d| !d.matches()).count(), 1);
+
+        assert!(matches!(
+            simulate(&[&policy], None),
+            Err(SimulateError::NoWorkload)
+        ));
+        assert!(matches!(
+            simulate(&[&policy, &policy], None),
+            Err(SimulateError::Workload(SettingsError::Parse { .. }))
+        ));
+    }
+}
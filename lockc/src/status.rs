@@ -0,0 +1,451 @@
+//! Status page for quick triage of a node without lockctl. `GET /status`
+//! returns registered containers, loaded programs with their counters,
+//! recent violations and the state of the eBPF thread as JSON, `GET /`
+//! renders the same as HTML tables. Data comes from the control API, so the
+//! page never shows anything `lockctl` couldn't, except for counters of
+//! programs, which are read by the eBPF thread on an interval like for the
+//! metrics endpoint, so requests never queue eBPF commands.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use lockc_common::{
+    control::{ContainerInfo, ControlRequest, ControlResponse, PolicySource, ProgramStatsInfo},
+    http, Enforcement, Hook,
+};
+use serde::Serialize;
+use tokio::{
+    io::BufReader,
+    net::{TcpListener, TcpStream},
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, error, warn};
+
+use crate::{
+    control::{self, ControlState},
+    falco::rfc3339,
+    metrics::{with_timeout, EbpfCounters},
+    supervisor::EbpfHealth,
+    violations::ViolationEvent,
+};
+
+/// Number of the most recent violations shown on the status page.
+const RECENT_VIOLATIONS: usize = 50;
+
+/// Requests of the status page are read-only, they never change policies.
+const SOURCE: PolicySource = PolicySource::ControlApi {
+    uid: None,
+    pid: None,
+};
+
+/// Violation shown on the status page.
+#[derive(Clone, Debug, Serialize)]
+pub struct ViolationInfo {
+    pub time: String,
+    pub container_id: String,
+    pub pod: Option<String>,
+    pub namespace: Option<String>,
+    pub hook: Hook,
+    pub detail: Option<String>,
+    pub enforcement: Enforcement,
+    pub killed: bool,
+    pub suppressed: u64,
+}
+
+impl ViolationInfo {
+    fn new(event: &ViolationEvent, time: SystemTime) -> Self {
+        let container = event.container.as_ref();
+        ViolationInfo {
+            time: rfc3339(time),
+            container_id: event.container_id.clone(),
+            pod: container.and_then(|c| c.pod.clone()),
+            namespace: container.and_then(|c| c.namespace.clone()),
+            hook: event.hook,
+            detail: event.detail.clone(),
+            enforcement: event.enforcement,
+            killed: event.killed,
+            suppressed: event.suppressed,
+        }
+    }
+}
+
+/// The most recent violations, newest last.
+#[derive(Default)]
+pub struct RecentViolations(VecDeque<ViolationInfo>);
+
+impl RecentViolations {
+    fn push(&mut self, violation: ViolationInfo) {
+        while self.0.len() >= RECENT_VIOLATIONS {
+            self.0.pop_front();
+        }
+        self.0.push_back(violation);
+    }
+}
+
+/// Keeps the most recent violations, until all senders are dropped.
+pub async fn record_violations(
+    mut rx: broadcast::Receiver<ViolationEvent>,
+    recent: Arc<Mutex<RecentViolations>>,
+) {
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "status page lags behind violations");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        match recent.lock() {
+            Ok(mut recent) => recent.push(ViolationInfo::new(&event, SystemTime::now())),
+            Err(_) => {
+                warn!("recent violations are poisoned");
+                break;
+            }
+        }
+    }
+    debug!("recording violations for the status page finished");
+}
+
+/// Status of lockc, served as JSON.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    /// State of the eBPF thread, `healthy`, `recovering` or `failed`.
+    pub ebpf: String,
+    pub containers: Vec<ContainerInfo>,
+    pub programs: Vec<ProgramStatsInfo>,
+    /// The most recent violations, newest first.
+    pub violations: Vec<ViolationInfo>,
+    /// Parts of the status which couldn't be collected.
+    pub errors: Vec<String>,
+}
+
+impl Status {
+    async fn collect(
+        state: &ControlState,
+        health: &EbpfHealth,
+        recent: &Mutex<RecentViolations>,
+        counters: &Mutex<EbpfCounters>,
+    ) -> Self {
+        let mut errors = Vec::new();
+        let containers =
+            match control::handle_request(ControlRequest::Containers, state, &SOURCE).await {
+                ControlResponse::Containers { containers } => containers,
+                response => {
+                    errors.push(format!("containers: {:?}", response));
+                    Vec::new()
+                }
+            };
+        let programs = match counters.lock() {
+            Ok(counters) => counters
+                .program_stats
+                .iter()
+                .map(|(program, stats)| ProgramStatsInfo {
+                    program: program.name().to_string(),
+                    loaded: state.loaded_programs.contains(program),
+                    invocations: stats.invocations,
+                    allows: stats.allows,
+                    denies: stats.denies,
+                })
+                .collect(),
+            Err(_) => {
+                errors.push("programs: eBPF counters are poisoned".to_string());
+                Vec::new()
+            }
+        };
+        let violations = match recent.lock() {
+            Ok(recent) => recent.0.iter().rev().cloned().collect(),
+            Err(_) => {
+                errors.push("violations: recent violations are poisoned".to_string());
+                Vec::new()
+            }
+        };
+        Status {
+            ebpf: health.state().to_string(),
+            containers,
+            programs,
+            violations,
+            errors,
+        }
+    }
+
+    /// Renders the status as an HTML page with a table of each part.
+    pub fn render_html(&self) -> String {
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>lockc status</title></head>\n<body>\n",
+        );
+        let _ = writeln!(out, "<h1>lockc</h1>\n<p>eBPF: {}</p>", escape(&self.ebpf));
+        for error in &self.errors {
+            let _ = writeln!(out, "<p>Error: {}</p>", escape(error));
+        }
+
+        table(
+            &mut out,
+            "Containers",
+            &[
+                "ID",
+                "Name",
+                "Pod",
+                "Namespace",
+                "Image",
+                "Policy",
+                "Enforcement",
+            ],
+            self.containers.iter().map(|c| {
+                vec![
+                    c.short_id().to_string(),
+                    c.name.clone().unwrap_or_default(),
+                    c.pod.clone().unwrap_or_default(),
+                    c.namespace.clone().unwrap_or_default(),
+                    c.image.clone().unwrap_or_default(),
+                    c.policy_level.to_string(),
+                    c.enforcement.to_string(),
+                ]
+            }),
+        );
+        table(
+            &mut out,
+            "Programs",
+            &["Program", "Loaded", "Invocations", "Allows", "Denies"],
+            self.programs.iter().map(|p| {
+                vec![
+                    p.program.clone(),
+                    if p.loaded { "yes" } else { "no" }.to_string(),
+                    p.invocations.to_string(),
+                    p.allows.to_string(),
+                    p.denies.to_string(),
+                ]
+            }),
+        );
+        table(
+            &mut out,
+            "Recent violations",
+            &[
+                "Time",
+                "Container",
+                "Pod",
+                "Namespace",
+                "Hook",
+                "Detail",
+                "Enforcement",
+                "Suppressed",
+            ],
+            self.violations.iter().map(|v| {
+                vec![
+                    v.time.clone(),
+                    v.container_id.clone(),
+                    v.pod.clone().unwrap_or_default(),
+                    v.namespace.clone().unwrap_or_default(),
+                    v.hook.to_string(),
+                    v.detail.clone().unwrap_or_default(),
+                    if v.killed {
+                        format!("{} (killed)", v.enforcement)
+                    } else {
+                        v.enforcement.to_string()
+                    },
+                    v.suppressed.to_string(),
+                ]
+            }),
+        );
+
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+/// Escapes text for HTML.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn table<I>(out: &mut String, title: &str, header: &[&str], rows: I)
+where
+    I: Iterator<Item = Vec<String>>,
+{
+    let _ = writeln!(
+        out,
+        "<h2>{}</h2>\n<table border=\"1\">\n<tr>",
+        escape(title)
+    );
+    for column in header {
+        let _ = write!(out, "<th>{}</th>", escape(column));
+    }
+    out.push_str("</tr>\n");
+    for row in rows {
+        out.push_str("<tr>");
+        for cell in row {
+            let _ = write!(out, "<td>{}</td>", escape(&cell));
+        }
+        out.push_str("</tr>\n");
+    }
+    out.push_str("</table>\n");
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    state: &ControlState,
+    health: &EbpfHealth,
+    recent: &Mutex<RecentViolations>,
+    counters: &Mutex<EbpfCounters>,
+) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = http::read_request(&mut stream).await?;
+    let (status, content_type, body) = if request.is("GET", "/status") {
+        let status = Status::collect(state, health, recent, counters).await;
+        match serde_json::to_string(&status) {
+            Ok(body) => ("200 OK", "application/json", body),
+            Err(e) => ("500 Internal Server Error", "text/plain", e.to_string()),
+        }
+    } else if request.is("GET", "/") {
+        let status = Status::collect(state, health, recent, counters).await;
+        ("200 OK", "text/html; charset=utf-8", status.render_html())
+    } else {
+        ("404 Not Found", "text/plain", String::new())
+    };
+    http::write_response(&mut stream, status, content_type, &body).await
+}
+
+/// Serves the status page on the given listener.
+pub async fn serve(
+    listener: TcpListener,
+    state: Arc<ControlState>,
+    health: EbpfHealth,
+    recent: Arc<Mutex<RecentViolations>>,
+    counters: Arc<Mutex<EbpfCounters>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let state = state.clone();
+                let health = health.clone();
+                let recent = recent.clone();
+                let counters = counters.clone();
+                tokio::spawn(async move {
+                    let connection = handle_connection(stream, &state, &health, &recent, &counters);
+                    if let Err(e) = with_timeout(connection).await {
+                        warn!(error = e.to_string().as_str(), "status connection failed");
+                    }
+                });
+            }
+            Err(e) => error!(
+                error = e.to_string().as_str(),
+                "could not accept a status connection"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use lockc_common::ContainerPolicyLevel;
+    use tokio::{io::AsyncWriteExt, sync::mpsc};
+
+    use super::*;
+    use crate::{
+        communication::EbpfRequest,
+        control::test_state,
+        registry::{ContainerMetadata, ContainerRegistry},
+        violations::test_event,
+    };
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response)
+            .await
+            .unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn status_serve() {
+        let (ebpf_tx, _ebpf_rx) = mpsc::channel::<EbpfRequest>(1);
+        let counters = Arc::new(Mutex::new(EbpfCounters {
+            map_errors: Vec::new(),
+            program_stats: vec![(
+                lockc_common::Program::FileOpen,
+                lockc_common::ProgramStats::default(),
+            )],
+        }));
+        let containers = Arc::new(RwLock::new(ContainerRegistry::default()));
+        containers.write().unwrap().insert(
+            "abc".to_string(),
+            ContainerPolicyLevel::Baseline,
+            ContainerMetadata {
+                name: Some("<nginx>".to_string()),
+                ..Default::default()
+            },
+        );
+        let state = Arc::new(ControlState {
+            containers,
+            ebpf_tx: ebpf_tx.downgrade(),
+            loaded_programs: vec![lockc_common::Program::FileOpen],
+            ..test_state()
+        });
+        let recent = Arc::new(Mutex::new(RecentViolations::default()));
+        for i in 0..RECENT_VIOLATIONS + 1 {
+            let event = ViolationEvent {
+                detail: Some(format!("/etc/shadow{}", i)),
+                ..test_event(Hook::FileOpen)
+            };
+            recent
+                .lock()
+                .unwrap()
+                .push(ViolationInfo::new(&event, SystemTime::UNIX_EPOCH));
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            state,
+            EbpfHealth::default(),
+            recent,
+            counters,
+        ));
+
+        let response = get(addr, "/status").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let status: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(status["ebpf"], "healthy");
+        assert_eq!(status["containers"][0]["id"], "abc");
+        assert_eq!(status["programs"][0]["program"], "file_open");
+        assert_eq!(status["programs"][0]["loaded"], true);
+        let violations = status["violations"].as_array().unwrap();
+        assert_eq!(violations.len(), RECENT_VIOLATIONS);
+        assert_eq!(violations[0]["detail"], "/etc/shadow50");
+
+        let response = get(addr, "/").await;
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("<td>&lt;nginx&gt;</td>"));
+        assert!(response.contains("<td>file_open</td><td>yes</td>"));
+
+        assert!(get(addr, "/metrics")
+            .await
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        drop(ebpf_tx);
+    }
+}
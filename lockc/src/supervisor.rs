@@ -0,0 +1,329 @@
+//! Supervision of the eBPF thread. Fatal failures of eBPF map operations
+//! (e.g. a map which disappeared) and periodic checks of the pinned maps
+//! (e.g. bpffs unmounted) trigger a recovery with backoff, which mounts bpffs
+//! and pins the maps again. When the recovery fails, the runc watcher denies
+//! execution of runc until a periodic check succeeds, or lockc exits and gets
+//! restarted, which loads and attaches the programs again. Containers are
+//! never created without being registered.
+//!
+//! Mounting bpffs and pinning maps requires root. After switching to an
+//! unprivileged user, failures are not repaired, they are handled right away
+//! as failed recoveries.
+
+use std::{
+    io,
+    os::unix::io::OwnedFd,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use aya::maps::MapError;
+use lockc_common::PID_MAPS;
+use thiserror::Error;
+use tokio::time;
+use tracing::{error, info, warn};
+
+use crate::{
+    load::pin_maps,
+    maps::{get_map_errors, get_process_container, LockcMaps, MapOperationError},
+    settings::{EbpfSupervision, SupervisionFailure},
+    sysutils::{ensure_bpffs, is_bpffs, SetupHostError},
+};
+
+/// Longest delay between recovery attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// State of the eBPF thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EbpfState {
+    Healthy,
+    /// Recovery from a failure is in progress.
+    Recovering,
+    /// Recovery failed, eBPF maps can't be operated on.
+    Failed,
+}
+
+impl std::fmt::Display for EbpfState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EbpfState::Healthy => write!(f, "healthy"),
+            EbpfState::Recovering => write!(f, "recovering"),
+            EbpfState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// State of the eBPF thread shared with the runc watcher.
+#[derive(Clone, Default)]
+pub struct EbpfHealth(Arc<AtomicU8>);
+
+impl EbpfHealth {
+    pub fn state(&self) -> EbpfState {
+        match self.0.load(Ordering::Acquire) {
+            0 => EbpfState::Healthy,
+            1 => EbpfState::Recovering,
+            _ => EbpfState::Failed,
+        }
+    }
+
+    pub fn set(&self, state: EbpfState) {
+        self.0.store(state as u8, Ordering::Release);
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HealthError {
+    #[error(transparent)]
+    Map(#[from] MapOperationError),
+
+    #[error("eBPF command {command} failed: {error}")]
+    Command {
+        command: &'static str,
+        error: String,
+    },
+
+    #[error(transparent)]
+    Host(#[from] SetupHostError),
+
+    #[error("bpffs is not mounted on {}", .0.display())]
+    BpffsGone(PathBuf),
+
+    #[error("eBPF map {0} is not pinned")]
+    PinMissing(&'static str),
+
+    #[error("could not open pinned eBPF maps: {0}")]
+    Open(#[source] io::Error),
+
+    #[error("could not pin eBPF maps: {0}")]
+    Pin(#[source] io::Error),
+}
+
+/// Returns whether the map operation failed because the map itself can't be
+/// used, rather than because of its arguments or contents.
+pub fn is_fatal(e: &MapOperationError) -> bool {
+    match e {
+        MapOperationError::Map(MapError::MapNotFound { .. }) => true,
+        MapOperationError::Map(MapError::SyscallError { io_error, .. }) => {
+            matches!(io_error.raw_os_error(), Some(libc::EBADF))
+        }
+        _ => false,
+    }
+}
+
+/// Exponential backoff of recovery attempts.
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new(initial: Duration) -> Self {
+        Backoff { delay: initial }
+    }
+
+    /// Returns the delay before the next attempt and doubles the following
+    /// one, up to [`MAX_BACKOFF`].
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+/// Supervisor of eBPF maps operated on by the eBPF thread.
+pub struct Supervisor {
+    health: EbpfHealth,
+    path_base: PathBuf,
+    mount_bpffs: bool,
+    /// Maps which are pinned again on recovery.
+    maps: Vec<(String, OwnedFd)>,
+    settings: EbpfSupervision,
+    /// Whether lockc still has privileges to repair bpffs and pins.
+    repairable: bool,
+}
+
+impl Supervisor {
+    pub fn new(
+        health: EbpfHealth,
+        path_base: PathBuf,
+        mount_bpffs: bool,
+        maps: Vec<(String, OwnedFd)>,
+        settings: EbpfSupervision,
+    ) -> Self {
+        Supervisor {
+            health,
+            path_base,
+            mount_bpffs,
+            maps,
+            settings,
+            repairable: true,
+        }
+    }
+
+    /// Disables repairs, which can't succeed after dropping privileges.
+    pub fn without_repair(mut self) -> Self {
+        self.repairable = false;
+        self
+    }
+
+    /// Interval of periodic checks, if enabled.
+    pub fn check_interval(&self) -> Option<Duration> {
+        (self.settings.check_interval_s > 0)
+            .then(|| Duration::from_secs(self.settings.check_interval_s))
+    }
+
+    /// Checks that maps can be operated on and that they are pinned.
+    pub fn check(&self, maps: &LockcMaps) -> Result<(), HealthError> {
+        get_map_errors(maps)?;
+        get_process_container(maps, 1)?;
+        if !is_bpffs(&self.path_base)? {
+            return Err(HealthError::BpffsGone(self.path_base.clone()));
+        }
+        match PID_MAPS
+            .iter()
+            .find(|map| !self.path_base.join(map).exists())
+        {
+            Some(map) => Err(HealthError::PinMissing(map)),
+            None => Ok(()),
+        }
+    }
+
+    /// Mounts bpffs, if it's gone, and pins the maps again.
+    fn repair(&self) -> Result<(), HealthError> {
+        if let Some(bpffs_path) = self.path_base.parent() {
+            if ensure_bpffs(bpffs_path, self.mount_bpffs)? {
+                info!(
+                    path = bpffs_path.to_string_lossy().as_ref(),
+                    "mounted bpffs again"
+                );
+            }
+        }
+        pin_maps(&self.path_base, &self.maps).map_err(HealthError::Pin)
+    }
+
+    /// Marks maps as healthy after a successful check. Clears the failed
+    /// state left by a previous recovery.
+    pub fn checked(&self) {
+        if self.health.state() != EbpfState::Healthy {
+            info!("eBPF maps are healthy again");
+            self.health.set(EbpfState::Healthy);
+        }
+    }
+
+    /// Tries to recover from the failure with backoff. Returns an error when
+    /// recovery failed and lockc has to exit. Otherwise the eBPF thread goes
+    /// on, even in the failed state, so the watcher can deny runc. In the
+    /// failed state, every further failure is repaired once, without backoff.
+    pub async fn recover(&self, maps: &LockcMaps, failure: HealthError) -> Result<(), HealthError> {
+        if self.health.state() == EbpfState::Failed {
+            if self.repairable && self.repair().and_then(|_| self.check(maps)).is_ok() {
+                info!("eBPF maps recovered");
+                self.health.set(EbpfState::Healthy);
+            }
+            return Ok(());
+        }
+        error!(
+            error = failure.to_string().as_str(),
+            "eBPF maps failed, recovering"
+        );
+        self.health.set(EbpfState::Recovering);
+
+        let mut backoff = Backoff::new(Duration::from_millis(self.settings.initial_backoff_ms));
+        let mut failure = failure;
+        let attempts = if self.repairable {
+            self.settings.recovery_attempts
+        } else {
+            warn!("eBPF maps can't be repaired without root");
+            0
+        };
+        for attempt in 1..=attempts {
+            time::sleep(backoff.next()).await;
+            match self.repair().and_then(|_| self.check(maps)) {
+                Ok(()) => {
+                    info!(attempt, "eBPF maps recovered");
+                    self.health.set(EbpfState::Healthy);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        attempt,
+                        error = e.to_string().as_str(),
+                        "recovery of eBPF maps failed"
+                    );
+                    failure = e;
+                }
+            }
+        }
+
+        self.health.set(EbpfState::Failed);
+        match self.settings.on_failure {
+            SupervisionFailure::FailClose => {
+                error!("could not recover eBPF maps, denying execution of runc");
+                Ok(())
+            }
+            SupervisionFailure::Exit => Err(failure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supervisor_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(2));
+        assert_eq!(backoff.next(), Duration::from_secs(2));
+        assert_eq!(backoff.next(), Duration::from_secs(4));
+        assert_eq!(backoff.next(), Duration::from_secs(8));
+        assert_eq!(backoff.next(), MAX_BACKOFF);
+        assert_eq!(backoff.next(), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn supervisor_fatal_errors() {
+        assert!(is_fatal(&MapOperationError::Map(MapError::MapNotFound {
+            name: "CONTAINERS".to_string(),
+        })));
+        assert!(is_fatal(&MapOperationError::Map(MapError::SyscallError {
+            call: "bpf_map_lookup_elem".to_string(),
+            io_error: io::Error::from_raw_os_error(libc::EBADF),
+        })));
+        assert!(!is_fatal(&MapOperationError::Map(MapError::SyscallError {
+            call: "bpf_map_delete_elem".to_string(),
+            io_error: io::Error::from_raw_os_error(libc::ENOENT),
+        })));
+        assert!(!is_fatal(&MapOperationError::Map(MapError::KeyNotFound)));
+        assert!(!is_fatal(&MapOperationError::ContainerNotFound(
+            "abc".to_string()
+        )));
+    }
+
+    #[test]
+    fn supervisor_health() {
+        let health = EbpfHealth::default();
+        assert_eq!(health.state(), EbpfState::Healthy);
+        health.set(EbpfState::Failed);
+        assert_eq!(health.clone().state(), EbpfState::Failed);
+    }
+
+    #[test]
+    fn supervisor_checked_clears_failed() {
+        let health = EbpfHealth::default();
+        let supervisor = Supervisor::new(
+            health.clone(),
+            PathBuf::from("/sys/fs/bpf/lockc"),
+            true,
+            Vec::new(),
+            EbpfSupervision::default(),
+        )
+        .without_repair();
+        health.set(EbpfState::Failed);
+        supervisor.checked();
+        assert_eq!(health.state(), EbpfState::Healthy);
+    }
+}
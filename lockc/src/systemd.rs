@@ -0,0 +1,245 @@
+//! Integration with systemd: readiness and watchdog notifications
+//! (`sd_notify`) and socket activation.
+
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, RawFd},
+        net::UnixDatagram,
+    },
+    path::Path,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    sys::socket::{sendto, MsgFlags, UnixAddr},
+};
+use thiserror::Error;
+use tracing::{error, warn};
+
+/// The first file descriptor passed by systemd with socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Error, Debug)]
+pub enum SystemdError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    Errno(#[from] nix::errno::Errno),
+}
+
+fn notify_to(socket_path: &OsStr, state: &str) -> Result<(), SystemdError> {
+    let socket = UnixDatagram::unbound()?;
+    match socket_path.as_bytes().strip_prefix(b"@") {
+        // Socket in the abstract namespace.
+        Some(name) => {
+            let addr = UnixAddr::new_abstract(name)?;
+            sendto(
+                socket.as_raw_fd(),
+                state.as_bytes(),
+                &addr,
+                MsgFlags::empty(),
+            )?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), Path::new(socket_path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends a state notification (e.g. `READY=1`) to systemd. Does nothing if
+/// lockc is not running as a systemd service with notify access.
+pub fn notify(state: &str) -> Result<(), SystemdError> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(socket_path) => notify_to(&socket_path, state),
+        None => Ok(()),
+    }
+}
+
+fn parse_watchdog_usec(
+    watchdog_usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+    match watchdog_usec?.parse::<u64>().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// Returns the watchdog timeout configured with `WatchdogSec=`, if any.
+pub fn watchdog_timeout() -> Option<Duration> {
+    parse_watchdog_usec(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    )
+}
+
+/// How often a loop watched by the watchdog advances its [`Liveness`] when
+/// it's idle. `WatchdogSec=` has to be more than twice as long.
+pub const LIVENESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token which a loop advances every time it goes through an iteration. The
+/// watchdog checks that it keeps advancing.
+#[derive(Clone, Default)]
+pub struct Liveness(Arc<AtomicU64>);
+
+impl Liveness {
+    pub fn advance(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Returns names of loops whose tokens didn't advance since the values in
+/// `last`, which get updated.
+fn stalled_loops<'a>(
+    loops: &'a [(&'static str, Liveness)],
+    last: &mut [Option<u64>],
+) -> Vec<&'a str> {
+    loops
+        .iter()
+        .zip(last.iter_mut())
+        .filter_map(|((name, liveness), last)| {
+            let current = liveness.get();
+            let stalled = *last == Some(current);
+            *last = Some(current);
+            stalled.then_some(*name)
+        })
+        .collect()
+}
+
+/// Sends watchdog heartbeats to systemd at half of the given timeout, as long
+/// as tokens of all the given loops advance between heartbeats. A stuck loop
+/// makes systemd restart lockc.
+pub async fn watchdog(timeout: Duration, loops: Vec<(&'static str, Liveness)>) {
+    let mut interval = tokio::time::interval(timeout / 2);
+    let mut last = vec![None; loops.len()];
+    loop {
+        interval.tick().await;
+        let stalled = stalled_loops(&loops, &mut last);
+        if !stalled.is_empty() {
+            error!(
+                loops = stalled.join(", ").as_str(),
+                "loops don't make progress, skipping the watchdog heartbeat"
+            );
+            continue;
+        }
+        if let Err(e) = notify("WATCHDOG=1") {
+            warn!(
+                error = e.to_string().as_str(),
+                "could not send the watchdog heartbeat"
+            );
+        }
+    }
+}
+
+fn parse_listen_fds(listen_fds: Option<&str>, listen_pid: Option<&str>, pid: u32) -> Vec<RawFd> {
+    if listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) != Some(pid) {
+        return Vec::new();
+    }
+    match listen_fds.and_then(|listen_fds| listen_fds.parse::<RawFd>().ok()) {
+        Some(n) if n > 0 => (LISTEN_FDS_START..LISTEN_FDS_START + n).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns file descriptors passed by systemd with socket activation. The
+/// related environment variables are unset, so they are not inherited by
+/// child processes.
+pub fn listen_fds() -> Result<Vec<RawFd>, SystemdError> {
+    let fds = parse_listen_fds(
+        env::var("LISTEN_FDS").ok().as_deref(),
+        env::var("LISTEN_PID").ok().as_deref(),
+        process::id(),
+    );
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    for fd in &fds {
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_to_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0; 16];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn watchdog_usec() {
+        assert_eq!(
+            parse_watchdog_usec(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog_usec(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_watchdog_usec(Some("30000000"), Some("1"), 42), None);
+        assert_eq!(parse_watchdog_usec(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog_usec(None, None, 42), None);
+    }
+
+    #[test]
+    fn watchdog_stalled_loops() {
+        let fanotify = Liveness::default();
+        let ebpf = Liveness::default();
+        let loops = [("fanotify", fanotify.clone()), ("ebpf", ebpf.clone())];
+        let mut last = [None, None];
+
+        assert!(stalled_loops(&loops, &mut last).is_empty());
+        assert_eq!(stalled_loops(&loops, &mut last), ["fanotify", "ebpf"]);
+        ebpf.advance();
+        assert_eq!(stalled_loops(&loops, &mut last), ["fanotify"]);
+        fanotify.advance();
+        ebpf.advance();
+        assert!(stalled_loops(&loops, &mut last).is_empty());
+    }
+
+    #[test]
+    fn listen_fds_for_pid() {
+        assert_eq!(parse_listen_fds(Some("2"), Some("42"), 42), vec![3, 4]);
+        assert_eq!(
+            parse_listen_fds(Some("2"), Some("1"), 42),
+            Vec::<RawFd>::new()
+        );
+        assert_eq!(parse_listen_fds(None, Some("42"), 42), Vec::<RawFd>::new());
+        assert_eq!(parse_listen_fds(Some("1"), None, 42), Vec::<RawFd>::new());
+    }
+}
@@ -1,9 +1,20 @@
 use std::{
-    fs::File,
+    fs::{self, File},
     io::{self, prelude::*},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+use nix::{
+    errno::Errno,
+    mount::{mount, MsFlags},
+    sys::{
+        resource::{setrlimit, Resource},
+        statfs::{statfs, BPF_FS_MAGIC},
+        utsname::uname,
+    },
+};
+use procfs::{process::Process, ProcResult};
+
 #[derive(thiserror::Error, Debug)]
 pub enum CheckBpfLsmError {
     #[error("regex compilation error")]
@@ -12,7 +23,10 @@ pub enum CheckBpfLsmError {
     #[error("I/O error")]
     IO(#[from] io::Error),
 
-    #[error("BPF LSM is not enabled")]
+    #[error(
+        "BPF LSM is not enabled, add `bpf` to the `lsm=` kernel command line parameter \
+         (the current list is in /sys/kernel/security/lsm)"
+    )]
     BpfLsmDisabled,
 }
 
@@ -30,6 +44,208 @@ pub fn check_bpf_lsm_enabled<P: AsRef<Path>>(sys_lsm_path: P) -> Result<(), Chec
     }
 }
 
+/// Path of the kernel lockdown mode.
+pub const LOCKDOWN_PATH: &str = "/sys/kernel/security/lockdown";
+/// Path of BTF of the running kernel, required by lockc's programs.
+pub const BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+/// EFI variable holding the Secure Boot state.
+pub const SECURE_BOOT_PATH: &str =
+    "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// Kernel lockdown mode, which restricts what eBPF programs can do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lockdown {
+    None,
+    /// Programs cannot modify the kernel or user memory.
+    Integrity,
+    /// Programs cannot read the kernel memory either.
+    Confidentiality,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CheckKernelError {
+    #[error("I/O error")]
+    IO(#[from] io::Error),
+
+    #[error(
+        "kernel lockdown is in the confidentiality mode{}, which forbids eBPF programs to read \
+         kernel memory; boot with `lockdown=integrity` on the kernel command line",
+        if *.secure_boot { " (enabled by Secure Boot)" } else { "" }
+    )]
+    LockdownConfidentiality { secure_boot: bool },
+
+    #[error(
+        "kernel BTF ({}) is missing, lockc requires a kernel built with \
+         CONFIG_DEBUG_INFO_BTF=y",
+        .0.display()
+    )]
+    BtfMissing(PathBuf),
+}
+
+/// Returns the active kernel lockdown mode, marked with brackets in the
+/// lockdown file, or `None` if the kernel doesn't support lockdown.
+pub fn lockdown_mode<P: AsRef<Path>>(lockdown_path: P) -> Result<Option<Lockdown>, io::Error> {
+    let content = match fs::read_to_string(lockdown_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(content.split_whitespace().find_map(|mode| match mode {
+        "[none]" => Some(Lockdown::None),
+        "[integrity]" => Some(Lockdown::Integrity),
+        "[confidentiality]" => Some(Lockdown::Confidentiality),
+        _ => None,
+    }))
+}
+
+/// Returns whether Secure Boot is enabled. The EFI variable consists of 4
+/// bytes of attributes and the value.
+pub fn secure_boot_enabled<P: AsRef<Path>>(secure_boot_path: P) -> bool {
+    fs::read(secure_boot_path)
+        .map(|content| content.get(4) == Some(&1))
+        .unwrap_or(false)
+}
+
+/// Checks whether the kernel allows loading lockc's programs. lockc uses
+/// only BTF tracepoints and LSM programs, which work in the integrity
+/// lockdown mode, but reading paths and mount sources needs the kernel
+/// memory access forbidden in the confidentiality mode.
+pub fn check_kernel<P: AsRef<Path>>(
+    lockdown_path: P,
+    btf_path: P,
+    secure_boot_path: P,
+) -> Result<Option<Lockdown>, CheckKernelError> {
+    let lockdown = lockdown_mode(lockdown_path)?;
+    if lockdown == Some(Lockdown::Confidentiality) {
+        return Err(CheckKernelError::LockdownConfidentiality {
+            secure_boot: secure_boot_enabled(secure_boot_path),
+        });
+    }
+    if !btf_path.as_ref().exists() {
+        return Err(CheckKernelError::BtfMissing(
+            btf_path.as_ref().to_path_buf(),
+        ));
+    }
+    Ok(lockdown)
+}
+
+/// Mount point of bpffs, where eBPF maps are pinned.
+pub const BPFFS_PATH: &str = "/sys/fs/bpf";
+
+/// Default directory in bpffs where eBPF maps of lockc are pinned.
+pub const BPF_PIN_PATH: &str = "/sys/fs/bpf/lockc";
+
+/// Returns the bpffs directory of tests which load eBPF programs. `cargo
+/// xtask test --privileged` mounts a private bpffs for them and passes it in
+/// `LOCKC_TEST_BPFFS`.
+#[cfg(test)]
+pub fn test_bpffs() -> PathBuf {
+    std::env::var_os("LOCKC_TEST_BPFFS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/sys/fs/bpf"))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SetupHostError {
+    #[error(
+        "could not raise RLIMIT_MEMLOCK ({0}), which kernels older than 5.11 require for \
+         eBPF maps; run lockc with CAP_SYS_RESOURCE or `LimitMEMLOCK=infinity` in the \
+         systemd unit"
+    )]
+    Memlock(#[source] Errno),
+
+    #[error("could not check the filesystem of {path}: {1}", path = .0.display())]
+    Statfs(PathBuf, #[source] Errno),
+
+    #[error(
+        "bpffs is not mounted on {path}, mount it with `mount -t bpf bpf {path}` or enable \
+         `mount_bpffs` in the configuration",
+        path = .0.display()
+    )]
+    BpffsNotMounted(PathBuf),
+
+    #[error("could not create the bpffs mount point: {0}")]
+    IO(#[from] io::Error),
+
+    #[error("could not mount bpffs on {path}: {1}", path = .0.display())]
+    BpffsMount(PathBuf, #[source] Errno),
+}
+
+/// Returns the major and minor version from a kernel release, e.g.
+/// `5.10.0-8-amd64`.
+pub fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Removes the RLIMIT_MEMLOCK limit. Kernels older than 5.11 charge eBPF
+/// maps against it instead of the memory cgroup, and the default limit is too
+/// low for lockc's maps. On newer kernels a failure is harmless, so it's
+/// reported only on older ones. Returns whether the limit was raised.
+pub fn bump_memlock_rlimit() -> Result<bool, SetupHostError> {
+    match setrlimit(
+        Resource::RLIMIT_MEMLOCK,
+        libc::RLIM_INFINITY,
+        libc::RLIM_INFINITY,
+    ) {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            let memcg_accounting = uname()
+                .ok()
+                .and_then(|uts| kernel_version(&uts.release().to_string_lossy()))
+                .map(|version| version >= (5, 11))
+                .unwrap_or(false);
+            if memcg_accounting {
+                Ok(false)
+            } else {
+                Err(SetupHostError::Memlock(e))
+            }
+        }
+    }
+}
+
+/// Returns whether bpffs is mounted on the given path.
+pub fn is_bpffs(path: &Path) -> Result<bool, SetupHostError> {
+    match statfs(path) {
+        Ok(stat) => Ok(stat.filesystem_type() == BPF_FS_MAGIC),
+        Err(Errno::ENOENT) => Ok(false),
+        Err(e) => Err(SetupHostError::Statfs(path.to_path_buf(), e)),
+    }
+}
+
+/// Makes sure that bpffs is mounted on the given path, mounting it if it's
+/// absent and `mount_bpffs` is set. Returns whether it was mounted.
+pub fn ensure_bpffs<P: AsRef<Path>>(path: P, mount_bpffs: bool) -> Result<bool, SetupHostError> {
+    let path = path.as_ref();
+    if is_bpffs(path)? {
+        return Ok(false);
+    }
+    if !mount_bpffs {
+        return Err(SetupHostError::BpffsNotMounted(path.to_path_buf()));
+    }
+    fs::create_dir_all(path)?;
+    mount(
+        Some("bpf"),
+        path,
+        Some("bpf"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .map_err(|e| SetupHostError::BpffsMount(path.to_path_buf(), e))?;
+    Ok(true)
+}
+
+/// Returns the number of nested PID namespaces the process is visible in.
+pub fn pid_ns_depth(process: &Process) -> ProcResult<usize> {
+    Ok(process
+        .status()?
+        .nspid
+        .map(|nspid| nspid.len())
+        .unwrap_or(1))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -45,6 +261,65 @@ mod tests {
         assert!(check_bpf_lsm_enabled(&sys_lsm_path).is_ok());
     }
 
+    #[test]
+    fn lockdown_mode_active() {
+        let dir = tempdir().unwrap();
+        let lockdown_path = dir.path().join("lockdown");
+        assert_eq!(lockdown_mode(&lockdown_path).unwrap(), None);
+        fs::write(&lockdown_path, "none [integrity] confidentiality\n").unwrap();
+        assert_eq!(
+            lockdown_mode(&lockdown_path).unwrap(),
+            Some(Lockdown::Integrity)
+        );
+    }
+
+    #[test]
+    fn check_kernel_errors() {
+        let dir = tempdir().unwrap();
+        let lockdown_path = dir.path().join("lockdown");
+        let btf_path = dir.path().join("vmlinux");
+        let secure_boot_path = dir.path().join("SecureBoot");
+
+        fs::write(&lockdown_path, "[none] integrity confidentiality\n").unwrap();
+        assert!(matches!(
+            check_kernel(&lockdown_path, &btf_path, &secure_boot_path),
+            Err(CheckKernelError::BtfMissing(_))
+        ));
+        fs::write(&btf_path, "").unwrap();
+        assert_eq!(
+            check_kernel(&lockdown_path, &btf_path, &secure_boot_path).unwrap(),
+            Some(Lockdown::None)
+        );
+
+        fs::write(&lockdown_path, "none integrity [confidentiality]\n").unwrap();
+        fs::write(&secure_boot_path, [6, 0, 0, 0, 1]).unwrap();
+        let res = check_kernel(&lockdown_path, &btf_path, &secure_boot_path);
+        assert!(matches!(
+            res,
+            Err(CheckKernelError::LockdownConfidentiality { secure_boot: true })
+        ));
+        assert!(res.unwrap_err().to_string().contains("Secure Boot"));
+    }
+
+    #[test]
+    fn kernel_version_from_release() {
+        assert_eq!(kernel_version("5.10.0-8-amd64"), Some((5, 10)));
+        assert_eq!(kernel_version("6.2.9-arch1-1"), Some((6, 2)));
+        assert_eq!(kernel_version("5.14.21-150400.24-default"), Some((5, 14)));
+        assert_eq!(kernel_version("unknown"), None);
+    }
+
+    #[test]
+    fn bpffs_not_mounted() {
+        let dir = tempdir().unwrap();
+        assert!(!is_bpffs(dir.path()).unwrap());
+        assert!(!is_bpffs(&dir.path().join("missing")).unwrap());
+        assert!(matches!(
+            ensure_bpffs(dir.path(), false),
+            Err(SetupHostError::BpffsNotMounted(_))
+        ));
+    }
+
     #[test]
     fn check_bpf_lsm_enabled_should_return_error() {
         let dir = tempdir().unwrap();
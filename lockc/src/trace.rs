@@ -0,0 +1,69 @@
+//! Events about containerized processes sent by eBPF programs in trace mode,
+//! streamed to clients of the control API.
+
+use aya::Bpf;
+use lockc_common::{control::ProcessEventInfo, ProcessEvent};
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::perf::{PerfBuffers, PerfError};
+
+/// Converts the raw event sent by eBPF programs. Returns `None` for
+/// malformed events.
+fn event_info(event: &ProcessEvent) -> Option<ProcessEventInfo> {
+    Some(ProcessEventInfo {
+        kind: event.kind()?,
+        container_id: event
+            .container_id
+            .as_str()
+            .ok()?
+            .trim_end_matches('\0')
+            .to_string(),
+        pid: event.pid,
+        ppid: event.ppid,
+        comm: event.comm(),
+    })
+}
+
+/// Starts reading process events and sending them to the given channel.
+/// Events are dropped when no client is subscribed.
+pub fn spawn(bpf: &mut Bpf, tx: broadcast::Sender<ProcessEventInfo>) -> Result<(), PerfError> {
+    PerfBuffers::open(bpf, "PROCESS_EVENTS")?.spawn(move |event: ProcessEvent| {
+        match event_info(&event) {
+            Some(info) => {
+                let _ = tx.send(info);
+            }
+            None => debug!("malformed process event"),
+        }
+        true
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use lockc_common::ProcessEventKind;
+
+    use super::*;
+
+    #[test]
+    fn process_event_info() {
+        let mut data = vec![0u8; mem::size_of::<ProcessEvent>()];
+        data[..3].copy_from_slice(b"abc");
+        let mut event = unsafe { (data.as_ptr() as *const ProcessEvent).read_unaligned() };
+        event.pid = 2;
+        event.ppid = 1;
+        event.kind = ProcessEventKind::Fork as u8;
+        event.comm[..2].copy_from_slice(b"sh");
+
+        let info = event_info(&event).unwrap();
+        assert_eq!(info.kind, ProcessEventKind::Fork);
+        assert_eq!(info.container_id, "abc");
+        assert_eq!(info.comm, "sh");
+
+        event.kind = 42;
+        assert!(event_info(&event).is_none());
+    }
+}
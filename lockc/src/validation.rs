@@ -0,0 +1,320 @@
+//! Validation of OCI runtime specs (`config.json`) of containers against
+//! their policy levels at create time. eBPF programs enforce policies only
+//! when containers do something at runtime, while a spec shows what the
+//! container is going to get before it starts, e.g. host namespaces or
+//! capabilities which are not restricted by any LSM hook.
+
+use std::fmt;
+
+use lockc_common::{
+    verdict::{self, Verdict},
+    ContainerPolicyLevel,
+};
+use serde::Deserialize;
+
+use crate::{custom_policies::CustomPolicy, profiles::AllowedPaths, settings::SpecValidationMode};
+
+/// Capabilities granted by container engines by default. The baseline Pod
+/// Security Standard doesn't allow adding any other ones.
+const DEFAULT_CAPABILITIES: &[&str] = &[
+    "CAP_AUDIT_WRITE",
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_MKNOD",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_RAW",
+    "CAP_SETFCAP",
+    "CAP_SETGID",
+    "CAP_SETPCAP",
+    "CAP_SETUID",
+    "CAP_SYS_CHROOT",
+];
+
+/// Namespaces which containers have to get on their own. A namespace missing
+/// in the spec is shared with the host.
+const HOST_NAMESPACES: &[&str] = &["ipc", "network", "pid"];
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Mount {
+    source: String,
+    #[serde(rename = "type")]
+    mount_type: String,
+    options: Vec<String>,
+}
+
+impl Mount {
+    fn is_bind(&self) -> bool {
+        self.mount_type == "bind"
+            || self
+                .options
+                .iter()
+                .any(|option| option == "bind" || option == "rbind")
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Capabilities {
+    bounding: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ProcessConfig {
+    capabilities: Option<Capabilities>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Namespace {
+    #[serde(rename = "type")]
+    ns_type: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Device {
+    path: String,
+}
+
+/// Device cgroup rule. A rule allowing access without a type or with the `a`
+/// type and without numbers covers all devices of the host.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct DeviceRule {
+    allow: bool,
+    #[serde(rename = "type")]
+    device_type: Option<String>,
+    major: Option<i64>,
+    minor: Option<i64>,
+}
+
+impl DeviceRule {
+    fn allows_all(&self) -> bool {
+        self.allow
+            && self.device_type.as_deref().unwrap_or("a") == "a"
+            && self.major.is_none()
+            && self.minor.is_none()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Resources {
+    devices: Vec<DeviceRule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LinuxConfig {
+    namespaces: Vec<Namespace>,
+    devices: Vec<Device>,
+    resources: Option<Resources>,
+}
+
+/// Parts of the OCI runtime spec which are validated.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct BundleConfig {
+    mounts: Vec<Mount>,
+    process: ProcessConfig,
+    linux: LinuxConfig,
+}
+
+/// Part of a spec which is not allowed by the policy level of the container.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpecViolation {
+    /// Bind mount of a host path outside of the allowed paths.
+    BindMount(String),
+    /// Capability added on top of the default ones.
+    Capability(String),
+    /// Namespace shared with the host.
+    HostNamespace(&'static str),
+    /// Device of the host exposed to the container.
+    Device(String),
+    /// Access to all devices of the host.
+    AllDevices,
+}
+
+impl fmt::Display for SpecViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecViolation::BindMount(source) => write!(f, "bind mount of {}", source),
+            SpecViolation::Capability(cap) => write!(f, "added capability {}", cap),
+            SpecViolation::HostNamespace(ns) => write!(f, "host {} namespace", ns),
+            SpecViolation::Device(path) => write!(f, "host device {}", path),
+            SpecViolation::AllDevices => write!(f, "access to all host devices"),
+        }
+    }
+}
+
+/// Validates specs of containers with the allowed paths the eBPF programs
+/// were loaded with.
+pub struct SpecValidator {
+    mode: SpecValidationMode,
+    allowed_paths: AllowedPaths,
+}
+
+impl SpecValidator {
+    pub fn new(mode: SpecValidationMode, allowed_paths: AllowedPaths) -> Self {
+        SpecValidator {
+            mode,
+            allowed_paths,
+        }
+    }
+
+    pub fn mode(&self) -> SpecValidationMode {
+        self.mode
+    }
+
+    /// Returns parts of the spec which violate the policy level. Only
+    /// baseline, restricted and offline containers are validated. Bind
+    /// mounts and capabilities allowed by the custom policy of the container
+    /// are not violations.
+    pub fn validate(
+        &self,
+        policy_level: ContainerPolicyLevel,
+        config: &BundleConfig,
+        custom_policy: Option<&CustomPolicy>,
+    ) -> Vec<SpecViolation> {
+        let mut violations = Vec::new();
+        if self.mode == SpecValidationMode::Off || !verdict::enforced(policy_level) {
+            return violations;
+        }
+
+        for mount in config.mounts.iter().filter(|mount| mount.is_bind()) {
+            if custom_policy.map_or(false, |custom| custom.allows_mount(&mount.source)) {
+                continue;
+            }
+            if verdict::mount(&self.allowed_paths, policy_level, "bind", &mount.source)
+                == Verdict::Deny
+            {
+                violations.push(SpecViolation::BindMount(mount.source.clone()));
+            }
+        }
+
+        if let Some(capabilities) = &config.process.capabilities {
+            for cap in &capabilities.bounding {
+                if !DEFAULT_CAPABILITIES.contains(&cap.as_str())
+                    && !custom_policy.map_or(false, |custom| custom.allows_capability(cap))
+                {
+                    violations.push(SpecViolation::Capability(cap.clone()));
+                }
+            }
+        }
+
+        for ns in HOST_NAMESPACES {
+            if !config.linux.namespaces.iter().any(|n| n.ns_type == *ns) {
+                violations.push(SpecViolation::HostNamespace(ns));
+            }
+        }
+
+        for device in &config.linux.devices {
+            violations.push(SpecViolation::Device(device.path.clone()));
+        }
+        if let Some(resources) = &config.linux.resources {
+            if resources.devices.iter().any(DeviceRule::allows_all) {
+                violations.push(SpecViolation::AllDevices);
+            }
+        }
+
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator(mode: SpecValidationMode) -> SpecValidator {
+        SpecValidator::new(
+            mode,
+            AllowedPaths {
+                mount_baseline: vec!["/var/lib/docker/containers".to_string()],
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn validate_privileged_spec() {
+        let config: BundleConfig = serde_json::from_str(
+            r#"{
+                "mounts": [
+                    {"destination": "/proc", "type": "proc", "source": "proc"},
+                    {"destination": "/etc/hostname", "type": "bind", "source": "/var/lib/docker/containers/abc/hostname", "options": ["rbind"]},
+                    {"destination": "/host", "type": "none", "source": "/etc", "options": ["rbind", "ro"]}
+                ],
+                "process": {"capabilities": {"bounding": ["CAP_CHOWN", "CAP_SYS_ADMIN"]}},
+                "linux": {
+                    "namespaces": [{"type": "mount"}, {"type": "ipc"}],
+                    "devices": [{"path": "/dev/sda", "type": "b", "major": 8, "minor": 0}],
+                    "resources": {"devices": [{"allow": false, "access": "rwm"}, {"allow": true, "access": "rwm"}]}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let violations = validator(SpecValidationMode::Warn).validate(
+            ContainerPolicyLevel::Baseline,
+            &config,
+            None,
+        );
+        assert_eq!(
+            violations,
+            vec![
+                SpecViolation::BindMount("/etc".to_string()),
+                SpecViolation::Capability("CAP_SYS_ADMIN".to_string()),
+                SpecViolation::HostNamespace("network"),
+                SpecViolation::HostNamespace("pid"),
+                SpecViolation::Device("/dev/sda".to_string()),
+                SpecViolation::AllDevices,
+            ]
+        );
+
+        let custom_policy = CustomPolicy::new(
+            ContainerPolicyLevel::Baseline,
+            None,
+            vec!["/etc".to_string()],
+            vec!["CAP_SYS_ADMIN".to_string()],
+            vec![],
+        )
+        .unwrap();
+        let violations = validator(SpecValidationMode::Warn).validate(
+            ContainerPolicyLevel::Baseline,
+            &config,
+            Some(&custom_policy),
+        );
+        assert!(!violations.contains(&SpecViolation::BindMount("/etc".to_string())));
+        assert!(!violations.contains(&SpecViolation::Capability("CAP_SYS_ADMIN".to_string())));
+
+        assert!(validator(SpecValidationMode::Strict)
+            .validate(ContainerPolicyLevel::Privileged, &config, None)
+            .is_empty());
+        assert!(validator(SpecValidationMode::Off)
+            .validate(ContainerPolicyLevel::Baseline, &config, None)
+            .is_empty());
+    }
+
+    #[test]
+    fn validate_default_spec() {
+        let config: BundleConfig = serde_json::from_str(
+            r#"{
+                "mounts": [{"destination": "/proc", "type": "proc", "source": "proc"}],
+                "process": {"capabilities": {"bounding": ["CAP_CHOWN", "CAP_NET_RAW"]}},
+                "linux": {
+                    "namespaces": [{"type": "pid"}, {"type": "network"}, {"type": "ipc"}],
+                    "resources": {"devices": [{"allow": false, "access": "rwm"}, {"allow": true, "type": "c", "major": 1, "minor": 3, "access": "rwm"}]}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(validator(SpecValidationMode::Strict)
+            .validate(ContainerPolicyLevel::Restricted, &config, None)
+            .is_empty());
+    }
+}
@@ -0,0 +1,397 @@
+//! Violation events sent by eBPF programs when they deny an operation in a
+//! container. eBPF programs rate limit events per container and hook, and
+//! identical events are deduplicated here, so a misbehaving container
+//! doesn't flood the consumers. Suppressed violations are counted and
+//! reported with the next event.
+
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use aya::Bpf;
+use lockc_common::{control::ContainerInfo, Enforcement, Hook, Violation};
+use tokio::{sync::broadcast, time};
+use tracing::debug;
+
+use crate::{
+    perf::{PerfBuffers, PerfError},
+    registry::ContainerRegistry,
+};
+
+/// Denied operation together with the metadata of the container it happened
+/// in.
+#[derive(Clone, Debug)]
+pub struct ViolationEvent {
+    pub container_id: String,
+    pub pid: u32,
+    pub hook: Hook,
+    pub detail: Option<String>,
+    /// Whether the operation was denied, or only reported in complain mode.
+    pub enforcement: Enforcement,
+    /// Whether the process was killed, because of the response configured
+    /// for the denied path.
+    pub killed: bool,
+    /// Number of identical violations suppressed before this one, by the
+    /// rate limit of eBPF programs or by deduplication.
+    pub suppressed: u64,
+    /// Metadata of the container, if it's still registered.
+    pub container: Option<ContainerInfo>,
+}
+
+impl ViolationEvent {
+    /// Creates the event from the raw data sent by eBPF programs. Returns
+    /// `None` for malformed events.
+    fn new(violation: &Violation, containers: &RwLock<ContainerRegistry>) -> Option<Self> {
+        let container_id = violation
+            .container_id
+            .as_str()
+            .ok()?
+            .trim_end_matches('\0')
+            .to_string();
+        let container = containers
+            .read()
+            .ok()
+            .and_then(|containers| containers.get(&container_id).cloned());
+        Some(ViolationEvent {
+            container_id,
+            pid: violation.pid,
+            hook: violation.hook()?,
+            detail: violation.detail(),
+            enforcement: violation.enforcement(),
+            killed: violation.killed(),
+            suppressed: violation.suppressed.into(),
+            container,
+        })
+    }
+
+    /// Returns a human-readable description of the denied operation.
+    pub fn description(&self) -> String {
+        let action = match self.hook {
+            Hook::Syslog => "accessing the kernel log",
+            Hook::SbMount => "bind mounting",
+            Hook::TaskFixSetuid => "changing the UID to root",
+            Hook::FileOpen => "opening",
+            Hook::UnixStreamConnect => "connecting to a denied socket",
+            Hook::InodePermission => "accessing pinned eBPF objects of lockc",
+            Hook::Connect => "connecting to a denied network",
+            Hook::FileReceive => "receiving a file descriptor of a host socket or file",
+            Hook::MoveMount => "attaching a detached bind mount",
+            Hook::ProcMasking => "unmasking a masked path of procfs",
+        };
+        let verb = match self.enforcement {
+            Enforcement::Enforce => "denied",
+            Enforcement::Complain => "would deny",
+        };
+        let description = match &self.detail {
+            Some(detail) => format!("lockc {} {} {} (pid {})", verb, action, detail, self.pid),
+            None => format!("lockc {} {} (pid {})", verb, action, self.pid),
+        };
+        if self.killed {
+            format!("{} and killed the process", description)
+        } else {
+            description
+        }
+    }
+
+    /// Returns the description followed by the number of suppressed repeats,
+    /// if any.
+    pub fn summary(&self) -> String {
+        match self.suppressed {
+            0 => self.description(),
+            suppressed => format!("{}, {} repeats suppressed", self.description(), suppressed),
+        }
+    }
+}
+
+/// Violations are identical when they happen in the same container, hook and
+/// path, regardless of the process.
+type DedupKey = (String, Hook, Enforcement, Option<String>);
+
+struct DedupEntry {
+    /// When the last event was sent.
+    sent: Instant,
+    /// Last suppressed event, sent as a summary when the interval ends.
+    pending: Option<ViolationEvent>,
+    /// Number of suppressed violations before the pending one.
+    suppressed: u64,
+}
+
+/// Sends identical violations at most once per interval. The last violation
+/// suppressed in the interval is sent as a summary with the count of the
+/// other ones when the interval ends.
+pub struct Deduplicator {
+    interval: Duration,
+    entries: HashMap<DedupKey, DedupEntry>,
+}
+
+impl Deduplicator {
+    /// Creates the deduplicator. A zero interval disables deduplication.
+    pub fn new(interval: Duration) -> Self {
+        Deduplicator {
+            interval,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns events to send for the received one: the event itself, none
+    /// if it's suppressed, or the summary of the previous interval followed
+    /// by the event.
+    pub fn push(&mut self, event: ViolationEvent, now: Instant) -> Vec<ViolationEvent> {
+        if self.interval.is_zero() {
+            return vec![event];
+        }
+        let key = (
+            event.container_id.clone(),
+            event.hook,
+            event.enforcement,
+            event.detail.clone(),
+        );
+        let entry = match self.entries.get_mut(&key) {
+            Some(entry) => entry,
+            None => {
+                self.entries.insert(
+                    key,
+                    DedupEntry {
+                        sent: now,
+                        pending: None,
+                        suppressed: 0,
+                    },
+                );
+                return vec![event];
+            }
+        };
+        if now.duration_since(entry.sent) < self.interval {
+            if let Some(pending) = entry.pending.replace(event) {
+                entry.suppressed += 1 + pending.suppressed;
+            }
+            return Vec::new();
+        }
+        let mut events: Vec<ViolationEvent> = entry.summary().into_iter().collect();
+        entry.sent = now;
+        events.push(event);
+        events
+    }
+
+    /// Returns summaries of intervals which ended and forgets violations
+    /// which were not repeated.
+    pub fn flush(&mut self, now: Instant) -> Vec<ViolationEvent> {
+        let interval = self.interval;
+        let mut events = Vec::new();
+        self.entries.retain(|_, entry| {
+            if now.duration_since(entry.sent) < interval {
+                return true;
+            }
+            events.extend(entry.summary());
+            false
+        });
+        events
+    }
+}
+
+impl DedupEntry {
+    fn summary(&mut self) -> Option<ViolationEvent> {
+        let mut summary = self.pending.take()?;
+        summary.suppressed += mem::take(&mut self.suppressed);
+        Some(summary)
+    }
+}
+
+/// Starts reading violation events and sending them to the given channel,
+/// until all receivers are dropped. Identical violations are sent at most
+/// once per `dedup_interval`.
+pub fn spawn(
+    bpf: &mut Bpf,
+    containers: Arc<RwLock<ContainerRegistry>>,
+    tx: broadcast::Sender<ViolationEvent>,
+    dedup_interval: Duration,
+) -> Result<(), PerfError> {
+    let dedup = Arc::new(Mutex::new(Deduplicator::new(dedup_interval)));
+    if !dedup_interval.is_zero() {
+        let dedup = dedup.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(dedup_interval);
+            loop {
+                interval.tick().await;
+                let summaries = match dedup.lock() {
+                    Ok(mut dedup) => dedup.flush(Instant::now()),
+                    Err(_) => return,
+                };
+                for summary in summaries {
+                    if tx.send(summary).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    PerfBuffers::open(bpf, "VIOLATIONS")?.spawn(move |violation: Violation| {
+        let event = match ViolationEvent::new(&violation, &containers) {
+            Some(event) => event,
+            None => {
+                debug!("malformed violation event");
+                return true;
+            }
+        };
+        let events = match dedup.lock() {
+            Ok(mut dedup) => dedup.push(event, Instant::now()),
+            Err(_) => vec![event],
+        };
+        events.into_iter().all(|event| tx.send(event).is_ok())
+    });
+    Ok(())
+}
+
+/// Returns an enforced violation of `hook` by pid 2 in the baseline container
+/// `abc`. Tests override the fields they care about with struct update
+/// syntax.
+#[cfg(test)]
+pub(crate) fn test_event(hook: Hook) -> ViolationEvent {
+    ViolationEvent {
+        container_id: "abc".to_string(),
+        pid: 2,
+        hook,
+        detail: None,
+        enforcement: Enforcement::Enforce,
+        killed: false,
+        suppressed: 0,
+        container: Some(ContainerInfo {
+            id: "abc".to_string(),
+            name: None,
+            pod: None,
+            namespace: None,
+            image: None,
+            parent: None,
+            root: None,
+            runtime_id: None,
+            policy_level: lockc_common::ContainerPolicyLevel::Baseline,
+            enforcement: Enforcement::Enforce,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+
+    use lockc_common::ContainerPolicyLevel;
+
+    use super::*;
+    use crate::registry::ContainerMetadata;
+
+    #[test]
+    fn violation_event_new() {
+        let containers = RwLock::new(ContainerRegistry::default());
+        containers.write().unwrap().insert(
+            "abc".to_string(),
+            ContainerPolicyLevel::Restricted,
+            ContainerMetadata {
+                pod: Some("web".to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+        );
+
+        let mut data = vec![0u8; mem::size_of::<Violation>()];
+        data[..3].copy_from_slice(b"abc");
+        let mut violation = unsafe { (data.as_ptr() as *const Violation).read_unaligned() };
+        violation.pid = 42;
+        violation.hook = Hook::FileOpen as u8;
+        violation.detail[..9].copy_from_slice(b"/sys/fs/\0");
+
+        let event = ViolationEvent::new(&violation, &containers).unwrap();
+        assert_eq!(event.container_id, "abc");
+        assert_eq!(
+            event.container.as_ref().unwrap().pod.as_deref(),
+            Some("web")
+        );
+        assert_eq!(
+            event.description(),
+            "lockc denied opening /sys/fs/ (pid 42)"
+        );
+        assert_eq!(event.summary(), event.description());
+
+        violation.suppressed = 3;
+        let event = ViolationEvent::new(&violation, &containers).unwrap();
+        assert_eq!(
+            event.summary(),
+            "lockc denied opening /sys/fs/ (pid 42), 3 repeats suppressed"
+        );
+
+        violation.enforcement = Enforcement::Complain as u8;
+        let event = ViolationEvent::new(&violation, &containers).unwrap();
+        assert_eq!(
+            event.description(),
+            "lockc would deny opening /sys/fs/ (pid 42)"
+        );
+
+        violation.hook = Hook::FileReceive as u8;
+        violation.detail[0] = 0;
+        let event = ViolationEvent::new(&violation, &containers).unwrap();
+        assert_eq!(
+            event.description(),
+            "lockc would deny receiving a file descriptor of a host socket or file (pid 42)"
+        );
+
+        violation.hook = 0;
+        assert!(ViolationEvent::new(&violation, &containers).is_none());
+    }
+
+    fn event(pid: u32, suppressed: u64) -> ViolationEvent {
+        ViolationEvent {
+            pid,
+            detail: Some("/sys/fs/".to_string()),
+            suppressed,
+            ..test_event(Hook::FileOpen)
+        }
+    }
+
+    #[test]
+    fn deduplicator() {
+        let mut dedup = Deduplicator::new(Duration::from_secs(1));
+        let now = Instant::now();
+
+        assert_eq!(dedup.push(event(1, 0), now).len(), 1);
+        assert!(dedup.push(event(2, 0), now).is_empty());
+        // Violations suppressed by eBPF programs are counted too.
+        assert!(dedup.push(event(3, 5), now).is_empty());
+        assert!(dedup.push(event(4, 0), now).is_empty());
+        // Different paths are not identical.
+        let mut other = event(5, 0);
+        other.detail = Some("/proc/kcore".to_string());
+        assert_eq!(dedup.push(other, now).len(), 1);
+
+        let later = now + Duration::from_millis(500);
+        assert!(dedup.flush(later).is_empty());
+        let later = now + Duration::from_secs(1);
+        let summaries = dedup.flush(later);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].pid, 4);
+        assert_eq!(summaries[0].suppressed, 7);
+
+        // The next violation starts a new interval.
+        assert_eq!(dedup.push(event(6, 0), later).len(), 1);
+        assert!(dedup.push(event(7, 0), later).is_empty());
+        let events = dedup.push(event(8, 0), later + Duration::from_secs(1));
+        assert_eq!(
+            events
+                .iter()
+                .map(|e| (e.pid, e.suppressed))
+                .collect::<Vec<_>>(),
+            vec![(7, 0), (8, 0)]
+        );
+    }
+
+    #[test]
+    fn deduplicator_disabled() {
+        let mut dedup = Deduplicator::new(Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(dedup.push(event(1, 0), now).len(), 1);
+        assert_eq!(dedup.push(event(2, 0), now).len(), 1);
+        assert!(dedup.flush(now).is_empty());
+    }
+}
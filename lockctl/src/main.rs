@@ -1,18 +1,43 @@
-use std::str::FromStr;
+use std::{
+    collections::HashMap as StdHashMap,
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::MetadataExt, net::UnixStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use aya::{
     include_bytes_aligned,
-    maps::{HashMap, MapRef, MapRefMut},
+    maps::{HashMap, LpmTrie, MapRef, MapRefMut},
     Bpf, BpfLoader,
 };
 use clap::{Parser, Subcommand};
 use cli_table::{print_stdout, Cell, Style, Table};
-use lockc_common::{Container, ContainerID, ContainerPolicyLevel, Process};
+use lockc_common::{
+    control::{
+        self, ContainerInfo, ControlRequest, ControlResponse, ImageFingerprintInfo,
+        LearnedMountInfo, CONTROL_SOCKET_PATH,
+    },
+    pid_map_size,
+    verdict::{self, PathList, PathLists, Verdict},
+    Container, ContainerID, ContainerPolicyLevel, ContainerSpec, Enforcement, FilePermission,
+    InodeId, InodeInfo, InodePrefix, PathClass, PathPrefix, Process, INODE_WALK_DEPTH, PATH_LEN,
+    PID_MAPS, PID_MAX_DEFAULT,
+};
 
 const PATH_BASE: &str = "/sys/fs/bpf/lockc";
 
 #[derive(Parser)]
 struct Args {
+    /// Path to the control API socket of lockc.
+    #[arg(long, global = true, default_value = CONTROL_SOCKET_PATH)]
+    socket: PathBuf,
+
+    /// Directory in bpffs where eBPF maps of lockc are pinned.
+    #[arg(long, global = true, default_value = PATH_BASE)]
+    bpf_pin_path: PathBuf,
+
     #[command(subcommand)]
     subcommand: Sub,
 }
@@ -29,12 +54,65 @@ enum Sub {
         #[command(subcommand)]
         process: SubProcess,
     },
+    /// Show digests of the eBPF object loaded by lockc.
+    Digests,
+    /// Show how many times each LSM program was invoked and how many
+    /// operations it allowed and denied.
+    Stats,
+    /// Show or change the log filter of lockc at runtime.
+    LogFilter {
+        /// New filter, a list of `target=level` directives with an optional
+        /// default level, e.g. `info,lockc=debug`.
+        filter: Option<String>,
+    },
+    /// Stream fork, exec and exit events of containerized processes. lockc
+    /// has to run in trace mode (`--trace`).
+    Trace {
+        /// The ID of the container. Events of all containers are shown when
+        /// not given.
+        #[arg(long)]
+        container: Option<String>,
+    },
+    /// Show when and by whom policies of containers were changed, oldest
+    /// first.
+    History {
+        /// The ID of the container, possibly truncated. Changes of all
+        /// containers are shown when not given.
+        container: Option<String>,
+    },
+    /// Show bind mounts recorded in learning mode (`learning_mode` setting).
+    Learn {
+        #[command(subcommand)]
+        learn: SubLearn,
+    },
+    /// Show fingerprints of container images and policies suggested by them
+    /// (`fingerprints` setting).
+    Fingerprint {
+        #[command(subcommand)]
+        fingerprint: SubFingerprint,
+    },
+    /// Evaluate what the current policy of a container would decide for the
+    /// given operation, without performing it.
+    Check {
+        /// The ID of the container.
+        #[arg(long, global = true)]
+        container: Option<String>,
+
+        #[command(subcommand)]
+        check: SubCheck,
+    },
 }
 
 #[derive(Subcommand)]
 enum SubContainer {
     /// List all containers.
     List,
+    /// Show registered containers matching the given container key or
+    /// runtime ID, which can be truncated (e.g. as shown by `crictl ps`).
+    Resolve {
+        /// The key or the runtime ID of the container.
+        id: String,
+    },
     ApplyPolicy {
         /// The ID of the container.
         container_id: String,
@@ -42,47 +120,245 @@ enum SubContainer {
         #[clap(value_enum)]
         policy: ContainerPolicyLevel,
     },
+    /// Switch a container between enforcing its policy and only reporting
+    /// violations (complain mode).
+    Enforcement {
+        /// The ID of the container.
+        container_id: String,
+        /// The enforcement mode.
+        #[clap(value_enum)]
+        enforcement: Enforcement,
+    },
+    /// Register a container with its first process. Meant for agents
+    /// registering containers when lockc runs with `--no-watcher`.
+    Add {
+        /// The ID of the container.
+        container_id: String,
+        /// PID of the first process of the container.
+        #[arg(long)]
+        pid: i32,
+        /// The policy of the container.
+        #[arg(long, value_enum)]
+        policy: ContainerPolicyLevel,
+        /// Whether violations of the policy are denied, or only reported.
+        #[arg(long, value_enum, default_value_t)]
+        enforcement: Enforcement,
+        /// Path to the bundle of the container, to read its spec from.
+        #[arg(long)]
+        bundle: Option<String>,
+        /// Name of the container.
+        #[arg(long)]
+        name: Option<String>,
+        /// Name of the Kubernetes pod.
+        #[arg(long)]
+        pod: Option<String>,
+        /// Kubernetes namespace.
+        #[arg(long)]
+        namespace: Option<String>,
+        /// Image of the container.
+        #[arg(long)]
+        image: Option<String>,
+        /// Allow the container to read the kernel log regardless of its
+        /// policy (e.g. node-problem-detector or a log collector).
+        #[arg(long)]
+        allow_syslog: bool,
+        /// Replace the policy of the container if it's already registered.
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Delete a registered container.
+    Delete {
+        /// The ID of the container.
+        container_id: String,
+    },
+    /// Thaw a container frozen in response to an attack
+    /// (`incident_response` setting).
+    Thaw {
+        /// The ID of the container.
+        container_id: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum SubLearn {
+    /// Print the `allowed_paths` settings which would allow all recorded
+    /// bind mounts.
+    Export,
+}
+
+#[derive(Subcommand)]
+enum SubFingerprint {
+    /// List fingerprints of images.
+    List {
+        /// Show only images whose reference contains this string.
+        #[arg(long)]
+        image: Option<String>,
+    },
+    /// Print `image_policies` rules with the lowest policy level which
+    /// allows everything containers of each image were observed doing.
+    Suggest {
+        /// Suggest policies only of images whose reference contains this
+        /// string.
+        #[arg(long)]
+        image: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SubCheck {
+    /// Mounting a filesystem.
+    Mount {
+        /// Source of the mount.
+        #[arg(long)]
+        source: String,
+        /// Type of the mount.
+        #[arg(long = "type", default_value = "bind")]
+        mount_type: String,
+    },
+    /// Opening a file.
+    Open {
+        /// Path of the file.
+        #[arg(long)]
+        path: String,
+    },
+    /// Changing the UID.
+    Setuid {
+        /// The new UID.
+        #[arg(long)]
+        uid: u32,
+    },
+    /// Accessing the kernel logs.
+    Syslog,
+    /// Sending or receiving messages through sockets.
+    Socket,
 }
 
 #[derive(Subcommand)]
 enum SubProcess {
     /// List all processes.
     List,
+    /// Add a process to a registered container.
+    Add {
+        /// The ID of the container.
+        container_id: String,
+        /// PID of the process.
+        pid: i32,
+    },
 }
 
-fn load_bpf() -> anyhow::Result<Bpf> {
+fn load_bpf(path_base: &Path) -> anyhow::Result<Bpf> {
+    let pid_max = procfs::sys::kernel::pid_max()
+        .map(pid_map_size)
+        .unwrap_or(PID_MAX_DEFAULT);
+
+    let mut loader = BpfLoader::new();
+    loader.map_pin_path(path_base);
+    for map in PID_MAPS {
+        loader.set_max_entries(map, pid_max);
+    }
+
     #[cfg(debug_assertions)]
-    let bpf = BpfLoader::new()
-        .map_pin_path(PATH_BASE)
-        .load(include_bytes_aligned!(
-            "../../target/bpfel-unknown-none/debug/lockc"
-        ))?;
+    let bpf = loader.load(include_bytes_aligned!(
+        "../../target/bpfel-unknown-none/debug/lockc"
+    ))?;
     #[cfg(not(debug_assertions))]
-    let bpf = BpfLoader::new()
-        .map_pin_path(PATH_BASE)
-        .load(include_bytes_aligned!(
-            "../../target/bpfel-unknown-none/release/lockc"
-        ))?;
+    let bpf = loader.load(include_bytes_aligned!(
+        "../../target/bpfel-unknown-none/release/lockc"
+    ))?;
 
     Ok(bpf)
 }
 
-fn container_list() -> anyhow::Result<()> {
-    let bpf = load_bpf()?;
+/// Returns metadata of containers registered by lockc. Containers are listed
+/// from eBPF maps even if lockc is not running, so the metadata is optional.
+fn container_metadata<P: AsRef<Path>>(socket: P) -> StdHashMap<String, ContainerInfo> {
+    match control_request(socket, &ControlRequest::Containers) {
+        Ok(ControlResponse::Containers { containers }) => containers
+            .into_iter()
+            .map(|container| (container.id.clone(), container))
+            .collect(),
+        _ => StdHashMap::new(),
+    }
+}
+
+fn container_list<P: AsRef<Path>>(socket: P, path_base: &Path) -> anyhow::Result<()> {
+    let bpf = load_bpf(path_base)?;
+    let metadata = container_metadata(socket);
 
     let containers: HashMap<MapRef, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
+    let specs: HashMap<MapRef, ContainerID, ContainerSpec> =
+        bpf.map("CONTAINER_SPECS")?.try_into()?;
     let mut table = Vec::new();
     for res in containers.iter() {
-        let (container_id, container) = res?;
+        let (container_key, container) = res?;
+        let rootless = specs
+            .get(&container_key, 0)
+            .map(|spec| spec.rootless)
+            .unwrap_or(false);
+        let container_id = container_key.as_str()?.to_string();
+        let info = metadata.get(&container_id);
+        let field = |f: fn(&ContainerInfo) -> &Option<String>| {
+            info.and_then(|info| f(info).clone())
+                .unwrap_or_else(|| "-".to_owned())
+        };
         table.push(vec![
-            container_id.as_str()?.to_string().cell(),
+            field(|info| &info.name).cell(),
+            field(|info| &info.pod).cell(),
+            field(|info| &info.namespace).cell(),
+            info.map(|info| info.short_id())
+                .unwrap_or_else(|| control::short_id(&container_id))
+                .cell(),
+            container_id.cell(),
+            field(|info| &info.root).cell(),
             format!("{}", container.policy_level).cell(),
+            format!("{}", container.enforcement).cell(),
+            if rootless { "yes" } else { "no" }.cell(),
         ]);
     }
 
     let table = table.table().title(vec![
+        "Name".cell().bold(true),
+        "Pod".cell().bold(true),
+        "Namespace".cell().bold(true),
+        "Short ID".cell().bold(true),
         "Container ID".cell().bold(true),
+        "Root".cell().bold(true),
         "Policy Level".cell().bold(true),
+        "Enforcement".cell().bold(true),
+        "Rootless".cell().bold(true),
+    ]);
+
+    print_stdout(table)?;
+
+    Ok(())
+}
+
+fn container_resolve<P: AsRef<Path>>(socket: P, id: String) -> anyhow::Result<()> {
+    let containers = match control_request(socket, &ControlRequest::ResolveContainer { id })? {
+        ControlResponse::Containers { containers } => containers,
+        response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    };
+    if containers.is_empty() {
+        return Err(anyhow::anyhow!("no registered container matches the ID"));
+    }
+
+    let mut table = Vec::new();
+    for info in containers {
+        table.push(vec![
+            info.name.clone().unwrap_or_else(|| "-".to_owned()).cell(),
+            info.short_id().cell(),
+            info.runtime_id().cell(),
+            info.id.cell(),
+            info.root.unwrap_or_else(|| "-".to_owned()).cell(),
+        ]);
+    }
+
+    let table = table.table().title(vec![
+        "Name".cell().bold(true),
+        "Short ID".cell().bold(true),
+        "Runtime ID".cell().bold(true),
+        "Container ID".cell().bold(true),
+        "Root".cell().bold(true),
     ]);
 
     print_stdout(table)?;
@@ -93,19 +369,20 @@ fn container_list() -> anyhow::Result<()> {
 fn container_apply_policy(
     container_id: String,
     policy: ContainerPolicyLevel,
+    path_base: &Path,
 ) -> anyhow::Result<()> {
-    let bpf = load_bpf()?;
+    let bpf = load_bpf(path_base)?;
 
     let mut containers: HashMap<MapRefMut, ContainerID, Container> =
         bpf.map_mut("CONTAINERS")?.try_into()?;
 
     let key = ContainerID::from_str(&container_id)?;
-    if containers.get(&key, 0).is_err() {
-        return Err(anyhow::anyhow!("container {} not found", container_id));
-    }
-
-    let container = Container {
-        policy_level: policy,
+    let container = match containers.get(&key, 0) {
+        Ok(mut container) => {
+            container.policy_level = policy;
+            container
+        }
+        Err(_) => return Err(anyhow::anyhow!("container {} not found", container_id)),
     };
     containers.remove(&key)?;
     containers.insert(key, container, 0)?;
@@ -113,8 +390,8 @@ fn container_apply_policy(
     Ok(())
 }
 
-fn process_list() -> anyhow::Result<()> {
-    let bpf = load_bpf()?;
+fn process_list(path_base: &Path) -> anyhow::Result<()> {
+    let bpf = load_bpf(path_base)?;
 
     let processes: HashMap<MapRef, i32, Process> = bpf.map("PROCESSES")?.try_into()?;
     let containers: HashMap<MapRef, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
@@ -152,20 +429,492 @@ fn process_list() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Path prefixes looked up in the eBPF maps filled by lockc.
+struct MapPathLists {
+    prefixes: LpmTrie<MapRef, PathPrefix, FilePermission>,
+    inodes: HashMap<MapRef, InodePrefix, InodeInfo>,
+}
+
+impl MapPathLists {
+    fn load(bpf: &Bpf) -> anyhow::Result<Self> {
+        let prefixes = bpf.map("PATH_PREFIXES")?.try_into()?;
+        let inodes = bpf.map("INODE_PREFIXES")?.try_into()?;
+        Ok(MapPathLists { prefixes, inodes })
+    }
+
+    /// Returns the permission of the nearest path prefix whose inode is the
+    /// given file or one of its parents, the same way as the eBPF program.
+    /// lockctl doesn't know whether hardlinked files inherit allow
+    /// permissions in the running lockc, so the default (they don't) is
+    /// assumed.
+    fn inode_permission(&self, class: PathClass, path: &Path) -> Option<FilePermission> {
+        let mut hardlinked = false;
+        for (depth, ancestor) in path.ancestors().take(INODE_WALK_DEPTH as usize).enumerate() {
+            let metadata = match fs::metadata(ancestor) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if depth == 0 {
+                hardlinked = !metadata.is_dir() && metadata.nlink() > 1;
+            }
+            let key = InodePrefix::new(class, InodeId::from_metadata(&metadata));
+            if let Ok(info) = self.inodes.get(&key, 0) {
+                if verdict::inherits(info.permission, depth as u32, hardlinked, false) {
+                    return Some(info.permission);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl PathLists for MapPathLists {
+    fn lookup(&self, class: PathClass, path: &[u8]) -> Option<FilePermission> {
+        let path = std::str::from_utf8(path).ok()?;
+        let key = PathPrefix::key(class, path).ok()?;
+        self.prefixes.get(&key, 0).ok()
+    }
+}
+
+/// Evaluates the verdict for the given operation using the policy of the
+/// container stored in eBPF maps.
+fn check(container_id: &str, check: SubCheck, path_base: &Path) -> anyhow::Result<()> {
+    let bpf = load_bpf(path_base)?;
+
+    let containers: HashMap<MapRef, ContainerID, Container> = bpf.map("CONTAINERS")?.try_into()?;
+    let key = ContainerID::from_str(container_id)?;
+    let container = containers
+        .get(&key, 0)
+        .map_err(|_| anyhow::anyhow!("container {} not found", container_id))?;
+    let policy_level = container.policy_level;
+    let path_lists = MapPathLists::load(&bpf)?;
+    let userns_override = matches!(check, SubCheck::Mount { .. } | SubCheck::Setuid { .. });
+    let syslog_exempt = matches!(check, SubCheck::Syslog) && container.syslog;
+
+    let verdict = match check {
+        SubCheck::Mount { source, mount_type } => {
+            verdict::mount(&path_lists, policy_level, &mount_type, &source)
+        }
+        SubCheck::Open { path } => {
+            let inode_permission = PathList::Access
+                .class(policy_level)
+                .and_then(|class| path_lists.inode_permission(class, Path::new(&path)));
+            if let Some(permission) = inode_permission {
+                match permission {
+                    FilePermission::Allow => Verdict::Allow,
+                    FilePermission::Deny => Verdict::Deny,
+                }
+            } else if path.len() >= PATH_LEN {
+                // Paths longer than the buffer used by the eBPF program are
+                // checked only by inodes.
+                println!(
+                    "Note: paths longer than {} bytes are not checked",
+                    PATH_LEN - 1
+                );
+                Verdict::Allow
+            } else {
+                verdict::file_open(&path_lists, policy_level, &path)
+            }
+        }
+        SubCheck::Setuid { uid } => {
+            let initial_setuid: HashMap<MapRef, ContainerID, bool> =
+                bpf.map("CONTAINER_INITIAL_SETUID")?.try_into()?;
+            let initial_setuid = initial_setuid.get(&key, 0).unwrap_or(false);
+            verdict::setuid(policy_level, initial_setuid, uid)
+        }
+        SubCheck::Syslog if syslog_exempt => Verdict::Allow,
+        SubCheck::Syslog => verdict::syslog(policy_level),
+        SubCheck::Socket => verdict::socket(policy_level),
+    };
+
+    println!("Policy level: {}", policy_level);
+    if userns_override {
+        let specs: HashMap<MapRef, ContainerID, ContainerSpec> =
+            bpf.map("CONTAINER_SPECS")?.try_into()?;
+        if specs
+            .get(&key, 0)
+            .map(|spec| spec.rootless)
+            .unwrap_or(false)
+        {
+            println!("Note: rootless container, user_namespaces overrides are not considered");
+        }
+    }
+    if syslog_exempt {
+        println!("Note: the container is exempted from the syslog denial");
+    }
+    println!("Verdict: {}", verdict);
+
+    Ok(())
+}
+
+/// Sends a request to the lockc control API and returns the response.
+fn control_request<P: AsRef<Path>>(
+    socket: P,
+    request: &ControlRequest,
+) -> anyhow::Result<ControlResponse> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut buf = serde_json::to_vec(request)?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    match serde_json::from_str(&line)? {
+        ControlResponse::Error { message } => Err(anyhow::anyhow!(message)),
+        response => Ok(response),
+    }
+}
+
+/// Sends a request which changes the state of lockc and expects no data
+/// in the response.
+fn control_command<P: AsRef<Path>>(socket: P, request: &ControlRequest) -> anyhow::Result<()> {
+    match control_request(socket, request)? {
+        ControlResponse::Ok => Ok(()),
+        response => Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    }
+}
+
+fn log_filter<P: AsRef<Path>>(socket: P, filter: Option<String>) -> anyhow::Result<()> {
+    let request = match filter {
+        Some(filter) => ControlRequest::SetLogFilter { filter },
+        None => ControlRequest::LogFilter,
+    };
+    match control_request(socket, &request)? {
+        ControlResponse::LogFilter { filter } => println!("{}", filter),
+        response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    }
+    Ok(())
+}
+
+fn trace<P: AsRef<Path>>(socket: P, container_id: Option<String>) -> anyhow::Result<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    let mut buf = serde_json::to_vec(&ControlRequest::Trace { container_id })?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+
+    for line in BufReader::new(stream).lines() {
+        match serde_json::from_str(&line?)? {
+            ControlResponse::ProcessEvent(event) => println!(
+                "{:<5} {} pid={} ppid={} comm={}",
+                event.kind, event.container_id, event.pid, event.ppid, event.comm
+            ),
+            ControlResponse::Error { message } => return Err(anyhow::anyhow!(message)),
+            response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints an allowed paths list in the TOML format of lockc settings, with
+/// learned mounts under each path as comments.
+fn print_allowed_paths(
+    name: &str,
+    mounts: &[LearnedMountInfo],
+    policy_level: ContainerPolicyLevel,
+) -> anyhow::Result<()> {
+    let paths = control::suggested_paths(mounts, policy_level);
+    if paths.is_empty() {
+        return Ok(());
+    }
+    println!("{} = [", name);
+    for path in paths {
+        for mount in mounts.iter().filter(|mount| {
+            mount.policy_level == policy_level
+                && (path == "/"
+                    || mount.path.trim_end_matches('/') == path
+                    || mount.path.starts_with(&format!("{}/", path)))
+        }) {
+            println!(
+                "    # {}: {} attempts in {} containers",
+                mount.path,
+                mount.count,
+                mount.containers.len()
+            );
+        }
+        println!("    {},", serde_json::to_string(&path)?);
+    }
+    println!("]");
+    Ok(())
+}
+
+fn learn_export<P: AsRef<Path>>(socket: P) -> anyhow::Result<()> {
+    let mounts = match control_request(socket, &ControlRequest::LearnedMounts)? {
+        ControlResponse::LearnedMounts { mounts } => mounts,
+        response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    };
+    if mounts.is_empty() {
+        println!("# No bind mounts were recorded in learning mode");
+        return Ok(());
+    }
+
+    println!("[allowed_paths]");
+    print_allowed_paths(
+        "mount_restricted",
+        &mounts,
+        ContainerPolicyLevel::Restricted,
+    )?;
+    print_allowed_paths("mount_baseline", &mounts, ContainerPolicyLevel::Baseline)?;
+
+    Ok(())
+}
+
+fn fingerprints<P: AsRef<Path>>(
+    socket: P,
+    image: Option<String>,
+) -> anyhow::Result<Vec<ImageFingerprintInfo>> {
+    match control_request(socket, &ControlRequest::Fingerprints { image })? {
+        ControlResponse::Fingerprints { images } => Ok(images),
+        response => Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    }
+}
+
+fn fingerprint_list<P: AsRef<Path>>(socket: P, image: Option<String>) -> anyhow::Result<()> {
+    let table = fingerprints(socket, image)?
+        .into_iter()
+        .map(|fingerprint| {
+            let capabilities = fingerprint
+                .capabilities
+                .iter()
+                .map(|capability| capability.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let violations: u64 = fingerprint
+                .violations
+                .iter()
+                .map(|violation| violation.count)
+                .sum();
+            vec![
+                fingerprint.image.cell(),
+                fingerprint.containers.cell(),
+                fingerprint.mounts.len().cell(),
+                fingerprint.file_opens.len().cell(),
+                capabilities.cell(),
+                violations.cell(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Image".cell().bold(true),
+            "Containers".cell().bold(true),
+            "Mounts".cell().bold(true),
+            "File Opens".cell().bold(true),
+            "Capabilities".cell().bold(true),
+            "Violations".cell().bold(true),
+        ]);
+
+    print_stdout(table)?;
+
+    Ok(())
+}
+
+/// Prints an image policy rule in the TOML format of lockc settings for each
+/// fingerprint, with the observations behind the suggested level as
+/// comments.
+fn fingerprint_suggest<P: AsRef<Path>>(socket: P, image: Option<String>) -> anyhow::Result<()> {
+    let images = fingerprints(socket, image)?;
+    if images.is_empty() {
+        println!("# No fingerprints of images were recorded");
+        return Ok(());
+    }
+
+    for (i, fingerprint) in images.iter().enumerate() {
+        let suggestion = control::suggested_policy(fingerprint);
+        if i > 0 {
+            println!();
+        }
+        println!(
+            "# {}: {} containers observed",
+            fingerprint.image, fingerprint.containers
+        );
+        for reason in &suggestion.reasons {
+            println!("# - {}", reason);
+        }
+        println!("[[image_policies]]");
+        println!("image = '{}'", control::image_pattern(&fingerprint.image));
+        println!("policy = \"{}\"", suggestion.policy_level);
+    }
+
+    Ok(())
+}
+
+fn digests<P: AsRef<Path>>(socket: P) -> anyhow::Result<()> {
+    let digests = match control_request(socket, &ControlRequest::Digests)? {
+        ControlResponse::Digests(digests) => digests,
+        response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    };
+
+    println!("Object: {}", digests.object);
+    println!(
+        "Path: {}",
+        digests.path.as_deref().unwrap_or("embedded in lockc")
+    );
+    println!("Signature verified: {}", digests.verified);
+
+    let table = digests
+        .programs
+        .into_iter()
+        .map(|program| vec![program.section.cell(), program.sha256.cell()])
+        .table()
+        .title(vec![
+            "Section".cell().bold(true),
+            "SHA-256".cell().bold(true),
+        ]);
+
+    print_stdout(table)?;
+
+    Ok(())
+}
+
+fn history<P: AsRef<Path>>(socket: P, container_id: Option<String>) -> anyhow::Result<()> {
+    let changes = match control_request(socket, &ControlRequest::PolicyHistory { container_id })? {
+        ControlResponse::PolicyHistory { changes } => changes,
+        response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    };
+
+    let table = changes
+        .into_iter()
+        .map(|change| {
+            let old = match (change.old_policy_level, change.old_enforcement) {
+                (Some(policy_level), Some(enforcement)) => {
+                    format!("{} ({})", policy_level, enforcement)
+                }
+                _ => "-".to_owned(),
+            };
+            vec![
+                change.time.cell(),
+                change.container_id.cell(),
+                old.cell(),
+                format!("{} ({})", change.policy_level, change.enforcement).cell(),
+                change.source.to_string().cell(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Time".cell().bold(true),
+            "Container ID".cell().bold(true),
+            "Old Policy".cell().bold(true),
+            "New Policy".cell().bold(true),
+            "Source".cell().bold(true),
+        ]);
+
+    print_stdout(table)?;
+
+    Ok(())
+}
+
+fn stats<P: AsRef<Path>>(socket: P) -> anyhow::Result<()> {
+    let programs = match control_request(socket, &ControlRequest::Stats)? {
+        ControlResponse::Stats { programs } => programs,
+        response => return Err(anyhow::anyhow!("unexpected response: {:?}", response)),
+    };
+
+    let table = programs
+        .into_iter()
+        .map(|program| {
+            vec![
+                program.program.cell(),
+                if program.loaded { "yes" } else { "no" }.cell(),
+                program.invocations.cell(),
+                program.allows.cell(),
+                program.denies.cell(),
+            ]
+        })
+        .table()
+        .title(vec![
+            "Program".cell().bold(true),
+            "Loaded".cell().bold(true),
+            "Invocations".cell().bold(true),
+            "Allows".cell().bold(true),
+            "Denies".cell().bold(true),
+        ]);
+
+    print_stdout(table)?;
+
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     match args.subcommand {
         Sub::Container { container } => match container {
-            SubContainer::List => container_list()?,
+            SubContainer::List => container_list(&args.socket, &args.bpf_pin_path)?,
+            SubContainer::Resolve { id } => container_resolve(&args.socket, id)?,
             SubContainer::ApplyPolicy {
                 container_id,
                 policy,
-            } => container_apply_policy(container_id, policy)?,
+            } => container_apply_policy(container_id, policy, &args.bpf_pin_path)?,
+            SubContainer::Enforcement {
+                container_id,
+                enforcement,
+            } => control_command(
+                &args.socket,
+                &ControlRequest::SetEnforcement {
+                    container_id,
+                    enforcement,
+                },
+            )?,
+            SubContainer::Add {
+                container_id,
+                pid,
+                policy,
+                enforcement,
+                bundle,
+                name,
+                pod,
+                namespace,
+                image,
+                allow_syslog,
+                replace,
+            } => control_command(
+                &args.socket,
+                &ControlRequest::AddContainer {
+                    container_id,
+                    pid,
+                    policy_level: policy,
+                    enforcement,
+                    syslog: allow_syslog,
+                    bundle,
+                    name,
+                    pod,
+                    namespace,
+                    image,
+                    replace,
+                },
+            )?,
+            SubContainer::Delete { container_id } => control_command(
+                &args.socket,
+                &ControlRequest::DeleteContainer { container_id },
+            )?,
+            SubContainer::Thaw { container_id } => control_command(
+                &args.socket,
+                &ControlRequest::ThawContainer { container_id },
+            )?,
         },
         Sub::Process { process } => match process {
-            SubProcess::List => process_list()?,
+            SubProcess::List => process_list(&args.bpf_pin_path)?,
+            SubProcess::Add { container_id, pid } => control_command(
+                &args.socket,
+                &ControlRequest::AddProcess { container_id, pid },
+            )?,
+        },
+        Sub::Digests => digests(&args.socket)?,
+        Sub::Stats => stats(&args.socket)?,
+        Sub::LogFilter { filter } => log_filter(&args.socket, filter)?,
+        Sub::Trace { container } => trace(&args.socket, container)?,
+        Sub::History { container } => history(&args.socket, container)?,
+        Sub::Learn { learn } => match learn {
+            SubLearn::Export => learn_export(&args.socket)?,
+        },
+        Sub::Fingerprint { fingerprint } => match fingerprint {
+            SubFingerprint::List { image } => fingerprint_list(&args.socket, image)?,
+            SubFingerprint::Suggest { image } => fingerprint_suggest(&args.socket, image)?,
         },
+        Sub::Check { container, check } => {
+            let container = container.ok_or_else(|| anyhow::anyhow!("--container is required"))?;
+            self::check(&container, check, &args.bpf_pin_path)?
+        }
     }
 
     Ok(())
@@ -0,0 +1,251 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Number of processes spawned in the fork/exec benchmark
+    #[structopt(default_value = "1000", long, parse(try_from_str = parse_iterations))]
+    pub fork_iterations: usize,
+    /// Command starting a container, e.g. "docker run --rm busybox true".
+    /// The container start benchmark is skipped when not set
+    #[structopt(long)]
+    pub container_command: Option<String>,
+    /// Number of containers started in the container start benchmark
+    #[structopt(default_value = "20", long, parse(try_from_str = parse_iterations))]
+    pub container_iterations: usize,
+    /// Skip the benchmarks of the decision logic of policies
+    #[structopt(long)]
+    pub no_micro: bool,
+    /// File to write the results to
+    #[structopt(default_value = "bench.json", long)]
+    pub output: PathBuf,
+    /// Results of a previous run to compare with
+    #[structopt(long)]
+    pub baseline: Option<PathBuf>,
+    /// Allowed increase of the mean time compared to the baseline, in percent
+    #[structopt(default_value = "10", long)]
+    pub threshold: f64,
+}
+
+/// Parses a number of iterations, which has to be positive for the results to
+/// make sense.
+fn parse_iterations(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>().map_err(|e| e.to_string())? {
+        0 => Err("the number of iterations has to be greater than 0".to_string()),
+        n => Ok(n),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BenchResult {
+    pub name: String,
+    pub iterations: usize,
+    pub mean_ns: u64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+    pub min_ns: u64,
+    pub max_ns: u64,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Report {
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchResult {
+    fn from_samples(name: &str, mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let ns = |d: &Duration| d.as_nanos() as u64;
+        let len = samples.len();
+        BenchResult {
+            name: name.to_string(),
+            iterations: len,
+            mean_ns: samples.iter().map(ns).sum::<u64>() / len as u64,
+            p50_ns: ns(&samples[len / 2]),
+            p99_ns: ns(&samples[len * 99 / 100]),
+            min_ns: ns(&samples[0]),
+            max_ns: ns(&samples[len - 1]),
+        }
+    }
+}
+
+/// Measures the given command executed `iterations` times.
+fn bench_command(name: &str, args: &[&str], iterations: usize) -> anyhow::Result<BenchResult> {
+    let (program, args) = args.split_first().context("empty command")?;
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = Instant::now();
+        let status = Command::new(program)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| format!("failed to run {}", program))?;
+        samples.push(start.elapsed());
+        if !status.success() {
+            anyhow::bail!("{} failed with {}", program, status);
+        }
+    }
+    Ok(BenchResult::from_samples(name, samples))
+}
+
+/// Criterion benchmark of the decision logic of policies in lockc-common.
+const MICRO_BENCH: &str = "verdict_linear_scan";
+
+/// Returns the directory where criterion stores the samples of `MICRO_BENCH`.
+/// It's in the target directory of the workspace, which `cargo bench` uses
+/// regardless of the directory xtask runs in.
+fn criterion_dir() -> PathBuf {
+    let target_dir = match env::var_os("CARGO_TARGET_DIR") {
+        Some(target_dir) => PathBuf::from(target_dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("..")
+            .join("target"),
+    };
+    target_dir.join("criterion").join(MICRO_BENCH)
+}
+
+/// Samples of a benchmark stored by criterion. Each sample measures the total
+/// time of the given number of iterations.
+#[derive(Deserialize)]
+struct CriterionSamples {
+    iters: Vec<f64>,
+    times: Vec<f64>,
+}
+
+/// Reads the samples which criterion stored for the benchmark in `dir` and
+/// converts them to the time of a single iteration.
+fn read_criterion_samples(name: &str, dir: &Path) -> anyhow::Result<BenchResult> {
+    let path = dir.join("new").join("sample.json");
+    let samples: CriterionSamples = serde_json::from_str(
+        &fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?,
+    )
+    .with_context(|| format!("invalid benchmark samples in {}", path.display()))?;
+    if samples.times.is_empty() {
+        anyhow::bail!("no benchmark samples in {}", path.display());
+    }
+    let samples = samples
+        .iters
+        .iter()
+        .zip(&samples.times)
+        .map(|(iters, time)| Duration::from_nanos((time / iters) as u64))
+        .collect();
+    Ok(BenchResult::from_samples(name, samples))
+}
+
+/// Runs criterion benchmarks of the decision logic of policies from
+/// lockc-common and collects their samples.
+fn bench_micro() -> anyhow::Result<Vec<BenchResult>> {
+    let criterion_dir = criterion_dir();
+    // Don't pick up benchmarks which were removed since the previous run.
+    match fs::remove_dir_all(&criterion_dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let status = Command::new("cargo")
+        .args([
+            "bench",
+            "--package",
+            "lockc-common",
+            "--features",
+            "user",
+            "--bench",
+            MICRO_BENCH,
+        ])
+        .status()
+        .context("failed to run cargo bench")?;
+    if !status.success() {
+        anyhow::bail!("cargo bench failed with {}", status);
+    }
+
+    let mut results = Vec::new();
+    for entry in fs::read_dir(&criterion_dir)
+        .with_context(|| format!("failed to read {}", criterion_dir.display()))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // criterion keeps a summary report next to the benchmarks.
+        if name == "report" {
+            continue;
+        }
+        results.push(read_criterion_samples(
+            &format!("{}_{}", MICRO_BENCH, name),
+            &entry.path(),
+        )?);
+    }
+    results.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(results)
+}
+
+/// Returns descriptions of results which are slower than in the baseline by
+/// more than `threshold` percent.
+fn regressions(report: &Report, baseline: &Report, threshold: f64) -> Vec<String> {
+    report
+        .results
+        .iter()
+        .filter_map(|result| {
+            let base = baseline.results.iter().find(|b| b.name == result.name)?;
+            let change =
+                (result.mean_ns as f64 - base.mean_ns as f64) / base.mean_ns as f64 * 100.0;
+            (change > threshold).then(|| {
+                format!(
+                    "{}: {} ns -> {} ns (+{:.1}%)",
+                    result.name, base.mean_ns, result.mean_ns, change
+                )
+            })
+        })
+        .collect()
+}
+
+/// Measures the overhead lockc adds to container starts (fanotify permission
+/// round-trip, policy resolution, eBPF map updates) and to fork-heavy
+/// workloads (process tracking programs). Run it once without lockc and once
+/// with lockc running, passing the first result as `--baseline`.
+pub fn bench(opts: Options) -> Result<(), anyhow::Error> {
+    let mut report = Report::default();
+
+    if !opts.no_micro {
+        report.results.extend(bench_micro()?);
+    }
+    report.results.push(bench_command(
+        "fork_exec",
+        &["/bin/true"],
+        opts.fork_iterations,
+    )?);
+    if let Some(container_command) = &opts.container_command {
+        let args: Vec<_> = container_command.split_whitespace().collect();
+        report.results.push(bench_command(
+            "container_start",
+            &args,
+            opts.container_iterations,
+        )?);
+    }
+
+    for result in &report.results {
+        println!(
+            "{:<40} mean {:>12} ns  p50 {:>12} ns  p99 {:>12} ns",
+            result.name, result.mean_ns, result.p50_ns, result.p99_ns
+        );
+    }
+    fs::write(&opts.output, serde_json::to_string_pretty(&report)?)?;
+
+    if let Some(baseline) = &opts.baseline {
+        let baseline: Report = serde_json::from_str(&fs::read_to_string(baseline)?)?;
+        let regressions = regressions(&report, &baseline, opts.threshold);
+        if !regressions.is_empty() {
+            anyhow::bail!("performance regressions:\n{}", regressions.join("\n"));
+        }
+    }
+
+    Ok(())
+}
@@ -1,12 +1,19 @@
-use std::{fs::File, path::Path};
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    process::Command,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use flate2::{write::GzEncoder, Compression};
+use ring::digest::{digest, SHA256};
 use scopeguard::guard;
 use structopt::StructOpt;
 use tempfile::tempdir;
 
-use crate::install;
+use crate::{install, package};
 
 #[derive(StructOpt)]
 pub struct Options {
@@ -21,8 +28,59 @@ pub struct Options {
     bindir: String,
     #[structopt(default_value = "etc", long)]
     sysconfdir: String,
+    #[structopt(default_value = "lib", long)]
+    libdir: String,
     #[structopt(default_value = "lib/systemd/system", long)]
     unitdir: String,
+
+    /// Target triple to cross-compile for (e.g. `x86_64-unknown-linux-musl`,
+    /// `aarch64-unknown-linux-musl`), can be repeated. A tarball is created
+    /// for each target. Binaries already built for the host are packaged
+    /// when not set. The eBPF object has to be built beforehand with
+    /// `build-ebpf`.
+    #[structopt(long)]
+    target: Vec<String>,
+
+    /// Build lockc without default features, e.g. without Kubernetes support
+    /// for hosts running only Docker. Used only with `--target`.
+    #[structopt(long)]
+    no_default_features: bool,
+}
+
+/// Appends the files of the directory in a sorted order. Together with the
+/// deterministic header mode, the same files always result in the same
+/// tarball.
+fn append_sorted<W: Write>(tar: &mut tar::Builder<W>, root: &Path, dir: &Path) -> Result<()> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    for path in paths {
+        let name = path.strip_prefix(root)?;
+        if path.is_dir() {
+            tar.append_dir(name, &path)?;
+            append_sorted(tar, root, &path)?;
+        } else {
+            tar.append_path_with_name(&path, name)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the SHA-256 checksum of the file next to it, in the format of
+/// `sha256sum`.
+fn write_checksum(path: &Path) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid path {}", path.display()))?;
+    let checksum = hex::encode(digest(&SHA256, &fs::read(path)?));
+    let mut checksum_path = path.as_os_str().to_owned();
+    checksum_path.push(".sha256");
+    fs::write(
+        checksum_path,
+        format!("{}  {}\n", checksum, file_name.to_string_lossy()),
+    )?;
+    Ok(())
 }
 
 pub struct BinTar {
@@ -34,7 +92,47 @@ impl BinTar {
         BinTar { opts }
     }
 
-    pub fn do_bin_tar(&self) -> Result<()> {
+    /// Builds the binaries for the given target. musl targets are linked
+    /// statically, so the binaries work on hosts without glibc.
+    fn build(&self, target: &str) -> Result<()> {
+        let mut cmd = Command::new("cargo");
+        cmd.args(["build", "--target", target]);
+        for package in ["lockc", "lockctl", "lockc-collector"] {
+            cmd.args(["--package", package]);
+        }
+        if self.opts.no_default_features {
+            cmd.arg("--no-default-features");
+        }
+        match self.opts.profile.as_str() {
+            "debug" => {}
+            "release" => {
+                cmd.arg("--release");
+            }
+            profile => {
+                cmd.args(["--profile", profile]);
+            }
+        }
+
+        // Strip the path of the source tree from binaries, so they don't
+        // depend on where they were built.
+        let mut rustflags = env::var("RUSTFLAGS").unwrap_or_default();
+        rustflags.push_str(&format!(
+            " --remap-path-prefix={}=.",
+            env::current_dir()?.display()
+        ));
+        if target.ends_with("-musl") {
+            rustflags.push_str(" -C target-feature=+crt-static");
+        }
+        cmd.env("RUSTFLAGS", rustflags.trim());
+
+        let status = cmd.status()?;
+        if !status.success() {
+            return Err(anyhow!("could not build lockc for {}", target));
+        }
+        Ok(())
+    }
+
+    fn bin_tar(&self, target: Option<&str>, tar_gz_path: &Path) -> Result<()> {
         let dir = guard(tempdir()?, |d| {
             // Ensure the dir is deleted.
             d.close().unwrap();
@@ -45,19 +143,42 @@ impl BinTar {
             prefix: self.opts.prefix.clone(),
             bindir: self.opts.bindir.clone(),
             sysconfdir: self.opts.sysconfdir.clone(),
+            libdir: self.opts.libdir.clone(),
             unitdir: self.opts.unitdir.clone(),
+            target: target.map(|target| target.to_owned()),
         })
         .do_install()?;
 
-        let tar_gz_path = Path::new("target")
-            .join(&self.opts.profile)
-            .join("lockc.tar.gz");
-        let tar_gz = File::create(&tar_gz_path)?;
+        let tar_gz = File::create(tar_gz_path)?;
+        // The gzip header has no timestamp and file name by default.
         let enc = GzEncoder::new(tar_gz, Compression::default());
         let mut tar = tar::Builder::new(enc);
-        tar.append_dir_all("", dir.path())?;
+        tar.mode(tar::HeaderMode::Deterministic);
+        append_sorted(&mut tar, dir.path(), dir.path())?;
+        tar.into_inner()?.finish()?;
+        write_checksum(tar_gz_path)?;
 
         println!("Tarball created: {}", tar_gz_path.display());
         Ok(())
     }
+
+    pub fn do_bin_tar(&self) -> Result<()> {
+        if self.opts.target.is_empty() {
+            let tar_gz_path = Path::new("target")
+                .join(&self.opts.profile)
+                .join("lockc.tar.gz");
+            return self.bin_tar(None, &tar_gz_path);
+        }
+
+        let version = package::lockc_version()?;
+        for target in &self.opts.target {
+            self.build(target)?;
+            let tar_gz_path = Path::new("target")
+                .join(target)
+                .join(&self.opts.profile)
+                .join(format!("lockc-{}-{}.tar.gz", version, target));
+            self.bin_tar(Some(target), &tar_gz_path)?;
+        }
+        Ok(())
+    }
 }
@@ -1,6 +1,16 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
+use anyhow::{anyhow, Context as _};
+use aya::{
+    programs::{Program, ProgramError},
+    BpfLoader, Btf,
+};
+use ring::{
+    digest::{digest, SHA256},
+    signature::Ed25519KeyPair,
+};
 use structopt::StructOpt;
 
 #[derive(Debug, Copy, Clone)]
@@ -21,6 +31,25 @@ impl std::str::FromStr for Architecture {
     }
 }
 
+impl Architecture {
+    /// Returns the BPF architecture with the endianness of the given target
+    /// triple, e.g. `aarch64-unknown-linux-musl`.
+    pub fn for_target(target: &str) -> Architecture {
+        let arch = target.split('-').next().unwrap_or_default();
+        let big_endian = arch.ends_with("_be")
+            || arch.starts_with("armeb")
+            || matches!(
+                arch,
+                "mips" | "mips64" | "powerpc" | "powerpc64" | "s390x" | "sparc64"
+            );
+        if big_endian {
+            Architecture::BpfEb
+        } else {
+            Architecture::BpfEl
+        }
+    }
+}
+
 impl std::fmt::Display for Architecture {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(match self {
@@ -38,6 +67,101 @@ pub struct Options {
     /// Build the release target
     #[structopt(long)]
     pub release: bool,
+    /// Ed25519 private key (PKCS#8, DER) used to sign the BPF object
+    #[structopt(long)]
+    pub sign_key: Option<PathBuf>,
+    /// Load the programs into the kernel, without attaching them, to check
+    /// them with the verifier. Requires root and a kernel with BTF
+    #[structopt(long)]
+    pub verify: bool,
+}
+
+/// Records the SHA-256 digest of the BPF object and, if a key is given, its
+/// Ed25519 signature. Both files are embedded in lockc, which verifies the
+/// object before loading it. Without a key, the signature file is empty.
+fn sign_ebpf(obj_path: &Path, sign_key: Option<&Path>) -> Result<(), anyhow::Error> {
+    let obj = fs::read(obj_path)?;
+    fs::write(
+        obj_path.with_extension("sha256"),
+        hex::encode(digest(&SHA256, &obj)),
+    )?;
+
+    let signature = match sign_key {
+        Some(sign_key) => {
+            let pkcs8 = fs::read(sign_key)?;
+            let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+                .map_err(|e| anyhow!("invalid signing key {}: {}", sign_key.display(), e))?;
+            key_pair.sign(&obj).as_ref().to_vec()
+        }
+        None => Vec::new(),
+    };
+    fs::write(obj_path.with_extension("sig"), signature)?;
+
+    Ok(())
+}
+
+/// Directory in bpffs under which maps of the verified object get pinned.
+const BPFFS_PATH: &str = "/sys/fs/bpf";
+
+/// LSM programs attached to a hook with a different name, because another
+/// program of the hook has its name.
+const LSM_HOOKS: &[(&str, &str)] = &[("sb_mount_proc", "sb_mount")];
+
+/// Loads all programs of the BPF object into the kernel without attaching
+/// them, so the verifier checks them the same way as when lockc starts.
+/// Verifier logs of rejected programs are printed.
+fn verify_ebpf(obj_path: &Path) -> Result<(), anyhow::Error> {
+    let data = fs::read(obj_path)?;
+    // Pinned maps must not clash with maps of a running lockc. The directory
+    // and the pins are removed when it's dropped.
+    let pin_dir = tempfile::Builder::new()
+        .prefix("lockc-verify-")
+        .tempdir_in(BPFFS_PATH)
+        .with_context(|| format!("failed to create a directory in {}", BPFFS_PATH))?;
+    let mut bpf = BpfLoader::new()
+        .map_pin_path(pin_dir.path())
+        .load(&data)
+        .context("failed to load the BPF object")?;
+    let btf = Btf::from_sys_fs().context("failed to read BTF of the kernel")?;
+
+    let mut failed = 0;
+    for (name, program) in bpf.programs_mut() {
+        let res = match program {
+            Program::Lsm(program) => {
+                let hook = LSM_HOOKS
+                    .iter()
+                    .find(|(program_name, _)| *program_name == name)
+                    .map(|(_, hook)| *hook)
+                    .unwrap_or(name);
+                program.load(hook, &btf)
+            }
+            Program::BtfTracePoint(program) => program.load(name, &btf),
+            Program::CgroupSockAddr(program) => program.load(),
+            _ => {
+                println!("{}: skipped, unsupported program type", name);
+                continue;
+            }
+        };
+        match res {
+            Ok(()) => println!("{}: ok", name),
+            Err(ProgramError::LoadError {
+                io_error,
+                verifier_log,
+            }) => {
+                failed += 1;
+                eprintln!("{}: rejected: {}\n{}", name, io_error, verifier_log);
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} programs failed verification", failed);
+    }
+
+    Ok(())
 }
 
 pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
@@ -58,7 +182,19 @@ pub fn build_ebpf(opts: Options) -> Result<(), anyhow::Error> {
         .current_dir(&dir)
         .args(&args)
         .status()
-        .expect("failed to build bpf program");
-    assert!(status.success());
+        .context("failed to run cargo")?;
+    if !status.success() {
+        anyhow::bail!("building the BPF program failed with {}", status);
+    }
+
+    let obj_path = PathBuf::from("target")
+        .join(opts.target.to_string())
+        .join(if opts.release { "release" } else { "debug" })
+        .join("lockc");
+    sign_ebpf(&obj_path, opts.sign_key.as_deref())?;
+    if opts.verify {
+        verify_ebpf(&obj_path)?;
+    }
+
     Ok(())
 }
@@ -1,15 +1,26 @@
 use std::{fs::File, io::Write, path::PathBuf};
 
+use anyhow::Context as _;
 use aya_tool::generate::InputFile;
+use structopt::StructOpt;
 
-pub fn generate() -> Result<(), anyhow::Error> {
+#[derive(StructOpt)]
+pub struct Options {
+    /// BTF of the kernel to generate the bindings from. A BTF file of
+    /// another kernel (e.g. from BTFHub) makes the bindings reproducible
+    /// regardless of the kernel of the build host
+    #[structopt(long, default_value = "/sys/kernel/btf/vmlinux")]
+    pub btf: PathBuf,
+}
+
+pub fn generate(opts: Options) -> Result<(), anyhow::Error> {
     let dir = PathBuf::from("lockc-ebpf/src");
     let names: Vec<&str> = vec!["cred", "file", "sock", "sock_common", "task_struct"];
-    let bindings = aya_tool::generate(
-        InputFile::Btf(PathBuf::from("/sys/kernel/btf/vmlinux")),
-        &names,
-        &[],
-    )?;
+    if !opts.btf.is_file() {
+        anyhow::bail!("BTF file {} not found", opts.btf.display());
+    }
+    let bindings = aya_tool::generate(InputFile::Btf(opts.btf.clone()), &names, &[])
+        .with_context(|| format!("failed to generate bindings from {}", opts.btf.display()))?;
     // Write the bindings to the $OUT_DIR/bindings.rs file.
     let mut out = File::create(dir.join("vmlinux.rs"))?;
     write!(out, "{}", bindings)?;
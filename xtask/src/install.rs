@@ -11,6 +11,8 @@ use structopt::StructOpt;
 use tera::{Context, Tera};
 use thiserror::Error;
 
+use crate::build_ebpf::Architecture;
+
 #[derive(Error, Debug)]
 enum EscalateIfNotOwnedError {
     #[error(transparent)]
@@ -59,8 +61,15 @@ pub(crate) struct Options {
     pub(crate) bindir: String,
     #[structopt(default_value = "etc", long)]
     pub(crate) sysconfdir: String,
+    #[structopt(default_value = "lib", long)]
+    pub(crate) libdir: String,
     #[structopt(default_value = "lib/systemd/system", long)]
     pub(crate) unitdir: String,
+
+    /// Target triple the binaries were cross-compiled for. Binaries built
+    /// for the host are installed when not set.
+    #[structopt(long)]
+    pub(crate) target: Option<String>,
 }
 
 impl Options {
@@ -107,6 +116,20 @@ impl Options {
         path::Path::new(&self.destdir).join(&self.sysconfdir)
     }
 
+    /// Returns a libdir path (prefix + libdir).
+    /// Should be used for templating configuration and unit files.
+    fn libdir(&self) -> path::PathBuf {
+        path::Path::new("/").join(&self.prefix).join(&self.libdir)
+    }
+
+    /// Returns a full libdir path (destdir + prefix + libdir).
+    /// Should be used as an installation target for files.
+    fn full_libdir(&self) -> path::PathBuf {
+        path::Path::new(&self.destdir)
+            .join(&self.prefix)
+            .join(&self.libdir)
+    }
+
     /// Returns an unitdir path (prefix + unitdir).
     /// Should be used for templating configuration and unit files.
     fn unitdir(&self) -> path::PathBuf {
@@ -144,6 +167,12 @@ struct InstallDirs {
     /// Full sysconfdir path.
     /// Should be used as an installation target for files.
     sysconfdir_full: path::PathBuf,
+    /// Libdir path (prefix + libdir).
+    /// Should be used for templating configuration and unit files.
+    libdir: path::PathBuf,
+    /// Full libdir path (destdir + prefix + libdir).
+    /// Should be used as an installation target for files.
+    libdir_full: path::PathBuf,
     /// Unitdir path.
     /// Should be used for templating configuration and unit files.
     unitdir: path::PathBuf,
@@ -169,6 +198,18 @@ enum InstallBinariesError {
     NotBuilt,
 }
 
+#[derive(Error, Debug)]
+enum InstallBpfObjectError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+
+    #[error(transparent)]
+    EscalateIfNotOwned(#[from] EscalateIfNotOwnedError),
+
+    #[error("the eBPF object is not built (with the requested profile)")]
+    NotBuilt,
+}
+
 #[derive(Error, Debug)]
 enum InstallConfigError {
     #[error(transparent)]
@@ -207,6 +248,8 @@ impl Installer {
                 bindir_full: opts.full_bindir(),
                 sysconfdir: opts.sysconfdir(),
                 sysconfdir_full: opts.full_sysconfdir(),
+                libdir: opts.libdir(),
+                libdir_full: opts.full_libdir(),
                 unitdir: opts.unitdir(),
                 unitdir_full: opts.full_unitdir(),
             },
@@ -220,7 +263,12 @@ impl Installer {
         mkdir_if_not_exists(bindir_full)?;
         escalate_if_not_owned(bindir_full)?;
 
-        let target_path = path::Path::new("target").join(&self.opts.profile);
+        let target_path = match &self.opts.target {
+            Some(target) => path::Path::new("target")
+                .join(target)
+                .join(&self.opts.profile),
+            None => path::Path::new("target").join(&self.opts.profile),
+        };
         if !target_path.exists() {
             return Err(InstallBinariesError::NotBuilt);
         }
@@ -255,6 +303,40 @@ impl Installer {
         Ok(())
     }
 
+    /// Installs the eBPF object, together with its digest and signature, to
+    /// `libdir/lockc/lockc.bpf.o`. The object built for the endianness of the
+    /// target is used.
+    fn install_bpf_object(&self) -> Result<(), InstallBpfObjectError> {
+        let dest_dir = self.install_dirs.libdir_full.join("lockc");
+
+        mkdir_if_not_exists(&dest_dir)?;
+        escalate_if_not_owned(&dest_dir)?;
+
+        let bpf_target = self
+            .opts
+            .target
+            .as_deref()
+            .map(Architecture::for_target)
+            .unwrap_or(Architecture::BpfEl);
+        let target_path = path::Path::new("target")
+            .join(bpf_target.to_string())
+            .join(&self.opts.profile);
+        if !target_path.join("lockc").exists() {
+            return Err(InstallBpfObjectError::NotBuilt);
+        }
+        for (src, dest) in [
+            ("lockc", "lockc.bpf.o"),
+            ("lockc.sha256", "lockc.bpf.o.sha256"),
+            ("lockc.sig", "lockc.bpf.o.sig"),
+        ] {
+            let path_dest = dest_dir.join(dest);
+            println!("Installing {} to {}", src, path_dest.display());
+            fs::copy(target_path.join(src), path_dest)?;
+        }
+
+        Ok(())
+    }
+
     fn install_config(&self) -> Result<(), InstallConfigError> {
         let sysconfdir_full = &self.install_dirs.sysconfdir_full;
 
@@ -366,6 +448,7 @@ impl Installer {
 
     pub(crate) fn do_install(&self) -> anyhow::Result<()> {
         self.install_binaries()?;
+        self.install_bpf_object()?;
         self.install_config()?;
         self.install_units()?;
         Ok(())
@@ -1,8 +1,11 @@
+mod bench;
 mod bintar;
 mod build_ebpf;
 mod codegen;
 mod install;
+mod package;
 mod run;
+mod test;
 
 use std::process::exit;
 
@@ -15,11 +18,18 @@ pub struct Options {
 
 #[derive(StructOpt)]
 enum Command {
+    /// Measure the overhead of lockc and of policy decisions
+    Bench(bench::Options),
     Bintar(bintar::Options),
     BuildEbpf(build_ebpf::Options),
     Install(install::Options),
+    /// Build a deb or rpm package
+    Package(package::Options),
     Run(run::Options),
-    Codegen,
+    /// Generate Rust bindings of kernel types used by eBPF programs
+    Codegen(codegen::Options),
+    /// Run tests, with --privileged also the ones loading eBPF programs
+    Test(test::Options),
 }
 
 fn main() {
@@ -27,11 +37,14 @@ fn main() {
 
     use Command::*;
     let ret = match opts.command {
+        Bench(opts) => bench::bench(opts),
         Bintar(opts) => bintar::BinTar::new(opts).do_bin_tar(),
         BuildEbpf(opts) => build_ebpf::build_ebpf(opts),
         Install(opts) => install::Installer::new(opts).do_install(),
+        Package(opts) => package::Package::new(opts).do_package(),
         Run(opts) => run::run(opts),
-        Codegen => codegen::generate(),
+        Codegen(opts) => codegen::generate(opts),
+        Test(opts) => test::test(opts),
     };
 
     if let Err(e) = ret {
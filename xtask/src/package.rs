@@ -0,0 +1,208 @@
+use std::{
+    fs, io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use scopeguard::guard;
+use serde::Serialize;
+use structopt::StructOpt;
+use tempfile::tempdir;
+use tera::{Context, Tera};
+
+use crate::install;
+
+const SUMMARY: &str = "eBPF-based MAC security audit for container workloads";
+
+#[derive(Debug, Copy, Clone)]
+pub enum Format {
+    Deb,
+    Rpm,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "deb" => Format::Deb,
+            "rpm" => Format::Rpm,
+            _ => return Err("invalid package format".to_owned()),
+        })
+    }
+}
+
+#[derive(StructOpt)]
+pub struct Options {
+    #[structopt(default_value = "debug", long)]
+    profile: String,
+
+    /// Package format (deb or rpm)
+    #[structopt(long)]
+    format: Format,
+}
+
+#[derive(Serialize)]
+struct PackageInfo {
+    name: &'static str,
+    version: String,
+    arch: String,
+    summary: &'static str,
+    /// Directory with the installed files.
+    stagedir: PathBuf,
+    /// Installed files, as absolute paths on the target system.
+    files: Vec<String>,
+}
+
+/// Returns the version of the lockc crate.
+pub(crate) fn lockc_version() -> Result<String> {
+    let manifest = fs::read_to_string(Path::new("lockc").join("Cargo.toml"))?;
+    manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("version = "))
+        .map(|version| version.trim_matches('"').to_owned())
+        .ok_or_else(|| anyhow!("could not find the version of lockc"))
+}
+
+/// Returns paths of all files in the given directory, relative to it and
+/// prefixed with "/".
+fn list_files(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files(root, &path, files)?;
+        } else if let Ok(rel) = path.strip_prefix(root) {
+            files.push(format!("/{}", rel.display()));
+        }
+    }
+    Ok(())
+}
+
+pub struct Package {
+    opts: Options,
+}
+
+impl Package {
+    pub fn new(opts: Options) -> Package {
+        Package { opts }
+    }
+
+    fn build_deb(&self, info: &PackageInfo, out_dir: &Path) -> Result<()> {
+        let debian_dir = info.stagedir.join("DEBIAN");
+        fs::create_dir_all(&debian_dir)?;
+
+        let packaging_dir = Path::new("contrib").join("packaging").join("deb");
+        let control = Tera::one_off(
+            &fs::read_to_string(packaging_dir.join("control.in"))?,
+            &Context::from_serialize(info)?,
+            false,
+        )?;
+        fs::write(debian_dir.join("control"), control)?;
+
+        let conffiles: Vec<&str> = info
+            .files
+            .iter()
+            .filter(|file| file.starts_with("/etc/"))
+            .map(|file| file.as_str())
+            .collect();
+        fs::write(debian_dir.join("conffiles"), conffiles.join("\n") + "\n")?;
+
+        for script in ["postinst", "prerm", "postrm"] {
+            let path_dest = debian_dir.join(script);
+            fs::copy(packaging_dir.join(script), &path_dest)?;
+            fs::set_permissions(&path_dest, fs::Permissions::from_mode(0o755))?;
+        }
+
+        let deb_path = out_dir.join(format!("{}_{}_{}.deb", info.name, info.version, info.arch));
+        let status = Command::new("dpkg-deb")
+            .arg("--root-owner-group")
+            .arg("--build")
+            .arg(&info.stagedir)
+            .arg(&deb_path)
+            .status()
+            .context("failed to run dpkg-deb")?;
+        if !status.success() {
+            return Err(anyhow!("dpkg-deb failed"));
+        }
+
+        println!("Package created: {}", deb_path.display());
+        Ok(())
+    }
+
+    fn build_rpm(&self, info: &PackageInfo, work_dir: &Path, out_dir: &Path) -> Result<()> {
+        let spec_path = work_dir.join("lockc.spec");
+        let spec = Tera::one_off(
+            &fs::read_to_string(
+                Path::new("contrib")
+                    .join("packaging")
+                    .join("rpm")
+                    .join("lockc.spec.in"),
+            )?,
+            &Context::from_serialize(info)?,
+            false,
+        )?;
+        fs::write(&spec_path, spec)?;
+
+        let out_dir = out_dir.canonicalize()?;
+        let status = Command::new("rpmbuild")
+            .arg("-bb")
+            .arg("--define")
+            .arg(format!("_topdir {}", work_dir.join("rpmbuild").display()))
+            .arg("--define")
+            .arg(format!("_rpmdir {}", out_dir.display()))
+            .arg(&spec_path)
+            .status()
+            .context("failed to run rpmbuild")?;
+        if !status.success() {
+            return Err(anyhow!("rpmbuild failed"));
+        }
+
+        println!("Package created in: {}", out_dir.join(&info.arch).display());
+        Ok(())
+    }
+
+    pub fn do_package(&self) -> Result<()> {
+        let dir = guard(tempdir()?, |d| {
+            // Ensure the dir is deleted.
+            d.close().unwrap();
+        });
+        let stagedir = dir.path().join("root");
+        install::Installer::new(install::Options {
+            profile: self.opts.profile.clone(),
+            destdir: stagedir.to_string_lossy().to_string(),
+            prefix: "usr".to_owned(),
+            bindir: "bin".to_owned(),
+            sysconfdir: "etc".to_owned(),
+            libdir: "lib".to_owned(),
+            unitdir: "lib/systemd/system".to_owned(),
+            target: None,
+        })
+        .do_install()?;
+
+        let mut files = Vec::new();
+        list_files(&stagedir, &stagedir, &mut files)?;
+        files.sort();
+
+        let arch = match (self.opts.format, std::env::consts::ARCH) {
+            (Format::Deb, "x86_64") => "amd64",
+            (Format::Deb, "aarch64") => "arm64",
+            (_, arch) => arch,
+        };
+        let info = PackageInfo {
+            name: "lockc",
+            version: lockc_version()?,
+            arch: arch.to_owned(),
+            summary: SUMMARY,
+            stagedir,
+            files,
+        };
+
+        let out_dir = Path::new("target").join(&self.opts.profile);
+        match self.opts.format {
+            Format::Deb => self.build_deb(&info, &out_dir),
+            Format::Rpm => self.build_rpm(&info, dir.path(), &out_dir),
+        }
+    }
+}
@@ -30,8 +30,10 @@ fn build(opts: &Options) -> Result<(), anyhow::Error> {
     let status = Command::new("cargo")
         .args(&args)
         .status()
-        .expect("failed to build userspace");
-    assert!(status.success());
+        .context("failed to run cargo")?;
+    if !status.success() {
+        anyhow::bail!("building userspace failed with {}", status);
+    }
     Ok(())
 }
 
@@ -41,6 +43,8 @@ pub fn run(opts: Options) -> Result<(), anyhow::Error> {
     build_ebpf(BuildOptions {
         target: opts.bpf_target,
         release: opts.release,
+        sign_key: None,
+        verify: false,
     })
     .context("Error while building eBPF program")?;
     build(&opts).context("Error while building userspace application")?;
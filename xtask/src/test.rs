@@ -0,0 +1,171 @@
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::Context as _;
+use structopt::StructOpt;
+
+use crate::build_ebpf::{build_ebpf, Architecture, Options as BuildOptions};
+
+/// Environment variable with the bpffs directory used by tests gated by the
+/// `tests_bpf` feature.
+const TEST_BPFFS_ENV: &str = "LOCKC_TEST_BPFFS";
+
+/// Returns the script which mounts a private bpffs instance on the directory
+/// given as `$0`, runs the test binary with its arguments and unmounts bpffs,
+/// which removes everything pinned by the tests.
+fn isolated_script() -> String {
+    format!(
+        r#"mount -t bpf bpf "$0" || exit 1
+{}="$0" "$@"
+ret=$?
+umount "$0"
+exit $ret"#,
+        TEST_BPFFS_ENV
+    )
+}
+
+#[derive(StructOpt)]
+pub struct Options {
+    /// Run the tests gated by the `tests_bpf` feature, which load eBPF
+    /// programs into the kernel, as root in new mount and PID namespaces
+    /// with their own bpffs
+    #[structopt(long)]
+    pub privileged: bool,
+    /// Set the endianness of the BPF target
+    #[structopt(default_value = "bpfel-unknown-none", long)]
+    pub bpf_target: Architecture,
+    /// Build and test the release target
+    #[structopt(long)]
+    pub release: bool,
+    /// The command used to gain root for privileged tests
+    #[structopt(short, long, default_value = "sudo -E")]
+    pub runner: String,
+    /// Arguments to pass to test binaries, e.g. a filter of test names
+    #[structopt(name = "args", last = true)]
+    pub test_args: Vec<String>,
+}
+
+/// Builds test binaries of lockc with the `tests_bpf` feature and returns
+/// their paths.
+fn build_tests(opts: &Options) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut args = vec![
+        "test",
+        "--no-run",
+        "--package",
+        "lockc",
+        "--features",
+        "tests_bpf",
+        "--message-format=json",
+    ];
+    if opts.release {
+        args.push("--release")
+    }
+    let output = Command::new("cargo")
+        .args(&args)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("failed to run cargo")?;
+    if !output.status.success() {
+        anyhow::bail!("building tests failed with {}", output.status);
+    }
+
+    let mut binaries = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let message: serde_json::Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if message["reason"] != "compiler-artifact" || message["profile"]["test"] != true {
+            continue;
+        }
+        if let Some(executable) = message["executable"].as_str() {
+            binaries.push(PathBuf::from(executable));
+        }
+    }
+    Ok(binaries)
+}
+
+/// Runs the test binary as root in new mount and PID namespaces, with a
+/// private bpffs mounted on `bpffs`.
+fn run_isolated(opts: &Options, binary: &Path, bpffs: &Path) -> Result<(), anyhow::Error> {
+    let script = isolated_script();
+    let mut args: Vec<_> = opts.runner.trim().split_terminator(' ').collect();
+    args.extend([
+        "unshare",
+        "--mount",
+        "--propagation",
+        "private",
+        "--pid",
+        "--fork",
+        "--mount-proc",
+        "sh",
+        "-c",
+        script.as_str(),
+    ]);
+    let bpffs = bpffs.to_string_lossy();
+    let binary = binary.to_string_lossy();
+    args.push(&bpffs);
+    args.push(&binary);
+    args.extend(opts.test_args.iter().map(String::as_str));
+
+    println!("Running {} (bpffs: {})", binary, bpffs);
+    let status = Command::new(args[0])
+        .args(&args[1..])
+        .status()
+        .with_context(|| format!("failed to run `{}`", args.join(" ")))?;
+    if !status.success() {
+        anyhow::bail!("{} failed with {}", binary, status);
+    }
+    Ok(())
+}
+
+/// Runs the tests of the workspace. Privileged tests get a temporary
+/// directory for their bpffs, removed afterwards along with anything left
+/// in it.
+pub fn test(opts: Options) -> Result<(), anyhow::Error> {
+    if !opts.privileged {
+        let status = Command::new("cargo")
+            .args(["test", "--workspace"])
+            .arg("--")
+            .args(&opts.test_args)
+            .status()
+            .context("failed to run cargo")?;
+        if !status.success() {
+            anyhow::bail!("tests failed with {}", status);
+        }
+        return Ok(());
+    }
+
+    // Tests embed the eBPF object.
+    build_ebpf(BuildOptions {
+        target: opts.bpf_target,
+        release: opts.release,
+        sign_key: None,
+        verify: false,
+    })
+    .context("Error while building eBPF program")?;
+    let binaries = build_tests(&opts).context("Error while building tests")?;
+
+    let bpffs = tempfile::Builder::new()
+        .prefix("lockc-test-bpffs-")
+        .tempdir()
+        .context("failed to create a directory for bpffs")?;
+    let mut failed = Vec::new();
+    for binary in &binaries {
+        if let Err(e) = run_isolated(&opts, binary, bpffs.path()) {
+            eprintln!("{:#}", e);
+            failed.push(binary.display().to_string());
+        }
+    }
+    bpffs
+        .close()
+        .context("failed to remove the directory for bpffs")?;
+
+    if !failed.is_empty() {
+        anyhow::bail!("tests failed: {}", failed.join(", "));
+    }
+    println!("All {} test binaries passed", binaries.len());
+    Ok(())
+}